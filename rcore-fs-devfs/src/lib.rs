@@ -19,7 +19,8 @@ pub mod special;
 /// The filesystem for all device files.
 /// It should be mounted at /dev.
 ///
-/// The file system is readonly from the root INode.
+/// The file system is readonly from the root INode,
+/// except that named pipes and sockets can be created and removed there.
 /// You can add or remove devices through `add()` and `remove()`.
 pub struct DevFS {
     devs: RwLock<BTreeMap<String, Arc<dyn INode>>>,
@@ -140,16 +141,27 @@ impl INode for DevRootINode {
         Err(FsError::IsDir)
     }
 
-    fn create(&self, _name: &str, _type_: FileType, _mode: u32) -> Result<Arc<dyn INode>> {
-        Err(FsError::NotSupported)
+    fn create(&self, name: &str, type_: FileType, mode: u32) -> Result<Arc<dyn INode>> {
+        match type_ {
+            FileType::NamedPipe | FileType::Socket => {
+                let inode: Arc<dyn INode> = Arc::new(special::MknodINode::new(type_, mode as u16));
+                self.fs.add(name, inode.clone())?;
+                Ok(inode)
+            }
+            _ => Err(FsError::NotSupported),
+        }
     }
 
     fn link(&self, _name: &str, _other: &Arc<dyn INode>) -> Result<()> {
         Err(FsError::NotSupported)
     }
 
-    fn unlink(&self, _name: &str) -> Result<()> {
-        Err(FsError::NotSupported)
+    fn unlink(&self, name: &str) -> Result<()> {
+        // only nodes created by `create()` can be removed here
+        match self.find(name)?.metadata()?.type_ {
+            FileType::NamedPipe | FileType::Socket => self.fs.remove(name),
+            _ => Err(FsError::NotSupported),
+        }
     }
 
     fn move_(&self, _old_name: &str, _target: &Arc<dyn INode>, _new_name: &str) -> Result<()> {
@@ -0,0 +1,58 @@
+use super::*;
+
+/// Named pipe or socket created by `mknod` in DevFS.
+///
+/// It only carries metadata, the kernel attaches the real pipe or socket to it.
+pub struct MknodINode {
+    type_: FileType,
+    mode: u16,
+    inode: usize,
+}
+
+impl MknodINode {
+    pub fn new(type_: FileType, mode: u16) -> Self {
+        use core::sync::atomic::*;
+        // leave low numbers for built-in devices
+        static ID: AtomicUsize = AtomicUsize::new(0x100);
+        MknodINode {
+            type_,
+            mode,
+            inode: ID.fetch_add(1, Ordering::SeqCst),
+        }
+    }
+}
+
+impl INode for MknodINode {
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize> {
+        Err(FsError::NotSupported)
+    }
+
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::NotSupported)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Err(FsError::NotSupported)
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(Metadata {
+            dev: 1,
+            inode: self.inode,
+            size: 0,
+            blk_size: 0,
+            blocks: 0,
+            atime: Timespec { sec: 0, nsec: 0 },
+            mtime: Timespec { sec: 0, nsec: 0 },
+            ctime: Timespec { sec: 0, nsec: 0 },
+            type_: self.type_,
+            mode: self.mode,
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+        })
+    }
+
+    impl_inode!();
+}
@@ -49,8 +49,10 @@ macro_rules! impl_inode {
     };
 }
 
+mod mknod;
 mod null;
 mod zero;
 
+pub use self::mknod::*;
 pub use self::null::*;
 pub use self::zero::*;
@@ -0,0 +1,96 @@
+//! Entries of dirs, in a linked list in each block: each entry tells the length of its
+//! record, which takes up the free space after it
+use alloc::{string::String, vec::Vec};
+
+use crate::structs::*;
+
+/// Bytes of an entry before its name
+const HEADER_SIZE: usize = 8;
+/// Record length on disk of a record of a whole 64K block, which does not fit
+const MAX_REC_LEN: u16 = 0xffff;
+
+/// A record in a dir, free if its INode is 0
+pub struct Dirent {
+    pub ino: u32,
+    pub name: String,
+    /// offset of the record in the dir
+    pub offset: usize,
+    pub rec_len: usize,
+}
+
+impl Dirent {
+    /// Bytes taken by the entry in its record, the rest is free
+    pub fn used_len(&self) -> usize {
+        match self.ino {
+            0 => 0,
+            _ => rec_size(self.name.len()),
+        }
+    }
+}
+
+/// Least record length of an entry with a name of `name_len` bytes
+pub fn rec_size(name_len: usize) -> usize {
+    (HEADER_SIZE + name_len + 3) & !3
+}
+
+/// The records of dir content `buf`, free ones too, or the index of the first bad block
+pub fn parse(buf: &[u8], block_size: usize) -> Result<Vec<Dirent>, usize> {
+    let mut dirents = Vec::new();
+    for (index, block) in buf.chunks(block_size).enumerate() {
+        let base = index * block_size;
+        let mut offset = 0;
+        while offset < block.len() {
+            if block.len() - offset < HEADER_SIZE {
+                return Err(index);
+            }
+            let ino = le32(block, offset);
+            let rec_len = match le16(block, offset + 4) {
+                MAX_REC_LEN => 1 << 16,
+                len => len as usize,
+            };
+            // without the filetype feature, the high byte of the name length, always 0
+            let name_len = block[offset + 6] as usize;
+            if rec_len < HEADER_SIZE
+                || rec_len % 4 != 0
+                || offset + rec_len > block.len()
+                || ino != 0 && HEADER_SIZE + name_len > rec_len
+            {
+                return Err(index);
+            }
+            let name_len = name_len.min(rec_len - HEADER_SIZE);
+            let name = &block[offset + HEADER_SIZE..offset + HEADER_SIZE + name_len];
+            dirents.push(Dirent {
+                ino,
+                name: String::from_utf8_lossy(name).into_owned(),
+                offset: base + offset,
+                rec_len,
+            });
+            offset += rec_len;
+        }
+    }
+    Ok(dirents)
+}
+
+/// Write an entry to the start of `buf`, with the file type only if `file_type` is
+pub fn write(buf: &mut [u8], ino: u32, rec_len: usize, name: &str, file_type: Option<u8>) {
+    put32(buf, 0, ino);
+    buf[4..6].copy_from_slice(&rec_len_bytes(rec_len));
+    buf[6] = name.len() as u8;
+    buf[7] = file_type.unwrap_or(0);
+    buf[HEADER_SIZE..HEADER_SIZE + name.len()].copy_from_slice(name.as_bytes());
+}
+
+/// Record length `rec_len` as on disk
+pub fn rec_len_bytes(rec_len: usize) -> [u8; 2] {
+    (rec_len.min(MAX_REC_LEN as usize) as u16).to_le_bytes()
+}
+
+/// Can `name` be the name of an entry?
+pub fn valid_name(name: &str) -> bool {
+    name != "."
+        && name != ".."
+        && !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && !name.contains('/')
+        && !name.contains('\0')
+}
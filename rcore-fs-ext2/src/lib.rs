@@ -1,115 +1,1427 @@
+//! ext2, read and written, e.g. on images made by `mke2fs -t ext2`
+//!
+//! Images with a feature of ext3 or ext4 not in `SUPPORTED_INCOMPAT` can not be loaded,
+//! and those with one not in `SUPPORTED_RO_COMPAT` are loaded read-only. Dirs with a
+//! hashed index are read as the plain lists they also are, and lose the index once changed.
 #![cfg_attr(not(any(test, feature = "std")), no_std)]
 
 extern crate alloc;
+#[macro_use]
+extern crate log;
 
-extern crate ext2;
+use alloc::{
+    collections::BTreeMap,
+    string::String,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use core::any::Any;
 
+use spin::{Mutex, RwLock};
+
+use rcore_fs::dev::{Device, TimeProvider};
+use rcore_fs::dirty::Dirty;
+use rcore_fs::vfs::{self, DirDefaults, FileType, FsError, INode, Metadata, Timespec};
+
+use self::dir::Dirent;
+pub use self::structs::*;
+
+mod dir;
+mod structs;
 #[cfg(test)]
 mod tests;
 
-use alloc::string::String;
-use alloc::sync::Arc;
-use alloc::vec;
-use core::ops::Range;
-use ext2::error::Error;
-use ext2::fs::sync::Synced;
-use ext2::fs::Ext2;
-use ext2::sector::{Address, Size512};
-use ext2::volume::size::Size;
-use ext2::volume::{Volume, VolumeCommit, VolumeSlice};
-use rcore_fs::dev::{DevError, Device};
-use rcore_fs::vfs;
-
-#[derive(Clone)]
-struct Ext2Volume {
-    inner: Arc<dyn Device>,
+trait DeviceExt: Device {
+    fn read_exact_at(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<()> {
+        match self.read_at(offset, buf)? {
+            len if len == buf.len() => Ok(()),
+            _ => Err(FsError::DeviceError),
+        }
+    }
+    fn write_exact_at(&self, offset: usize, buf: &[u8]) -> vfs::Result<()> {
+        match self.write_at(offset, buf)? {
+            len if len == buf.len() => Ok(()),
+            _ => Err(FsError::DeviceError),
+        }
+    }
+    /// Write `len` zeros at `offset`
+    fn zero_at(&self, offset: usize, len: usize) -> vfs::Result<()> {
+        let zeros = [0u8; 4096];
+        let mut done = 0;
+        while done < len {
+            let chunk = (len - done).min(zeros.len());
+            self.write_exact_at(offset + done, &zeros[..chunk])?;
+            done += chunk;
+        }
+        Ok(())
+    }
 }
 
-#[derive(Clone)]
-pub struct Ext2FileSystem {
-    inner: Synced<Ext2<Size512, Ext2Volume>>,
-    volume: Ext2Volume,
-}
+impl DeviceExt for dyn Device + '_ {}
+
+/// Magic of an xattr block, shared by the INodes with the same xattrs
+const XATTR_MAGIC: u32 = 0xea02_0000;
 
-/// A conversion between vfs::FsError and ext2::Error
-#[derive(Debug)]
-struct Ext2Error {
-    inner: Error,
+/// INode for ext2
+pub struct INodeImpl {
+    /// INode number
+    ino: u32,
+    disk_inode: RwLock<Dirty<DiskINode>>,
+    fs: Arc<Ext2FileSystem>,
 }
 
-impl core::convert::From<Ext2Error> for vfs::FsError {
-    fn from(err: Ext2Error) -> Self {
-        match err.inner {
-            _ => vfs::FsError::DeviceError,
+impl INodeImpl {
+    fn block_size(&self) -> usize {
+        self.fs.block_size
+    }
+
+    /// Is it a symlink kept in its block pointers? A new symlink is one till it is too long.
+    fn is_fast_symlink(&self, disk: &DiskINode) -> bool {
+        let xattr = match disk.file_acl {
+            0 => 0,
+            _ => self.fs.sectors_per_block(),
+        };
+        disk.is(S_IFLNK) && disk.blocks == xattr
+    }
+
+    /// Are its block pointers those of blocks, not a device number or a short symlink?
+    fn has_blocks(&self, disk: &DiskINode) -> bool {
+        match disk.mode & S_IFMT {
+            S_IFREG | S_IFDIR => true,
+            S_IFLNK => !self.is_fast_symlink(disk),
+            _ => false,
+        }
+    }
+
+    /// Device block of block `index` of the content, 0 for a hole
+    fn get_block(&self, disk: &DiskINode, index: usize) -> vfs::Result<u32> {
+        let (slot, path) = self.fs.block_path(index)?;
+        let mut block = disk.block[slot];
+        for &i in path.iter() {
+            if block == 0 {
+                break;
+            }
+            block = self.fs.read_ptr(block, i)?;
+        }
+        Ok(block)
+    }
+
+    /// Device block of block `index` of the content, taken with the indirect blocks above it
+    /// if it is a hole. Tell if it is new, and so not zeroed.
+    fn get_or_alloc_block(&self, disk: &mut DiskINode, index: usize) -> vfs::Result<(u32, bool)> {
+        let (slot, path) = self.fs.block_path(index)?;
+        let goal = self.goal(disk, index)?;
+        let sectors = self.fs.sectors_per_block();
+        let mut new = false;
+        let mut block = disk.block[slot];
+        if block == 0 {
+            block = self.fs.alloc_block(goal)?;
+            disk.block[slot] = block;
+            disk.blocks += sectors;
+            new = true;
+        }
+        for &i in path.iter() {
+            // pointers of a new indirect block are zeroed below, without reading it again
+            let mut next = match new {
+                true => 0,
+                false => self.fs.read_ptr(block, i)?,
+            };
+            if new {
+                self.fs
+                    .device
+                    .zero_at(block as usize * self.block_size(), self.block_size())?;
+            }
+            if next == 0 {
+                next = self.fs.alloc_block(goal)?;
+                self.fs.write_ptr(block, i, next)?;
+                disk.blocks += sectors;
+                new = true;
+            }
+            block = next;
+        }
+        Ok((block, new))
+    }
+
+    /// Block to look for a free block from, next to block `index - 1` of the content
+    fn goal(&self, disk: &DiskINode, index: usize) -> vfs::Result<u32> {
+        if index > 0 {
+            let prev = self.get_block(disk, index - 1)?;
+            if prev != 0 {
+                return Ok(prev + 1);
+            }
+        }
+        Ok(self.fs.group_first_block(self.fs.ino_group(self.ino)))
+    }
+
+    /// Free the blocks of the content from block `from` on, and the indirect blocks left empty
+    fn free_blocks_from(&self, disk: &mut DiskINode, from: usize) -> vfs::Result<()> {
+        let mut freed = Vec::new();
+        for ptr in disk.block[from.min(NDIR_BLOCKS)..NDIR_BLOCKS].iter_mut() {
+            if *ptr != 0 {
+                freed.push(*ptr);
+                *ptr = 0;
+            }
+        }
+        let mut begin = NDIR_BLOCKS;
+        for &(level, slot) in [(1, IND_BLOCK), (2, DIND_BLOCK), (3, TIND_BLOCK)].iter() {
+            let span = self.fs.span(level);
+            let keep = from.saturating_sub(begin).min(span);
+            self.fs
+                .free_tree(&mut disk.block[slot], level, keep, &mut freed)?;
+            begin = begin.saturating_add(span);
+        }
+        disk.blocks -= freed.len() as u32 * self.fs.sectors_per_block();
+        self.fs.free_blocks(&freed)
+    }
+
+    /// Device ranges of `len` bytes at `offset` of the content, none for holes
+    fn extents(
+        &self,
+        disk: &DiskINode,
+        offset: usize,
+        len: usize,
+    ) -> vfs::Result<Vec<(Option<usize>, usize)>> {
+        let block_size = self.block_size();
+        let mut extents: Vec<(Option<usize>, usize)> = Vec::new();
+        let mut done = 0;
+        while done < len {
+            let index = (offset + done) / block_size;
+            let begin = (offset + done) % block_size;
+            let chunk = (block_size - begin).min(len - done);
+            let at = match self.get_block(disk, index)? {
+                0 => None,
+                block => Some(block as usize * block_size + begin),
+            };
+            match (extents.last_mut(), at) {
+                // blocks next to each other, as they often are
+                (Some((Some(last), last_len)), Some(at)) if *last + *last_len == at => {
+                    *last_len += chunk
+                }
+                (Some((None, last_len)), None) => *last_len += chunk,
+                _ => extents.push((at, chunk)),
+            }
+            done += chunk;
+        }
+        Ok(extents)
+    }
+
+    fn read_content(&self, disk: &DiskINode, offset: usize, buf: &mut [u8]) -> vfs::Result<()> {
+        let mut done = 0;
+        for (at, len) in self.extents(disk, offset, buf.len())? {
+            let part = &mut buf[done..done + len];
+            match at {
+                Some(at) => self.fs.device.read_exact_at(at, part)?,
+                None => part.iter_mut().for_each(|byte| *byte = 0),
+            }
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Write `buf` at `offset` of the content, taking blocks for the holes
+    fn write_content(&self, disk: &mut DiskINode, offset: usize, buf: &[u8]) -> vfs::Result<()> {
+        let block_size = self.block_size();
+        let mut done = 0;
+        while done < buf.len() {
+            let index = (offset + done) / block_size;
+            let begin = (offset + done) % block_size;
+            let chunk = (block_size - begin).min(buf.len() - done);
+            let (block, new) = self.get_or_alloc_block(disk, index)?;
+            let at = block as usize * block_size;
+            if new && chunk < block_size {
+                self.fs.device.zero_at(at, block_size)?;
+            }
+            self.fs
+                .device
+                .write_exact_at(at + begin, &buf[done..done + chunk])?;
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Resize the content to `len`, whose bytes past the old size read as zeros
+    fn resize_content(&self, disk: &mut DiskINode, len: usize) -> vfs::Result<()> {
+        if len as u64 > self.fs.max_len {
+            return Err(FsError::InvalidParam);
+        }
+        let block_size = self.block_size();
+        if len < disk.file_size() as usize {
+            self.free_blocks_from(disk, (len + block_size - 1) / block_size)?;
+            // so that they read as zeros once it grows again
+            let tail = len % block_size;
+            if tail != 0 {
+                let block = self.get_block(disk, len / block_size)?;
+                if block != 0 {
+                    let at = block as usize * block_size + tail;
+                    self.fs.device.zero_at(at, block_size - tail)?;
+                }
+            }
+        }
+        if len > i32::max_value() as usize {
+            self.fs.set_large_file();
+        }
+        disk.set_file_size(len as u64);
+        Ok(())
+    }
+
+    /// Move a short symlink from its block pointers to a block, so that it can grow
+    fn unfast_symlink(&self, disk: &mut DiskINode) -> vfs::Result<()> {
+        let len = disk.file_size() as usize;
+        let target = disk.block_bytes();
+        disk.block = [0; N_BLOCKS];
+        if len > 0 {
+            self.write_content(disk, 0, &target[..len])?;
+        }
+        Ok(())
+    }
+
+    /// Set the modification and change times to now
+    fn touch(&self, disk: &mut DiskINode) {
+        if let Some(now) = self.fs.now() {
+            disk.mtime = now.sec as u32;
+            disk.ctime = now.sec as u32;
+        }
+    }
+
+    /// Set the change time to now
+    fn touch_ctime(&self, disk: &mut DiskINode) {
+        if let Some(now) = self.fs.now() {
+            disk.ctime = now.sec as u32;
+        }
+    }
+
+    fn check_dir(&self, disk: &DiskINode) -> vfs::Result<()> {
+        if !disk.is(S_IFDIR) {
+            return Err(FsError::NotDir);
+        }
+        if disk.links_count == 0 {
+            return Err(FsError::DirRemoved);
+        }
+        Ok(())
+    }
+
+    /// All records of the dir, free ones too
+    fn dirents(&self, disk: &DiskINode) -> vfs::Result<Vec<Dirent>> {
+        let mut buf = vec![0u8; disk.file_size() as usize];
+        self.read_content(disk, 0, &mut buf)?;
+        dir::parse(&buf, self.block_size()).map_err(|index| {
+            let block = self.get_block(disk, index).unwrap_or(0);
+            FsError::Corrupted(block as usize)
+        })
+    }
+
+    fn find_dirent(&self, disk: &DiskINode, name: &str) -> vfs::Result<Dirent> {
+        self.dirents(disk)?
+            .into_iter()
+            .find(|dirent| dirent.ino != 0 && dirent.name == name)
+            .ok_or(FsError::EntryNotFound)
+    }
+
+    /// Add entry `name` of INode `ino` of `mode` to the dir, in the first record with room
+    /// for it, or in a new block
+    fn add_entry(&self, disk: &mut DiskINode, name: &str, ino: u32, mode: u16) -> vfs::Result<()> {
+        let block_size = self.block_size();
+        let size = dir::rec_size(name.len());
+        let dirents = self.dirents(disk)?;
+        let room = dirents
+            .iter()
+            .find(|dirent| dirent.rec_len - dirent.used_len() >= size);
+        let mut buf = vec![0u8; size];
+        match room {
+            Some(free) if free.ino == 0 => {
+                dir::write(&mut buf, ino, free.rec_len, name, self.fs.file_type(mode));
+                self.write_content(disk, free.offset, &buf)?;
+            }
+            // after the entry, which is then cut to what it uses
+            Some(entry) => {
+                let used = entry.used_len();
+                dir::write(
+                    &mut buf,
+                    ino,
+                    entry.rec_len - used,
+                    name,
+                    self.fs.file_type(mode),
+                );
+                self.write_content(disk, entry.offset + used, &buf)?;
+                self.write_content(disk, entry.offset + 4, &dir::rec_len_bytes(used))?;
+            }
+            None => {
+                let offset = disk.file_size() as usize;
+                dir::write(&mut buf, ino, block_size, name, self.fs.file_type(mode));
+                self.write_content(disk, offset, &buf)?;
+                disk.set_file_size((offset + block_size) as u64);
+            }
+        }
+        disk.flags &= !INDEX_FL;
+        self.touch(disk);
+        Ok(())
+    }
+
+    /// Remove entry `dirent` of the dir, whose record is merged into the one before it
+    fn remove_entry(&self, disk: &mut DiskINode, dirent: &Dirent) -> vfs::Result<()> {
+        let block_size = self.block_size();
+        let prev = self.dirents(disk)?.into_iter().find(|prev| {
+            prev.offset + prev.rec_len == dirent.offset
+                && prev.offset / block_size == dirent.offset / block_size
+        });
+        match prev {
+            Some(prev) => {
+                let rec_len = dir::rec_len_bytes(prev.rec_len + dirent.rec_len);
+                self.write_content(disk, prev.offset + 4, &rec_len)?;
+            }
+            // the first in its block
+            None => self.write_content(disk, dirent.offset, &0u32.to_le_bytes())?,
+        }
+        disk.flags &= !INDEX_FL;
+        self.touch(disk);
+        Ok(())
+    }
+
+    /// Point entry `dirent` of the dir at INode `ino` of `mode`
+    fn set_entry(
+        &self,
+        disk: &mut DiskINode,
+        dirent: &Dirent,
+        ino: u32,
+        mode: u16,
+    ) -> vfs::Result<()> {
+        self.write_content(disk, dirent.offset, &ino.to_le_bytes())?;
+        if let Some(file_type) = self.fs.file_type(mode) {
+            self.write_content(disk, dirent.offset + 7, &[file_type])?;
+        }
+        self.touch(disk);
+        Ok(())
+    }
+
+    /// Is the dir empty but for "." and ".."?
+    fn is_empty_dir(&self, disk: &DiskINode) -> vfs::Result<bool> {
+        Ok(self
+            .dirents(disk)?
+            .iter()
+            .all(|dirent| dirent.ino == 0 || dirent.name == "." || dirent.name == ".."))
+    }
+
+    /// Is dir `self` `dir`, or under it?
+    fn is_under(&self, dir: &INodeImpl) -> vfs::Result<bool> {
+        let mut ino = self.ino;
+        loop {
+            if ino == dir.ino {
+                return Ok(true);
+            }
+            if ino == ROOT_INO {
+                return Ok(false);
+            }
+            let inode = self.fs.get_inode(ino)?;
+            let disk = inode.disk_inode.read();
+            ino = inode.find_dirent(&disk, "..")?.ino;
+        }
+    }
+
+    /// Device number of a device INode, in the old format if it fits in 16 bits
+    fn rdev(&self, disk: &DiskINode) -> usize {
+        if !disk.is(S_IFCHR) && !disk.is(S_IFBLK) {
+            return 0;
+        }
+        match disk.block[0] {
+            0 => disk.block[1] as usize,
+            old => vfs::make_rdev((old as usize >> 8) & 0xff, old as usize & 0xff),
         }
     }
-}
 
-impl core::convert::From<Ext2Error> for Error {
-    fn from(err: Ext2Error) -> Self {
-        err.inner
+    fn metadata_of(&self, disk: &DiskINode) -> Metadata {
+        let type_ = match disk.mode & S_IFMT {
+            S_IFDIR => FileType::Dir,
+            S_IFLNK => FileType::SymLink,
+            S_IFCHR => FileType::CharDevice,
+            S_IFBLK => FileType::BlockDevice,
+            S_IFIFO => FileType::NamedPipe,
+            S_IFSOCK => FileType::Socket,
+            _ => FileType::File,
+        };
+        let time = |sec: u32| Timespec {
+            sec: sec as i64,
+            nsec: 0,
+        };
+        Metadata {
+            dev: 0,
+            inode: self.ino as usize,
+            size: disk.file_size() as usize,
+            blk_size: self.block_size(),
+            blocks: (disk.blocks / self.fs.sectors_per_block()) as usize,
+            atime: time(disk.atime),
+            mtime: time(disk.mtime),
+            ctime: time(disk.ctime),
+            type_,
+            mode: disk.mode & !S_IFMT,
+            nlinks: disk.links_count as usize,
+            uid: disk.uid as usize,
+            gid: disk.gid as usize,
+            rdev: self.rdev(disk),
+        }
+    }
+
+    /// Write the INode back if it is changed
+    fn write_back(&self, disk: &mut Dirty<DiskINode>) -> vfs::Result<()> {
+        if disk.dirty() {
+            self.fs.write_inode(self.ino, disk)?;
+            disk.sync();
+        }
+        Ok(())
+    }
+
+    /// Free the INode and its blocks, once its last link is gone and it is dropped
+    fn free(&self, disk: &mut Dirty<DiskINode>) -> vfs::Result<()> {
+        if self.has_blocks(disk) {
+            self.free_blocks_from(disk, 0)?;
+        }
+        if disk.file_acl != 0 {
+            self.fs.release_xattr_block(disk.file_acl)?;
+            disk.file_acl = 0;
+            disk.blocks -= self.fs.sectors_per_block();
+        }
+        disk.set_file_size(0);
+        disk.dtime = match self.fs.now() {
+            Some(now) => now.sec as u32,
+            None => disk.ctime.max(1),
+        };
+        self.fs.write_inode(self.ino, disk)?;
+        disk.sync();
+        self.fs.free_inode(self.ino, disk.is(S_IFDIR))
     }
 }
 
-impl core::convert::From<Error> for Ext2Error {
-    fn from(err: Error) -> Self {
-        Ext2Error { inner: err }
+impl vfs::INode for INodeImpl {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<usize> {
+        let disk = self.disk_inode.read();
+        match disk.mode & S_IFMT {
+            S_IFREG | S_IFLNK => {}
+            S_IFDIR => return Err(FsError::IsDir),
+            _ => return Err(FsError::NotFile),
+        }
+        let size = disk.file_size() as usize;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min(size - offset);
+        if self.is_fast_symlink(&disk) {
+            buf[..len].copy_from_slice(&disk.block_bytes()[offset..offset + len]);
+        } else {
+            self.read_content(&disk, offset, &mut buf[..len])?;
+        }
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> vfs::Result<usize> {
+        self.fs.check_writable()?;
+        let mut disk = self.disk_inode.write();
+        match disk.mode & S_IFMT {
+            S_IFREG | S_IFLNK => {}
+            S_IFDIR => return Err(FsError::IsDir),
+            _ => return Err(FsError::NotFile),
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let end = offset + buf.len();
+        if end as u64 > self.fs.max_len {
+            return Err(FsError::InvalidParam);
+        }
+        if self.is_fast_symlink(&disk) {
+            // with room for a NUL, as by Linux
+            if end < FAST_SYMLINK_SIZE {
+                let mut target = disk.block_bytes();
+                target[offset..end].copy_from_slice(buf);
+                disk.set_block_bytes(&target);
+                if end > disk.file_size() as usize {
+                    disk.set_file_size(end as u64);
+                }
+                self.touch(&mut disk);
+                return Ok(buf.len());
+            }
+            self.unfast_symlink(&mut disk)?;
+        }
+        self.write_content(&mut disk, offset, buf)?;
+        if end > disk.file_size() as usize {
+            self.resize_content(&mut disk, end)?;
+        }
+        self.touch(&mut disk);
+        Ok(buf.len())
+    }
+
+    fn poll(&self) -> vfs::Result<vfs::PollStatus> {
+        Ok(vfs::PollStatus {
+            read: true,
+            write: true,
+            error: false,
+        })
+    }
+
+    fn metadata(&self) -> vfs::Result<Metadata> {
+        Ok(self.metadata_of(&self.disk_inode.read()))
+    }
+
+    fn set_metadata(&self, metadata: &Metadata) -> vfs::Result<()> {
+        self.fs.check_writable()?;
+        let mut disk = self.disk_inode.write();
+        disk.atime = metadata.atime.sec as u32;
+        disk.mtime = metadata.mtime.sec as u32;
+        disk.ctime = metadata.ctime.sec as u32;
+        disk.mode = (disk.mode & S_IFMT) | (metadata.mode & !S_IFMT);
+        disk.uid = metadata.uid as u32;
+        disk.gid = metadata.gid as u32;
+        Ok(())
+    }
+
+    fn sync_all(&self) -> vfs::Result<()> {
+        self.write_back(&mut self.disk_inode.write())
+    }
+
+    fn sync_data(&self) -> vfs::Result<()> {
+        self.sync_all()
+    }
+
+    fn resize(&self, len: usize) -> vfs::Result<()> {
+        self.fs.check_writable()?;
+        let mut disk = self.disk_inode.write();
+        if !disk.is(S_IFREG) {
+            return Err(FsError::NotFile);
+        }
+        self.resize_content(&mut disk, len)?;
+        self.touch(&mut disk);
+        Ok(())
+    }
+
+    /// `data` is the device number of a device, made by `make_rdev()`
+    fn create2(
+        &self,
+        name: &str,
+        type_: FileType,
+        mode: u32,
+        data: usize,
+    ) -> vfs::Result<Arc<dyn INode>> {
+        self.fs.check_writable()?;
+        let _namespace = self.fs.namespace.lock();
+        let mut disk = self.disk_inode.write();
+        self.check_dir(&disk)?;
+        if name == "." || name == ".." || self.find_dirent(&disk, name).is_ok() {
+            return Err(FsError::EntryExist);
+        }
+        if !dir::valid_name(name) {
+            return Err(FsError::InvalidParam);
+        }
+        let is_dir = type_ == FileType::Dir;
+        if is_dir && disk.links_count >= LINK_MAX {
+            return Err(FsError::NoDeviceSpace);
+        }
+        let format = match type_ {
+            FileType::File => S_IFREG,
+            FileType::Dir => S_IFDIR,
+            FileType::SymLink => S_IFLNK,
+            FileType::CharDevice => S_IFCHR,
+            FileType::BlockDevice => S_IFBLK,
+            FileType::NamedPipe => S_IFIFO,
+            FileType::Socket => S_IFSOCK,
+        };
+        let (mode, gid) = DirDefaults::default().apply(&self.metadata_of(&disk), type_, mode);
+        let now = self.fs.now().map_or(0, |now| now.sec as u32);
+        let mut new = DiskINode {
+            mode: format | (mode & !S_IFMT),
+            gid: gid as u32,
+            atime: now,
+            ctime: now,
+            mtime: now,
+            links_count: 1,
+            ..DiskINode::default()
+        };
+        if type_ == FileType::CharDevice || type_ == FileType::BlockDevice {
+            let (major, minor) = (vfs::rdev_major(data), vfs::rdev_minor(data));
+            match major < 0x100 && minor < 0x100 {
+                true => new.block[0] = (major << 8 | minor) as u32,
+                false => new.block[1] = data as u32,
+            }
+        }
+        let ino = self.fs.alloc_inode(self.ino, is_dir)?;
+        let inode = match self.fs.init_inode(ino, new) {
+            Ok(inode) => inode,
+            Err(err) => {
+                self.fs.free_inode(ino, is_dir)?;
+                return Err(err);
+            }
+        };
+        // freed once dropped, if it is not linked
+        let mut child = inode.disk_inode.write();
+        let mut result = Ok(());
+        if is_dir {
+            // with "." and "..", and a link from ".."
+            child.links_count = 2;
+            result = inode
+                .add_entry(&mut child, ".", ino, S_IFDIR)
+                .and_then(|_| inode.add_entry(&mut child, "..", self.ino, S_IFDIR));
+        }
+        let mode = child.mode;
+        if let Err(err) = result.and_then(|_| self.add_entry(&mut disk, name, ino, mode)) {
+            child.links_count = 0;
+            return Err(err);
+        }
+        if is_dir {
+            disk.links_count += 1;
+        }
+        drop(child);
+        Ok(inode)
+    }
+
+    fn link(&self, name: &str, other: &Arc<dyn INode>) -> vfs::Result<()> {
+        self.fs.check_writable()?;
+        let other = other
+            .downcast_ref::<INodeImpl>()
+            .ok_or(FsError::NotSameFs)?;
+        if !Arc::ptr_eq(&self.fs, &other.fs) {
+            return Err(FsError::NotSameFs);
+        }
+        let _namespace = self.fs.namespace.lock();
+        let mut disk = self.disk_inode.write();
+        self.check_dir(&disk)?;
+        if name == "." || name == ".." || self.find_dirent(&disk, name).is_ok() {
+            return Err(FsError::EntryExist);
+        }
+        if !dir::valid_name(name) {
+            return Err(FsError::InvalidParam);
+        }
+        // the INode of the dir is not locked again
+        if other.ino == self.ino {
+            return Err(FsError::IsDir);
+        }
+        let mut child = other.disk_inode.write();
+        if child.is(S_IFDIR) {
+            return Err(FsError::IsDir);
+        }
+        if child.links_count == 0 {
+            return Err(FsError::EntryNotFound);
+        }
+        if child.links_count >= LINK_MAX {
+            return Err(FsError::NoDeviceSpace);
+        }
+        self.add_entry(&mut disk, name, other.ino, child.mode)?;
+        child.links_count += 1;
+        other.touch_ctime(&mut child);
+        Ok(())
+    }
+
+    fn unlink(&self, name: &str) -> vfs::Result<()> {
+        self.fs.check_writable()?;
+        if name == "." || name == ".." {
+            return Err(FsError::IsDir);
+        }
+        let _namespace = self.fs.namespace.lock();
+        let mut disk = self.disk_inode.write();
+        self.check_dir(&disk)?;
+        let dirent = self.find_dirent(&disk, name)?;
+        let inode = self.fs.get_inode(dirent.ino)?;
+        let mut child = inode.disk_inode.write();
+        let is_dir = child.is(S_IFDIR);
+        if is_dir && !inode.is_empty_dir(&child)? {
+            return Err(FsError::DirNotEmpty);
+        }
+        self.remove_entry(&mut disk, &dirent)?;
+        if is_dir {
+            // and its ".."
+            child.links_count = 0;
+            disk.links_count -= 1;
+        } else {
+            child.links_count -= 1;
+        }
+        inode.touch_ctime(&mut child);
+        Ok(())
+    }
+
+    fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> vfs::Result<()> {
+        self.fs.check_writable()?;
+        if old_name == "." || old_name == ".." {
+            return Err(FsError::IsDir);
+        }
+        let target = target
+            .downcast_ref::<INodeImpl>()
+            .ok_or(FsError::NotSameFs)?;
+        if !Arc::ptr_eq(&self.fs, &target.fs) {
+            return Err(FsError::NotSameFs);
+        }
+        if !dir::valid_name(new_name) {
+            return Err(FsError::InvalidParam);
+        }
+        let same_dir = self.ino == target.ino;
+        if same_dir && old_name == new_name {
+            return Ok(());
+        }
+        let _namespace = self.fs.namespace.lock();
+        // dir contents only change under the namespace lock, so they are read as they are
+        let dirent = {
+            let disk = self.disk_inode.read();
+            self.check_dir(&disk)?;
+            self.find_dirent(&disk, old_name)?
+        };
+        let inode = self.fs.get_inode(dirent.ino)?;
+        target.check_dir(&target.disk_inode.read())?;
+        let (mode, is_dir) = {
+            let child = inode.disk_inode.read();
+            (child.mode, child.is(S_IFDIR))
+        };
+        if is_dir && target.is_under(&inode)? {
+            return Err(FsError::InvalidParam);
+        }
+        let old = target.find_dirent(&target.disk_inode.read(), new_name).ok();
+        match old {
+            // another link to the same INode, left as it is
+            Some(ref old) if old.ino == dirent.ino => return Ok(()),
+            Some(old) => {
+                let old_inode = self.fs.get_inode(old.ino)?;
+                // the dir holding what is moved
+                if old_inode.ino == self.ino {
+                    return Err(FsError::DirNotEmpty);
+                }
+                let mut old_disk = old_inode.disk_inode.write();
+                match (is_dir, old_disk.is(S_IFDIR)) {
+                    (true, true) => {
+                        if !old_inode.is_empty_dir(&old_disk)? {
+                            return Err(FsError::DirNotEmpty);
+                        }
+                    }
+                    (true, false) => return Err(FsError::NotDir),
+                    (false, true) => return Err(FsError::IsDir),
+                    (false, false) => {}
+                }
+                // replaced in place, so that the new name is never missing
+                let mut target_disk = target.disk_inode.write();
+                target.set_entry(&mut target_disk, &old, dirent.ino, mode)?;
+                if old_disk.is(S_IFDIR) {
+                    old_disk.links_count = 0;
+                    target_disk.links_count -= 1;
+                } else {
+                    old_disk.links_count -= 1;
+                }
+                old_inode.touch_ctime(&mut old_disk);
+            }
+            None => {
+                let mut target_disk = target.disk_inode.write();
+                if is_dir && !same_dir && target_disk.links_count >= LINK_MAX {
+                    return Err(FsError::NoDeviceSpace);
+                }
+                target.add_entry(&mut target_disk, new_name, dirent.ino, mode)?;
+            }
+        }
+        {
+            let mut disk = self.disk_inode.write();
+            // the dir may have changed when it is the target
+            let dirent = self.find_dirent(&disk, old_name)?;
+            self.remove_entry(&mut disk, &dirent)?;
+        }
+        let mut child = inode.disk_inode.write();
+        if is_dir && !same_dir {
+            let dotdot = inode.find_dirent(&child, "..")?;
+            inode.set_entry(&mut child, &dotdot, target.ino, S_IFDIR)?;
+            self.disk_inode.write().links_count -= 1;
+            target.disk_inode.write().links_count += 1;
+        }
+        inode.touch_ctime(&mut child);
+        Ok(())
+    }
+
+    fn find(&self, name: &str) -> vfs::Result<Arc<dyn INode>> {
+        let disk = self.disk_inode.read();
+        self.check_dir(&disk)?;
+        let ino = match name {
+            "." => self.ino,
+            _ => self.find_dirent(&disk, name)?.ino,
+        };
+        Ok(self.fs.get_inode(ino)?)
+    }
+
+    fn get_entry(&self, id: usize) -> vfs::Result<String> {
+        let disk = self.disk_inode.read();
+        self.check_dir(&disk)?;
+        self.dirents(&disk)?
+            .into_iter()
+            .filter(|dirent| dirent.ino != 0)
+            .nth(id)
+            .map(|dirent| dirent.name)
+            .ok_or(FsError::EntryNotFound)
+    }
+
+    fn fs(&self) -> Arc<dyn vfs::FileSystem> {
+        self.fs.clone()
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
     }
 }
 
-impl core::convert::From<DevError> for Ext2Error {
-    fn from(_: DevError) -> Self {
-        Ext2Error {
-            inner: Error::Other(String::from("unknown")),
+impl Drop for INodeImpl {
+    /// Write back the INode, or free it once its last link is gone
+    fn drop(&mut self) {
+        let mut disk = self.disk_inode.write();
+        let result = match disk.links_count {
+            0 if !self.fs.read_only => self.free(&mut disk),
+            _ => self.write_back(&mut disk),
+        };
+        if let Err(err) = result {
+            warn!("failed to write back INode {}: {:?}", self.ino, err);
+            disk.sync();
         }
     }
 }
 
+/// The super block and the group descriptors, as changed when blocks and INodes are taken
+struct Alloc {
+    super_block: SuperBlock,
+    groups: Vec<GroupDesc>,
+}
+
+/// ext2 file system
+pub struct Ext2FileSystem {
+    device: Arc<dyn Device>,
+    /// The super block as loaded, for the layout
+    super_block: SuperBlock,
+    block_size: usize,
+    /// First block of the INode table of each group
+    inode_tables: Vec<u32>,
+    alloc: Mutex<Dirty<Alloc>>,
+    /// Largest size of a file
+    max_len: u64,
+    /// Loaded with features not handled, so it can not be changed
+    read_only: bool,
+    /// INodes in memory, by their numbers
+    inodes: RwLock<BTreeMap<u32, Weak<INodeImpl>>>,
+    /// Held while the entries of any dir are changed, so that a move sees both dirs at once
+    namespace: Mutex<()>,
+    /// Pointer to self, used by INodes
+    self_ptr: Weak<Ext2FileSystem>,
+    /// Clock for timestamps, which are left alone without it
+    time: Option<&'static dyn TimeProvider>,
+}
+
 impl Ext2FileSystem {
+    /// Load ext2 from device without a clock, so timestamps are only set by `set_metadata`
     pub fn open(device: Arc<dyn Device>) -> vfs::Result<Arc<Self>> {
-        Ok(Self::open_internal(device)?)
+        Self::_open(device, None)
+    }
+
+    /// Load ext2 from device, with timestamps from `time`
+    pub fn open_with_time(
+        device: Arc<dyn Device>,
+        time: &'static dyn TimeProvider,
+    ) -> vfs::Result<Arc<Self>> {
+        Self::_open(device, Some(time))
+    }
+
+    fn _open(
+        device: Arc<dyn Device>,
+        time: Option<&'static dyn TimeProvider>,
+    ) -> vfs::Result<Arc<Self>> {
+        let mut buf = vec![0u8; SUPER_SIZE];
+        device.read_exact_at(SUPER_OFFSET, &mut buf)?;
+        let super_block = SuperBlock::parse(&buf);
+        if !super_block.check() {
+            return Err(FsError::WrongFs);
+        }
+        let unknown = super_block.feature_incompat & !SUPPORTED_INCOMPAT;
+        if unknown != 0 {
+            warn!("ext2 with unknown incompatible features {:#x}", unknown);
+            return Err(FsError::NotSupported);
+        }
+        let read_only = super_block.feature_ro_compat & !SUPPORTED_RO_COMPAT != 0;
+        if read_only {
+            warn!("ext2 with unknown features, loaded read-only");
+        }
+        let block_size = super_block.block_size();
+        // a block must be written without touching its neighbours
+        if 1usize << device.sector_size_log2() > block_size {
+            return Err(FsError::InvalidParam);
+        }
+        let mut buf = vec![0u8; super_block.groups() * DESC_SIZE];
+        let table = (super_block.first_data_block as usize + 1) * block_size;
+        device.read_exact_at(table, &mut buf)?;
+        let groups: Vec<GroupDesc> = buf.chunks_exact(DESC_SIZE).map(GroupDesc::parse).collect();
+        let fs = Ext2FileSystem {
+            device,
+            block_size,
+            inode_tables: groups.iter().map(|group| group.inode_table).collect(),
+            max_len: max_len(&super_block),
+            alloc: Mutex::new(Dirty::new(Alloc {
+                super_block: super_block.clone(),
+                groups,
+            })),
+            super_block,
+            read_only,
+            inodes: RwLock::new(BTreeMap::new()),
+            namespace: Mutex::new(()),
+            self_ptr: Weak::default(),
+            time,
+        };
+        Ok(fs.wrap())
+    }
+
+    /// Wrap pure Ext2FileSystem with Arc
+    /// Used in constructors
+    fn wrap(self) -> Arc<Self> {
+        // Create an Arc, make a Weak from it, then put it into the struct.
+        // It's a little tricky.
+        let fs = Arc::new(self);
+        let weak = Arc::downgrade(&fs);
+        let ptr = Arc::into_raw(fs) as *mut Self;
+        unsafe {
+            (*ptr).self_ptr = weak;
+        }
+        unsafe { Arc::from_raw(ptr) }
+    }
+
+    /// Is it loaded read-only, as it has features not handled?
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn check_writable(&self) -> vfs::Result<()> {
+        match self.read_only {
+            true => Err(FsError::ReadOnlyFs),
+            false => Ok(()),
+        }
+    }
+
+    fn now(&self) -> Option<Timespec> {
+        self.time.map(|time| time.current_time())
+    }
+
+    fn sectors_per_block(&self) -> u32 {
+        (self.block_size / 512) as u32
+    }
+
+    /// Block pointers in an indirect block
+    fn ptrs_per_block(&self) -> usize {
+        self.block_size / 4
+    }
+
+    /// Blocks of content under a tree of indirect blocks of `level`
+    fn span(&self, level: u32) -> usize {
+        self.ptrs_per_block().saturating_pow(level)
+    }
+
+    /// The slot in the block pointers of an INode, and the slots in the indirect blocks below
+    /// it, which lead to block `index` of its content
+    fn block_path(&self, index: usize) -> vfs::Result<(usize, Vec<usize>)> {
+        let per_block = self.ptrs_per_block();
+        if index < NDIR_BLOCKS {
+            return Ok((index, Vec::new()));
+        }
+        let index = index - NDIR_BLOCKS;
+        if index < self.span(1) {
+            return Ok((IND_BLOCK, vec![index]));
+        }
+        let index = index - self.span(1);
+        if index < self.span(2) {
+            return Ok((DIND_BLOCK, vec![index / per_block, index % per_block]));
+        }
+        let index = index - self.span(2);
+        if index < self.span(3) {
+            let path = vec![
+                index / self.span(2),
+                index / per_block % per_block,
+                index % per_block,
+            ];
+            return Ok((TIND_BLOCK, path));
+        }
+        Err(FsError::InvalidParam)
+    }
+
+    fn read_ptr(&self, block: u32, index: usize) -> vfs::Result<u32> {
+        let mut buf = [0u8; 4];
+        self.device
+            .read_exact_at(block as usize * self.block_size + index * 4, &mut buf)?;
+        Ok(u32::from_le_bytes(buf))
     }
 
-    fn open_internal(device: Arc<dyn Device>) -> Result<Arc<Self>, Ext2Error> {
-        let volume = Ext2Volume { inner: device };
-        let fs = Synced::new(volume.clone())?;
-        Ok(Arc::new(Ext2FileSystem { inner: fs, volume }))
+    fn write_ptr(&self, block: u32, index: usize, ptr: u32) -> vfs::Result<()> {
+        self.device.write_exact_at(
+            block as usize * self.block_size + index * 4,
+            &ptr.to_le_bytes(),
+        )
+    }
+
+    /// Free the blocks of the tree of `level` at `*root` but for its first `keep` blocks of
+    /// content, and the indirect blocks left empty, adding them to `freed`
+    fn free_tree(
+        &self,
+        root: &mut u32,
+        level: u32,
+        keep: usize,
+        freed: &mut Vec<u32>,
+    ) -> vfs::Result<()> {
+        if *root == 0 || keep >= self.span(level) {
+            return Ok(());
+        }
+        if level > 0 {
+            let mut buf = vec![0u8; self.block_size];
+            let at = *root as usize * self.block_size;
+            self.device.read_exact_at(at, &mut buf)?;
+            let span = self.span(level - 1);
+            for i in keep / span..self.ptrs_per_block() {
+                let mut ptr = le32(&buf, i * 4);
+                let child_keep = keep.saturating_sub(i * span);
+                self.free_tree(&mut ptr, level - 1, child_keep, freed)?;
+                put32(&mut buf, i * 4, ptr);
+            }
+            if keep > 0 {
+                return self.device.write_exact_at(at, &buf);
+            }
+        }
+        freed.push(*root);
+        *root = 0;
+        Ok(())
+    }
+
+    fn ino_group(&self, ino: u32) -> usize {
+        ((ino - 1) / self.super_block.inodes_per_group) as usize
+    }
+
+    fn group_first_block(&self, group: usize) -> u32 {
+        self.super_block.first_data_block + group as u32 * self.super_block.blocks_per_group
+    }
+
+    /// Type of an entry of INode of `mode`, if entries have types
+    fn file_type(&self, mode: u16) -> Option<u8> {
+        match self.super_block.has_incompat(FEATURE_INCOMPAT_FILETYPE) {
+            true => Some(file_type(mode)),
+            false => None,
+        }
+    }
+
+    /// Set the first clear bit of bitmap `block`, looking in bits `goal..len`, then
+    /// `begin..goal`
+    fn take_bit(
+        &self,
+        block: u32,
+        begin: usize,
+        goal: usize,
+        len: usize,
+    ) -> vfs::Result<Option<usize>> {
+        let mut buf = vec![0u8; (len + 7) / 8];
+        let at = block as usize * self.block_size;
+        self.device.read_exact_at(at, &mut buf)?;
+        let clear = |bit: &usize| buf[bit / 8] & (1 << (bit % 8)) == 0;
+        let found = (goal..len).chain(begin..goal).find(clear);
+        if let Some(bit) = found {
+            let byte = buf[bit / 8] | 1 << (bit % 8);
+            self.device.write_exact_at(at + bit / 8, &[byte])?;
+        }
+        Ok(found)
+    }
+
+    /// Clear bit `bit` of bitmap `block`, false if it is clear already
+    fn clear_bit(&self, block: u32, bit: usize) -> vfs::Result<bool> {
+        let at = block as usize * self.block_size + bit / 8;
+        let mut byte = [0u8];
+        self.device.read_exact_at(at, &mut byte)?;
+        if byte[0] & 1 << (bit % 8) == 0 {
+            return Ok(false);
+        }
+        byte[0] &= !(1 << (bit % 8));
+        self.device.write_exact_at(at, &byte)?;
+        Ok(true)
+    }
+
+    /// Take a free block, the first from `goal` on in its group, or in the groups after it
+    fn alloc_block(&self, goal: u32) -> vfs::Result<u32> {
+        let sb = &self.super_block;
+        let mut alloc = self.alloc.lock();
+        if alloc.super_block.free_blocks_count == 0 {
+            return Err(FsError::NoDeviceSpace);
+        }
+        let goal = match goal {
+            goal if goal >= sb.first_data_block && goal < sb.blocks_count => goal,
+            _ => sb.first_data_block,
+        } - sb.first_data_block;
+        let per_group = sb.blocks_per_group;
+        let start = (goal / per_group) as usize;
+        let count = alloc.groups.len();
+        for i in 0..count {
+            let group = (start + i) % count;
+            if alloc.groups[group].free_blocks_count == 0 {
+                continue;
+            }
+            let from = match i {
+                0 => goal % per_group,
+                _ => 0,
+            };
+            let len = per_group.min(sb.blocks_count - self.group_first_block(group));
+            let bitmap = alloc.groups[group].block_bitmap;
+            if let Some(bit) = self.take_bit(bitmap, 0, from as usize, len as usize)? {
+                alloc.groups[group].free_blocks_count -= 1;
+                alloc.super_block.free_blocks_count -= 1;
+                return Ok(self.group_first_block(group) + bit as u32);
+            }
+        }
+        Err(FsError::NoDeviceSpace)
+    }
+
+    /// Give back `blocks` to the free blocks
+    fn free_blocks(&self, blocks: &[u32]) -> vfs::Result<()> {
+        if blocks.is_empty() {
+            return Ok(());
+        }
+        let sb = &self.super_block;
+        let mut alloc = self.alloc.lock();
+        for &block in blocks {
+            if block < sb.first_data_block || block >= sb.blocks_count {
+                warn!("freeing block {} out of ext2", block);
+                continue;
+            }
+            let group = ((block - sb.first_data_block) / sb.blocks_per_group) as usize;
+            let bit = ((block - sb.first_data_block) % sb.blocks_per_group) as usize;
+            let bitmap = alloc.groups[group].block_bitmap;
+            if !self.clear_bit(bitmap, bit)? {
+                warn!("freeing free block {}", block);
+                continue;
+            }
+            alloc.groups[group].free_blocks_count += 1;
+            alloc.super_block.free_blocks_count += 1;
+        }
+        Ok(())
+    }
+
+    /// Take a free INode, in the group of dir `parent` for a file, in the group with the
+    /// most free INodes for a dir, so that dirs are spread out
+    fn alloc_inode(&self, parent: u32, is_dir: bool) -> vfs::Result<u32> {
+        let sb = &self.super_block;
+        let mut alloc = self.alloc.lock();
+        if alloc.super_block.free_inodes_count == 0 {
+            return Err(FsError::NoDeviceSpace);
+        }
+        let count = alloc.groups.len();
+        let start = match is_dir {
+            true => (0..count)
+                .max_by_key(|&group| {
+                    let desc = &alloc.groups[group];
+                    (desc.free_inodes_count, desc.free_blocks_count)
+                })
+                .unwrap(),
+            false => self.ino_group(parent),
+        };
+        let per_group = sb.inodes_per_group;
+        for i in 0..count {
+            let group = (start + i) % count;
+            if alloc.groups[group].free_inodes_count == 0 {
+                continue;
+            }
+            // the reserved INodes, which are all in group 0
+            let begin = match group {
+                0 => (sb.first_ino - 1) as usize,
+                _ => 0,
+            };
+            let len = per_group.min(sb.inodes_count - group as u32 * per_group) as usize;
+            let bitmap = alloc.groups[group].inode_bitmap;
+            if let Some(bit) = self.take_bit(bitmap, begin, begin, len)? {
+                let desc = &mut alloc.groups[group];
+                desc.free_inodes_count -= 1;
+                if is_dir {
+                    desc.used_dirs_count += 1;
+                }
+                alloc.super_block.free_inodes_count -= 1;
+                return Ok(group as u32 * per_group + bit as u32 + 1);
+            }
+        }
+        Err(FsError::NoDeviceSpace)
+    }
+
+    fn free_inode(&self, ino: u32, is_dir: bool) -> vfs::Result<()> {
+        let per_group = self.super_block.inodes_per_group;
+        let group = self.ino_group(ino);
+        let mut alloc = self.alloc.lock();
+        let bitmap = alloc.groups[group].inode_bitmap;
+        if !self.clear_bit(bitmap, ((ino - 1) % per_group) as usize)? {
+            warn!("freeing free INode {}", ino);
+            return Ok(());
+        }
+        let desc = &mut alloc.groups[group];
+        desc.free_inodes_count += 1;
+        if is_dir {
+            desc.used_dirs_count -= 1;
+        }
+        alloc.super_block.free_inodes_count += 1;
+        Ok(())
+    }
+
+    /// Drop a reference to the xattr block shared by INodes, freed by the last one
+    fn release_xattr_block(&self, block: u32) -> vfs::Result<()> {
+        let mut header = [0u8; 8];
+        let at = block as usize * self.block_size;
+        self.device.read_exact_at(at, &mut header)?;
+        if le32(&header, 0) != XATTR_MAGIC {
+            warn!("bad xattr block {}", block);
+            return Ok(());
+        }
+        match le32(&header, 4) {
+            0 | 1 => self.free_blocks(&[block]),
+            refs => self
+                .device
+                .write_exact_at(at + 4, &(refs - 1).to_le_bytes()),
+        }
+    }
+
+    /// Mark the super block as having files of 2G or more
+    fn set_large_file(&self) {
+        let mut alloc = self.alloc.lock();
+        if !alloc
+            .super_block
+            .has_ro_compat(FEATURE_RO_COMPAT_LARGE_FILE)
+        {
+            alloc.super_block.feature_ro_compat |= FEATURE_RO_COMPAT_LARGE_FILE;
+        }
+    }
+
+    /// Byte offset of INode `ino` on the device
+    fn inode_offset(&self, ino: u32) -> usize {
+        let index = ((ino - 1) % self.super_block.inodes_per_group) as usize;
+        let table = self.inode_tables[self.ino_group(ino)] as usize;
+        table * self.block_size + index * self.super_block.inode_size as usize
+    }
+
+    fn read_inode(&self, ino: u32) -> vfs::Result<DiskINode> {
+        let mut buf = [0u8; GOOD_OLD_INODE_SIZE];
+        self.device
+            .read_exact_at(self.inode_offset(ino), &mut buf)?;
+        Ok(DiskINode::parse(&buf))
+    }
+
+    /// Write INode `ino`, but for the bytes past those known to ext2 in a larger INode
+    fn write_inode(&self, ino: u32, disk: &DiskINode) -> vfs::Result<()> {
+        let mut buf = [0u8; GOOD_OLD_INODE_SIZE];
+        disk.write(&mut buf);
+        self.device.write_exact_at(self.inode_offset(ino), &buf)
+    }
+
+    /// Write new INode `ino` as `disk`, zeroing the rest of a larger INode, and load it
+    fn init_inode(&self, ino: u32, disk: DiskINode) -> vfs::Result<Arc<INodeImpl>> {
+        let at = self.inode_offset(ino);
+        self.device
+            .zero_at(at, self.super_block.inode_size as usize)?;
+        self.write_inode(ino, &disk)?;
+        self.get_inode(ino)
+    }
+
+    /// Get INode `ino`. Load if not in memory.
+    fn get_inode(&self, ino: u32) -> vfs::Result<Arc<INodeImpl>> {
+        if ino == 0 || ino > self.super_block.inodes_count {
+            warn!("bad INode number {}", ino);
+            return Err(FsError::DeviceError);
+        }
+        if let Some(inode) = self.inodes.read().get(&ino).and_then(Weak::upgrade) {
+            return Ok(inode);
+        }
+        let disk = self.read_inode(ino)?;
+        let mut inodes = self.inodes.write();
+        // loaded by another thread meanwhile
+        if let Some(inode) = inodes.get(&ino).and_then(Weak::upgrade) {
+            return Ok(inode);
+        }
+        let inode = Arc::new(INodeImpl {
+            ino,
+            disk_inode: RwLock::new(Dirty::new(disk)),
+            fs: self.self_ptr.upgrade().unwrap(),
+        });
+        inodes.insert(ino, Arc::downgrade(&inode));
+        Ok(inode)
+    }
+
+    /// Write back the super block and the group descriptors, if they are changed.
+    /// Only the first copies are written, as by Linux.
+    fn write_alloc(&self) -> vfs::Result<()> {
+        let mut alloc = self.alloc.lock();
+        if !alloc.dirty() {
+            return Ok(());
+        }
+        if let Some(now) = self.now() {
+            alloc.super_block.wtime = now.sec as u32;
+        }
+        self.device
+            .write_exact_at(SUPER_OFFSET, &alloc.super_block.write())?;
+        let mut buf = vec![0u8; alloc.groups.len() * DESC_SIZE];
+        for (group, raw) in alloc.groups.iter().zip(buf.chunks_exact_mut(DESC_SIZE)) {
+            group.write(raw);
+        }
+        let table = (self.super_block.first_data_block as usize + 1) * self.block_size;
+        self.device.write_exact_at(table, &buf)?;
+        alloc.sync();
+        Ok(())
     }
 }
 
-impl Volume<u8, Size512> for Ext2Volume {
-    type Error = Ext2Error;
+/// Largest size of a file: what the block pointers reach, but that its sectors can be
+/// counted in 32 bits, and 2G in revision 0, which has no high bits of the size
+fn max_len(super_block: &SuperBlock) -> u64 {
+    if super_block.rev_level == 0 {
+        return i32::max_value() as u64;
+    }
+    let block_size = super_block.block_size() as u64;
+    let per_block = block_size / 4;
+    let tree = NDIR_BLOCKS as u64 + per_block + per_block.pow(2) + per_block.pow(3);
+    // less the indirect blocks of a whole tree
+    let indirect = 3 + 2 * per_block + per_block.pow(2);
+    let counted = u32::max_value() as u64 * 512 / block_size - indirect;
+    let max = tree.min(counted) * block_size;
+    max.min(usize::max_value() as u64)
+}
 
-    fn size(&self) -> Size<Size512> {
-        Size::Unbounded
+impl vfs::FileSystem for Ext2FileSystem {
+    fn sync(&self) -> vfs::Result<()> {
+        let inodes: Vec<_> = self
+            .inodes
+            .read()
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect();
+        for inode in inodes {
+            inode.sync_all()?;
+        }
+        self.write_alloc()?;
+        self.device.sync()?;
+        self.device.flush()?;
+        Ok(())
     }
 
-    fn commit(&mut self, _slice: Option<VolumeCommit<u8, Size512>>) -> Result<(), Self::Error> {
-        unimplemented!()
+    fn root_inode(&self) -> Arc<dyn vfs::INode> {
+        self.get_inode(ROOT_INO)
+            .expect("failed to load the root dir")
     }
 
-    unsafe fn slice_unchecked<'a>(
-        &'a self,
-        range: Range<Address<Size512>>,
-    ) -> VolumeSlice<'a, u8, Size512> {
-        let index = range.start;
-        let len = range.end - range.start;
-        let mut vec = vec![0; len.into_index() as usize];
-        self.inner
-            .read_at(index.into_index() as usize, vec.as_mut_slice())
-            .unwrap();
-        VolumeSlice::new_owned(vec, index)
+    fn info(&self) -> vfs::FsInfo {
+        let alloc = self.alloc.lock();
+        let sb = &alloc.super_block;
+        vfs::FsInfo {
+            bsize: self.block_size,
+            frsize: self.block_size,
+            blocks: sb.blocks_count as usize,
+            bfree: sb.free_blocks_count as usize,
+            bavail: sb.free_blocks_count.saturating_sub(sb.r_blocks_count) as usize,
+            files: sb.inodes_count as usize,
+            ffree: sb.free_inodes_count as usize,
+            namemax: MAX_NAME_LEN,
+        }
     }
 
-    fn slice<'a>(
-        &'a self,
-        range: Range<Address<Size512>>,
-    ) -> Result<VolumeSlice<'a, u8, Size512>, Self::Error> {
-        let index = range.start;
-        let len = range.end - range.start;
-        let mut vec = vec![0; len.into_index() as usize];
-        self.inner
-            .read_at(index.into_index() as usize, vec.as_mut_slice())?;
-        Ok(VolumeSlice::new_owned(vec, index))
+    fn capabilities(&self) -> vfs::FsCapabilities {
+        vfs::FsCapabilities {
+            features: vfs::FsFeatures::SYMLINK
+                | vfs::FsFeatures::HARDLINK
+                | vfs::FsFeatures::SPARSE
+                | vfs::FsFeatures::CASE_SENSITIVE,
+            namemax: MAX_NAME_LEN,
+        }
+    }
+}
+
+impl Drop for Ext2FileSystem {
+    /// Auto sync when drop
+    fn drop(&mut self) {
+        self.write_alloc()
+            .expect("failed to write back the super block of ext2");
     }
 }
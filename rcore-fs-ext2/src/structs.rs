@@ -0,0 +1,350 @@
+//! On-disk structures of ext2, all little-endian
+//!
+//! Ref: [https://www.nongnu.org/ext2-doc/ext2.html]
+use alloc::vec::Vec;
+
+/// Byte offset of the super block, whatever the block size
+pub const SUPER_OFFSET: usize = 1024;
+pub const SUPER_SIZE: usize = 1024;
+pub const MAGIC: u16 = 0xef53;
+/// Size of a group descriptor, without the 64bit feature of ext4
+pub const DESC_SIZE: usize = 32;
+/// Bytes of an inode known to ext2, kept at the start of larger ones
+pub const GOOD_OLD_INODE_SIZE: usize = 128;
+/// First INode which is not reserved, in revision 0
+pub const GOOD_OLD_FIRST_INO: u32 = 11;
+pub const ROOT_INO: u32 = 2;
+pub const MAX_NAME_LEN: usize = 255;
+/// Most hard links to an INode, as by Linux
+pub const LINK_MAX: u16 = 32000;
+
+/// Block pointers in an INode: direct, then single, double and triple indirect
+pub const N_BLOCKS: usize = 15;
+pub const NDIR_BLOCKS: usize = 12;
+pub const IND_BLOCK: usize = 12;
+pub const DIND_BLOCK: usize = 13;
+pub const TIND_BLOCK: usize = 14;
+/// Bytes of the block pointers, where short symlinks are kept instead
+pub const FAST_SYMLINK_SIZE: usize = N_BLOCKS * 4;
+
+pub const FEATURE_COMPAT_DIR_INDEX: u32 = 0x0020;
+pub const FEATURE_INCOMPAT_FILETYPE: u32 = 0x0002;
+/// Known but not handled: the journal must be replayed first
+pub const FEATURE_INCOMPAT_RECOVER: u32 = 0x0004;
+pub const FEATURE_RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+pub const FEATURE_RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+/// Never used, so it only has to be kept
+pub const FEATURE_RO_COMPAT_BTREE_DIR: u32 = 0x0004;
+pub const SUPPORTED_INCOMPAT: u32 = FEATURE_INCOMPAT_FILETYPE;
+pub const SUPPORTED_RO_COMPAT: u32 =
+    FEATURE_RO_COMPAT_SPARSE_SUPER | FEATURE_RO_COMPAT_LARGE_FILE | FEATURE_RO_COMPAT_BTREE_DIR;
+
+/// Flag of a dir with a hashed index, which is dropped once it is changed
+pub const INDEX_FL: u32 = 0x1000;
+
+pub const S_IFMT: u16 = 0o170000;
+pub const S_IFSOCK: u16 = 0o140000;
+pub const S_IFLNK: u16 = 0o120000;
+pub const S_IFREG: u16 = 0o100000;
+pub const S_IFBLK: u16 = 0o060000;
+pub const S_IFDIR: u16 = 0o040000;
+pub const S_IFCHR: u16 = 0o020000;
+pub const S_IFIFO: u16 = 0o010000;
+
+/// Types in dir entries, with the filetype feature
+pub const FT_UNKNOWN: u8 = 0;
+pub const FT_REG_FILE: u8 = 1;
+pub const FT_DIR: u8 = 2;
+pub const FT_CHRDEV: u8 = 3;
+pub const FT_BLKDEV: u8 = 4;
+pub const FT_FIFO: u8 = 5;
+pub const FT_SOCK: u8 = 6;
+pub const FT_SYMLINK: u8 = 7;
+
+/// The super block, of which only the fields used are parsed.
+/// The rest is kept as read, to be written back.
+#[derive(Debug, Clone)]
+pub struct SuperBlock {
+    pub inodes_count: u32,
+    pub blocks_count: u32,
+    pub r_blocks_count: u32,
+    pub free_blocks_count: u32,
+    pub free_inodes_count: u32,
+    pub first_data_block: u32,
+    pub log_block_size: u32,
+    pub blocks_per_group: u32,
+    pub inodes_per_group: u32,
+    pub wtime: u32,
+    pub magic: u16,
+    pub rev_level: u32,
+    pub first_ino: u32,
+    pub inode_size: u16,
+    pub feature_compat: u32,
+    pub feature_incompat: u32,
+    pub feature_ro_compat: u32,
+    raw: Vec<u8>,
+}
+
+impl SuperBlock {
+    pub fn parse(buf: &[u8]) -> Self {
+        let rev_level = le32(buf, 76);
+        let (first_ino, inode_size) = match rev_level {
+            0 => (GOOD_OLD_FIRST_INO, GOOD_OLD_INODE_SIZE as u16),
+            _ => (le32(buf, 84), le16(buf, 88)),
+        };
+        SuperBlock {
+            inodes_count: le32(buf, 0),
+            blocks_count: le32(buf, 4),
+            r_blocks_count: le32(buf, 8),
+            free_blocks_count: le32(buf, 12),
+            free_inodes_count: le32(buf, 16),
+            first_data_block: le32(buf, 20),
+            log_block_size: le32(buf, 24),
+            blocks_per_group: le32(buf, 32),
+            inodes_per_group: le32(buf, 40),
+            wtime: le32(buf, 48),
+            magic: le16(buf, 56),
+            rev_level,
+            first_ino,
+            inode_size,
+            feature_compat: le32(buf, 92),
+            feature_incompat: le32(buf, 96),
+            feature_ro_compat: le32(buf, 100),
+            raw: buf[..SUPER_SIZE].to_vec(),
+        }
+    }
+
+    /// The block as read, with the fields changed since
+    pub fn write(&self) -> Vec<u8> {
+        let mut buf = self.raw.clone();
+        put32(&mut buf, 12, self.free_blocks_count);
+        put32(&mut buf, 16, self.free_inodes_count);
+        put32(&mut buf, 48, self.wtime);
+        if self.rev_level > 0 {
+            put32(&mut buf, 100, self.feature_ro_compat);
+        }
+        buf
+    }
+
+    /// Check that it is a super block ext2 can handle
+    pub fn check(&self) -> bool {
+        self.magic == MAGIC
+            && self.log_block_size <= 6
+            && self.blocks_per_group != 0
+            && self.blocks_per_group as usize <= self.block_size() * 8
+            && self.inodes_per_group != 0
+            && self.inodes_per_group as usize <= self.block_size() * 8
+            && self.inode_size as usize >= GOOD_OLD_INODE_SIZE
+            && self.inode_size.is_power_of_two()
+            && self.inode_size as usize <= self.block_size()
+            && self.first_data_block < self.blocks_count
+            && self.first_ino > ROOT_INO
+            && self.groups() as u64 * self.inodes_per_group as u64 >= self.inodes_count as u64
+    }
+
+    pub fn block_size(&self) -> usize {
+        1024 << self.log_block_size
+    }
+
+    pub fn groups(&self) -> usize {
+        let blocks = (self.blocks_count - self.first_data_block) as usize;
+        let per_group = self.blocks_per_group as usize;
+        (blocks + per_group - 1) / per_group
+    }
+
+    pub fn has_ro_compat(&self, feature: u32) -> bool {
+        self.feature_ro_compat & feature != 0
+    }
+
+    pub fn has_incompat(&self, feature: u32) -> bool {
+        self.feature_incompat & feature != 0
+    }
+}
+
+/// Descriptor of a block group, of which only the fields used are parsed
+#[derive(Debug, Clone)]
+pub struct GroupDesc {
+    pub block_bitmap: u32,
+    pub inode_bitmap: u32,
+    pub inode_table: u32,
+    pub free_blocks_count: u16,
+    pub free_inodes_count: u16,
+    pub used_dirs_count: u16,
+    /// Padding and reserved fields, as read
+    rest: [u8; 14],
+}
+
+impl GroupDesc {
+    pub fn parse(buf: &[u8]) -> Self {
+        let mut rest = [0u8; 14];
+        rest.copy_from_slice(&buf[18..DESC_SIZE]);
+        GroupDesc {
+            block_bitmap: le32(buf, 0),
+            inode_bitmap: le32(buf, 4),
+            inode_table: le32(buf, 8),
+            free_blocks_count: le16(buf, 12),
+            free_inodes_count: le16(buf, 14),
+            used_dirs_count: le16(buf, 16),
+            rest,
+        }
+    }
+
+    pub fn write(&self, buf: &mut [u8]) {
+        put32(buf, 0, self.block_bitmap);
+        put32(buf, 4, self.inode_bitmap);
+        put32(buf, 8, self.inode_table);
+        put16(buf, 12, self.free_blocks_count);
+        put16(buf, 14, self.free_inodes_count);
+        put16(buf, 16, self.used_dirs_count);
+        buf[18..DESC_SIZE].copy_from_slice(&self.rest);
+    }
+}
+
+/// The first 128 bytes of an INode, all that ext2 knows of
+#[derive(Debug, Clone, Default)]
+pub struct DiskINode {
+    pub mode: u16,
+    pub uid: u32,
+    /// Low 32 bits of the size
+    pub size: u32,
+    pub atime: u32,
+    pub ctime: u32,
+    pub mtime: u32,
+    pub dtime: u32,
+    pub gid: u32,
+    pub links_count: u16,
+    /// Number of 512-byte sectors taken, with indirect blocks and the xattr block
+    pub blocks: u32,
+    pub flags: u32,
+    pub osd1: u32,
+    pub block: [u32; N_BLOCKS],
+    pub generation: u32,
+    pub file_acl: u32,
+    /// High 32 bits of the size of a file, the ACL of a dir
+    pub size_high: u32,
+    pub faddr: u32,
+    /// With the high 16 bits of the uid and the gid, at 4 and 6
+    pub osd2: [u8; 12],
+}
+
+impl DiskINode {
+    pub fn parse(buf: &[u8]) -> Self {
+        let mut block = [0u32; N_BLOCKS];
+        for (i, ptr) in block.iter_mut().enumerate() {
+            *ptr = le32(buf, 40 + i * 4);
+        }
+        let mut osd2 = [0u8; 12];
+        osd2.copy_from_slice(&buf[116..128]);
+        DiskINode {
+            mode: le16(buf, 0),
+            uid: le16(buf, 2) as u32 | (le16(buf, 120) as u32) << 16,
+            size: le32(buf, 4),
+            atime: le32(buf, 8),
+            ctime: le32(buf, 12),
+            mtime: le32(buf, 16),
+            dtime: le32(buf, 20),
+            gid: le16(buf, 24) as u32 | (le16(buf, 122) as u32) << 16,
+            links_count: le16(buf, 26),
+            blocks: le32(buf, 28),
+            flags: le32(buf, 32),
+            osd1: le32(buf, 36),
+            block,
+            generation: le32(buf, 100),
+            file_acl: le32(buf, 104),
+            size_high: le32(buf, 108),
+            faddr: le32(buf, 112),
+            osd2,
+        }
+    }
+
+    pub fn write(&self, buf: &mut [u8]) {
+        put16(buf, 0, self.mode);
+        put16(buf, 2, self.uid as u16);
+        put32(buf, 4, self.size);
+        put32(buf, 8, self.atime);
+        put32(buf, 12, self.ctime);
+        put32(buf, 16, self.mtime);
+        put32(buf, 20, self.dtime);
+        put16(buf, 24, self.gid as u16);
+        put16(buf, 26, self.links_count);
+        put32(buf, 28, self.blocks);
+        put32(buf, 32, self.flags);
+        put32(buf, 36, self.osd1);
+        for (i, &ptr) in self.block.iter().enumerate() {
+            put32(buf, 40 + i * 4, ptr);
+        }
+        put32(buf, 100, self.generation);
+        put32(buf, 104, self.file_acl);
+        put32(buf, 108, self.size_high);
+        put32(buf, 112, self.faddr);
+        buf[116..128].copy_from_slice(&self.osd2);
+        put16(buf, 120, (self.uid >> 16) as u16);
+        put16(buf, 122, (self.gid >> 16) as u16);
+    }
+
+    pub fn is(&self, type_: u16) -> bool {
+        self.mode & S_IFMT == type_
+    }
+
+    /// Size in bytes, with the high bits for all but dirs
+    pub fn file_size(&self) -> u64 {
+        match self.is(S_IFDIR) {
+            true => self.size as u64,
+            false => self.size as u64 | (self.size_high as u64) << 32,
+        }
+    }
+
+    pub fn set_file_size(&mut self, len: u64) {
+        self.size = len as u32;
+        if !self.is(S_IFDIR) {
+            self.size_high = (len >> 32) as u32;
+        }
+    }
+
+    /// Its block pointers as bytes, the target of a short symlink
+    pub fn block_bytes(&self) -> [u8; FAST_SYMLINK_SIZE] {
+        let mut buf = [0u8; FAST_SYMLINK_SIZE];
+        for (i, &ptr) in self.block.iter().enumerate() {
+            put32(&mut buf, i * 4, ptr);
+        }
+        buf
+    }
+
+    pub fn set_block_bytes(&mut self, buf: &[u8; FAST_SYMLINK_SIZE]) {
+        for (i, ptr) in self.block.iter_mut().enumerate() {
+            *ptr = le32(buf, i * 4);
+        }
+    }
+}
+
+/// Type of a dir entry for an INode of `mode`
+pub fn file_type(mode: u16) -> u8 {
+    match mode & S_IFMT {
+        S_IFREG => FT_REG_FILE,
+        S_IFDIR => FT_DIR,
+        S_IFCHR => FT_CHRDEV,
+        S_IFBLK => FT_BLKDEV,
+        S_IFIFO => FT_FIFO,
+        S_IFSOCK => FT_SOCK,
+        S_IFLNK => FT_SYMLINK,
+        _ => FT_UNKNOWN,
+    }
+}
+
+pub fn le16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+pub fn le32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+pub fn put16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+pub fn put32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
@@ -1,21 +1,216 @@
 extern crate std;
 
 use crate::*;
-use std::fs::{self, OpenOptions};
-use std::sync::Arc;
-use std::sync::Mutex;
+use rcore_fs::dev::std_impl::StdTimeProvider;
+use rcore_fs::vfs::{FileSystem, FileType, Result};
+use std::fs::File;
+use std::io;
+use std::sync::{Arc, Mutex};
 
-fn open_sample_file() -> Arc<Ext2FileSystem> {
-    fs::copy("ext2.img", "test.img").expect("failed to open ext2.img");
-    let file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open("test.img")
-        .expect("failed to open test.img");
-    Ext2FileSystem::open(Arc::new(Mutex::new(file))).expect("failed to open Ext2")
+/// ext2.img is made by `mke2fs`, with 1K blocks, "lost+found" and "home"
+fn open_sample_file() -> (Arc<Mutex<File>>, Arc<Ext2FileSystem>) {
+    let mut image = File::open("ext2.img").expect("failed to open ext2.img");
+    let mut file = tempfile::tempfile().expect("failed to create file");
+    io::copy(&mut image, &mut file).expect("failed to copy ext2.img");
+    let file = Arc::new(Mutex::new(file));
+    let fs = Ext2FileSystem::open_with_time(file.clone(), &StdTimeProvider)
+        .expect("failed to open Ext2");
+    (file, fs)
+}
+
+fn _reopen(file: Arc<Mutex<File>>, fs: Arc<Ext2FileSystem>) -> Arc<Ext2FileSystem> {
+    fs.sync().expect("failed to sync Ext2");
+    drop(fs);
+    Ext2FileSystem::open(file).expect("failed to open Ext2")
+}
+
+#[test]
+fn test_open() -> Result<()> {
+    let (_, fs) = open_sample_file();
+    let root = fs.root_inode();
+    assert_eq!(root.list()?, vec![".", "..", "lost+found", "home"]);
+    assert_eq!(root.metadata()?.nlinks, 4);
+    assert!(root.find("..")?.is_same(&*root)?);
+    assert_eq!(root.find("home")?.metadata()?.uid, 1000);
+    assert_eq!(fs.info().bsize, 1024);
+    Ok(())
+}
+
+#[test]
+fn write_read_and_reopen() -> Result<()> {
+    let (file, fs) = open_sample_file();
+    let free = fs.info();
+    let root = fs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o755)?;
+    let file1 = dir.create("file1", FileType::File, 0o644)?;
+    let data: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+    assert_eq!(file1.write_at(0, &data)?, data.len());
+    assert_eq!(fs.info().ffree, free.ffree - 2);
+    // 10 blocks of data, 1 of the dir
+    assert_eq!(fs.info().bfree, free.bfree - 11);
+    drop((root, dir, file1));
+
+    let fs = _reopen(file, fs);
+    let root = fs.root_inode();
+    assert_eq!(root.metadata()?.nlinks, 5);
+    let dir = root.find("dir")?;
+    assert_eq!(dir.list()?, vec![".", "..", "file1"]);
+    assert!(dir.find("..")?.is_same(&*root)?);
+    let file1 = root.lookup("dir/file1")?;
+    let metadata = file1.metadata()?;
+    assert_eq!(
+        (metadata.size, metadata.mode, metadata.nlinks),
+        (10000, 0o644, 1)
+    );
+    let mut buf = vec![0u8; data.len() + 10];
+    assert_eq!(file1.read_at(0, &mut buf)?, data.len());
+    assert_eq!(&buf[..data.len()], &data[..]);
+    assert_eq!(fs.info().bfree, free.bfree - 11);
+    Ok(())
+}
+
+#[test]
+fn large_file_with_indirect_blocks() -> Result<()> {
+    let (file, fs) = open_sample_file();
+    let free = fs.info().bfree;
+    let file1 = fs.root_inode().create("file1", FileType::File, 0o644)?;
+    file1.write_at(0, &[1; 3000])?;
+    // past the single indirect block, so through the double one
+    let far = 1 << 20;
+    file1.write_at(far, &[2; 3000])?;
+    // 3 blocks of data at each end, the double indirect block and one under it
+    assert_eq!(fs.info().bfree, free - 8);
+    assert_eq!(file1.metadata()?.blocks, 8);
+    drop(file1);
+
+    let fs = _reopen(file, fs);
+    let file1 = fs.root_inode().find("file1")?;
+    assert_eq!(file1.metadata()?.size, far + 3000);
+    let mut buf = vec![0xaau8; 6000];
+    file1.read_at(far - 3000, &mut buf)?;
+    assert!(buf[..3000].iter().all(|&b| b == 0), "a hole");
+    assert!(buf[3000..].iter().all(|&b| b == 2));
+    file1.resize(1000)?;
+    assert_eq!(fs.info().bfree, free - 1);
+    file1.resize(5000)?;
+    file1.read_at(0, &mut buf[..5000])?;
+    assert!(buf[..1000].iter().all(|&b| b == 1));
+    assert!(buf[1000..5000].iter().all(|&b| b == 0));
+    fs.root_inode().unlink("file1")?;
+    // still open
+    assert_eq!(fs.info().bfree, free - 1);
+    drop(file1);
+    assert_eq!(fs.info().bfree, free);
+    Ok(())
+}
+
+#[test]
+fn symlinks_and_devices() -> Result<()> {
+    let (file, fs) = open_sample_file();
+    let root = fs.root_inode();
+    let short = "home/short/target";
+    let long: String = "a/long/target".repeat(10);
+    root.create("short", FileType::SymLink, 0o777)?
+        .write_at(0, short.as_bytes())?;
+    root.create("long", FileType::SymLink, 0o777)?
+        .write_at(0, long.as_bytes())?;
+    root.create_device("null", FileType::CharDevice, 0o666, 1, 3)?;
+    root.create_device("big", FileType::BlockDevice, 0o660, 259, 70000)?;
+    drop(root);
+
+    let fs = _reopen(file, fs);
+    let root = fs.root_inode();
+    let mut buf = [0u8; 200];
+    let link = root.find("short")?;
+    assert_eq!(link.metadata()?.blocks, 0, "kept in the INode");
+    assert_eq!(link.read_at(0, &mut buf)?, short.len());
+    assert_eq!(&buf[..short.len()], short.as_bytes());
+    let link = root.find("long")?;
+    assert_eq!(link.metadata()?.type_, FileType::SymLink);
+    assert_eq!(link.read_at(0, &mut buf)?, long.len());
+    assert_eq!(&buf[..long.len()], long.as_bytes());
+    let null = root.find("null")?.metadata()?;
+    assert_eq!(null.type_, FileType::CharDevice);
+    assert_eq!(null.rdev, vfs::make_rdev(1, 3));
+    let big = root.find("big")?.metadata()?;
+    assert_eq!(big.rdev, vfs::make_rdev(259, 70000));
+    Ok(())
+}
+
+#[test]
+fn link_unlink_and_move() -> Result<()> {
+    let (file, fs) = open_sample_file();
+    let free = fs.info();
+    let root = fs.root_inode();
+    let dir1 = root.create("dir1", FileType::Dir, 0o755)?;
+    let dir2 = root.create("dir2", FileType::Dir, 0o755)?;
+    let sub = dir1.create("sub", FileType::Dir, 0o755)?;
+    let file1 = sub.create("file", FileType::File, 0o644)?;
+    file1.write_at(0, b"hello")?;
+    dir2.link("hard", &file1)?;
+    assert_eq!(file1.metadata()?.nlinks, 2);
+    assert_eq!(root.unlink("dir1"), Err(FsError::DirNotEmpty));
+    assert_eq!(
+        dir1.move_("sub", &sub, "sub2"),
+        Err(FsError::InvalidParam),
+        "moved into itself"
+    );
+    dir1.move_("sub", &dir2, "moved")?;
+    assert!(sub.find("..")?.is_same(&*dir2)?);
+    assert_eq!(dir1.metadata()?.nlinks, 2);
+    assert_eq!(dir2.metadata()?.nlinks, 3);
+    root.unlink("dir1")?;
+    assert_eq!(dir1.get_entry(0), Err(FsError::DirRemoved));
+    // renamed over another file
+    dir2.create("other", FileType::File, 0o644)?;
+    dir2.move_("hard", &dir2, "other")?;
+    sub.unlink("file")?;
+    assert_eq!(file1.metadata()?.nlinks, 1);
+    drop((root, dir1, dir2, sub, file1));
+    // "dir2", "moved" and "file" are left
+    assert_eq!(fs.info().ffree, free.ffree - 3);
+
+    let fs = _reopen(file, fs);
+    let root = fs.root_inode();
+    assert_eq!(root.list()?, vec![".", "..", "lost+found", "home", "dir2"]);
+    let dir2 = root.find("dir2")?;
+    assert_eq!(dir2.list()?, vec![".", "..", "moved", "other"]);
+    let mut buf = [0u8; 5];
+    root.lookup("dir2/other")?.read_at(0, &mut buf)?;
+    assert_eq!(&buf, b"hello");
+    for name in ["other", "moved"].iter() {
+        dir2.unlink(name)?;
+    }
+    root.unlink("dir2")?;
+    drop((root, dir2));
+    assert_eq!(fs.info().ffree, free.ffree);
+    assert_eq!(fs.info().bfree, free.bfree);
+    Ok(())
 }
 
 #[test]
-fn test_open() {
-    let _ = open_sample_file();
+fn grow_dirs() -> Result<()> {
+    let (file, fs) = open_sample_file();
+    let dir = fs.root_inode().create("dir", FileType::Dir, 0o755)?;
+    for i in 0..200 {
+        dir.create(
+            &format!("a file with a long name {}", i),
+            FileType::File,
+            0o644,
+        )?;
+    }
+    for i in (0..200).step_by(2) {
+        dir.unlink(&format!("a file with a long name {}", i))?;
+    }
+    assert!(dir.metadata()?.size > 1024);
+    drop(dir);
+    let fs = _reopen(file, fs);
+    let dir = fs.root_inode().find("dir")?;
+    assert_eq!(dir.list()?.len(), 102);
+    dir.find("a file with a long name 199")?;
+    assert_eq!(
+        dir.find("a file with a long name 198").err(),
+        Some(FsError::EntryNotFound)
+    );
+    Ok(())
 }
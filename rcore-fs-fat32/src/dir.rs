@@ -0,0 +1,186 @@
+//! Names in directories, each in a short (8.3) entry, after the long entries of its long name
+use alloc::{format, string::String, vec, vec::Vec};
+
+use crate::structs::*;
+
+/// An entry of a directory, with the long entries before it
+pub struct Dirent {
+    /// Long name, or the short name if there is none
+    pub name: String,
+    pub entry: DirEntry,
+    /// offset in the dir of the 1st of its entries, long or short
+    pub begin: usize,
+    /// offset in the dir of the short entry
+    pub offset: usize,
+}
+
+impl Dirent {
+    /// Is it `name`, whose case does not count?
+    pub fn is(&self, name: &str) -> bool {
+        same_name(&self.name, name) || same_name(&self.entry.short_name(), name)
+    }
+}
+
+/// A long name being read, from its last part, which comes first
+struct LongName {
+    begin: usize,
+    checksum: u8,
+    /// the number of the part expected next, 0 once all are read
+    next: usize,
+    parts: Vec<Vec<u16>>,
+}
+
+/// The entries of dir content `buf`, but for the volume label, "." and ".."
+pub fn parse(buf: &[u8]) -> Vec<Dirent> {
+    let mut dirents = Vec::new();
+    let mut long: Option<LongName> = None;
+    for (i, raw) in buf.chunks_exact(DIRENT_SIZE).enumerate() {
+        let offset = i * DIRENT_SIZE;
+        match raw[0] {
+            ENTRY_END => break,
+            ENTRY_FREE => {
+                long = None;
+                continue;
+            }
+            _ => {}
+        }
+        if raw[11] & 0x3f == ATTR_LONG_NAME {
+            let ord = (raw[0] & !LFN_LAST) as usize;
+            let chars = long_entry_chars(raw);
+            long = match long.take() {
+                _ if ord == 0 || ord * LFN_CHARS > MAX_NAME_LEN + LFN_CHARS => None,
+                _ if raw[0] & LFN_LAST != 0 => {
+                    let mut parts = vec![Vec::new(); ord];
+                    parts[ord - 1] = chars;
+                    Some(LongName {
+                        begin: offset,
+                        checksum: raw[13],
+                        next: ord - 1,
+                        parts,
+                    })
+                }
+                Some(mut name) if name.next == ord && name.checksum == raw[13] => {
+                    name.parts[ord - 1] = chars;
+                    name.next -= 1;
+                    Some(name)
+                }
+                // an orphan, left by a system without long names
+                _ => None,
+            };
+            continue;
+        }
+        let entry = DirEntry::parse(raw);
+        let long = long.take();
+        if entry.attr & ATTR_VOLUME_ID != 0 || entry.name[0] == b'.' {
+            continue;
+        }
+        let (name, begin) = match long {
+            Some(long) if long.next == 0 && long.checksum == entry.checksum() => {
+                let chars: Vec<u16> = long.parts.concat();
+                (String::from_utf16_lossy(&chars), long.begin)
+            }
+            _ => (entry.short_name(), offset),
+        };
+        dirents.push(Dirent {
+            name,
+            entry,
+            begin,
+            offset,
+        });
+    }
+    dirents
+}
+
+/// Are names `a` and `b` the same, as FAT does not tell their case apart?
+pub fn same_name(a: &str, b: &str) -> bool {
+    a.to_uppercase() == b.to_uppercase()
+}
+
+/// Can `name` be the name of an entry?
+pub fn valid_name(name: &str) -> bool {
+    name != "."
+        && name != ".."
+        && !name.is_empty()
+        && name.encode_utf16().count() <= MAX_NAME_LEN
+        // dropped by Windows, so the name would change
+        && !name.ends_with(' ')
+        && !name.ends_with('.')
+        && name
+            .chars()
+            .all(|c| c >= ' ' && !"\"*/:<>?\\|\x7f".contains(c))
+}
+
+/// Characters of a short name besides letters and digits
+const SHORT_SPECIAL: &[u8] = b"$%'-_@~`!(){}^#&";
+
+fn short_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || SHORT_SPECIAL.contains(&c)
+}
+
+/// The short name of `name` and the NTRES flags of its case, if it is a valid 8.3 name
+/// with one case in the base and one in the extension
+pub fn short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+    let fits = |part: &str, max: usize| part.len() <= max && part.bytes().all(short_char);
+    if base.is_empty() || !fits(base, 8) || !fits(ext, 3) {
+        return None;
+    }
+    let lower = |part: &str| part.bytes().any(|c| c.is_ascii_lowercase());
+    let upper = |part: &str| part.bytes().any(|c| c.is_ascii_uppercase());
+    if lower(base) && upper(base) || lower(ext) && upper(ext) {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    for (i, c) in base.bytes().enumerate() {
+        short[i] = c.to_ascii_uppercase();
+    }
+    for (i, c) in ext.bytes().enumerate() {
+        short[8 + i] = c.to_ascii_uppercase();
+    }
+    let mut ntres = 0;
+    if lower(base) {
+        ntres |= NTRES_LOWER_BASE;
+    }
+    if lower(ext) {
+        ntres |= NTRES_LOWER_EXT;
+    }
+    Some((short, ntres))
+}
+
+/// A short name for long name `name` not among `taken`, as "BASE~N.EXT"
+pub fn unique_short_name(name: &str, taken: &[[u8; 11]]) -> [u8; 11] {
+    let fold = |part: &str| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| match c.is_ascii() && short_char(c as u8) {
+                true => c.to_ascii_uppercase() as u8,
+                false => b'_',
+            })
+            .collect()
+    };
+    let name = name.trim_start_matches('.');
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) => (fold(&name[..dot]), fold(&name[dot + 1..])),
+        None => (fold(name), Vec::new()),
+    };
+    let mut short = [b' '; 11];
+    for (i, &c) in ext.iter().take(3).enumerate() {
+        short[8 + i] = c;
+    }
+    for n in 1.. {
+        let tail = format!("~{}", n);
+        let len = base.len().min(8 - tail.len());
+        for byte in short[..8].iter_mut() {
+            *byte = b' ';
+        }
+        short[..len].copy_from_slice(&base[..len]);
+        short[len..len + tail.len()].copy_from_slice(tail.as_bytes());
+        if !taken.contains(&short) {
+            break;
+        }
+    }
+    short
+}
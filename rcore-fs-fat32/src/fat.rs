@@ -0,0 +1,273 @@
+//! The file allocation table, chaining the clusters of each file
+//!
+//! Entries are read and written on the device, in all copies of the table, so that the table
+//! is never kept but in the block cache under it. Only the number of free clusters and where
+//! to look for the next are kept, and written to the FSInfo of FAT32 on sync.
+use alloc::{vec, vec::Vec};
+use spin::Mutex;
+
+use rcore_fs::dev::Device;
+use rcore_fs::vfs::{FsError, Result};
+
+use crate::structs::*;
+use crate::DeviceExt;
+
+/// Entries read at once when scanning the table
+const SCAN_ENTRIES: usize = 1024;
+
+pub struct Fat {
+    pub type_: FatType,
+    /// byte offset of the 1st copy
+    offset: usize,
+    /// bytes of a copy
+    size: usize,
+    copies: usize,
+    /// number of data clusters, numbered from `FIRST_CLUSTER`
+    clusters: u32,
+    free: Mutex<FreeInfo>,
+}
+
+struct FreeInfo {
+    /// number of free clusters
+    count: u32,
+    /// cluster to look for a free one from
+    next: u32,
+}
+
+/// An entry of the table
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Entry {
+    Free,
+    Next(u32),
+    End,
+    Bad,
+}
+
+impl Fat {
+    pub fn new(bs: &BootSector, type_: FatType) -> Self {
+        Fat {
+            type_,
+            offset: bs.reserved_sectors as usize * bs.bytes_per_sector as usize,
+            size: bs.fat_size as usize * bs.bytes_per_sector as usize,
+            copies: bs.fats as usize,
+            clusters: bs.clusters(),
+            free: Mutex::new(FreeInfo {
+                count: 0,
+                next: FIRST_CLUSTER,
+            }),
+        }
+    }
+
+    fn entry_size(&self) -> usize {
+        match self.type_ {
+            FatType::Fat16 => 2,
+            FatType::Fat32 => 4,
+        }
+    }
+
+    /// Is `cluster` a data cluster?
+    pub fn valid(&self, cluster: u32) -> bool {
+        cluster >= FIRST_CLUSTER && cluster < FIRST_CLUSTER + self.clusters
+    }
+
+    pub fn clusters(&self) -> u32 {
+        self.clusters
+    }
+
+    fn decode(&self, raw: u32) -> Entry {
+        let (raw, bad) = match self.type_ {
+            FatType::Fat16 => (raw & 0xffff, 0xfff7),
+            FatType::Fat32 => (raw & 0x0fff_ffff, 0x0fff_fff7),
+        };
+        match raw {
+            0 => Entry::Free,
+            n if n == bad => Entry::Bad,
+            n if n > bad => Entry::End,
+            n => Entry::Next(n),
+        }
+    }
+
+    fn encode(&self, entry: Entry) -> u32 {
+        let end = match self.type_ {
+            FatType::Fat16 => 0xffff,
+            FatType::Fat32 => 0x0fff_ffff,
+        };
+        match entry {
+            Entry::Free => 0,
+            Entry::Next(n) => n,
+            Entry::End => end,
+            Entry::Bad => end - 8,
+        }
+    }
+
+    /// Read entries from that of `cluster` into `entries`
+    fn read_entries(&self, device: &dyn Device, cluster: u32, entries: &mut [u32]) -> Result<()> {
+        let size = self.entry_size();
+        let mut buf = vec![0u8; entries.len() * size];
+        device.read_exact_at(self.offset + cluster as usize * size, &mut buf)?;
+        for (i, entry) in entries.iter_mut().enumerate() {
+            *entry = match self.type_ {
+                FatType::Fat16 => le16(&buf, i * size) as u32,
+                FatType::Fat32 => le32(&buf, i * size),
+            };
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, device: &dyn Device, cluster: u32) -> Result<Entry> {
+        let mut raw = [0u32];
+        self.read_entries(device, cluster, &mut raw)?;
+        Ok(self.decode(raw[0]))
+    }
+
+    /// Set the entry of `cluster`, in all copies
+    pub fn set(&self, device: &dyn Device, cluster: u32, entry: Entry) -> Result<()> {
+        let offset = cluster as usize * self.entry_size();
+        let value = self.encode(entry);
+        for copy in 0..self.copies {
+            let at = self.offset + copy * self.size + offset;
+            match self.type_ {
+                FatType::Fat16 => device.write_exact_at(at, &(value as u16).to_le_bytes())?,
+                FatType::Fat32 => {
+                    // the top 4 bits are reserved
+                    let mut old = [0u8; 4];
+                    device.read_exact_at(at, &mut old)?;
+                    let value = u32::from_le_bytes(old) & 0xf000_0000 | value;
+                    device.write_exact_at(at, &value.to_le_bytes())?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Clusters of the chain from `first`, none if it is 0
+    pub fn chain(&self, device: &dyn Device, first: u32) -> Result<Vec<u32>> {
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        while cluster != 0 {
+            // out of range, or looping
+            if !self.valid(cluster) || chain.len() >= self.clusters as usize {
+                return Err(FsError::Corrupted(cluster as usize));
+            }
+            chain.push(cluster);
+            cluster = match self.get(device, cluster)? {
+                Entry::Next(next) => next,
+                Entry::End => 0,
+                Entry::Free | Entry::Bad => return Err(FsError::Corrupted(cluster as usize)),
+            };
+        }
+        Ok(chain)
+    }
+
+    /// Count the free clusters, and look for them from the first
+    pub fn scan_free(&self, device: &dyn Device) -> Result<()> {
+        let mut count = 0;
+        let mut entries = vec![0u32; SCAN_ENTRIES];
+        let mut cluster = FIRST_CLUSTER;
+        while cluster < FIRST_CLUSTER + self.clusters {
+            let n = SCAN_ENTRIES.min((FIRST_CLUSTER + self.clusters - cluster) as usize);
+            self.read_entries(device, cluster, &mut entries[..n])?;
+            count += entries[..n]
+                .iter()
+                .filter(|&&raw| self.decode(raw) == Entry::Free)
+                .count() as u32;
+            cluster += n as u32;
+        }
+        self.set_free_info(count, FIRST_CLUSTER);
+        Ok(())
+    }
+
+    /// Number of free clusters, and where to look for the next
+    pub fn free_info(&self) -> (u32, u32) {
+        let free = self.free.lock();
+        (free.count, free.next)
+    }
+
+    pub fn set_free_info(&self, count: u32, next: u32) {
+        let mut free = self.free.lock();
+        free.count = count.min(self.clusters);
+        free.next = match self.valid(next) {
+            true => next,
+            false => FIRST_CLUSTER,
+        };
+    }
+
+    /// Take `n` free clusters, chained and ended, after `prev` if some
+    pub fn alloc(&self, device: &dyn Device, n: usize, prev: Option<u32>) -> Result<Vec<u32>> {
+        let mut free = self.free.lock();
+        if (free.count as usize) < n {
+            return Err(FsError::NoDeviceSpace);
+        }
+        let mut found = Vec::with_capacity(n);
+        let mut entries = vec![0u32; SCAN_ENTRIES];
+        let mut cluster = free.next;
+        let mut scanned = 0;
+        while found.len() < n && scanned < self.clusters {
+            let left = (FIRST_CLUSTER + self.clusters - cluster).min(self.clusters - scanned);
+            let count = SCAN_ENTRIES.min(left as usize);
+            self.read_entries(device, cluster, &mut entries[..count])?;
+            for (i, &raw) in entries[..count].iter().enumerate() {
+                if self.decode(raw) == Entry::Free && found.len() < n {
+                    found.push(cluster + i as u32);
+                }
+            }
+            scanned += count as u32;
+            cluster += count as u32;
+            if cluster == FIRST_CLUSTER + self.clusters {
+                cluster = FIRST_CLUSTER;
+            }
+        }
+        if found.len() < n {
+            // the count was wrong
+            free.count = found.len() as u32;
+            return Err(FsError::NoDeviceSpace);
+        }
+        // ended before it is linked, so that the chain is never left open
+        for (i, &cluster) in found.iter().enumerate().rev() {
+            let entry = match found.get(i + 1) {
+                Some(&next) => Entry::Next(next),
+                None => Entry::End,
+            };
+            self.set(device, cluster, entry)?;
+        }
+        if let (Some(prev), Some(&first)) = (prev, found.first()) {
+            self.set(device, prev, Entry::Next(first))?;
+        }
+        free.count -= n as u32;
+        if let Some(&last) = found.last() {
+            free.next = match last + 1 < FIRST_CLUSTER + self.clusters {
+                true => last + 1,
+                false => FIRST_CLUSTER,
+            };
+        }
+        Ok(found)
+    }
+
+    /// Free `clusters`, ending the chain at `last` if some
+    pub fn free(&self, device: &dyn Device, clusters: &[u32], last: Option<u32>) -> Result<()> {
+        let mut free = self.free.lock();
+        if let Some(last) = last {
+            self.set(device, last, Entry::End)?;
+        }
+        for &cluster in clusters {
+            self.set(device, cluster, Entry::Free)?;
+        }
+        free.count += clusters.len() as u32;
+        Ok(())
+    }
+
+    /// Write the media and the clean flags to entries 0 and 1 of a new table, and end the
+    /// chain of `root`, the root dir of FAT32
+    pub fn init(&self, device: &dyn Device, root: Option<u32>) -> Result<()> {
+        let (media, clean) = match self.type_ {
+            FatType::Fat16 => (0xfff8, 0xffff),
+            FatType::Fat32 => (0x0fff_fff8, 0x0fff_ffff),
+        };
+        self.set(device, 0, Entry::Next(media))?;
+        self.set(device, 1, Entry::Next(clean))?;
+        if let Some(root) = root {
+            self.set(device, root, Entry::End)?;
+        }
+        Ok(())
+    }
+}
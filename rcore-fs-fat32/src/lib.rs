@@ -0,0 +1,1091 @@
+//! FAT32 and FAT16, with long file names, e.g. on SD cards and EFI system partitions
+//!
+//! FAT has no inodes: the metadata of a file is in its short entry in its dir, and INodes
+//! are numbered when they are loaded, as by Linux. An INode in memory is found again by
+//! its dir and the offset of its short entry there.
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+extern crate alloc;
+#[macro_use]
+extern crate log;
+
+use alloc::{
+    collections::BTreeMap,
+    string::String,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use core::any::Any;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::{Mutex, RwLock};
+
+use rcore_fs::dev::{Device, TimeProvider};
+use rcore_fs::dirty::Dirty;
+use rcore_fs::vfs::{self, FileSystem, FileType, FsError, INode, Metadata, Timespec};
+
+use self::dir::Dirent;
+use self::fat::Fat;
+pub use self::structs::*;
+
+mod dir;
+mod fat;
+mod structs;
+#[cfg(test)]
+mod tests;
+
+trait DeviceExt: Device {
+    fn read_exact_at(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<()> {
+        match self.read_at(offset, buf)? {
+            len if len == buf.len() => Ok(()),
+            _ => Err(FsError::DeviceError),
+        }
+    }
+    fn write_exact_at(&self, offset: usize, buf: &[u8]) -> vfs::Result<()> {
+        match self.write_at(offset, buf)? {
+            len if len == buf.len() => Ok(()),
+            _ => Err(FsError::DeviceError),
+        }
+    }
+    /// Write `len` zeros at `offset`
+    fn zero_at(&self, offset: usize, len: usize) -> vfs::Result<()> {
+        let zeros = [0u8; 4096];
+        let mut done = 0;
+        while done < len {
+            let chunk = (len - done).min(zeros.len());
+            self.write_exact_at(offset + done, &zeros[..chunk])?;
+            done += chunk;
+        }
+        Ok(())
+    }
+}
+
+impl DeviceExt for dyn Device + '_ {}
+
+/// Number of the root INode
+const ROOT_ID: usize = 1;
+
+/// Mode of INodes, less the write bits if they are read-only, as FAT keeps no owner
+const MODE: u16 = 0o777;
+
+/// INode for FAT
+pub struct INodeImpl {
+    /// Number given when it is loaded
+    id: usize,
+    inner: RwLock<Inner>,
+    /// Reference to myself, to be the parent of INodes in the dir
+    this: Weak<INodeImpl>,
+    fs: Arc<FatFileSystem>,
+}
+
+struct Inner {
+    /// Its short entry, with the first cluster and the size, unused for the root
+    entry: Dirty<DirEntry>,
+    /// Clusters in order, none for the root of FAT16, which is a fixed area
+    chain: Vec<u32>,
+    /// Where its short entry is, none for the root
+    place: Option<Place>,
+    /// Removed from its dir, so its clusters are freed once it is dropped
+    unlinked: bool,
+}
+
+struct Place {
+    /// Dir of its entry
+    parent: Arc<INodeImpl>,
+    /// Byte offset of its short entry on the device
+    pos: usize,
+}
+
+impl INodeImpl {
+    fn is_root(&self) -> bool {
+        self.id == ROOT_ID
+    }
+
+    fn is_dir(&self, inner: &Inner) -> bool {
+        self.is_root() || inner.entry.is_dir()
+    }
+
+    /// Bytes of the content, all its clusters for a dir
+    fn size(&self, inner: &Inner) -> usize {
+        match self.is_dir(inner) {
+            true if inner.chain.is_empty() => self.fs.root_dir_size(),
+            true => inner.chain.len() * self.fs.cluster_size(),
+            false => inner.entry.size as usize,
+        }
+    }
+
+    /// Device ranges of `len` bytes at `offset` of the content, within its clusters
+    fn extents(&self, inner: &Inner, offset: usize, len: usize) -> Vec<(usize, usize)> {
+        if self.is_root() && inner.chain.is_empty() {
+            return vec![(self.fs.root_dir_offset() + offset, len)];
+        }
+        let cluster_size = self.fs.cluster_size();
+        let mut extents: Vec<(usize, usize)> = Vec::new();
+        let mut done = 0;
+        while done < len {
+            let index = (offset + done) / cluster_size;
+            let begin = (offset + done) % cluster_size;
+            let chunk = (cluster_size - begin).min(len - done);
+            let at = self.fs.cluster_offset(inner.chain[index]) + begin;
+            match extents.last_mut() {
+                // clusters next to each other, as they often are
+                Some(last) if last.0 + last.1 == at => last.1 += chunk,
+                _ => extents.push((at, chunk)),
+            }
+            done += chunk;
+        }
+        extents
+    }
+
+    fn read_content(&self, inner: &Inner, offset: usize, buf: &mut [u8]) -> vfs::Result<()> {
+        let mut done = 0;
+        for (at, len) in self.extents(inner, offset, buf.len()) {
+            self.fs
+                .device
+                .read_exact_at(at, &mut buf[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn write_content(&self, inner: &Inner, offset: usize, buf: &[u8]) -> vfs::Result<()> {
+        let mut done = 0;
+        for (at, len) in self.extents(inner, offset, buf.len()) {
+            self.fs.device.write_exact_at(at, &buf[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn zero_content(&self, inner: &Inner, offset: usize, len: usize) -> vfs::Result<()> {
+        for (at, len) in self.extents(inner, offset, len) {
+            self.fs.device.zero_at(at, len)?;
+        }
+        Ok(())
+    }
+
+    /// Take or give back clusters, so that it has `clusters`. New clusters are zeroed.
+    fn resize_chain(&self, inner: &mut Inner, clusters: usize) -> vfs::Result<()> {
+        let old = inner.chain.len();
+        if clusters > old {
+            let new = self.fs.fat.alloc(
+                &*self.fs.device,
+                clusters - old,
+                inner.chain.last().cloned(),
+            )?;
+            if old == 0 {
+                inner.entry.first_cluster = new[0];
+            }
+            inner.chain.extend(new);
+            let cluster_size = self.fs.cluster_size();
+            self.zero_content(inner, old * cluster_size, (clusters - old) * cluster_size)?;
+        } else if clusters < old {
+            let last = match clusters {
+                0 => None,
+                n => Some(inner.chain[n - 1]),
+            };
+            self.fs
+                .fat
+                .free(&*self.fs.device, &inner.chain[clusters..], last)?;
+            inner.chain.truncate(clusters);
+            if clusters == 0 {
+                inner.entry.first_cluster = 0;
+            }
+        }
+        Ok(())
+    }
+
+    /// Resize a file to `len`, whose bytes past the old size read as zeros
+    fn resize_file(&self, inner: &mut Inner, len: usize) -> vfs::Result<()> {
+        if len > u32::max_value() as usize {
+            return Err(FsError::InvalidParam);
+        }
+        let old = inner.entry.size as usize;
+        let cluster_size = self.fs.cluster_size();
+        self.resize_chain(inner, (len + cluster_size - 1) / cluster_size)?;
+        // left there by an earlier shrink
+        if len > old {
+            let end = len.min((old + cluster_size - 1) / cluster_size * cluster_size);
+            if end > old {
+                self.zero_content(inner, old, end - old)?;
+            }
+        }
+        inner.entry.size = len as u32;
+        Ok(())
+    }
+
+    /// Set the modification time to now
+    fn touch(&self, inner: &mut Inner) {
+        if let Some(now) = self.fs.now() {
+            let (date, time, _) = fat_time(now);
+            inner.entry.wrt_date = date;
+            inner.entry.wrt_time = time;
+            inner.entry.acc_date = date;
+        }
+    }
+
+    /// Write back its short entry if it is changed
+    fn write_entry(&self, inner: &mut Inner) -> vfs::Result<()> {
+        if inner.entry.dirty() && !inner.unlinked {
+            if let Some(place) = inner.place.as_ref() {
+                let mut buf = [0u8; DIRENT_SIZE];
+                inner.entry.write(&mut buf);
+                self.fs.device.write_exact_at(place.pos, &buf)?;
+            }
+            inner.entry.sync();
+        }
+        Ok(())
+    }
+
+    fn check_dir(&self, inner: &Inner) -> vfs::Result<()> {
+        if !self.is_dir(inner) {
+            return Err(FsError::NotDir);
+        }
+        if inner.unlinked {
+            return Err(FsError::DirRemoved);
+        }
+        Ok(())
+    }
+
+    /// The whole content of a dir
+    fn dir_content(&self, inner: &Inner) -> vfs::Result<Vec<u8>> {
+        let mut buf = vec![0u8; self.size(inner)];
+        self.read_content(inner, 0, &mut buf)?;
+        Ok(buf)
+    }
+
+    fn dirents(&self, inner: &Inner) -> vfs::Result<Vec<Dirent>> {
+        Ok(dir::parse(&self.dir_content(inner)?))
+    }
+
+    fn find_dirent(&self, inner: &Inner, name: &str) -> vfs::Result<Dirent> {
+        self.dirents(inner)?
+            .into_iter()
+            .find(|dirent| dirent.is(name))
+            .ok_or(FsError::EntryNotFound)
+    }
+
+    /// Add the entries of `name` to the dir, with short entry `entry` but for its name,
+    /// return the offset of the short entry
+    fn add_entries(
+        &self,
+        inner: &mut Inner,
+        name: &str,
+        mut entry: DirEntry,
+    ) -> vfs::Result<usize> {
+        let content = self.dir_content(inner)?;
+        let taken: Vec<[u8; 11]> = dir::parse(&content)
+            .iter()
+            .map(|dirent| dirent.entry.name)
+            .collect();
+        let long: Vec<u16> = match dir::short_name(name) {
+            Some((short, ntres)) if !taken.contains(&short) => {
+                entry.name = short;
+                entry.ntres = ntres;
+                Vec::new()
+            }
+            _ => {
+                entry.name = dir::unique_short_name(name, &taken);
+                entry.ntres = 0;
+                name.encode_utf16().collect()
+            }
+        };
+        let longs = (long.len() + LFN_CHARS - 1) / LFN_CHARS;
+        let slots = longs + 1;
+        // a run of free entries, or those past the end, which are all free
+        let mut run = 0;
+        let mut begin = None;
+        for (i, raw) in content.chunks_exact(DIRENT_SIZE).enumerate() {
+            if raw[0] == ENTRY_END {
+                begin = Some(i - run);
+                break;
+            }
+            run = match raw[0] {
+                ENTRY_FREE => run + 1,
+                _ => 0,
+            };
+            if run == slots {
+                begin = Some(i + 1 - run);
+                break;
+            }
+        }
+        let begin = match begin {
+            Some(begin) => begin,
+            None => content.len() / DIRENT_SIZE - run,
+        };
+        let end = (begin + slots) * DIRENT_SIZE;
+        if end > content.len() {
+            if self.is_root() && inner.chain.is_empty() || end > MAX_DIR_SIZE {
+                return Err(FsError::NoDeviceSpace);
+            }
+            let cluster_size = self.fs.cluster_size();
+            self.resize_chain(inner, (end + cluster_size - 1) / cluster_size)?;
+        }
+        let mut buf = vec![0u8; slots * DIRENT_SIZE];
+        let checksum = entry.checksum();
+        for (i, raw) in buf.chunks_exact_mut(DIRENT_SIZE).take(longs).enumerate() {
+            let ord = longs - i;
+            write_long_entry(raw, &long, ord, i == 0, checksum);
+        }
+        entry.write(&mut buf[longs * DIRENT_SIZE..]);
+        self.write_content(inner, begin * DIRENT_SIZE, &buf)?;
+        Ok(end - DIRENT_SIZE)
+    }
+
+    /// Free the entries of `dirent` in the dir
+    fn remove_entries(&self, inner: &Inner, dirent: &Dirent) -> vfs::Result<()> {
+        for offset in (dirent.begin..=dirent.offset).step_by(DIRENT_SIZE) {
+            self.write_content(inner, offset, &[ENTRY_FREE])?;
+        }
+        Ok(())
+    }
+
+    /// Device offset of the entry at `offset` of the dir
+    fn entry_pos(&self, inner: &Inner, offset: usize) -> usize {
+        self.extents(inner, offset, DIRENT_SIZE)[0].0
+    }
+
+    /// First cluster of the dir, as told by ".." in its subdirs, 0 for the root
+    fn dir_cluster(&self, inner: &Inner) -> u32 {
+        match self.is_root() {
+            true => 0,
+            false => inner.entry.first_cluster,
+        }
+    }
+
+    /// Is it `dir`, or under it?
+    fn is_under(&self, dir: &INodeImpl) -> bool {
+        let mut inode = self.this.upgrade().unwrap();
+        loop {
+            if inode.id == dir.id {
+                return true;
+            }
+            let parent = match inode.inner.read().place.as_ref() {
+                Some(place) => place.parent.clone(),
+                None => return false,
+            };
+            inode = parent;
+        }
+    }
+}
+
+impl vfs::INode for INodeImpl {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<usize> {
+        let inner = self.inner.read();
+        if self.is_dir(&inner) {
+            return Err(FsError::IsDir);
+        }
+        let size = inner.entry.size as usize;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min(size - offset);
+        self.read_content(&inner, offset, &mut buf[..len])?;
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> vfs::Result<usize> {
+        let mut inner = self.inner.write();
+        if self.is_dir(&inner) {
+            return Err(FsError::IsDir);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let end = offset + buf.len();
+        if end > inner.entry.size as usize {
+            self.resize_file(&mut inner, end)?;
+        }
+        self.write_content(&inner, offset, buf)?;
+        inner.entry.attr |= ATTR_ARCHIVE;
+        self.touch(&mut inner);
+        Ok(buf.len())
+    }
+
+    fn poll(&self) -> vfs::Result<vfs::PollStatus> {
+        Ok(vfs::PollStatus {
+            read: true,
+            write: true,
+            error: false,
+        })
+    }
+
+    fn metadata(&self) -> vfs::Result<Metadata> {
+        let inner = self.inner.read();
+        let entry = &inner.entry;
+        let (type_, nlinks) = match self.is_dir(&inner) {
+            true => (FileType::Dir, 2),
+            false => (FileType::File, 1),
+        };
+        let mtime = unix_time(entry.wrt_date, entry.wrt_time);
+        let mode = match entry.attr & ATTR_READ_ONLY {
+            0 => MODE,
+            _ => MODE & !0o222,
+        };
+        Ok(Metadata {
+            dev: 0,
+            inode: self.id,
+            size: self.size(&inner),
+            blk_size: self.fs.cluster_size(),
+            blocks: inner.chain.len(),
+            atime: unix_time(entry.acc_date, 0),
+            mtime,
+            // FAT keeps no time of change
+            ctime: mtime,
+            type_,
+            mode,
+            nlinks,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+        })
+    }
+
+    /// Only the times and the write bits of the mode are kept
+    fn set_metadata(&self, metadata: &Metadata) -> vfs::Result<()> {
+        let mut inner = self.inner.write();
+        if self.is_root() {
+            return Ok(());
+        }
+        let (date, time, _) = fat_time(metadata.mtime);
+        inner.entry.wrt_date = date;
+        inner.entry.wrt_time = time;
+        inner.entry.acc_date = fat_time(metadata.atime).0;
+        match metadata.mode & 0o222 {
+            0 => inner.entry.attr |= ATTR_READ_ONLY,
+            _ => inner.entry.attr &= !ATTR_READ_ONLY,
+        }
+        Ok(())
+    }
+
+    fn sync_all(&self) -> vfs::Result<()> {
+        self.write_entry(&mut self.inner.write())
+    }
+
+    fn sync_data(&self) -> vfs::Result<()> {
+        self.sync_all()
+    }
+
+    fn resize(&self, len: usize) -> vfs::Result<()> {
+        let mut inner = self.inner.write();
+        if self.is_dir(&inner) {
+            return Err(FsError::NotFile);
+        }
+        self.resize_file(&mut inner, len)?;
+        inner.entry.attr |= ATTR_ARCHIVE;
+        self.touch(&mut inner);
+        Ok(())
+    }
+
+    /// Only files and dirs can be created
+    fn create2(
+        &self,
+        name: &str,
+        type_: FileType,
+        mode: u32,
+        _data: usize,
+    ) -> vfs::Result<Arc<dyn INode>> {
+        let attr = match type_ {
+            FileType::File => ATTR_ARCHIVE,
+            FileType::Dir => ATTR_DIRECTORY,
+            _ => return Err(FsError::NotSupported),
+        };
+        let _namespace = self.fs.namespace.lock();
+        let mut inner = self.inner.write();
+        self.check_dir(&inner)?;
+        if name == "." || name == ".." || self.find_dirent(&inner, name).is_ok() {
+            return Err(FsError::EntryExist);
+        }
+        if !dir::valid_name(name) {
+            return Err(FsError::InvalidParam);
+        }
+        let mut entry = DirEntry {
+            attr,
+            ..DirEntry::default()
+        };
+        if mode & 0o222 == 0 {
+            entry.attr |= ATTR_READ_ONLY;
+        }
+        entry.set_times(self.fs.now().unwrap_or(Timespec { sec: 0, nsec: 0 }));
+        let mut chain = Vec::new();
+        if type_ == FileType::Dir {
+            // with "." and "..", which are kept in all but the root
+            chain = self.fs.fat.alloc(&*self.fs.device, 1, None)?;
+            entry.first_cluster = chain[0];
+            let mut buf = vec![0u8; self.fs.cluster_size()];
+            let mut dot = entry.clone();
+            dot.name = *b".          ";
+            dot.write(&mut buf[..DIRENT_SIZE]);
+            dot.name = *b"..         ";
+            dot.first_cluster = self.dir_cluster(&inner);
+            dot.write(&mut buf[DIRENT_SIZE..2 * DIRENT_SIZE]);
+            let at = self.fs.cluster_offset(chain[0]);
+            self.fs.device.write_exact_at(at, &buf)?;
+        }
+        let offset = match self.add_entries(&mut inner, name, entry.clone()) {
+            Ok(offset) => offset,
+            Err(err) => {
+                self.fs.fat.free(&*self.fs.device, &chain, None)?;
+                return Err(err);
+            }
+        };
+        // as written by `add_entries()`
+        let mut raw = [0u8; DIRENT_SIZE];
+        self.read_content(&inner, offset, &mut raw)?;
+        let dirent = Dirent {
+            name: String::from(name),
+            entry: DirEntry::parse(&raw),
+            begin: offset,
+            offset,
+        };
+        Ok(self.fs.get_inode(self, &inner, &dirent)?)
+    }
+
+    fn unlink(&self, name: &str) -> vfs::Result<()> {
+        if name == "." || name == ".." {
+            return Err(FsError::IsDir);
+        }
+        let _namespace = self.fs.namespace.lock();
+        let inner = self.inner.read();
+        self.check_dir(&inner)?;
+        let dirent = self.find_dirent(&inner, name)?;
+        let inode = self.fs.get_inode(self, &inner, &dirent)?;
+        let mut child = inode.inner.write();
+        if child.entry.is_dir() && !inode.dirents(&child)?.is_empty() {
+            return Err(FsError::DirNotEmpty);
+        }
+        self.remove_entries(&inner, &dirent)?;
+        child.unlinked = true;
+        self.fs.inodes.write().remove(&(self.id, dirent.offset));
+        Ok(())
+    }
+
+    fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> vfs::Result<()> {
+        if old_name == "." || old_name == ".." {
+            return Err(FsError::IsDir);
+        }
+        let target = target
+            .downcast_ref::<INodeImpl>()
+            .ok_or(FsError::NotSameFs)?;
+        if !Arc::ptr_eq(&self.fs, &target.fs) {
+            return Err(FsError::NotSameFs);
+        }
+        if !dir::valid_name(new_name) {
+            return Err(FsError::InvalidParam);
+        }
+        let same_dir = self.id == target.id;
+        if same_dir && old_name == new_name {
+            return Ok(());
+        }
+        let _namespace = self.fs.namespace.lock();
+        // dir contents only change under the namespace lock, so they are read as they are
+        let dirent = {
+            let inner = self.inner.read();
+            self.check_dir(&inner)?;
+            self.find_dirent(&inner, old_name)?
+        };
+        let inode = self.fs.get_inode(self, &self.inner.read(), &dirent)?;
+        target.check_dir(&target.inner.read())?;
+        let is_dir = inode.inner.read().entry.is_dir();
+        if is_dir && target.is_under(&inode) {
+            return Err(FsError::InvalidParam);
+        }
+        let old = target.find_dirent(&target.inner.read(), new_name).ok();
+        let old = match old {
+            // renamed to itself, maybe in another case
+            Some(old) if same_dir && old.offset == dirent.offset => None,
+            Some(old) => {
+                let old_inode = self.fs.get_inode(target, &target.inner.read(), &old)?;
+                // the dir holding what is moved
+                if old_inode.id == self.id {
+                    return Err(FsError::DirNotEmpty);
+                }
+                let old_dir = old_inode.inner.read().entry.is_dir();
+                match (is_dir, old_dir) {
+                    (true, true) => {
+                        if !old_inode.dirents(&old_inode.inner.read())?.is_empty() {
+                            return Err(FsError::DirNotEmpty);
+                        }
+                    }
+                    (true, false) => return Err(FsError::NotDir),
+                    (false, true) => return Err(FsError::IsDir),
+                    (false, false) => {}
+                }
+                Some((old, old_inode))
+            }
+            None => None,
+        };
+        if let Some((old, old_inode)) = old {
+            target.remove_entries(&target.inner.read(), &old)?;
+            old_inode.inner.write().unlinked = true;
+            self.fs.inodes.write().remove(&(target.id, old.offset));
+        }
+        let mut child = inode.inner.write();
+        // added before the old entries are freed, so that a crash leaves one of them
+        let offset = {
+            let mut target_inner = target.inner.write();
+            let offset = target.add_entries(&mut target_inner, new_name, (*child.entry).clone())?;
+            // with its new short name, as written
+            let mut raw = [0u8; DIRENT_SIZE];
+            target.read_content(&target_inner, offset, &mut raw)?;
+            child.entry.sync();
+            child.entry = Dirty::new(DirEntry::parse(&raw));
+            if is_dir && !same_dir {
+                let mut raw = [0u8; DIRENT_SIZE];
+                inode.read_content(&child, DIRENT_SIZE, &mut raw)?;
+                let mut dotdot = DirEntry::parse(&raw);
+                dotdot.first_cluster = target.dir_cluster(&target_inner);
+                dotdot.write(&mut raw);
+                inode.write_content(&child, DIRENT_SIZE, &raw)?;
+            }
+            child.place = Some(Place {
+                parent: target.this.upgrade().unwrap(),
+                pos: target.entry_pos(&target_inner, offset),
+            });
+            offset
+        };
+        self.remove_entries(&self.inner.read(), &dirent)?;
+        let mut inodes = self.fs.inodes.write();
+        inodes.remove(&(self.id, dirent.offset));
+        inodes.insert((target.id, offset), Arc::downgrade(&inode));
+        Ok(())
+    }
+
+    fn find(&self, name: &str) -> vfs::Result<Arc<dyn INode>> {
+        let inner = self.inner.read();
+        self.check_dir(&inner)?;
+        match name {
+            "." => Ok(self.this.upgrade().unwrap()),
+            ".." => match inner.place.as_ref() {
+                Some(place) => Ok(place.parent.clone()),
+                None => Ok(self.this.upgrade().unwrap()),
+            },
+            _ => {
+                let dirent = self.find_dirent(&inner, name)?;
+                Ok(self.fs.get_inode(self, &inner, &dirent)?)
+            }
+        }
+    }
+
+    fn get_entry(&self, id: usize) -> vfs::Result<String> {
+        let inner = self.inner.read();
+        self.check_dir(&inner)?;
+        match id {
+            0 => Ok(String::from(".")),
+            1 => Ok(String::from("..")),
+            _ => self
+                .dirents(&inner)?
+                .into_iter()
+                .nth(id - 2)
+                .map(|dirent| dirent.name)
+                .ok_or(FsError::EntryNotFound),
+        }
+    }
+
+    fn fs(&self) -> Arc<dyn vfs::FileSystem> {
+        self.fs.clone()
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl Drop for INodeImpl {
+    /// Write back its entry, or free its clusters once it is unlinked
+    fn drop(&mut self) {
+        let mut inner = self.inner.write();
+        if inner.unlinked {
+            inner.entry.sync();
+            if let Err(err) = self.fs.fat.free(&*self.fs.device, &inner.chain, None) {
+                warn!("failed to free clusters of INode {}: {:?}", self.id, err);
+            }
+            return;
+        }
+        drop(inner);
+        if let Err(err) = self.sync_all() {
+            warn!("failed to write back INode {}: {:?}", self.id, err);
+        }
+    }
+}
+
+/// FAT file system
+pub struct FatFileSystem {
+    device: Arc<dyn Device>,
+    boot: BootSector,
+    fat: Fat,
+    /// INodes in memory, by the number of their dir and the offset of their short entries,
+    /// (0, 0) for the root
+    inodes: RwLock<BTreeMap<(usize, usize), Weak<INodeImpl>>>,
+    /// Number of the next INode loaded
+    next_id: AtomicUsize,
+    /// Held while the entries of any dir are changed, so that a move sees both dirs at once
+    namespace: Mutex<()>,
+    /// Pointer to self, used by INodes
+    self_ptr: Weak<FatFileSystem>,
+    /// Clock for timestamps, which are left alone without it
+    time: Option<&'static dyn TimeProvider>,
+}
+
+impl FatFileSystem {
+    /// Load FAT from device without a clock, so timestamps are only set by `set_metadata`
+    pub fn open(device: Arc<dyn Device>) -> vfs::Result<Arc<Self>> {
+        Self::_open(device, None)
+    }
+
+    /// Load FAT from device, with timestamps from `time`
+    pub fn open_with_time(
+        device: Arc<dyn Device>,
+        time: &'static dyn TimeProvider,
+    ) -> vfs::Result<Arc<Self>> {
+        Self::_open(device, Some(time))
+    }
+
+    fn _open(
+        device: Arc<dyn Device>,
+        time: Option<&'static dyn TimeProvider>,
+    ) -> vfs::Result<Arc<Self>> {
+        let mut buf = [0u8; 512];
+        device.read_exact_at(0, &mut buf)?;
+        let boot = BootSector::parse(&buf).ok_or(FsError::WrongFs)?;
+        // FAT12 is only on floppies
+        let type_ = boot.fat_type().ok_or(FsError::NotSupported)?;
+        if (type_ == FatType::Fat32) != (boot.root_entries == 0) {
+            return Err(FsError::WrongFs);
+        }
+        // a sector must be written without touching its neighbours
+        if 1usize << device.sector_size_log2() > boot.bytes_per_sector as usize {
+            return Err(FsError::InvalidParam);
+        }
+        let fat = Fat::new(&boot, type_);
+        let fs = FatFileSystem {
+            device,
+            boot,
+            fat,
+            inodes: RwLock::new(BTreeMap::new()),
+            next_id: AtomicUsize::new(ROOT_ID + 1),
+            namespace: Mutex::new(()),
+            self_ptr: Weak::default(),
+            time,
+        };
+        match fs.read_fs_info()? {
+            Some((free, next)) => fs.fat.set_free_info(free, next),
+            None => fs.fat.scan_free(&*fs.device)?,
+        }
+        Ok(fs.wrap())
+    }
+
+    /// Create a new FAT32 on blank disk of `space` bytes without a clock
+    pub fn create(device: Arc<dyn Device>, space: usize) -> vfs::Result<Arc<Self>> {
+        Self::_create(device, space, FatType::Fat32, None)
+    }
+
+    /// Create a new FAT of `type_` on blank disk of `space` bytes, with timestamps from `time`.
+    /// Clusters are sized as by Windows, and the disk must be large enough for the type:
+    /// at least about 33M for FAT32, and from about 4M to 2G for FAT16.
+    pub fn create_with_type(
+        device: Arc<dyn Device>,
+        space: usize,
+        type_: FatType,
+        time: &'static dyn TimeProvider,
+    ) -> vfs::Result<Arc<Self>> {
+        Self::_create(device, space, type_, Some(time))
+    }
+
+    fn _create(
+        device: Arc<dyn Device>,
+        space: usize,
+        type_: FatType,
+        time: Option<&'static dyn TimeProvider>,
+    ) -> vfs::Result<Arc<Self>> {
+        const SECTOR: usize = 512;
+        const MB: usize = 1 << 20;
+        if device.sector_size_log2() > 9 {
+            return Err(FsError::InvalidParam);
+        }
+        let sectors = (space / SECTOR).min(u32::max_value() as usize) as u32;
+        let sectors_per_cluster = match (type_, space) {
+            (FatType::Fat16, n) if n <= 16 * MB => 2,
+            (FatType::Fat16, n) if n <= 128 * MB => 4,
+            (FatType::Fat16, n) if n <= 256 * MB => 8,
+            (FatType::Fat16, n) if n <= 512 * MB => 16,
+            (FatType::Fat16, n) if n <= 1024 * MB => 32,
+            (FatType::Fat16, n) if n <= 2048 * MB => 64,
+            (FatType::Fat16, _) => return Err(FsError::InvalidParam),
+            (FatType::Fat32, n) if n <= 260 * MB => 1,
+            (FatType::Fat32, n) if n <= 8192 * MB => 8,
+            (FatType::Fat32, n) if n <= 16384 * MB => 16,
+            (FatType::Fat32, n) if n <= 32768 * MB => 32,
+            (FatType::Fat32, _) => 64,
+        };
+        let (reserved_sectors, root_entries) = match type_ {
+            FatType::Fat16 => (1, 512),
+            FatType::Fat32 => (32, 0),
+        };
+        let mut boot = BootSector {
+            bytes_per_sector: SECTOR as u16,
+            sectors_per_cluster,
+            reserved_sectors,
+            fats: 2,
+            root_entries,
+            total_sectors: sectors,
+            fat_size: 0,
+            root_cluster: FIRST_CLUSTER,
+            fs_info: 1,
+        };
+        // as told by Microsoft, a little more than needed
+        let rest = sectors.saturating_sub(reserved_sectors as u32 + boot.root_dir_sectors());
+        let per_fat_sector = match type_ {
+            FatType::Fat16 => 256 * sectors_per_cluster as u32 + 2,
+            FatType::Fat32 => (256 * sectors_per_cluster as u32 + 2) / 2,
+        };
+        boot.fat_size = (rest + per_fat_sector - 1) / per_fat_sector;
+        if boot.data_sector() >= sectors
+            || boot.fat_type() != Some(type_)
+            || boot.clusters() > MAX_CLUSTERS_FAT32
+        {
+            return Err(FsError::InvalidParam);
+        }
+
+        // the tables and the root dir, zeroed
+        let data = boot.data_sector() as usize * SECTOR;
+        let root_size = match type_ {
+            FatType::Fat16 => 0,
+            FatType::Fat32 => boot.cluster_size(),
+        };
+        device.zero_at(0, data + root_size)?;
+        // so that a device too small fails now, and an image file is as large as the disk
+        device.zero_at((sectors as usize - 1) * SECTOR, SECTOR)?;
+        let fat = Fat::new(&boot, type_);
+        let root = match type_ {
+            FatType::Fat16 => None,
+            FatType::Fat32 => Some(FIRST_CLUSTER),
+        };
+        fat.init(&*device, root)?;
+        let mut buf = [0u8; SECTOR];
+        boot.write(type_, &mut buf);
+        device.write_exact_at(0, &buf)?;
+        if type_ == FatType::Fat32 {
+            // the backup
+            device.write_exact_at(6 * SECTOR, &buf)?;
+        }
+        let fs = FatFileSystem {
+            device,
+            boot,
+            fat,
+            inodes: RwLock::new(BTreeMap::new()),
+            next_id: AtomicUsize::new(ROOT_ID + 1),
+            namespace: Mutex::new(()),
+            self_ptr: Weak::default(),
+            time,
+        };
+        let used = match type_ {
+            FatType::Fat16 => 0,
+            FatType::Fat32 => 1,
+        };
+        fs.fat
+            .set_free_info(fs.fat.clusters() - used, FIRST_CLUSTER + used);
+        fs.write_fs_info()?;
+        let fs = fs.wrap();
+        fs.sync()?;
+        Ok(fs)
+    }
+
+    /// Wrap pure FatFileSystem with Arc
+    /// Used in constructors
+    fn wrap(self) -> Arc<Self> {
+        // Create an Arc, make a Weak from it, then put it into the struct.
+        // It's a little tricky.
+        let fs = Arc::new(self);
+        let weak = Arc::downgrade(&fs);
+        let ptr = Arc::into_raw(fs) as *mut Self;
+        unsafe {
+            (*ptr).self_ptr = weak;
+        }
+        unsafe { Arc::from_raw(ptr) }
+    }
+
+    /// FAT16 or FAT32
+    pub fn fat_type(&self) -> FatType {
+        self.fat.type_
+    }
+
+    /// Free cluster count and next free cluster in the FSInfo of FAT32, none if not known
+    fn read_fs_info(&self) -> vfs::Result<Option<(u32, u32)>> {
+        if self.fat.type_ != FatType::Fat32 {
+            return Ok(None);
+        }
+        let mut buf = [0u8; 512];
+        let at = self.boot.fs_info as usize * self.boot.bytes_per_sector as usize;
+        self.device.read_exact_at(at, &mut buf)?;
+        let valid = le32(&buf, 0) == FS_INFO_LEAD_SIG
+            && le32(&buf, 484) == FS_INFO_STRUCT_SIG
+            && le32(&buf, 508) == FS_INFO_TRAIL_SIG;
+        let free = le32(&buf, FS_INFO_FREE);
+        match valid && free <= self.fat.clusters() {
+            true => Ok(Some((free, le32(&buf, FS_INFO_NEXT)))),
+            false => Ok(None),
+        }
+    }
+
+    /// Write the free cluster count and next free cluster to the FSInfo of FAT32
+    fn write_fs_info(&self) -> vfs::Result<()> {
+        if self.fat.type_ != FatType::Fat32 || self.boot.fs_info == 0 {
+            return Ok(());
+        }
+        let (free, next) = self.fat.free_info();
+        let mut buf = [0u8; 512];
+        put32(&mut buf, 0, FS_INFO_LEAD_SIG);
+        put32(&mut buf, 484, FS_INFO_STRUCT_SIG);
+        put32(&mut buf, FS_INFO_FREE, free);
+        put32(&mut buf, FS_INFO_NEXT, next);
+        put32(&mut buf, 508, FS_INFO_TRAIL_SIG);
+        let at = self.boot.fs_info as usize * self.boot.bytes_per_sector as usize;
+        self.device.write_exact_at(at, &buf)
+    }
+
+    fn now(&self) -> Option<Timespec> {
+        self.time.map(|time| time.current_time())
+    }
+
+    fn cluster_size(&self) -> usize {
+        self.boot.cluster_size()
+    }
+
+    /// Byte offset of `cluster` on the device
+    fn cluster_offset(&self, cluster: u32) -> usize {
+        let sector = self.boot.data_sector() as usize
+            + (cluster - FIRST_CLUSTER) as usize * self.boot.sectors_per_cluster as usize;
+        sector * self.boot.bytes_per_sector as usize
+    }
+
+    /// Byte offset of the root dir of FAT16
+    fn root_dir_offset(&self) -> usize {
+        self.boot.root_dir_sector() as usize * self.boot.bytes_per_sector as usize
+    }
+
+    /// Bytes of the root dir of FAT16
+    fn root_dir_size(&self) -> usize {
+        self.boot.root_entries as usize * DIRENT_SIZE
+    }
+
+    fn new_inode(&self, inner: Inner) -> Arc<INodeImpl> {
+        let id = match inner.place {
+            None => ROOT_ID,
+            Some(_) => self.next_id.fetch_add(1, Ordering::SeqCst),
+        };
+        let inode = Arc::new(INodeImpl {
+            id,
+            inner: RwLock::new(inner),
+            this: Weak::default(),
+            fs: self.self_ptr.upgrade().unwrap(),
+        });
+        let weak = Arc::downgrade(&inode);
+        let ptr = Arc::into_raw(inode) as *mut INodeImpl;
+        unsafe {
+            (*ptr).this = weak;
+            Arc::from_raw(ptr)
+        }
+    }
+
+    /// Get the INode of `dirent` in dir `parent`. Load if not in memory.
+    fn get_inode(
+        &self,
+        parent: &INodeImpl,
+        parent_inner: &Inner,
+        dirent: &Dirent,
+    ) -> vfs::Result<Arc<INodeImpl>> {
+        let key = (parent.id, dirent.offset);
+        if let Some(inode) = self.inodes.read().get(&key).and_then(Weak::upgrade) {
+            return Ok(inode);
+        }
+        let chain = self.fat.chain(&*self.device, dirent.entry.first_cluster)?;
+        let mut inodes = self.inodes.write();
+        // loaded by another thread meanwhile
+        if let Some(inode) = inodes.get(&key).and_then(Weak::upgrade) {
+            return Ok(inode);
+        }
+        let inode = self.new_inode(Inner {
+            entry: Dirty::new(dirent.entry.clone()),
+            chain,
+            place: Some(Place {
+                parent: parent.this.upgrade().unwrap(),
+                pos: parent.entry_pos(parent_inner, dirent.offset),
+            }),
+            unlinked: false,
+        });
+        inodes.insert(key, Arc::downgrade(&inode));
+        Ok(inode)
+    }
+
+    fn get_root(&self) -> vfs::Result<Arc<INodeImpl>> {
+        if let Some(inode) = self.inodes.read().get(&(0, 0)).and_then(Weak::upgrade) {
+            return Ok(inode);
+        }
+        let chain = match self.fat.type_ {
+            FatType::Fat16 => Vec::new(),
+            FatType::Fat32 => self.fat.chain(&*self.device, self.boot.root_cluster)?,
+        };
+        let mut inodes = self.inodes.write();
+        if let Some(inode) = inodes.get(&(0, 0)).and_then(Weak::upgrade) {
+            return Ok(inode);
+        }
+        let inode = self.new_inode(Inner {
+            entry: Dirty::new(DirEntry {
+                attr: ATTR_DIRECTORY,
+                first_cluster: self.boot.root_cluster,
+                ..DirEntry::default()
+            }),
+            chain,
+            place: None,
+            unlinked: false,
+        });
+        inodes.insert((0, 0), Arc::downgrade(&inode));
+        Ok(inode)
+    }
+}
+
+impl vfs::FileSystem for FatFileSystem {
+    fn sync(&self) -> vfs::Result<()> {
+        let inodes: Vec<_> = self
+            .inodes
+            .read()
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect();
+        for inode in inodes {
+            inode.sync_all()?;
+        }
+        self.write_fs_info()?;
+        self.device.sync()?;
+        self.device.flush()?;
+        Ok(())
+    }
+
+    fn root_inode(&self) -> Arc<dyn vfs::INode> {
+        self.get_root().expect("failed to load the root dir")
+    }
+
+    /// Clusters as blocks. Files are not counted, as there are no inodes.
+    fn info(&self) -> vfs::FsInfo {
+        let (free, _) = self.fat.free_info();
+        vfs::FsInfo {
+            bsize: self.cluster_size(),
+            frsize: self.cluster_size(),
+            blocks: self.fat.clusters() as usize,
+            bfree: free as usize,
+            bavail: free as usize,
+            files: 0,
+            ffree: 0,
+            namemax: MAX_NAME_LEN,
+        }
+    }
+
+    fn capabilities(&self) -> vfs::FsCapabilities {
+        vfs::FsCapabilities {
+            features: vfs::FsFeatures::empty(),
+            namemax: MAX_NAME_LEN,
+        }
+    }
+}
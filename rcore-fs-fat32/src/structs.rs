@@ -0,0 +1,402 @@
+//! On-disk structures of FAT, all little-endian
+
+use alloc::{string::String, vec::Vec};
+use rcore_fs::vfs::Timespec;
+
+/// Kind of FAT, by the bits of its entries
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum FatType {
+    Fat16,
+    Fat32,
+}
+
+/// BIOS parameter block, from the boot sector
+#[derive(Debug, Clone)]
+pub struct BootSector {
+    pub bytes_per_sector: u16,
+    pub sectors_per_cluster: u8,
+    pub reserved_sectors: u16,
+    pub fats: u8,
+    /// entries of the root dir, 0 for FAT32, whose root dir is in clusters
+    pub root_entries: u16,
+    pub total_sectors: u32,
+    /// sectors of a FAT
+    pub fat_size: u32,
+    /// first cluster of the root dir, FAT32 only
+    pub root_cluster: u32,
+    /// sector of the FSInfo, FAT32 only
+    pub fs_info: u16,
+}
+
+/// A short (8.3) directory entry
+#[derive(Debug, Default, Clone)]
+pub struct DirEntry {
+    /// base name and extension, padded with spaces
+    pub name: [u8; 11],
+    pub attr: u8,
+    /// NTRES_* of the case of the name
+    pub ntres: u8,
+    pub crt_time_tenth: u8,
+    pub crt_time: u16,
+    pub crt_date: u16,
+    pub acc_date: u16,
+    pub first_cluster: u32,
+    pub wrt_time: u16,
+    pub wrt_date: u16,
+    pub size: u32,
+}
+
+/// size of a directory entry
+pub const DIRENT_SIZE: usize = 32;
+/// max length of a long name, in UTF-16 units
+pub const MAX_NAME_LEN: usize = 255;
+/// characters of a long name in a long entry
+pub const LFN_CHARS: usize = 13;
+/// max size of a dir, as its entries are counted by u16
+pub const MAX_DIR_SIZE: usize = 65536 * DIRENT_SIZE;
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+/// attributes of a long entry
+pub const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+/// the base of the short name is in lower case
+pub const NTRES_LOWER_BASE: u8 = 0x08;
+/// the extension of the short name is in lower case
+pub const NTRES_LOWER_EXT: u8 = 0x10;
+/// 1st byte of the name of a free entry
+pub const ENTRY_FREE: u8 = 0xe5;
+/// 1st byte of the name of the free entry after the last, as are all after it
+pub const ENTRY_END: u8 = 0x00;
+/// 1st byte of the name of the last long entry of a name, which comes first
+pub const LFN_LAST: u8 = 0x40;
+
+/// signature at the end of the boot sector
+pub const BOOT_SIGNATURE: u16 = 0xaa55;
+pub const FS_INFO_LEAD_SIG: u32 = 0x4161_5252;
+pub const FS_INFO_STRUCT_SIG: u32 = 0x6141_7272;
+pub const FS_INFO_TRAIL_SIG: u32 = 0xaa55_0000;
+/// offset of the free cluster count in the FSInfo
+pub const FS_INFO_FREE: usize = 488;
+/// offset of the next free cluster hint in the FSInfo
+pub const FS_INFO_NEXT: usize = 492;
+
+/// fewest clusters of FAT16, fewer are FAT12
+pub const MIN_CLUSTERS_FAT16: u32 = 4085;
+/// fewest clusters of FAT32
+pub const MIN_CLUSTERS_FAT32: u32 = 65525;
+/// most clusters of FAT32, as its entries are 28 bits, but for those reserved
+pub const MAX_CLUSTERS_FAT32: u32 = 0x0fff_fff5;
+/// number of the first data cluster
+pub const FIRST_CLUSTER: u32 = 2;
+
+pub fn le16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+pub fn le32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+pub fn put16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+pub fn put32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+impl BootSector {
+    /// Parse the boot sector, none if it is not of FAT
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        if le16(buf, 510) != BOOT_SIGNATURE {
+            return None;
+        }
+        let total_sectors = match le16(buf, 0x13) {
+            0 => le32(buf, 0x20),
+            n => n as u32,
+        };
+        let fat_size = match le16(buf, 0x16) {
+            0 => le32(buf, 0x24),
+            n => n as u32,
+        };
+        let bs = BootSector {
+            bytes_per_sector: le16(buf, 0x0b),
+            sectors_per_cluster: buf[0x0d],
+            reserved_sectors: le16(buf, 0x0e),
+            fats: buf[0x10],
+            root_entries: le16(buf, 0x11),
+            total_sectors,
+            fat_size,
+            root_cluster: le32(buf, 0x2c),
+            fs_info: le16(buf, 0x30),
+        };
+        let valid = bs.bytes_per_sector.is_power_of_two()
+            && bs.bytes_per_sector >= 512
+            && bs.bytes_per_sector <= 4096
+            && bs.sectors_per_cluster.is_power_of_two()
+            && bs.reserved_sectors != 0
+            && bs.fats != 0
+            && bs.fat_size != 0
+            && bs.data_sector() < bs.total_sectors;
+        match valid {
+            true => Some(bs),
+            false => None,
+        }
+    }
+
+    /// Write it to the boot sector `buf` of FAT of `type_`, with the rest of its fields
+    pub fn write(&self, type_: FatType, buf: &mut [u8]) {
+        let sector = self.bytes_per_sector as usize;
+        for byte in buf[..sector].iter_mut() {
+            *byte = 0;
+        }
+        let jump = match type_ {
+            FatType::Fat16 => 0x3c,
+            FatType::Fat32 => 0x58,
+        };
+        buf[..3].copy_from_slice(&[0xeb, jump, 0x90]);
+        buf[3..11].copy_from_slice(b"MSWIN4.1");
+        put16(buf, 0x0b, self.bytes_per_sector);
+        buf[0x0d] = self.sectors_per_cluster;
+        put16(buf, 0x0e, self.reserved_sectors);
+        buf[0x10] = self.fats;
+        put16(buf, 0x11, self.root_entries);
+        // fixed disk
+        buf[0x15] = 0xf8;
+        put16(buf, 0x18, 63);
+        put16(buf, 0x1a, 255);
+        match self.total_sectors {
+            n if n < 0x10000 && type_ == FatType::Fat16 => put16(buf, 0x13, n as u16),
+            n => put32(buf, 0x20, n),
+        }
+        let ext = match type_ {
+            FatType::Fat16 => {
+                put16(buf, 0x16, self.fat_size as u16);
+                0x24
+            }
+            FatType::Fat32 => {
+                put32(buf, 0x24, self.fat_size);
+                put32(buf, 0x2c, self.root_cluster);
+                put16(buf, 0x30, self.fs_info);
+                // backup boot sector
+                put16(buf, 0x32, 6);
+                0x40
+            }
+        };
+        buf[ext] = 0x80;
+        buf[ext + 2] = 0x29;
+        buf[ext + 7..ext + 18].copy_from_slice(b"NO NAME    ");
+        let name: &[u8] = match type_ {
+            FatType::Fat16 => b"FAT16   ",
+            FatType::Fat32 => b"FAT32   ",
+        };
+        buf[ext + 18..ext + 26].copy_from_slice(name);
+        put16(buf, 510, BOOT_SIGNATURE);
+    }
+
+    /// sectors of the root dir, 0 for FAT32
+    pub fn root_dir_sectors(&self) -> u32 {
+        let bytes = self.root_entries as u32 * DIRENT_SIZE as u32;
+        (bytes + self.bytes_per_sector as u32 - 1) / self.bytes_per_sector as u32
+    }
+
+    /// first sector of the root dir of FAT16
+    pub fn root_dir_sector(&self) -> u32 {
+        self.reserved_sectors as u32 + self.fats as u32 * self.fat_size
+    }
+
+    /// first sector of cluster 2
+    pub fn data_sector(&self) -> u32 {
+        self.root_dir_sector() + self.root_dir_sectors()
+    }
+
+    /// number of data clusters
+    pub fn clusters(&self) -> u32 {
+        (self.total_sectors - self.data_sector()) / self.sectors_per_cluster as u32
+    }
+
+    pub fn cluster_size(&self) -> usize {
+        self.bytes_per_sector as usize * self.sectors_per_cluster as usize
+    }
+
+    /// Kind of FAT, by the number of clusters, none for FAT12
+    pub fn fat_type(&self) -> Option<FatType> {
+        match self.clusters() {
+            n if n < MIN_CLUSTERS_FAT16 => None,
+            n if n < MIN_CLUSTERS_FAT32 => Some(FatType::Fat16),
+            _ => Some(FatType::Fat32),
+        }
+    }
+}
+
+impl DirEntry {
+    pub fn parse(buf: &[u8]) -> Self {
+        let mut name = [0u8; 11];
+        name.copy_from_slice(&buf[..11]);
+        DirEntry {
+            name,
+            attr: buf[11],
+            ntres: buf[12],
+            crt_time_tenth: buf[13],
+            crt_time: le16(buf, 14),
+            crt_date: le16(buf, 16),
+            acc_date: le16(buf, 18),
+            first_cluster: (le16(buf, 20) as u32) << 16 | le16(buf, 26) as u32,
+            wrt_time: le16(buf, 22),
+            wrt_date: le16(buf, 24),
+            size: le32(buf, 28),
+        }
+    }
+
+    pub fn write(&self, buf: &mut [u8]) {
+        buf[..11].copy_from_slice(&self.name);
+        buf[11] = self.attr;
+        buf[12] = self.ntres;
+        buf[13] = self.crt_time_tenth;
+        put16(buf, 14, self.crt_time);
+        put16(buf, 16, self.crt_date);
+        put16(buf, 18, self.acc_date);
+        put16(buf, 20, (self.first_cluster >> 16) as u16);
+        put16(buf, 22, self.wrt_time);
+        put16(buf, 24, self.wrt_date);
+        put16(buf, 26, self.first_cluster as u16);
+        put32(buf, 28, self.size);
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    /// The short name as shown, in the case told by `ntres`
+    pub fn short_name(&self) -> String {
+        let mut name = self.name;
+        // a name starting with 0xe5, not a free entry
+        if name[0] == 0x05 {
+            name[0] = ENTRY_FREE;
+        }
+        let part = |bytes: &[u8], lower: bool| -> String {
+            let len = bytes.iter().rposition(|&b| b != b' ').map_or(0, |i| i + 1);
+            bytes[..len]
+                .iter()
+                .map(|&b| match lower {
+                    true => b.to_ascii_lowercase() as char,
+                    false => b as char,
+                })
+                .collect()
+        };
+        let mut short = part(&name[..8], self.ntres & NTRES_LOWER_BASE != 0);
+        let ext = part(&name[8..], self.ntres & NTRES_LOWER_EXT != 0);
+        if !ext.is_empty() {
+            short.push('.');
+            short.push_str(&ext);
+        }
+        short
+    }
+
+    /// Checksum of the short name, kept in each long entry of it
+    pub fn checksum(&self) -> u8 {
+        self.name.iter().fold(0u8, |sum, &b| {
+            ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(b)
+        })
+    }
+
+    /// Set the times of creation, access and modification to `time`
+    pub fn set_times(&mut self, time: Timespec) {
+        let (date, time_, tenth) = fat_time(time);
+        self.crt_date = date;
+        self.crt_time = time_;
+        self.crt_time_tenth = tenth;
+        self.acc_date = date;
+        self.wrt_date = date;
+        self.wrt_time = time_;
+    }
+}
+
+/// Write long entry `ord` of `name`, of the short entry of `checksum`, to `buf`
+pub fn write_long_entry(buf: &mut [u8], name: &[u16], ord: usize, last: bool, checksum: u8) {
+    buf[0] = ord as u8 | if last { LFN_LAST } else { 0 };
+    buf[11] = ATTR_LONG_NAME;
+    buf[12] = 0;
+    buf[13] = checksum;
+    put16(buf, 26, 0);
+    let begin = (ord - 1) * LFN_CHARS;
+    for i in 0..LFN_CHARS {
+        // terminated by 0 if it does not fill the entry, padded by 0xffff
+        let c = match begin + i {
+            j if j < name.len() => name[j],
+            j if j == name.len() => 0,
+            _ => 0xffff,
+        };
+        put16(buf, LFN_OFFSETS[i], c);
+    }
+}
+
+/// Characters of the long name in long entry `buf`, up to its end
+pub fn long_entry_chars(buf: &[u8]) -> Vec<u16> {
+    LFN_OFFSETS
+        .iter()
+        .map(|&offset| le16(buf, offset))
+        .take_while(|&c| c != 0)
+        .collect()
+}
+
+/// offsets of the characters in a long entry
+const LFN_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// Days since the epoch of `year`-`month`-`day`
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let yoe = year - era * 400;
+    let month = month as i64;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Year, month and day of `days` since the epoch
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = if days >= 0 { days } else { days - 146_096 } / 146_097;
+    let doe = days - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Date, time and tenths of a second of `time`, as UTC, within the years FAT can tell
+pub fn fat_time(time: Timespec) -> (u16, u16, u8) {
+    let min = days_from_civil(1980, 1, 1) * 86400;
+    let max = days_from_civil(2107, 12, 31) * 86400 + 86399;
+    let sec = time.sec.max(min).min(max);
+    let (days, secs) = (sec.div_euclid(86400), sec.rem_euclid(86400));
+    let (year, month, day) = civil_from_days(days);
+    let date = ((year - 1980) as u16) << 9 | (month as u16) << 5 | day as u16;
+    let (hour, min, sec) = (secs / 3600, secs / 60 % 60, secs % 60);
+    let time_ = (hour as u16) << 11 | (min as u16) << 5 | (sec / 2) as u16;
+    let tenth = (sec % 2 * 100) as u8 + (time.nsec / 10_000_000).max(0) as u8;
+    (date, time_, tenth)
+}
+
+/// The time of FAT `date` and `time`, as UTC
+pub fn unix_time(date: u16, time: u16) -> Timespec {
+    let year = 1980 + (date >> 9) as i64;
+    let month = ((date >> 5) & 0xf).max(1).min(12) as u32;
+    let day = (date & 0x1f).max(1) as u32;
+    let secs =
+        (time >> 11) as i64 * 3600 + ((time >> 5) & 0x3f) as i64 * 60 + (time & 0x1f) as i64 * 2;
+    Timespec {
+        sec: days_from_civil(year, month, day) * 86400 + secs,
+        nsec: 0,
+    }
+}
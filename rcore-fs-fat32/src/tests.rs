@@ -0,0 +1,185 @@
+extern crate std;
+
+use crate::*;
+use rcore_fs::dev::std_impl::StdTimeProvider;
+use rcore_fs::vfs::{FileSystem, FileType, Result};
+use std::fs::File;
+use std::sync::{Arc, Mutex};
+
+fn _create_new_fat(type_: FatType, space: usize) -> (Arc<Mutex<File>>, Arc<FatFileSystem>) {
+    let file = Arc::new(Mutex::new(
+        tempfile::tempfile().expect("failed to create file"),
+    ));
+    let fs = FatFileSystem::create_with_type(file.clone(), space, type_, &StdTimeProvider)
+        .expect("failed to create FAT");
+    (file, fs)
+}
+
+fn _reopen(file: Arc<Mutex<File>>, fs: Arc<FatFileSystem>) -> Arc<FatFileSystem> {
+    fs.sync().expect("failed to sync FAT");
+    drop(fs);
+    FatFileSystem::open(file).expect("failed to open FAT")
+}
+
+#[test]
+fn create_fat32_and_fat16() -> Result<()> {
+    let (_, fs) = _create_new_fat(FatType::Fat32, 64 << 20);
+    assert_eq!(fs.fat_type(), FatType::Fat32);
+    assert_eq!(fs.root_inode().list()?, vec![".", ".."]);
+    let (_, fs) = _create_new_fat(FatType::Fat16, 16 << 20);
+    assert_eq!(fs.fat_type(), FatType::Fat16);
+    assert_eq!(fs.root_inode().list()?, vec![".", ".."]);
+    // too few clusters for FAT32
+    let file = Arc::new(Mutex::new(tempfile::tempfile().unwrap()));
+    assert!(FatFileSystem::create(file, 16 << 20).is_err());
+    Ok(())
+}
+
+#[test]
+fn write_read_and_reopen() -> Result<()> {
+    for &(type_, space) in [(FatType::Fat32, 64 << 20), (FatType::Fat16, 16 << 20)].iter() {
+        let (file, fs) = _create_new_fat(type_, space);
+        let root = fs.root_inode();
+        let dir = root.create("Long Directory Name", FileType::Dir, 0o777)?;
+        let file1 = dir.create("file1.txt", FileType::File, 0o777)?;
+        let data: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+        assert_eq!(file1.write_at(0, &data)?, data.len());
+        drop((root, dir, file1));
+
+        let fs = _reopen(file, fs);
+        let file1 = fs.root_inode().lookup("long directory name/FILE1.TXT")?;
+        assert_eq!(file1.metadata()?.size, data.len());
+        let mut buf = vec![0u8; data.len() + 10];
+        assert_eq!(file1.read_at(0, &mut buf)?, data.len());
+        assert_eq!(&buf[..data.len()], &data[..]);
+        let dir = fs.root_inode().find("Long Directory Name")?;
+        assert_eq!(dir.list()?, vec![".", "..", "file1.txt"]);
+        assert!(dir.find("..")?.is_same(&*fs.root_inode())?);
+    }
+    Ok(())
+}
+
+#[test]
+fn long_and_short_names() -> Result<()> {
+    let (file, fs) = _create_new_fat(FatType::Fat32, 64 << 20);
+    let root = fs.root_inode();
+    let names = [
+        "README",
+        "lower.rs",
+        "a long name with spaces.tar.gz",
+        "a long name with spaces.tar.bz2",
+        "MixedCase.Txt",
+        "日本語のファイル名",
+    ];
+    for name in names.iter() {
+        root.create(name, FileType::File, 0o777)?;
+    }
+    assert_eq!(
+        root.create("readme", FileType::File, 0o777).err(),
+        Some(FsError::EntryExist)
+    );
+    assert_eq!(
+        root.create("a:b", FileType::File, 0o777).err(),
+        Some(FsError::InvalidParam)
+    );
+    drop(root);
+    let fs = _reopen(file, fs);
+    let root = fs.root_inode();
+    assert_eq!(&root.list()?[2..], &names[..]);
+    // by the short names made for them
+    root.find("ALONGN~1.GZ")?;
+    root.find("alongn~1.bz2")?;
+    root.find("MIXEDC~1.TXT")?;
+    Ok(())
+}
+
+#[test]
+fn resize_and_free_space() -> Result<()> {
+    let (_, fs) = _create_new_fat(FatType::Fat32, 64 << 20);
+    let free = fs.info().bfree;
+    let root = fs.root_inode();
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    file1.write_at(0, &[0xff; 3000])?;
+    file1.resize(100)?;
+    file1.resize(5000)?;
+    let mut buf = [0xaau8; 5000];
+    assert_eq!(file1.read_at(0, &mut buf)?, 5000);
+    assert!(buf[..100].iter().all(|&b| b == 0xff));
+    assert!(buf[100..].iter().all(|&b| b == 0));
+    assert_eq!(fs.info().bfree, free - 10);
+    assert_eq!(root.resize(0), Err(FsError::NotFile));
+    root.unlink("file1")?;
+    // still open
+    assert_eq!(fs.info().bfree, free - 10);
+    assert_eq!(file1.read_at(0, &mut buf)?, 5000);
+    drop(file1);
+    assert_eq!(fs.info().bfree, free);
+    Ok(())
+}
+
+#[test]
+fn unlink_and_move() -> Result<()> {
+    let (file, fs) = _create_new_fat(FatType::Fat32, 64 << 20);
+    let root = fs.root_inode();
+    let dir1 = root.create("dir1", FileType::Dir, 0o777)?;
+    let dir2 = root.create("dir2", FileType::Dir, 0o777)?;
+    let sub = dir1.create("sub", FileType::Dir, 0o777)?;
+    sub.create("file", FileType::File, 0o777)?
+        .write_at(0, b"hello")?;
+    assert_eq!(root.unlink("dir1"), Err(FsError::DirNotEmpty));
+    assert_eq!(
+        dir1.move_("sub", &sub, "sub2"),
+        Err(FsError::InvalidParam),
+        "moved into itself"
+    );
+    dir1.move_("sub", &dir2, "Moved Sub")?;
+    assert!(sub.find("..")?.is_same(&*dir2)?);
+    assert_eq!(dir1.list()?, vec![".", ".."]);
+    root.unlink("dir1")?;
+    // renamed over another file
+    dir2.create("other", FileType::File, 0o777)?;
+    sub.move_("file", &dir2, "OTHER")?;
+    drop((root, dir1, dir2, sub));
+
+    let fs = _reopen(file, fs);
+    let root = fs.root_inode();
+    assert_eq!(root.list()?, vec![".", "..", "dir2"]);
+    let dir2 = root.find("dir2")?;
+    assert_eq!(dir2.list()?, vec![".", "..", "Moved Sub", "OTHER"]);
+    let sub = dir2.find("moved sub")?;
+    assert!(sub.find("..")?.is_same(&*dir2)?);
+    let mut buf = [0u8; 5];
+    root.lookup("dir2/other")?.read_at(0, &mut buf)?;
+    assert_eq!(&buf, b"hello");
+    Ok(())
+}
+
+#[test]
+fn grow_dirs() -> Result<()> {
+    let (file, fs) = _create_new_fat(FatType::Fat16, 16 << 20);
+    let root = fs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    for i in 0..200 {
+        dir.create(
+            &format!("a file with a long name {}", i),
+            FileType::File,
+            0o777,
+        )?;
+    }
+    // the root of FAT16 has a fixed size
+    let mut created = 0;
+    loop {
+        match root.create(&format!("F{}", created), FileType::File, 0o777) {
+            Ok(_) => created += 1,
+            Err(FsError::NoDeviceSpace) => break,
+            Err(err) => return Err(err),
+        }
+    }
+    assert_eq!(created, 511);
+    drop((root, dir));
+    let fs = _reopen(file, fs);
+    let dir = fs.root_inode().find("dir")?;
+    assert_eq!(dir.list()?.len(), 202);
+    dir.find("A FILE WITH A LONG NAME 199")?;
+    Ok(())
+}
@@ -0,0 +1,204 @@
+//! A user-mode rCore FS server
+//!
+//! Serve a whole VFS stack on a Unix socket, without booting rCore:
+//!
+//! ```text
+//! /       ramfs, under mountfs
+//! /dev    devfs with null and zero
+//! /sfs    SFS on <image>, created if it does not exist
+//! ```
+//!
+//! Usage: `cargo run --example server -- <image> <socket>`
+//!
+//! Each request is one line of words separated by spaces, and gets one line of reply:
+//! `ok [<result>]` or `err <FsError>`. Paths are absolute, data is in hex.
+//!
+//! ```text
+//! ls <path>                       -> ok <name> <name> ...
+//! stat <path>                     -> ok <type> <size> <mode in octal> <inode> <nlinks>
+//! mkdir <path> | create <path>    -> ok
+//! rm <path>                       -> ok
+//! mv <path> <new path>            -> ok
+//! read <path> <offset> <len>      -> ok <data>
+//! write <path> <offset> <data>    -> ok <len>
+//! truncate <path> <len>           -> ok
+//! getxattr <path> <name>          -> ok <data>
+//! setxattr <path> <name> <data>   -> ok
+//! sync                            -> ok
+//! ```
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use rcore_fs::dev::std_impl::StdTimeProvider;
+use rcore_fs::vfs::{FileSystem, FileType, FsError, INode, Result};
+use rcore_fs_devfs::{special::*, DevFS};
+use rcore_fs_mountfs::MountFS;
+use rcore_fs_ramfs::RamFS;
+use rcore_fs_sfs::SimpleFileSystem;
+
+/// Size of the SFS image when it is created
+const IMAGE_SIZE: usize = 16 * 1024 * 1024;
+
+fn main() {
+    env_logger::init().unwrap();
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: {} <image> <socket>", args[0]);
+        std::process::exit(1);
+    }
+    let fs = build(Path::new(&args[1])).expect("failed to build the VFS");
+    let root: Arc<dyn INode> = fs.root_inode();
+
+    let _ = std::fs::remove_file(&args[2]);
+    let listener = UnixListener::bind(&args[2]).expect("failed to bind the socket");
+    println!("serving on {}", args[2]);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("failed to accept: {}", e);
+                continue;
+            }
+        };
+        let root = root.clone();
+        let fs = fs.clone();
+        thread::spawn(move || {
+            if let Err(e) = serve(stream, &root, &*fs) {
+                eprintln!("connection closed: {}", e);
+            }
+        });
+    }
+}
+
+/// Mount devfs and SFS on `image` under a ramfs root
+fn build(image: &Path) -> Result<Arc<MountFS>> {
+    let rootfs = MountFS::new(RamFS::with_time(&StdTimeProvider));
+    let root = rootfs.root_inode();
+
+    let devfs = DevFS::new();
+    devfs.add("null", Arc::new(NullINode))?;
+    devfs.add("zero", Arc::new(ZeroINode))?;
+    root.create("dev", FileType::Dir, 0o755)?.mount(devfs)?;
+
+    let exists = image.exists();
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(image)
+        .expect("failed to open the image");
+    let device = Arc::new(Mutex::new(file));
+    let sfs = match exists {
+        true => SimpleFileSystem::open_with_time(device, &StdTimeProvider)?,
+        false => SimpleFileSystem::create_with_time(device, IMAGE_SIZE, 0, &StdTimeProvider)?,
+    };
+    root.create("sfs", FileType::Dir, 0o755)?.mount(sfs)?;
+    Ok(rootfs)
+}
+
+fn serve(stream: UnixStream, root: &Arc<dyn INode>, fs: &dyn FileSystem) -> std::io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let args: Vec<&str> = line.split_whitespace().collect();
+        if args.is_empty() {
+            continue;
+        }
+        let reply = match handle(&args, root, fs) {
+            Ok(result) if result.is_empty() => "ok".into(),
+            Ok(result) => format!("ok {}", result),
+            Err(e) => format!("err {:?}", e),
+        };
+        writeln!(writer, "{}", reply)?;
+    }
+    Ok(())
+}
+
+/// Handle one request, return the result to reply
+fn handle(args: &[&str], root: &Arc<dyn INode>, fs: &dyn FileSystem) -> Result<String> {
+    let lookup = |path: &str| root.lookup(path);
+    match args {
+        ["ls", path] => Ok(lookup(path)?.list()?.join(" ")),
+        ["stat", path] => {
+            let info = lookup(path)?.metadata()?;
+            Ok(format!(
+                "{:?} {} {:o} {} {}",
+                info.type_, info.size, info.mode, info.inode, info.nlinks
+            ))
+        }
+        ["mkdir", path] | ["create", path] => {
+            let (dir, name) = split(path);
+            let type_ = match args[0] {
+                "mkdir" => FileType::Dir,
+                _ => FileType::File,
+            };
+            lookup(dir)?.create(name, type_, 0o755)?;
+            Ok(String::new())
+        }
+        ["rm", path] => {
+            let (dir, name) = split(path);
+            lookup(dir)?.unlink(name)?;
+            Ok(String::new())
+        }
+        ["mv", old, new] => {
+            let (old_dir, old_name) = split(old);
+            let (new_dir, new_name) = split(new);
+            lookup(old_dir)?.move_(old_name, &lookup(new_dir)?, new_name)?;
+            Ok(String::new())
+        }
+        ["read", path, offset, len] => {
+            let mut buf = vec![0; parse(len)?];
+            let len = lookup(path)?.read_at(parse(offset)?, &mut buf)?;
+            Ok(to_hex(&buf[..len]))
+        }
+        ["write", path, offset, data] => {
+            let len = lookup(path)?.write_at(parse(offset)?, &from_hex(data)?)?;
+            Ok(len.to_string())
+        }
+        ["truncate", path, len] => {
+            lookup(path)?.resize(parse(len)?)?;
+            Ok(String::new())
+        }
+        ["getxattr", path, name] => Ok(to_hex(&lookup(path)?.get_xattr(name)?)),
+        ["setxattr", path, name, data] => {
+            lookup(path)?.set_xattr(name, &from_hex(data)?)?;
+            Ok(String::new())
+        }
+        ["sync"] => {
+            fs.sync()?;
+            Ok(String::new())
+        }
+        _ => Err(FsError::InvalidParam),
+    }
+}
+
+/// Split `path` into its parent directory and name
+fn split(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(0) => ("/", &path[1..]),
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => (".", path),
+    }
+}
+
+fn parse(s: &str) -> Result<usize> {
+    s.parse().map_err(|_| FsError::InvalidParam)
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Result<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return Err(FsError::InvalidParam);
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| FsError::InvalidParam))
+        .collect()
+}
@@ -47,7 +47,7 @@ impl VfsFuse {
             nlink: info.nlinks as u32,
             uid: 501, // info.uid as u32,
             gid: 20,  // info.gid as u32,
-            rdev: 0,
+            rdev: info.rdev as u32,
             flags: 0,
         }
     }
@@ -77,6 +77,9 @@ impl VfsFuse {
             vfs::FsError::DirRemoved => ENOENT,
             vfs::FsError::DirNotEmpty => ENOTEMPTY,
             vfs::FsError::WrongFs => EINVAL,
+            vfs::FsError::Corrupted(_) => EIO,
+            vfs::FsError::NotPermitted => EPERM,
+            vfs::FsError::ReadOnlyFs => EROFS,
             _ => EINVAL,
         }
     }
@@ -170,12 +173,28 @@ impl Filesystem for VfsFuse {
         parent: u64,
         name: &OsStr,
         mode: u32,
-        _rdev: u32,
+        rdev: u32,
         reply: ReplyEntry,
     ) {
         let name = name.to_str().unwrap();
+        let type_ = match mode & libc::S_IFMT {
+            libc::S_IFIFO => vfs::FileType::NamedPipe,
+            libc::S_IFSOCK => vfs::FileType::Socket,
+            libc::S_IFCHR => vfs::FileType::CharDevice,
+            libc::S_IFBLK => vfs::FileType::BlockDevice,
+            _ => vfs::FileType::File,
+        };
         let inode = try_vfs!(reply, self.get_inode(parent));
-        let target = try_vfs!(reply, inode.create(name, vfs::FileType::File, mode));
+        let target = match type_ {
+            vfs::FileType::CharDevice | vfs::FileType::BlockDevice => {
+                let (major, minor) = (
+                    vfs::rdev_major(rdev as usize),
+                    vfs::rdev_minor(rdev as usize),
+                );
+                try_vfs!(reply, inode.create_device(name, type_, mode, major, minor))
+            }
+            _ => try_vfs!(reply, inode.create(name, type_, mode)),
+        };
         let info = try_vfs!(reply, target.metadata());
         self.inodes.insert(info.inode, target);
         let attr = Self::trans_attr(info);
@@ -292,20 +311,22 @@ impl Filesystem for VfsFuse {
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
+        const BATCH: usize = 64;
         let inode = try_vfs!(reply, self.get_inode(ino));
-        for i in offset as usize.. {
-            let name = match inode.get_entry(i) {
-                Ok(name) => name,
-                Err(vfs::FsError::EntryNotFound) => break,
-                e @ _ => try_vfs!(reply, e),
-            };
-            let inode = try_vfs!(reply, inode.find(name.as_str()));
-            let info = try_vfs!(reply, inode.metadata());
-            let kind = Self::trans_type(info.type_);
-            let full = reply.add(info.inode as u64, i as i64 + 1, kind, name);
-            if full {
+        let mut i = offset as usize;
+        'outer: loop {
+            let entries = try_vfs!(reply, inode.get_entries_with_metadata(i, BATCH));
+            if entries.is_empty() {
                 break;
             }
+            for (info, name) in entries {
+                i += 1;
+                let kind = Self::trans_type(info.type_);
+                let full = reply.add(info.inode as u64, i as i64, kind, name);
+                if full {
+                    break 'outer;
+                }
+            }
         }
         reply.ok();
     }
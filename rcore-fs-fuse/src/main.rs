@@ -1,19 +1,31 @@
-use std::fs::OpenOptions;
+use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
 use std::io::SeekFrom;
-use std::path::PathBuf;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
 use structopt::StructOpt;
 
+use log::debug;
+use rcore_fs::dev::cached::CachedBlockDevice;
+use rcore_fs::dev::eviction::EvictionPolicy;
+use rcore_fs::dev::instrumented::InstrumentedDevice;
+use rcore_fs::dev::latency::{LatencyDevice, LatencyProfile};
+use rcore_fs::dev::partition::{partitions, PartitionDevice};
 use rcore_fs::dev::std_impl::StdTimeProvider;
+#[cfg(feature = "uring")]
+use rcore_fs::dev::std_impl::UringDevice;
+use rcore_fs::dev::Device;
 use rcore_fs::vfs::FileSystem;
+use rcore_fs_ext2 as ext2;
+use rcore_fs_fat32 as fat32;
 #[cfg(feature = "use_fuse")]
 use rcore_fs_fuse::fuse::VfsFuse;
-use log::debug;
-use rcore_fs_fuse::zip::{unzip_dir, zip_dir, zip_dir2, pressure_test};
-use rcore_fs_sfs as sfs;
+use rcore_fs_fuse::zip::{pressure_test, unzip_dir_with, zip_dir, zip_dir2};
+use rcore_fs_iso9660 as iso9660;
 use rcore_fs_lfs as lfs;
+use rcore_fs_sfs as sfs;
 
 use git_version::git_version;
 
@@ -31,9 +43,101 @@ struct Opt {
     #[structopt(parse(from_os_str))]
     dir: PathBuf,
 
-    /// File system: [sfs | sefs | ramfs]
+    /// File system: [sfs | lfs | fat32 | ext2 | iso9660]
     #[structopt(short = "f", long = "fs", default_value = "sfs")]
     fs: String,
+
+    /// Zero blocks when they are freed (sfs only)
+    #[structopt(long = "zero-on-free")]
+    zero_on_free: bool,
+
+    /// Bytes of space per inode in the inode table, 0 for no inode table (sfs only)
+    #[structopt(long = "inode-ratio", default_value = "0")]
+    inode_ratio: usize,
+
+    /// Blocks of the metadata journal at the end of a new image, 0 for no journal (sfs only)
+    #[structopt(long = "journal", default_value = "0")]
+    journal: usize,
+
+    /// Bytes of a new image, with an optional suffix K, M, G or T (sfs, lfs and fat32 only)
+    #[structopt(
+        long = "size",
+        default_value = "16G",
+        parse(try_from_str = "parse_size")
+    )]
+    size: usize,
+
+    /// Percent of the blocks of a new image reserved for files owned by root (sfs only)
+    #[structopt(long = "reserved-percent", default_value = "5")]
+    reserved_percent: usize,
+
+    /// Blocks of a segment of a new image (lfs only)
+    #[structopt(long = "segment-blocks", default_value = "1024")]
+    segment_blocks: usize,
+
+    /// Look up names in a new image case-insensitively, folding [ascii | utf8] (sfs only)
+    #[structopt(long = "case-fold", parse(try_from_str = "parse_case_fold"))]
+    case_fold: Option<sfs::CaseFold>,
+
+    /// Max bytes of file data buffered when unzipping
+    #[structopt(long = "mem-limit", default_value = "1048576")]
+    mem_limit: usize,
+
+    /// Emulate the speed of storage: [none | sd | nvme]
+    #[structopt(
+        long = "latency",
+        default_value = "none",
+        parse(try_from_str = "parse_latency")
+    )]
+    latency: LatencyProfile,
+
+    /// Use partition <n> of a disk image with MBR or GPT, 0 for the whole image
+    #[structopt(short = "p", long = "partition", default_value = "0")]
+    partition: usize,
+
+    /// Print I/O statistics of the image when done
+    #[structopt(name = "io-stats", long = "stats")]
+    stats: bool,
+
+    /// Bytes reserved at the start of the image for boot data, the fs follows them
+    #[structopt(long = "boot-size", default_value = "0")]
+    boot_size: usize,
+
+    /// Keep boot data after the blocks of the fs instead, till the end of the image
+    #[structopt(long = "boot-at-end")]
+    boot_at_end: bool,
+}
+
+fn parse_latency(name: &str) -> Result<LatencyProfile, String> {
+    match name {
+        "none" => Ok(LatencyProfile::NONE),
+        "sd" => Ok(LatencyProfile::SD_CARD),
+        "nvme" => Ok(LatencyProfile::NVME),
+        _ => Err(format!("unknown latency profile: {}", name)),
+    }
+}
+
+fn parse_size(size: &str) -> Result<usize, String> {
+    let (digits, shift) = match size.chars().last() {
+        Some('K') | Some('k') => (&size[..size.len() - 1], 10),
+        Some('M') | Some('m') => (&size[..size.len() - 1], 20),
+        Some('G') | Some('g') => (&size[..size.len() - 1], 30),
+        Some('T') | Some('t') => (&size[..size.len() - 1], 40),
+        _ => (size, 0),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("invalid size: {}", size))
+}
+
+fn parse_case_fold(name: &str) -> Result<sfs::CaseFold, String> {
+    match name {
+        "ascii" => Ok(sfs::CaseFold::Ascii),
+        "utf8" => Ok(sfs::CaseFold::Utf8),
+        _ => Err(format!("unknown case folding: {}", name)),
+    }
 }
 
 #[derive(Debug, StructOpt)]
@@ -50,6 +154,56 @@ enum Cmd {
     #[structopt(name = "unzip")]
     Unzip,
 
+    /// Zero free space and deleted data in <image>
+    #[structopt(name = "sanitize")]
+    Sanitize,
+
+    /// Copy the boot area of <image> to file <dir>
+    #[structopt(name = "read-boot")]
+    ReadBoot,
+
+    /// Copy file <dir> into the boot area of <image>
+    #[structopt(name = "write-boot")]
+    WriteBoot,
+
+    /// Check <image> for problems, and repair them if they can be
+    #[structopt(name = "fsck")]
+    Fsck {
+        /// Repair the problems found
+        #[structopt(long = "repair")]
+        repair: bool,
+        /// Make the imap again from the blocks of inodes before checking (lfs only)
+        #[structopt(long = "rebuild-imap")]
+        rebuild_imap: bool,
+    },
+
+    /// Grow <image> to <size> bytes, with an optional suffix K, M, G or T (sfs only)
+    #[structopt(name = "resize")]
+    Resize {
+        #[structopt(parse(try_from_str = "parse_size"))]
+        size: usize,
+    },
+
+    /// Pack the inodes of <image> in its inode table, several in a block (sfs only)
+    #[structopt(name = "pack-inodes")]
+    PackInodes,
+
+    /// Move the blocks of each file of <image> next to each other (sfs only)
+    #[structopt(name = "defrag")]
+    Defrag,
+
+    /// Clean up to <segments> segments of the log of <image>, freeing them (lfs only)
+    #[structopt(name = "clean")]
+    Clean { segments: usize },
+
+    /// Move the live data of <image> to its front and cut off the segments after it (lfs only)
+    #[structopt(name = "compact")]
+    Compact,
+
+    /// Print how full the log of <image> is, by segment, and what was written to it (lfs only)
+    #[structopt(name = "stats")]
+    Stats,
+
     /// Mount <image> to <dir>
     #[cfg(feature = "use_fuse")]
     #[structopt(name = "mount")]
@@ -59,6 +213,77 @@ enum Cmd {
     GitVersion,
 }
 
+/// `<path>.tmp` in the same directory, so that it can be renamed to `path` atomically
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().expect("invalid image path").to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+/// The image file, R/W by io_uring if enabled
+#[cfg(not(feature = "uring"))]
+type Disk = Mutex<File>;
+#[cfg(feature = "uring")]
+type Disk = UringDevice;
+
+#[cfg(not(feature = "uring"))]
+fn disk(file: File) -> Disk {
+    Mutex::new(file)
+}
+
+#[cfg(feature = "uring")]
+fn disk(file: File) -> Disk {
+    UringDevice::new(file).expect("failed to set up io_uring")
+}
+
+/// Bytes of the image in partition `opt.partition`
+fn disk_range(opt: &Opt, disk: &dyn Device) -> Range<usize> {
+    match opt.partition {
+        0 => 0..usize::max_value(),
+        n => {
+            let partition = partitions(disk)
+                .expect("failed to read partition table")
+                .into_iter()
+                .find(|partition| partition.index == n)
+                .expect("partition not found");
+            partition.begin..partition.begin + partition.size
+        }
+    }
+}
+
+/// Open the fs part of `path`, in partition `opt.partition` and after the boot area
+fn open_disk(opt: &Opt, path: &Path, create: bool, writable: bool) -> PartitionDevice<Disk> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(writable)
+        .create(create)
+        .truncate(create)
+        .open(path)
+        .expect("failed to open image");
+    let file = disk(file);
+    let mut range = disk_range(opt, &file);
+    if !opt.boot_at_end {
+        range.start += opt.boot_size;
+    }
+    PartitionDevice::range(file, range)
+}
+
+/// Bytes of the image reserved for boot data
+fn boot_area(opt: &Opt, fs: &dyn FileSystem) -> Range<u64> {
+    let file = File::open(&opt.image).expect("failed to open image");
+    let disk = disk_range(opt, &Mutex::new(file));
+    let area = match opt.boot_at_end {
+        true => {
+            let info = fs.info();
+            let begin = disk.start + info.blocks * info.frsize;
+            begin..disk.end
+        }
+        false if opt.boot_size == 0 => panic!("no boot area, set it by --boot-size"),
+        false => disk.start..disk.start + opt.boot_size,
+    };
+    area.start as u64..area.end as u64
+}
+
 fn main() {
     debug!("modified in aoslab, supporting lfs");
     env_logger::init().unwrap();
@@ -70,47 +295,151 @@ fn main() {
         Cmd::Mount => !opt.image.is_dir() && !opt.image.is_file(),
         Cmd::Zip => true,
         Cmd::Unzip => false,
+        Cmd::Sanitize => false,
+        Cmd::ReadBoot | Cmd::WriteBoot => false,
+        Cmd::Fsck { .. } | Cmd::Resize { .. } | Cmd::PackInodes | Cmd::Defrag => false,
+        Cmd::Clean { .. } | Cmd::Compact | Cmd::Stats => false,
         Cmd::Test => true,
         Cmd::GitVersion => {
             println!("{}", git_version!());
             return;
         }
     };
+    let writable = match opt.cmd {
+        #[cfg(feature = "use_fuse")]
+        Cmd::Mount => true,
+        Cmd::Sanitize | Cmd::WriteBoot | Cmd::Resize { .. } | Cmd::PackInodes | Cmd::Defrag => true,
+        Cmd::Clean { .. } | Cmd::Compact => true,
+        Cmd::Fsck {
+            repair,
+            rebuild_imap,
+        } => repair || rebuild_imap,
+        _ => create,
+    };
+    if create && opt.partition != 0 {
+        panic!("can not create a partition, make it with a partition tool first");
+    }
+    // a new image is built aside, and only replaces <image> when complete
+    let image = match opt.cmd {
+        Cmd::Zip => temp_path(&opt.image),
+        _ => opt.image.clone(),
+    };
 
+    // counters of the device, shared by the cache and the fs
+    let stats;
+    // for commands only for sfs
+    let mut simple_fs = None;
+    // for commands only for lfs
+    let mut log_fs = None;
     let fs: Arc<dyn FileSystem> = match opt.fs.as_str() {
         "sfs" => {
-            let file = OpenOptions::new()
-                .read(true)
-                .write(create)
-                .create(create)
-                .truncate(create)
-                .open(&opt.image)
-                .expect("failed to open image");
-            let device = Mutex::new(file);
-            const MAX_SPACE: usize = 0x1000 * 0x1000 * 1024; // 1G
-            match create {
-                true => sfs::SimpleFileSystem::create(Arc::new(device), MAX_SPACE)
-                    .expect("failed to create sfs"),
-                false => sfs::SimpleFileSystem::open(Arc::new(device)).expect("failed to open sfs"),
+            let disk = open_disk(&opt, &image, create, writable);
+            const CACHE_BLOCKS: usize = 0x1000; // 16M
+            let device = CachedBlockDevice::new(
+                InstrumentedDevice::new(LatencyDevice::new(disk, opt.latency), StdTimeProvider),
+                sfs::BLKSIZE_LOG2,
+                CACHE_BLOCKS,
+                EvictionPolicy::Lru,
+            );
+            stats = device.stats();
+            let sfs = match create {
+                true => sfs::SimpleFileSystem::create_with_journal(
+                    Arc::new(device),
+                    opt.size,
+                    opt.inode_ratio,
+                    opt.journal,
+                    &StdTimeProvider,
+                )
+                .expect("failed to create sfs"),
+                false => sfs::SimpleFileSystem::open_with_time(Arc::new(device), &StdTimeProvider)
+                    .expect("failed to open sfs"),
+            };
+            if create {
+                let blocks = opt.size / sfs::BLKSIZE * opt.reserved_percent / 100;
+                sfs.set_reserved_blocks(blocks)
+                    .expect("failed to reserve blocks");
+                sfs.set_case_fold(opt.case_fold)
+                    .expect("failed to set case folding");
             }
+            sfs.set_zero_on_free(opt.zero_on_free);
+            simple_fs = Some(sfs.clone());
+            sfs
         }
         "lfs" => {
-            let file = OpenOptions::new()
-                .read(true)
-                .write(create)
-                .create(create)
-                .truncate(create)
-                .open(&opt.image)
-                .expect("failed to open image");
-            let device = Mutex::new(file);
-            const MAX_SPACE: usize = 128 * 1024 * 1024; // 128MB
-            // const MAX_SPACE: usize = 1024 * 1024 * 1024; // 1GB
-            // const MAX_SPACE: usize = 16 * 1024 * 1024; // 16MB
+            let disk = open_disk(&opt, &image, create, writable);
+            let device =
+                InstrumentedDevice::new(LatencyDevice::new(disk, opt.latency), StdTimeProvider);
+            stats = device.stats();
+            let lfs = match create {
+                true => {
+                    let geometry = lfs::Geometry::new(opt.segment_blocks);
+                    lfs::LogFileSystem::create_with_geometry(
+                        Arc::new(device),
+                        opt.size,
+                        geometry,
+                        &StdTimeProvider,
+                    )
+                    .expect("failed to create lfs")
+                }
+                false => lfs::LogFileSystem::open_with_time(Arc::new(device), &StdTimeProvider)
+                    .expect("failed to open lfs"),
+            };
+            log_fs = Some(lfs.clone());
+            lfs
+        }
+        "fat32" => {
+            let disk = open_disk(&opt, &image, create, writable);
+            const CACHE_SECTORS: usize = 0x8000; // 16M
+            let device = CachedBlockDevice::new(
+                InstrumentedDevice::new(LatencyDevice::new(disk, opt.latency), StdTimeProvider),
+                9,
+                CACHE_SECTORS,
+                EvictionPolicy::Lru,
+            );
+            stats = device.stats();
             match create {
-                true => lfs::LogFileSystem::create(Arc::new(device), MAX_SPACE)
-                    .expect("failed to create lfs"),
-                false => lfs::LogFileSystem::open(Arc::new(device)).expect("failed to open lfs"),
+                true => fat32::FatFileSystem::create_with_type(
+                    Arc::new(device),
+                    opt.size,
+                    fat32::FatType::Fat32,
+                    &StdTimeProvider,
+                )
+                .expect("failed to create fat32"),
+                false => fat32::FatFileSystem::open_with_time(Arc::new(device), &StdTimeProvider)
+                    .expect("failed to open fat32"),
+            }
+        }
+        "ext2" => {
+            if create {
+                panic!("can not create ext2, make it with mke2fs first");
             }
+            let disk = open_disk(&opt, &image, create, writable);
+            const CACHE_SECTORS: usize = 0x8000; // 16M
+            let device = CachedBlockDevice::new(
+                InstrumentedDevice::new(LatencyDevice::new(disk, opt.latency), StdTimeProvider),
+                9,
+                CACHE_SECTORS,
+                EvictionPolicy::Lru,
+            );
+            stats = device.stats();
+            ext2::Ext2FileSystem::open_with_time(Arc::new(device), &StdTimeProvider)
+                .expect("failed to open ext2")
+        }
+        "iso9660" => {
+            if create {
+                panic!("can not create iso9660, make it with mkisofs first");
+            }
+            // read-only, so the image is never opened for writing
+            let disk = open_disk(&opt, &image, false, false);
+            const CACHE_SECTORS: usize = 0x2000; // 16M
+            let device = CachedBlockDevice::new(
+                InstrumentedDevice::new(LatencyDevice::new(disk, opt.latency), StdTimeProvider),
+                11,
+                CACHE_SECTORS,
+                EvictionPolicy::Lru,
+            );
+            stats = device.stats();
+            iso9660::Iso9660FileSystem::open(Arc::new(device)).expect("failed to open iso9660")
         }
         _ => panic!("unsupported file system"),
     };
@@ -125,20 +454,208 @@ fn main() {
         }
         Cmd::Zip => {
             debug!("fuse ready to zip");
-            zip_dir(&opt.dir, fs.root_inode()).expect("failed to zip fs");
+            let result = zip_dir(&opt.dir, fs.root_inode()).and_then(|_| Ok(fs.sync()?));
             // zip_dir2(&opt.dir, fs.root_inode(), 0).expect("failed to zip fs");
+            // write back everything before publishing
+            drop(fs);
+            if let Err(e) = result.and_then(|_| Ok(std::fs::rename(&image, &opt.image)?)) {
+                std::fs::remove_file(&image).ok();
+                eprintln!("failed to zip fs: {}", e);
+                std::process::exit(1);
+            }
             debug!("fuse zip done");
         }
         Cmd::Test => {
+            let start = std::time::Instant::now();
             pressure_test(&opt.dir, fs.root_inode()).expect("fs test failed");
-            println!("test FS done");
+            fs.sync().expect("failed to sync fs");
+            println!("test FS done in {:?}", start.elapsed());
         }
         Cmd::Unzip => {
             std::fs::create_dir(&opt.dir).expect("failed to create dir");
-            unzip_dir(&opt.dir, fs.root_inode()).expect("failed to unzip fs");
+            let mut entries = 0usize;
+            let mut progress = |path: &std::path::Path, bytes: u64| {
+                entries += 1;
+                debug!(
+                    "unzipped {} entries, {} bytes: {}",
+                    entries,
+                    bytes,
+                    path.display()
+                );
+            };
+            unzip_dir_with(&opt.dir, fs.root_inode(), opt.mem_limit, &mut progress)
+                .expect("failed to unzip fs");
             debug!("fuse unzip done");
         }
+        Cmd::Sanitize => {
+            let count = fs.wipe_free_space().expect("failed to sanitize fs");
+            println!("sanitize done, {} free blocks zeroed", count);
+        }
+        Cmd::ReadBoot => {
+            let area = boot_area(&opt, &*fs);
+            let mut file = File::open(&opt.image).expect("failed to open image");
+            file.seek(SeekFrom::Start(area.start))
+                .expect("failed to read boot area");
+            let mut data = Vec::new();
+            file.take(area.end - area.start)
+                .read_to_end(&mut data)
+                .expect("failed to read boot area");
+            std::fs::write(&opt.dir, &data).expect("failed to write boot file");
+            println!("read {} bytes of boot data", data.len());
+        }
+        Cmd::WriteBoot => {
+            let area = boot_area(&opt, &*fs);
+            let data = std::fs::read(&opt.dir).expect("failed to read boot file");
+            if data.len() as u64 > area.end - area.start {
+                eprintln!(
+                    "boot data of {} bytes does not fit in the boot area",
+                    data.len()
+                );
+                std::process::exit(1);
+            }
+            drop(fs);
+            let mut file = OpenOptions::new()
+                .write(true)
+                .open(&opt.image)
+                .expect("failed to open image");
+            file.seek(SeekFrom::Start(area.start))
+                .and_then(|_| file.write_all(&data))
+                .expect("failed to write boot area");
+            println!("wrote {} bytes of boot data", data.len());
+        }
+        Cmd::Fsck {
+            repair,
+            rebuild_imap,
+        } => {
+            let (problems, repaired) = match (simple_fs.take(), log_fs.take()) {
+                (Some(simple_fs), _) if !rebuild_imap => {
+                    let report = sfs::fsck::check(&simple_fs, repair).expect("failed to check sfs");
+                    let problems: Vec<_> =
+                        report.problems.iter().map(|p| format!("{:?}", p)).collect();
+                    (problems, report.repaired)
+                }
+                (_, Some(log_fs)) => {
+                    if rebuild_imap {
+                        let found =
+                            lfs::fsck::rebuild_imap(&log_fs).expect("failed to rebuild imap");
+                        println!("imap rebuilt, {} inodes found", found);
+                    }
+                    let report = lfs::fsck::check(&log_fs, repair).expect("failed to check lfs");
+                    let problems: Vec<_> =
+                        report.problems.iter().map(|p| format!("{:?}", p)).collect();
+                    (problems, report.repaired)
+                }
+                _ => {
+                    eprintln!("--rebuild-imap is only for lfs");
+                    std::process::exit(1);
+                }
+            };
+            for problem in problems.iter() {
+                println!("{}", problem);
+            }
+            let left = match repaired {
+                true => 0,
+                false => problems.len(),
+            };
+            println!(
+                "fsck done, {} problems found, {} repaired",
+                problems.len(),
+                problems.len() - left
+            );
+            drop(fs);
+            if left != 0 {
+                std::process::exit(1);
+            }
+        }
+        Cmd::Resize { size } => {
+            let simple_fs = simple_fs.take().unwrap_or_else(|| {
+                eprintln!("resize is only for sfs");
+                std::process::exit(1);
+            });
+            if let Err(e) = simple_fs.resize(size) {
+                eprintln!("failed to resize fs: {}", e);
+                std::process::exit(1);
+            }
+            println!("resize done, {} blocks", fs.info().blocks);
+        }
+        Cmd::PackInodes => {
+            let simple_fs = simple_fs.take().unwrap_or_else(|| {
+                eprintln!("pack-inodes is only for sfs");
+                std::process::exit(1);
+            });
+            if let Err(e) = simple_fs.pack_inodes() {
+                eprintln!("failed to pack inodes: {}", e);
+                std::process::exit(1);
+            }
+            println!("pack-inodes done, {} inodes free", fs.info().ffree);
+        }
+        Cmd::Defrag => {
+            let simple_fs = simple_fs.take().unwrap_or_else(|| {
+                eprintln!("defrag is only for sfs");
+                std::process::exit(1);
+            });
+            let report = simple_fs
+                .defragment(|done, total| {
+                    if done % 1000 == 0 || done == total {
+                        eprint!("\r{}/{} files", done, total);
+                    }
+                })
+                .unwrap_or_else(|e| {
+                    eprintln!("failed to defragment: {}", e);
+                    std::process::exit(1);
+                });
+            eprintln!();
+            println!(
+                "defrag done, {} of {} files moved, {} blocks, {} left fragmented, {} shared",
+                report.moved, report.files, report.blocks, report.fragmented, report.shared
+            );
+        }
+        Cmd::Clean { segments } => {
+            let log_fs = log_fs.take().unwrap_or_else(|| {
+                eprintln!("clean is only for lfs");
+                std::process::exit(1);
+            });
+            let cleaned = log_fs.clean(segments).unwrap_or_else(|e| {
+                eprintln!("failed to clean: {}", e);
+                std::process::exit(1);
+            });
+            println!(
+                "clean done, {} segments cleaned, {} free",
+                cleaned,
+                log_fs.free_segments()
+            );
+        }
+        Cmd::Compact => {
+            let log_fs = log_fs.take().unwrap_or_else(|| {
+                eprintln!("compact is only for lfs");
+                std::process::exit(1);
+            });
+            let size = log_fs.compact().unwrap_or_else(|e| {
+                eprintln!("failed to compact: {}", e);
+                std::process::exit(1);
+            });
+            // a partition, or a boot area after the fs, is left where it is
+            if opt.partition == 0 && !opt.boot_at_end {
+                let file = OpenOptions::new()
+                    .write(true)
+                    .open(&opt.image)
+                    .expect("failed to open image");
+                file.set_len((opt.boot_size + size) as u64)
+                    .expect("failed to truncate image");
+            }
+            println!("compact done, {} blocks, {} bytes", fs.info().blocks, size);
+        }
+        Cmd::Stats => {
+            let log_fs = log_fs.take().unwrap_or_else(|| {
+                eprintln!("stats is only for lfs");
+                std::process::exit(1);
+            });
+            print!("{}", log_fs.stats());
+        }
         Cmd::GitVersion => unreachable!(),
     }
+    if let (true, Some(stats)) = (opt.stats, stats) {
+        print!("{}", stats.snapshot());
+    }
     debug!("fuse all done");
 }
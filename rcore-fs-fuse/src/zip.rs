@@ -6,24 +6,31 @@ use std::io::{Read, Write};
 use std::mem::MaybeUninit;
 #[cfg(unix)]
 use std::os::unix::ffi::OsStrExt;
+#[cfg(unix)]
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
 use std::path::Path;
 use std::str;
 use std::sync::Arc;
 
-use log::debug;
-use rcore_fs::vfs::{FileSystem, FileType, INode};
+use log::{debug, warn};
+use rcore_fs::vfs::{self, FileSystem, FileType, FsError, FsFeatures, INode};
 
 const DEFAULT_MODE: u32 = 0o664;
 const BUF_SIZE: usize = 0x1000;
 
 pub fn zip_dir(path: &Path, inode: Arc<dyn INode>) -> Result<(), Box<dyn Error>> {
     debug!("into zip dir:{}", path.display());
+    let caps = inode.fs().capabilities();
     let dir = fs::read_dir(path)?;
     for entry in dir {
         let entry = entry?;
         let name_ = entry.file_name();
         let name = name_.to_str().unwrap();
         let type_ = entry.file_type()?;
+        if name.len() > caps.namemax {
+            warn!("skip {:?}: name is too long", entry.path());
+            continue;
+        }
         if type_.is_file() {
             let mut file = fs::File::open(entry.path())?;
             debug!("processing file {:?} len: {}", entry.path(), file.metadata()?.len());
@@ -43,6 +50,10 @@ pub fn zip_dir(path: &Path, inode: Arc<dyn INode>) -> Result<(), Box<dyn Error>>
             let inode = inode.create(name, FileType::Dir, DEFAULT_MODE)?;
             zip_dir(entry.path().as_path(), inode)?;
         } else if type_.is_symlink() {
+            if !caps.features.contains(FsFeatures::SYMLINK) {
+                warn!("skip {:?}: symlinks are not supported", entry.path());
+                continue;
+            }
             let target = fs::read_link(entry.path())?;
             let inode = inode.create(name, FileType::SymLink, DEFAULT_MODE)?;
             #[cfg(unix)]
@@ -51,12 +62,76 @@ pub fn zip_dir(path: &Path, inode: Arc<dyn INode>) -> Result<(), Box<dyn Error>>
             let data = target.to_str().unwrap().as_bytes();
             inode.resize(data.len())?;
             inode.write_at(0, data)?;
+        } else if let Some((type_, major, minor)) = device(&entry)? {
+            match inode.create_device(name, type_, DEFAULT_MODE, major, minor) {
+                Ok(_) => {}
+                Err(FsError::NotSupported) | Err(FsError::InvalidParam) => {
+                    warn!("skip {:?}: devices are not supported", entry.path());
+                }
+                Err(e) => return Err(e.into()),
+            }
         }
     }
 
     Ok(())
 }
 
+/// Type and (major, minor) of `entry` if it is a char or block device
+#[cfg(unix)]
+fn device(entry: &fs::DirEntry) -> Result<Option<(FileType, usize, usize)>, Box<dyn Error>> {
+    let type_ = entry.file_type()?;
+    let type_ = if type_.is_char_device() {
+        FileType::CharDevice
+    } else if type_.is_block_device() {
+        FileType::BlockDevice
+    } else {
+        return Ok(None);
+    };
+    // glibc's encoding of dev_t
+    let rdev = entry.metadata()?.rdev();
+    let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+    let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+    Ok(Some((type_, major as usize, minor as usize)))
+}
+
+#[cfg(not(unix))]
+fn device(_entry: &fs::DirEntry) -> Result<Option<(FileType, usize, usize)>, Box<dyn Error>> {
+    Ok(None)
+}
+
+/// Make device node `path` as `meta` of the VFS tells.
+/// Return whether it is made, which needs privilege.
+#[cfg(unix)]
+fn make_device(path: &Path, meta: &vfs::Metadata) -> Result<bool, Box<dyn Error>> {
+    let major = vfs::rdev_major(meta.rdev) as u64;
+    let minor = vfs::rdev_minor(meta.rdev) as u64;
+    // glibc's encoding of dev_t
+    let dev = ((major & !0xfff) << 32)
+        | ((major & 0xfff) << 8)
+        | ((minor & !0xff) << 12)
+        | (minor & 0xff);
+    let mode = match meta.type_ {
+        FileType::BlockDevice => libc::S_IFBLK,
+        _ => libc::S_IFCHR,
+    } | (meta.mode as libc::mode_t & 0o7777);
+    let cpath = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    match unsafe { libc::mknod(cpath.as_ptr(), mode, dev as libc::dev_t) } {
+        0 => Ok(true),
+        _ => {
+            let err = std::io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::EPERM) => Ok(false),
+                _ => Err(err.into()),
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn make_device(_path: &Path, _meta: &vfs::Metadata) -> Result<bool, Box<dyn Error>> {
+    Ok(false)
+}
+
 pub fn zip_dir2(path: &Path, inode: Arc<dyn INode>, depth: usize) -> Result<(), Box<dyn Error>> {
     debug!("fuse: finish creating img");
     inode.ls();
@@ -125,51 +200,94 @@ pub trait INodeExt {
 
 impl INodeExt for dyn INode {
     fn ls(&self) {
-        let mut id = 0;
-        while let Ok(name) = self.get_entry(id) {
+        let mut cookie = 0;
+        while let Ok((name, next)) = self.get_entry_from(cookie) {
             println!("{}", name);
-            id += 1;
+            cookie = next;
         }
         println!("");
     }
 }
 
 pub fn unzip_dir(path: &Path, inode: Arc<dyn INode>) -> Result<(), Box<dyn Error>> {
-    debug!("into unzip dir:{}", path.display());
-    let files = inode.list()?;
-    inode.ls();
-    for name in files.iter().skip(2) {
+    unzip_dir_with(path, inode, BUF_SIZE, &mut |_, _| {})
+}
+
+/// Unzip the tree under `inode` to `path`.
+///
+/// The tree is walked iteratively, so only the current branch is kept in memory.
+/// File data is copied through a buffer of at most `mem_limit` bytes.
+/// `progress` is called after each entry with its path and the total bytes copied so far.
+pub fn unzip_dir_with(
+    path: &Path,
+    inode: Arc<dyn INode>,
+    mem_limit: usize,
+    progress: &mut dyn FnMut(&Path, u64),
+) -> Result<(), Box<dyn Error>> {
+    let mut buf = vec![0u8; mem_limit.max(1)];
+    let mut copied = 0u64;
+    // (dir inode, dir path, cookie of the next entry)
+    let mut stack = vec![(inode, path.to_path_buf(), 0usize)];
+    while let Some((dir, dir_path, cookie)) = stack.last_mut() {
+        let name = match dir.get_entry_from(*cookie) {
+            Ok((name, next)) => {
+                *cookie = next;
+                name
+            }
+            Err(FsError::EntryNotFound) => {
+                debug!("unzip dir {} done", dir_path.display());
+                stack.pop();
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if name == "." || name == ".." {
+            continue;
+        }
         debug!("processing file {}", name);
-        let inode = inode.lookup(name.as_str())?;
-        let mut path = path.to_path_buf();
-        path.push(name);
-        let info = inode.metadata()?;
-        match info.type_ {
+        let inode = dir.find(&name)?;
+        let path = dir_path.join(&name);
+        let meta = inode.metadata()?;
+        match meta.type_ {
             FileType::File => {
                 let mut file = fs::File::create(&path)?;
-                let mut buf: [u8; BUF_SIZE] = unsafe { MaybeUninit::uninit().assume_init() };
                 let mut offset = 0usize;
-                let mut len = BUF_SIZE;
-                while len == BUF_SIZE {
-                    len = inode.read_at(offset, buf.as_mut())?;
-                    file.write(&buf[..len])?;
+                loop {
+                    let len = inode.read_at(offset, &mut buf)?;
+                    if len == 0 {
+                        break;
+                    }
+                    file.write_all(&buf[..len])?;
                     offset += len;
+                    copied += len as u64;
                 }
             }
             FileType::Dir => {
                 fs::create_dir(&path)?;
-                unzip_dir(path.as_path(), inode)?;
+                progress(&path, copied);
+                stack.push((inode, path, 0));
+                continue;
             }
             FileType::SymLink => {
                 let mut buf: [u8; BUF_SIZE] = unsafe { MaybeUninit::uninit().assume_init() };
                 let len = inode.read_at(0, buf.as_mut())?;
                 #[cfg(unix)]
-                std::os::unix::fs::symlink(str::from_utf8(&buf[..len]).unwrap(), path)?;
+                std::os::unix::fs::symlink(str::from_utf8(&buf[..len]).unwrap(), &path)?;
                 #[cfg(windows)]
-                std::os::windows::fs::symlink_file(str::from_utf8(&buf[..len]).unwrap(), path)?;
+                std::os::windows::fs::symlink_file(str::from_utf8(&buf[..len]).unwrap(), &path)?;
+            }
+            FileType::CharDevice | FileType::BlockDevice => {
+                if !make_device(&path, &meta)? {
+                    warn!("skip {:?}: no privilege to make a device", path);
+                    continue;
+                }
+            }
+            type_ => {
+                warn!("skip {:?}: unsupported file type {:?}", path, type_);
+                continue;
             }
-            _ => panic!("unsupported file type"),
         }
+        progress(&path, copied);
     }
     Ok(())
 }
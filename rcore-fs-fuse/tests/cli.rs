@@ -0,0 +1,408 @@
+//! The command line tool.
+//!
+//! Each test makes a tree on the host, zips it into a new image with the tool, and unzips
+//! the image again, checking what comes out against what went in.
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::{Arc, Mutex};
+
+use rcore_fs::dev::{self, Device};
+use rcore_fs::vfs::{FileSystem, FileType};
+use rcore_fs_sfs::{SimpleFileSystem, BLKSIZE};
+use tempfile::TempDir;
+
+/// An entry of a tree on the host
+#[derive(Debug, PartialEq, Eq)]
+enum Entry {
+    File(Vec<u8>),
+    Dir,
+    SymLink(PathBuf),
+}
+
+/// All entries under `root`, by their paths relative to it
+fn tree(root: &Path) -> BTreeMap<PathBuf, Entry> {
+    let mut entries = BTreeMap::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            let type_ = fs::symlink_metadata(&path).unwrap().file_type();
+            let entry = if type_.is_symlink() {
+                Entry::SymLink(fs::read_link(&path).unwrap())
+            } else if type_.is_dir() {
+                dirs.push(path.clone());
+                Entry::Dir
+            } else {
+                Entry::File(fs::read(&path).unwrap())
+            };
+            entries.insert(path.strip_prefix(root).unwrap().to_path_buf(), entry);
+        }
+    }
+    entries
+}
+
+/// Make a small tree under `root`, with a file in a subdir and a symlink to it
+fn make_tree(root: &Path) {
+    fs::create_dir(root).unwrap();
+    fs::write(root.join("hello"), b"hello, world\n").unwrap();
+    fs::create_dir(root.join("sub")).unwrap();
+    let data: Vec<u8> = (0..20000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(root.join("sub").join("data"), &data).unwrap();
+    fs::write(root.join("sub").join("empty"), b"").unwrap();
+    symlink("sub/data", root.join("link")).unwrap();
+}
+
+/// Run command `cmd` of the tool with options `args`
+fn tool(cmd: &str, args: &[&str], image: &Path, dir: &Path) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rcore-fs-fuse"))
+        .args(args)
+        .arg(image)
+        .arg(dir)
+        .arg(cmd)
+        .output()
+        .unwrap()
+}
+
+/// Run command `cmd` of the tool with options `args`, and return what it prints
+fn run(cmd: &str, args: &[&str], image: &Path, dir: &Path) -> String {
+    let output = tool(cmd, args, image, dir);
+    assert!(
+        output.status.success(),
+        "{} {:?} failed: {}",
+        cmd,
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn zip_skips_what_the_fs_can_not_hold() {
+    let temp = TempDir::new().unwrap();
+    let (input, image) = (temp.path().join("in"), temp.path().join("img"));
+    make_tree(&input);
+
+    // the symlink is kept where symlinks are supported
+    let output = temp.path().join("sfs");
+    run("zip", &["--size", "16M"], &image, &input);
+    run("unzip", &[], &image, &output);
+    assert_eq!(tree(&output), tree(&input));
+
+    // and skipped where they are not, with the rest of the tree kept
+    let output = temp.path().join("fat32");
+    run("zip", &["-f", "fat32", "--size", "64M"], &image, &input);
+    run("unzip", &["-f", "fat32"], &image, &output);
+    let mut expected = tree(&input);
+    assert_eq!(
+        expected.remove(Path::new("link")),
+        Some(Entry::SymLink("sub/data".into()))
+    );
+    assert_eq!(tree(&output), expected);
+}
+
+/// An image file which keeps the data of freed blocks, as a disk does,
+/// while `Mutex<File>` punches them out of the file on Linux
+struct Disk(Mutex<File>);
+
+impl Device for Disk {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> dev::Result<usize> {
+        self.0.read_at(offset, buf)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> dev::Result<usize> {
+        self.0.write_at(offset, buf)
+    }
+
+    fn sync(&self) -> dev::Result<()> {
+        self.0.sync()
+    }
+}
+
+/// Blocks of `image` full of `byte`
+fn blocks_of(image: &Path, byte: u8) -> usize {
+    let image = fs::read(image).unwrap();
+    image
+        .chunks(BLKSIZE)
+        .filter(|block| block.iter().all(|&b| b == byte))
+        .count()
+}
+
+/// Zip the tree under `input` into SFS `image`, then add file "a" full of 0xaa and file "b"
+/// full of 0xbb, of `blocks` blocks each, a block of one after a block of the other.
+/// "b" is removed again, leaving its data in free blocks and "a" fragmented.
+fn fragmented_image(input: &Path, image: &Path, blocks: usize) {
+    make_tree(input);
+    run("zip", &["--size", "16M"], image, input);
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(image)
+        .unwrap();
+    let sfs = SimpleFileSystem::open(Arc::new(Disk(Mutex::new(file)))).unwrap();
+    let root = sfs.root_inode();
+    let a = root.create("a", FileType::File, 0o644).unwrap();
+    let b = root.create("b", FileType::File, 0o644).unwrap();
+    for i in 0..blocks {
+        a.write_at((blocks - 1 - i) * BLKSIZE, &[0xaa; BLKSIZE])
+            .unwrap();
+        b.write_at(i * BLKSIZE, &[0xbb; BLKSIZE]).unwrap();
+    }
+    drop((a, b));
+    root.unlink("b").unwrap();
+    sfs.sync().unwrap();
+}
+
+#[test]
+fn sanitize() {
+    let temp = TempDir::new().unwrap();
+    let (input, image) = (temp.path().join("in"), temp.path().join("img"));
+    fragmented_image(&input, &image, 8);
+    assert_eq!(blocks_of(&image, 0xbb), 8);
+
+    let report = run("sanitize", &[], &image, &input);
+    assert!(report.starts_with("sanitize done"), "{}", report);
+    assert_eq!(blocks_of(&image, 0xbb), 0);
+    assert_eq!(blocks_of(&image, 0xaa), 8);
+
+    let output = temp.path().join("out");
+    run("unzip", &[], &image, &output);
+    let mut expected = tree(&input);
+    expected.insert("a".into(), Entry::File(vec![0xaa; 8 * BLKSIZE]));
+    assert_eq!(tree(&output), expected);
+}
+
+/// Counter `name` in the I/O statistics printed with `--stats`
+fn stat(report: &str, name: &str) -> usize {
+    report
+        .lines()
+        .find_map(|line| {
+            let mut words = line.split(' ');
+            match words.next() {
+                Some(word) if word == name => words.next(),
+                _ => None,
+            }
+        })
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+#[test]
+fn zero_on_free() {
+    let temp = TempDir::new().unwrap();
+    let (input, image) = (temp.path().join("in"), temp.path().join("img"));
+    fragmented_image(&input, &image, 8);
+    let copy = temp.path().join("copy");
+    fs::copy(&image, &copy).unwrap();
+
+    // defrag moves "a", freeing the blocks it was in. They are punched out of an image
+    // file either way, but only zeroed first with the option.
+    let report = run("defrag", &["--stats"], &copy, &input);
+    assert!(report.starts_with("defrag done, 1 of"), "{}", report);
+    let written = stat(&report, "bytes_written");
+    let report = run("defrag", &["--stats", "--zero-on-free"], &image, &input);
+    assert!(report.starts_with("defrag done, 1 of"), "{}", report);
+    assert_eq!(stat(&report, "bytes_written"), written + 8 * BLKSIZE);
+    assert_eq!(blocks_of(&image, 0xaa), 8);
+    // only blocks freed while it is on are zeroed
+    assert_eq!(blocks_of(&image, 0xbb), 8);
+
+    let output = temp.path().join("out");
+    run("unzip", &[], &image, &output);
+    let mut expected = tree(&input);
+    expected.insert("a".into(), Entry::File(vec![0xaa; 8 * BLKSIZE]));
+    assert_eq!(tree(&output), expected);
+}
+
+#[test]
+fn unzip_with_mem_limit() {
+    let temp = TempDir::new().unwrap();
+    let (input, image) = (temp.path().join("in"), temp.path().join("img"));
+    make_tree(&input);
+    let mut dir = input.join("deep");
+    for i in 0..64 {
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("file"), format!("file {}\n", i)).unwrap();
+        dir = dir.join("deep");
+    }
+    run("zip", &["--size", "16M"], &image, &input);
+
+    // a buffer shorter than the files, 0 taken as 1 byte, and the default
+    for &limit in ["7", "0", "1048576"].iter() {
+        let output = temp.path().join(format!("out{}", limit));
+        run("unzip", &["--mem-limit", limit], &image, &output);
+        assert_eq!(tree(&output), tree(&input));
+    }
+}
+
+#[test]
+fn zip_with_inode_ratio() {
+    let temp = TempDir::new().unwrap();
+    let (input, image) = (temp.path().join("in"), temp.path().join("img"));
+    make_tree(&input);
+
+    // an inode table of 16M / 64K inodes, packed in its blocks in a new image
+    run(
+        "zip",
+        &["--size", "16M", "--inode-ratio", "65536"],
+        &image,
+        &input,
+    );
+    let report = run("pack-inodes", &[], &image, &input);
+    let free = 256 - tree(&input).len();
+    assert_eq!(
+        report.trim(),
+        format!("pack-inodes done, {} inodes free", free)
+    );
+    let report = run("fsck", &[], &image, &input);
+    assert!(
+        report.ends_with("fsck done, 0 problems found, 0 repaired\n"),
+        "{}",
+        report
+    );
+    let output = temp.path().join("out");
+    run("unzip", &[], &image, &output);
+    assert_eq!(tree(&output), tree(&input));
+}
+
+#[test]
+fn failed_zip_leaves_the_image() {
+    let temp = TempDir::new().unwrap();
+    let (input, image) = (temp.path().join("in"), temp.path().join("img"));
+    make_tree(&input);
+    run("zip", &["--size", "16M"], &image, &input);
+    let old = fs::read(&image).unwrap();
+
+    // more files than inodes in the table
+    let many = temp.path().join("many");
+    fs::create_dir(&many).unwrap();
+    for i in 0..300 {
+        fs::write(many.join(i.to_string()), b"").unwrap();
+    }
+    let args = ["--size", "16M", "--inode-ratio", "65536"];
+    let output = tool("zip", &args, &image, &many);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("failed to zip fs"));
+    assert_eq!(fs::read(&image).unwrap(), old);
+    assert!(!temp.path().join("img.tmp").exists());
+
+    // nor is a half made one left where there was none
+    let image = temp.path().join("new");
+    assert!(!tool("zip", &args, &image, &many).status.success());
+    assert!(!image.exists());
+    assert!(!temp.path().join("new.tmp").exists());
+}
+
+#[test]
+fn boot_area() {
+    let temp = TempDir::new().unwrap();
+    let (input, image) = (temp.path().join("in"), temp.path().join("img"));
+    make_tree(&input);
+    let boot = temp.path().join("boot");
+    let data: Vec<u8> = (0..5000u32).map(|i| (i % 253) as u8 + 1).collect();
+    fs::write(&boot, &data).unwrap();
+    let read = temp.path().join("read");
+
+    // before the fs, of a fixed size
+    let args = ["--size", "16M", "--boot-size", "65536"];
+    run("zip", &args, &image, &input);
+    run("write-boot", &args, &image, &boot);
+    run("read-boot", &args, &image, &read);
+    let mut area = data.clone();
+    area.resize(65536, 0);
+    assert_eq!(fs::read(&read).unwrap(), area);
+    assert_eq!(fs::read(&image).unwrap()[..65536], area[..]);
+    let output = temp.path().join("out");
+    run("unzip", &args, &image, &output);
+    assert_eq!(tree(&output), tree(&input));
+    fs::write(&boot, vec![1u8; 65537]).unwrap();
+    assert!(!tool("write-boot", &args, &image, &boot).status.success());
+
+    // after the fs, till the end of the image
+    let args = ["--size", "16M", "--boot-at-end"];
+    run("zip", &args, &image, &input);
+    fs::write(&boot, &data).unwrap();
+    run("write-boot", &args, &image, &boot);
+    run("read-boot", &args, &image, &read);
+    assert_eq!(fs::read(&read).unwrap(), data);
+    let output = temp.path().join("out2");
+    run("unzip", &args, &image, &output);
+    assert_eq!(tree(&output), tree(&input));
+}
+
+#[test]
+fn lfs_stats() {
+    let temp = TempDir::new().unwrap();
+    let (input, image) = (temp.path().join("in"), temp.path().join("img"));
+    make_tree(&input);
+    let args = ["-f", "lfs", "--size", "128M", "--segment-blocks", "64"];
+    run("zip", &args, &image, &input);
+    let report = run("stats", &["-f", "lfs"], &image, &input);
+    let value = |name: &str| -> usize {
+        let prefix = format!("{} ", name);
+        let line = report.lines().find(|line| line.starts_with(&prefix));
+        line.unwrap_or_else(|| panic!("no {} in {}", name, report))[prefix.len()..]
+            .parse()
+            .unwrap()
+    };
+    // the tree and the metadata of it, in the segments counted
+    assert!(value("live_bytes") > 20000);
+    // the blocks of a segment but those of its metadata
+    let data_bytes = value("segment_data_bytes");
+    assert!(data_bytes % BLKSIZE == 0 && data_bytes < 64 * BLKSIZE);
+    let segments = report
+        .lines()
+        .filter(|line| line.starts_with("segment "))
+        .count();
+    assert!(segments > 0);
+    assert_eq!(value("free_segments") + segments, value("total_segments"));
+    assert_eq!(
+        report
+            .lines()
+            .filter(|line| line.ends_with(" current"))
+            .count(),
+        1
+    );
+    // written before it was opened
+    assert_eq!(value("data_bytes_written"), 0);
+
+    let sfs = temp.path().join("sfs");
+    run("zip", &["--size", "16M"], &sfs, &input);
+    let output = tool("stats", &[], &sfs, &input);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("stats is only for lfs"));
+}
+
+#[test]
+fn lfs_compact() {
+    let temp = TempDir::new().unwrap();
+    let (input, image) = (temp.path().join("in"), temp.path().join("img"));
+    make_tree(&input);
+    let args = ["-f", "lfs", "--size", "128M", "--segment-blocks", "64"];
+    run("zip", &args, &image, &input);
+    let before = fs::metadata(&image).unwrap().len() as usize;
+    let report = run("compact", &args, &image, &input);
+    // the image cut to the segments in use, and a few free after
+    let size = report
+        .trim_end()
+        .rsplit(' ')
+        .nth(1)
+        .and_then(|size| size.parse::<usize>().ok())
+        .unwrap_or_else(|| panic!("no size in {}", report));
+    assert!(report.starts_with("compact done, "), "{}", report);
+    assert_eq!(fs::metadata(&image).unwrap().len() as usize, size);
+    assert_eq!(size % (64 * BLKSIZE), 0);
+    assert!(size <= 16 * 64 * BLKSIZE && size < before / 16, "{}", size);
+    let output = temp.path().join("out");
+    run("unzip", &["-f", "lfs"], &image, &output);
+    assert_eq!(tree(&output), tree(&input));
+
+    let sfs = temp.path().join("sfs");
+    run("zip", &["--size", "16M"], &sfs, &input);
+    let output = tool("compact", &[], &sfs, &input);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("compact is only for lfs"));
+}
@@ -41,6 +41,13 @@ impl FileSystem for HostFS {
     fn info(&self) -> FsInfo {
         unimplemented!()
     }
+
+    fn capabilities(&self) -> FsCapabilities {
+        FsCapabilities {
+            features: FsFeatures::HARDLINK | FsFeatures::CASE_SENSITIVE,
+            namemax: 255,
+        }
+    }
 }
 
 impl HostFS {
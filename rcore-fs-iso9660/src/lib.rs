@@ -0,0 +1,500 @@
+//! ISO9660, read-only, with the Rock Ridge extensions, e.g. on CD images made by
+//! `mkisofs -R` or `xorriso`
+//!
+//! Where the image has Rock Ridge, names, modes, owners, links, times, symlinks and devices
+//! are those it tells, and the dirs it moved away for being too deep are shown where they were.
+//! Without it, names are shown as Linux does, in lower case and without their version.
+//! Joliet is not read. Nothing can be changed: the operations changing INodes fail with
+//! `ReadOnlyFs`.
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+extern crate alloc;
+#[macro_use]
+extern crate log;
+
+use alloc::{
+    string::String,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use core::any::Any;
+
+use rcore_fs::dev::Device;
+use rcore_fs::vfs::{self, FileType, FsError, INode, Metadata, Timespec};
+
+use self::rock::RockRidge;
+pub use self::structs::*;
+
+mod rock;
+mod structs;
+#[cfg(test)]
+mod tests;
+
+trait DeviceExt: Device {
+    fn read_exact_at(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<()> {
+        match self.read_at(offset, buf)? {
+            len if len == buf.len() => Ok(()),
+            _ => Err(FsError::DeviceError),
+        }
+    }
+}
+
+impl DeviceExt for dyn Device + '_ {}
+
+/// Longest name of Rock Ridge, as of other Unix file systems
+const MAX_NAME_LEN: usize = 255;
+/// Most volume descriptors looked through for the primary one
+const MAX_DESCRIPTORS: usize = 32;
+/// Longest directory record
+const MAX_RECORD_SIZE: usize = 255;
+
+const S_IFMT: u32 = 0o170_000;
+const S_IFSOCK: u32 = 0o140_000;
+const S_IFLNK: u32 = 0o120_000;
+const S_IFBLK: u32 = 0o060_000;
+const S_IFDIR: u32 = 0o040_000;
+const S_IFCHR: u32 = 0o020_000;
+const S_IFIFO: u32 = 0o010_000;
+
+/// INode for ISO9660, all of which is read when it is loaded
+pub struct INodeImpl {
+    metadata: Metadata,
+    /// Positions on the device and lengths of the extents of its content
+    extents: Vec<(usize, usize)>,
+    /// Target of a symlink
+    symlink: Vec<u8>,
+    /// Is it a file interleaved with others, which can not be read?
+    interleaved: bool,
+    fs: Arc<Iso9660FileSystem>,
+}
+
+/// An entry of a dir
+struct Entry {
+    name: String,
+    /// Position of its record on the device
+    position: usize,
+    record: DirRecord,
+    rock: RockRidge,
+    /// The extents of all its records, for a file of several extents
+    extents: Vec<(usize, usize)>,
+}
+
+impl INodeImpl {
+    fn check_dir(&self) -> vfs::Result<()> {
+        match self.metadata.type_ {
+            FileType::Dir => Ok(()),
+            _ => Err(FsError::NotDir),
+        }
+    }
+
+    /// The entries of the dir, "." and ".." first
+    fn entries(&self) -> vfs::Result<Vec<Entry>> {
+        let (start, len) = self.extents[0];
+        let mut buf = vec![0u8; len];
+        self.fs.device.read_exact_at(start, &mut buf)?;
+        let mut entries: Vec<Entry> = Vec::new();
+        // the entries to list, not the ones hidden or moved away
+        let mut shown = Vec::new();
+        // does the last entry go on in the next record?
+        let mut more = false;
+        let mut offset = 0;
+        while offset < len {
+            let record = match DirRecord::parse(&buf[offset..]) {
+                Some(record) => record,
+                // records do not cross sectors, the rest of which is padding
+                None => {
+                    offset = (offset / SECTOR_SIZE + 1) * SECTOR_SIZE;
+                    continue;
+                }
+            };
+            let extent = self.fs.extent_of(&record);
+            if more {
+                let last = entries.last_mut().unwrap();
+                last.extents.push(extent);
+                more = record.flags & FLAG_MULTI_EXTENT != 0;
+                offset += record.len;
+                continue;
+            }
+            more = record.flags & FLAG_MULTI_EXTENT != 0;
+            let rock = self
+                .fs
+                .rock_of(&buf[offset..offset + record.len], &record)?;
+            let name = match record.name.as_slice() {
+                [0] => String::from("."),
+                [1] => String::from(".."),
+                _ => {
+                    let name = match rock.name {
+                        Some(ref name) => name.clone(),
+                        None => plain_name(&record.name),
+                    };
+                    String::from_utf8_lossy(&name).into_owned()
+                }
+            };
+            shown.push(record.flags & FLAG_ASSOCIATED == 0 && !rock.relocated);
+            entries.push(Entry {
+                name,
+                position: start + offset,
+                record,
+                rock,
+                extents: vec![extent],
+            });
+            offset += entries.last().unwrap().record.len;
+        }
+        let mut shown = shown.into_iter();
+        entries.retain(|_| shown.next().unwrap());
+        Ok(entries)
+    }
+}
+
+impl vfs::INode for INodeImpl {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<usize> {
+        match self.metadata.type_ {
+            FileType::File => {}
+            FileType::SymLink => {
+                let target = &self.symlink[offset.min(self.symlink.len())..];
+                let len = buf.len().min(target.len());
+                buf[..len].copy_from_slice(&target[..len]);
+                return Ok(len);
+            }
+            FileType::Dir => return Err(FsError::IsDir),
+            _ => return Err(FsError::NotFile),
+        }
+        if self.interleaved {
+            return Err(FsError::NotSupported);
+        }
+        let size = self.metadata.size;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min(size - offset);
+        let mut done = 0;
+        let mut pos = offset;
+        for &(start, extent_len) in self.extents.iter() {
+            if done == len {
+                break;
+            }
+            if pos >= extent_len {
+                pos -= extent_len;
+                continue;
+            }
+            let chunk = (extent_len - pos).min(len - done);
+            self.fs
+                .device
+                .read_exact_at(start + pos, &mut buf[done..done + chunk])?;
+            done += chunk;
+            pos = 0;
+        }
+        Ok(done)
+    }
+
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> vfs::Result<usize> {
+        Err(FsError::ReadOnlyFs)
+    }
+
+    fn poll(&self) -> vfs::Result<vfs::PollStatus> {
+        Ok(vfs::PollStatus {
+            read: true,
+            write: false,
+            error: false,
+        })
+    }
+
+    fn metadata(&self) -> vfs::Result<Metadata> {
+        Ok(self.metadata.clone())
+    }
+
+    fn set_metadata(&self, _metadata: &Metadata) -> vfs::Result<()> {
+        Err(FsError::ReadOnlyFs)
+    }
+
+    fn sync_all(&self) -> vfs::Result<()> {
+        Ok(())
+    }
+
+    fn sync_data(&self) -> vfs::Result<()> {
+        Ok(())
+    }
+
+    fn resize(&self, _len: usize) -> vfs::Result<()> {
+        Err(FsError::ReadOnlyFs)
+    }
+
+    fn create2(
+        &self,
+        _name: &str,
+        _type_: FileType,
+        _mode: u32,
+        _data: usize,
+    ) -> vfs::Result<Arc<dyn INode>> {
+        Err(FsError::ReadOnlyFs)
+    }
+
+    fn link(&self, _name: &str, _other: &Arc<dyn INode>) -> vfs::Result<()> {
+        Err(FsError::ReadOnlyFs)
+    }
+
+    fn unlink(&self, _name: &str) -> vfs::Result<()> {
+        Err(FsError::ReadOnlyFs)
+    }
+
+    fn move_(&self, _old_name: &str, _target: &Arc<dyn INode>, _new_name: &str) -> vfs::Result<()> {
+        Err(FsError::ReadOnlyFs)
+    }
+
+    fn find(&self, name: &str) -> vfs::Result<Arc<dyn INode>> {
+        self.check_dir()?;
+        let entry = self
+            .entries()?
+            .into_iter()
+            .find(|entry| entry.name == name)
+            .ok_or(FsError::EntryNotFound)?;
+        Ok(self.fs.load(entry)?)
+    }
+
+    fn get_entry(&self, id: usize) -> vfs::Result<String> {
+        self.check_dir()?;
+        self.entries()?
+            .into_iter()
+            .nth(id)
+            .map(|entry| entry.name)
+            .ok_or(FsError::EntryNotFound)
+    }
+
+    fn fs(&self) -> Arc<dyn vfs::FileSystem> {
+        self.fs.clone()
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// ISO9660 file system, read-only
+pub struct Iso9660FileSystem {
+    device: Arc<dyn Device>,
+    /// Bytes of a logical block, 2048 on all but a few images
+    block_size: usize,
+    volume: PrimaryVolume,
+    /// Block of the root dir
+    root: u32,
+    /// Bytes skipped at the start of each system use area, if Rock Ridge is used
+    rock_skip: Option<usize>,
+    self_ptr: Weak<Iso9660FileSystem>,
+}
+
+impl Iso9660FileSystem {
+    /// Load ISO9660 from device, by its primary volume descriptor
+    pub fn open(device: Arc<dyn Device>) -> vfs::Result<Arc<Self>> {
+        let mut buf = vec![0u8; SECTOR_SIZE];
+        let mut volume = None;
+        for sector in FIRST_DESCRIPTOR..FIRST_DESCRIPTOR + MAX_DESCRIPTORS {
+            device.read_exact_at(sector * SECTOR_SIZE, &mut buf)?;
+            if &buf[1..6] != STANDARD_ID || buf[0] == TYPE_TERMINATOR {
+                break;
+            }
+            volume = PrimaryVolume::parse(&buf);
+            if volume.is_some() {
+                break;
+            }
+        }
+        let volume = volume.ok_or(FsError::WrongFs)?;
+        let block_size = volume.block_size as usize;
+        if !block_size.is_power_of_two() || !(512..=SECTOR_SIZE).contains(&block_size) {
+            return Err(FsError::WrongFs);
+        }
+        let root = DirRecord::parse(&volume.root).ok_or(FsError::WrongFs)?;
+        // Rock Ridge is told by SP in the "." of the root dir
+        let mut buf = [0u8; MAX_RECORD_SIZE];
+        device.read_exact_at(root.extent as usize * block_size, &mut buf)?;
+        let dot = DirRecord::parse(&buf).ok_or(FsError::WrongFs)?;
+        let rock_skip = rock::skip_of(&buf[dot.system_use.min(dot.len)..dot.len]);
+        if rock_skip.is_none() {
+            warn!("no Rock Ridge, names are those of ISO9660");
+        }
+        let fs = Iso9660FileSystem {
+            device,
+            block_size,
+            root: root.extent,
+            volume,
+            rock_skip,
+            self_ptr: Weak::default(),
+        };
+        Ok(fs.wrap())
+    }
+
+    /// Wrap pure Iso9660FileSystem with Arc
+    /// Used in constructors
+    fn wrap(self) -> Arc<Self> {
+        // Create an Arc, make a Weak from it, then put it into the struct.
+        // It's a little tricky.
+        let fs = Arc::new(self);
+        let weak = Arc::downgrade(&fs);
+        let ptr = Arc::into_raw(fs) as *mut Self;
+        unsafe {
+            (*ptr).self_ptr = weak;
+        }
+        unsafe { Arc::from_raw(ptr) }
+    }
+
+    /// Label of the volume, without its padding
+    pub fn volume_id(&self) -> String {
+        let id = String::from_utf8_lossy(&self.volume.volume_id);
+        String::from(id.trim_end())
+    }
+
+    /// Are there the Rock Ridge extensions?
+    pub fn has_rock_ridge(&self) -> bool {
+        self.rock_skip.is_some()
+    }
+
+    /// Position and length of the content of a record, after its extended attributes
+    fn extent_of(&self, record: &DirRecord) -> (usize, usize) {
+        let block = record.extent as usize + record.ext_attr_len as usize;
+        (block * self.block_size, record.data_len as usize)
+    }
+
+    /// The Rock Ridge entries of record `record` in `buf`
+    fn rock_of(&self, buf: &[u8], record: &DirRecord) -> vfs::Result<RockRidge> {
+        let skip = match self.rock_skip {
+            Some(skip) => skip,
+            None => return Ok(RockRidge::default()),
+        };
+        let area = &buf[(record.system_use + skip).min(record.len)..record.len];
+        RockRidge::parse(area, |(block, offset, len)| {
+            let mut area = vec![0u8; len as usize];
+            let position = block as usize * self.block_size + offset as usize;
+            self.device.read_exact_at(position, &mut area)?;
+            Ok(area)
+        })
+    }
+
+    /// Load the dir at block `block`, by its "."
+    fn load_dir(&self, block: u32) -> vfs::Result<Arc<INodeImpl>> {
+        let position = block as usize * self.block_size;
+        let mut buf = [0u8; MAX_RECORD_SIZE];
+        self.device.read_exact_at(position, &mut buf)?;
+        let record = DirRecord::parse(&buf).ok_or(FsError::Corrupted(block as usize))?;
+        let rock = self.rock_of(&buf, &record)?;
+        let extents = vec![self.extent_of(&record)];
+        Ok(self.new_inode(position, record, rock, extents))
+    }
+
+    /// Load the INode of an entry
+    fn load(&self, entry: Entry) -> vfs::Result<Arc<INodeImpl>> {
+        let rock = &entry.rock;
+        let dir = match rock.child {
+            Some(block) => Some(block),
+            None if entry.record.is_dir() && entry.name == ".." => {
+                Some(rock.parent.unwrap_or(entry.record.extent))
+            }
+            None if entry.record.is_dir() => Some(entry.record.extent),
+            None => None,
+        };
+        match dir {
+            Some(block) => self.load_dir(block),
+            None => Ok(self.new_inode(entry.position, entry.record, entry.rock, entry.extents)),
+        }
+    }
+
+    fn new_inode(
+        &self,
+        position: usize,
+        record: DirRecord,
+        rock: RockRidge,
+        extents: Vec<(usize, usize)>,
+    ) -> Arc<INodeImpl> {
+        let type_ = match rock.mode {
+            Some(mode) => file_type(mode),
+            None if record.is_dir() => FileType::Dir,
+            None => FileType::File,
+        };
+        let symlink = rock.symlink.unwrap_or_default();
+        let size = match type_ {
+            FileType::File | FileType::Dir => extents.iter().map(|&(_, len)| len).sum(),
+            FileType::SymLink => symlink.len(),
+            _ => 0,
+        };
+        let recorded = record_time(&record.time);
+        let time = |sec: Option<i64>| Timespec {
+            sec: sec.unwrap_or(recorded),
+            nsec: 0,
+        };
+        let (mode, nlinks) = match rock.mode {
+            Some(mode) => ((mode & 0o7777) as u16, rock.nlinks as usize),
+            None if type_ == FileType::Dir => (0o555, 2),
+            None => (0o444, 1),
+        };
+        let metadata = Metadata {
+            dev: 0,
+            inode: rock.serial.map_or(position, |serial| serial as usize),
+            size,
+            blk_size: self.block_size,
+            blocks: match type_ {
+                FileType::File | FileType::Dir => (size + self.block_size - 1) / self.block_size,
+                _ => 0,
+            },
+            atime: time(rock.atime),
+            mtime: time(rock.mtime),
+            ctime: time(rock.ctime),
+            type_,
+            mode,
+            nlinks,
+            uid: rock.uid as usize,
+            gid: rock.gid as usize,
+            rdev: rock
+                .rdev
+                .map_or(0, |(major, minor)| vfs::make_rdev(major, minor)),
+        };
+        Arc::new(INodeImpl {
+            metadata,
+            extents,
+            symlink,
+            interleaved: record.unit_size != 0,
+            fs: self.self_ptr.upgrade().unwrap(),
+        })
+    }
+}
+
+fn file_type(mode: u32) -> FileType {
+    match mode & S_IFMT {
+        S_IFDIR => FileType::Dir,
+        S_IFLNK => FileType::SymLink,
+        S_IFCHR => FileType::CharDevice,
+        S_IFBLK => FileType::BlockDevice,
+        S_IFIFO => FileType::NamedPipe,
+        S_IFSOCK => FileType::Socket,
+        _ => FileType::File,
+    }
+}
+
+impl vfs::FileSystem for Iso9660FileSystem {
+    fn sync(&self) -> vfs::Result<()> {
+        Ok(())
+    }
+
+    fn root_inode(&self) -> Arc<dyn vfs::INode> {
+        self.load_dir(self.root)
+            .expect("failed to load the root dir")
+    }
+
+    fn info(&self) -> vfs::FsInfo {
+        vfs::FsInfo {
+            bsize: self.block_size,
+            frsize: self.block_size,
+            blocks: self.volume.space_size as usize,
+            bfree: 0,
+            bavail: 0,
+            files: 0,
+            ffree: 0,
+            namemax: MAX_NAME_LEN,
+        }
+    }
+
+    fn capabilities(&self) -> vfs::FsCapabilities {
+        vfs::FsCapabilities {
+            features: vfs::FsFeatures::CASE_SENSITIVE,
+            namemax: MAX_NAME_LEN,
+        }
+    }
+}
@@ -0,0 +1,200 @@
+//! Rock Ridge: POSIX names, modes, links and times, in the entries of the System Use Sharing
+//! Protocol (SUSP) in the system use area of each directory record
+use alloc::vec::Vec;
+
+use crate::structs::{le32, long_time, record_time};
+
+/// Bytes of an entry before its data: signature, length and version
+const HEADER_SIZE: usize = 4;
+/// Most continuation areas followed for a record, against loops on a bad image
+const MAX_CONTINUATIONS: usize = 32;
+
+/// Flags of NM and of the components of SL
+const CONTINUE: u8 = 0x01;
+const CURRENT: u8 = 0x02;
+const PARENT: u8 = 0x04;
+const ROOT: u8 = 0x08;
+
+/// Flags of TF, telling which times follow, in this order
+const TF_CREATION: u8 = 0x01;
+const TF_MODIFY: u8 = 0x02;
+const TF_ACCESS: u8 = 0x04;
+const TF_ATTRIBUTES: u8 = 0x08;
+/// The times are of 17 bytes, not 7
+const TF_LONG_FORM: u8 = 0x80;
+
+/// What the entries of a record tell
+#[derive(Debug, Default, Clone)]
+pub struct RockRidge {
+    /// NM: the name
+    pub name: Option<Vec<u8>>,
+    /// PX: mode with the type, links, owner and the serial number of RRIP 1.12
+    pub mode: Option<u32>,
+    pub nlinks: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub serial: Option<u32>,
+    /// PN: the device number, as (major, minor)
+    pub rdev: Option<(usize, usize)>,
+    /// SL: the target of a symlink
+    pub symlink: Option<Vec<u8>>,
+    /// TF: seconds since the epoch
+    pub mtime: Option<i64>,
+    pub atime: Option<i64>,
+    pub ctime: Option<i64>,
+    /// CL: the block of a dir moved away, as it was too deep, which the record stands for
+    pub child: Option<u32>,
+    /// PL: the block of the real parent of a dir moved away, in its ".."
+    pub parent: Option<u32>,
+    /// RE: the record of a dir moved away, not to be listed where it is
+    pub relocated: bool,
+}
+
+/// A continuation area, told by CE: block, offset in it, and length
+pub type Continuation = (u32, u32, u32);
+
+/// Bytes to skip at the start of each system use area, if the area, that of the "." of the
+/// root dir, starts with SP and so tells that SUSP is used
+pub fn skip_of(area: &[u8]) -> Option<usize> {
+    if area.len() >= 7 && &area[..2] == b"SP" && area[2] >= 7 && area[4..6] == [0xbe, 0xef] {
+        Some(area[6] as usize)
+    } else {
+        None
+    }
+}
+
+impl RockRidge {
+    /// Parse system use area `area`, reading each continuation area by `read`
+    pub fn parse<E>(
+        area: &[u8],
+        mut read: impl FnMut(Continuation) -> Result<Vec<u8>, E>,
+    ) -> Result<Self, E> {
+        let mut rock = RockRidge::default();
+        let mut symlink = SymlinkBuilder::default();
+        let mut next = rock.parse_area(area, &mut symlink);
+        for _ in 0..MAX_CONTINUATIONS {
+            let continuation = match next {
+                Some(continuation) => continuation,
+                None => break,
+            };
+            next = rock.parse_area(&read(continuation)?, &mut symlink);
+        }
+        if symlink.seen {
+            rock.symlink = Some(symlink.target);
+        }
+        Ok(rock)
+    }
+
+    /// Parse the entries of an area, return the continuation area it tells of
+    fn parse_area(&mut self, area: &[u8], symlink: &mut SymlinkBuilder) -> Option<Continuation> {
+        let mut continuation = None;
+        let mut offset = 0;
+        while offset + HEADER_SIZE <= area.len() {
+            let len = area[offset + 2] as usize;
+            if len < HEADER_SIZE || offset + len > area.len() {
+                break;
+            }
+            let entry = &area[offset..offset + len];
+            let data = &entry[HEADER_SIZE..];
+            match &entry[..2] {
+                b"NM" if !data.is_empty() && data[0] & (CURRENT | PARENT) == 0 => {
+                    self.name
+                        .get_or_insert_with(Vec::new)
+                        .extend_from_slice(&data[1..]);
+                }
+                b"PX" if len >= 36 => {
+                    self.mode = Some(le32(entry, 4));
+                    self.nlinks = le32(entry, 12);
+                    self.uid = le32(entry, 20);
+                    self.gid = le32(entry, 28);
+                    if len >= 44 {
+                        self.serial = Some(le32(entry, 36));
+                    }
+                }
+                b"PN" if len >= 20 => {
+                    let (high, low) = (le32(entry, 4) as usize, le32(entry, 12) as usize);
+                    // as Linux: a 16-bit device number in the low half only
+                    self.rdev = Some(match high {
+                        0 if low & !0xff != 0 => (low >> 8, low & 0xff),
+                        _ => (high, low),
+                    });
+                }
+                b"SL" if !data.is_empty() => symlink.push(&data[1..]),
+                b"TF" if !data.is_empty() => self.parse_times(data),
+                b"CL" if len >= 12 => self.child = Some(le32(entry, 4)),
+                b"PL" if len >= 12 => self.parent = Some(le32(entry, 4)),
+                b"RE" => self.relocated = true,
+                b"CE" if len >= 28 => {
+                    continuation = Some((le32(entry, 4), le32(entry, 12), le32(entry, 20)));
+                }
+                b"ST" => break,
+                _ => {}
+            }
+            offset += len;
+        }
+        continuation
+    }
+
+    fn parse_times(&mut self, data: &[u8]) {
+        let flags = data[0];
+        let size = if flags & TF_LONG_FORM != 0 { 17 } else { 7 };
+        let mut offset = 1;
+        for &bit in [TF_CREATION, TF_MODIFY, TF_ACCESS, TF_ATTRIBUTES].iter() {
+            if flags & bit == 0 {
+                continue;
+            }
+            if offset + size > data.len() {
+                return;
+            }
+            let stamp = &data[offset..offset + size];
+            let time = match size {
+                17 => long_time(stamp),
+                _ => record_time(stamp),
+            };
+            match bit {
+                TF_MODIFY => self.mtime = Some(time),
+                TF_ACCESS => self.atime = Some(time),
+                TF_ATTRIBUTES => self.ctime = Some(time),
+                _ => {}
+            }
+            offset += size;
+        }
+    }
+}
+
+/// The target of a symlink, from the components of its SL entries, one of which may go on
+/// in the next entry
+#[derive(Default)]
+struct SymlinkBuilder {
+    target: Vec<u8>,
+    seen: bool,
+    /// Is a '/' needed before the next component?
+    separate: bool,
+}
+
+impl SymlinkBuilder {
+    fn push(&mut self, mut components: &[u8]) {
+        self.seen = true;
+        while components.len() >= 2 {
+            let (flags, len) = (components[0], components[1] as usize);
+            let content = &components[2..(2 + len).min(components.len())];
+            if self.separate {
+                self.target.push(b'/');
+            }
+            if flags & ROOT != 0 {
+                self.target.push(b'/');
+                self.separate = false;
+            } else {
+                if flags & CURRENT != 0 {
+                    self.target.push(b'.');
+                } else if flags & PARENT != 0 {
+                    self.target.extend_from_slice(b"..");
+                } else {
+                    self.target.extend_from_slice(content);
+                }
+                self.separate = flags & CONTINUE == 0;
+            }
+            components = &components[2 + content.len()..];
+        }
+    }
+}
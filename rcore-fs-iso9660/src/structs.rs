@@ -0,0 +1,172 @@
+//! On-disk structures of ISO9660 (ECMA-119), whose numbers are kept in both byte orders,
+//! of which the little-endian half is read
+use alloc::vec::Vec;
+
+/// Bytes of a sector, the logical block size of all CDs
+pub const SECTOR_SIZE: usize = 2048;
+/// Sector of the first volume descriptor, after the system area
+pub const FIRST_DESCRIPTOR: usize = 16;
+pub const STANDARD_ID: &[u8] = b"CD001";
+pub const TYPE_PRIMARY: u8 = 1;
+pub const TYPE_TERMINATOR: u8 = 255;
+
+pub const FLAG_HIDDEN: u8 = 0x01;
+pub const FLAG_DIRECTORY: u8 = 0x02;
+/// An associated file, e.g. a resource fork, which is not listed
+pub const FLAG_ASSOCIATED: u8 = 0x04;
+/// Not the last record of a file of several extents
+pub const FLAG_MULTI_EXTENT: u8 = 0x80;
+
+/// Bytes of a directory record before its name
+pub const DIR_RECORD_SIZE: usize = 33;
+
+/// The primary volume descriptor, of which only the fields used are parsed
+#[derive(Debug, Clone)]
+pub struct PrimaryVolume {
+    pub volume_id: Vec<u8>,
+    /// Number of logical blocks
+    pub space_size: u32,
+    pub block_size: u16,
+    /// The record of the root dir
+    pub root: Vec<u8>,
+}
+
+impl PrimaryVolume {
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        if buf[0] != TYPE_PRIMARY || &buf[1..6] != STANDARD_ID {
+            return None;
+        }
+        Some(PrimaryVolume {
+            volume_id: buf[40..72].to_vec(),
+            space_size: le32(buf, 80),
+            block_size: le16(buf, 128),
+            root: buf[156..156 + 34].to_vec(),
+        })
+    }
+}
+
+/// A directory record
+#[derive(Debug, Clone)]
+pub struct DirRecord {
+    /// Bytes of the record, with its system use area
+    pub len: usize,
+    pub ext_attr_len: u8,
+    pub extent: u32,
+    pub data_len: u32,
+    pub time: [u8; 7],
+    pub flags: u8,
+    /// Non-zero for an interleaved file
+    pub unit_size: u8,
+    pub name: Vec<u8>,
+    /// Offset of the system use area in the record, with Rock Ridge entries
+    pub system_use: usize,
+}
+
+impl DirRecord {
+    /// Parse the record at the start of `buf`, none if it is the padding at a sector's end
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        let len = *buf.first()? as usize;
+        if len < DIR_RECORD_SIZE || len > buf.len() {
+            return None;
+        }
+        let name_len = buf[32] as usize;
+        if DIR_RECORD_SIZE + name_len > len {
+            return None;
+        }
+        let mut time = [0u8; 7];
+        time.copy_from_slice(&buf[18..25]);
+        Some(DirRecord {
+            len,
+            ext_attr_len: buf[1],
+            extent: le32(buf, 2),
+            data_len: le32(buf, 10),
+            time,
+            flags: buf[25],
+            unit_size: buf[26],
+            name: buf[DIR_RECORD_SIZE..DIR_RECORD_SIZE + name_len].to_vec(),
+            // padded to an even offset
+            system_use: (DIR_RECORD_SIZE + name_len + 1) & !1,
+        })
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.flags & FLAG_DIRECTORY != 0
+    }
+
+    /// Is it "." or "..", named by a byte 0 or 1?
+    pub fn is_dot(&self) -> bool {
+        self.name == [0] || self.name == [1]
+    }
+}
+
+/// The name of a file without Rock Ridge, as shown by Linux: in lower case, without
+/// the version after ';' and without a trailing '.'
+pub fn plain_name(name: &[u8]) -> Vec<u8> {
+    let end = name.iter().position(|&c| c == b';').unwrap_or(name.len());
+    let mut name = &name[..end];
+    if name.len() > 1 && name.ends_with(b".") {
+        name = &name[..name.len() - 1];
+    }
+    name.to_ascii_lowercase()
+}
+
+/// Seconds since the epoch of the 7-byte time of a directory record:
+/// years since 1900, month, day, hour, minute, second, and the offset from GMT in quarters
+pub fn record_time(time: &[u8]) -> i64 {
+    let offset = time[6] as i8 as i64 * 15 * 60;
+    unix_time(
+        1900 + time[0] as i64,
+        time[1] as i64,
+        time[2] as i64,
+        time[3] as i64,
+        time[4] as i64,
+        time[5] as i64,
+    ) - offset
+}
+
+/// Seconds since the epoch of the 17-byte time of a volume descriptor:
+/// "YYYYMMDDHHMMSScc" in ASCII, and the offset from GMT in quarters
+pub fn long_time(time: &[u8]) -> i64 {
+    let digits = |range: core::ops::Range<usize>| {
+        time[range]
+            .iter()
+            .fold(0i64, |n, &c| n * 10 + (c.wrapping_sub(b'0') % 10) as i64)
+    };
+    let offset = time[16] as i8 as i64 * 15 * 60;
+    unix_time(
+        digits(0..4),
+        digits(4..6),
+        digits(6..8),
+        digits(8..10),
+        digits(10..12),
+        digits(12..14),
+    ) - offset
+}
+
+/// Seconds since the epoch of a time in UTC
+fn unix_time(year: i64, month: i64, day: i64, hour: i64, minute: i64, second: i64) -> i64 {
+    if !(1..=12).contains(&month) || day < 1 {
+        return 0;
+    }
+    // days from 1970-01-01 to the date, by moving January and February to the year before
+    let (y, m) = match month {
+        1 | 2 => (year - 1, month + 9),
+        _ => (year, month - 3),
+    };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * m + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    days * 86400 + hour * 3600 + minute * 60 + second
+}
+
+pub fn le16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+pub fn le32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
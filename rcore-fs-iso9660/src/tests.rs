@@ -0,0 +1,151 @@
+extern crate std;
+
+use crate::*;
+use rcore_fs::vfs::{FileSystem, Result};
+use std::fs::File;
+use std::sync::Mutex;
+
+/// rockridge.iso and plain.iso are made by `bsdtar --format=iso9660`, with and without
+/// Rock Ridge
+fn _open(path: &str) -> Arc<Iso9660FileSystem> {
+    let file = File::open(path).expect("failed to open the image");
+    Iso9660FileSystem::open(Arc::new(Mutex::new(file))).expect("failed to open ISO9660")
+}
+
+fn _read_all(inode: &Arc<dyn INode>) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; inode.metadata()?.size + 10];
+    let len = inode.read_at(0, &mut buf)?;
+    buf.truncate(len);
+    Ok(buf)
+}
+
+#[test]
+fn test_open() -> Result<()> {
+    let fs = _open("rockridge.iso");
+    assert!(fs.has_rock_ridge());
+    assert_eq!(fs.volume_id(), "CDROM");
+    assert_eq!(fs.info().bsize, 2048);
+    assert_eq!(fs.info().blocks, 67);
+    let root = fs.root_inode();
+    let long_name = format!("{}.txt", "n".repeat(150));
+    assert_eq!(
+        root.list()?,
+        vec![
+            ".",
+            "..",
+            "a",
+            "a file with a rather long name.tar.gz",
+            "boot",
+            "hello.txt",
+            "kernel",
+            "Mixed.Case",
+            &long_name,
+            "null",
+            "rr_moved",
+        ]
+    );
+    assert!(root.find("..")?.is_same(&*root)?);
+    assert!(root.lookup("boot/grub/..")?.is_same(&*root.find("boot")?)?);
+    assert_eq!(_read_all(&root.find(&long_name)?)?, b"longer");
+    assert_eq!(root.find("mixed.case").err(), Some(FsError::EntryNotFound));
+    Ok(())
+}
+
+#[test]
+fn read_files_and_metadata() -> Result<()> {
+    let fs = _open("rockridge.iso");
+    let root = fs.root_inode();
+    let kernel = root.lookup("boot/vmlinuz")?;
+    let data: Vec<u8> = (0..10000).map(|i| (i * 7) as u8).collect();
+    assert_eq!(_read_all(&kernel)?, data);
+    let mut buf = [0u8; 100];
+    assert_eq!(kernel.read_at(9950, &mut buf)?, 50);
+    assert_eq!(&buf[..50], &data[9950..]);
+
+    let hello = root.find("hello.txt")?;
+    assert_eq!(_read_all(&hello)?, b"hello\n");
+    let metadata = hello.metadata()?;
+    assert_eq!(
+        (metadata.type_, metadata.mode, metadata.nlinks),
+        (FileType::File, 0o755, 2)
+    );
+    // 2020-01-02 03:04:05 UTC
+    assert_eq!(metadata.mtime.sec, 1_577_934_245);
+    assert!(root.lookup("boot/hello.lnk")?.is_same(&*hello)?);
+    let boot = root.find("boot")?.metadata()?;
+    assert_eq!(
+        (boot.type_, boot.mode, boot.nlinks),
+        (FileType::Dir, 0o755, 3)
+    );
+
+    let null = root.find("null")?.metadata()?;
+    assert_eq!(null.type_, FileType::CharDevice);
+    assert_eq!(null.rdev, vfs::make_rdev(1, 3));
+    Ok(())
+}
+
+#[test]
+fn symlinks_and_deep_dirs() -> Result<()> {
+    let fs = _open("rockridge.iso");
+    let root = fs.root_inode();
+    let kernel = root.find("kernel")?;
+    assert_eq!(kernel.metadata()?.type_, FileType::SymLink);
+    assert_eq!(_read_all(&kernel)?, b"boot/vmlinuz");
+    assert_eq!(
+        _read_all(&root.lookup("boot/grub/up")?)?,
+        b"../../hello.txt"
+    );
+    assert!(root
+        .lookup_follow("kernel", 1)?
+        .is_same(&*root.lookup("boot/vmlinuz")?)?);
+
+    // "h" is moved to "rr_moved" by the image, and is shown where it was
+    let deep = root.lookup("a/b/c/d/e/f/g/h/i/deep.txt")?;
+    assert_eq!(_read_all(&deep)?, b"deep\n");
+    let h = root.lookup("a/b/c/d/e/f/g/h")?;
+    assert_eq!(h.metadata()?.type_, FileType::Dir);
+    assert_eq!(h.list()?, vec![".", "..", "i"]);
+    assert!(h.find("..")?.is_same(&*root.lookup("a/b/c/d/e/f/g")?)?);
+    assert_eq!(root.find("rr_moved")?.list()?, vec![".", ".."]);
+    Ok(())
+}
+
+#[test]
+fn read_only() -> Result<()> {
+    let fs = _open("rockridge.iso");
+    let root = fs.root_inode();
+    let hello = root.find("hello.txt")?;
+    assert_eq!(hello.write_at(0, b"x"), Err(FsError::ReadOnlyFs));
+    assert_eq!(hello.resize(0), Err(FsError::ReadOnlyFs));
+    assert_eq!(
+        root.create("new", FileType::File, 0o644).err(),
+        Some(FsError::ReadOnlyFs)
+    );
+    assert_eq!(root.unlink("hello.txt"), Err(FsError::ReadOnlyFs));
+    assert_eq!(
+        root.move_("hello.txt", &root, "moved"),
+        Err(FsError::ReadOnlyFs)
+    );
+    assert_eq!(_read_all(&hello)?, b"hello\n");
+    Ok(())
+}
+
+#[test]
+fn without_rock_ridge() -> Result<()> {
+    let fs = _open("plain.iso");
+    assert!(!fs.has_rock_ridge());
+    let root = fs.root_inode();
+    assert_eq!(root.list()?, vec![".", "..", "boot", "hello.txt"]);
+    assert_eq!(
+        root.find("boot")?.list()?,
+        vec![".", "..", "grub", "hello.lnk", "vmlinuz"]
+    );
+    let hello = root.find("hello.txt")?;
+    assert_eq!(_read_all(&hello)?, b"hello\n");
+    let metadata = hello.metadata()?;
+    assert_eq!((metadata.mode, metadata.nlinks), (0o444, 1));
+    assert_eq!(metadata.mtime.sec, 1_577_934_245);
+    assert_eq!(_read_all(&root.lookup("boot/vmlinuz")?)?.len(), 10000);
+    assert_eq!(plain_name(b"UP.;1"), b"up");
+    Ok(())
+}
@@ -0,0 +1,149 @@
+//! Write buffer of the current segment
+//!
+//! The blocks the log takes are written to the current segment one after another, so they
+//! are kept in memory, and written to the device at once: when the log moves on to the
+//! next segment, the whole segment, or at a sync, the part written since the last one.
+//! Reads of those blocks are served from the buffer. Writes anywhere else, e.g. the
+//! metadata of the segments and the checkpoints, go to the device as they are.
+use crate::*;
+use core::ops::Range;
+use rcore_fs::dev::{DevError, Result as DevResult};
+use spin::Mutex;
+
+/// A device buffering the writes to the part of the current segment not on it yet
+pub(crate) struct SegmentBuffer {
+    device: Arc<dyn Device>,
+    /// size of a segment
+    segment_size: usize,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    /// offset of the first byte buffered
+    begin: usize,
+    /// end of the segment, the writes from `begin` up to it are buffered
+    end: usize,
+    /// bytes written from `begin`, a gap being zeros
+    data: Vec<u8>,
+}
+
+impl SegmentBuffer {
+    /// Buffer nothing until `start()`, the segments being `segment_size` bytes
+    pub fn new(device: Arc<dyn Device>, segment_size: usize) -> Self {
+        SegmentBuffer {
+            device,
+            segment_size,
+            inner: Mutex::new(Inner {
+                begin: 0,
+                end: 0,
+                data: Vec::new(),
+            }),
+        }
+    }
+
+    /// Write back what is buffered, then buffer segment `seg_id` from byte `size` of it.
+    /// If it fails, the old part stays buffered, and the segment is not.
+    pub fn start(&self, seg_id: SegmentId, size: usize) -> DevResult<()> {
+        let mut inner = self.inner.lock();
+        Self::write_back_locked(&self.device, &mut inner)?;
+        inner.begin = seg_id * self.segment_size + size;
+        inner.end = (seg_id + 1) * self.segment_size;
+        Ok(())
+    }
+
+    /// Write what is buffered to the device in one request, without syncing it.
+    /// The segment stays buffered after it.
+    pub fn write_back(&self) -> DevResult<()> {
+        Self::write_back_locked(&self.device, &mut self.inner.lock())
+    }
+
+    /// Whether block `blk_id` is of the part of the segment not on the device yet
+    pub fn buffered(&self, blk_id: BlockId) -> bool {
+        let inner = self.inner.lock();
+        let offset = blk_id * BLKSIZE;
+        inner.begin <= offset && offset < inner.end
+    }
+
+    fn write_back_locked(device: &Arc<dyn Device>, inner: &mut Inner) -> DevResult<()> {
+        if inner.data.is_empty() {
+            return Ok(());
+        }
+        // whole blocks, so that the rest of a block partly written is buffered no more
+        let len = (inner.data.len() + BLKSIZE - 1) / BLKSIZE * BLKSIZE;
+        inner.data.resize(len, 0);
+        match device.write_at(inner.begin, &inner.data) {
+            Ok(written) if written == len => {}
+            Ok(_) => return Err(DevError::Io),
+            Err(err) => return Err(err),
+        }
+        debug!("write back {} bytes at {}", len, inner.begin);
+        inner.begin = (inner.begin + len).min(inner.end);
+        inner.data.clear();
+        Ok(())
+    }
+}
+
+impl Device for SegmentBuffer {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> DevResult<usize> {
+        let inner = self.inner.lock();
+        let buffered = inner.begin..inner.begin + inner.data.len();
+        let lo = offset.max(buffered.start);
+        let hi = (offset + buf.len()).min(buffered.end);
+        if lo >= hi {
+            drop(inner);
+            return self.device.read_at(offset, buf);
+        }
+        let len = match lo == offset && hi == offset + buf.len() {
+            true => buf.len(),
+            false => self.device.read_at(offset, buf)?,
+        };
+        buf[lo - offset..hi - offset]
+            .copy_from_slice(&inner.data[lo - buffered.start..hi - buffered.start]);
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> DevResult<usize> {
+        let mut inner = self.inner.lock();
+        let lo = offset.max(inner.begin);
+        let hi = (offset + buf.len()).min(inner.end);
+        if lo >= hi {
+            drop(inner);
+            return self.device.write_at(offset, buf);
+        }
+        // the parts out of the segment, if any, are not buffered
+        if offset < lo {
+            self.device.write_at(offset, &buf[..lo - offset])?;
+        }
+        if hi < offset + buf.len() {
+            self.device.write_at(hi, &buf[hi - offset..])?;
+        }
+        let begin = inner.begin;
+        if inner.data.len() < hi - begin {
+            inner.data.resize(hi - begin, 0);
+        }
+        inner.data[lo - begin..hi - begin].copy_from_slice(&buf[lo - offset..hi - offset]);
+        Ok(buf.len())
+    }
+
+    fn sync(&self) -> DevResult<()> {
+        self.write_back()?;
+        self.device.sync()
+    }
+
+    fn trim(&self, range: Range<usize>) -> DevResult<()> {
+        self.device.trim(range)
+    }
+
+    fn flush(&self) -> DevResult<()> {
+        self.write_back()?;
+        self.device.flush()
+    }
+
+    fn stats(&self) -> Option<Arc<Stats>> {
+        self.device.stats()
+    }
+
+    fn sector_size_log2(&self) -> u8 {
+        self.device.sector_size_log2()
+    }
+}
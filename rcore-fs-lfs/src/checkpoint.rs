@@ -0,0 +1,583 @@
+//! Checkpoints and crash recovery of LFS
+//!
+//! A checkpoint is a consistent state of the log: the blocks the imap is kept in, the
+//! segment usage table and the segment the log was written to, with a crc32c. It is
+//! written to each of the two regions after the super block in turn, so that a crash
+//! while writing one leaves the other. `sync()` writes one every `CHECKPOINT_SEGMENTS`
+//! segments started, and `checkpoint()` at once, e.g. when unmounted.
+//!
+//! Checkpoints pinned for snapshots are kept after the table, see `snapshot`.
+//!
+//! The metadata of a segment, its imap, summary and the records of the namespace operations
+//! begun in it, see `dirlog`, is written with a crc32c at each sync, and with a crc32c of
+//! its data blocks, extended by those written since the last one. When opened, LFS loads
+//! the latest valid checkpoint, then rolls forward through the log written after it: the
+//! segment current at the checkpoint, then each segment started next, taking the inodes
+//! recorded in them, then redoing the namespace operations. The first segment missing or
+//! whose checksums do not match, e.g. as a crash tore its data blocks, is the end of the
+//! log. The imaps of the segments are dropped at each checkpoint, as it tells where all the
+//! inodes are.
+//!
+//! The segments freed before a checkpoint, cleaned or with no live block left, are free on
+//! disk once it is written: their headers are written again with no imap, summary or
+//! records, so that recovery finds nothing stale in them, and their data blocks are
+//! discarded with `Device::trim()`, so that a flash device can erase them. Those a crash
+//! left as they were are found when opened, in use by their headers but free by the
+//! checkpoint, and made free then.
+use crate::*;
+
+/// Segments started between the checkpoints written by `sync()`
+pub const CHECKPOINT_SEGMENTS: u32 = 4;
+
+/// Bytes of the header of a checkpoint, before its imap blocks
+pub(crate) const CHECKPOINT_HEADER: usize = mem::size_of::<CheckRegion>();
+
+/// The state of the log at a checkpoint: its header, the imap blocks and the segment usage table
+type Checkpoint = (CheckRegion, Vec<BlockId>, Vec<SegmentMeta>);
+/// The checkpoints pinned, by number
+type Pinned = BTreeMap<u32, Snapshot>;
+
+impl LogFileSystem {
+    /// Sync, then write a checkpoint
+    pub fn checkpoint(&self) -> vfs::Result<()> {
+        let _ops = self.ops.write();
+        self._sync()?;
+        self.write_checkpoint()
+    }
+
+    /// Whether `CHECKPOINT_SEGMENTS` segments were started since the last checkpoint, or a
+    /// namespace operation asked for one, see `dirlog`
+    pub(crate) fn checkpoint_due(&self) -> bool {
+        let seg_seq = self.super_block.read().seg_seq;
+        seg_seq.wrapping_sub(self.check_region.read().seg_seq) >= CHECKPOINT_SEGMENTS
+            || *self.checkpoint_wanted.read()
+    }
+
+    /// Write a checkpoint of the state synced, to the region not holding the latest one,
+    /// unless nothing changed since the last. The segments cleaned before it are free on
+    /// disk after it.
+    pub(crate) fn write_checkpoint(&self) -> vfs::Result<()> {
+        *self.checkpoint_wanted.write() = false;
+        let seq = {
+            let mut cr = self.check_region.write();
+            let sb = self.super_block.read();
+            let segments = self.segments.read();
+            let mut imap = self.imap.write();
+            if !cr.dirty() && !imap.locations_dirty() && self.cleaned.read().is_empty() {
+                return Ok(());
+            }
+            cr.seq += 1;
+            cr.seg_seq = sb.seg_seq;
+            cr.current_seg_id = sb.current_seg_id;
+            cr.imap_len = imap.locations().len() as u32;
+            cr.n_segment = sb.n_segment;
+            let pinned = self.encode_pinned();
+            cr.pinned_len = pinned.len() as u32;
+            cr.checksum = 0;
+            let mut buf = cr.as_buf().to_vec();
+            for &blk_id in imap.locations() {
+                buf.extend_from_slice(&(blk_id as u64).to_ne_bytes());
+            }
+            for seg_id in 1..sb.n_segment as usize {
+                buf.extend_from_slice(segments[&seg_id].meta.as_buf());
+            }
+            buf.extend_from_slice(&pinned);
+            let cr_blks = self.geometry.cr_blks();
+            if buf.len() > cr_blks * BLKSIZE {
+                return Err(FsError::NoDeviceSpace);
+            }
+            cr.checksum = crc32c(&buf);
+            let header = cr.as_buf().len();
+            buf[..header].copy_from_slice(cr.as_buf());
+            let block = BLKN_CR + cr.seq as usize % 2 * cr_blks;
+            debug!("checkpoint {} to blk {} len {}", cr.seq, block, buf.len());
+            self.device.write_block(block, 0, &buf)?;
+            self.device.sync()?;
+            cr.sync();
+            imap.sync_locations();
+            cr.seq
+        };
+        // the inodes written before it are rolled forward no more, and those left on disk in
+        // the current segment are where the checkpoint tells, so it need not be written again
+        for segment in self.segments.read().values() {
+            let mut seg_imap = segment.seg_imap.write();
+            if !seg_imap.dirty() && !seg_imap.is_empty() {
+                *seg_imap = Dirty::new(BTreeMap::new());
+            }
+        }
+        self.drop_dir_logs(seq);
+        // the segments are free on disk now, with headers telling nothing and no data
+        let cleaned = core::mem::replace(&mut *self.cleaned.write(), BTreeSet::new());
+        for seg_id in cleaned {
+            self.write_segment(seg_id, self.segments.write().get_mut(&seg_id).unwrap())?;
+            let segment_size = self.geometry.segment_size();
+            let begin = seg_id * segment_size + self.geometry.header_size();
+            self.device.trim(begin..(seg_id + 1) * segment_size)?;
+        }
+        Ok(())
+    }
+
+    /// The dirty segments to write at a sync: those of the log since the checkpoint, in the
+    /// order they were started, and the others. Those cleaned since it are left as they are
+    /// on disk until the next one.
+    pub(crate) fn segments_to_write(&self, n_segment: usize) -> (Vec<SegmentId>, Vec<SegmentId>) {
+        let seg_seq = self.check_region.read().seg_seq;
+        let segments = self.segments.read();
+        let cleaned = self.cleaned.read();
+        let mut log = Vec::new();
+        let mut others = Vec::new();
+        for seg_id in 1..n_segment {
+            let segment = &segments[&seg_id];
+            let dirty = segment.meta.dirty()
+                || segment.seg_imap.read().dirty()
+                || segment.summary_map.read().dirty()
+                || segment.dir_log.read().dirty();
+            if !dirty || cleaned.contains(&seg_id) {
+                continue;
+            }
+            if segment.meta.unused == 0 && started_since(segment.meta.seq, seg_seq) {
+                log.push((segment.meta.seq.wrapping_sub(seg_seq), seg_id));
+            } else {
+                others.push(seg_id);
+            }
+        }
+        log.sort();
+        (log.into_iter().map(|(_, seg_id)| seg_id).collect(), others)
+    }
+
+    /// Load the latest valid checkpoint of an image of `n_segment` segments laid out as
+    /// `geometry`, if there is one, with the checkpoints pinned at it
+    pub(crate) fn load_checkpoint(
+        device: &Arc<dyn Device>,
+        n_segment: usize,
+        geometry: &Geometry,
+    ) -> vfs::Result<Option<(Checkpoint, Pinned)>> {
+        let cr_blks = geometry.cr_blks();
+        let mut latest: Option<(Checkpoint, Pinned)> = None;
+        for region in 0..2 {
+            let block = BLKN_CR + region * cr_blks;
+            let mut cr = device.load_struct::<CheckRegion>(block)?;
+            let header = cr.as_buf().len();
+            let table_len = n_segment.saturating_sub(1) * mem::size_of::<SegmentMeta>();
+            let table_end = header + cr.imap_len as usize * BLK_ID_SIZE + table_len;
+            let len = table_end + cr.pinned_len as usize;
+            if cr.n_segment as usize != n_segment || len > cr_blks * BLKSIZE {
+                continue;
+            }
+            let mut buf = vec![0u8; len];
+            device.read_block(block, 0, &mut buf)?;
+            let checksum = cr.checksum;
+            cr.checksum = 0;
+            buf[..header].copy_from_slice(cr.as_buf());
+            cr.checksum = checksum;
+            if crc32c(&buf) != checksum {
+                debug!("checkpoint region {} is not valid", region);
+                continue;
+            }
+            if let Some(((latest, _, _), _)) = latest.as_ref() {
+                if latest.seq >= cr.seq {
+                    continue;
+                }
+            }
+            let table_begin = table_end - table_len;
+            let imap = buf[header..table_begin]
+                .chunks(BLK_ID_SIZE)
+                .map(|entry| le64(entry) as BlockId)
+                .collect();
+            let table = buf[table_begin..table_end]
+                .chunks(mem::size_of::<SegmentMeta>())
+                .map(|entry| {
+                    let mut meta: SegmentMeta = unsafe { MaybeUninit::zeroed().assume_init() };
+                    meta.as_buf_mut().copy_from_slice(entry);
+                    meta
+                })
+                .collect();
+            let pinned = Snapshot::decode_all(&buf[table_end..]);
+            latest = Some(((cr, imap, table), pinned));
+        }
+        Ok(latest)
+    }
+
+    /// The state at checkpoint `checkpoint`, with the log written after it rolled forward.
+    /// `segments` are as loaded, those in `valid` with a matching checksum.
+    /// Return the check region, the imap, the namespace operations to redo, whether
+    /// anything was rolled forward or dropped, and whether the log was cut at a torn segment.
+    pub(crate) fn recover(
+        device: &Arc<dyn Device>,
+        super_block: &mut SuperBlock,
+        checkpoint: Checkpoint,
+        segments: &mut BTreeMap<SegmentId, Segment>,
+        valid: &BTreeSet<SegmentId>,
+    ) -> vfs::Result<(CheckRegion, IMap, Vec<DirOp>, bool, bool)> {
+        let (mut cr, locations, table) = checkpoint;
+        let geometry = super_block.geometry;
+        let mut imap = IMap::new(locations);
+        // the segments written after the checkpoint, in the order they were started
+        let mut started = BTreeMap::new();
+        let mut torn = false;
+        for &seg_id in valid.iter() {
+            let meta = &segments[&seg_id].meta;
+            if meta.unused == 0 && started_since(meta.seq, cr.seg_seq) {
+                // its data blocks torn by a crash, so the log is cut before it
+                if data_checksum(device, &geometry, seg_id, meta.size)? != meta.data_checksum {
+                    warn!("segment {} torn, not rolled forward", seg_id);
+                    torn = true;
+                    continue;
+                }
+                started.entry(meta.seq).or_insert(seg_id);
+            }
+        }
+        let mut log = Vec::new();
+        if started.get(&cr.seg_seq) == Some(&(cr.current_seg_id as usize)) {
+            let mut seq = cr.seg_seq;
+            while let Some(&seg_id) = started.get(&seq) {
+                log.push(seg_id);
+                seq = seq.wrapping_add(1);
+            }
+        }
+
+        let mut recovered = false;
+        for (seg_id, meta) in (1..).zip(table) {
+            let segment = segments.get_mut(&seg_id).unwrap();
+            if log.contains(&seg_id) {
+                recovered |= segment.meta.size != meta.size || segment.meta.seq != meta.seq;
+                continue;
+            }
+            // as it was at the checkpoint, written again if it is not on disk
+            let changed = segment.meta.unused != meta.unused
+                || segment.meta.seq != meta.seq
+                || segment.meta.size != meta.size
+                || segment.meta.live_bytes != meta.live_bytes;
+            *segment.seg_imap.write() = Dirty::new(BTreeMap::new());
+            *segment.dir_log.write() = Dirty::new(DirLog::default());
+            if meta.unused == 1 {
+                *segment.summary_map.write() = Dirty::new(BTreeMap::new());
+            } else {
+                let end = seg_id * geometry.segment_blks() + meta.size as usize / BLKSIZE;
+                let mut summary = segment.summary_map.write();
+                let dropped = summary.split_off(&end);
+                summary.sync();
+                drop(dropped);
+            }
+            let inodes_num = segment.seg_imap.read().len() as u32;
+            segment.meta = match changed {
+                true => {
+                    // the blocks freed since the checkpoint are told by the summary
+                    let summary = segment.summary_map.read();
+                    let live_bytes = summary_live_bytes(&geometry, &meta, &summary);
+                    Dirty::new_dirty(SegmentMeta {
+                        inodes_num,
+                        live_bytes,
+                        ..meta
+                    })
+                }
+                false => Dirty::new(SegmentMeta { inodes_num, ..meta }),
+            };
+            segment.checksum_end = segment.meta.size;
+            recovered |= changed;
+        }
+
+        let mut ops = Vec::new();
+        for &seg_id in log.iter() {
+            let segment = segments.get_mut(&seg_id).unwrap();
+            for (&ino_id, &blk_id) in segment.seg_imap.read().iter() {
+                imap.set(device, ino_id, blk_id)?;
+                cr.inodes_num = cr.inodes_num.max(ino_id as u32 + 1);
+            }
+            // those recorded before the checkpoint are done in it
+            let mut dir_log = segment.dir_log.write();
+            if started_since(dir_log.seq, cr.seq) {
+                ops.extend(DirOp::decode_all(&dir_log.records));
+            } else if !dir_log.records.is_empty() {
+                *dir_log = Dirty::new(DirLog::default());
+            }
+            drop(dir_log);
+            super_block.current_seg_id = seg_id as u32;
+            super_block.seg_seq = segment.meta.seq;
+            debug!("roll forward seg {} seq {}", seg_id, segment.meta.seq);
+        }
+        // the imap blocks the checkpoint tells may have been freed since, as they were written again
+        for (index, &blk_id) in imap.locations().iter().enumerate() {
+            if blk_id == INVALID_BLKID {
+                continue;
+            }
+            let segment = segments
+                .get_mut(&(blk_id / geometry.segment_blks()))
+                .unwrap();
+            let mut summary = segment.summary_map.write();
+            let entry = SummaryEntry {
+                inode_id: index as i32,
+                entry_id: ENTRY_IMAPBLOCK as i32,
+            };
+            if summary.get(&blk_id).map(|entry| entry.entry_id) != Some(entry.entry_id) {
+                summary.insert(blk_id, entry);
+                segment.meta.live_bytes += BLKSIZE as u32;
+                recovered = true;
+            }
+        }
+        // and those written after it, again from the imap rolled forward
+        for &seg_id in log.iter() {
+            let segment = segments.get_mut(&seg_id).unwrap();
+            let mut summary = segment.summary_map.write();
+            let dead: Vec<BlockId> = summary
+                .iter()
+                .filter(|(_, entry)| entry.entry_id == ENTRY_IMAPBLOCK as i32)
+                .filter(|&(&blk_id, entry)| !imap.is_at(entry.inode_id as usize, blk_id))
+                .map(|(&blk_id, _)| blk_id)
+                .collect();
+            for &blk_id in dead.iter() {
+                let entry = SummaryEntry {
+                    inode_id: INVALID_INO as i32,
+                    entry_id: ENTRY_GARBAGE as i32,
+                };
+                summary.insert(blk_id, entry);
+            }
+            if !dead.is_empty() {
+                let live_bytes = segment.meta.live_bytes;
+                segment.meta.live_bytes = live_bytes.saturating_sub((dead.len() * BLKSIZE) as u32);
+                recovered = true;
+            }
+        }
+        if log.is_empty() {
+            super_block.current_seg_id = cr.current_seg_id;
+            super_block.seg_seq = cr.seg_seq;
+        }
+        super_block.unused_blocks = unused_blocks(&geometry, segments);
+        recovered |= !ops.is_empty();
+        Ok((cr, imap, ops, recovered, torn))
+    }
+
+    /// Load segment `seg_id` laid out as `geometry`, and whether its checksum matches
+    pub(crate) fn load_segment(
+        device: &Arc<dyn Device>,
+        geometry: &Geometry,
+        seg_id: SegmentId,
+    ) -> vfs::Result<(Segment, bool)> {
+        let header = geometry.header_size();
+        let mut buf = vec![0u8; header];
+        device.read_block(seg_id * geometry.segment_blks(), 0, &mut buf)?;
+        let mut meta: SegmentMeta = unsafe { MaybeUninit::zeroed().assume_init() };
+        let meta_len = meta.as_buf().len();
+        meta.as_buf_mut().copy_from_slice(&buf[..meta_len]);
+        let size = meta.size as usize;
+        if meta.inodes_num as usize > imap_entries(geometry)
+            || size < header
+            || size > geometry.segment_size()
+            || size % BLKSIZE != 0
+        {
+            // never written
+            let segment = Segment {
+                meta: Dirty::new(SegmentMeta {
+                    size: header as u32,
+                    inodes_num: 0,
+                    unused: 1,
+                    seq: 0,
+                    live_bytes: 0,
+                    class: SegmentClass::Hot as u32,
+                    data_checksum: 0,
+                    checksum: 0,
+                }),
+                seg_imap: RwLock::new(Dirty::new(BTreeMap::new())),
+                summary_map: RwLock::new(Dirty::new(BTreeMap::new())),
+                dir_log: RwLock::new(Dirty::new(DirLog::default())),
+                checksum_end: header as u32,
+            };
+            return Ok((segment, false));
+        }
+        let valid = segment_checksum(geometry, &buf, &meta) == meta.checksum;
+        debug!("load segment {} valid {} {:?}", seg_id, valid, meta);
+
+        let seg_imap = buf[SEGMENT_META_SIZE..]
+            .chunks(IMAP_ENTRY_SIZE)
+            .take(meta.inodes_num as usize)
+            .map(|entry| (le32(&entry[..4]) as INodeId, le64(&entry[4..]) as BlockId))
+            .collect();
+        let mut summary = BTreeMap::new();
+        let first = seg_id * geometry.segment_blks();
+        for blk_id in first + geometry.data_begin()..first + size / BLKSIZE {
+            let offset = summary_offset(geometry, blk_id);
+            let mut entry: SummaryEntry = unsafe { MaybeUninit::zeroed().assume_init() };
+            let len = entry.as_buf().len();
+            entry
+                .as_buf_mut()
+                .copy_from_slice(&buf[offset..offset + len]);
+            summary.insert(blk_id, entry);
+        }
+        let records = match le32(&buf[DIRLOG_OFFSET + 4..]) as usize {
+            len if len <= DIRLOG_SIZE => buf[DIRLOG_OFFSET + 8..DIRLOG_OFFSET + 8 + len].to_vec(),
+            _ => Vec::new(),
+        };
+        let dir_log = DirLog {
+            seq: le32(&buf[DIRLOG_OFFSET..]),
+            synced: records.len(),
+            records,
+        };
+        let segment = Segment {
+            meta: Dirty::new(meta),
+            seg_imap: RwLock::new(Dirty::new(seg_imap)),
+            summary_map: RwLock::new(Dirty::new(summary)),
+            dir_log: RwLock::new(Dirty::new(dir_log)),
+            checksum_end: size as u32,
+        };
+        Ok((segment, valid))
+    }
+
+    /// Write the metadata of segment `seg_id`, its imap, summary and records, with its checksum
+    pub(crate) fn write_segment(
+        &self,
+        seg_id: SegmentId,
+        segment: &mut Segment,
+    ) -> vfs::Result<()> {
+        let geometry = &self.geometry;
+        let mut buf = vec![0u8; geometry.header_size()];
+        let mut seg_imap = segment.seg_imap.write();
+        let mut summary = segment.summary_map.write();
+        assert!(seg_imap.len() <= imap_entries(geometry));
+        for (i, (&ino_id, &blk_id)) in seg_imap.iter().enumerate() {
+            let offset = SEGMENT_META_SIZE + i * IMAP_ENTRY_SIZE;
+            buf[offset..offset + 4].copy_from_slice((ino_id as u32).as_buf());
+            buf[offset + 4..offset + 12].copy_from_slice(&(blk_id as u64).to_ne_bytes());
+        }
+        // blocks taken but not recorded yet are garbage
+        let garbage = SummaryEntry {
+            inode_id: INVALID_INO as i32,
+            entry_id: ENTRY_GARBAGE as i32,
+        };
+        let first = seg_id * geometry.segment_blks();
+        let end = first + segment.meta.size as usize / BLKSIZE;
+        for blk_id in first + geometry.data_begin()..end {
+            let offset = summary_offset(geometry, blk_id);
+            let entry = summary.get(&blk_id).unwrap_or(&garbage);
+            buf[offset..offset + entry.as_buf().len()].copy_from_slice(entry.as_buf());
+        }
+        let mut dir_log = segment.dir_log.write();
+        if !dir_log.records.is_empty() {
+            let records = &dir_log.records;
+            buf[DIRLOG_OFFSET..DIRLOG_OFFSET + 4].copy_from_slice(dir_log.seq.as_buf());
+            buf[DIRLOG_OFFSET + 4..DIRLOG_OFFSET + 8]
+                .copy_from_slice((records.len() as u32).as_buf());
+            buf[DIRLOG_OFFSET + 8..DIRLOG_OFFSET + 8 + records.len()].copy_from_slice(records);
+        }
+        // the data blocks written since it was, on disk as the buffer is written back first,
+        // and not written again
+        if segment.checksum_end < segment.meta.size {
+            let begin = first * BLKSIZE + segment.checksum_end as usize;
+            debug_assert!(!self.buffer.buffered(begin / BLKSIZE));
+            let mut data = vec![0u8; (segment.meta.size - segment.checksum_end) as usize];
+            self.device.read_at(begin, &mut data)?;
+            segment.meta.data_checksum = crc32c_update(segment.meta.data_checksum, &data);
+            segment.checksum_end = segment.meta.size;
+        }
+        segment.meta.inodes_num = seg_imap.len() as u32;
+        segment.meta.checksum = 0;
+        segment.meta.checksum = segment_checksum(geometry, &buf, &segment.meta);
+        let meta = segment.meta.as_buf();
+        buf[..meta.len()].copy_from_slice(meta);
+        self.device.write_block(first, 0, &buf)?;
+        segment.meta.sync();
+        seg_imap.sync();
+        summary.sync();
+        dir_log.synced = dir_log.records.len();
+        dir_log.sync();
+        Ok(())
+    }
+}
+
+/// Bytes of the data blocks of a segment with metadata `meta` and summary `summary` in use:
+/// those taken, but not freed
+pub(crate) fn summary_live_bytes(
+    geometry: &Geometry,
+    meta: &SegmentMeta,
+    summary: &BTreeMap<BlockId, SummaryEntry>,
+) -> u32 {
+    if meta.unused == 1 {
+        return 0;
+    }
+    let taken = meta.size as usize / BLKSIZE - geometry.data_begin();
+    let garbage = summary
+        .values()
+        .filter(|entry| entry.entry_id == ENTRY_GARBAGE as i32)
+        .count();
+    (taken.saturating_sub(garbage) * BLKSIZE) as u32
+}
+
+/// Number of data blocks of `segments` not in use, as counted by `alloc_block()` and `free_block()`
+pub(crate) fn unused_blocks(geometry: &Geometry, segments: &BTreeMap<SegmentId, Segment>) -> u64 {
+    let live: usize = segments
+        .values()
+        .map(|segment| segment.meta.live_bytes as usize / BLKSIZE)
+        .sum();
+    (segments.len() * geometry.data_blks() - live) as u64
+}
+
+/// Whether the segment started with `seq` was started since `SuperBlock::seg_seq` was `seg_seq`
+pub(crate) fn started_since(seq: u32, seg_seq: u32) -> bool {
+    seq.wrapping_sub(seg_seq) < u32::max_value() / 2
+}
+
+/// Entries of the imap of a segment laid out as `geometry`
+pub(crate) fn imap_entries(geometry: &Geometry) -> usize {
+    geometry.imap_blks as usize * BLKSIZE / IMAP_ENTRY_SIZE
+}
+
+/// Offset of the summary entry of block `blk_id` in the metadata of its segment
+fn summary_offset(geometry: &Geometry, blk_id: BlockId) -> usize {
+    geometry.summary_offset() + blk_id % geometry.segment_blks() * mem::size_of::<SummaryEntry>()
+}
+
+/// crc32c of `meta`, with its checksum 0, and of the imap, summary and namespace operations
+/// of it in `buf`, the metadata of a segment as on disk
+fn segment_checksum(geometry: &Geometry, buf: &[u8], meta: &SegmentMeta) -> u32 {
+    let header = SegmentMeta {
+        size: meta.size,
+        inodes_num: meta.inodes_num,
+        unused: meta.unused,
+        seq: meta.seq,
+        live_bytes: meta.live_bytes,
+        class: meta.class,
+        data_checksum: meta.data_checksum,
+        checksum: 0,
+    };
+    let mut data = Vec::with_capacity(buf.len());
+    data.extend_from_slice(header.as_buf());
+    let imap_len = meta.inodes_num as usize * IMAP_ENTRY_SIZE;
+    data.extend_from_slice(&buf[SEGMENT_META_SIZE..SEGMENT_META_SIZE + imap_len]);
+    let summary = geometry.summary_offset();
+    let entry_len = mem::size_of::<SummaryEntry>();
+    let blocks = meta.size as usize / BLKSIZE;
+    data.extend_from_slice(
+        &buf[summary + geometry.data_begin() * entry_len..summary + blocks * entry_len],
+    );
+    // none in segments written before there were records
+    let records = le32(&buf[DIRLOG_OFFSET + 4..]) as usize;
+    if records != 0 {
+        let end = DIRLOG_OFFSET + 8 + records.min(DIRLOG_SIZE);
+        data.extend_from_slice(&buf[DIRLOG_OFFSET..end]);
+    }
+    crc32c(&data)
+}
+
+/// crc32c of the data blocks of segment `seg_id` laid out as `geometry`, up to byte `size` of it
+fn data_checksum(
+    device: &Arc<dyn Device>,
+    geometry: &Geometry,
+    seg_id: SegmentId,
+    size: u32,
+) -> vfs::Result<u32> {
+    let begin = geometry.header_size();
+    let mut data = vec![0u8; (size as usize).saturating_sub(begin)];
+    device.read_at(seg_id * geometry.segment_size() + begin, &mut data)?;
+    Ok(crc32c(&data))
+}
+
+/// u32 in the byte order of the structs on disk
+fn le32(bytes: &[u8]) -> u32 {
+    u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// u64 in the byte order of the structs on disk
+pub(crate) fn le64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_ne_bytes(buf)
+}
@@ -0,0 +1,422 @@
+//! Segment cleaner of LFS
+//!
+//! The log takes free segments as it grows, and the cleaner makes more of them. It picks
+//! the segments to clean by cost-benefit, `(1 - u) * age / (1 + u)` as in the LFS paper,
+//! where `u` is the part of the data blocks of a segment still live, and its age is counted
+//! in segments started since it was, both told by the segment usage table, see
+//! `segment_usage()`. The live blocks, told by the segment summary and checked against the
+//! inodes and the imap, are written again at the head of the log, the inodes and imap
+//! blocks are synced, then the segments are free once a checkpoint is written, and trimmed
+//! on the device, see `checkpoint`. `clean()` runs it on demand. The `CleanerPolicy` tells
+//! when it runs by itself: once fewer free segments than its low watermark are left, up to
+//! its high one, so that the log does not run out of them and fail writes. A write finding
+//! the log short cleans before it takes any block, unless the policy leaves it to the
+//! background, where a kernel thread polls `run_cleaner_once()`, like
+//! `Flusher::flush_if_needed()`. The segments of the checkpoints pinned for snapshots are
+//! not cleaned. One run at a time cleans, alongside the writes, moving each block under the
+//! lock of its inode, and leaving out the segments with blocks taken by writes under way,
+//! not in their summary yet.
+//!
+//! The blocks the cleaner moves stayed live while those around them died, so they are
+//! likely to stay: they are cold, and written to segments of their own, `SegmentClass::Cold`,
+//! apart from the hot ones written as the filesystem is used. The log has one segment open
+//! at a time, so the cleaner starts a cold one before moving blocks, and a hot one is
+//! started after it, unless the segment current has nothing in it yet, which is taken as it
+//! is, or fewer than 2 segments are free, when the classes are mixed, as are the blocks
+//! written while the cleaner runs. A cold segment left
+//! fragmented stays so, as its blocks are not overwritten, so its age counts twice in the
+//! cost-benefit: it is cleaned at a higher utilization than a hot one.
+use crate::*;
+
+/// Free segments below which the log is cleaned, by default
+pub const CLEAN_LOW: usize = 4;
+/// Free segments the log is cleaned up to, by default
+pub const CLEAN_HIGH: usize = 8;
+
+/// When the log is cleaned by itself, told by its free segments
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct CleanerPolicy {
+    /// Free segments below which the log needs cleaning
+    pub low: usize,
+    /// Free segments a cleaning run makes, as far as dead blocks allow
+    pub high: usize,
+    /// Leave the cleaning to the kernel polling `run_cleaner_once()`,
+    /// rather than doing it in the write which finds the log short
+    pub background: bool,
+}
+
+impl Default for CleanerPolicy {
+    fn default() -> Self {
+        CleanerPolicy {
+            low: CLEAN_LOW,
+            high: CLEAN_HIGH,
+            background: false,
+        }
+    }
+}
+
+/// What a live block holds
+pub(crate) enum Live {
+    /// The inode itself, in a block of inodes
+    INode(INodeId),
+    /// An indirect block of the inode
+    Indirect(Arc<INodeImpl>, Node),
+    /// A block of the content of the inode, at its index
+    Data(Arc<INodeImpl>, usize),
+    /// The block of the extended attributes of the inode
+    XAttr(Arc<INodeImpl>),
+    /// An imap block, at its index
+    IMap(usize),
+}
+
+/// An entry of the segment usage table
+#[derive(Debug, Clone)]
+pub struct SegmentUsage {
+    pub seg_id: SegmentId,
+    /// bytes of its data blocks in use
+    pub live_bytes: usize,
+    /// bytes of its data blocks written and dead since, which cleaning it takes back
+    pub garbage_bytes: usize,
+    /// segments started since it was
+    pub age: u32,
+    /// whether the log is written to it now
+    pub current: bool,
+    /// class of its blocks
+    pub class: SegmentClass,
+}
+
+impl LogFileSystem {
+    /// The segment usage table: the segments in use by the log, with their live bytes and age.
+    /// It is kept in the metadata of each segment and in the checkpoints.
+    pub fn segment_usage(&self) -> Vec<SegmentUsage> {
+        let (current, now) = {
+            let sb = self.super_block.read();
+            (sb.current_seg_id as usize, sb.seg_seq)
+        };
+        self.segments
+            .read()
+            .iter()
+            .filter(|(_, seg)| seg.meta.unused == 0)
+            .map(|(&seg_id, seg)| SegmentUsage {
+                seg_id,
+                live_bytes: seg.meta.live_bytes as usize,
+                garbage_bytes: (seg.meta.size as usize - self.geometry.header_size())
+                    .saturating_sub(seg.meta.live_bytes as usize),
+                age: now.wrapping_sub(seg.meta.seq),
+                current: seg_id == current,
+                class: seg.meta.class(),
+            })
+            .collect()
+    }
+
+    /// Clean up to `max` segments, the best by cost-benefit first, see the module docs.
+    /// Return the number of segments cleaned, fewer if the log has no room for their live blocks.
+    pub fn clean(&self, max: usize) -> vfs::Result<usize> {
+        self.check_writable()?;
+        let cleaned = self.clean_cold(|| self.victims().into_iter().take(max).collect());
+        self.set_class(SegmentClass::Hot);
+        cleaned
+    }
+
+    /// Clean the segments `pick` tells, picked once one run at a time cleans, moving their
+    /// live blocks to cold segments
+    pub(crate) fn clean_cold(&self, pick: impl FnOnce() -> Vec<SegmentId>) -> vfs::Result<usize> {
+        let _cleaning = self.cleaning.lock();
+        let mut cleaned = Vec::new();
+        {
+            let _ops = self.ops.read();
+            for seg_id in pick() {
+                let live = self.live_blocks(seg_id);
+                // moving a block may move its inode too
+                if live.len() * 2 >= self.room() {
+                    break;
+                }
+                // none to move from a dead one, which needs no cold segment
+                if !live.is_empty() {
+                    self.set_class(SegmentClass::Cold);
+                }
+                debug!("clean seg {} live {}", seg_id, live.len());
+                for (block, live) in live {
+                    self.move_block(block, live)?;
+                    self.stats.update(|s| s.blocks_moved += 1);
+                }
+                cleaned.push(seg_id);
+            }
+        }
+        if cleaned.is_empty() {
+            return Ok(0);
+        }
+        // the segments are only reused once nothing on disk refers to them,
+        // the blocks moved being synced and the checkpoint written without them
+        self.sync()?;
+        for &seg_id in cleaned.iter() {
+            self.release_segment(seg_id);
+        }
+        self.checkpoint()?;
+        Ok(cleaned.len())
+    }
+
+    /// Set when the log is cleaned by itself, `CleanerPolicy::default()` until then
+    pub fn set_cleaner_policy(&self, policy: CleanerPolicy) {
+        *self.cleaner.write() = policy;
+    }
+
+    pub fn cleaner_policy(&self) -> CleanerPolicy {
+        *self.cleaner.read()
+    }
+
+    /// Whether fewer segments are free than the low watermark of the policy
+    pub fn needs_cleaning(&self) -> bool {
+        self.snapshot.is_none()
+            && !self.read_only
+            && self.free_segments() < self.cleaner.read().low
+    }
+
+    /// Clean if the log needs it, up to the high watermark of the policy.
+    /// Return the number of segments cleaned.
+    pub fn run_cleaner_once(&self) -> vfs::Result<usize> {
+        if !self.needs_cleaning() {
+            return Ok(0);
+        }
+        let high = self.cleaner.read().high;
+        let mut cleaned = 0;
+        // the live blocks moved take free segments too, so it goes on while it gains some
+        loop {
+            let free = self.free_segments();
+            if free >= high {
+                break;
+            }
+            cleaned += self.clean(high - free)?;
+            if self.free_segments() <= free {
+                break;
+            }
+        }
+        Ok(cleaned)
+    }
+
+    /// Clean before a write takes blocks, if the log needs it and the policy does not
+    /// leave it to the background. The write goes on if there was nothing to clean,
+    /// it may still fit.
+    pub(crate) fn make_room(&self) -> vfs::Result<()> {
+        if self.cleaner.read().background {
+            return Ok(());
+        }
+        match self.run_cleaner_once() {
+            Err(FsError::NoDeviceSpace) => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+
+    /// Number of segments not in use by the log
+    pub fn free_segments(&self) -> usize {
+        self.segments
+            .read()
+            .values()
+            .filter(|seg| seg.meta.unused == 1)
+            .count()
+    }
+
+    /// The segments in use but the current one and those pinned, with some dead blocks,
+    /// the best to clean first
+    pub(crate) fn victims(&self) -> Vec<SegmentId> {
+        let pinned = self.pinned_segments();
+        let data_blks = self.geometry.data_blks() as u64;
+        let mut victims = Vec::new();
+        for usage in self.segment_usage() {
+            let live = ((usage.live_bytes / BLKSIZE) as u64).min(data_blks);
+            if usage.current
+                || live == data_blks
+                || pinned.contains(&usage.seg_id)
+                || !self.all_recorded(usage.seg_id)
+            {
+                continue;
+            }
+            let age = match usage.class {
+                SegmentClass::Hot => usage.age as u64 + 1,
+                SegmentClass::Cold => (usage.age as u64 + 1) * 2,
+            };
+            // (1 - u) * age / (1 + u), scaled to be compared as an integer
+            let benefit = ((data_blks - live) * age << 16) / (data_blks + live);
+            victims.push((benefit, usage.seg_id));
+        }
+        victims.sort_by(|a, b| b.0.cmp(&a.0));
+        victims.into_iter().map(|(_, seg_id)| seg_id).collect()
+    }
+
+    /// The live blocks of segment `seg_id`, told by its summary and checked against the inodes
+    pub(crate) fn live_blocks(&self, seg_id: SegmentId) -> Vec<(BlockId, Live)> {
+        let entries: Vec<(BlockId, INodeId, i32)> = {
+            let segments = self.segments.read();
+            let summary = segments[&seg_id].summary_map.read();
+            summary
+                .iter()
+                .filter(|(_, entry)| entry.entry_id != ENTRY_GARBAGE as i32)
+                .map(|(&block, entry)| (block, entry.inode_id as INodeId, entry.entry_id))
+                .collect()
+        };
+        let mut live = Vec::new();
+        for (block, ino_id, entry_id) in entries {
+            if entry_id == ENTRY_IMAPBLOCK as i32 {
+                if self.imap.read().is_at(ino_id, block) {
+                    live.push((block, Live::IMap(ino_id)));
+                }
+                continue;
+            }
+            if entry_id == ENTRY_INODEBLOCK as i32 {
+                let inodes = self
+                    .inodes_in_block(block)
+                    .expect("failed to read block of inodes");
+                for ino_id in inodes {
+                    if self.imap_get(ino_id) == Some(block) {
+                        live.push((block, Live::INode(ino_id)));
+                    }
+                }
+                continue;
+            }
+            let inode = match self.find_inode(ino_id) {
+                Some(inode) => inode,
+                None => continue,
+            };
+            // being changed, it is checked again once moved
+            let changing = inode.lock.try_read().is_none();
+            if entry_id == ENTRY_XATTR as i32 {
+                if changing || inode.disk_inode.read().xattr == block as u64 {
+                    live.push((block, Live::XAttr(inode)));
+                }
+            } else if let Some(node) = Node::from_entry(entry_id) {
+                // an indirect block of the inode, or an old copy of it
+                if changing || inode.indirect_block_id(node).ok() == Some(block) {
+                    live.push((block, Live::Indirect(inode, node)));
+                }
+            } else if changing || inode.get_disk_block_id(entry_id as usize).ok() == Some(block) {
+                live.push((block, Live::Data(inode, entry_id as usize)));
+            }
+        }
+        live
+    }
+
+    /// Whether the blocks taken from segment `seg_id` are all in its summary, which they
+    /// are not while the writes taking them are under way
+    pub(crate) fn all_recorded(&self, seg_id: SegmentId) -> bool {
+        let segments = self.segments.read();
+        let seg = &segments[&seg_id];
+        let taken = (seg.meta.size as usize - self.geometry.header_size()) / BLKSIZE;
+        let recorded = seg.summary_map.read().len();
+        recorded >= taken
+    }
+
+    /// Write live block `block` again at the head of the log, and free it
+    fn move_block(&self, block: BlockId, live: Live) -> vfs::Result<()> {
+        match live {
+            Live::INode(ino_id) => {
+                // a stale inode is written to a new block, with the others at the next sync
+                if let Some(inode) = self.find_inode(ino_id) {
+                    let mut disk_inode = inode.disk_inode.write();
+                    disk_inode.turn_dirty();
+                    disk_inode.turn_stale();
+                }
+                Ok(())
+            }
+            Live::Indirect(inode, node) => {
+                let _lock = inode.lock.write();
+                // copied already if a block it tells of was moved before
+                if inode.indirect_block_id(node).ok() != Some(block) {
+                    return Ok(());
+                }
+                let new_blk_id = self.copy_block(block)?;
+                inode.set_indirect_block_id(node, new_blk_id)?;
+                self._record_block_summary(inode.id, new_blk_id, node.entry());
+                self.free_block(block);
+                Ok(())
+            }
+            Live::Data(inode, entry_id) => {
+                let _lock = inode.lock.write();
+                // written over since
+                if inode.get_disk_block_id(entry_id).ok() != Some(block) {
+                    return Ok(());
+                }
+                let new_blk_id = self.copy_block(block)?;
+                inode.set_disk_block_id(entry_id, new_blk_id)?;
+                self._record_block_summary(inode.id, new_blk_id, entry_id as isize);
+                self.free_block(block);
+                Ok(())
+            }
+            Live::XAttr(inode) => {
+                let _lock = inode.lock.write();
+                // set or removed since
+                if inode.disk_inode.read().xattr != block as u64 {
+                    return Ok(());
+                }
+                let new_blk_id = self.copy_block(block)?;
+                inode.set_xattr_block(new_blk_id);
+                self._record_block_summary(inode.id, new_blk_id, ENTRY_XATTR);
+                self.free_block(block);
+                Ok(())
+            }
+            // written to a new block at the next sync
+            Live::IMap(index) => self.imap.write().touch(&self.device, index),
+        }
+    }
+
+    /// Copy block `block` to a new block of the log, return the new one
+    pub(crate) fn copy_block(&self, block: BlockId) -> vfs::Result<BlockId> {
+        let new_blk_id = self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
+        let mut buf = [0u8; BLKSIZE];
+        self.device.read_block(block, 0, &mut buf)?;
+        self.device.write_block(new_blk_id, 0, &buf)?;
+        Ok(new_blk_id)
+    }
+
+    /// Write the blocks taken from now on to segments of class `class`, see the module docs
+    fn set_class(&self, class: SegmentClass) {
+        *self.class.write() = class;
+        let current = self.super_block.read().current_seg_id as usize;
+        let started = {
+            let mut segments = self.segments.write();
+            let meta = &mut segments.get_mut(&current).unwrap().meta;
+            if meta.class() == class {
+                return;
+            }
+            if meta.size as usize == self.geometry.header_size() {
+                meta.class = class as u32;
+                return;
+            }
+            meta.size as usize != self.geometry.segment_size()
+        };
+        if started && self.free_segments() >= 2 {
+            debug!("end seg {} for {:?} blocks", current, class);
+            self.alloc_segment();
+        }
+    }
+
+    /// Blocks the log can take before it runs out of free segments
+    pub(crate) fn room(&self) -> usize {
+        let current = self.super_block.read().current_seg_id as usize;
+        let segments = self.segments.read();
+        let free = segments.values().filter(|seg| seg.meta.unused == 1).count();
+        let size = segments[&current].meta.size as usize;
+        (self.geometry.segment_size() - size) / BLKSIZE + free * self.geometry.data_blks()
+    }
+
+    /// Make segment `seg_id`, with no live block left, free for the log
+    pub(crate) fn release_segment(&self, seg_id: SegmentId) {
+        {
+            let mut segments = self.segments.write();
+            let seg = segments.get_mut(&seg_id).unwrap();
+            if seg.meta.unused == 0 {
+                self.stats.update(|s| s.segments_cleaned += 1);
+                self.cleaned.write().insert(seg_id);
+            }
+            seg.meta.unused = 1;
+            seg.meta.inodes_num = 0;
+            seg.meta.live_bytes = 0;
+            seg.meta.size = self.geometry.header_size() as u32;
+            seg.meta.data_checksum = 0;
+            seg.checksum_end = seg.meta.size;
+            seg.seg_imap.write().clear();
+            seg.summary_map.write().clear();
+            seg.dir_log.write().clear();
+        }
+        debug!("seg {} unused", seg_id);
+    }
+}
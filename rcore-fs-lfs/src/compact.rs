@@ -0,0 +1,98 @@
+//! Compaction of LFS
+//!
+//! `LogFileSystem::compact()` makes an image as small as its live data, to be handed out or
+//! archived. The segments with dead blocks are cleaned as the cleaner does, their live
+//! blocks packed in segments of their own, then the segments in use are moved to the front
+//! of the device, the highest first, each cleaned too, its live blocks written to the
+//! lowest free segments, until no free segment is left below the highest in use, or its
+//! blocks no longer fit below it. Then the segments after it are cut off but for
+//! `CLEAN_HEADROOM` free ones, so that the log can still be cleaned once the image is
+//! mounted: the super block and the checkpoint tell the fewer segments, and the device
+//! can be truncated to the size returned. The checkpoint is written first, so that a crash
+//! before the super block leaves the one before it valid, which differs only by the
+//! segments it tells. It is meant for an idle filesystem: it fails with `Busy` if an inode
+//! is in use or a checkpoint is pinned for a snapshot, whose segments could not be moved.
+use crate::*;
+
+impl LogFileSystem {
+    /// Move the live data to the front of the device and cut off the segments after it,
+    /// see `compact`. Return the bytes of the device in use now, which it can be truncated to.
+    pub fn compact(&self) -> vfs::Result<usize> {
+        self.check_writable()?;
+        if !self.pinned.read().is_empty()
+            || self.inodes.read().values().any(|inode| inode.strong_count() > 0)
+        {
+            return Err(FsError::Busy);
+        }
+        // the segments with dead blocks packed first, then moved down
+        self.clean_cold(|| self.victims())?;
+        let mut last = None;
+        loop {
+            let (highest, free_below) = self.used_tail();
+            if !free_below {
+                break;
+            }
+            if highest == self.super_block.read().current_seg_id as usize {
+                // the head of the log goes to the lowest free segment
+                self.alloc_segment();
+                continue;
+            }
+            // its blocks did not fit below it
+            if last.map_or(false, |last| highest >= last) {
+                break;
+            }
+            last = Some(highest);
+            debug!("compact seg {}", highest);
+            self.clean_cold(|| vec![highest])?;
+        }
+        // the segment current is kept, rather than starting one after it
+        *self.class.write() = SegmentClass::Hot;
+        self.shrink()
+    }
+
+    /// The highest segment in use, and whether any below it is free
+    pub(crate) fn used_tail(&self) -> (SegmentId, bool) {
+        let segments = self.segments.read();
+        let highest = segments
+            .iter()
+            .filter(|(_, seg)| seg.meta.unused == 0)
+            .map(|(&seg_id, _)| seg_id)
+            .max()
+            .unwrap_or(1);
+        let free_below = segments
+            .range(..highest)
+            .any(|(_, seg)| seg.meta.unused == 1);
+        (highest, free_below)
+    }
+
+    /// Cut off the free segments after the highest in use but for the headroom of the
+    /// cleaner, as many as `create` allows.
+    /// Return the bytes of the device in use.
+    fn shrink(&self) -> vfs::Result<usize> {
+        let _ops = self.ops.write();
+        let segment_size = self.geometry.segment_size();
+        let (highest, _) = self.used_tail();
+        let n_segment = (highest + 1 + CLEAN_HEADROOM).max(3);
+        {
+            let mut sb = self.super_block.write();
+            if n_segment >= sb.n_segment as usize {
+                return Ok(sb.n_segment as usize * segment_size);
+            }
+            debug!("shrink {} -> {} segments", sb.n_segment, n_segment);
+            // synced by the checkpoint before, they are dropped as they are
+            let mut segments = self.segments.write();
+            segments.split_off(&n_segment);
+            self.cleaned.write().retain(|&seg_id| seg_id < n_segment);
+            sb.n_segment = n_segment as u32;
+            sb.blocks = (n_segment * self.geometry.segment_blks()) as u64;
+            sb.unused_blocks = unused_blocks(&self.geometry, &segments);
+            self.check_region.write().turn_dirty();
+        }
+        self.write_checkpoint()?;
+        let mut sb = self.super_block.write();
+        self.device.write_block(BLKN_SUPER, 0, sb.as_buf())?;
+        sb.sync();
+        self.device.sync()?;
+        Ok(n_segment * segment_size)
+    }
+}
@@ -0,0 +1,290 @@
+//! Directories of LFS
+//!
+//! The entries of a dir are kept in a hash table of its blocks, so that creating or
+//! removing one writes a single block to the log, however large the dir. A block holds
+//! `DIRENTS_PER_BLOCK` slots, and an entry is put in the first block with a slot free from
+//! the one its name hashes to, wrapping around, so it is looked up from there up to the
+//! first block with a slot never used. The slot of an entry removed is marked so: the next
+//! entry put in the block may take it, but it does not end a lookup. Once 3/4 of the slots
+//! would be taken, or an entry would be put more than `MAX_PROBE` blocks after its own, the
+//! table is made again, twice as large if it is that full, leaving out the slots removed.
+//!
+//! The size of a dir is `DIRENT_SIZE` times the number of its entries, and its blocks are
+//! those of the table. `get_entry()` tells the entries in the order of their slots, going
+//! on from the one it told last.
+use crate::*;
+
+/// Blocks after the one its name hashes to an entry is put in at most
+const MAX_PROBE: usize = 4;
+/// `DiskEntry::id` of a slot whose entry was removed
+const REMOVED: u32 = u32::max_value();
+
+/// What a slot of a dir holds
+pub(crate) enum Slot<'a> {
+    /// never an entry, which ends a lookup
+    Free,
+    /// an entry removed
+    Removed,
+    /// an entry, naming inode `id`
+    Entry(u32, &'a str),
+}
+
+/// What slot `i` of block `buf` of a dir holds. A name not well formed is told as empty.
+pub(crate) fn slot(buf: &[u8], i: usize) -> Slot<'_> {
+    let entry = &buf[i * DIRENT_SIZE..(i + 1) * DIRENT_SIZE];
+    let id = u32::from_ne_bytes([entry[0], entry[1], entry[2], entry[3]]);
+    let name = &entry[ENTRY_SIZE..];
+    let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    match (len, id) {
+        (0, REMOVED) => Slot::Removed,
+        (0, _) => Slot::Free,
+        _ => Slot::Entry(id, core::str::from_utf8(&name[..len]).unwrap_or("")),
+    }
+}
+
+/// Block of a table of `blocks` blocks entry `name` is looked up from
+pub(crate) fn home_block(name: &str, blocks: usize) -> usize {
+    crc32c(name.as_bytes()) as usize % blocks
+}
+
+/// Put entry `entry` in slot `i` of block `buf` of a dir
+fn put(buf: &mut [u8], i: usize, entry: &DiskEntry) {
+    let bytes = entry.as_buf();
+    buf[i * DIRENT_SIZE..i * DIRENT_SIZE + bytes.len()].copy_from_slice(bytes);
+}
+
+impl INodeImpl {
+    /// Init dir content. Insert 2 init entries.
+    /// This do not init nlinks, please modify the nlinks in the invoker.
+    pub(crate) fn init_direntry(&self, parent: INodeId) -> vfs::Result<()> {
+        let entries = [(self.id as u32, "."), (parent as u32, "..")];
+        self.write_table(&entries, 1)
+    }
+
+    /// Inode named `name` in the dir, with its slot
+    pub(crate) fn get_file_inode_and_entry_id(&self, name: &str) -> Option<(INodeId, usize)> {
+        let blocks = self.disk_inode.read().blocks as usize;
+        let home = home_block(name, blocks);
+        let mut buf = [0u8; BLKSIZE];
+        for n in 0..blocks {
+            let index = (home + n) % blocks;
+            self.read_dir_block(index, &mut buf).unwrap();
+            let mut free = false;
+            for i in 0..DIRENTS_PER_BLOCK {
+                match slot(&buf, i) {
+                    Slot::Entry(id, entry) if entry == name => {
+                        return Some((id as INodeId, index * DIRENTS_PER_BLOCK + i))
+                    }
+                    Slot::Free => free = true,
+                    _ => {}
+                }
+            }
+            if free {
+                break;
+            }
+        }
+        None
+    }
+
+    /// Write `direntry` to slot `id`, which must be the one of its name
+    pub(crate) fn write_direntry(&self, id: usize, direntry: &DiskEntry) -> vfs::Result<()> {
+        *self.dir_cursor.write() = (0, 0);
+        let offset = slot_offset(id);
+        let buf = direntry.as_buf();
+        self.disk_inode.write().turn_dirty();
+        let res = self._io_at(
+            offset,
+            offset + buf.len(),
+            |device, range, offset| {
+                device.write_block(range.block, range.begin, &buf[offset..offset + range.len()])
+            },
+            true,
+        );
+        self.disk_inode.write().clear_stale();
+        res.map(|_| ())
+    }
+
+    /// Put `direntry` in the table, whose name is not in it
+    pub(crate) fn append_direntry(&self, direntry: &DiskEntry) -> vfs::Result<()> {
+        let (count, blocks) = {
+            let disk_inode = self.disk_inode.read();
+            (
+                disk_inode.size as usize / DIRENT_SIZE,
+                disk_inode.blocks as usize,
+            )
+        };
+        if (count + 1) * 4 <= blocks * DIRENTS_PER_BLOCK * 3 {
+            let home = home_block(direntry.name.as_ref(), blocks);
+            let mut buf = [0u8; BLKSIZE];
+            for n in 0..blocks.min(MAX_PROBE + 1) {
+                let index = (home + n) % blocks;
+                self.read_dir_block(index, &mut buf)?;
+                let free = (0..DIRENTS_PER_BLOCK).find(|&i| match slot(&buf, i) {
+                    Slot::Entry(..) => false,
+                    _ => true,
+                });
+                if let Some(i) = free {
+                    self.write_direntry(index * DIRENTS_PER_BLOCK + i, direntry)?;
+                    self.disk_inode.write().size += DIRENT_SIZE as u64;
+                    self.touch();
+                    return Ok(());
+                }
+            }
+        }
+        let mut entries = self.dir_entries()?;
+        entries.push((direntry.id, String::from(direntry.name.as_ref())));
+        let mut new_blocks = blocks;
+        while new_blocks * DIRENTS_PER_BLOCK * 3 < entries.len() * 4 {
+            new_blocks *= 2;
+        }
+        // as many blocks as a file can have
+        let new_blocks = new_blocks.min(MAX_NBLOCK_TRIPLE_INDIRECT);
+        if entries.len() > new_blocks * DIRENTS_PER_BLOCK {
+            return Err(FsError::NoDeviceSpace);
+        }
+        debug!("dir {} table {} -> {} blocks", self.id, blocks, new_blocks);
+        let entries: Vec<_> = entries
+            .iter()
+            .map(|(id, name)| (*id, name.as_str()))
+            .collect();
+        self.write_table(&entries, new_blocks)?;
+        self.touch();
+        Ok(())
+    }
+
+    /// Mark slot `id` removed
+    pub(crate) fn remove_direntry(&self, id: usize) -> vfs::Result<()> {
+        debug!(
+            "remove_dentry id {} count {}",
+            id,
+            self.disk_inode.read().size as usize / DIRENT_SIZE
+        );
+        self.write_direntry(
+            id,
+            &DiskEntry {
+                id: REMOVED,
+                name: Str256::from(""),
+            },
+        )?;
+        self.disk_inode.write().size -= DIRENT_SIZE as u64;
+        self.touch();
+        Ok(())
+    }
+
+    /// Name of the entry `id`-th in the order of the slots, if there are as many
+    pub(crate) fn nth_entry(&self, id: usize) -> vfs::Result<Option<String>> {
+        let slots = self.disk_inode.read().blocks as usize * DIRENTS_PER_BLOCK;
+        let (mut n, mut pos) = match *self.dir_cursor.read() {
+            (n, pos) if n <= id => (n, pos),
+            _ => (0, 0),
+        };
+        let mut buf = [0u8; BLKSIZE];
+        let mut loaded = None;
+        while pos < slots {
+            let index = pos / DIRENTS_PER_BLOCK;
+            if loaded != Some(index) {
+                self.read_dir_block(index, &mut buf)?;
+                loaded = Some(index);
+            }
+            if let Slot::Entry(_, name) = slot(&buf, pos % DIRENTS_PER_BLOCK) {
+                if n == id {
+                    *self.dir_cursor.write() = (n, pos);
+                    return Ok(Some(String::from(name)));
+                }
+                n += 1;
+            }
+            pos += 1;
+        }
+        Ok(None)
+    }
+
+    /// The entries of the table, in the order of their slots
+    fn dir_entries(&self) -> vfs::Result<Vec<(u32, String)>> {
+        let blocks = self.disk_inode.read().blocks as usize;
+        let mut entries = Vec::new();
+        let mut buf = [0u8; BLKSIZE];
+        for index in 0..blocks {
+            self.read_dir_block(index, &mut buf)?;
+            for i in 0..DIRENTS_PER_BLOCK {
+                if let Slot::Entry(id, name) = slot(&buf, i) {
+                    entries.push((id, String::from(name)));
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Make the table again with `entries`, in `blocks` blocks, as many as it has or more
+    fn write_table(&self, entries: &[(u32, &str)], blocks: usize) -> vfs::Result<()> {
+        let mut table = vec![0u8; blocks * BLKSIZE];
+        for &(id, name) in entries {
+            let home = home_block(name, blocks);
+            let (index, i) = (0..blocks)
+                .map(|n| (home + n) % blocks)
+                .find_map(|index| {
+                    let buf = &table[index * BLKSIZE..(index + 1) * BLKSIZE];
+                    (0..DIRENTS_PER_BLOCK)
+                        .find(|&i| match slot(buf, i) {
+                            Slot::Free => true,
+                            _ => false,
+                        })
+                        .map(|i| (index, i))
+                })
+                .unwrap();
+            let entry = DiskEntry {
+                id,
+                name: Str256::from(name),
+            };
+            put(
+                &mut table[index * BLKSIZE..(index + 1) * BLKSIZE],
+                i,
+                &entry,
+            );
+        }
+        if blocks > self.disk_inode.read().blocks as usize {
+            self._resize(blocks * BLKSIZE)?;
+        }
+        *self.dir_cursor.write() = (0, 0);
+        // the blocks written whole are new ones, nothing is copied
+        self.disk_inode.write().turn_dirty();
+        let res = self._io_at(
+            0,
+            table.len(),
+            |device, range, offset| {
+                device.write_block(
+                    range.block,
+                    range.begin,
+                    &table[offset..offset + range.len()],
+                )
+            },
+            true,
+        );
+        let mut disk_inode = self.disk_inode.write();
+        disk_inode.clear_stale();
+        disk_inode.size = (entries.len() * DIRENT_SIZE) as u64;
+        res.map(|_| ())
+    }
+
+    /// Read block `index` of the table
+    fn read_dir_block(&self, index: usize, buf: &mut [u8; BLKSIZE]) -> vfs::Result<()> {
+        let begin = index * BLKSIZE;
+        self._io_at(
+            begin,
+            begin + BLKSIZE,
+            |device, range, offset| {
+                device.read_block(
+                    range.block,
+                    range.begin,
+                    &mut buf[offset..offset + range.len()],
+                )
+            },
+            false,
+        )?;
+        Ok(())
+    }
+}
+
+/// Offset of slot `id` in the table
+fn slot_offset(id: usize) -> usize {
+    id / DIRENTS_PER_BLOCK * BLKSIZE + id % DIRENTS_PER_BLOCK * DIRENT_SIZE
+}
@@ -0,0 +1,233 @@
+//! Log of the namespace operations of LFS
+//!
+//! Creating, linking, unlinking or renaming changes a directory and the inodes it names,
+//! which reach the log at different times, e.g. the directory while a segment is filled and
+//! the inodes at the next sync. So each operation is recorded by what it leaves behind: the
+//! entries it sets or removes, and the links of the inodes it changes. The record is kept
+//! in the metadata block of the segment current when the operation began, and written with
+//! it, under its checksum. Blocks already on disk, inodes and indirect blocks too, are not
+//! written over, but to the log again, so nothing an operation changes is reachable on
+//! disk before the segment holding its record.
+//!
+//! When the log is rolled forward, the records in it are redone in order, once the inodes
+//! are: an entry naming an inode which did not reach the log is removed instead, and an
+//! inode left with no link is freed. The records are dropped at each checkpoint, which
+//! tells their results, and if a segment has no room left for one, a checkpoint is
+//! written instead of it, once the operations under way are done, see `dirop()`.
+use crate::*;
+
+/// Offset of the records in the metadata block of a segment, after `SegmentMeta`
+pub(crate) const DIRLOG_OFFSET: usize = 64;
+/// Bytes of records a segment can keep, after their `DirLog::seq` and length, u32 each
+pub(crate) const DIRLOG_SIZE: usize = SEGMENT_META_SIZE - DIRLOG_OFFSET - 8;
+
+const TAG_ENTRY: u8 = 1;
+const TAG_LINKS: u8 = 2;
+/// `DirOp::Entry::child` of an entry removed
+const NO_CHILD: u32 = u32::max_value();
+
+/// What a namespace operation leaves behind
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) enum DirOp {
+    /// Entry `name` of directory `dir` names inode `child`, or is removed if none
+    Entry {
+        dir: INodeId,
+        name: String,
+        child: Option<INodeId>,
+    },
+    /// Inode `ino` has `nlinks` links, and is freed if none
+    Links { ino: INodeId, nlinks: u16 },
+}
+
+impl DirOp {
+    pub fn entry(dir: INodeId, name: &str, child: Option<INodeId>) -> Self {
+        DirOp::Entry {
+            dir,
+            name: String::from(name),
+            child,
+        }
+    }
+
+    /// Append it to `buf` as recorded on disk
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            DirOp::Entry { dir, name, child } => {
+                buf.push(TAG_ENTRY);
+                buf.extend_from_slice(&(*dir as u32).to_ne_bytes());
+                let child = child.map_or(NO_CHILD, |child| child as u32);
+                buf.extend_from_slice(&child.to_ne_bytes());
+                buf.push(name.len() as u8);
+                buf.extend_from_slice(name.as_bytes());
+            }
+            DirOp::Links { ino, nlinks } => {
+                buf.push(TAG_LINKS);
+                buf.extend_from_slice(&(*ino as u32).to_ne_bytes());
+                buf.extend_from_slice(&(*nlinks as u32).to_ne_bytes());
+            }
+        }
+    }
+
+    /// The operations recorded in `buf`, up to the first one not well formed
+    pub fn decode_all(mut buf: &[u8]) -> Vec<DirOp> {
+        let u32_at =
+            |buf: &[u8], i: usize| u32::from_ne_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+        let mut ops = Vec::new();
+        loop {
+            let len = match buf.first() {
+                Some(&TAG_ENTRY) if buf.len() >= 10 => 10 + buf[9] as usize,
+                Some(&TAG_LINKS) => 9,
+                _ => break,
+            };
+            if buf.len() < len {
+                break;
+            }
+            let op = match buf[0] {
+                TAG_ENTRY => {
+                    let name = match core::str::from_utf8(&buf[10..len]) {
+                        Ok(name) => name,
+                        Err(_) => break,
+                    };
+                    let child = match u32_at(buf, 5) {
+                        NO_CHILD => None,
+                        child => Some(child as INodeId),
+                    };
+                    DirOp::entry(u32_at(buf, 1) as INodeId, name, child)
+                }
+                _ => DirOp::Links {
+                    ino: u32_at(buf, 1) as INodeId,
+                    nlinks: u32_at(buf, 5) as u16,
+                },
+            };
+            ops.push(op);
+            buf = &buf[len..];
+        }
+        ops
+    }
+}
+
+impl DirLog {
+    pub fn clear(&mut self) {
+        self.records.clear();
+        self.synced = 0;
+    }
+}
+
+impl INodeImpl {
+    /// Its links now, to be recorded
+    pub(crate) fn links_op(&self) -> DirOp {
+        DirOp::Links {
+            ino: self.id,
+            nlinks: self.disk_inode.read().nlinks,
+        }
+    }
+}
+
+impl LogFileSystem {
+    /// Segment the record of a namespace operation beginning now is kept in
+    pub(crate) fn dirop_segment(&self) -> SegmentId {
+        self.super_block.read().current_seg_id as usize
+    }
+
+    /// Run namespace operation `op`, which records what it does with `log_dirop()`, then
+    /// write the checkpoint one of them asked for, if any
+    pub(crate) fn dirop<T>(&self, op: impl FnOnce() -> vfs::Result<T>) -> vfs::Result<T> {
+        let res = {
+            let _ops = self.ops.read();
+            op()
+        };
+        if *self.checkpoint_wanted.read() {
+            self.checkpoint()?;
+        }
+        res
+    }
+
+    /// Record namespace operation `ops`, begun when segment `seg_id` was current. If it has
+    /// no room left, or is not part of the log since the checkpoint any more, ask for a
+    /// checkpoint instead.
+    pub(crate) fn log_dirop(&self, seg_id: SegmentId, ops: &[DirOp]) {
+        let mut record = Vec::new();
+        for op in ops {
+            op.encode(&mut record);
+        }
+        let (seq, seg_seq) = {
+            let cr = self.check_region.read();
+            (cr.seq, cr.seg_seq)
+        };
+        {
+            let segments = self.segments.read();
+            let segment = &segments[&seg_id];
+            let in_log = segment.meta.unused == 0 && started_since(segment.meta.seq, seg_seq);
+            let mut dir_log = segment.dir_log.write();
+            if in_log && dir_log.records.len() + record.len() <= DIRLOG_SIZE {
+                if dir_log.records.is_empty() {
+                    dir_log.seq = seq;
+                }
+                dir_log.records.extend_from_slice(&record);
+                return;
+            }
+        }
+        debug!("no room for dirop in seg {}", seg_id);
+        // written once the operations under way are done
+        *self.checkpoint_wanted.write() = true;
+    }
+
+    /// Drop the records written before checkpoint `seq`, the rest are done after it
+    pub(crate) fn drop_dir_logs(&self, seq: u32) {
+        for segment in self.segments.read().values() {
+            let mut dir_log = segment.dir_log.write();
+            if dir_log.records.is_empty() {
+                continue;
+            }
+            let synced = dir_log.synced;
+            dir_log.records.drain(..synced);
+            dir_log.synced = 0;
+            dir_log.seq = seq;
+            // those left on disk are told to be older than the checkpoint
+            if dir_log.records.is_empty() {
+                dir_log.sync();
+            }
+        }
+    }
+
+    /// Redo namespace operations `ops` rolled forward, in order
+    pub(crate) fn redo_dirops(&self, ops: Vec<DirOp>) -> vfs::Result<()> {
+        for op in ops {
+            debug!("redo {:?}", op);
+            match op {
+                DirOp::Entry { dir, name, child } => {
+                    if self.imap_get(dir).is_none() {
+                        continue;
+                    }
+                    let dir = self.get_inode(dir);
+                    // an inode which did not reach the log is named by no entry
+                    let child = child.filter(|&child| self.imap_get(child).is_some());
+                    let entry = child.map(|child| DiskEntry {
+                        id: child as u32,
+                        name: Str256::from(name.as_str()),
+                    });
+                    match (dir.get_file_inode_and_entry_id(&name), entry) {
+                        (Some((id, _)), Some(entry)) if id == entry.id as INodeId => {}
+                        (Some((_, entry_id)), Some(entry)) => {
+                            dir.write_direntry(entry_id, &entry)?
+                        }
+                        (None, Some(entry)) => dir.append_direntry(&entry)?,
+                        (Some((_, entry_id)), None) => dir.remove_direntry(entry_id)?,
+                        (None, None) => {}
+                    }
+                }
+                DirOp::Links { ino, nlinks } => {
+                    if self.imap_get(ino).is_none() {
+                        continue;
+                    }
+                    // freed when dropped if it has none
+                    let inode = self.get_inode(ino);
+                    let mut disk_inode = inode.disk_inode.write();
+                    if disk_inode.nlinks != nlinks {
+                        disk_inode.nlinks = nlinks;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,686 @@
+//! Consistency check of LFS, like `fsck` of other file systems
+//!
+//! The imap blocks are checked first, as the inodes are found through them. The dir tree is
+//! then walked from root, finding the blocks in use, what each one holds and the entries
+//! referring to each inode. They are compared with the summaries of the segments, the
+//! bytes live in each one, the slots in use of each block of inodes and the link counts,
+//! which can then be repaired. An imap lost or written over can be made again from the
+//! blocks of inodes by `rebuild_imap()`, before a check repairs what it leaves. LFS opened
+//! after a crash tore a segment of its log runs a check with repairs, see `checkpoint`.
+use crate::dir::{self, Slot};
+use crate::indirect;
+use crate::*;
+
+/// A problem found by `check()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// An imap block is kept in a block out of the data blocks in use, or in a block used
+    /// twice, nothing else is checked, see `rebuild_imap()`
+    BadIMapBlock(usize),
+    /// A block of an inode is out of the data blocks in use of the segments
+    BadBlock { inode: INodeId, block: BlockId },
+    /// A block is used twice, by the same inode or by two inodes
+    DuplicateBlock { inode: INodeId, block: BlockId },
+    /// A block in use is garbage in the summary of its segment, so that it would be taken
+    /// back by the cleaner
+    UsedBlockFree(BlockId),
+    /// The summary entry of a block in use does not tell what it holds
+    WrongSummary(BlockId),
+    /// A block live in the summary of its segment is used by nothing, a dangling block
+    DanglingBlock(BlockId),
+    /// The number of slots in use of a block of inodes in its summary entry is wrong
+    INodeSlots {
+        block: BlockId,
+        recorded: usize,
+        actual: usize,
+    },
+    /// The bytes live in a segment are not those of the blocks in use in it
+    LiveBytes {
+        segment: SegmentId,
+        recorded: usize,
+        actual: usize,
+    },
+    /// The number of unused blocks in the super block is wrong
+    UnusedBlocks { recorded: usize, actual: usize },
+    /// An inode of the imap is in no dir, or can not be found where the imap tells
+    OrphanINode(INodeId),
+    /// An entry of a dir refers to something which is not an inode, has a bad name or the
+    /// name of another entry, is in a slot a lookup of its name does not reach, or refers
+    /// to a dir in another dir
+    BadEntry { dir: INodeId, index: usize },
+    /// A dir has wrong '.' or '..' entries, or is not in the dir its '..' refers to
+    BadDir(INodeId),
+    /// The number of entries a dir tells by its size is not the one in its slots
+    EntryCount {
+        dir: INodeId,
+        recorded: usize,
+        actual: usize,
+    },
+    /// The link count of an inode is not the number of entries referring to it
+    LinkCount {
+        inode: INodeId,
+        recorded: usize,
+        actual: usize,
+    },
+}
+
+impl Problem {
+    /// Whether `check()` can repair it
+    pub fn can_repair(&self) -> bool {
+        match self {
+            Problem::UsedBlockFree(_)
+            | Problem::WrongSummary(_)
+            | Problem::DanglingBlock(_)
+            | Problem::INodeSlots { .. }
+            | Problem::LiveBytes { .. }
+            | Problem::UnusedBlocks { .. }
+            | Problem::OrphanINode(_)
+            | Problem::BadEntry { .. }
+            | Problem::EntryCount { .. }
+            | Problem::LinkCount { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+/// Result of `check()`
+#[derive(Debug)]
+pub struct Report {
+    pub problems: Vec<Problem>,
+    /// Whether the problems are repaired
+    pub repaired: bool,
+}
+
+/// Check `fs`, and repair it if `repair` is set and all problems can be repaired:
+/// the summaries, the bytes live in each segment and the count of unused blocks follow
+/// the blocks found in use, orphan inodes are dropped from the imap, bad entries are
+/// removed, and link counts are corrected. A checkpoint is written after a repair.
+///
+/// `fs` is synced first. It must not be in use, so that no inode is open,
+/// or `Busy` is returned.
+pub fn check(fs: &LogFileSystem, repair: bool) -> vfs::Result<Report> {
+    if repair {
+        fs.check_writable()?;
+    }
+    fs.sync()?;
+    if fs
+        .inodes
+        .read()
+        .values()
+        .any(|inode| inode.strong_count() > 0)
+    {
+        return Err(FsError::Busy);
+    }
+    let mut checker = Checker::new(fs);
+    checker.check_imap();
+    if !checker.problems.is_empty() {
+        return Ok(Report {
+            problems: checker.problems,
+            repaired: false,
+        });
+    }
+    checker.walk()?;
+    checker.check_orphans();
+    checker.check_summaries();
+    checker.check_links();
+    let repaired = repair && checker.problems.iter().all(Problem::can_repair);
+    if repaired && !checker.problems.is_empty() {
+        checker.repair()?;
+    }
+    Ok(Report {
+        problems: checker.problems,
+        repaired,
+    })
+}
+
+/// Make the imap again from the blocks of inodes the summaries tell of, each inode being
+/// where it was last written, and return the number of inodes found. Those deleted while
+/// others were left in their blocks are found again, as orphans for `check()` to drop,
+/// and the old imap blocks are left dangling.
+///
+/// `fs` is synced first. It must not be in use, or `Busy` is returned.
+pub fn rebuild_imap(fs: &LogFileSystem) -> vfs::Result<usize> {
+    fs.check_writable()?;
+    fs.sync()?;
+    if fs
+        .inodes
+        .read()
+        .values()
+        .any(|inode| inode.strong_count() > 0)
+    {
+        return Err(FsError::Busy);
+    }
+    // the newest copy of each inode: of the segment started last, and last in it
+    let mut found: BTreeMap<INodeId, ((u32, BlockId), BlockId)> = BTreeMap::new();
+    let seg_seq = fs.super_block.read().seg_seq;
+    let blocks: Vec<(u32, BlockId)> = {
+        let segments = fs.segments.read();
+        segments
+            .values()
+            .filter(|segment| segment.meta.unused == 0)
+            .flat_map(|segment| {
+                let age = seg_seq.wrapping_sub(segment.meta.seq);
+                segment
+                    .summary_map
+                    .read()
+                    .iter()
+                    .filter(|(_, entry)| entry.entry_id == ENTRY_INODEBLOCK as i32)
+                    .map(|(&block, _)| (age, block))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    };
+    for (age, block) in blocks {
+        // those of the segments started later, then later in a segment, compare less
+        let key = (age, BlockId::max_value() - block);
+        for ino_id in fs.inodes_in_block(block)? {
+            let newer = found.get(&ino_id).map_or(true, |&(other, _)| key < other);
+            if newer {
+                found.insert(ino_id, (key, block));
+            }
+        }
+    }
+    {
+        let mut cr = fs.check_region.write();
+        let inodes_num = found.keys().next_back().map_or(0, |&ino_id| ino_id + 1);
+        if cr.inodes_num < inodes_num as u32 {
+            cr.inodes_num = inodes_num as u32;
+        }
+        let mut imap = IMap::new(Vec::new());
+        for (&ino_id, &(_, block)) in found.iter() {
+            imap.set(&fs.device, ino_id, block)?;
+        }
+        // the blocks of the old one are left dangling
+        mem::replace(&mut *fs.imap.write(), imap).sync_locations();
+        cr.turn_dirty();
+    }
+    fs.checkpoint()?;
+    Ok(found.len())
+}
+
+struct Checker<'a> {
+    fs: &'a LogFileSystem,
+    /// Blocks found in use, with the summary entries telling what they hold,
+    /// as (inode id, entry id)
+    used: BTreeMap<BlockId, (i32, i32)>,
+    /// Entries found referring to each inode, and its link count
+    links: BTreeMap<INodeId, (usize, usize)>,
+    /// Bad entries, by dir and index
+    bad_entries: Vec<(INodeId, usize)>,
+    /// Entries referring to dirs not in the dir of the entry, by the dir referred to,
+    /// and the dir and index of the entry
+    stray_entries: Vec<(INodeId, INodeId, usize)>,
+    /// Inodes of the imap in no dir, or not found where it tells
+    orphans: Vec<INodeId>,
+    problems: Vec<Problem>,
+}
+
+impl<'a> Checker<'a> {
+    fn new(fs: &'a LogFileSystem) -> Self {
+        let mut links = BTreeMap::new();
+        links.insert(INO_ROOT, (0, 0));
+        Checker {
+            fs,
+            used: BTreeMap::new(),
+            links,
+            bad_entries: Vec::new(),
+            stray_entries: Vec::new(),
+            orphans: Vec::new(),
+            problems: Vec::new(),
+        }
+    }
+
+    /// Whether `block` is a data block taken in a segment in use
+    fn valid(&self, block: BlockId) -> bool {
+        let segment_blks = self.fs.geometry.segment_blks();
+        let segments = self.fs.segments.read();
+        match segments.get(&(block / segment_blks)) {
+            Some(segment) if block != INVALID_BLKID && segment.meta.unused == 0 => {
+                let offset = block % segment_blks;
+                offset >= self.fs.geometry.data_begin()
+                    && offset < segment.meta.size as usize / BLKSIZE
+            }
+            _ => false,
+        }
+    }
+
+    /// Mark `block` used by `inode`, holding what `entry` of a summary tells.
+    /// Return whether it can be read.
+    fn mark(&mut self, inode: INodeId, block: BlockId, entry: (i32, i32)) -> bool {
+        if !self.valid(block) {
+            self.problems.push(Problem::BadBlock { inode, block });
+            return false;
+        }
+        if self.used.insert(block, entry).is_some() {
+            self.problems.push(Problem::DuplicateBlock { inode, block });
+        }
+        true
+    }
+
+    /// Mark the slot of inode `id` in block of inodes `block`
+    fn mark_slot(&mut self, id: INodeId, block: BlockId) {
+        match self.used.get_mut(&block) {
+            Some(entry) if entry.1 == ENTRY_INODEBLOCK as i32 => entry.0 += 1,
+            _ => {
+                self.mark(id, block, (1, ENTRY_INODEBLOCK as i32));
+            }
+        }
+    }
+
+    /// Mark the blocks the imap is kept in
+    fn check_imap(&mut self) {
+        let locations = self.fs.imap.read().locations().to_vec();
+        for (index, block) in locations.into_iter().enumerate() {
+            if block == INVALID_BLKID {
+                continue;
+            }
+            let entry = (index as i32, ENTRY_IMAPBLOCK as i32);
+            if !self.valid(block) || self.used.insert(block, entry).is_some() {
+                self.problems.push(Problem::BadIMapBlock(index));
+            }
+        }
+    }
+
+    /// Load inode `id` and the block it is in, if it is where the imap tells
+    fn load_inode(&self, id: INodeId) -> vfs::Result<Option<(BlockId, DiskINode)>> {
+        let block = match self.fs.imap_get(id) {
+            Some(block) if self.valid(block) => block,
+            _ => return Ok(None),
+        };
+        let disk_inode = match self.fs.load_inode(id, block) {
+            Ok(disk_inode) => disk_inode,
+            Err(FsError::WrongFs) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        if disk_inode.blocks > MAX_NBLOCK_TRIPLE_INDIRECT as u64 {
+            return Ok(None);
+        }
+        Ok(Some((block, disk_inode)))
+    }
+
+    /// Mark the blocks of inode `id`, with that of its attributes, and return its data blocks
+    /// in order, or `None` if some can not be found
+    fn map_blocks(
+        &mut self,
+        id: INodeId,
+        disk_inode: &DiskINode,
+    ) -> vfs::Result<Option<Vec<BlockId>>> {
+        let count = disk_inode.blocks as usize;
+        let mut blocks = Vec::with_capacity(count);
+        let mut ok = true;
+        for (i, &block) in disk_inode.direct.iter().take(count).enumerate() {
+            ok &= self.mark(id, block as BlockId, (id as i32, i as i32));
+            blocks.push(block as BlockId);
+        }
+        for root in Node::roots(count) {
+            let block = indirect::root_block(disk_inode, root.height);
+            ok &= self.map_indirect(id, root, block, count, &mut blocks)?;
+        }
+        if disk_inode.xattr as BlockId != INVALID_BLKID {
+            self.mark(id, disk_inode.xattr as BlockId, (id as i32, ENTRY_XATTR as i32));
+        }
+        Ok(match ok {
+            true => Some(blocks),
+            false => None,
+        })
+    }
+
+    /// Mark indirect block `node` of inode `id` of `count` blocks, kept in `block`, and the
+    /// blocks below it, pushing its data blocks to `blocks` in order. Return whether all of
+    /// them can be found.
+    fn map_indirect(
+        &mut self,
+        id: INodeId,
+        node: Node,
+        block: BlockId,
+        count: usize,
+        blocks: &mut Vec<BlockId>,
+    ) -> vfs::Result<bool> {
+        if !self.mark(id, block, (id as i32, node.entry() as i32)) {
+            return Ok(false);
+        }
+        let mut buf = [0u8; BLKSIZE];
+        self.fs.device.read_block(block, 0, &mut buf)?;
+        let mut ok = true;
+        for (slot, bytes) in buf.chunks(BLK_ID_SIZE).enumerate() {
+            let child = node.child(slot);
+            if child.first >= count {
+                break;
+            }
+            let block = le64(bytes) as BlockId;
+            if child.height == 0 {
+                ok &= self.mark(id, block, (id as i32, child.first as i32));
+                blocks.push(block);
+            } else {
+                ok &= self.map_indirect(id, child, block, count, blocks)?;
+            }
+        }
+        Ok(ok)
+    }
+
+    /// Walk the dir tree from root
+    fn walk(&mut self) -> vfs::Result<()> {
+        let root = match self.load_inode(INO_ROOT)? {
+            Some((block, root)) if root.type_ == FileType::Dir => {
+                self.mark_slot(INO_ROOT, block);
+                root
+            }
+            _ => {
+                self.problems.push(Problem::BadDir(INO_ROOT));
+                return Ok(());
+            }
+        };
+        let mut dirs = vec![(INO_ROOT, INO_ROOT, root)];
+        while let Some((id, parent, disk_inode)) = dirs.pop() {
+            self.links.get_mut(&id).unwrap().1 = disk_inode.nlinks as usize;
+            let blocks = match self.map_blocks(id, &disk_inode)? {
+                Some(blocks) => blocks,
+                None => continue,
+            };
+            let size = disk_inode.size as usize;
+            if size % DIRENT_SIZE != 0 || blocks.is_empty() {
+                self.problems.push(Problem::BadDir(id));
+                continue;
+            }
+            let mut content = vec![0u8; blocks.len() * BLKSIZE];
+            for (&block, buf) in blocks.iter().zip(content.chunks_mut(BLKSIZE)) {
+                self.fs.device.read_block(block, 0, buf)?;
+            }
+            let mut names = BTreeSet::new();
+            let mut count = 0;
+            for index in 0..blocks.len() * DIRENTS_PER_BLOCK {
+                let buf = &content[index / DIRENTS_PER_BLOCK * BLKSIZE..];
+                let (target, name) = match dir::slot(buf, index % DIRENTS_PER_BLOCK) {
+                    Slot::Entry(target, name) => (target as INodeId, name),
+                    _ => continue,
+                };
+                count += 1;
+                if name == "." || name == ".." {
+                    let expected = match name {
+                        "." => id,
+                        _ => parent,
+                    };
+                    if target != expected
+                        || !names.insert(String::from(name))
+                        || !reachable(&content, index, name)
+                    {
+                        self.problems.push(Problem::BadDir(id));
+                        continue;
+                    }
+                    self.links.get_mut(&target).unwrap().0 += 1;
+                    continue;
+                }
+                if name.is_empty() || name.contains('/') || !reachable(&content, index, name) {
+                    self.problems.push(Problem::BadEntry { dir: id, index });
+                    self.bad_entries.push((id, index));
+                    continue;
+                }
+                // left twice by a crash while entries are moved
+                if !names.insert(String::from(name)) {
+                    self.problems.push(Problem::BadEntry { dir: id, index });
+                    self.bad_entries.push((id, index));
+                    continue;
+                }
+                let (block, child) = match self.load_inode(target)? {
+                    Some(child) => child,
+                    None => {
+                        self.problems.push(Problem::BadEntry { dir: id, index });
+                        self.bad_entries.push((id, index));
+                        continue;
+                    }
+                };
+                if child.type_ == FileType::Dir {
+                    // a dir is only in the one its '..' refers to,
+                    // but a crash while it is moved can leave it in another too
+                    if self.links.contains_key(&target) || self.parent_of(&child)? != Some(id) {
+                        self.stray_entries.push((target, id, index));
+                        continue;
+                    }
+                } else if let Some(links) = self.links.get_mut(&target) {
+                    links.0 += 1;
+                    continue;
+                }
+                self.mark_slot(target, block);
+                self.links.insert(target, (1, child.nlinks as usize));
+                match child.type_ {
+                    FileType::Dir => dirs.push((target, id, child)),
+                    _ => {
+                        self.map_blocks(target, &child)?;
+                    }
+                }
+            }
+            if !names.contains(".") || !names.contains("..") {
+                self.problems.push(Problem::BadDir(id));
+            }
+            if count != size / DIRENT_SIZE {
+                self.problems.push(Problem::EntryCount {
+                    dir: id,
+                    recorded: size / DIRENT_SIZE,
+                    actual: count,
+                });
+            }
+        }
+        let mut lost_dirs = BTreeSet::new();
+        for &(target, dir, index) in self.stray_entries.iter() {
+            match self.links.contains_key(&target) {
+                true => {
+                    self.problems.push(Problem::BadEntry { dir, index });
+                    self.bad_entries.push((dir, index));
+                }
+                false => {
+                    lost_dirs.insert(target);
+                }
+            }
+        }
+        self.problems
+            .extend(lost_dirs.into_iter().map(Problem::BadDir));
+        Ok(())
+    }
+
+    /// The dir which the '..' entry of dir `disk_inode` refers to, if it has one
+    fn parent_of(&self, disk_inode: &DiskINode) -> vfs::Result<Option<INodeId>> {
+        let blocks = disk_inode.blocks as usize;
+        if blocks == 0 {
+            return Ok(None);
+        }
+        let home = dir::home_block("..", blocks);
+        let mut buf = [0u8; BLKSIZE];
+        for n in 0..blocks {
+            let block = match self.content_block(disk_inode, (home + n) % blocks)? {
+                Some(block) => block,
+                None => return Ok(None),
+            };
+            self.fs.device.read_block(block, 0, &mut buf)?;
+            let mut free = false;
+            for i in 0..DIRENTS_PER_BLOCK {
+                match dir::slot(&buf, i) {
+                    Slot::Entry(parent, "..") => return Ok(Some(parent as INodeId)),
+                    Slot::Free => free = true,
+                    _ => {}
+                }
+            }
+            if free {
+                break;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Block `index` of the content of `disk_inode`, if it is in the data blocks in use
+    fn content_block(&self, disk_inode: &DiskINode, index: usize) -> vfs::Result<Option<BlockId>> {
+        let device = &self.fs.device;
+        let block = indirect::lookup(disk_inode, index, 0, |block, slot| {
+            match self.valid(block) {
+                true => indirect::read_entry(device, block, slot).map(Some),
+                false => Ok(None),
+            }
+        })?;
+        Ok(block.filter(|&block| self.valid(block)))
+    }
+
+    /// Find the inodes of the imap in no dir. Their blocks are not marked, as those of an
+    /// inode deleted and found again by `rebuild_imap()` may be in use by others since.
+    fn check_orphans(&mut self) {
+        let inodes_num = self.fs.check_region.read().inodes_num as INodeId;
+        for id in 0..inodes_num {
+            if !self.links.contains_key(&id) && self.fs.imap_get(id).is_some() {
+                self.problems.push(Problem::OrphanINode(id));
+                self.orphans.push(id);
+            }
+        }
+    }
+
+    /// Compare the blocks found in use with the summaries of the segments, and the bytes
+    /// live in them and the unused blocks with those found
+    fn check_summaries(&mut self) {
+        let segment_blks = self.fs.geometry.segment_blks();
+        let data_begin = self.fs.geometry.data_begin();
+        let segments = self.fs.segments.read();
+        let mut live_blocks = 0;
+        for (&seg_id, segment) in segments.iter() {
+            if segment.meta.unused == 1 {
+                continue;
+            }
+            let summary = segment.summary_map.read();
+            let first = seg_id * segment_blks;
+            let mut live = 0;
+            for block in first + data_begin..first + segment.meta.size as usize / BLKSIZE {
+                let recorded = summary
+                    .get(&block)
+                    .map(|entry| (entry.inode_id, entry.entry_id));
+                let garbage = recorded.map(|entry| entry.1) == Some(ENTRY_GARBAGE as i32);
+                let entry = match self.used.get(&block) {
+                    Some(&entry) => entry,
+                    None => {
+                        if !garbage {
+                            self.problems.push(Problem::DanglingBlock(block));
+                        }
+                        continue;
+                    }
+                };
+                live += 1;
+                match recorded {
+                    _ if garbage => self.problems.push(Problem::UsedBlockFree(block)),
+                    Some(recorded) if recorded == entry => {}
+                    Some(recorded)
+                        if entry.1 == ENTRY_INODEBLOCK as i32 && recorded.1 == entry.1 =>
+                    {
+                        self.problems.push(Problem::INodeSlots {
+                            block,
+                            recorded: recorded.0 as usize,
+                            actual: entry.0 as usize,
+                        })
+                    }
+                    _ => self.problems.push(Problem::WrongSummary(block)),
+                }
+            }
+            let (recorded, actual) = (segment.meta.live_bytes as usize, live * BLKSIZE);
+            if recorded != actual {
+                self.problems.push(Problem::LiveBytes {
+                    segment: seg_id,
+                    recorded,
+                    actual,
+                });
+            }
+            live_blocks += live;
+        }
+        let recorded = self.fs.super_block.read().unused_blocks as usize;
+        let actual = segments.len() * self.fs.geometry.data_blks() - live_blocks;
+        if recorded != actual {
+            self.problems
+                .push(Problem::UnusedBlocks { recorded, actual });
+        }
+    }
+
+    fn check_links(&mut self) {
+        for (&inode, &(actual, recorded)) in self.links.iter() {
+            if actual != recorded {
+                self.problems.push(Problem::LinkCount {
+                    inode,
+                    recorded,
+                    actual,
+                });
+            }
+        }
+    }
+
+    fn repair(&mut self) -> vfs::Result<()> {
+        // the summaries first, so that what is freed below is freed from them
+        {
+            let segment_blks = self.fs.geometry.segment_blks();
+            let data_begin = self.fs.geometry.data_begin();
+            let mut segments = self.fs.segments.write();
+            let mut live_blocks = 0;
+            for (&seg_id, segment) in segments.iter_mut() {
+                if segment.meta.unused == 1 {
+                    continue;
+                }
+                let mut summary = segment.summary_map.write();
+                let first = seg_id * segment_blks;
+                let mut live = 0;
+                for block in first + data_begin..first + segment.meta.size as usize / BLKSIZE {
+                    let (inode_id, entry_id) = match self.used.get(&block) {
+                        Some(&entry) => {
+                            live += 1;
+                            entry
+                        }
+                        None => (INVALID_INO as i32, ENTRY_GARBAGE as i32),
+                    };
+                    let recorded = summary
+                        .get(&block)
+                        .map(|entry| (entry.inode_id, entry.entry_id));
+                    if recorded != Some((inode_id, entry_id)) {
+                        summary.insert(block, SummaryEntry { inode_id, entry_id });
+                    }
+                }
+                if segment.meta.live_bytes != (live * BLKSIZE) as u32 {
+                    segment.meta.live_bytes = (live * BLKSIZE) as u32;
+                }
+                live_blocks += live;
+            }
+            let unused = (segments.len() * self.fs.geometry.data_blks() - live_blocks) as u64;
+            let mut super_block = self.fs.super_block.write();
+            if super_block.unused_blocks != unused {
+                super_block.unused_blocks = unused;
+            }
+        }
+        // their slots and blocks are freed with the others in no use above
+        for &id in self.orphans.iter() {
+            self.fs.imap_set(id, INVALID_BLKID);
+        }
+        // the bad entries are counted, and uncounted as they are removed
+        for problem in self.problems.iter() {
+            if let Problem::EntryCount { dir, actual, .. } = *problem {
+                self.fs.get_inode(dir).disk_inode.write().size = (actual * DIRENT_SIZE) as u64;
+            }
+        }
+        for &(dir, index) in self.bad_entries.iter() {
+            self.fs.get_inode(dir).remove_direntry(index)?;
+        }
+        for (&id, &(actual, recorded)) in self.links.iter() {
+            if actual != recorded {
+                self.fs.get_inode(id).disk_inode.write().nlinks = actual as u16;
+            }
+        }
+        self.fs.checkpoint()
+    }
+}
+
+/// Whether a lookup of `name` reaches slot `index` of the hash table `content` of a dir,
+/// going by no block with a free slot before, see `dir`
+fn reachable(content: &[u8], index: usize, name: &str) -> bool {
+    let blocks = content.len() / BLKSIZE;
+    let mut block = dir::home_block(name, blocks);
+    while block != index / DIRENTS_PER_BLOCK {
+        let buf = &content[block * BLKSIZE..(block + 1) * BLKSIZE];
+        let free = (0..DIRENTS_PER_BLOCK).any(|i| match dir::slot(buf, i) {
+            Slot::Free => true,
+            _ => false,
+        });
+        if free {
+            return false;
+        }
+        block = (block + 1) % blocks;
+    }
+    true
+}
@@ -0,0 +1,221 @@
+//! Inode map of LFS, paged
+//!
+//! The imap tells the block each inode is kept in. It is an array of block ids indexed by
+//! inode, cut into imap blocks of `IMAP_PER_BLOCK` entries, which are written to the log
+//! like other blocks, and the checkpoint region tells where each one is. Only the imap
+//! blocks in use are kept in memory, up to `IMAP_CACHE_BLOCKS` of them, the least recently
+//! used being dropped first. Those changed are kept until the next sync writes them to the
+//! log. The imaps of the segments only tell of the inodes written since the checkpoint,
+//! to roll them forward.
+use crate::*;
+
+/// Entries of an imap block
+pub const IMAP_PER_BLOCK: usize = BLKSIZE / BLK_ID_SIZE;
+/// Imap blocks kept in memory, but those changed since the last sync
+pub const IMAP_CACHE_BLOCKS: usize = 16;
+
+/// An imap block in memory
+struct CachedBlock {
+    entries: Dirty<Vec<u64>>,
+    /// `IMap::tick` when it was last used
+    used: u64,
+}
+
+/// The imap, see the module docs
+pub(crate) struct IMap {
+    /// block each imap block is kept in, `INVALID_BLKID` for those never written
+    locations: Dirty<Vec<BlockId>>,
+    /// imap blocks in memory, by index
+    cache: BTreeMap<usize, CachedBlock>,
+    tick: u64,
+}
+
+impl IMap {
+    /// The imap of imap blocks kept in `locations`
+    pub fn new(locations: Vec<BlockId>) -> Self {
+        IMap {
+            locations: Dirty::new(locations),
+            cache: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    /// Block each imap block is kept in
+    pub fn locations(&self) -> &[BlockId] {
+        &self.locations
+    }
+
+    /// Whether an imap block was written to another block since `sync_locations()`
+    pub fn locations_dirty(&self) -> bool {
+        self.locations.dirty()
+    }
+
+    pub fn sync_locations(&mut self) {
+        self.locations.sync();
+    }
+
+    /// Block inode `ino_id` is kept in, if it is
+    pub fn get(
+        &mut self,
+        device: &Arc<dyn Device>,
+        ino_id: INodeId,
+    ) -> vfs::Result<Option<BlockId>> {
+        let entries = self.load(device, ino_id / IMAP_PER_BLOCK)?;
+        match entries[ino_id % IMAP_PER_BLOCK] as BlockId {
+            INVALID_BLKID => Ok(None),
+            blk_id => Ok(Some(blk_id)),
+        }
+    }
+
+    /// Keep inode `ino_id` in block `blk_id`, or none if `INVALID_BLKID`
+    pub fn set(
+        &mut self,
+        device: &Arc<dyn Device>,
+        ino_id: INodeId,
+        blk_id: BlockId,
+    ) -> vfs::Result<()> {
+        let entries = self.load(device, ino_id / IMAP_PER_BLOCK)?;
+        if entries[ino_id % IMAP_PER_BLOCK] != blk_id as u64 {
+            entries[ino_id % IMAP_PER_BLOCK] = blk_id as u64;
+        }
+        Ok(())
+    }
+
+    /// Whether imap block `index` is kept in block `blk_id`
+    pub fn is_at(&self, index: usize, blk_id: BlockId) -> bool {
+        self.locations.get(index) == Some(&blk_id)
+    }
+
+    /// Load imap block `index`, to be written again at the next sync
+    pub fn touch(&mut self, device: &Arc<dyn Device>, index: usize) -> vfs::Result<()> {
+        self.load(device, index)?.turn_dirty();
+        Ok(())
+    }
+
+    /// Take the imap blocks changed, as synced, to be written
+    pub fn take_dirty(&mut self) -> Vec<(usize, Vec<u64>)> {
+        let mut dirty = Vec::new();
+        for (&index, block) in self.cache.iter_mut() {
+            if block.entries.dirty() {
+                dirty.push((index, block.entries.to_vec()));
+                block.entries.sync();
+            }
+        }
+        dirty
+    }
+
+    /// Keep imap block `index`, taken as `entries`, changed as it failed to be written.
+    /// If it is still in memory, it may have been changed again since.
+    pub fn put_back(&mut self, index: usize, entries: Vec<u64>) {
+        if let Some(block) = self.cache.get_mut(&index) {
+            block.entries.turn_dirty();
+            return;
+        }
+        self.tick += 1;
+        let block = CachedBlock {
+            entries: Dirty::new_dirty(entries),
+            used: self.tick,
+        };
+        self.cache.insert(index, block);
+    }
+
+    /// Imap block `index` was written to block `blk_id`, return where it was
+    pub fn moved(&mut self, index: usize, blk_id: BlockId) -> BlockId {
+        if self.locations.len() <= index {
+            self.locations.resize(index + 1, INVALID_BLKID);
+        }
+        core::mem::replace(&mut self.locations[index], blk_id)
+    }
+
+    /// Imap block `index`, loading it, and dropping the least recently used one if there are too many
+    fn load(
+        &mut self,
+        device: &Arc<dyn Device>,
+        index: usize,
+    ) -> vfs::Result<&mut Dirty<Vec<u64>>> {
+        self.tick += 1;
+        if !self.cache.contains_key(&index) {
+            if self.cache.len() >= IMAP_CACHE_BLOCKS {
+                let lru = self
+                    .cache
+                    .iter()
+                    .filter(|(_, block)| !block.entries.dirty())
+                    .min_by_key(|(_, block)| block.used)
+                    .map(|(&index, _)| index);
+                if let Some(lru) = lru {
+                    self.cache.remove(&lru);
+                }
+            }
+            let mut entries = vec![0u64; IMAP_PER_BLOCK];
+            match self.locations.get(index) {
+                Some(&blk_id) if blk_id != INVALID_BLKID => {
+                    let mut buf = [0u8; BLKSIZE];
+                    device.read_block(blk_id, 0, &mut buf)?;
+                    for (entry, bytes) in entries.iter_mut().zip(buf.chunks(BLK_ID_SIZE)) {
+                        *entry = le64(bytes);
+                    }
+                }
+                _ => {}
+            }
+            let block = CachedBlock {
+                entries: Dirty::new(entries),
+                used: 0,
+            };
+            self.cache.insert(index, block);
+        }
+        let block = self.cache.get_mut(&index).unwrap();
+        block.used = self.tick;
+        Ok(&mut block.entries)
+    }
+}
+
+impl LogFileSystem {
+    /// Block inode `ino_id` is kept in, if it is
+    pub(crate) fn imap_get(&self, ino_id: INodeId) -> Option<BlockId> {
+        self.imap
+            .write()
+            .get(&self.device, ino_id)
+            .expect("failed to read imap block")
+    }
+
+    /// Keep inode `ino_id` in block `blk_id`, or none if `INVALID_BLKID`
+    pub(crate) fn imap_set(&self, ino_id: INodeId, blk_id: BlockId) {
+        self.imap
+            .write()
+            .set(&self.device, ino_id, blk_id)
+            .expect("failed to read imap block")
+    }
+
+    /// Write the imap blocks changed to the log
+    pub(crate) fn flush_imap(&self) -> vfs::Result<()> {
+        let mut dirty = self.imap.write().take_dirty().into_iter();
+        while let Some((index, entries)) = dirty.next() {
+            if let Err(err) = self.write_imap_block(index, &entries) {
+                let mut imap = self.imap.write();
+                imap.put_back(index, entries);
+                for (index, entries) in dirty {
+                    imap.put_back(index, entries);
+                }
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write imap block `index` of `entries` to a new block of the log
+    fn write_imap_block(&self, index: usize, entries: &[u64]) -> vfs::Result<()> {
+        let blk_id = self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
+        let mut buf = [0u8; BLKSIZE];
+        for (bytes, entry) in buf.chunks_mut(BLK_ID_SIZE).zip(entries.iter()) {
+            bytes.copy_from_slice(&entry.to_ne_bytes());
+        }
+        self.device.write_block(blk_id, 0, &buf)?;
+        self._record_block_summary(index, blk_id, ENTRY_IMAPBLOCK);
+        let old = self.imap.write().moved(index, blk_id);
+        if old != INVALID_BLKID {
+            self.free_block(old);
+        }
+        debug!("imap block {} to blk {}", index, blk_id);
+        Ok(())
+    }
+}
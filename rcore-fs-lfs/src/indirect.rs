@@ -0,0 +1,286 @@
+//! Indirect blocks of LFS
+//!
+//! The blocks of a file after its `NDIRECT` direct ones are told by trees of indirect blocks
+//! of `BLK_NENTRY` block ids each: the next `BLK_NENTRY` blocks by the indirect block of its
+//! inode, the next `BLK_NENTRY`^2 by the tree of its double indirect block, and the next
+//! `BLK_NENTRY`^3 by that of its triple indirect block. An indirect block is a `Node` of a
+//! tree, known by its height, 1 for those telling data blocks, and the first block of the
+//! file it covers. Its summary entry tells both, see `Node::entry()`, so that the cleaner
+//! finds what refers to it.
+//!
+//! The indirect blocks of a file are taken as it grows to the first block each one covers,
+//! and freed with it. As any block on disk, an indirect block is copied to the log before
+//! it is changed, and so are the ones above it, up to the inode, see `dirlog`.
+use crate::*;
+
+/// An indirect block of a file, or at height 0 a data block
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct Node {
+    /// 1 for an indirect block telling data blocks, 2 for one telling those, and so on
+    pub height: usize,
+    /// the first block of the file it covers
+    pub first: usize,
+}
+
+/// Blocks of a file a node at `height` covers
+fn span(height: usize) -> usize {
+    BLK_NENTRY.pow(height as u32)
+}
+
+impl Node {
+    /// The root of the tree telling block `file_id` of a file, `None` for a direct one
+    pub fn root(file_id: usize) -> Option<Node> {
+        if file_id < NDIRECT {
+            return None;
+        }
+        let mut first = NDIRECT;
+        for height in 1..=3 {
+            if file_id < first + span(height) {
+                return Some(Node { height, first });
+            }
+            first += span(height);
+        }
+        None
+    }
+
+    /// The roots of the trees of a file of `blocks` blocks
+    pub fn roots(blocks: usize) -> Vec<Node> {
+        [NDIRECT, MAX_NBLOCK_INDIRECT, MAX_NBLOCK_DOUBLE_INDIRECT]
+            .iter()
+            .filter(|&&first| first < blocks)
+            .filter_map(|&first| Node::root(first))
+            .collect()
+    }
+
+    /// The node at `height` above block `file_id` of a file, in a tree at least as high
+    pub fn above(file_id: usize, height: usize) -> Node {
+        let root = Node::root(file_id).unwrap();
+        let span = span(height);
+        Node {
+            height,
+            first: root.first + (file_id - root.first) / span * span,
+        }
+    }
+
+    /// The node `slot` of this one tells
+    pub fn child(&self, slot: usize) -> Node {
+        Node {
+            height: self.height - 1,
+            first: self.first + slot * span(self.height - 1),
+        }
+    }
+
+    /// The slot of this one telling the node above block `file_id` of the file
+    pub fn slot(&self, file_id: usize) -> usize {
+        (file_id - self.first) / span(self.height - 1)
+    }
+
+    /// Summary entry of the indirect block, below `ENTRY_INDIRECT`
+    pub fn entry(&self) -> isize {
+        ENTRY_INDIRECT - ((self.first - NDIRECT) * 3 + self.height - 1) as isize
+    }
+
+    /// The indirect block summary entry `entry_id` tells of, if it does
+    pub fn from_entry(entry_id: i32) -> Option<Node> {
+        if entry_id as isize > ENTRY_INDIRECT {
+            return None;
+        }
+        let n = (ENTRY_INDIRECT - entry_id as isize) as usize;
+        let node = Node {
+            height: n % 3 + 1,
+            first: n / 3 + NDIRECT,
+        };
+        match Node::root(node.first) {
+            Some(root)
+                if root.height >= node.height && Node::above(node.first, node.height) == node =>
+            {
+                Some(node)
+            }
+            _ => None,
+        }
+    }
+
+    /// The indirect blocks of a file of `blocks` blocks
+    pub fn all(blocks: usize) -> Vec<Node> {
+        let mut nodes = Vec::new();
+        for root in Node::roots(blocks) {
+            let end = blocks.min(root.first + span(root.height));
+            for height in 1..=root.height {
+                let span = span(height);
+                nodes.extend(
+                    (root.first..end)
+                        .step_by(span)
+                        .map(|first| Node { height, first }),
+                );
+            }
+        }
+        nodes
+    }
+}
+
+/// Block of the root of the tree of `height` of `disk_inode`
+pub(crate) fn root_block(disk_inode: &DiskINode, height: usize) -> BlockId {
+    (match height {
+        1 => disk_inode.indirect,
+        2 => disk_inode.db_indirect,
+        _ => disk_inode.tr_indirect,
+    }) as BlockId
+}
+
+/// Keep the root of the tree of `height` of `disk_inode` in `block`
+fn set_root_block(disk_inode: &mut DiskINode, height: usize, block: BlockId) {
+    match height {
+        1 => disk_inode.indirect = block as u64,
+        2 => disk_inode.db_indirect = block as u64,
+        _ => disk_inode.tr_indirect = block as u64,
+    }
+}
+
+/// Entry `slot` of indirect block `block`
+pub(crate) fn read_entry(
+    device: &Arc<dyn Device>,
+    block: BlockId,
+    slot: usize,
+) -> vfs::Result<BlockId> {
+    let mut bytes = [0u8; BLK_ID_SIZE];
+    device.read_block(block, slot * BLK_ID_SIZE, &mut bytes)?;
+    Ok(le64(&bytes) as BlockId)
+}
+
+/// Block `file_id` of the file of `disk_inode`, or the indirect block at `height` above it,
+/// reading entry `slot` of an indirect block `block` by `read(block, slot)`, `None` if it
+/// can not
+pub(crate) fn lookup<F>(
+    disk_inode: &DiskINode,
+    file_id: usize,
+    height: usize,
+    mut read: F,
+) -> vfs::Result<Option<BlockId>>
+where
+    F: FnMut(BlockId, usize) -> vfs::Result<Option<BlockId>>,
+{
+    let root = match Node::root(file_id) {
+        Some(root) => root,
+        None => return Ok(Some(disk_inode.direct[file_id] as BlockId)),
+    };
+    let mut block = root_block(disk_inode, root.height);
+    for height in (height + 1..=root.height).rev() {
+        let node = Node::above(file_id, height);
+        block = match read(block, node.slot(file_id))? {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+    }
+    Ok(Some(block))
+}
+
+impl INodeImpl {
+    /// Map file id to disk block id
+    pub(crate) fn get_disk_block_id(&self, file_id: BlockId) -> vfs::Result<BlockId> {
+        self.block_above(file_id, 0)
+    }
+
+    /// Block of indirect block `node` of the file
+    pub(crate) fn indirect_block_id(&self, node: Node) -> vfs::Result<BlockId> {
+        self.block_above(node.first, node.height)
+    }
+
+    pub(crate) fn set_disk_block_id(
+        &self,
+        file_id: BlockId,
+        disk_block_id: BlockId,
+    ) -> vfs::Result<()> {
+        if file_id >= self.disk_inode.read().blocks as usize {
+            return Err(FsError::InvalidParam);
+        }
+        self.set_block_above(file_id, 0, disk_block_id)
+    }
+
+    /// Keep indirect block `node` of the file in block `block`
+    pub(crate) fn set_indirect_block_id(&self, node: Node, block: BlockId) -> vfs::Result<()> {
+        self.set_block_above(node.first, node.height, block)
+    }
+
+    /// Take the indirect blocks whose first block is `file_id`, as the file grows to it
+    pub(crate) fn take_indirect(&self, file_id: usize) -> vfs::Result<()> {
+        let root = match Node::root(file_id) {
+            Some(root) => root,
+            None => return Ok(()),
+        };
+        for height in (1..=root.height).rev() {
+            let node = Node::above(file_id, height);
+            if node.first != file_id {
+                continue;
+            }
+            let block = self.fs.alloc_block().ok_or(FsError::NoDeviceSpace)?;
+            self.fs._record_block_summary(self.id, block, node.entry());
+            self.set_block_above(file_id, height, block)?;
+        }
+        Ok(())
+    }
+
+    /// Free the indirect blocks of the file, of `blocks` blocks
+    pub(crate) fn free_indirect(&self, blocks: usize) -> vfs::Result<()> {
+        for node in Node::all(blocks) {
+            let block = self.indirect_block_id(node)?;
+            self.fs.free_block(block);
+        }
+        let mut disk_inode = self.disk_inode.write();
+        for root in Node::roots(blocks) {
+            set_root_block(&mut disk_inode, root.height, INVALID_BLKID);
+        }
+        Ok(())
+    }
+
+    /// The block at `height` above block `file_id` of the file, or at 0 the block itself
+    fn block_above(&self, file_id: usize, height: usize) -> vfs::Result<BlockId> {
+        let disk_inode = self.disk_inode.read();
+        if file_id >= disk_inode.blocks as usize {
+            return Err(FsError::InvalidParam);
+        }
+        let device = &self.fs.device;
+        let block = lookup(&disk_inode, file_id, height, |block, slot| {
+            read_entry(device, block, slot).map(Some)
+        })?;
+        Ok(block.unwrap())
+    }
+
+    /// Keep the block at `height` above block `file_id` of the file, or at 0 the block
+    /// itself, in block `block`
+    fn set_block_above(&self, file_id: usize, height: usize, block: BlockId) -> vfs::Result<()> {
+        match Node::root(file_id) {
+            None => {
+                debug!("set disk id {} -> {}", file_id, block);
+                self.disk_inode.write().direct[file_id] = block as u64;
+            }
+            Some(root) if root.height == height => {
+                set_root_block(&mut self.disk_inode.write(), height, block);
+            }
+            Some(_) => {
+                let parent = Node::above(file_id, height + 1);
+                let parent_block = self.own_indirect(parent)?;
+                let offset = parent.slot(file_id) * BLK_ID_SIZE;
+                self.fs
+                    .device
+                    .write_block(parent_block, offset, &(block as u64).to_ne_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy indirect block `node` to a new block of the log if it is on disk,
+    /// as blocks of metadata on disk are not written over, see `dirlog`.
+    /// Return the block it is in.
+    fn own_indirect(&self, node: Node) -> vfs::Result<BlockId> {
+        let block = self.indirect_block_id(node)?;
+        if self.fs.buffer.buffered(block) {
+            return Ok(block);
+        }
+        let new_blk_id = self.fs.copy_block(block)?;
+        self.fs
+            ._record_block_summary(self.id, new_blk_id, node.entry());
+        self.set_indirect_block_id(node, new_blk_id)?;
+        self.fs.free_block(block);
+        Ok(new_blk_id)
+    }
+}
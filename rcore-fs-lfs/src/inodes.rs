@@ -0,0 +1,251 @@
+//! Blocks of inodes of LFS
+//!
+//! An inode takes a slot of `INODE_SLOT_SIZE` bytes in a block of inodes, the slot telling
+//! its number before it. Inodes are not written as they change, e.g. as each block of their
+//! content is written, nor as they are dropped: those changed since the last sync are
+//! written by it all together, packed `INODES_PER_BLOCK` in new blocks at the head of the
+//! log, so that a sync after changing many inodes takes few blocks. An inode in a block not
+//! on disk yet is written in its slot again instead. The imap tells the block each inode is
+//! in, and the summary entry of a block of inodes the number of its slots in use, the block
+//! being dead once all of them moved on. `FsStats::inodes_written` and
+//! `FsStats::inode_blocks_written` count the inodes written and the blocks it took.
+use crate::*;
+
+/// Number in a slot not in use
+const NO_INODE: u32 = u32::max_value();
+/// Offset of the inode in its slot, after its number
+const SLOT_INODE_OFFSET: usize = 8;
+
+/// An inode changed since it was last written
+enum Changed {
+    /// in use
+    Live(Arc<INodeImpl>),
+    /// dropped since, with the block it was in, if any
+    Dropped(INodeId, BlockId, Dirty<DiskINode>),
+}
+
+impl Changed {
+    fn id(&self) -> INodeId {
+        match self {
+            Changed::Live(inode) => inode.id,
+            Changed::Dropped(ino_id, _, _) => *ino_id,
+        }
+    }
+}
+
+impl LogFileSystem {
+    /// Inode `ino_id` as kept in block `blk_id` of inodes
+    pub(crate) fn load_inode(&self, ino_id: INodeId, blk_id: BlockId) -> vfs::Result<DiskINode> {
+        let mut buf = [0u8; BLKSIZE];
+        self.device.read_block(blk_id, 0, &mut buf)?;
+        let slot = slot_of(&buf, ino_id).ok_or(FsError::WrongFs)?;
+        let mut disk_inode = DiskINode::new_file();
+        let len = disk_inode.as_buf().len();
+        let offset = slot * INODE_SLOT_SIZE + SLOT_INODE_OFFSET;
+        disk_inode
+            .as_buf_mut()
+            .copy_from_slice(&buf[offset..offset + len]);
+        Ok(disk_inode)
+    }
+
+    /// The inodes in the slots of block `blk_id` of inodes, in use or not
+    pub(crate) fn inodes_in_block(&self, blk_id: BlockId) -> vfs::Result<Vec<INodeId>> {
+        let mut buf = [0u8; BLKSIZE];
+        self.device.read_block(blk_id, 0, &mut buf)?;
+        Ok(slot_inos(&buf)
+            .filter(|&ino| ino != NO_INODE)
+            .map(|ino| ino as INodeId)
+            .collect())
+    }
+
+    /// One slot fewer in use in block `blk_id` of inodes, freed once none is
+    pub(crate) fn free_inode(&self, blk_id: BlockId) {
+        let last = {
+            let segments = self.segments.read();
+            let seg = &segments[&(blk_id / self.geometry.segment_blks())];
+            let mut summary = seg.summary_map.write();
+            match summary.get_mut(&blk_id) {
+                Some(entry) if entry.entry_id == ENTRY_INODEBLOCK as i32 && entry.inode_id > 1 => {
+                    entry.inode_id -= 1;
+                    false
+                }
+                _ => true,
+            }
+        };
+        if last {
+            self.free_block(blk_id);
+        }
+    }
+
+    /// Write the inodes changed, see the module docs
+    pub(crate) fn write_inodes(&self) -> vfs::Result<()> {
+        let inodes: Vec<_> = self
+            .inodes
+            .read()
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect();
+        let mut live = Vec::new();
+        for inode in inodes {
+            let mut disk_inode = inode.disk_inode.write();
+            if !disk_inode.dirty() {
+                continue;
+            }
+            let blk_id = *inode.blk_id.read();
+            if self.in_place(blk_id, &disk_inode) {
+                self.rewrite_inode(inode.id, blk_id, &disk_inode)?;
+                disk_inode.sync();
+            } else {
+                drop(disk_inode);
+                live.push(inode);
+            }
+        }
+        {
+            let mut pending = self.pending.write();
+            let mut written = Vec::new();
+            for (&ino_id, (blk_id, disk_inode)) in pending.iter_mut() {
+                if self.in_place(*blk_id, disk_inode) {
+                    self.rewrite_inode(ino_id, *blk_id, disk_inode)?;
+                    disk_inode.sync();
+                    written.push(ino_id);
+                }
+            }
+            for ino_id in written {
+                pending.remove(&ino_id);
+            }
+        }
+        // dropped once `pending` is not held, which dropping the last of them takes
+        let mut taken = Vec::new();
+        // those dropped are taken once the block is, as taking it may look them up, and
+        // held until the imap tells of it, so that they are not looked up where they were
+        while !live.is_empty() || !self.pending.read().is_empty() {
+            let blk_id = self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
+            let room = self.imap_room(blk_id).min(INODES_PER_BLOCK);
+            let mut pending = self.pending.write();
+            let mut batch = Vec::new();
+            while batch.len() < room {
+                if let Some(inode) = live.pop() {
+                    batch.push(Changed::Live(inode.clone()));
+                    taken.push(inode);
+                    continue;
+                }
+                let ino_id = match pending.keys().next() {
+                    Some(&ino_id) => ino_id,
+                    None => break,
+                };
+                let (old, disk_inode) = pending.remove(&ino_id).unwrap();
+                batch.push(Changed::Dropped(ino_id, old, disk_inode));
+            }
+            if batch.is_empty() {
+                // the imap of its segment is full, the rest of it is skipped
+                self.free_block(blk_id);
+                continue;
+            }
+            self.write_inode_block(blk_id, batch, &mut pending)?;
+        }
+        Ok(())
+    }
+
+    /// Whether an inode kept in block `blk_id` is written in its slot again
+    fn in_place(&self, blk_id: BlockId, disk_inode: &Dirty<DiskINode>) -> bool {
+        // a stale one is written to a new block, see `INodeImpl::_io_at`
+        !disk_inode.stale() && self.buffer.buffered(blk_id)
+    }
+
+    /// Write inode `ino_id` in its slot of block `blk_id` of inodes again
+    fn rewrite_inode(
+        &self,
+        ino_id: INodeId,
+        blk_id: BlockId,
+        disk_inode: &DiskINode,
+    ) -> vfs::Result<()> {
+        let mut buf = [0u8; BLKSIZE];
+        self.device.read_block(blk_id, 0, &mut buf)?;
+        let slot = slot_of(&buf, ino_id).ok_or(FsError::WrongFs)?;
+        let mut bytes = [0u8; INODE_SLOT_SIZE];
+        fill_slot(&mut bytes, ino_id as u32, disk_inode);
+        self.device
+            .write_block(blk_id, slot * INODE_SLOT_SIZE, &bytes)?;
+        self.stats.update(|s| s.inodes_written += 1);
+        Ok(())
+    }
+
+    /// Write the inodes of `batch` to block `blk_id` of inodes, just taken, those dropped
+    /// being put back in `pending` if it fails
+    fn write_inode_block(
+        &self,
+        blk_id: BlockId,
+        batch: Vec<Changed>,
+        pending: &mut BTreeMap<INodeId, (BlockId, Dirty<DiskINode>)>,
+    ) -> vfs::Result<()> {
+        let mut buf = [0u8; BLKSIZE];
+        for slot in buf.chunks_mut(INODE_SLOT_SIZE) {
+            slot[..4].copy_from_slice(&NO_INODE.to_ne_bytes());
+        }
+        for (slot, changed) in buf.chunks_mut(INODE_SLOT_SIZE).zip(batch.iter()) {
+            let ino_id = changed.id() as u32;
+            match changed {
+                Changed::Live(inode) => fill_slot(slot, ino_id, &inode.disk_inode.read()),
+                Changed::Dropped(_, _, disk_inode) => fill_slot(slot, ino_id, disk_inode),
+            }
+        }
+        if let Err(err) = self.device.write_block(blk_id, 0, &buf) {
+            // written at the next sync
+            self.free_block(blk_id);
+            for changed in batch {
+                if let Changed::Dropped(ino_id, old, disk_inode) = changed {
+                    pending.insert(ino_id, (old, disk_inode));
+                }
+            }
+            return Err(err);
+        }
+        let n = batch.len();
+        self._record_block_summary(n, blk_id, ENTRY_INODEBLOCK);
+        for changed in batch {
+            let (ino_id, old) = match changed {
+                Changed::Live(inode) => {
+                    inode.disk_inode.write().sync();
+                    let old = mem::replace(&mut *inode.blk_id.write(), blk_id);
+                    (inode.id, old)
+                }
+                Changed::Dropped(ino_id, old, mut disk_inode) => {
+                    disk_inode.sync();
+                    (ino_id, old)
+                }
+            };
+            self._move_inode(ino_id, old, blk_id);
+        }
+        self.stats.update(|s| {
+            s.inodes_written += n as u64;
+            s.inode_blocks_written += 1;
+        });
+        debug!("{} inodes to blk {}", n, blk_id);
+        Ok(())
+    }
+
+    /// Number of inodes the imap of the segment of block `blk_id` can still tell of
+    fn imap_room(&self, blk_id: BlockId) -> usize {
+        let segments = self.segments.read();
+        let seg = &segments[&(blk_id / self.geometry.segment_blks())];
+        let len = seg.seg_imap.read().len();
+        imap_entries(&self.geometry).saturating_sub(len)
+    }
+}
+
+/// Numbers in the slots of block of inodes `buf`
+fn slot_inos(buf: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    buf.chunks(INODE_SLOT_SIZE)
+        .map(|slot| u32::from_ne_bytes([slot[0], slot[1], slot[2], slot[3]]))
+}
+
+/// Slot of inode `ino_id` in block of inodes `buf`, if it is in it
+fn slot_of(buf: &[u8], ino_id: INodeId) -> Option<usize> {
+    slot_inos(buf).position(|ino| ino == ino_id as u32)
+}
+
+/// Fill `slot` with inode `ino_id`
+fn fill_slot(slot: &mut [u8], ino_id: u32, disk_inode: &DiskINode) {
+    let bytes = disk_inode.as_buf();
+    slot[..4].copy_from_slice(&ino_id.to_ne_bytes());
+    slot[SLOT_INODE_OFFSET..SLOT_INODE_OFFSET + bytes.len()].copy_from_slice(bytes);
+}
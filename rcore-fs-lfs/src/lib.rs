@@ -8,7 +8,7 @@ extern crate log;
 use alloc::{
     string::String,
     vec,
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     sync::{Arc, Weak},
     vec::Vec,
 };
@@ -18,16 +18,47 @@ use core::fmt::{Debug, Error, Formatter};
 // MaybeUninit is used, to notify compiler not to transform inner struct since it may not be initilized and causes undefined behavior.
 use core::mem::MaybeUninit;
 
-use spin::RwLock;
+use spin::{Mutex, RwLock};
 
-use rcore_fs::dev::Device;
+use rcore_fs::dev::{Device, TimeProvider};
 use rcore_fs::dirty::Dirty;
+use rcore_fs::stats::Stats;
 use rcore_fs::util::*;
 use rcore_fs::vfs::{self, FileSystem, FsError, MMapArea, INode, Timespec};
 
+pub use self::checkpoint::CHECKPOINT_SEGMENTS;
+pub use self::cleaner::{CleanerPolicy, SegmentUsage, CLEAN_HIGH, CLEAN_LOW};
+use self::checkpoint::{imap_entries, le64, started_since, summary_live_bytes, unused_blocks, CHECKPOINT_HEADER};
+use self::dirlog::{DirOp, DIRLOG_OFFSET, DIRLOG_SIZE};
+pub use self::imap::{IMAP_CACHE_BLOCKS, IMAP_PER_BLOCK};
+pub use self::reserve::CLEAN_HEADROOM;
+use self::buffer::SegmentBuffer;
+use self::imap::IMap;
+use self::indirect::Node;
+use self::reserve::write_blocks;
+use self::snapshot::Snapshot;
+pub use self::snapshot::CheckpointInfo;
+pub use self::stats::LogStats;
 pub use self::structs::*;
 
+mod buffer;
+mod checkpoint;
+mod cleaner;
+mod compact;
+mod dir;
+mod dirlog;
+pub mod fsck;
+mod imap;
+mod indirect;
+mod inodes;
+mod readonly;
+mod reserve;
+mod snapshot;
+mod stats;
 mod structs;
+#[cfg(test)]
+mod tests;
+mod xattr;
 
 trait DeviceExt: Device {
     fn read_block(&self, id: BlockId, offset: usize, buf: &mut [u8]) -> vfs::Result<usize> {
@@ -52,6 +83,13 @@ trait DeviceExt: Device {
         self.read_block(id, 0, s.as_buf_mut())?;
         Ok(s)
     }
+    /// A block must be written without touching its neighbours
+    fn check_sector_size(&self) -> vfs::Result<()> {
+        if self.sector_size_log2() > BLKSIZE_LOG2 {
+            return Err(FsError::InvalidParam);
+        }
+        Ok(())
+    }
 }
 
 impl DeviceExt for dyn Device {}
@@ -60,7 +98,9 @@ impl DeviceExt for dyn Device {}
 pub struct INodeImpl {
     /// INode number (usize type)
     id: INodeId,
-    blk_id: BlockId,
+    /// Block of inodes the inode is kept in, `INVALID_BLKID` until it is first written,
+    /// moved as it is written again, see `inodes`
+    blk_id: RwLock<BlockId>,
     /// On-disk INode instance
     disk_inode: RwLock<Dirty<DiskINode>>,
     /// Reference to LFS, used by almost all operations
@@ -68,6 +108,10 @@ pub struct INodeImpl {
     /// Char/block device id (major, minor)
     /// e.g. crw-rw-rw- 1 root wheel 3, 2 May 13 16:40 /dev/null
     device_inode_id: usize,
+    /// Number and slot of the entry of a dir last told by `get_entry()`, see `dir`
+    dir_cursor: RwLock<(usize, usize)>,
+    /// Held by the operations on it, written by those changing it, see `LogFileSystem`
+    lock: RwLock<()>,
 }
 
 impl Debug for INodeImpl {
@@ -84,155 +128,39 @@ impl Debug for SegmentMeta {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
         write!(
             f,
-            "Meta {{ size: {}, ino_num: {}, unused: {} }}",
-            self.size, self.inodes_num, self.unused
+            "Meta {{ size: {}, ino_num: {}, unused: {}, seq: {}, live: {}, class: {:?} }}",
+            self.size, self.inodes_num, self.unused, self.seq, self.live_bytes, self.class()
         )
     }
 }
 
 impl INodeImpl {
-    /// Map file id to disk block id
-    fn get_disk_block_id(&self, file_id: BlockId) -> vfs::Result<BlockId> {
-        let disk_inode = self.disk_inode.read();
-        match file_id {
-            id if id >= disk_inode.blocks as BlockId => Err(FsError::InvalidParam),
-            id if id < MAX_NBLOCK_DIRECT => {
-                // debug!("get disk id {} -> {}", id, disk_inode.direct[id]);
-                Ok(disk_inode.direct[id] as BlockId)
-            }
-            id if id < MAX_NBLOCK_INDIRECT => {
-                let mut disk_block_id: u32 = 0;
-                self.fs.device.read_block(
-                    disk_inode.indirect as usize,
-                    ENTRY_SIZE * (id - NDIRECT),
-                    disk_block_id.as_buf_mut(),
-                )?;
-                // debug!("get disk id {} -> {}", id, disk_block_id);
-                Ok(disk_block_id as BlockId)
-            }
-            _ => unimplemented!("double indirect blocks is not supported"),
-        }
-    }
-    fn set_disk_block_id(&self, file_id: BlockId, disk_block_id: BlockId) -> vfs::Result<()> {
-        // debug!("inode blocks {}", self.disk_inode.read().blocks);
-        match file_id {
-            id if id >= self.disk_inode.read().blocks as BlockId => Err(FsError::InvalidParam),
-            id if id < MAX_NBLOCK_DIRECT => {
-                debug!("set disk id {} -> {}", id, disk_block_id);
-                self.disk_inode.write().direct[id] = disk_block_id as u32;
-                Ok(())
-            }
-            id if id < MAX_NBLOCK_INDIRECT => {
-                let disk_block_id = disk_block_id as u32;
-                self.fs.device.write_block(
-                    self.disk_inode.read().indirect as usize,
-                    ENTRY_SIZE * (id - NDIRECT),
-                    disk_block_id.as_buf(),
-                )?;
-                // debug!("set disk id {} -> {}", id, disk_block_id);
-                Ok(())
-            }
-            _ => unimplemented!("double indirect blocks is not supported"),
-        }
-    }
-    /// Only for Dir
-    fn get_file_inode_and_entry_id(&self, name: &str) -> Option<(INodeId, usize)> {
-        let inode = self.disk_inode.read();
-        // debug!("D {}", self.id);
-        // for i in 0..(inode.size as usize / DIRENT_SIZE) {
-            // let dir_i = self.read_direntry(i as usize).unwrap();
-            // debug!("D i {} {:?}", i, dir_i.name);
-        // }
-        (0..inode.size as usize / DIRENT_SIZE)
-            .map(|i| (self.read_direntry(i as usize).unwrap(), i))
-            .find(|(entry, _)| entry.name.as_ref() == name)
-            .map(|(entry, id)| (entry.id as INodeId, id as usize))
-    }
     fn get_file_inode_id(&self, name: &str) -> Option<INodeId> {
         self.get_file_inode_and_entry_id(name)
             .map(|(inode_id, _)| inode_id)
     }
-    /// Init dir content. Insert 2 init entries.
-    /// This do not init nlinks, please modify the nlinks in the invoker.
-    fn init_direntry(&self, parent: INodeId) -> vfs::Result<()> {
-        // Insert entries: '.' '..'
-        self._resize(DIRENT_SIZE * 2)?;
-        self.write_direntry(
-            0,
-            &DiskEntry {
-                id: self.id as u32,
-                name: Str256::from("."),
-            },
-        )?;
-        self.write_direntry(
-            1,
-            &DiskEntry {
-                id: parent as u32,
-                name: Str256::from(".."),
-            },
-        )?;
-        Ok(())
-    }
-    fn read_direntry(&self, id: usize) -> vfs::Result<DiskEntry> {
-        let mut direntry: DiskEntry = unsafe { MaybeUninit::uninit().assume_init() };
-        self._read_at(DIRENT_SIZE * id, direntry.as_buf_mut())?;
-        Ok(direntry)
-    }
-    fn write_direntry(&self, id: usize, direntry: &DiskEntry) -> vfs::Result<()> {
-        self._write_at(DIRENT_SIZE * id, direntry.as_buf())?;
-        Ok(())
-    }
-    fn append_direntry(&self, direntry: &DiskEntry) -> vfs::Result<()> {
-        let size = self.disk_inode.read().size as usize;
-        let dirent_count = size / DIRENT_SIZE;
-        self._resize(size + DIRENT_SIZE)?;
-        self.write_direntry(dirent_count, direntry)?;
-        // debug!("D write directory to {:?}", direntry.name);
-        Ok(())
-    }
-    /// remove a direntry in middle of file and insert the last one here, useful for direntry remove
-    /// should be only used in unlink
-    fn remove_direntry(&self, id: usize) -> vfs::Result<()> {
-        let size = self.disk_inode.read().size as usize;
-        let dirent_count = size / DIRENT_SIZE;
-        debug_assert!(id < dirent_count);
-        debug!("remove_dentry id {} count {}", id, dirent_count);
-        let last_dirent = self.read_direntry(dirent_count - 1)?;
-        self.write_direntry(id, &last_dirent)?;
-        self.disk_inode.write().size -= DIRENT_SIZE as u32;
-        Ok(())
-    }
     /// Resize content size, no matter what type it is.
     fn _resize(&self, len: usize) -> vfs::Result<()> {
-        if len > MAX_FILE_SIZE {
-            return Err(FsError::InvalidParam);
-        }
-        let blocks = ((len + BLKSIZE - 1) / BLKSIZE) as u32;
-        if blocks > MAX_NBLOCK_DOUBLE_INDIRECT as u32 {
+        if len as u64 > MAX_FILE_SIZE {
             return Err(FsError::InvalidParam);
         }
+        let blocks = ((len + BLKSIZE - 1) / BLKSIZE) as u64;
         use core::cmp::Ordering;
         let mut disk_inode = self.disk_inode.write();
         let old_blocks = disk_inode.blocks;
         debug!("_resize: id {} dirty {} stale {}", self.id, disk_inode.dirty(), disk_inode.stale());
         match blocks.cmp(&old_blocks) {
             Ordering::Equal => {
-                disk_inode.size = len as u32;
+                disk_inode.size = len as u64;
             }
             Ordering::Greater => {
                 disk_inode.blocks = blocks;
                 // debug!("disk inode old_blocks {} blocks {}", old_blocks, blocks);
-                if blocks >= MAX_NBLOCK_INDIRECT as u32 {
-                    unimplemented!("not support double indirect");
-                }
-                if old_blocks < MAX_NBLOCK_DIRECT as u32 && blocks >= MAX_NBLOCK_DIRECT as u32 {
-                    disk_inode.indirect = self.fs.alloc_block().expect("no space") as u32;
-                    self.fs._record_block_summary(self.id, disk_inode.indirect as usize, ENTRY_SPECIALBLOCK);
-                }
                 drop(disk_inode);
                 // allocate extra blocks
                 for i in old_blocks..blocks {
-                    let disk_block_id = self.fs.alloc_block().expect("no space");
+                    self.take_indirect(i as usize)?;
+                    let disk_block_id = self.fs.alloc_block().ok_or(FsError::NoDeviceSpace)?;
                     self.fs._record_block_summary(self.id, disk_block_id, i as isize);
                     // debug!("in_resize disk inode blocks i {} {}", i, disk_block_id);
                     self.set_disk_block_id(i as usize, disk_block_id)?;
@@ -240,68 +168,50 @@ impl INodeImpl {
                 // debug!("set_disk_block_id finish");
                 // clean up
                 let mut disk_inode = self.disk_inode.write();
-                disk_inode.size = len as u32;
+                disk_inode.size = len as u64;
                 // debug!("disk inode size {}", len);
                 drop(disk_inode);
             }
             Ordering::Less => {
-                // Not support space reduction!
+                // Not support space reduction! The blocks are kept.
+                disk_inode.size = len as u64;
             }
         }
         // debug!("resize finish");
         Ok(())
     }
-    // Note: the _\w*_at method always return begin>size?0:begin<end?0:(min(size,end)-begin) when success
-    /// Read/Write content, no matter what type it is
+    /// Read/Write content in its blocks, no matter what type it is, or its size
     fn _io_at<F>(&self, begin: usize, end: usize, mut f: F, iswrite: bool) -> vfs::Result<usize>
     where
         F: FnMut(&Arc<dyn Device>, &BlockRange, usize) -> vfs::Result<usize>,
     {
-        let size = self.disk_inode.read().size as usize;
         let iter = BlockIter {
-            begin: size.min(begin),
-            end: size.min(end),
+            begin,
+            end,
             block_size_log2: BLKSIZE_LOG2,
         };
 
-        if iswrite && self.disk_inode.read().dirty() && (self.disk_inode.read().stale()) {
-            // back up stale data
-            let mut buf: [u8; BLKSIZE] = unsafe { MaybeUninit::uninit().assume_init() };
-            let begin_offset_align = begin/BLKSIZE * BLKSIZE;
-            let begin_entryid = begin/BLKSIZE;
-            let end_entryid = (end + BLKSIZE - 1) / BLKSIZE;
-            let end_offset_align = end_entryid * BLKSIZE;
-            // debug!("DDD offbegin {}, offbegin_align {}, offend {}, offend_align {}, dirty {}, stale {}", begin, begin_offset_align, end, end_offset_align, self.disk_inode.read().dirty(), self.disk_inode.read().stale());
-            let old_begin_blkid = self.get_disk_block_id(begin_entryid)?;
-
-            &self.fs.device.read_block(
-                old_begin_blkid,
-                0,
-                &mut buf[0..begin - begin_offset_align],
-            );
-
-            for i in begin_entryid..end_entryid {
-                let disk_block_id = self.fs.alloc_block().expect("no space");
+        let stale = {
+            let disk_inode = self.disk_inode.read();
+            disk_inode.dirty() && disk_inode.stale()
+        };
+        if iswrite && iter.begin < iter.end {
+            // write to copies of the blocks: those of a stale inode are still the ones on disk,
+            // and the blocks on disk are not written over, see `dirlog` and `snapshot`
+            for i in iter.begin / BLKSIZE..(iter.end + BLKSIZE - 1) / BLKSIZE {
+                let old_blk_id = self.get_disk_block_id(i)?;
+                if !stale && self.fs.buffer.buffered(old_blk_id) {
+                    continue;
+                }
+                // nothing to copy from a block written whole
+                let disk_block_id = match iter.begin <= i * BLKSIZE && (i + 1) * BLKSIZE <= iter.end {
+                    true => self.fs.alloc_block().ok_or(FsError::NoDeviceSpace)?,
+                    false => self.fs.copy_block(old_blk_id)?,
+                };
                 self.fs._record_block_summary(self.id, disk_block_id, i as isize);
                 self.set_disk_block_id(i as usize, disk_block_id)?;
-            }
-
-            if begin_offset_align < begin {
-                let new_begin_blkid = self.get_disk_block_id(begin_entryid)?;
-                &self.fs.device.write_block(
-                    new_begin_blkid,
-                    0,
-                    &mut buf[0..begin - begin_offset_align],
-                );
-            }
-
-            if end < end_offset_align {
-                let new_end_blkid = self.get_disk_block_id(end_entryid - 1)?;
-                &self.fs.device.write_block(
-                    new_end_blkid,
-                    end,
-                    &mut buf[end..end_offset_align],
-                );
+                // the old copy is dead
+                self.fs.free_block(old_blk_id);
             }
         }
 
@@ -316,9 +226,11 @@ impl INodeImpl {
         }
         Ok(buf_offset)
     }
+    // Note: it returns begin>size?0:begin<end?0:(min(size,end)-begin) when success
     /// Read content, no matter what type it is
     fn _read_at(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<usize> {
-        self._io_at(offset, offset + buf.len(), |device, range, offset| {
+        let size = self.disk_inode.read().size as usize;
+        self._io_at(size.min(offset), size.min(offset + buf.len()), |device, range, offset| {
             device.read_block(
                 range.block,
                 range.begin,
@@ -326,7 +238,7 @@ impl INodeImpl {
             )
         }, false)
     }
-    /// Write content, no matter what type it is
+    /// Write content, no matter what type it is, resized first to hold it
     fn _write_at(&self, offset: usize, buf: &[u8]) -> vfs::Result<usize> {
         self.disk_inode.write().turn_dirty();
         let res = self._io_at(offset, offset + buf.len(), |device, range, offset| {
@@ -336,12 +248,27 @@ impl INodeImpl {
         res
     }
     fn nlinks_inc(&self) {
-        self.disk_inode.write().nlinks += 1;
+        let mut disk_inode = self.disk_inode.write();
+        disk_inode.nlinks += 1;
+        if let Some(now) = self.fs.now() {
+            disk_inode.ctime = now;
+        }
     }
     fn nlinks_dec(&self) {
         let mut disk_inode = self.disk_inode.write();
         assert!(disk_inode.nlinks > 0);
         disk_inode.nlinks -= 1;
+        if let Some(now) = self.fs.now() {
+            disk_inode.ctime = now;
+        }
+    }
+    /// Set mtime and ctime to now, after the content is changed
+    fn touch(&self) {
+        if let Some(now) = self.fs.now() {
+            let mut disk_inode = self.disk_inode.write();
+            disk_inode.mtime = now;
+            disk_inode.ctime = now;
+        }
     }
     fn _free_all_block(&self) -> vfs::Result<()> {
         let old_blocks = self.disk_inode.read().blocks;
@@ -349,23 +276,24 @@ impl INodeImpl {
             let disk_block_id = self.get_disk_block_id(i as usize)?;
             self.fs.free_block(disk_block_id);
         }
-        let mut disk_inode = self.disk_inode.write();
-        // free indirect block if needed
-        if old_blocks >= MAX_NBLOCK_DIRECT as u32
-        {
-            self.fs.free_block(disk_inode.indirect as usize);
-            disk_inode.indirect = 0;
+        // free indirect blocks if needed
+        self.free_indirect(old_blocks as usize)?;
+        let xattr = self.set_xattr_block(INVALID_BLKID);
+        if xattr != INVALID_BLKID {
+            self.fs.free_block(xattr);
         }
+        let mut disk_inode = self.disk_inode.write();
         disk_inode.blocks = 0;
-        disk_inode.size = 0 as u32;
-        drop(disk_inode);
-        self.sync_data()?;
+        disk_inode.size = 0;
+        // the inode is dead, do not append it to the log again
+        disk_inode.sync();
         Ok(())
     }
 }
 
 impl vfs::INode for INodeImpl {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<usize> {
+        let _lock = self.lock.read();
         let inode = self.disk_inode.read();
         match inode.type_ {
             FileType::File => self._read_at(offset, buf),
@@ -382,6 +310,12 @@ impl vfs::INode for INodeImpl {
         }
     }
     fn write_at(&self, offset: usize, buf: &[u8]) -> vfs::Result<usize> {
+        self.fs.check_writable()?;
+        self.fs.make_room()?;
+        let blocks = self.disk_inode.read().blocks as usize;
+        let _reservation = self.fs.reserve(write_blocks(blocks, offset, offset + buf.len()))?;
+        let _ops = self.fs.ops.read();
+        let _lock = self.lock.write();
         let DiskINode { type_, size, .. } = **self.disk_inode.read();
         match type_ {
             FileType::File | FileType::SymLink => {
@@ -390,7 +324,10 @@ impl vfs::INode for INodeImpl {
                     debug!("Need resize for alignment");
                     self._resize(end_offset)?;
                 }
-                self._write_at(offset, buf)
+                let len = self._write_at(offset, buf)?;
+                self.touch();
+                self.fs.stats.update(|s| s.data_bytes_written += len as u64);
+                Ok(len)
             }
             FileType::CharDevice => {
                 let device_inodes = self.fs.device_inodes.write();
@@ -418,168 +355,363 @@ impl vfs::INode for INodeImpl {
                 FileType::Dir => disk_inode.size as usize,
                 FileType::CharDevice => 0,
                 FileType::BlockDevice => 0,
+                FileType::NamedPipe | FileType::Socket => 0,
                 _ => panic!("Unknown file type"),
             },
-            mode: 0o777,
+            mode: disk_inode.mode,
             type_: vfs::FileType::from(disk_inode.type_.clone()),
             blocks: disk_inode.blocks as usize,
-            atime: Timespec { sec: 0, nsec: 0 },
-            mtime: Timespec { sec: 0, nsec: 0 },
-            ctime: Timespec { sec: 0, nsec: 0 },
+            atime: disk_inode.atime,
+            mtime: disk_inode.mtime,
+            ctime: disk_inode.ctime,
             nlinks: disk_inode.nlinks as usize,
-            uid: 0,
-            gid: 0,
+            uid: disk_inode.uid as usize,
+            gid: disk_inode.gid as usize,
             blk_size: BLKSIZE,
             rdev: self.device_inode_id,
         })
     }
-    fn set_metadata(&self, _metadata: &vfs::Metadata) -> vfs::Result<()> {
-        // No-op for lfs
+    fn set_metadata(&self, metadata: &vfs::Metadata) -> vfs::Result<()> {
+        self.fs.check_writable()?;
+        let mut disk_inode = self.disk_inode.write();
+        disk_inode.atime = metadata.atime;
+        disk_inode.mtime = metadata.mtime;
+        disk_inode.ctime = metadata.ctime;
+        disk_inode.mode = metadata.mode & 0o7777;
+        disk_inode.uid = metadata.uid as u32;
+        disk_inode.gid = metadata.gid as u32;
         Ok(())
     }
+    /// Write the inode back, with the others changed, packed in blocks of inodes
     fn sync_all(&self) -> vfs::Result<()> {
-        let mut disk_inode = self.disk_inode.write();
-        debug!("sync_all: id {} dirty {} stale {}", self.id, disk_inode.dirty(), disk_inode.stale());
-        if disk_inode.dirty() {
-            // allocate a new block and append write to it
-            let new_blk_id = if disk_inode.stale() {
-                self.fs.alloc_block().unwrap()
-            } else {
-                self.blk_id
-            };
-            self.fs._record_block_summary(self.id, new_blk_id, ENTRY_SPECIALBLOCK);
-            // update imaps
-            let mut imaps = self.fs.imaps.write();
-            if let Some(x) = imaps.get_mut(&self.id) {
-                *x = new_blk_id;
-            }
-            self.fs
-                .device
-                .write_block(new_blk_id, 0, disk_inode.as_buf())?;
-            disk_inode.sync();
-        }
-        Ok(())
+        debug!("sync_all: id {} dirty {}", self.id, self.disk_inode.read().dirty());
+        let _ops = self.fs.ops.write();
+        self.fs.write_inodes()
     }
     fn sync_data(&self) -> vfs::Result<()> {
         self.sync_all()
     }
     fn resize(&self, len: usize) -> vfs::Result<()> {
+        self.fs.check_writable()?;
+        self.fs.make_room()?;
+        let blocks = self.disk_inode.read().blocks as usize;
+        let _reservation = self.fs.reserve(write_blocks(blocks, len, len))?;
+        let _ops = self.fs.ops.read();
+        let _lock = self.lock.write();
         if self.disk_inode.read().type_ != FileType::File
             && self.disk_inode.read().type_ != FileType::SymLink
         {
             return Err(FsError::NotFile);
         }
-        self._resize(len)
+        self._resize(len)?;
+        self.touch();
+        Ok(())
     }
     fn create2(
         &self,
         name: &str,
         type_: vfs::FileType,
-        _mode: u32,
+        mode: u32,
         data: usize,
     ) -> vfs::Result<Arc<dyn vfs::INode>> {
-        let info = self.metadata()?;
-        if info.type_ != vfs::FileType::Dir {
-            return Err(FsError::NotDir);
-        }
-        if info.nlinks <= 0 {
-            return Err(FsError::DirRemoved);
-        }
+        self.fs.check_writable()?;
+        self.fs.make_room()?;
+        // and the table of a new dir
+        let new_dir = match type_ {
+            vfs::FileType::Dir => write_blocks(0, 0, BLKSIZE),
+            _ => 0,
+        };
+        let _reservation = self.fs.reserve(self.dirop_blocks(0) + new_dir)?;
+        let inode = self.fs.dirop(|| {
+            let _lock = self.lock.write();
+            let seg_id = self.fs.dirop_segment();
+            let info = self.metadata()?;
+            if info.type_ != vfs::FileType::Dir {
+                return Err(FsError::NotDir);
+            }
+            if info.nlinks == 0 {
+                return Err(FsError::DirRemoved);
+            }
 
-        // Ensure the name is not exist
-        if !self.get_file_inode_id(name).is_none() {
-            return Err(FsError::EntryExist);
-        }
+            // Ensure the name is not exist
+            if !self.get_file_inode_id(name).is_none() {
+                return Err(FsError::EntryExist);
+            }
 
-        // Create new INode
-        let inode = match type_ {
-            vfs::FileType::File => self.fs.new_inode_file()?,
-            vfs::FileType::SymLink => self.fs.new_inode_symlink()?,
-            vfs::FileType::Dir => self.fs.new_inode_dir(self.id)?,
-            vfs::FileType::CharDevice => self.fs.new_inode_chardevice(data)?,
-            _ => return Err(vfs::FsError::InvalidParam),
-        };
+            // Create new INode
+            let inode = match type_ {
+                vfs::FileType::File => self.fs.new_inode_file()?,
+                vfs::FileType::SymLink => self.fs.new_inode_symlink()?,
+                vfs::FileType::Dir => self.fs.new_inode_dir(self.id)?,
+                vfs::FileType::CharDevice => self.fs.new_inode_chardevice(data)?,
+                vfs::FileType::NamedPipe => self.fs.new_inode_fifo()?,
+                vfs::FileType::Socket => self.fs.new_inode_socket()?,
+                _ => return Err(vfs::FsError::InvalidParam),
+            };
+            inode.disk_inode.write().mode = (mode & 0o7777) as u16;
 
-        // Write new entry
-        self.append_direntry(&DiskEntry {
-            id: inode.id as u32,
-            name: Str256::from(name),
+            // Write new entry
+            self.append_direntry(&DiskEntry {
+                id: inode.id as u32,
+                name: Str256::from(name),
+            })?;
+            inode.nlinks_inc();
+            let mut ops = vec![DirOp::entry(self.id, name, Some(inode.id))];
+            if type_ == vfs::FileType::Dir {
+                inode.nlinks_inc(); //for .
+                self.nlinks_inc(); //for ..
+                ops.push(self.links_op());
+            }
+            ops.push(inode.links_op());
+            self.fs.log_dirop(seg_id, &ops);
+            debug!("create2: {} created ino:{} blkid:{}", name, inode.id, *inode.blk_id.read());
+            Ok(inode)
         })?;
-        inode.nlinks_inc();
-        if type_ == vfs::FileType::Dir {
-            inode.nlinks_inc(); //for .
-            self.nlinks_inc(); //for ..
-        }
-        debug!("create2: {} created ino:{} blkid:{}", name, inode.id, inode.blk_id);
         Ok(inode)
     }
     fn link(&self, name: &str, other: &Arc<dyn INode>) -> vfs::Result<()> {
-        let info = self.metadata()?;
-        if info.type_ != vfs::FileType::Dir {
-            return Err(FsError::NotDir);
-        }
-        if info.nlinks <= 0 {
-            return Err(FsError::DirRemoved);
-        }
-        if !self.get_file_inode_id(name).is_none() {
-            return Err(FsError::EntryExist);
-        }
-        let child = other
-            .downcast_ref::<INodeImpl>()
-            .ok_or(FsError::NotSameFs)?;
-        if !Arc::ptr_eq(&self.fs, &child.fs) {
-            return Err(FsError::NotSameFs);
-        }
-        if child.metadata()?.type_ == vfs::FileType::Dir {
-            return Err(FsError::IsDir);
-        }
-        self.append_direntry(&DiskEntry {
-            id: child.id as u32,
-            name: Str256::from(name),
-        })?;
-        child.nlinks_inc();
-        Ok(())
+        self.fs.check_writable()?;
+        self.fs.make_room()?;
+        let _reservation = self.fs.reserve(self.dirop_blocks(0))?;
+        self.fs.dirop(|| {
+            let _lock = self.lock.write();
+            let seg_id = self.fs.dirop_segment();
+            let info = self.metadata()?;
+            if info.type_ != vfs::FileType::Dir {
+                return Err(FsError::NotDir);
+            }
+            if info.nlinks == 0 {
+                return Err(FsError::DirRemoved);
+            }
+            if !self.get_file_inode_id(name).is_none() {
+                return Err(FsError::EntryExist);
+            }
+            let child = other
+                .downcast_ref::<INodeImpl>()
+                .ok_or(FsError::NotSameFs)?;
+            if !Arc::ptr_eq(&self.fs, &child.fs) {
+                return Err(FsError::NotSameFs);
+            }
+            if child.metadata()?.type_ == vfs::FileType::Dir {
+                return Err(FsError::IsDir);
+            }
+            let _child_lock = child.lock.write();
+            // removed while it is open, it is freed once dropped
+            let nlinks = child.disk_inode.read().nlinks;
+            if nlinks == 0 {
+                return Err(FsError::EntryNotFound);
+            }
+            // the count would wrap to none, and the inode be freed while still named
+            if nlinks == u16::max_value() {
+                return Err(FsError::InvalidParam);
+            }
+            self.append_direntry(&DiskEntry {
+                id: child.id as u32,
+                name: Str256::from(name),
+            })?;
+            child.nlinks_inc();
+            let ops = [DirOp::entry(self.id, name, Some(child.id)), child.links_op()];
+            self.fs.log_dirop(seg_id, &ops);
+            Ok(())
+        })
     }
     fn unlink(&self, name: &str) -> vfs::Result<()> {
-        let info = self.metadata()?;
-        if info.type_ != vfs::FileType::Dir {
-            return Err(FsError::NotDir);
-        }
-        if info.nlinks <= 0 {
-            return Err(FsError::DirRemoved);
-        }
-        if name == "." {
-            return Err(FsError::IsDir);
-        }
-        if name == ".." {
-            return Err(FsError::IsDir);
-        }
+        self.fs.check_writable()?;
+        self.fs.dirop(|| {
+            let _lock = self.lock.write();
+            let seg_id = self.fs.dirop_segment();
+            let info = self.metadata()?;
+            if info.type_ != vfs::FileType::Dir {
+                return Err(FsError::NotDir);
+            }
+            if info.nlinks == 0 {
+                return Err(FsError::DirRemoved);
+            }
+            if name == "." {
+                return Err(FsError::IsDir);
+            }
+            if name == ".." {
+                return Err(FsError::IsDir);
+            }
 
-        let (inode_id, entry_id) = self
-            .get_file_inode_and_entry_id(name)
-            .ok_or(FsError::EntryNotFound)?;
-        let inode = self.fs.get_inode(inode_id);
+            let (inode_id, entry_id) = self
+                .get_file_inode_and_entry_id(name)
+                .ok_or(FsError::EntryNotFound)?;
+            let inode = self.fs.get_inode(inode_id);
+            let _child_lock = inode.lock.write();
 
-        let type_ = inode.disk_inode.read().type_;
-        if type_ == FileType::Dir {
-            // only . and ..
-            if inode.disk_inode.read().size as usize / DIRENT_SIZE > 2 {
-                return Err(FsError::DirNotEmpty);
+            let type_ = inode.disk_inode.read().type_;
+            if type_ == FileType::Dir {
+                // only . and ..
+                if inode.disk_inode.read().size as usize / DIRENT_SIZE > 2 {
+                    return Err(FsError::DirNotEmpty);
+                }
             }
-        }
-        inode.nlinks_dec();
-        debug!("inode links {}", inode.disk_inode.read().nlinks);
-        if type_ == FileType::Dir {
-            inode.nlinks_dec(); //for .
-            self.nlinks_dec(); //for ..
-        }
-        self.remove_direntry(entry_id)?;
-        Ok(())
+            inode.nlinks_dec();
+            debug!("inode links {}", inode.disk_inode.read().nlinks);
+            if type_ == FileType::Dir {
+                inode.nlinks_dec(); //for .
+                self.nlinks_dec(); //for ..
+            }
+            self.remove_direntry(entry_id)?;
+            let mut ops = vec![DirOp::entry(self.id, name, None), inode.links_op()];
+            if type_ == FileType::Dir {
+                ops.push(self.links_op());
+            }
+            self.fs.log_dirop(seg_id, &ops);
+            if inode.disk_inode.read().nlinks == 0 {
+                let mut batch = self.fs.batch.write();
+                if batch.0 > 0 {
+                    batch.1.push(inode.clone());
+                }
+            }
+            Ok(())
+        })
     }
     fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> vfs::Result<()> {
-        Err(FsError::NotSupported)
+        self.fs.check_writable()?;
+        self.fs.make_room()?;
+        let dest = target
+            .downcast_ref::<INodeImpl>()
+            .ok_or(FsError::NotSameFs)?;
+        if !Arc::ptr_eq(&self.fs, &dest.fs) {
+            return Err(FsError::NotSameFs);
+        }
+        // the entry removed here, and '..' of a dir moved
+        let _reservation = self.fs.reserve(dest.dirop_blocks(2))?;
+        self.fs.dirop(|| {
+            // the dirs the two are in, which no other move changes meanwhile
+            let (_renaming, parents, dest_parents) = if self.id == dest.id {
+                (None, Vec::new(), Vec::new())
+            } else {
+                let renaming = self.fs.renaming.lock();
+                (Some(renaming), self.fs.parents(self.id), self.fs.parents(dest.id))
+            };
+            // in the order of the tree
+            let (_lock, _dest_lock) = if self.id == dest.id {
+                (self.lock.write(), None)
+            } else if parents.contains(&dest.id) {
+                let dest_lock = dest.lock.write();
+                (self.lock.write(), Some(dest_lock))
+            } else {
+                let lock = self.lock.write();
+                (lock, Some(dest.lock.write()))
+            };
+            let seg_id = self.fs.dirop_segment();
+            let info = self.metadata()?;
+            if info.type_ != vfs::FileType::Dir {
+                return Err(FsError::NotDir);
+            }
+            if info.nlinks == 0 {
+                return Err(FsError::DirRemoved);
+            }
+            if old_name == "." || old_name == ".." || new_name == "." || new_name == ".." {
+                return Err(FsError::IsDir);
+            }
+            let dest_info = dest.metadata()?;
+            if dest_info.type_ != vfs::FileType::Dir {
+                return Err(FsError::NotDir);
+            }
+            if dest_info.nlinks == 0 {
+                return Err(FsError::DirRemoved);
+            }
+            let inode_id = self
+                .get_file_inode_id(old_name)
+                .ok_or(FsError::EntryNotFound)?;
+            // nor to one in it
+            if inode_id == dest.id || dest_parents.contains(&inode_id) {
+                return Err(FsError::InvalidParam);
+            }
+            let inode = self.fs.get_inode(inode_id);
+            let _inode_lock = inode.lock.write();
+            let is_dir = inode.disk_inode.read().type_ == FileType::Dir;
+            let mut ops = vec![DirOp::entry(self.id, old_name, None)];
+
+            // kept until the move is recorded, as it is freed when dropped if it has no link left
+            let replaced = match dest.get_file_inode_and_entry_id(new_name) {
+                // both names link to it
+                Some((replaced_id, _)) if replaced_id == inode_id => return Ok(()),
+                // one the dir moved from is in
+                Some((replaced_id, _)) if parents.contains(&replaced_id) => {
+                    return Err(FsError::DirNotEmpty)
+                }
+                Some((replaced_id, entry_id)) => Some((self.fs.get_inode(replaced_id), entry_id)),
+                None => None,
+            };
+            let _replaced_lock = replaced.as_ref().map(|(replaced, _)| replaced.lock.write());
+            if let Some((replaced, entry_id)) = replaced.as_ref() {
+                let replaced_dir = replaced.disk_inode.read().type_ == FileType::Dir;
+                match (is_dir, replaced_dir) {
+                    (false, true) => return Err(FsError::IsDir),
+                    (true, false) => return Err(FsError::NotDir),
+                    // only . and ..
+                    (true, true) if replaced.disk_inode.read().size as usize / DIRENT_SIZE > 2 => {
+                        return Err(FsError::DirNotEmpty)
+                    }
+                    _ => {}
+                }
+                replaced.nlinks_dec();
+                if replaced_dir {
+                    replaced.nlinks_dec(); //for .
+                    dest.nlinks_dec(); //for ..
+                }
+                dest.remove_direntry(*entry_id)?;
+                ops.push(replaced.links_op());
+            }
+
+            let new_entry = DiskEntry {
+                id: inode_id as u32,
+                name: Str256::from(new_name),
+            };
+            if self.id == dest.id {
+                // rename: the entry moves to the slots of its new name
+                let (_, entry_id) = self
+                    .get_file_inode_and_entry_id(old_name)
+                    .ok_or(FsError::EntryNotFound)?;
+                self.remove_direntry(entry_id)?;
+                self.append_direntry(&new_entry)?;
+            } else {
+                dest.append_direntry(&new_entry)?;
+                let (_, entry_id) = self
+                    .get_file_inode_and_entry_id(old_name)
+                    .ok_or(FsError::EntryNotFound)?;
+                self.remove_direntry(entry_id)?;
+                if is_dir {
+                    // a dir is taken to be in the one its '..' refers to
+                    let (_, dotdot) = inode
+                        .get_file_inode_and_entry_id("..")
+                        .ok_or(FsError::EntryNotFound)?;
+                    inode.write_direntry(
+                        dotdot,
+                        &DiskEntry {
+                            id: dest.id as u32,
+                            name: Str256::from(".."),
+                        },
+                    )?;
+                    dest.nlinks_inc();
+                    self.nlinks_dec();
+                    ops.push(DirOp::entry(inode_id, "..", Some(dest.id)));
+                    ops.push(self.links_op());
+                }
+            }
+            ops.push(DirOp::entry(dest.id, new_name, Some(inode_id)));
+            if is_dir {
+                ops.push(dest.links_op());
+            }
+            self.fs.log_dirop(seg_id, &ops);
+            if let Some((replaced, _)) = replaced.as_ref() {
+                if replaced.disk_inode.read().nlinks == 0 {
+                    let mut batch = self.fs.batch.write();
+                    if batch.0 > 0 {
+                        batch.1.push(replaced.clone());
+                    }
+                }
+            }
+            Ok(())
+        })
     }
     fn find(&self, name: &str) -> vfs::Result<Arc<dyn vfs::INode>> {
+        let _lock = self.lock.read();
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
@@ -589,14 +721,40 @@ impl vfs::INode for INodeImpl {
         Ok(self.fs.get_inode(inode_id))
     }
     fn get_entry(&self, id: usize) -> vfs::Result<String> {
+        let _lock = self.lock.read();
         if self.disk_inode.read().type_ != FileType::Dir {
             return Err(FsError::NotDir);
         }
         if id >= self.disk_inode.read().size as usize / DIRENT_SIZE {
             return Err(FsError::EntryNotFound);
         };
-        let entry = self.read_direntry(id)?;
-        Ok(String::from(entry.name.as_ref()))
+        self.nth_entry(id)?.ok_or(FsError::EntryNotFound)
+    }
+    fn get_xattr(&self, name: &str) -> vfs::Result<Vec<u8>> {
+        let _lock = self.lock.read();
+        self.xattrs()?
+            .into_iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value)
+            .ok_or(FsError::EntryNotFound)
+    }
+    fn set_xattr(&self, name: &str, value: &[u8]) -> vfs::Result<()> {
+        self.fs.check_writable()?;
+        self.fs.make_room()?;
+        let _reservation = self.fs.reserve(write_blocks(0, 0, BLKSIZE))?;
+        let _ops = self.fs.ops.read();
+        let _lock = self.lock.write();
+        self._set_xattr(name, Some(value))
+    }
+    fn list_xattr(&self) -> vfs::Result<Vec<String>> {
+        let _lock = self.lock.read();
+        Ok(self.xattrs()?.into_iter().map(|(name, _)| name).collect())
+    }
+    fn remove_xattr(&self, name: &str) -> vfs::Result<()> {
+        self.fs.check_writable()?;
+        let _ops = self.fs.ops.read();
+        let _lock = self.lock.write();
+        self._set_xattr(name, None)
     }
     fn io_control(&self, _cmd: u32, _data: usize) -> vfs::Result<()> {
         Err(FsError::NotSupported)
@@ -615,26 +773,44 @@ impl vfs::INode for INodeImpl {
 impl Drop for INodeImpl {
     /// Auto sync when drop
     fn drop(&mut self) {
-        if self.disk_inode.read().nlinks <= 0 {
+        // a snapshot is left as it is
+        if self.fs.snapshot.is_none() {
+            self.release();
+        }
+        // looked up again from now on, see `LogFileSystem::find_inode()`
+        self.fs.inodes.write().remove(&self.id);
+    }
+}
+
+impl INodeImpl {
+    /// Free it if it has no link left, or keep it to be written at the next sync if it changed
+    fn release(&self) {
+        if self.disk_inode.read().nlinks == 0 {
             let mut disk_inode = self.disk_inode.write();
             // clean data block and inode itself
             disk_inode.sync();
-            let mut segments = self.fs.segments.write();
-            let seg_id = self.blk_id / SEGMENT_BLKS;
-            let seg = segments.get_mut(&(seg_id)).unwrap();
-            seg.seg_imap.write().insert(self.id, INVALID_BLKID);
-            drop(seg);
-            drop(segments);
             drop(disk_inode);
+            // none if it was never written
+            let blk_id = *self.blk_id.read();
+            if blk_id != INVALID_BLKID {
+                let seg_id = blk_id / self.fs.geometry.segment_blks();
+                {
+                    let mut segments = self.fs.segments.write();
+                    let seg = segments.get_mut(&seg_id).unwrap();
+                    seg.seg_imap.write().insert(self.id, INVALID_BLKID);
+                }
+                self.fs.imap_set(self.id, INVALID_BLKID);
+            }
             self._free_all_block().unwrap();
-            self.fs.free_block(self.blk_id);
-            let mut segments = self.fs.segments.write();
-            let seg = segments.get_mut(&(seg_id)).unwrap();
-            let seg_id = self.blk_id / SEGMENT_BLKS;
-            debug!("freed seg {} blk {} entry {}", seg_id, self.blk_id, seg.summary_map.read().get(&self.blk_id).unwrap().entry_id);
-        } else {
-            self.sync_all()
-                .expect("Failed to sync when dropping the LogStructureFileSystem Inode");
+            if blk_id != INVALID_BLKID {
+                self.fs.free_inode(blk_id);
+            }
+            debug!("freed inode {} blk {}", self.id, blk_id);
+        } else if self.disk_inode.read().dirty() {
+            // written with the others at the next sync, see `inodes`
+            let disk_inode = mem::replace(&mut *self.disk_inode.write(), Dirty::new(DiskINode::new_file()));
+            let blk_id = *self.blk_id.read();
+            self.fs.pending.write().insert(self.id, (blk_id, disk_inode));
         }
     }
 }
@@ -645,115 +821,295 @@ impl Drop for INodeImpl {
 /// 为了方便协调外部及INode对LFS的访问，并为日后并行化做准备，
 /// 将LFS设置为内部可变，即对外接口全部是&self，struct的全部field用RwLock包起来
 /// 这样其内部各field均可独立访问
+///
+/// ## Concurrency
+/// Operations on different inodes run at once. Each one holds the lock of the inodes it
+/// changes, written, or reads, read: those of a namespace operation in the order of the
+/// tree, a dir before those in it, and a dir moved to another one holds `renaming` too, so
+/// that no other is moved meanwhile. The blocks are taken at the head of the log one at a
+/// time under `appender`. A sync holds `ops` written, which the operations changing inodes
+/// hold read, so that it writes none of them half done, and the cleaner holds it read
+/// while it moves blocks, under the lock of each inode they are of, see `cleaner`.
 pub struct LogFileSystem {
     /// on-disk superblock
     super_block: RwLock<Dirty<SuperBlock>>,
-    imaps: RwLock<Dirty<IMapTable>>,
+    /// inode map, paged
+    imap: RwLock<IMap>,
     check_region: RwLock<Dirty<CheckRegion>>,
     inodes: RwLock<BTreeMap<INodeId, Weak<INodeImpl>>>, // should be in Segment struct
     segments: RwLock<BTreeMap<SegmentId, Segment>>,
-    /// device
+    /// device, written through `buffer`
     device: Arc<dyn Device>,
+    /// write buffer of the current segment
+    buffer: Arc<SegmentBuffer>,
     /// Pointer to self, used by INodes
     self_ptr: Weak<LogFileSystem>,
     /// device inode
     device_inodes: RwLock<BTreeMap<usize, Arc<DeviceINode>>>, // aoslab don't know the use
+    /// depth of nested batches, and the removed inodes whose release is deferred until the
+    /// outermost ends
+    batch: RwLock<(usize, Vec<Arc<INodeImpl>>)>,
+    /// inodes dropped since they were changed, with their blocks, written at the next sync
+    pending: RwLock<BTreeMap<INodeId, (BlockId, Dirty<DiskINode>)>>,
+    /// segments cleaned since the last checkpoint, reused and discarded on the device after it
+    cleaned: RwLock<BTreeSet<SegmentId>>,
+    /// checkpoints pinned for snapshots, by number
+    pinned: RwLock<BTreeMap<u32, Snapshot>>,
+    /// checkpoint it is a read-only view at, see `open_snapshot`
+    snapshot: Option<u32>,
+    /// opened by `open_readonly()`, so that it is not changed, see `readonly`
+    read_only: bool,
+    /// layout of the segments, as in the super block
+    geometry: Geometry,
+    /// class of the blocks written now, and of the segments started, see `cleaner`
+    class: RwLock<SegmentClass>,
+    /// when the log is cleaned by itself, see `cleaner`
+    cleaner: RwLock<CleanerPolicy>,
+    /// counters, shared with the device if it keeps any
+    stats: Arc<Stats>,
+    /// clock for timestamps, which are left alone without it
+    time: Option<&'static dyn TimeProvider>,
+    /// held read by the operations changing inodes, and written by a sync
+    ops: RwLock<()>,
+    /// held while a block is taken at the head of the log
+    appender: Mutex<()>,
+    /// held while the log is cleaned
+    cleaning: Mutex<()>,
+    /// held while a dir is moved to another one
+    renaming: Mutex<()>,
+    /// a namespace operation found no room to be recorded, so a checkpoint is written
+    /// after it, see `dirlog`
+    checkpoint_wanted: RwLock<bool>,
+    /// blocks reserved by the operations under way, see `reserve`
+    reserved: RwLock<usize>,
 }
 
 impl LogFileSystem {
-    /// Load LFS from device
+    /// Load LFS from device without a clock, so timestamps are only set by `set_metadata`
     pub fn open(device: Arc<dyn Device>) -> vfs::Result<Arc<Self>> {
-        let super_block = device.load_struct::<SuperBlock>(BLKN_SUPER)?;
-        let check_region = device.load_struct::<CheckRegion>(BLKN_CR)?;
-        let mut imaps = BTreeMap::new();
-        let inodes_num: u32 = check_region.inodes_num;
+        Self::_open(device, None, false)
+    }
+    /// Load LFS from device, with timestamps from `time`
+    pub fn open_with_time(device: Arc<dyn Device>, time: &'static dyn TimeProvider) -> vfs::Result<Arc<Self>> {
+        Self::_open(device, Some(time), false)
+    }
+    fn _open(
+        device: Arc<dyn Device>,
+        time: Option<&'static dyn TimeProvider>,
+        read_only: bool,
+    ) -> vfs::Result<Arc<Self>> {
+        device.check_sector_size()?;
+        let mut super_block = device.load_struct::<SuperBlock>(BLKN_SUPER)?;
+        if !super_block.check() {
+            return Err(FsError::WrongFs);
+        }
+        if super_block.geometry.segment_blks == 0 {
+            super_block.geometry = Geometry::default();
+        }
+        let geometry = super_block.geometry;
+        if !geometry.check() {
+            return Err(FsError::WrongFs);
+        }
         debug!("sb size: {} info {:?}", mem::size_of::<SuperBlock>(), super_block.info);
-        debug!("imaps inonum {}", inodes_num);
-        // let current_segment_id = super_block.current_seg_id as usize;
+        let n_segment = super_block.n_segment as usize;
         let mut segments = BTreeMap::new();
-        for i in 1..super_block.n_segment as usize {
-            let seg_meta: SegmentMeta = device.load_struct::<SegmentMeta>(i * SEGMENT_SIZE / BLKSIZE)?;
-
-            let mut seg_imap = BTreeMap::new();
-            debug!("load segment {} {} {} {:?}", i, seg_meta.unused, i * SEGMENT_SIZE, seg_meta);
-            let mut seg_summary = BTreeMap::new();
-            for ino_i in 0..seg_meta.inodes_num as usize {
-                let mut blk_id: u32 = 0;
-                let mut inode_id: u32 = 0;
-                // debug!("read device {}", (i * SEGMENT_SIZE + SEGMENT_META_SIZE) / BLKSIZE + ino_i * 8);
-                device.read_block((i * SEGMENT_SIZE + SEGMENT_META_SIZE) / BLKSIZE, ino_i * 8, inode_id.as_buf_mut())?;
-                device.read_block((i * SEGMENT_SIZE + SEGMENT_META_SIZE) / BLKSIZE, ino_i * 8 + 4, blk_id.as_buf_mut())?;
-                if blk_id != 0 {
-                    imaps.insert(inode_id as usize, blk_id as usize);
-                    seg_imap.insert(inode_id as usize, blk_id as usize);
-                    debug!("load ino {} blkid {}", inode_id, blk_id);
-                }
+        let mut valid = BTreeSet::new();
+        for i in 1..n_segment {
+            let (segment, ok) = Self::load_segment(&device, &geometry, i)?;
+            if ok {
+                valid.insert(i);
             }
-            let blk_id_begin = i * SEGMENT_BLKS + (SEGMENT_META_SIZE + IMAP_PER_SEGMENT_SIZE + SS_PER_SEGMENT_SIZE) / BLKSIZE;
-            let blk_id_end = i * SEGMENT_BLKS + seg_meta.size as usize / BLKSIZE;
-            for blk_i in blk_id_begin..blk_id_end {
-                let mut entry_i: SummaryEntry = unsafe { MaybeUninit::uninit().assume_init() };
-                debug!("load summary offset segid{} blkid{} {}", i, blk_i, (i * SEGMENT_SIZE + SEGMENT_META_SIZE + SS_PER_SEGMENT_SIZE) + blk_i * mem::size_of::<SummaryEntry>());
-                device.read_block((i * SEGMENT_SIZE + SEGMENT_META_SIZE + SS_PER_SEGMENT_SIZE) / BLKSIZE, blk_i * mem::size_of::<SummaryEntry>(), entry_i.as_buf_mut())?;
-                seg_summary.insert(blk_i, entry_i);
-            }
-
-            let segment_i = Segment {
-                meta: Dirty::new(seg_meta),
-                seg_imap: RwLock::new(Dirty::new(seg_imap)),
-                summary_map: RwLock::new(Dirty::new(seg_summary)),
-            };
-
-            segments.insert(i, segment_i);
-        }
-        debug!("finish loading imaps and segment meta...");
-
-        if !super_block.check() {
-            return Err(FsError::WrongFs);
+            segments.insert(i, segment);
         }
+        let used: Vec<SegmentId> = segments
+            .iter()
+            .filter(|(_, segment)| segment.meta.unused == 0)
+            .map(|(&seg_id, _)| seg_id)
+            .collect();
+        let (check_region, imap, dirops, recovered, torn, pinned) = match Self::load_checkpoint(&device, n_segment, &geometry)? {
+            Some((checkpoint, pinned)) => {
+                let (check_region, imap, dirops, recovered, torn) =
+                    Self::recover(&device, &mut super_block, checkpoint, &mut segments, &valid)?;
+                (check_region, imap, dirops, recovered, torn, pinned)
+            }
+            None => {
+                // made before checkpoints, the inodes are where the segments tell
+                let legacy = device.load_struct::<CheckRegion>(BLKN_CR)?;
+                let mut imap = IMap::new(Vec::new());
+                let mut inodes_num = 0;
+                for segment in segments.values_mut() {
+                    for (&ino_id, &blk_id) in segment.seg_imap.read().iter() {
+                        if blk_id != INVALID_BLKID {
+                            imap.set(&device, ino_id, blk_id)?;
+                            inodes_num = inodes_num.max(ino_id as u32 + 1);
+                        }
+                    }
+                    let live_bytes = summary_live_bytes(&geometry, &segment.meta, &segment.summary_map.read());
+                    segment.meta.live_bytes = live_bytes;
+                }
+                super_block.unused_blocks = unused_blocks(&geometry, &segments);
+                let check_region = CheckRegion {
+                    inodes_num: legacy.inodes_num.max(inodes_num),
+                    seq: 0,
+                    seg_seq: super_block.seg_seq,
+                    current_seg_id: super_block.current_seg_id,
+                    imap_len: 0,
+                    n_segment: super_block.n_segment,
+                    pinned_len: 0,
+                    checksum: 0,
+                };
+                (check_region, imap, Vec::new(), true, false, BTreeMap::new())
+            }
+        };
+        debug!("imap inonum {} recovered {}", check_region.inodes_num, recovered);
+        // those freed by the checkpoint, but left as they were on disk by a crash, are made
+        // free there too by the one below
+        let cleaned: BTreeSet<SegmentId> = used
+            .into_iter()
+            .filter(|seg_id| segments[seg_id].meta.unused == 1)
+            .collect();
 
-        Ok(LogFileSystem {
-            super_block: RwLock::new(Dirty::new(super_block)),
-            imaps: RwLock::new(Dirty::new(imaps)),
-            check_region: RwLock::new(Dirty::new(check_region)),
+        let stats = device.stats().unwrap_or_default();
+        let buffer = Arc::new(SegmentBuffer::new(device, geometry.segment_size()));
+        let current_seg_id = super_block.current_seg_id as usize;
+        buffer.start(current_seg_id, segments[&current_seg_id].meta.size as usize)?;
+        let (super_block, check_region) = match recovered {
+            true => (Dirty::new_dirty(super_block), Dirty::new_dirty(check_region)),
+            false => (Dirty::new(super_block), Dirty::new(check_region)),
+        };
+        let lfs = LogFileSystem {
+            super_block: RwLock::new(super_block),
+            imap: RwLock::new(imap),
+            check_region: RwLock::new(check_region),
             inodes: RwLock::new(BTreeMap::new()),
             segments: RwLock::new(segments),
-            device,
+            device: buffer.clone(),
+            buffer,
             self_ptr: Weak::default(),
             device_inodes: RwLock::new(BTreeMap::new()),
+            batch: RwLock::new((0, Vec::new())),
+            pending: RwLock::new(BTreeMap::new()),
+            cleaned: RwLock::new(cleaned),
+            pinned: RwLock::new(pinned),
+            snapshot: None,
+            read_only,
+            geometry,
+            class: RwLock::new(SegmentClass::Hot),
+            cleaner: RwLock::new(CleanerPolicy::default()),
+            stats,
+            time,
+            ops: RwLock::new(()),
+            appender: Mutex::new(()),
+            cleaning: Mutex::new(()),
+            renaming: Mutex::new(()),
+            checkpoint_wanted: RwLock::new(false),
+            reserved: RwLock::new(0),
         }
-        .wrap())
+        .wrap();
+        // the log may end with a segment just filled
+        if lfs.segments.read()[&current_seg_id].meta.size as usize == geometry.segment_size() {
+            lfs.alloc_segment();
+        }
+        lfs.redo_dirops(dirops)?;
+        // so that a crash before the next one does not roll forward again
+        if recovered {
+            lfs.checkpoint()?;
+        }
+        // the blocks freed since the checkpoint may have been freed by the part of the log
+        // cut, so the summaries are made to follow the inodes again, unless it is not to be
+        // changed, when they are only of use to free blocks
+        if torn {
+            let report = fsck::check(&lfs, !read_only)?;
+            warn!("log cut at a torn segment: {:?}", report);
+        }
+        Ok(lfs)
     }
-    /// Create a new LFS on blank disk
+    /// Create a new LFS on blank disk without a clock
     pub fn create(device: Arc<dyn Device>, space: usize) -> vfs::Result<Arc<Self>> {
+        Self::_create(device, space, Geometry::default(), None)
+    }
+    /// Create a new LFS on blank disk, with timestamps from `time`
+    pub fn create_with_time(device: Arc<dyn Device>, space: usize, time: &'static dyn TimeProvider) -> vfs::Result<Arc<Self>> {
+        Self::_create(device, space, Geometry::default(), Some(time))
+    }
+    /// Create a new LFS on blank disk like `create_with_time`, with segments laid out as
+    /// `geometry`, e.g. `Geometry::new(64)` for small ones on a small device.
+    /// It fails with `InvalidParam` if `geometry` is not valid, if there is room for fewer
+    /// than 2 segments, or if the checkpoint regions in segment 0 are too small for them.
+    pub fn create_with_geometry(
+        device: Arc<dyn Device>,
+        space: usize,
+        geometry: Geometry,
+        time: &'static dyn TimeProvider,
+    ) -> vfs::Result<Arc<Self>> {
+        Self::_create(device, space, geometry, Some(time))
+    }
+    fn _create(device: Arc<dyn Device>, space: usize, geometry: Geometry, time: Option<&'static dyn TimeProvider>) -> vfs::Result<Arc<Self>> {
+        device.check_sector_size()?;
         let blocks = space / BLKSIZE;
         let current_seg_id_: usize = 1; // segment 0 is reserved for superblock
-        let n_segment = space / SEGMENT_SIZE; // available seg id: [1, ..., n_segment - 1]
-        let unused_blocks_ = ((n_segment - current_seg_id_) + SEGMENT_SIZE) * SEGMENT_SIZE / BLKSIZE;
+        let n_segment = space / geometry.segment_size(); // available seg id: [1, ..., n_segment - 1]
         assert!(blocks >= 16, "space too small");
+        // a checkpoint tells of an imap of an inode per block at most, and of each segment
+        let checkpoint_len = CHECKPOINT_HEADER
+            + (blocks / IMAP_PER_BLOCK + 1) * BLK_ID_SIZE
+            + n_segment.saturating_sub(1) * mem::size_of::<SegmentMeta>();
+        if !geometry.check() || n_segment < 3 || checkpoint_len > geometry.cr_blks() * BLKSIZE {
+            return Err(FsError::InvalidParam);
+        }
+        let unused_blocks_ = (n_segment - current_seg_id_) * geometry.data_blks();
         let super_block = SuperBlock {
             magic: MAGIC,
-            blocks: blocks as u32,
-            unused_blocks: unused_blocks_ as u32,
+            blocks: blocks as u64,
+            unused_blocks: unused_blocks_ as u64,
             info: Str32::from(DEFAULT_INFO),
             current_seg_id: current_seg_id_ as u32,
             next_ino_number: INO_ROOT as u32,
             n_segment: n_segment as u32,
+            seg_seq: 0,
+            geometry,
         };
 
         let check_region = CheckRegion {
             inodes_num: 0,
+            seq: 0,
+            seg_seq: 0,
+            current_seg_id: current_seg_id_ as u32,
+            imap_len: 0,
+            n_segment: n_segment as u32,
+            pinned_len: 0,
+            checksum: 0,
         };
 
+        let stats = device.stats().unwrap_or_default();
+        let buffer = Arc::new(SegmentBuffer::new(device, geometry.segment_size()));
         let lfs = LogFileSystem {
             super_block: RwLock::new(Dirty::new_dirty(super_block)),
-            imaps: RwLock::new(Dirty::new_dirty(BTreeMap::new())),
+            imap: RwLock::new(IMap::new(Vec::new())),
             check_region: RwLock::new(Dirty::new_dirty(check_region)),
             inodes: RwLock::new(BTreeMap::new()),
             segments: RwLock::new(BTreeMap::new()),
-            device,
+            device: buffer.clone(),
+            buffer,
             self_ptr: Weak::default(),
             device_inodes: RwLock::new(BTreeMap::new()),
+            batch: RwLock::new((0, Vec::new())),
+            pending: RwLock::new(BTreeMap::new()),
+            cleaned: RwLock::new(BTreeSet::new()),
+            pinned: RwLock::new(BTreeMap::new()),
+            snapshot: None,
+            read_only: false,
+            geometry,
+            class: RwLock::new(SegmentClass::Hot),
+            cleaner: RwLock::new(CleanerPolicy::default()),
+            stats,
+            time,
+            ops: RwLock::new(()),
+            appender: Mutex::new(()),
+            cleaning: Mutex::new(()),
+            renaming: Mutex::new(()),
+            checkpoint_wanted: RwLock::new(false),
+            reserved: RwLock::new(0),
         }
         .wrap();
         debug!("alloc segment...");
@@ -761,17 +1117,16 @@ impl LogFileSystem {
         // Insert segment1
         lfs.initialize_segments();
         lfs.segments.write().get_mut(&SEGN_ROOT).unwrap().meta.unused = 0;
+        lfs.buffer.start(SEGN_ROOT, geometry.header_size())?;
         debug!("init root inode...");
         // Init root INode
-        let root_blkid = lfs.alloc_block().ok_or(FsError::NoDeviceSpace)?;
-        // debug!("init current_segment_size:{}", lfs.super_block.read().current_seg_size);
-        let root_inode = lfs._new_inode(root_blkid, Dirty::new_dirty(DiskINode::new_dir()));
-        lfs._record_block_summary(root_inode.id, root_blkid, ENTRY_SPECIALBLOCK);
-        root_inode.init_direntry(root_blkid)?;
+        let root_inode = lfs._new_inode(lfs.new_disk_inode(DiskINode::new_dir()));
+        root_inode.init_direntry(root_inode.id)?;
         root_inode.nlinks_inc(); //for .
         root_inode.nlinks_inc(); //for ..(root's parent is itself)
         debug!("syncing root inode...");
         root_inode.sync_all()?;
+        lfs.checkpoint()?;
         debug!("create lfs done");
         debug!("rootnode type {:?}", root_inode.disk_inode.read().type_);
         Ok(lfs)
@@ -796,27 +1151,47 @@ impl LogFileSystem {
         for seg_id in 1..n_segment as usize {
             let segment = Segment {
                 meta: Dirty::new_dirty(SegmentMeta {
-                    size: (SEGMENT_META_SIZE + IMAP_PER_SEGMENT_SIZE + SS_PER_SEGMENT_SIZE) as u32,
+                    size: self.geometry.header_size() as u32,
                     inodes_num: 0,
                     unused: 1, // init to be available
+                    seq: 0,
+                    live_bytes: 0,
+                    class: SegmentClass::Hot as u32,
+                    data_checksum: 0,
+                    checksum: 0,
                 }),
                 seg_imap: RwLock::new(Dirty::new_dirty(BTreeMap::new())),
                 summary_map: RwLock::new(Dirty::new_dirty(BTreeMap::new())),
+                dir_log: RwLock::new(Dirty::new(DirLog::default())),
+                checksum_end: self.geometry.header_size() as u32,
             };
             self.segments.write().insert(seg_id, segment);
         }
     }
 
     fn find_available_segment(&self) -> usize {
-        for (seg_id, segment) in self.segments.write().iter_mut() {
-            if segment.meta.unused == 1 {
+        let segments = self.segments.read();
+        let cleaned = self.cleaned.read();
+        for (seg_id, segment) in segments.iter() {
+            if segment.meta.unused == 1 && !cleaned.contains(seg_id) {
                 return *seg_id;
             }
         }
+        // the last checkpoint still refers to it, the log is only recovered up to there
+        if let Some(&seg_id) = cleaned.iter().next() {
+            warn!("reuse segment {} cleaned since the last checkpoint", seg_id);
+            return seg_id;
+        }
         return 0;
     }
 
     fn alloc_segment(&self) {
+        let _appender = self.appender.lock();
+        self._alloc_segment();
+    }
+
+    /// Start a new segment for the log, under `appender`
+    fn _alloc_segment(&self) {
         let mut new_seg_id = self.find_available_segment();
         if new_seg_id == 0 {
             self._detect_garbage_segment();
@@ -827,45 +1202,72 @@ impl LogFileSystem {
             // no available segment even after collecting garbage segment
             error!("Not enough space");
         } else {
+            let mut sb = self.super_block.write();
             let mut segments = self.segments.write();
             let seg = segments.get_mut(&new_seg_id).unwrap();
+            sb.seg_seq += 1;
             seg.meta.unused = 0;
-            self.super_block.write().current_seg_id = new_seg_id as u32;
+            seg.meta.seq = sb.seg_seq;
+            seg.meta.class = *self.class.read() as u32;
+            self.cleaned.write().remove(&new_seg_id);
+            sb.current_seg_id = new_seg_id as u32;
+            let size = seg.meta.size as usize;
+            drop(sb);
+            drop(segments);
+            // the segment just filled is written at once
+            if let Err(err) = self.buffer.start(new_seg_id, size) {
+                error!("failed to write back segment: {:?}", err);
+            }
         }
     }
 
-    /// Allocate a block, return block id
+    /// Allocate a block, return block id. The blocks are taken one at a time, and the
+    /// segment filled is followed by a new one before the next is.
     fn alloc_block(&self) -> Option<usize> {
-        let current_seg_id = self.super_block.read().current_seg_id as usize;
-        let mut sb = self.super_block.write();
-        sb.unused_blocks -= 1;
-        let cur_seg_id = sb.current_seg_id as usize;
-        let mut current_seg_size = self.segments.read().get(&cur_seg_id).unwrap().meta.size as usize;
-        let new_blk_id = (current_seg_size + current_seg_id * SEGMENT_SIZE) / BLKSIZE;
-        // debug!("seg size {} {}", current_seg_id, current_seg_size);
-        if current_seg_size > SEGMENT_SIZE - BLKSIZE {
-            return None;
-        } else {
-            current_seg_size += BLKSIZE;
-            self.segments.write().get_mut(&cur_seg_id).unwrap().meta.size = current_seg_size as u32;
-            if current_seg_size == SEGMENT_SIZE {
-                drop(sb);
-                self.alloc_segment();
+        let _appender = self.appender.lock();
+        let segment_size = self.geometry.segment_size();
+        let (new_blk_id, filled) = {
+            let mut sb = self.super_block.write();
+            let cur_seg_id = sb.current_seg_id as usize;
+            let mut segments = self.segments.write();
+            let meta = &mut segments.get_mut(&cur_seg_id).unwrap().meta;
+            let current_seg_size = meta.size as usize;
+            // debug!("seg size {} {}", cur_seg_id, current_seg_size);
+            if current_seg_size > segment_size - BLKSIZE {
+                return None;
             }
+            meta.size += BLKSIZE as u32;
+            meta.live_bytes += BLKSIZE as u32;
+            sb.unused_blocks -= 1;
+            let new_blk_id = (current_seg_size + cur_seg_id * segment_size) / BLKSIZE;
+            (new_blk_id, current_seg_size + BLKSIZE == segment_size)
+        };
+        if filled {
+            self._alloc_segment();
         }
+        self.stats.update(|s| s.blocks_allocated += 1);
         Some(new_blk_id)
     }
 
     /// Free a block
     fn free_block(&self, block_id: usize) {
+        let mut sb = self.super_block.write();
         let mut segments = self.segments.write();
-        let seg_id = block_id / SEGMENT_BLKS;
+        let seg_id = block_id / self.geometry.segment_blks();
         let seg = segments.get_mut(&seg_id).unwrap();
-        seg.summary_map.write().insert(block_id, SummaryEntry {
+        let old = seg.summary_map.write().insert(block_id, SummaryEntry {
             entry_id: ENTRY_GARBAGE as i32,
             inode_id: INVALID_INO as i32,
         });
-        self.super_block.write().unused_blocks += 1;
+        if let Some(old) = old {
+            if old.entry_id == ENTRY_GARBAGE as i32 {
+                warn!("block {} freed twice", block_id);
+                return;
+            }
+        }
+        seg.meta.live_bytes = seg.meta.live_bytes.saturating_sub(BLKSIZE as u32);
+        sb.unused_blocks += 1;
+        self.stats.update(|s| s.blocks_freed += 1);
         debug!("free block {} seg {}", block_id, seg_id);
     }
 
@@ -876,33 +1278,30 @@ impl LogFileSystem {
     }
 
     /// Create a new INode struct, then insert it to self.inodes
-    /// Private used for load or create INode
-    fn _new_inode(&self, blk_id: BlockId, disk_inode: Dirty<DiskINode>) -> Arc<INodeImpl> {
+    /// Private used for create INode, it is kept in no block until it is written
+    fn _new_inode(&self, disk_inode: Dirty<DiskINode>) -> Arc<INodeImpl> {
         let device_inode_id = disk_inode.device_inode_id;
         let mut cr = self.check_region.write();
         let ino_id = cr.inodes_num as usize;
         let inode = Arc::new(INodeImpl {
             id: ino_id,
-            blk_id: blk_id,
+            blk_id: RwLock::new(INVALID_BLKID),
             disk_inode: RwLock::new(disk_inode),
             fs: self.self_ptr.upgrade().unwrap(),
             device_inode_id: device_inode_id,
+            dir_cursor: RwLock::new((0, 0)),
+            lock: RwLock::new(()),
         });
         cr.inodes_num += 1;
-        let cur_seg_id = self.super_block.read().current_seg_id as usize;
-        let mut segments = self.segments.write();
-        let cur_seg = segments.get_mut(&cur_seg_id).unwrap();
-        cur_seg.meta.inodes_num += 1;
-        cur_seg.seg_imap.write().insert(ino_id, blk_id);
-        self.imaps.write().insert(ino_id, blk_id);
+        drop(cr);
         self.inodes.write().insert(ino_id, Arc::downgrade(&inode));
-        debug!("add inode {} -> {}  segid {}", ino_id, blk_id, cur_seg_id);
+        debug!("add inode {}", ino_id);
         inode
     }
 
     fn _record_block_summary(&self, ino_id: INodeId, blk_id: BlockId, entry_id: isize) {
         let mut segments = self.segments.write();
-        let seg_id = blk_id / SEGMENT_BLKS;
+        let seg_id = blk_id / self.geometry.segment_blks();
         let seg = segments.get_mut(&(seg_id)).unwrap();
         seg.summary_map.write().insert(blk_id, SummaryEntry{
             inode_id: ino_id as i32,
@@ -910,214 +1309,283 @@ impl LogFileSystem {
         });
     }
 
+    /// Record that inode `ino_id` is kept in block `blk_id`, in the imap of its segment
+    fn _record_inode(&self, ino_id: INodeId, blk_id: BlockId) {
+        let mut segments = self.segments.write();
+        let seg = segments.get_mut(&(blk_id / self.geometry.segment_blks())).unwrap();
+        if seg.seg_imap.write().insert(ino_id, blk_id).is_none() {
+            seg.meta.inodes_num += 1;
+        }
+    }
+
+    /// Move inode `ino_id` from block `old` of inodes, `INVALID_BLKID` if it was in none,
+    /// to block `new`, freeing its slot in the old one
+    fn _move_inode(&self, ino_id: INodeId, old: BlockId, new: BlockId) {
+        // the entry in the imap of the old segment is kept: the new one is rolled forward
+        // after it, and if the log is cut before, the inode is still where it tells
+        self._record_inode(ino_id, new);
+        self.imap_set(ino_id, new);
+        if old != INVALID_BLKID {
+            self.free_inode(old);
+        }
+    }
+
     // map an inode to a existing block
-    fn _map_inode(&self, ino_id: INodeId, blk_id: BlockId, disk_inode: Dirty<DiskINode>) -> Arc<INodeImpl> {
+    fn _map_inode(
+        &self,
+        inodes: &mut BTreeMap<INodeId, Weak<INodeImpl>>,
+        ino_id: INodeId,
+        blk_id: BlockId,
+        disk_inode: Dirty<DiskINode>,
+    ) -> Arc<INodeImpl> {
         let device_inode_id = disk_inode.device_inode_id;
         let inode = Arc::new(INodeImpl {
             id: ino_id,
-            blk_id: blk_id,
+            blk_id: RwLock::new(blk_id),
             disk_inode: RwLock::new(disk_inode),
             fs: self.self_ptr.upgrade().unwrap(),
             device_inode_id: device_inode_id,
+            dir_cursor: RwLock::new((0, 0)),
+            lock: RwLock::new(()),
         });
-        self.inodes.write().insert(ino_id, Arc::downgrade(&inode));
+        inodes.insert(ino_id, Arc::downgrade(&inode));
         inode
     }
 
     /// Get inode by id. Load if not in memory.
     /// ** Must ensure it's a valid INode **
     fn get_inode(&self, id: INodeId) -> Arc<INodeImpl> {
-        // debug!("in get_inode contains {}", self.imaps.read().contains_key(&id))
-        assert!(self.imaps.read().contains_key(&id));
-        let imaps_ptr = self.imaps.read();
+        self.find_inode(id).expect("inode not in the imap")
+    }
+    /// Inode `id` if it is in use: in memory, dropped since it was changed, or in the imap
+    fn find_inode(&self, id: INodeId) -> Option<Arc<INodeImpl>> {
         debug!("get_inode: id={}", id);
-        let blk_ptr = imaps_ptr.get(&id).unwrap();
-        debug!("get_inode: blkid={}", blk_ptr);
-        // In the BTreeSet and not weak.
-        if let Some(inode) = self.inodes.read().get(&id) {
-            if let Some(inode) = inode.upgrade() {
-                return inode;
+        loop {
+            let mut inodes = self.inodes.write();
+            match inodes.get(&id).map(Weak::upgrade) {
+                Some(Some(inode)) => return Some(inode),
+                // being dropped, it is looked up again once it is, see `Drop for INodeImpl`
+                Some(None) => {
+                    drop(inodes);
+                    spin_loop();
+                    continue;
+                }
+                None => {}
+            }
+            // dropped since it was changed, and not written yet
+            let mut pending = self.pending.write();
+            if let Some((blk, disk_inode)) = pending.remove(&id) {
+                return Some(self._map_inode(&mut inodes, id, blk, disk_inode));
+            }
+            let blk = self.imap_get(id)?;
+            debug!("get_inode: blkid={}", blk);
+            // Load if not in set, or is weak ref.
+            let mut disk_inode = Dirty::new(self.load_inode(id, blk).unwrap());
+            // debug!("TTT id {} turn_stale", id);
+            disk_inode.turn_stale();
+            drop(pending);
+            return Some(self._map_inode(&mut inodes, id, blk, disk_inode));
+        }
+    }
+    /// Dir `dir` and those it is in, up to the root, as their '..' tell
+    fn parents(&self, dir: INodeId) -> Vec<INodeId> {
+        let mut parents = vec![dir];
+        let mut id = dir;
+        while id != INO_ROOT {
+            let inode = match self.find_inode(id) {
+                Some(inode) => inode,
+                None => break,
+            };
+            let _lock = inode.lock.read();
+            match inode.get_file_inode_id("..") {
+                Some(parent) if !parents.contains(&parent) => parents.push(parent),
+                _ => break,
             }
+            id = *parents.last().unwrap();
         }
-        let blk = *blk_ptr;
-        // Load if not in set, or is weak ref.
-        let mut disk_inode = Dirty::new(self.device.load_struct::<DiskINode>(blk).unwrap());
-        // debug!("TTT id {} turn_stale", id);
-        disk_inode.turn_stale();
-        self._map_inode(id, blk, disk_inode)
+        parents
+    }
+    /// Current time, if there is a clock
+    fn now(&self) -> Option<Timespec> {
+        self.time.map(|time| time.current_time())
+    }
+    /// A new disk inode created now
+    fn new_disk_inode(&self, mut disk_inode: DiskINode) -> Dirty<DiskINode> {
+        if let Some(now) = self.now() {
+            disk_inode.atime = now;
+            disk_inode.mtime = now;
+            disk_inode.ctime = now;
+        }
+        Dirty::new_dirty(disk_inode)
     }
     /// Create a new INode file
     fn new_inode_file(&self) -> vfs::Result<Arc<INodeImpl>> {
-        let id = self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
-        let disk_inode = Dirty::new_dirty(DiskINode::new_file());
-        let new_inode = self._new_inode(id, disk_inode);
-        self._record_block_summary(new_inode.id, new_inode.blk_id, ENTRY_SPECIALBLOCK);
-        Ok(new_inode)
+        let disk_inode = self.new_disk_inode(DiskINode::new_file());
+        Ok(self._new_inode(disk_inode))
     }
     /// Create a new INode symlink
     fn new_inode_symlink(&self) -> vfs::Result<Arc<INodeImpl>> {
-        Err(FsError::NotSupported)
+        let disk_inode = self.new_disk_inode(DiskINode::new_symlink());
+        Ok(self._new_inode(disk_inode))
     }
     /// Create a new INode dir
     fn new_inode_dir(&self, parent: INodeId) -> vfs::Result<Arc<INodeImpl>> {
-        let id = self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
-        let disk_inode = Dirty::new_dirty(DiskINode::new_dir());
-        let inode = self._new_inode(id, disk_inode);
+        let disk_inode = self.new_disk_inode(DiskINode::new_dir());
+        let inode = self._new_inode(disk_inode);
         inode.init_direntry(parent)?;
-        self._record_block_summary(inode.id, inode.blk_id, ENTRY_SPECIALBLOCK);
         Ok(inode)
     }
+    /// Create a new INode fifo
+    fn new_inode_fifo(&self) -> vfs::Result<Arc<INodeImpl>> {
+        let disk_inode = self.new_disk_inode(DiskINode::new_fifo());
+        Ok(self._new_inode(disk_inode))
+    }
+    /// Create a new INode socket
+    fn new_inode_socket(&self) -> vfs::Result<Arc<INodeImpl>> {
+        let disk_inode = self.new_disk_inode(DiskINode::new_socket());
+        Ok(self._new_inode(disk_inode))
+    }
     /// Create a new INode chardevice
-    pub fn new_inode_chardevice(&self, device_inode_id: usize) -> vfs::Result<Arc<INodeImpl>> {
+    pub fn new_inode_chardevice(&self, _device_inode_id: usize) -> vfs::Result<Arc<INodeImpl>> {
         Err(FsError::NotSupported)
     }
-    fn flush_weak_inodes(&self) {
-        let mut inodes = self.inodes.write();
-        let remove_ids: Vec<_> = inodes
-            .iter()
-            .filter(|(_, inode)| inode.upgrade().is_none())
-            .map(|(&id, _)| id)
-            .collect();
-        for id in remove_ids.iter() {
-            inodes.remove(&id);
-        }
-    }
-
+    /// Free the segments full, or ended before, with no live block left, which takes no
+    /// block to move
     fn _detect_garbage_segment(&self) {
-        for seg_i in 1..self.super_block.read().n_segment as usize {
-            let mut segments = self.segments.write();
-            let seg = segments.get_mut(&seg_i).unwrap();
-            let mut cleanable = true;
-            if seg.meta.size == SEGMENT_SIZE as u32 {
-                // only search for full segments, which can be full of stale blocks
-                for (blkid, entry_i) in seg.summary_map.write().iter() {
-                    let ino_id = entry_i.inode_id as usize;
-                    let alive;
-                    if entry_i.entry_id == ENTRY_GARBAGE as i32 {
-                        alive = false;
-                    }
-                    else if entry_i.entry_id == ENTRY_SPECIALBLOCK as i32 {
-                        // if it is an inode/indirect block, it is alive only when it exists in imaps
-                        if self.imaps.read().contains_key(&ino_id) && (*self.imaps.read().get(&ino_id).unwrap())>0 {
-                            alive = true;
-                        } else {
-                            alive = false;
-                        }
-                    } else {
-                        let inode = self.get_inode(ino_id);
-                        let latest_blk_id = inode.get_disk_block_id(entry_i.entry_id as usize).unwrap();
-                        alive = (*blkid) == latest_blk_id;
-                    }
-                    if alive {
-                        // there is still at least one live block in one segment, skip it.
-                        cleanable = false;
-                        break
-                    }
-                }
-            }
-            if cleanable {
-                seg.meta.unused = 1;
-                seg.meta.inodes_num = 0;
-                seg.meta.size = (SEGMENT_META_SIZE + SS_PER_SEGMENT_SIZE + IMAP_PER_SEGMENT_SIZE) as u32;
-                debug!("seg {} unused", seg_i);
+        let (n_segment, current) = {
+            let sb = self.super_block.read();
+            (sb.n_segment as usize, sb.current_seg_id as usize)
+        };
+        let pinned = self.pinned_segments();
+        for seg_i in 1..n_segment {
+            let ended = {
+                let meta = &self.segments.read()[&seg_i].meta;
+                meta.size as usize == self.geometry.segment_size() || (meta.unused == 0 && seg_i != current)
+            };
+            if ended && !pinned.contains(&seg_i) && self.all_recorded(seg_i) && self.live_blocks(seg_i).is_empty() {
+                self.release_segment(seg_i);
             }
         }
     }
-}
 
-impl vfs::FileSystem for LogFileSystem {
-    /// Write back super block if dirty
-    fn sync(&self) -> vfs::Result<()> {
-        let mut super_block = self.super_block.write();
-        if super_block.dirty() {
-            self.device
-                .write_block(BLKN_SUPER, 0, super_block.as_buf())?;
-            super_block.sync();
-        }
-        let mut imaps = self.imaps.write();
-        let mut cr = self.check_region.write();
-        if imaps.dirty() {
-            cr.inodes_num = imaps.len() as u32;
-            // debug!("writeback imaps offset {} len:{}", BLKN_CR * BLKSIZE, cr.inodes_num);
-            self.device.write_block(BLKN_CR, 0, cr.as_buf())?;
-            cr.sync();
-            imaps.sync();
+    /// Write back super block and segments if dirty, and a checkpoint if it is due,
+    /// under `ops`
+    fn _sync(&self) -> vfs::Result<()> {
+        if self.snapshot.is_some() {
+            return Ok(());
         }
-        for seg_id in 1..super_block.n_segment as usize {
-            assert!(self.segments.read().contains_key(&seg_id));
-            let mut segments = self.segments.write();
-            let segment = segments.get_mut(&seg_id).unwrap();
-            // sync metadata
-            if segment.meta.dirty() {
-                self.device.write_block(0, seg_id * SEGMENT_SIZE, segment.meta.as_buf())?;
-                segment.meta.sync();
-            }
-            // sync imaps per seg
-            let mut seg_imaps = segment.seg_imap.write();
-            if seg_imaps.dirty() {
-                let mut idx = 0;
-                for (ino_i, blkid_i) in seg_imaps.iter() {
-                    self.device.write_block(0, seg_id * SEGMENT_SIZE + SEGMENT_META_SIZE + idx * 8, (*ino_i as u32).as_buf())?;
-                    self.device.write_block(0, seg_id * SEGMENT_SIZE + SEGMENT_META_SIZE + idx * 8 + 4, (*blkid_i as u32).as_buf())?;
-                    debug!("sync ino {} blkid {}", ino_i, blkid_i);
-                    idx += 1;
-                }
-                seg_imaps.sync();
-            }
-            // sync segment summary
-            let mut seg_summary = segment.summary_map.write();
-            if seg_summary.dirty() {
-                // let mut idx = 0;
-                for (blk_id, entry_i) in seg_summary.iter() {
-                    // println!("sync summary offset segid{} blkid{} {}", seg_id, blk_id, seg_id * SEGMENT_SIZE + SEGMENT_META_SIZE + IMAP_PER_SEGMENT_SIZE + blk_id * mem::size_of::<SummaryEntry>());
-                    debug!("sync blkid {} offset {}", blk_id, seg_id * SEGMENT_SIZE + SEGMENT_META_SIZE + IMAP_PER_SEGMENT_SIZE + blk_id * mem::size_of::<SummaryEntry>());
-                    self.device.write_block(0, seg_id * SEGMENT_SIZE + SEGMENT_META_SIZE + IMAP_PER_SEGMENT_SIZE + blk_id * mem::size_of::<SummaryEntry>(), entry_i.as_buf())?;
-                    // idx += 1;
-                }
-                seg_summary.sync();
+        // first, as the inodes written take blocks and change the imap
+        self.write_inodes()?;
+        self.flush_imap()?;
+        // the blocks of the log, before the segments telling of them
+        self.buffer.write_back()?;
+        let n_segment = {
+            let mut super_block = self.super_block.write();
+            if super_block.dirty() {
+                self.device
+                    .write_block(BLKN_SUPER, 0, super_block.as_buf())?;
+                super_block.sync();
             }
+            super_block.n_segment as usize
+        };
+        // the log since the checkpoint first, so that the blocks the others tell are freed
+        // are only freed on disk once what freed them can be rolled forward
+        let (log, others) = self.segments_to_write(n_segment);
+        if !log.is_empty() || !others.is_empty() {
+            // the segment usage table changed
+            self.check_region.write().turn_dirty();
         }
-
-        self.flush_weak_inodes();
-        for inode in self.inodes.read().values() {
-            if let Some(inode) = inode.upgrade() {
-                inode.sync_all()?;
-            }
+        // each on disk before the next, which may hold what an operation recorded in it did
+        for seg_id in log {
+            self.write_segment(seg_id, self.segments.write().get_mut(&seg_id).unwrap())?;
+            self.device.flush()?;
+        }
+        self.device.sync()?;
+        for seg_id in others {
+            self.write_segment(seg_id, self.segments.write().get_mut(&seg_id).unwrap())?;
         }
         self.device.sync()?;
+        if self.checkpoint_due() {
+            self.write_checkpoint()?;
+        }
+        self.device.flush()?;
         Ok(())
     }
 
+}
+
+impl vfs::FileSystem for LogFileSystem {
+    /// Write back super block and segments if dirty, and a checkpoint if it is due
+    fn sync(&self) -> vfs::Result<()> {
+        let _ops = self.ops.write();
+        self._sync()
+    }
+
     fn root_inode(&self) -> Arc<dyn vfs::INode> {
         debug!("get root inode");
         return self.get_inode(INO_ROOT);
     }
 
     fn info(&self) -> vfs::FsInfo {
+        // dead blocks are free, as the cleaner can take them back
+        let bfree = unused_blocks(&self.geometry, &self.segments.read()) as usize;
+        // those the writes can reserve, see `reserve`
+        let bavail = self.free_blocks();
         let sb = self.super_block.read();
         vfs::FsInfo {
             bsize: BLKSIZE,
             frsize: BLKSIZE,
             blocks: sb.blocks as usize,
-            bfree: sb.unused_blocks as usize,
-            bavail: sb.unused_blocks as usize,
+            bfree,
+            bavail,
             files: sb.blocks as usize,        // inaccurate
-            ffree: sb.unused_blocks as usize, // inaccurate
+            ffree: bfree,                     // inaccurate
             namemax: MAX_FNAME_LEN,
         }
     }
-}
 
-impl Drop for LogFileSystem {
-    /// Auto sync when drop
-    fn drop(&mut self) {
+    fn snapshot_stats(&self) -> vfs::FsStats {
+        self.stats.snapshot()
+    }
+
+    fn capabilities(&self) -> vfs::FsCapabilities {
+        vfs::FsCapabilities {
+            features: vfs::FsFeatures::SYMLINK
+                | vfs::FsFeatures::HARDLINK
+                | vfs::FsFeatures::CASE_SENSITIVE
+                | vfs::FsFeatures::XATTR,
+            namemax: MAX_FNAME_LEN,
+        }
+    }
+
+    fn begin_batch(&self) {
+        self.batch.write().0 += 1;
+    }
+
+    /// Free all inodes removed in the batch, then sync once, unless it is nested in another
+    fn end_batch(&self) -> vfs::Result<()> {
+        let removed = {
+            let mut batch = self.batch.write();
+            batch.0 = batch.0.saturating_sub(1);
+            if batch.0 > 0 {
+                return Ok(());
+            }
+            core::mem::take(&mut batch.1)
+        };
+        drop(removed);
         self.sync()
-            .expect("Failed to sync when dropping the LogFileSystem");
     }
 }
 
-trait BitsetAlloc {
-    fn alloc(&mut self) -> Option<usize>;
+impl Drop for LogFileSystem {
+    /// Auto checkpoint when drop, but for a snapshot
+    fn drop(&mut self) {
+        if self.snapshot.is_some() {
+            return;
+        }
+        self.checkpoint()
+            .expect("Failed to checkpoint when dropping the LogFileSystem");
+    }
 }
 
 impl AsBuf for [u8; BLKSIZE] {}
@@ -1130,6 +1598,8 @@ impl From<FileType> for vfs::FileType {
             FileType::Dir => vfs::FileType::Dir,
             FileType::CharDevice => vfs::FileType::CharDevice,
             FileType::BlockDevice => vfs::FileType::BlockDevice,
+            FileType::NamedPipe => vfs::FileType::NamedPipe,
+            FileType::Socket => vfs::FileType::Socket,
             _ => panic!("unknown file type"),
         }
     }
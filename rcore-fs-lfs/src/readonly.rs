@@ -0,0 +1,96 @@
+//! Read-only mounts of LFS
+//!
+//! `LogFileSystem::open_readonly()` mounts an image without writing to it, to inspect it or
+//! on a write-protected device. The log since the checkpoint is rolled forward as `open()`
+//! does, so that what is seen is what `open()` would find, but the device is wrapped in a
+//! `Shadow`, which keeps what is written to it in memory: what rolling the log forward
+//! writes, and the checkpoint after it, never reach the image, and are dropped with the
+//! filesystem. Then nothing is changed: the operations changing inodes fail with
+//! `ReadOnlyFs`, and the log is not cleaned.
+use crate::*;
+use rcore_fs::dev::Result as DevResult;
+
+/// A device whose writes are kept in memory, over one which is only read
+pub(crate) struct Shadow {
+    device: Arc<dyn Device>,
+    /// blocks written, by number
+    blocks: Mutex<BTreeMap<BlockId, Vec<u8>>>,
+}
+
+impl Shadow {
+    pub fn new(device: Arc<dyn Device>) -> Self {
+        Shadow {
+            device,
+            blocks: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl Device for Shadow {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> DevResult<usize> {
+        let blocks = self.blocks.lock();
+        let iter = BlockIter {
+            begin: offset,
+            end: offset + buf.len(),
+            block_size_log2: BLKSIZE_LOG2,
+        };
+        let mut pos = 0;
+        for range in iter {
+            let dst = &mut buf[pos..pos + range.len()];
+            match blocks.get(&range.block) {
+                Some(block) => dst.copy_from_slice(&block[range.begin..range.end]),
+                None => {
+                    if self.device.read_at(range.block * BLKSIZE + range.begin, dst)? != dst.len() {
+                        return Ok(pos);
+                    }
+                }
+            }
+            pos += range.len();
+        }
+        Ok(pos)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> DevResult<usize> {
+        let mut blocks = self.blocks.lock();
+        let iter = BlockIter {
+            begin: offset,
+            end: offset + buf.len(),
+            block_size_log2: BLKSIZE_LOG2,
+        };
+        let mut pos = 0;
+        for range in iter {
+            if !blocks.contains_key(&range.block) {
+                let mut block = vec![0u8; BLKSIZE];
+                // the rest of a block written in part is as on the device
+                if range.len() != BLKSIZE {
+                    self.device.read_at(range.block * BLKSIZE, &mut block)?;
+                }
+                blocks.insert(range.block, block);
+            }
+            let block = blocks.get_mut(&range.block).unwrap();
+            block[range.begin..range.end].copy_from_slice(&buf[pos..pos + range.len()]);
+            pos += range.len();
+        }
+        Ok(pos)
+    }
+    fn sync(&self) -> DevResult<()> {
+        Ok(())
+    }
+    fn stats(&self) -> Option<Arc<Stats>> {
+        self.device.stats()
+    }
+    fn sector_size_log2(&self) -> u8 {
+        self.device.sector_size_log2()
+    }
+}
+
+impl LogFileSystem {
+    /// Load LFS from device without writing to it, see `readonly`
+    pub fn open_readonly(device: Arc<dyn Device>) -> vfs::Result<Arc<Self>> {
+        Self::_open(Arc::new(Shadow::new(device)), None, true)
+    }
+
+    /// Whether it was opened by `open_readonly()`
+    pub fn is_readonly(&self) -> bool {
+        self.read_only
+    }
+}
@@ -0,0 +1,110 @@
+//! Write reservations of LFS
+//!
+//! An operation takes the blocks it writes from the head of the log as it goes, so one
+//! finding the log full midway would fail with part of it done: a file grown but not
+//! written, or the table of a dir half made again. So before it changes anything, it
+//! reserves the blocks it takes at worst, see `write_blocks()`, and fails with
+//! `NoDeviceSpace` if they are not free. The blocks free are the data blocks of the
+//! segments which are not live, as the cleaner takes the dead ones back, but for those of
+//! the segments pinned for snapshots, less `CLEAN_HEADROOM` segments left for the cleaner
+//! to move live blocks to, and less the blocks the operations under way reserved, counted
+//! until they end. If the free segments have no room for them, the log is cleaned first,
+//! whatever the `CleanerPolicy`, as the operation could not go on otherwise.
+//!
+//! Removing an entry reserves nothing, so that a full log can still be emptied: the block
+//! of the dir it writes is taken from the headroom.
+use crate::*;
+
+/// Segments of free blocks left for the cleaner
+pub const CLEAN_HEADROOM: usize = 1;
+
+/// Blocks writing a block of a file over takes at worst: its copy, the indirect blocks
+/// above it, copied, and a block of inodes and one of the imap for the inode
+const BLOCK_REWRITE: usize = 6;
+
+/// Blocks a write of bytes `begin..end` of a file of `blocks` blocks takes at worst: those
+/// it adds to the file, those it writes, each copied, and the indirect blocks above them,
+/// each taken or copied, with a block of inodes and one of the imap for the inode
+pub(crate) fn write_blocks(blocks: usize, begin: usize, end: usize) -> usize {
+    let first = begin / BLKSIZE;
+    let last = (end + BLKSIZE - 1) / BLKSIZE;
+    let added = last.saturating_sub(blocks);
+    let written = last.saturating_sub(first);
+    // at each height, one more than they span at each end of each of the 3 trees
+    let from = first.min(blocks).max(NDIRECT);
+    let indirect: usize = match last > from {
+        true => (1..=3u32)
+            .map(|height| (last - from) / BLK_NENTRY.pow(height) + 6)
+            .sum(),
+        false => 0,
+    };
+    added + written + indirect + 2
+}
+
+/// Blocks reserved by an operation under way, given back when it is dropped
+pub(crate) struct Reservation<'a> {
+    fs: &'a LogFileSystem,
+    blocks: usize,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        *self.fs.reserved.write() -= self.blocks;
+    }
+}
+
+impl INodeImpl {
+    /// Blocks a namespace operation adding an entry to this dir takes at worst, making its
+    /// table again twice as large, and writing a block over in each of `others` inodes
+    pub(crate) fn dirop_blocks(&self, others: usize) -> usize {
+        let blocks = self.disk_inode.read().blocks as usize;
+        write_blocks(0, 0, (blocks * 2).max(1) * BLKSIZE) + others * BLOCK_REWRITE
+    }
+}
+
+impl LogFileSystem {
+    /// Reserve `blocks` blocks for an operation about to take them, see the module docs.
+    /// It is called before the operation holds `ops`, as cleaning syncs.
+    pub(crate) fn reserve(&self, blocks: usize) -> vfs::Result<Reservation<'_>> {
+        loop {
+            let short = {
+                let mut reserved = self.reserved.write();
+                let wanted = *reserved + blocks;
+                if wanted > self.unreserved_blocks() {
+                    return Err(FsError::NoDeviceSpace);
+                }
+                // the headroom is left in the free segments too
+                let room = self.room().saturating_sub(CLEAN_HEADROOM * self.geometry.data_blks());
+                if wanted <= room {
+                    *reserved = wanted;
+                    return Ok(Reservation { fs: self, blocks });
+                }
+                wanted - room
+            };
+            let data_blks = self.geometry.data_blks();
+            debug!("reserve {} blocks, {} short", blocks, short);
+            if self.clean((short + data_blks - 1) / data_blks)? == 0 {
+                return Err(FsError::NoDeviceSpace);
+            }
+        }
+    }
+
+    /// Blocks free for the operations to take, see the module docs
+    pub fn free_blocks(&self) -> usize {
+        self.unreserved_blocks().saturating_sub(*self.reserved.read())
+    }
+
+    /// Blocks free, with those reserved
+    fn unreserved_blocks(&self) -> usize {
+        let pinned = self.pinned_segments();
+        let data_blks = self.geometry.data_blks();
+        let free: usize = self
+            .segments
+            .read()
+            .iter()
+            .filter(|(seg_id, _)| !pinned.contains(seg_id))
+            .map(|(_, seg)| data_blks - (seg.meta.live_bytes as usize / BLKSIZE).min(data_blks))
+            .sum();
+        free.saturating_sub(CLEAN_HEADROOM * data_blks)
+    }
+}
@@ -0,0 +1,218 @@
+//! Snapshots of LFS
+//!
+//! Blocks on disk are not written over, so the blocks a checkpoint tells of stay as they
+//! were until their segments are cleaned. Pinning a checkpoint keeps it, its imap blocks
+//! and the segments in use at it, after the table of each checkpoint written later, and
+//! the cleaner leaves those segments alone. `open_snapshot()` opens a read-only view of
+//! the filesystem at a checkpoint pinned, reading it through its imap.
+use crate::*;
+
+/// A checkpoint kept, see `checkpoints()`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CheckpointInfo {
+    /// number of the checkpoint
+    pub id: u32,
+    /// whether it is kept after later ones, and can be opened
+    pub pinned: bool,
+}
+
+/// A checkpoint pinned
+pub(crate) struct Snapshot {
+    /// block each imap block was kept in
+    imap: Vec<BlockId>,
+    /// segments in use at it
+    segments: Vec<SegmentId>,
+    /// the view opened at it, if any
+    view: Weak<LogFileSystem>,
+}
+
+impl Snapshot {
+    /// Append checkpoint `seq` to `buf` as kept on disk: its number, the number of its imap
+    /// blocks and of its segments, u32 each, then the blocks, u64 each, and the segments, u32
+    /// each
+    fn encode(&self, seq: u32, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&seq.to_ne_bytes());
+        buf.extend_from_slice(&(self.imap.len() as u32).to_ne_bytes());
+        buf.extend_from_slice(&(self.segments.len() as u32).to_ne_bytes());
+        for &blk_id in self.imap.iter() {
+            buf.extend_from_slice(&(blk_id as u64).to_ne_bytes());
+        }
+        for &seg_id in self.segments.iter() {
+            buf.extend_from_slice(&(seg_id as u32).to_ne_bytes());
+        }
+    }
+
+    /// The checkpoints pinned kept in `buf`, up to the first one not well formed
+    pub fn decode_all(mut buf: &[u8]) -> BTreeMap<u32, Snapshot> {
+        let u32_at =
+            |buf: &[u8], i: usize| u32::from_ne_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+        let mut pinned = BTreeMap::new();
+        while buf.len() >= 12 {
+            let imap_len = u32_at(buf, 4) as usize;
+            let segments_len = u32_at(buf, 8) as usize;
+            let segments_begin = 12 + imap_len * BLK_ID_SIZE;
+            let len = segments_begin + segments_len * 4;
+            if buf.len() < len {
+                break;
+            }
+            let snapshot = Snapshot {
+                imap: buf[12..segments_begin]
+                    .chunks(BLK_ID_SIZE)
+                    .map(|entry| le64(entry) as BlockId)
+                    .collect(),
+                segments: (0..segments_len)
+                    .map(|i| u32_at(buf, segments_begin + i * 4) as SegmentId)
+                    .collect(),
+                view: Weak::new(),
+            };
+            pinned.insert(u32_at(buf, 0), snapshot);
+            buf = &buf[len..];
+        }
+        pinned
+    }
+}
+
+impl LogFileSystem {
+    /// The checkpoints kept: the latest one, and those pinned, by number
+    pub fn checkpoints(&self) -> Vec<CheckpointInfo> {
+        let latest = self.check_region.read().seq;
+        let pinned = self.pinned.read();
+        let mut ids: BTreeSet<u32> = pinned.keys().cloned().collect();
+        ids.insert(latest);
+        ids.into_iter()
+            .map(|id| CheckpointInfo {
+                id,
+                pinned: pinned.contains_key(&id),
+            })
+            .collect()
+    }
+
+    /// Pin checkpoint `id`, which must be the latest, once the state is synced, or pinned
+    /// already. It is kept, and can be opened by `open_snapshot()`, until unpinned.
+    pub fn pin_checkpoint(&self, id: u32) -> vfs::Result<()> {
+        self.check_writable()?;
+        if self.pinned.read().contains_key(&id) {
+            return Ok(());
+        }
+        self.checkpoint()?;
+        {
+            let mut cr = self.check_region.write();
+            if cr.seq != id {
+                return Err(FsError::EntryNotFound);
+            }
+            let segments = self.segments.read();
+            let snapshot = Snapshot {
+                imap: self.imap.read().locations().to_vec(),
+                segments: segments
+                    .iter()
+                    .filter(|(_, seg)| seg.meta.unused == 0)
+                    .map(|(&seg_id, _)| seg_id)
+                    .collect(),
+                view: Weak::new(),
+            };
+            self.pinned.write().insert(id, snapshot);
+            cr.turn_dirty();
+        }
+        debug!("pin checkpoint {}", id);
+        self.checkpoint()
+    }
+
+    /// Unpin checkpoint `id`, so that its segments may be cleaned. It is busy while a view
+    /// opened at it is in use.
+    pub fn unpin_checkpoint(&self, id: u32) -> vfs::Result<()> {
+        self.check_writable()?;
+        {
+            let mut pinned = self.pinned.write();
+            match pinned.get(&id) {
+                None => return Err(FsError::EntryNotFound),
+                Some(snapshot) if snapshot.view.upgrade().is_some() => return Err(FsError::Busy),
+                Some(_) => {}
+            }
+            pinned.remove(&id);
+        }
+        debug!("unpin checkpoint {}", id);
+        self.check_region.write().turn_dirty();
+        self.checkpoint()
+    }
+
+    /// A read-only view of the filesystem at checkpoint `id`, which must be pinned
+    pub fn open_snapshot(&self, id: u32) -> vfs::Result<Arc<dyn FileSystem>> {
+        let mut pinned = self.pinned.write();
+        let snapshot = pinned.get_mut(&id).ok_or(FsError::EntryNotFound)?;
+        if let Some(view) = snapshot.view.upgrade() {
+            return Ok(view);
+        }
+        let super_block = self.device.load_struct::<SuperBlock>(BLKN_SUPER)?;
+        let check_region = CheckRegion {
+            inodes_num: 0,
+            seq: id,
+            seg_seq: 0,
+            current_seg_id: 0,
+            imap_len: snapshot.imap.len() as u32,
+            n_segment: super_block.n_segment,
+            pinned_len: 0,
+            checksum: 0,
+        };
+        let view = LogFileSystem {
+            super_block: RwLock::new(Dirty::new(super_block)),
+            imap: RwLock::new(IMap::new(snapshot.imap.clone())),
+            check_region: RwLock::new(Dirty::new(check_region)),
+            inodes: RwLock::new(BTreeMap::new()),
+            segments: RwLock::new(BTreeMap::new()),
+            device: self.device.clone(),
+            buffer: self.buffer.clone(),
+            self_ptr: Weak::default(),
+            device_inodes: RwLock::new(BTreeMap::new()),
+            batch: RwLock::new((0, Vec::new())),
+            pending: RwLock::new(BTreeMap::new()),
+            cleaned: RwLock::new(BTreeSet::new()),
+            pinned: RwLock::new(BTreeMap::new()),
+            snapshot: Some(id),
+            read_only: false,
+            geometry: self.geometry,
+            class: RwLock::new(SegmentClass::Hot),
+            cleaner: RwLock::new(CleanerPolicy::default()),
+            stats: self.stats.clone(),
+            time: None,
+            ops: RwLock::new(()),
+            appender: Mutex::new(()),
+            cleaning: Mutex::new(()),
+            renaming: Mutex::new(()),
+            checkpoint_wanted: RwLock::new(false),
+            reserved: RwLock::new(0),
+        }
+        .wrap();
+        snapshot.view = Arc::downgrade(&view);
+        debug!("open snapshot {}", id);
+        Ok(view)
+    }
+
+    /// Fail if it is a view of a snapshot, or opened read-only, which are not changed
+    pub(crate) fn check_writable(&self) -> vfs::Result<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnlyFs);
+        }
+        match self.snapshot {
+            Some(_) => Err(FsError::NotPermitted),
+            None => Ok(()),
+        }
+    }
+
+    /// The segments in use at the checkpoints pinned, which are not cleaned
+    pub(crate) fn pinned_segments(&self) -> BTreeSet<SegmentId> {
+        let pinned = self.pinned.read();
+        pinned
+            .values()
+            .flat_map(|snapshot| snapshot.segments.iter().cloned())
+            .collect()
+    }
+
+    /// The checkpoints pinned, as kept after the table of a checkpoint
+    pub(crate) fn encode_pinned(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for (&seq, snapshot) in self.pinned.read().iter() {
+            snapshot.encode(seq, &mut buf);
+        }
+        buf
+    }
+}
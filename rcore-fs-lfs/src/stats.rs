@@ -0,0 +1,111 @@
+//! Statistics of the log of LFS
+//!
+//! `stats()` tells how full the log is and why: the bytes of each segment in use still live
+//! and dead, which the cleaner takes back, the free segments left, how far behind the last
+//! checkpoint is, and how many bytes the log took for the file content written through it,
+//! counting the blocks of inodes and imap and those moved by the cleaner.
+use crate::*;
+use core::fmt;
+
+/// Statistics of the log, see `LogFileSystem::stats()`
+#[derive(Debug, Clone)]
+pub struct LogStats {
+    /// the segments in use, with their live and dead bytes
+    pub segments: Vec<SegmentUsage>,
+    /// segments the log is written to, not counting the one of the checkpoints
+    pub total_segments: usize,
+    /// segments not in use by the log
+    pub free_segments: usize,
+    /// bytes of the data blocks of a segment
+    pub segment_data_bytes: usize,
+    /// bytes of data blocks in use
+    pub live_bytes: usize,
+    /// bytes of data blocks written to segments in use and dead since
+    pub garbage_bytes: usize,
+    /// number of the last checkpoint
+    pub checkpoint_seq: u32,
+    /// segments started since the last checkpoint, which recovery rolls forward
+    pub checkpoint_age: u32,
+    /// bytes of file content written since it was opened
+    pub data_bytes_written: u64,
+    /// bytes of blocks taken by the log since it was opened
+    pub log_bytes_written: u64,
+    /// blocks moved by the cleaner since it was opened
+    pub blocks_moved: u64,
+}
+
+impl LogStats {
+    /// Percent of the data blocks of segment `usage` in use
+    pub fn utilization(&self, usage: &SegmentUsage) -> usize {
+        usage.live_bytes * 100 / self.segment_data_bytes
+    }
+
+    /// Bytes taken by the log for each byte of file content written, in hundredths,
+    /// `None` if no content was written
+    pub fn write_amplification(&self) -> Option<u64> {
+        match self.data_bytes_written {
+            0 => None,
+            bytes => Some(self.log_bytes_written * 100 / bytes),
+        }
+    }
+}
+
+/// One `name value` pair per line like `FsStats`, then a line for each segment in use
+impl fmt::Display for LogStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "total_segments {}", self.total_segments)?;
+        writeln!(f, "free_segments {}", self.free_segments)?;
+        writeln!(f, "segment_data_bytes {}", self.segment_data_bytes)?;
+        writeln!(f, "live_bytes {}", self.live_bytes)?;
+        writeln!(f, "garbage_bytes {}", self.garbage_bytes)?;
+        writeln!(f, "checkpoint_seq {}", self.checkpoint_seq)?;
+        writeln!(f, "checkpoint_age {}", self.checkpoint_age)?;
+        writeln!(f, "data_bytes_written {}", self.data_bytes_written)?;
+        writeln!(f, "log_bytes_written {}", self.log_bytes_written)?;
+        writeln!(f, "blocks_moved {}", self.blocks_moved)?;
+        match self.write_amplification() {
+            Some(wa) => writeln!(f, "write_amplification {}.{:02}", wa / 100, wa % 100)?,
+            None => writeln!(f, "write_amplification -")?,
+        }
+        for usage in self.segments.iter() {
+            writeln!(
+                f,
+                "segment {} live_bytes {} garbage_bytes {} utilization {}% age {} {:?}{}",
+                usage.seg_id,
+                usage.live_bytes,
+                usage.garbage_bytes,
+                self.utilization(usage),
+                usage.age,
+                usage.class,
+                if usage.current { " current" } else { "" }
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl LogFileSystem {
+    /// Statistics of the log, see the module docs
+    pub fn stats(&self) -> LogStats {
+        let segments = self.segment_usage();
+        let (checkpoint_seq, checkpoint_age) = {
+            let cr = self.check_region.read();
+            let sb = self.super_block.read();
+            (cr.seq, sb.seg_seq.wrapping_sub(cr.seg_seq))
+        };
+        let counters = self.stats.snapshot();
+        LogStats {
+            total_segments: self.segments.read().len(),
+            free_segments: self.free_segments(),
+            segment_data_bytes: self.geometry.data_blks() * BLKSIZE,
+            live_bytes: segments.iter().map(|usage| usage.live_bytes).sum(),
+            garbage_bytes: segments.iter().map(|usage| usage.garbage_bytes).sum(),
+            checkpoint_seq,
+            checkpoint_age,
+            data_bytes_written: counters.data_bytes_written,
+            log_bytes_written: counters.blocks_allocated * BLKSIZE as u64,
+            blocks_moved: counters.blocks_moved,
+            segments,
+        }
+    }
+}
@@ -4,6 +4,7 @@ use crate::vfs;
 use alloc::{
     str,
     collections::BTreeMap,
+    vec::Vec,
 };
 
 use core::fmt::{Debug, Error, Formatter};
@@ -20,14 +21,30 @@ pub struct SuperBlock {
     /// magic number, should be LFS_MAGIC
     pub magic: u32,
     /// number of blocks in fs
-    pub blocks: u32,
+    pub blocks: u64,
     /// number of unused blocks in fs
-    pub unused_blocks: u32,
+    pub unused_blocks: u64,
     /// information for sfs
     pub info: Str32,
     pub current_seg_id: u32,
     pub next_ino_number: u32,
     pub n_segment: u32,
+    /// number of segments started, the clock the age of a segment is told by
+    pub seg_seq: u32,
+    /// layout of the segments, 0 in images made before it, taken as `Geometry::default()`
+    pub geometry: Geometry,
+}
+
+/// Layout of the segments, chosen when created, see `LogFileSystem::create_with_geometry`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Geometry {
+    /// blocks of a segment, segment 0 holding the super block and the checkpoint regions
+    pub segment_blks: u32,
+    /// blocks of the imap of a segment, after its metadata block
+    pub imap_blks: u32,
+    /// blocks of the summary of a segment, after its imap
+    pub summary_blks: u32,
 }
 
 /// inode (on disk)
@@ -35,23 +52,39 @@ pub struct SuperBlock {
 #[derive(Debug)]
 pub struct DiskINode {
     /// size of the file (in bytes)
-    /// undefined in dir (256 * #entries ?)
-    pub size: u32,
+    /// in dir, `DIRENT_SIZE` * #entries
+    pub size: u64,
     /// one of SYS_TYPE_* above
     pub type_: FileType,
     /// number of hard links to this file
     /// Note: "." and ".." is counted in this nlinks
     pub nlinks: u16,
     /// number of blocks
-    pub blocks: u32,
+    pub blocks: u64,
     /// direct blocks
-    pub direct: [u32; NDIRECT],
-    /// indirect blocks
-    pub indirect: u32,
+    pub direct: [u64; NDIRECT],
+    /// indirect blocks, see `indirect`
+    pub indirect: u64,
     /// double indirect blocks
-    pub db_indirect: u32,
+    pub db_indirect: u64,
+    /// triple indirect blocks
+    pub tr_indirect: u64,
     /// device inode id for char/block device (major, minor)
     pub device_inode_id: usize,
+    /// Time of last access
+    pub atime: vfs::Timespec,
+    /// Time of last modification
+    pub mtime: vfs::Timespec,
+    /// Time of last change
+    pub ctime: vfs::Timespec,
+    /// permission bits
+    pub mode: u16,
+    /// owner
+    pub uid: u32,
+    /// group
+    pub gid: u32,
+    /// block of the extended attributes, see `xattr`
+    pub xattr: u64,
 }
 
 /*
@@ -66,7 +99,7 @@ pub type DeviceINode = dyn vfs::INode;
 
 #[repr(C)]
 pub struct IndirectBlock {
-    pub entries: [u32; BLK_NENTRY],
+    pub entries: [u64; BLK_NENTRY],
 }
 
 /// file entry (on disk)
@@ -78,9 +111,25 @@ pub struct DiskEntry {
     /// file name
     pub name: Str256,
 }
+/// Header of a checkpoint region, followed by the blocks of the imap and the segment usage table
+#[repr(C)]
 pub struct CheckRegion {
     // pub imaps_blkid: u32,
     pub inodes_num: u32,
+    /// number of the checkpoint, the valid region with the larger one is the latest
+    pub seq: u32,
+    /// `SuperBlock::seg_seq` at the checkpoint, the segments started later are rolled forward
+    pub seg_seq: u32,
+    /// segment the log was written to at the checkpoint
+    pub current_seg_id: u32,
+    /// number of imap blocks, the u64 blocks they are kept in follow the header
+    pub imap_len: u32,
+    /// number of `SegmentMeta` entries of the segment usage table following them
+    pub n_segment: u32,
+    /// bytes of the checkpoints pinned for snapshots following the table, see `snapshot`
+    pub pinned_len: u32,
+    /// crc32c of the header, with this field 0, the imap blocks, the table and the pinned
+    pub checksum: u32,
 }
 
 #[repr(C)]
@@ -137,6 +186,62 @@ impl SuperBlock {
     }
 }
 
+impl Geometry {
+    /// Segments of `segment_blks` blocks, with an imap and a summary just large enough
+    pub const fn new(segment_blks: usize) -> Self {
+        let imap_blks = (segment_blks * IMAP_ENTRY_SIZE + BLKSIZE - 1) / BLKSIZE;
+        let summary_blks = (segment_blks * size_of::<SummaryEntry>() + BLKSIZE - 1) / BLKSIZE;
+        Geometry {
+            segment_blks: segment_blks as u32,
+            imap_blks: imap_blks as u32,
+            summary_blks: summary_blks as u32,
+        }
+    }
+    /// Whether segments can be laid out so: between `MIN_SEGMENT_BLKS` and `MAX_SEGMENT_BLKS`
+    /// blocks, each with an entry in the summary, and each data block in the imap
+    pub fn check(&self) -> bool {
+        let blks = self.segment_blks as usize;
+        blks >= MIN_SEGMENT_BLKS
+            && blks <= MAX_SEGMENT_BLKS
+            && self.data_begin() < blks
+            && self.summary_blks as usize * BLKSIZE >= blks * size_of::<SummaryEntry>()
+            && self.imap_blks as usize * BLKSIZE >= self.data_blks() * IMAP_ENTRY_SIZE
+    }
+    pub fn segment_blks(&self) -> usize {
+        self.segment_blks as usize
+    }
+    /// size of a segment
+    pub fn segment_size(&self) -> usize {
+        self.segment_blks as usize * BLKSIZE
+    }
+    /// block of a segment its data begins at, after its metadata, imap and summary
+    pub fn data_begin(&self) -> usize {
+        (SEGMENT_META_SIZE / BLKSIZE) + self.imap_blks as usize + self.summary_blks as usize
+    }
+    /// size of the metadata, imap and summary of a segment
+    pub fn header_size(&self) -> usize {
+        self.data_begin() * BLKSIZE
+    }
+    /// number of data blocks of a segment
+    pub fn data_blks(&self) -> usize {
+        self.segment_blks() - self.data_begin()
+    }
+    /// offset of the summary in a segment
+    pub fn summary_offset(&self) -> usize {
+        SEGMENT_META_SIZE + self.imap_blks as usize * BLKSIZE
+    }
+    /// number of blocks of a checkpoint region, in segment 0
+    pub fn cr_blks(&self) -> usize {
+        (self.segment_blks() - BLKN_CR) / 2
+    }
+}
+
+impl Default for Geometry {
+    fn default() -> Self {
+        Geometry::new(SEGMENT_BLKS)
+    }
+}
+
 impl DiskINode {
     pub const fn new_file() -> Self {
         DiskINode {
@@ -147,7 +252,15 @@ impl DiskINode {
             direct: [0; NDIRECT],
             indirect: 0,
             db_indirect: 0,
+            tr_indirect: 0,
             device_inode_id: NODEVICE,
+            atime: vfs::Timespec { sec: 0, nsec: 0 },
+            mtime: vfs::Timespec { sec: 0, nsec: 0 },
+            ctime: vfs::Timespec { sec: 0, nsec: 0 },
+            mode: 0o777,
+            uid: 0,
+            gid: 0,
+            xattr: 0,
         }
     }
     pub const fn new_symlink() -> Self {
@@ -159,7 +272,15 @@ impl DiskINode {
             direct: [0; NDIRECT],
             indirect: 0,
             db_indirect: 0,
+            tr_indirect: 0,
             device_inode_id: NODEVICE,
+            atime: vfs::Timespec { sec: 0, nsec: 0 },
+            mtime: vfs::Timespec { sec: 0, nsec: 0 },
+            ctime: vfs::Timespec { sec: 0, nsec: 0 },
+            mode: 0o777,
+            uid: 0,
+            gid: 0,
+            xattr: 0,
         }
     }
     pub const fn new_dir() -> Self {
@@ -171,7 +292,55 @@ impl DiskINode {
             direct: [0; NDIRECT],
             indirect: 0,
             db_indirect: 0,
+            tr_indirect: 0,
+            device_inode_id: NODEVICE,
+            atime: vfs::Timespec { sec: 0, nsec: 0 },
+            mtime: vfs::Timespec { sec: 0, nsec: 0 },
+            ctime: vfs::Timespec { sec: 0, nsec: 0 },
+            mode: 0o777,
+            uid: 0,
+            gid: 0,
+            xattr: 0,
+        }
+    }
+    pub const fn new_fifo() -> Self {
+        DiskINode {
+            size: 0,
+            type_: FileType::NamedPipe,
+            nlinks: 0,
+            blocks: 0,
+            direct: [0; NDIRECT],
+            indirect: 0,
+            db_indirect: 0,
+            tr_indirect: 0,
             device_inode_id: NODEVICE,
+            atime: vfs::Timespec { sec: 0, nsec: 0 },
+            mtime: vfs::Timespec { sec: 0, nsec: 0 },
+            ctime: vfs::Timespec { sec: 0, nsec: 0 },
+            mode: 0o777,
+            uid: 0,
+            gid: 0,
+            xattr: 0,
+        }
+    }
+    pub const fn new_socket() -> Self {
+        DiskINode {
+            size: 0,
+            type_: FileType::Socket,
+            nlinks: 0,
+            blocks: 0,
+            direct: [0; NDIRECT],
+            indirect: 0,
+            db_indirect: 0,
+            tr_indirect: 0,
+            device_inode_id: NODEVICE,
+            atime: vfs::Timespec { sec: 0, nsec: 0 },
+            mtime: vfs::Timespec { sec: 0, nsec: 0 },
+            ctime: vfs::Timespec { sec: 0, nsec: 0 },
+            mode: 0o777,
+            uid: 0,
+            gid: 0,
+            xattr: 0,
         }
     }
     pub const fn new_chardevice(device_inode_id: usize) -> Self {
@@ -183,7 +352,15 @@ impl DiskINode {
             direct: [0; NDIRECT],
             indirect: 0,
             db_indirect: 0,
+            tr_indirect: 0,
             device_inode_id: device_inode_id,
+            atime: vfs::Timespec { sec: 0, nsec: 0 },
+            mtime: vfs::Timespec { sec: 0, nsec: 0 },
+            ctime: vfs::Timespec { sec: 0, nsec: 0 },
+            mode: 0o777,
+            uid: 0,
+            gid: 0,
+            xattr: 0,
         }
     }
 }
@@ -203,10 +380,50 @@ pub struct SummaryEntry {
     pub entry_id: i32,
 }
 
+#[repr(C)]
 pub struct SegmentMeta {
     pub size: u32,
     pub inodes_num: u32,
     pub unused: u32,
+    /// `SuperBlock::seg_seq` when the segment was started
+    pub seq: u32,
+    /// bytes of its data blocks in use, counted as they are taken and freed
+    pub live_bytes: u32,
+    /// class of the blocks written to it, `SegmentClass` as u32
+    pub class: u32,
+    /// crc32c of its data blocks up to `size`, checked as it is rolled forward
+    pub data_checksum: u32,
+    /// crc32c of the metadata, with this field 0, its imap, its summary and its records
+    pub checksum: u32,
+}
+
+/// Class of the blocks a segment is written with, see `cleaner`
+#[repr(u32)]
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum SegmentClass {
+    /// written as the filesystem is used: content, and the metadata telling of it
+    Hot = 0,
+    /// moved by the cleaner, as they stayed live while the blocks around them died
+    Cold = 1,
+}
+
+impl SegmentMeta {
+    pub fn class(&self) -> SegmentClass {
+        match self.class {
+            1 => SegmentClass::Cold,
+            _ => SegmentClass::Hot,
+        }
+    }
+}
+
+/// The records of the namespace operations in the metadata of a segment
+#[derive(Default)]
+pub struct DirLog {
+    /// `CheckRegion::seq` of the checkpoint they were done after
+    pub seq: u32,
+    pub records: Vec<u8>,
+    /// bytes of `records` on disk
+    pub synced: usize,
 }
 
 pub struct Segment {
@@ -214,6 +431,10 @@ pub struct Segment {
     pub meta: Dirty<SegmentMeta>,
     pub seg_imap: RwLock<Dirty<IMapTable>>,
     pub summary_map: RwLock<Dirty<BTreeMap<BlockId, SummaryEntry>>>,
+    /// namespace operations done since it was current, see `dirlog`
+    pub dir_log: RwLock<Dirty<DirLog>>,
+    /// end of the data blocks `meta.data_checksum` is of, extended as they are written
+    pub checksum_end: u32,
     // imap: RwLock<BTreeMap<INodeId, INodeImpl>>,
 }
 
@@ -241,8 +462,9 @@ pub type INodeId = BlockId;
 
 pub const NODEVICE: usize = 100;
 
-/// magic number for lfs
-pub const MAGIC: u32 = 0x2f8dbe2c;
+/// magic number for lfs, since sizes and block ids are u64.
+/// Images made before, with magic 0x2f8dbe2c to 0x2f8dbe32, are not opened.
+pub const MAGIC: u32 = 0x2f8dbe33;
 /// size of block
 pub const BLKSIZE: usize = 1usize << BLKSIZE_LOG2; // 4KB
 /// log2( size of block )
@@ -255,11 +477,11 @@ pub const DEFAULT_INFO: &str = "log file system";
 pub const MAX_INFO_LEN: usize = 31;
 /// max length of filename
 pub const MAX_FNAME_LEN: usize = 255;
-/// max file size in theory (48KB + 4MB + 4GB)
-/// however, the file size is stored in u32
-pub const MAX_FILE_SIZE: usize = 0xffffffff;
+/// max file size (48KB + 2MB + 1GB + 512GB)
+pub const MAX_FILE_SIZE: u64 = MAX_NBLOCK_TRIPLE_INDIRECT as u64 * BLKSIZE as u64;
 /// block the superblock lives in
 pub const BLKN_SUPER: BlockId = 0;
+/// block the first checkpoint region starts at, the second one follows it
 pub const BLKN_CR: BlockId = 1;
 pub const BLKN_SEGMENT: BlockId = 0x100;
 /// location of the root dir inode
@@ -269,28 +491,44 @@ pub const INO_ROOT: INodeId = 0;
 pub const BLKBITS: usize = BLKSIZE * 8;
 /// size of one entry
 pub const ENTRY_SIZE: usize = 4;
+/// size of a block id in an indirect block
+pub const BLK_ID_SIZE: usize = 8;
 /// number of entries in a block
-pub const BLK_NENTRY: usize = BLKSIZE / ENTRY_SIZE; // 1024
+pub const BLK_NENTRY: usize = BLKSIZE / BLK_ID_SIZE; // 512
 /// size of a dirent used in the size field
-pub const DIRENT_SIZE: usize = MAX_FNAME_LEN + 1 + ENTRY_SIZE; // 270
+pub const DIRENT_SIZE: usize = MAX_FNAME_LEN + 1 + ENTRY_SIZE; // 260
+/// number of dirents in a block of a dir, see `dir`
+pub const DIRENTS_PER_BLOCK: usize = BLKSIZE / DIRENT_SIZE;
 /// max number of blocks with direct blocks
 pub const MAX_NBLOCK_DIRECT: usize = NDIRECT;
 /// max number of blocks with indirect blocks
 pub const MAX_NBLOCK_INDIRECT: usize = NDIRECT + BLK_NENTRY;
 /// max number of blocks with double indirect blocks
 pub const MAX_NBLOCK_DOUBLE_INDIRECT: usize = NDIRECT + BLK_NENTRY + BLK_NENTRY * BLK_NENTRY;
+/// max number of blocks with triple indirect blocks
+pub const MAX_NBLOCK_TRIPLE_INDIRECT: usize =
+    MAX_NBLOCK_DOUBLE_INDIRECT + BLK_NENTRY * BLK_NENTRY * BLK_NENTRY;
 
-/// size of one segment
+/// blocks of a segment by default, see `Geometry`
 pub const SEGMENT_BLKS: usize = 1024;
-pub const SEGMENT_SIZE: usize = SEGMENT_BLKS * BLKSIZE;
-
-pub const IMAP_PER_SEGMENT_SIZE: usize = BLKSIZE * 2;
-pub const SS_PER_SEGMENT_SIZE: usize = BLKSIZE * 2;
+/// fewest blocks of a segment
+pub const MIN_SEGMENT_BLKS: usize = 32;
+/// most blocks of a segment, which is buffered in memory while it is written
+pub const MAX_SEGMENT_BLKS: usize = 8192;
+/// size of the metadata block of a segment, before its imap
 pub const SEGMENT_META_SIZE: usize = BLKSIZE;
-pub const BLK_DATA_BEGIN: usize = (IMAP_PER_SEGMENT_SIZE + SS_PER_SEGMENT_SIZE + SEGMENT_META_SIZE) / BLKSIZE;
+/// size of an entry of the imap of a segment: the inode, u32, and its block, u64
+pub const IMAP_ENTRY_SIZE: usize = 12;
 pub const SEGN_ROOT: usize = 1;
-pub const ENTRY_SPECIALBLOCK: isize = -1; // for inode, "indirect block"
+pub const ENTRY_XATTR: isize = -1; // for block of extended attributes, see `xattr`
 pub const ENTRY_GARBAGE: isize = -2; // for deleted block
+pub const ENTRY_IMAPBLOCK: isize = -3; // for imap block, whose index is the inode id
+pub const ENTRY_INODEBLOCK: isize = -4; // for block of inodes, whose inode id is the number of them in use
+pub const ENTRY_INDIRECT: isize = -5; // for indirect block, and below it, see `indirect`
+/// size of the slot of an inode in a block of inodes: its number, then the inode
+pub const INODE_SLOT_SIZE: usize = 256;
+/// number of inodes in a block of inodes
+pub const INODES_PER_BLOCK: usize = BLKSIZE / INODE_SLOT_SIZE;
 pub const INVALID_INO: isize = -1;
 pub const INVALID_BLKID: usize = 0;
 
@@ -304,10 +542,12 @@ pub enum FileType {
     SymLink = 3,
     CharDevice = 4,
     BlockDevice = 5,
+    NamedPipe = 6,
+    Socket = 7,
 }
 
 const_assert!(o1; size_of::<SuperBlock>() <= BLKSIZE);
-const_assert!(o2; size_of::<DiskINode>() <= BLKSIZE);
+const_assert!(o2; size_of::<DiskINode>() + 8 <= INODE_SLOT_SIZE);
 const_assert!(o3; size_of::<DiskEntry>() <= BLKSIZE);
 const_assert!(o4; size_of::<IndirectBlock>() == BLKSIZE);
 const_assert!(o5; DEFAULT_INFO.len() <= MAX_INFO_LEN);
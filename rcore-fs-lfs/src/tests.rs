@@ -1,28 +1,139 @@
 extern crate std;
 
 use crate::*;
+use core::ops::Range;
+use rcore_fs::dev;
 use rcore_fs::vfs::{FileSystem, FileType, Metadata, Result, Timespec};
-use std::fs::{self, OpenOptions};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
 
 use std::sync::Arc;
 use std::sync::Mutex;
 
-fn _open_sample_file() -> Arc<SimpleFileSystem> {
+fn _open_sample_file() -> Arc<LogFileSystem> {
     fs::copy("sfs.img", "test.img").expect("failed to open sfs.img");
     let file = OpenOptions::new()
         .read(true)
         .write(true)
         .open("test.img")
         .expect("failed to open test.img");
-    SimpleFileSystem::open(Arc::new(Mutex::new(file))).expect("failed to open SFS")
+    LogFileSystem::open(Arc::new(Mutex::new(file))).expect("failed to open SFS")
 }
 
-fn _create_new_sfs() -> Arc<SimpleFileSystem> {
+fn _create_new_sfs() -> Arc<LogFileSystem> {
     let file = tempfile::tempfile().expect("failed to create file");
-    SimpleFileSystem::create(Arc::new(Mutex::new(file)), 32 * 4096 * 4096)
+    LogFileSystem::create(Arc::new(Mutex::new(file)), 32 * 4096 * 4096)
         .expect("failed to create SFS")
 }
 
+struct Clock;
+
+impl TimeProvider for Clock {
+    fn current_time(&self) -> Timespec {
+        Timespec { sec: 1, nsec: 0 }
+    }
+}
+
+static CLOCK: Clock = Clock;
+
+/// A small LFS of 64 segments of 64 blocks, and its image, to open it again
+fn small_lfs() -> (Arc<LogFileSystem>, File) {
+    let file = tempfile::tempfile().expect("failed to create file");
+    let image = file.try_clone().expect("failed to clone file");
+    let lfs = LogFileSystem::create_with_geometry(
+        Arc::new(Mutex::new(file)),
+        64 * 64 * BLKSIZE,
+        Geometry::new(64),
+        &CLOCK,
+    )
+    .expect("failed to create LFS");
+    (lfs, image)
+}
+
+fn reopen(image: &File) -> Arc<LogFileSystem> {
+    let file = image.try_clone().expect("failed to clone file");
+    LogFileSystem::open(Arc::new(Mutex::new(file))).expect("failed to open LFS")
+}
+
+/// Content `len` bytes long, told apart by `seed`
+fn data(len: usize, seed: usize) -> Vec<u8> {
+    (0..len).map(|i| (i / 509 + seed * 7) as u8).collect()
+}
+
+fn read_all(file: &Arc<dyn INode>) -> Result<Vec<u8>> {
+    let mut buf = vec![0; file.metadata()?.size];
+    let len = file.read_at(0, &mut buf)?;
+    assert_eq!(len, buf.len());
+    Ok(buf)
+}
+
+/// An image in memory, recording the writes and trims done to it
+struct MemDevice {
+    image: Mutex<Vec<u8>>,
+    writes: Mutex<Vec<Range<usize>>>,
+    trims: Mutex<Vec<Range<usize>>>,
+}
+
+impl MemDevice {
+    fn new(len: usize) -> Arc<Self> {
+        Arc::new(MemDevice {
+            image: Mutex::new(vec![0; len]),
+            writes: Mutex::new(Vec::new()),
+            trims: Mutex::new(Vec::new()),
+        })
+    }
+}
+
+impl Device for MemDevice {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> dev::Result<usize> {
+        let image = self.image.lock().unwrap();
+        buf.copy_from_slice(&image[offset..offset + buf.len()]);
+        Ok(buf.len())
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> dev::Result<usize> {
+        let mut image = self.image.lock().unwrap();
+        image[offset..offset + buf.len()].copy_from_slice(buf);
+        self.writes.lock().unwrap().push(offset..offset + buf.len());
+        Ok(buf.len())
+    }
+    fn sync(&self) -> dev::Result<()> {
+        Ok(())
+    }
+    fn trim(&self, range: Range<usize>) -> dev::Result<()> {
+        self.trims.lock().unwrap().push(range);
+        Ok(())
+    }
+}
+
+/// A copy of image `image` as it is on disk now, as a crash would leave it
+fn crash_image(image: &File) -> File {
+    let mut from = image.try_clone().expect("failed to clone file");
+    let mut to = tempfile::tempfile().expect("failed to create file");
+    from.seek(SeekFrom::Start(0)).unwrap();
+    io::copy(&mut from, &mut to).expect("failed to copy image");
+    to
+}
+
+/// Write `buf` over image `image` at `offset`
+fn write_image(image: &File, offset: usize, buf: &[u8]) {
+    let mut file = image.try_clone().expect("failed to clone file");
+    file.seek(SeekFrom::Start(offset as u64)).unwrap();
+    file.write_all(buf).expect("failed to write image");
+}
+
+/// Names in dir `dir`, sorted, as a hashed dir tells them in no order
+fn names(dir: &Arc<dyn INode>) -> Result<Vec<String>> {
+    let mut names = dir.list()?;
+    names.sort();
+    Ok(names)
+}
+
+fn check_clean(lfs: &LogFileSystem) -> Result<()> {
+    let report = fsck::check(lfs, false)?;
+    assert!(report.problems.is_empty(), "{:?}", report.problems);
+    Ok(())
+}
+
 #[test]
 #[ignore]
 fn open_sample_file() {
@@ -44,7 +155,7 @@ fn create_file() -> Result<()> {
     assert_eq!(
         file1.metadata()?,
         Metadata {
-            inode: 7,
+            inode: 1,
             size: 0,
             type_: FileType::File,
             mode: 0o777,
@@ -196,6 +307,10 @@ fn create_then_lookup() -> Result<()> {
 fn test_symlinks() -> Result<()> {
     let sfs = _create_new_sfs();
     let root = sfs.root_inode();
+    assert!(sfs
+        .capabilities()
+        .features
+        .contains(vfs::FsFeatures::SYMLINK));
 
     let file1 = root
         .create("file1", FileType::File, 0o777)
@@ -534,3 +649,1906 @@ fn nlinks() -> Result<()> {
     sfs.sync()?;
     Ok(())
 }
+
+#[test]
+fn remove_dir_all_batched() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let kept = root.create("kept", FileType::File, 0o777)?;
+
+    // a chain of dirs, each with a file, and a link to a file out of it at the bottom
+    let dir1 = root.create("dir1", FileType::Dir, 0o777)?;
+    let mut dir = dir1.clone();
+    for i in 0..64 {
+        dir = dir.create(&format!("d{}", i), FileType::Dir, 0o777)?;
+        let file = dir.create("file", FileType::File, 0o777)?;
+        file.write_at(0, &[i as u8; 5000])?;
+    }
+    dir.link("kept_link", &kept)?;
+    let bottom = dir.find("file")?;
+    drop(dir);
+
+    // nested in a batch of the caller, the removed inodes are released when it ends
+    sfs.begin_batch();
+    vfs::remove_dir_all(&dir1)?;
+    assert_eq!(dir1.list()?, vec![".", ".."]);
+    assert_eq!(bottom.metadata()?.nlinks, 0);
+    assert_eq!(kept.metadata()?.nlinks, 1);
+    assert_eq!(sfs.batch.read().0, 1);
+    assert!(!sfs.batch.read().1.is_empty());
+    sfs.end_batch()?;
+    assert_eq!(sfs.batch.read().0, 0);
+    assert!(sfs.batch.read().1.is_empty());
+
+    drop(bottom);
+    root.unlink("dir1")?;
+    drop(dir1);
+    assert_eq!(root.list()?, vec![".", "..", "kept"]);
+    drop((root, kept));
+    let report = fsck::check(&sfs, false)?;
+    assert!(report.problems.is_empty(), "{:?}", report.problems);
+    Ok(())
+}
+
+#[test]
+fn clean_segments() -> Result<()> {
+    let (lfs, image) = small_lfs();
+    // only on demand
+    lfs.set_cleaner_policy(CleanerPolicy {
+        low: 0,
+        high: 0,
+        background: false,
+    });
+    let root = lfs.root_inode();
+    let kept = root.create("kept", FileType::File, 0o644)?;
+    kept.write_at(0, &data(20 * BLKSIZE, 1))?;
+    // written over and over, leaving segments with few live blocks
+    let hot = root.create("hot", FileType::File, 0o644)?;
+    for i in 0..8 {
+        hot.write_at(0, &data(30 * BLKSIZE, i))?;
+        lfs.sync()?;
+    }
+    let garbage = |lfs: &LogFileSystem| -> usize {
+        lfs.segment_usage()
+            .iter()
+            .filter(|usage| !usage.current)
+            .map(|usage| usage.garbage_bytes)
+            .sum()
+    };
+    let before = garbage(&lfs);
+    let free = lfs.free_segments();
+    let moved = lfs.stats().blocks_moved;
+
+    // those with no live block first, which takes no block to move
+    let victims = lfs.victims();
+    assert!(victims.len() >= 3, "{:?}", lfs.segment_usage());
+    assert_eq!(lfs.clean(2)?, 2);
+    assert_eq!(lfs.stats().blocks_moved, moved);
+    assert_eq!(lfs.free_segments(), free + 2);
+    assert!(garbage(&lfs) < before);
+
+    // then those with some, but the one written now
+    let current = lfs.segment_usage().into_iter().find(|usage| usage.current);
+    let current = current.unwrap().seg_id;
+    assert!(lfs.clean(64)? > 0);
+    assert!(lfs.stats().blocks_moved > moved);
+    // the blocks moved are in cold segments
+    for usage in lfs.segment_usage() {
+        assert!(
+            usage.current || usage.seg_id == current || usage.class == SegmentClass::Cold,
+            "{:?}",
+            usage
+        );
+    }
+    assert_eq!(read_all(&kept)?, data(20 * BLKSIZE, 1));
+    assert_eq!(read_all(&hot)?, data(30 * BLKSIZE, 7));
+    drop((root, kept, hot));
+    check_clean(&lfs)?;
+    drop(lfs);
+
+    let lfs = reopen(&image);
+    let root = lfs.root_inode();
+    assert_eq!(read_all(&root.find("kept")?)?, data(20 * BLKSIZE, 1));
+    assert_eq!(read_all(&root.find("hot")?)?, data(30 * BLKSIZE, 7));
+    drop(root);
+    check_clean(&lfs)
+}
+
+#[test]
+fn crash_recovery() -> Result<()> {
+    let (lfs, image) = small_lfs();
+    let root = lfs.root_inode();
+    root.create("a", FileType::File, 0o644)?
+        .write_at(0, &data(10 * BLKSIZE, 1))?;
+    lfs.checkpoint()?;
+    let older = lfs.check_region.read().seq;
+    // more than a segment, so that the log goes on in the next one
+    let b = root.create("b", FileType::File, 0o644)?;
+    b.write_at(0, &data(100 * BLKSIZE, 2))?;
+    lfs.checkpoint()?;
+    let latest = lfs.check_region.read().seq;
+    assert_eq!(latest, older + 1);
+    let geometry = lfs.geometry;
+    let n_segment = lfs.super_block.read().n_segment as usize;
+    let check = |lfs: &Arc<LogFileSystem>, files: &[(&str, Vec<u8>)]| -> Result<()> {
+        let root = lfs.root_inode();
+        for (name, content) in files {
+            assert!(read_all(&root.find(name)?)? == *content, "{} changed", name);
+        }
+        drop(root);
+        check_clean(lfs)
+    };
+
+    // torn as the latest checkpoint was written: the older one is taken, and the log
+    // written after it rolled forward
+    let torn = crash_image(&image);
+    let region = BLKN_CR + latest as usize % 2 * geometry.cr_blks();
+    write_image(&torn, region * BLKSIZE + CHECKPOINT_HEADER, &[0xff; 64]);
+    let device: Arc<dyn Device> = Arc::new(Mutex::new(torn.try_clone().unwrap()));
+    let ((cr, _, _), _) = LogFileSystem::load_checkpoint(&device, n_segment, &geometry)?.unwrap();
+    assert_eq!(cr.seq, older);
+    drop(device);
+    let recovered = reopen(&torn);
+    check(
+        &recovered,
+        &[("a", data(10 * BLKSIZE, 1)), ("b", data(100 * BLKSIZE, 2))],
+    )?;
+    // with a checkpoint of its own, after the one left
+    assert_eq!(recovered.check_region.read().seq, older + 1);
+    drop(recovered);
+
+    // crashed after a sync: the log since the latest checkpoint is rolled forward, which
+    // goes on to a segment started after it
+    let size = lfs.segments.read()[&(lfs.super_block.read().current_seg_id as usize)]
+        .meta
+        .size as usize;
+    let len = geometry.segment_size() - size + 10 * BLKSIZE;
+    let c = root.create("c", FileType::File, 0o644)?;
+    c.write_at(0, &data(len, 3))?;
+    root.unlink("a")?;
+    lfs.sync()?;
+    assert_eq!(lfs.check_region.read().seq, latest);
+    let crashed = crash_image(&image);
+    let cut = crash_image(&image);
+    let recovered = reopen(&crashed);
+    assert_eq!(names(&recovered.root_inode())?, vec![".", "..", "b", "c"]);
+    check(
+        &recovered,
+        &[("b", data(100 * BLKSIZE, 2)), ("c", data(len, 3))],
+    )?;
+    drop(recovered);
+
+    // torn at the start of that segment, so that the log is cut there: what was done before
+    // is rolled forward, but for the inode of "c", written at the sync, and the unlink of
+    // "a", recorded after the writes
+    let current = lfs.super_block.read().current_seg_id as usize;
+    let begin = current * geometry.segment_size() + geometry.header_size();
+    write_image(&cut, begin, &[0xff; BLKSIZE]);
+    let recovered = reopen(&cut);
+    assert_eq!(names(&recovered.root_inode())?, vec![".", "..", "a", "b"]);
+    check(
+        &recovered,
+        &[("a", data(10 * BLKSIZE, 1)), ("b", data(100 * BLKSIZE, 2))],
+    )?;
+    drop(recovered);
+
+    drop((root, b, c));
+    check_clean(&lfs)
+}
+
+#[test]
+fn segment_usage_table() -> Result<()> {
+    let (lfs, image) = small_lfs();
+    let live = |lfs: &LogFileSystem| -> usize {
+        lfs.segment_usage()
+            .iter()
+            .map(|usage| usage.live_bytes)
+            .sum()
+    };
+    let garbage = |lfs: &LogFileSystem| -> usize {
+        lfs.segment_usage()
+            .iter()
+            .map(|usage| usage.garbage_bytes)
+            .sum()
+    };
+    let (live0, bfree0) = (live(&lfs), lfs.info().bfree);
+    let root = lfs.root_inode();
+    let file = root.create("file", FileType::File, 0o644)?;
+    file.write_at(0, &data(20 * BLKSIZE, 1))?;
+    assert!(live(&lfs) >= live0 + 20 * BLKSIZE);
+    assert!(lfs.info().bfree <= bfree0 - 20);
+    // written over in the write buffer, in place
+    let (live1, garbage1, bfree1) = (live(&lfs), garbage(&lfs), lfs.info().bfree);
+    file.write_at(0, &data(20 * BLKSIZE, 2))?;
+    assert_eq!((live(&lfs), garbage(&lfs)), (live1, garbage1));
+    // once synced, the blocks written over are dead, and free for df
+    lfs.sync()?;
+    let (live1, garbage1) = (live(&lfs), garbage(&lfs));
+    file.write_at(0, &data(20 * BLKSIZE, 2))?;
+    assert_eq!(live(&lfs), live1);
+    // with the indirect block telling of them
+    assert_eq!(garbage(&lfs), garbage1 + 21 * BLKSIZE);
+    assert_eq!(lfs.info().bfree, bfree1);
+    // a segment is older by each one started after it
+    file.write_at(0, &data(80 * BLKSIZE, 3))?;
+    let usage = lfs.segment_usage();
+    assert!(usage.len() >= 2);
+    assert!(usage.iter().filter(|usage| usage.current).count() == 1);
+    for pair in usage.windows(2) {
+        assert_eq!(pair[0].age, pair[1].age + 1, "{:?}", usage);
+    }
+    let table = |lfs: &LogFileSystem| -> Vec<(SegmentId, usize, usize, u32)> {
+        lfs.segment_usage()
+            .iter()
+            .map(|usage| {
+                (
+                    usage.seg_id,
+                    usage.live_bytes,
+                    usage.garbage_bytes,
+                    usage.age,
+                )
+            })
+            .collect()
+    };
+
+    // kept in the checkpoint
+    lfs.checkpoint()?;
+    let checkpointed = (table(&lfs), lfs.info().bfree);
+    let reopened = reopen(&crash_image(&image));
+    assert_eq!((table(&reopened), reopened.info().bfree), checkpointed);
+    // and in the segments written since, as rolled forward, but for the blocks the
+    // checkpoint written then takes
+    root.unlink("file")?;
+    drop(file);
+    lfs.sync()?;
+    assert!(live(&lfs) < live1);
+    let live_table = |lfs: &LogFileSystem| -> Vec<(SegmentId, usize)> {
+        lfs.segment_usage()
+            .iter()
+            .map(|usage| (usage.seg_id, usage.live_bytes))
+            .collect()
+    };
+    let recovered = reopen(&crash_image(&image));
+    assert_eq!(live_table(&recovered), live_table(&lfs));
+    assert_eq!(recovered.info().bfree, lfs.info().bfree);
+    Ok(())
+}
+
+#[test]
+fn imap_paging() -> Result<()> {
+    let (lfs, image) = small_lfs();
+    let root = lfs.root_inode();
+    // each inode in an imap block of its own, more than are kept in memory
+    let files = IMAP_CACHE_BLOCKS + 4;
+    for i in 1..=files {
+        lfs.check_region.write().inodes_num = (i * IMAP_PER_BLOCK) as u32;
+        let file = root.create(&format!("file{}", i), FileType::File, 0o644)?;
+        assert_eq!(file.metadata()?.inode, i * IMAP_PER_BLOCK);
+        file.write_at(0, &data(100, i))?;
+    }
+    lfs.sync()?;
+    assert_eq!(lfs.imap.read().locations().len(), files + 1);
+    let check = |lfs: &LogFileSystem, seed: usize| -> Result<()> {
+        let root = lfs.root_inode();
+        for i in 1..=files {
+            let file = root.find(&format!("file{}", i))?;
+            assert_eq!(read_all(&file)?, data(100, i + seed));
+        }
+        Ok(())
+    };
+    drop(root);
+    drop(lfs);
+
+    // read in again, dropping those least recently used
+    let lfs = reopen(&image);
+    check(&lfs, 0)?;
+    check(&lfs, 0)?;
+    // all of them changed, and kept until written
+    let root = lfs.root_inode();
+    for i in 1..=files {
+        let file = root.find(&format!("file{}", i))?;
+        file.write_at(0, &data(100, i + 1))?;
+        drop(file);
+        root.find(&format!("file{}", i))?.sync_all()?;
+    }
+    check(&lfs, 1)?;
+    drop(root);
+    // and moved by the cleaner
+    lfs.clean(64)?;
+    check(&lfs, 1)?;
+    check_clean(&lfs)?;
+    drop(lfs);
+    let lfs = reopen(&image);
+    check(&lfs, 1)?;
+    check_clean(&lfs)
+}
+
+#[test]
+fn write_buffer() -> Result<()> {
+    const SEGMENT: usize = 64 * BLKSIZE;
+    let device = MemDevice::new(4 * SEGMENT);
+    let buffer = SegmentBuffer::new(device.clone(), SEGMENT);
+    let writes = || core::mem::replace(&mut *device.writes.lock().unwrap(), Vec::new());
+    // from block 3 of segment 2 on
+    let begin = 2 * SEGMENT + 3 * BLKSIZE;
+    buffer.start(2, 3 * BLKSIZE)?;
+    assert!(!buffer.buffered(begin / BLKSIZE - 1));
+    assert!(buffer.buffered(begin / BLKSIZE));
+    assert!(!buffer.buffered(3 * SEGMENT / BLKSIZE));
+    buffer.write_at(begin, &[1; BLKSIZE])?;
+    buffer.write_at(begin + BLKSIZE, &[2; 100])?;
+    // past a gap
+    buffer.write_at(begin + 3 * BLKSIZE, &[3; BLKSIZE])?;
+    assert!(writes().is_empty());
+    // out of it, at once
+    buffer.write_at(0, &[4; 10])?;
+    buffer.write_at(begin - 10, &[5; 20])?;
+    assert_eq!(writes(), vec![0..10, begin - 10..begin]);
+
+    // read from the buffer, and from the device around it
+    let mut buf = vec![0; 3 * BLKSIZE];
+    buffer.read_at(begin - BLKSIZE, &mut buf)?;
+    assert_eq!(&buf[BLKSIZE - 10..BLKSIZE], &[5; 10]);
+    assert_eq!(&buf[BLKSIZE..BLKSIZE + 10], &[5; 10]);
+    assert_eq!(&buf[BLKSIZE + 10..2 * BLKSIZE], &[1; BLKSIZE - 10][..]);
+    assert_eq!(&buf[2 * BLKSIZE..2 * BLKSIZE + 100], &[2; 100][..]);
+    assert!(buf[2 * BLKSIZE + 100..].iter().all(|&b| b == 0));
+    assert_eq!(device.image.lock().unwrap()[begin], 0);
+
+    // written back in one request of whole blocks, the gap being zeros
+    buffer.sync()?;
+    assert_eq!(writes(), vec![begin..begin + 4 * BLKSIZE]);
+    {
+        let image = device.image.lock().unwrap();
+        assert_eq!(image[begin + BLKSIZE + 99], 2);
+        assert_eq!(image[begin + 2 * BLKSIZE], 0);
+        assert_eq!(image[begin + 3 * BLKSIZE], 3);
+    }
+    // not again, and the blocks written back are buffered no more
+    buffer.write_back()?;
+    assert!(writes().is_empty());
+    assert!(!buffer.buffered(begin / BLKSIZE + 3));
+    assert!(buffer.buffered(begin / BLKSIZE + 4));
+    buffer.write_at(begin, &[6; 10])?;
+    assert_eq!(writes(), vec![begin..begin + 10]);
+
+    // the rest when the next segment is started
+    buffer.write_at(begin + 4 * BLKSIZE, &[7; BLKSIZE])?;
+    buffer.start(3, 3 * BLKSIZE)?;
+    assert_eq!(writes(), vec![begin + 4 * BLKSIZE..begin + 5 * BLKSIZE]);
+    assert!(!buffer.buffered(begin / BLKSIZE + 4));
+
+    // so the file content written to LFS is on the device once synced, in few requests
+    let device = MemDevice::new(64 * SEGMENT);
+    let lfs = LogFileSystem::create_with_geometry(
+        device.clone(),
+        64 * SEGMENT,
+        Geometry::new(64),
+        &CLOCK,
+    )?;
+    let writes = || core::mem::replace(&mut *device.writes.lock().unwrap(), Vec::new());
+    let file = lfs.root_inode().create("file", FileType::File, 0o644)?;
+    writes();
+    for i in 0..20 {
+        file.write_at(i * BLKSIZE, &data(BLKSIZE, i))?;
+    }
+    assert!(writes().is_empty());
+    lfs.sync()?;
+    let written = writes();
+    assert!(written.len() < 10, "{:?}", written);
+    assert!(written.iter().any(|range| range.len() >= 20 * BLKSIZE));
+    Ok(())
+}
+
+#[test]
+fn redo_namespace_operations() -> Result<()> {
+    let (lfs, image) = small_lfs();
+    let root = lfs.root_inode();
+    for (i, name) in ["kept", "old", "gone"].iter().enumerate() {
+        root.create(name, FileType::File, 0o644)?
+            .write_at(0, &data(3 * BLKSIZE, i))?;
+    }
+    let dir = root.create("dir", FileType::Dir, 0o755)?;
+    root.create("empty", FileType::Dir, 0o755)?;
+    lfs.checkpoint()?;
+    let seq = lfs.check_region.read().seq;
+    root.create("made", FileType::File, 0o644)?
+        .write_at(0, &data(BLKSIZE, 3))?;
+    let sub = dir.create("sub", FileType::Dir, 0o755)?;
+    sub.link("link", &root.find("kept")?)?;
+    root.unlink("gone")?;
+    root.unlink("empty")?;
+    root.move_("old", &dir, "moved")?;
+    root.move_("kept", &root, "renamed")?;
+    dir.move_("sub", &root, "sub")?;
+    // so that the operations are only told by the log after the checkpoint
+    lfs.sync()?;
+    assert_eq!(lfs.check_region.read().seq, seq);
+    assert!(lfs
+        .segments
+        .read()
+        .values()
+        .any(|segment| !segment.dir_log.read().records.is_empty()));
+    let crashed = crash_image(&image);
+    drop((root, dir, sub));
+    drop(lfs);
+
+    for _ in 0..2 {
+        let recovered = reopen(&crashed);
+        let root = recovered.root_inode();
+        assert_eq!(
+            names(&root)?,
+            vec![".", "..", "dir", "made", "renamed", "sub"]
+        );
+        let dir = root.find("dir")?;
+        let sub = root.find("sub")?;
+        assert_eq!(names(&dir)?, vec![".", "..", "moved"]);
+        assert_eq!(names(&sub)?, vec![".", "..", "link"]);
+        assert!(sub.find("..")?.is_same(&*root)?);
+        let renamed = root.find("renamed")?;
+        assert!(sub.find("link")?.is_same(&*renamed)?);
+        assert_eq!(read_all(&renamed)?, data(3 * BLKSIZE, 0));
+        assert_eq!(read_all(&dir.find("moved")?)?, data(3 * BLKSIZE, 1));
+        assert_eq!(read_all(&root.find("made")?)?, data(BLKSIZE, 3));
+        assert_eq!(root.metadata()?.nlinks, 4);
+        assert_eq!(dir.metadata()?.nlinks, 2);
+        assert_eq!(renamed.metadata()?.nlinks, 2);
+        drop((root, dir, sub, renamed));
+        check_clean(&recovered)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn metadata_kept() -> Result<()> {
+    let (lfs, image) = small_lfs();
+    let root = lfs.root_inode();
+    let file = root.create("file", FileType::File, 0o100644)?;
+    let mut metadata = file.metadata()?;
+    // by the clock, and the type bits are not kept
+    assert_eq!(metadata.mode, 0o644);
+    assert_eq!(metadata.mtime, Timespec { sec: 1, nsec: 0 });
+    assert_eq!(metadata.ctime, Timespec { sec: 1, nsec: 0 });
+    metadata.mode = 0o104755;
+    metadata.uid = 1000;
+    metadata.gid = 100;
+    metadata.atime = Timespec { sec: 10, nsec: 1 };
+    metadata.mtime = Timespec { sec: 20, nsec: 2 };
+    metadata.ctime = Timespec { sec: 30, nsec: 3 };
+    file.set_metadata(&metadata)?;
+    let expected = Metadata {
+        mode: 0o4755,
+        ..metadata
+    };
+    assert_eq!(file.metadata()?, expected);
+    drop((root, file));
+    drop(lfs);
+
+    let lfs = reopen(&image);
+    assert_eq!(lfs.root_inode().find("file")?.metadata()?, expected);
+    Ok(())
+}
+
+#[test]
+fn snapshots() -> Result<()> {
+    let (lfs, image) = small_lfs();
+    let root = lfs.root_inode();
+    let a = root.create("a", FileType::File, 0o644)?;
+    a.write_at(0, &data(30 * BLKSIZE, 1))?;
+    root.create("b", FileType::File, 0o644)?
+        .write_at(0, &data(BLKSIZE, 2))?;
+    lfs.checkpoint()?;
+    let id = lfs.check_region.read().seq;
+    assert_eq!(lfs.open_snapshot(id).err(), Some(FsError::EntryNotFound));
+    lfs.pin_checkpoint(id)?;
+    assert!(lfs
+        .checkpoints()
+        .contains(&CheckpointInfo { id, pinned: true }));
+
+    // changed since, and cleaned as far as it can be
+    a.write_at(0, &data(30 * BLKSIZE, 3))?;
+    root.unlink("b")?;
+    root.create("c", FileType::File, 0o644)?;
+    lfs.clean(64)?;
+    let check_view = |lfs: &LogFileSystem| -> Result<()> {
+        let view = lfs.open_snapshot(id)?;
+        let root = view.root_inode();
+        assert_eq!(names(&root)?, vec![".", "..", "a", "b"]);
+        assert_eq!(read_all(&root.find("a")?)?, data(30 * BLKSIZE, 1));
+        assert_eq!(read_all(&root.find("b")?)?, data(BLKSIZE, 2));
+        // which is not changed
+        assert_eq!(
+            root.create("d", FileType::File, 0o644).err(),
+            Some(FsError::NotPermitted)
+        );
+        assert_eq!(
+            root.find("a")?.write_at(0, b"x"),
+            Err(FsError::NotPermitted)
+        );
+        assert_eq!(root.unlink("a"), Err(FsError::NotPermitted));
+        // nor cleaned while it is in use
+        assert_eq!(lfs.unpin_checkpoint(id), Err(FsError::Busy));
+        Ok(())
+    };
+    check_view(&lfs)?;
+    assert_eq!(names(&root)?, vec![".", "..", "a", "c"]);
+    assert_eq!(read_all(&a)?, data(30 * BLKSIZE, 3));
+    drop((root, a));
+    check_clean(&lfs)?;
+    drop(lfs);
+
+    // kept when opened again
+    let lfs = reopen(&image);
+    assert!(lfs
+        .checkpoints()
+        .contains(&CheckpointInfo { id, pinned: true }));
+    check_view(&lfs)?;
+    let pinned = lfs.pinned_segments();
+    assert!(!pinned.is_empty());
+    lfs.unpin_checkpoint(id)?;
+    assert!(lfs.pinned_segments().is_empty());
+    assert!(!lfs.checkpoints().iter().any(|info| info.pinned));
+    assert_eq!(lfs.open_snapshot(id).err(), Some(FsError::EntryNotFound));
+    // and cleaned once unpinned
+    lfs.clean(64)?;
+    assert!(lfs
+        .segment_usage()
+        .iter()
+        .all(|usage| usage.current || !pinned.contains(&usage.seg_id) || usage.garbage_bytes == 0));
+    check_clean(&lfs)?;
+    assert_eq!(
+        read_all(&lfs.root_inode().find("a")?)?,
+        data(30 * BLKSIZE, 3)
+    );
+    Ok(())
+}
+
+#[test]
+fn geometry() -> Result<()> {
+    assert!(Geometry::new(MIN_SEGMENT_BLKS).check());
+    assert!(Geometry::new(MAX_SEGMENT_BLKS).check());
+    assert!(!Geometry::new(MIN_SEGMENT_BLKS - 1).check());
+    assert!(!Geometry::new(MAX_SEGMENT_BLKS * 2).check());
+    let g = Geometry::new(64);
+    assert_eq!(
+        g.data_begin(),
+        1 + g.imap_blks as usize + g.summary_blks as usize
+    );
+    assert_eq!(g.data_blks() + g.data_begin(), 64);
+    // too small a summary, or an imap
+    let mut bad = g;
+    bad.summary_blks = 0;
+    assert!(!bad.check());
+    let mut bad = g;
+    bad.imap_blks = 0;
+    assert!(!bad.check());
+    // larger than needed is fine
+    let mut roomy = g;
+    roomy.imap_blks += 1;
+    assert!(roomy.check());
+
+    let create = |space, geometry| {
+        let file = tempfile::tempfile().expect("failed to create file");
+        let image = file.try_clone().expect("failed to clone file");
+        LogFileSystem::create_with_geometry(Arc::new(Mutex::new(file)), space, geometry, &CLOCK)
+            .map(|lfs| (lfs, image))
+    };
+    assert_eq!(
+        create(64 * 64 * BLKSIZE, bad).err(),
+        Some(FsError::InvalidParam)
+    );
+    // fewer than 3 segments
+    assert_eq!(
+        create(2 * 64 * BLKSIZE, g).err(),
+        Some(FsError::InvalidParam)
+    );
+    // more segments than the checkpoint regions of a small one tell of
+    assert_eq!(
+        create(4096 * 64 * BLKSIZE, Geometry::new(MIN_SEGMENT_BLKS)).err(),
+        Some(FsError::InvalidParam)
+    );
+
+    for &geometry in [g, roomy, Geometry::new(256)].iter() {
+        let (lfs, image) = create(32 * geometry.segment_size(), geometry)?;
+        assert_eq!(lfs.super_block.read().n_segment, 32);
+        assert_eq!(
+            lfs.stats().segment_data_bytes,
+            geometry.data_blks() * BLKSIZE
+        );
+        // content spanning several segments
+        let len = 3 * geometry.data_blks() * BLKSIZE;
+        let file = lfs.root_inode().create("file", FileType::File, 0o644)?;
+        file.write_at(0, &data(len, 1))?;
+        lfs.sync()?;
+        drop((file, lfs));
+
+        // kept in the super block
+        let lfs = reopen(&image);
+        assert_eq!(lfs.geometry, geometry);
+        assert_eq!(lfs.super_block.read().geometry, geometry);
+        assert_eq!(read_all(&lfs.root_inode().find("file")?)?, data(len, 1));
+        check_clean(&lfs)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn inode_batching() -> Result<()> {
+    let (lfs, _image) = small_lfs();
+    let root = lfs.root_inode();
+    lfs.sync()?;
+
+    // inodes changed together take a block for each `INODES_PER_BLOCK` of them
+    let before = lfs.snapshot_stats();
+    let files = (0..40)
+        .map(|i| {
+            let file = root.create(&format!("file{}", i), FileType::File, 0o644)?;
+            file.write_at(0, &data(BLKSIZE, i))?;
+            Ok(file)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let after = lfs.snapshot_stats();
+    assert_eq!(after.inodes_written, before.inodes_written);
+    lfs.sync()?;
+    let after = lfs.snapshot_stats();
+    // and the root
+    assert_eq!(after.inodes_written - before.inodes_written, 41);
+    assert_eq!(
+        after.inode_blocks_written - before.inode_blocks_written,
+        ((41 + INODES_PER_BLOCK - 1) / INODES_PER_BLOCK) as u64
+    );
+
+    // a block at a time, the inode and the indirect block written once
+    let file = &files[0];
+    let before = lfs.snapshot_stats();
+    let seg_seq = lfs.super_block.read().seg_seq;
+    for i in 0..30 {
+        file.write_at(i * BLKSIZE, &data(BLKSIZE, i))?;
+    }
+    let after = lfs.snapshot_stats();
+    assert_eq!(after.inodes_written, before.inodes_written);
+    // the first block written once again, the rest of them and an indirect block, but for
+    // a block taken as a segment filled, which is written again in the next
+    let started = (lfs.super_block.read().seg_seq - seg_seq) as u64;
+    let allocated = after.blocks_allocated - before.blocks_allocated;
+    assert!(
+        allocated >= 31 && allocated <= 31 + started,
+        "{}",
+        allocated
+    );
+    lfs.sync()?;
+    let after = lfs.snapshot_stats();
+    assert_eq!(after.inodes_written - before.inodes_written, 1);
+    assert_eq!(after.inode_blocks_written - before.inode_blocks_written, 1);
+    let amplification = lfs.stats().write_amplification().unwrap();
+    drop((files, root));
+    check_clean(&lfs)?;
+    // many blocks of content for few of inodes and imap
+    assert!(amplification < 150, "{}", amplification);
+    Ok(())
+}
+
+#[test]
+fn hot_and_cold_segments() -> Result<()> {
+    let (lfs, image) = small_lfs();
+    lfs.set_cleaner_policy(CleanerPolicy {
+        low: 0,
+        high: 0,
+        background: false,
+    });
+    let root = lfs.root_inode();
+    let kept = root.create("kept", FileType::File, 0o644)?;
+    kept.write_at(0, &data(20 * BLKSIZE, 1))?;
+    let hot = root.create("hot", FileType::File, 0o644)?;
+    for i in 0..8 {
+        hot.write_at(0, &data(30 * BLKSIZE, i))?;
+        lfs.sync()?;
+    }
+    let class_of = |lfs: &LogFileSystem, blk_id: BlockId| {
+        let segments = lfs.segments.read();
+        segments[&(blk_id / lfs.geometry.segment_blks())]
+            .meta
+            .class()
+    };
+    let kept_blk = |lfs: &LogFileSystem| -> Result<BlockId> {
+        let kept = lfs.root_inode().find("kept")?;
+        let kept = kept.downcast_ref::<INodeImpl>().unwrap();
+        kept.get_disk_block_id(0)
+    };
+    assert_eq!(class_of(&lfs, kept_blk(&lfs)?), SegmentClass::Hot);
+    assert!(lfs
+        .segment_usage()
+        .iter()
+        .all(|usage| usage.class == SegmentClass::Hot));
+
+    // the blocks moved are written apart, to cold segments
+    assert!(lfs.clean(64)? > 0);
+    assert!(lfs.stats().blocks_moved > 0);
+    assert_eq!(class_of(&lfs, kept_blk(&lfs)?), SegmentClass::Cold);
+    let cold: Vec<_> = lfs
+        .segment_usage()
+        .into_iter()
+        .filter(|usage| usage.class == SegmentClass::Cold)
+        .map(|usage| usage.seg_id)
+        .collect();
+    assert!(!cold.is_empty());
+    // while those written next are hot again
+    let new = root.create("new", FileType::File, 0o644)?;
+    new.write_at(0, &data(4 * BLKSIZE, 2))?;
+    let new_blk = new
+        .downcast_ref::<INodeImpl>()
+        .unwrap()
+        .get_disk_block_id(0)?;
+    assert_eq!(class_of(&lfs, new_blk), SegmentClass::Hot);
+    assert!(!cold.contains(&(new_blk / lfs.geometry.segment_blks())));
+    lfs.sync()?;
+    drop((root, kept, hot, new));
+    check_clean(&lfs)?;
+    drop(lfs);
+
+    // kept in the segment metadata
+    let lfs = reopen(&image);
+    assert_eq!(class_of(&lfs, kept_blk(&lfs)?), SegmentClass::Cold);
+    assert_eq!(
+        read_all(&lfs.root_inode().find("kept")?)?,
+        data(20 * BLKSIZE, 1)
+    );
+    for &seg_id in cold.iter() {
+        assert_eq!(
+            lfs.segments.read()[&seg_id].meta.class(),
+            SegmentClass::Cold
+        );
+    }
+
+    // of two segments alike but for their class, the cold one is cleaned first
+    let len = 3 * lfs.geometry.data_blks() * BLKSIZE;
+    lfs.root_inode()
+        .create("more", FileType::File, 0o644)?
+        .write_at(0, &data(len, 3))?;
+    lfs.sync()?;
+    let (hot_id, cold_id) = {
+        let mut segments = lfs.segments.write();
+        let current = lfs.super_block.read().current_seg_id as usize;
+        let mut ids = segments
+            .iter()
+            .filter(|(&seg_id, seg)| {
+                seg_id != current && seg.meta.size as usize == lfs.geometry.segment_size()
+            })
+            .map(|(&seg_id, _)| seg_id);
+        let (hot_id, cold_id) = (ids.next().unwrap(), ids.next().unwrap());
+        let seq = segments[&hot_id].meta.seq;
+        for &(seg_id, class) in [(hot_id, SegmentClass::Hot), (cold_id, SegmentClass::Cold)].iter()
+        {
+            let seg = segments.get_mut(&seg_id).unwrap();
+            seg.meta.live_bytes = (lfs.geometry.data_blks() / 2 * BLKSIZE) as u32;
+            seg.meta.seq = seq;
+            seg.meta.class = class as u32;
+        }
+        (hot_id, cold_id)
+    };
+    let victims = lfs.victims();
+    let rank = |seg_id| victims.iter().position(|&id| id == seg_id).unwrap();
+    assert!(rank(cold_id) < rank(hot_id), "{:?}", victims);
+    Ok(())
+}
+
+#[test]
+fn fsck_finds_corruption() -> Result<()> {
+    use crate::fsck::Problem;
+    let (lfs, image) = small_lfs();
+    {
+        let root = lfs.root_inode();
+        let dir = root.create("dir", FileType::Dir, 0o755)?;
+        let file = dir.create("file", FileType::File, 0o644)?;
+        file.write_at(0, &data(20 * BLKSIZE, 1))?;
+        root.link("link", &file)?;
+        lfs.sync()?;
+        // leaving some garbage behind
+        file.write_at(0, &data(2 * BLKSIZE, 2))?;
+    }
+    check_clean(&lfs)?;
+    let file_id = lfs.root_inode().lookup("dir/file")?.metadata()?.inode;
+    let block = |i: usize| -> BlockId {
+        let file = lfs.find_inode(file_id).unwrap();
+        file.get_disk_block_id(i).unwrap()
+    };
+    let set_entry = |blk_id: BlockId, entry_id: isize, inode_id: isize| {
+        let segments = lfs.segments.read();
+        let seg = &segments[&(blk_id / lfs.geometry.segment_blks())];
+        seg.summary_map.write().insert(
+            blk_id,
+            SummaryEntry {
+                entry_id: entry_id as i32,
+                inode_id: inode_id as i32,
+            },
+        );
+    };
+    let expect = |found: &dyn Fn(&Problem) -> bool| -> Result<()> {
+        let report = fsck::check(&lfs, false)?;
+        assert!(report.problems.iter().any(found), "{:?}", report.problems);
+        assert!(!report.repaired);
+        let report = fsck::check(&lfs, true)?;
+        assert!(report.repaired, "{:?}", report.problems);
+        check_clean(&lfs)
+    };
+
+    // a block in use told free
+    let b = block(0);
+    set_entry(b, ENTRY_GARBAGE, INVALID_INO);
+    expect(&|p| *p == Problem::UsedBlockFree(b))?;
+    // or told as a block of another inode
+    let b = block(1);
+    set_entry(b, 1, INO_ROOT as isize);
+    expect(&|p| *p == Problem::WrongSummary(b))?;
+    // a dead one told live
+    let dead = {
+        let segments = lfs.segments.read();
+        let dead = segments.values().find_map(|seg| {
+            let summary = seg.summary_map.read();
+            summary
+                .iter()
+                .find(|(_, entry)| entry.entry_id == ENTRY_GARBAGE as i32)
+                .map(|(&blk_id, _)| blk_id)
+        });
+        dead.expect("no dead block")
+    };
+    set_entry(dead, 100, file_id as isize);
+    expect(&|p| *p == Problem::DanglingBlock(dead))?;
+    // counts of the segments and the super block
+    let seg_id = block(0) / lfs.geometry.segment_blks();
+    lfs.segments
+        .write()
+        .get_mut(&seg_id)
+        .unwrap()
+        .meta
+        .live_bytes += BLKSIZE as u32;
+    expect(&|p| match p {
+        Problem::LiveBytes { segment, .. } => *segment == seg_id,
+        _ => false,
+    })?;
+    lfs.super_block.write().unused_blocks += 1;
+    expect(&|p| match p {
+        Problem::UnusedBlocks { recorded, actual } => *recorded == *actual + 1,
+        _ => false,
+    })?;
+    // a link count
+    lfs.find_inode(file_id).unwrap().disk_inode.write().nlinks = 5;
+    expect(&|p| {
+        *p == Problem::LinkCount {
+            inode: file_id,
+            recorded: 5,
+            actual: 2,
+        }
+    })?;
+    // an inode of the imap in no dir
+    let orphan = {
+        let mut cr = lfs.check_region.write();
+        cr.inodes_num += 1;
+        cr.inodes_num as INodeId - 1
+    };
+    lfs.imap_set(orphan, lfs.imap_get(file_id).unwrap());
+    expect(&|p| *p == Problem::OrphanINode(orphan))?;
+    assert_eq!(lfs.imap_get(orphan), None);
+
+    // an imap lost can not be repaired, but made again
+    let locations = lfs.imap.read().locations().to_vec();
+    // in the metadata of a segment
+    let header =
+        |&blk_id: &BlockId| blk_id / lfs.geometry.segment_blks() * lfs.geometry.segment_blks();
+    *lfs.imap.write() = IMap::new(locations.iter().map(header).collect());
+    let report = fsck::check(&lfs, true)?;
+    assert!(!report.repaired);
+    assert!(report
+        .problems
+        .iter()
+        .any(|p| matches!(p, Problem::BadIMapBlock(_))));
+    // root, dir and file
+    assert_eq!(fsck::rebuild_imap(&lfs)?, 3);
+    fsck::check(&lfs, true)?;
+    check_clean(&lfs)?;
+    drop(lfs);
+
+    let lfs = reopen(&image);
+    let root = lfs.root_inode();
+    let mut content = data(20 * BLKSIZE, 1);
+    content[..2 * BLKSIZE].copy_from_slice(&data(2 * BLKSIZE, 2));
+    assert_eq!(read_all(&root.lookup("dir/file")?)?, content);
+    assert_eq!(read_all(&root.find("link")?)?, content);
+    assert_eq!(root.find("link")?.metadata()?.nlinks, 2);
+    drop(root);
+    check_clean(&lfs)
+}
+
+#[test]
+fn segment_checksums() -> Result<()> {
+    let (lfs, image) = small_lfs();
+    let geometry = lfs.geometry;
+    let root = lfs.root_inode();
+    root.create("a", FileType::File, 0o644)?
+        .write_at(0, &data(10 * BLKSIZE, 1))?;
+    lfs.checkpoint()?;
+    let first = lfs.super_block.read().current_seg_id as usize;
+    root.create("b", FileType::File, 0o644)?
+        .write_at(0, &data(5 * BLKSIZE, 2))?;
+    lfs.sync()?;
+    // so that the log goes on in the next segment, synced twice, the checksum of its
+    // data blocks extended by those written since
+    let size = lfs.segments.read()[&first].meta.size as usize;
+    let len = geometry.segment_size() - size + 10 * BLKSIZE;
+    let c = root.create("c", FileType::File, 0o644)?;
+    c.write_at(0, &data(len, 3))?;
+    lfs.sync()?;
+    c.write_at(len, &data(5 * BLKSIZE, 4))?;
+    lfs.sync()?;
+    let last = lfs.super_block.read().current_seg_id as usize;
+    assert_ne!(first, last);
+    assert_eq!(
+        lfs.check_region.read().seg_seq,
+        lfs.segments.read()[&first].meta.seq
+    );
+
+    // as on disk
+    let data_checksum = |device: &Arc<dyn Device>, seg_id: SegmentId, size: u32| -> Result<u32> {
+        let begin = geometry.header_size();
+        let mut buf = vec![0u8; size as usize - begin];
+        device.read_at(seg_id * geometry.segment_size() + begin, &mut buf)?;
+        Ok(crc32c(&buf))
+    };
+    let device: Arc<dyn Device> = Arc::new(Mutex::new(image.try_clone().unwrap()));
+    for &seg_id in [first, last].iter() {
+        let (segment, valid) = LogFileSystem::load_segment(&device, &geometry, seg_id)?;
+        assert!(valid, "segment {}", seg_id);
+        let size = segment.meta.size;
+        assert_eq!(
+            data_checksum(&device, seg_id, size)?,
+            segment.meta.data_checksum
+        );
+    }
+    // a summary torn
+    let torn = crash_image(&image);
+    let device: Arc<dyn Device> = Arc::new(Mutex::new(torn.try_clone().unwrap()));
+    let offset = last * geometry.segment_size() + geometry.summary_offset();
+    let entry = geometry.data_begin() * mem::size_of::<SummaryEntry>();
+    write_image(&torn, offset + entry, &[0x5a; 8]);
+    assert!(!LogFileSystem::load_segment(&device, &geometry, last)?.1);
+
+    // the last data block torn: the segment before is rolled forward, but not that one
+    let torn = crash_image(&image);
+    let size = lfs.segments.read()[&last].meta.size as usize;
+    write_image(
+        &torn,
+        last * geometry.segment_size() + size - BLKSIZE,
+        &[0xff; BLKSIZE],
+    );
+    let device: Arc<dyn Device> = Arc::new(Mutex::new(torn.try_clone().unwrap()));
+    let (segment, valid) = LogFileSystem::load_segment(&device, &geometry, last)?;
+    assert!(valid);
+    let size = segment.meta.size;
+    assert_ne!(
+        data_checksum(&device, last, size)?,
+        segment.meta.data_checksum
+    );
+    drop(device);
+    let recovered = reopen(&torn);
+    let dir = recovered.root_inode();
+    assert_eq!(names(&dir)?, vec![".", "..", "a", "b"]);
+    assert_eq!(read_all(&dir.find("a")?)?, data(10 * BLKSIZE, 1));
+    assert_eq!(read_all(&dir.find("b")?)?, data(5 * BLKSIZE, 2));
+    drop(dir);
+    check_clean(&recovered)?;
+    drop(recovered);
+    // and cut there for good
+    let recovered = reopen(&torn);
+    assert_eq!(names(&recovered.root_inode())?, vec![".", "..", "a", "b"]);
+    check_clean(&recovered)?;
+
+    drop((root, c));
+    check_clean(&lfs)
+}
+
+#[test]
+fn hard_links_and_renames() -> Result<()> {
+    let (lfs, image) = small_lfs();
+    lfs.set_cleaner_policy(CleanerPolicy {
+        low: 0,
+        high: 0,
+        background: false,
+    });
+    let root = lfs.root_inode();
+    let file = root.create("f", FileType::File, 0o644)?;
+    file.write_at(0, &data(20 * BLKSIZE, 1))?;
+    let id = file.metadata()?.inode;
+    let dir = root.create("d", FileType::Dir, 0o755)?;
+    lfs.checkpoint()?;
+    let seq = lfs.check_region.read().seq;
+    let nlinks = |inode: &Arc<dyn INode>| inode.metadata().map(|m| m.nlinks);
+
+    dir.link("g", &file)?;
+    assert_eq!(nlinks(&file)?, 2);
+    // moved to the dir with the other link
+    root.move_("f", &dir, "h")?;
+    assert_eq!(names(&dir)?, vec![".", "..", "g", "h"]);
+    assert_eq!(nlinks(&file)?, 2);
+    // over another link of it, which leaves both
+    dir.move_("g", &dir, "h")?;
+    assert_eq!(names(&dir)?, vec![".", "..", "g", "h"]);
+    assert_eq!(nlinks(&file)?, 2);
+    root.link("f2", &dir.find("h")?)?;
+    assert_eq!(nlinks(&file)?, 3);
+    // over another file, which is freed
+    let victim = root.create("victim", FileType::File, 0o644)?;
+    victim.write_at(0, &data(4 * BLKSIZE, 2))?;
+    dir.move_("g", &root, "victim")?;
+    assert_eq!(nlinks(&victim)?, 0);
+    assert_eq!(root.link("again", &victim), Err(FsError::EntryNotFound));
+    drop(victim);
+    assert_eq!(nlinks(&file)?, 3);
+    dir.unlink("h")?;
+    assert_eq!(nlinks(&file)?, 2);
+    assert_eq!(names(&root)?, vec![".", "..", "d", "f2", "victim"]);
+    assert_eq!(names(&dir)?, vec![".", ".."]);
+    // the same content through each of them
+    root.find("victim")?.write_at(0, &data(BLKSIZE, 3))?;
+    let mut content = data(20 * BLKSIZE, 1);
+    content[..BLKSIZE].copy_from_slice(&data(BLKSIZE, 3));
+    assert_eq!(read_all(&root.find("f2")?)?, content);
+    assert!(root.find("f2")?.is_same(&*root.find("victim")?)?);
+    // a dir is not linked, nor are links counted past what they are kept in
+    assert_eq!(root.link("d2", &dir).err(), Some(FsError::IsDir));
+    let max = u16::max_value();
+    file.downcast_ref::<INodeImpl>()
+        .unwrap()
+        .disk_inode
+        .write()
+        .nlinks = max;
+    assert_eq!(root.link("more", &file), Err(FsError::InvalidParam));
+    file.downcast_ref::<INodeImpl>()
+        .unwrap()
+        .disk_inode
+        .write()
+        .nlinks = 2;
+    lfs.sync()?;
+    // redone from the records when rolled forward
+    assert_eq!(lfs.check_region.read().seq, seq);
+    let crashed = crash_image(&image);
+
+    let check = |lfs: &LogFileSystem, content: &[u8]| -> Result<()> {
+        let root = lfs.root_inode();
+        assert_eq!(names(&root)?, vec![".", "..", "d", "f2", "victim"]);
+        assert_eq!(names(&root.find("d")?)?, vec![".", ".."]);
+        let (f2, victim) = (root.find("f2")?, root.find("victim")?);
+        assert!(f2.is_same(&*victim)?);
+        assert_eq!(nlinks(&f2)?, 2);
+        assert_eq!(read_all(&f2)?, content);
+        drop((root, f2, victim));
+        check_clean(lfs)
+    };
+    let recovered = reopen(&crashed);
+    check(&recovered, &content)?;
+    drop(recovered);
+
+    // the blocks of an inode of several links are moved once, for all of them
+    for i in 0..6 {
+        root.find("f2")?
+            .write_at(BLKSIZE, &data(10 * BLKSIZE, 4 + i))?;
+        lfs.sync()?;
+    }
+    content[BLKSIZE..11 * BLKSIZE].copy_from_slice(&data(10 * BLKSIZE, 9));
+    let moved = lfs.stats().blocks_moved;
+    assert!(lfs.clean(64)? > 0);
+    assert!(lfs.stats().blocks_moved > moved);
+    drop((root, file, dir));
+    check(&lfs, &content)?;
+    drop(lfs);
+    let lfs = reopen(&image);
+    check(&lfs, &content)?;
+
+    // the last link removed frees it
+    let root = lfs.root_inode();
+    root.unlink("f2")?;
+    assert_eq!(nlinks(&root.find("victim")?)?, 1);
+    root.unlink("victim")?;
+    drop(root);
+    lfs.sync()?;
+    assert_eq!(lfs.imap_get(id), None);
+    check_clean(&lfs)
+}
+
+#[test]
+fn cleaner_watermarks() -> Result<()> {
+    let (lfs, image) = small_lfs();
+    assert_eq!(
+        lfs.cleaner_policy(),
+        CleanerPolicy {
+            low: CLEAN_LOW,
+            high: CLEAN_HIGH,
+            background: false,
+        }
+    );
+    let overwrite = |lfs: &LogFileSystem, rounds: usize| -> Result<()> {
+        let file = lfs.root_inode().find("file")?;
+        for i in 0..rounds {
+            file.write_at(0, &data(30 * BLKSIZE, i))?;
+            lfs.sync()?;
+        }
+        Ok(())
+    };
+    lfs.root_inode().create("file", FileType::File, 0o644)?;
+    assert!(!lfs.needs_cleaning());
+    assert_eq!(lfs.run_cleaner_once()?, 0);
+
+    // left to the background: the writes take the free segments below the low watermark
+    let policy = CleanerPolicy {
+        low: 6,
+        high: 12,
+        background: true,
+    };
+    lfs.set_cleaner_policy(policy);
+    while !lfs.needs_cleaning() {
+        overwrite(&lfs, 1)?;
+    }
+    overwrite(&lfs, 4)?;
+    assert!(lfs.free_segments() < policy.low);
+    assert_eq!(lfs.stats().blocks_moved, 0);
+    // until it is polled, which cleans up to the high one
+    assert!(lfs.run_cleaner_once()? > 0);
+    assert!(lfs.free_segments() >= policy.high);
+    assert!(!lfs.needs_cleaning());
+    assert_eq!(lfs.run_cleaner_once()?, 0);
+
+    // cleaned by the writes, which the log never fails for room, twice what it holds
+    lfs.set_cleaner_policy(CleanerPolicy::default());
+    let rounds = 2 * lfs.segments.read().len() * lfs.geometry.data_blks() / 30;
+    overwrite(&lfs, rounds)?;
+    assert!(lfs.free_segments() >= CLEAN_LOW - 1);
+    assert_eq!(
+        read_all(&lfs.root_inode().find("file")?)?,
+        data(30 * BLKSIZE, rounds - 1)
+    );
+    check_clean(&lfs)?;
+    drop(lfs);
+
+    // which is not kept on disk
+    let lfs = reopen(&image);
+    assert_eq!(lfs.cleaner_policy(), CleanerPolicy::default());
+    check_clean(&lfs)
+}
+
+#[test]
+fn log_stats() -> Result<()> {
+    let (lfs, image) = small_lfs();
+    lfs.set_cleaner_policy(CleanerPolicy {
+        low: 0,
+        high: 0,
+        background: false,
+    });
+    let stats = lfs.stats();
+    assert_eq!(stats.total_segments, lfs.segments.read().len());
+    assert_eq!(
+        stats.free_segments + stats.segments.len(),
+        stats.total_segments
+    );
+    assert_eq!(stats.segment_data_bytes, lfs.geometry.data_blks() * BLKSIZE);
+    assert_eq!(stats.data_bytes_written, 0);
+    assert_eq!(stats.write_amplification(), None);
+    assert_eq!(stats.checkpoint_seq, lfs.check_region.read().seq);
+    assert!(stats.to_string().contains("write_amplification -\n"));
+
+    let file = lfs.root_inode().create("file", FileType::File, 0o644)?;
+    file.write_at(0, &data(10 * BLKSIZE, 1))?;
+    lfs.checkpoint()?;
+    let stats = lfs.stats();
+    assert_eq!(stats.data_bytes_written, 10 * BLKSIZE as u64);
+    // with the blocks of the inodes, the dir and the imap
+    let wa = stats.write_amplification().unwrap();
+    assert!(wa > 100 && wa < 200, "{}", wa);
+    assert_eq!(stats.log_bytes_written * 100 / stats.data_bytes_written, wa);
+    assert_eq!(stats.checkpoint_age, 0);
+    assert_eq!(stats.checkpoint_seq, lfs.check_region.read().seq);
+    let seg_seq = lfs.super_block.read().seg_seq;
+    assert_eq!(
+        stats.live_bytes,
+        stats
+            .segments
+            .iter()
+            .map(|usage| usage.live_bytes)
+            .sum::<usize>()
+    );
+    let current = stats.segments.iter().find(|usage| usage.current).unwrap();
+    assert_eq!(
+        stats.utilization(current),
+        current.live_bytes * 100 / stats.segment_data_bytes
+    );
+    assert!(stats.utilization(current) > 0);
+
+    // dead once written again, a segment on
+    let garbage = stats.garbage_bytes;
+    file.write_at(0, &data(10 * BLKSIZE, 2))?;
+    let len = stats.segment_data_bytes;
+    lfs.root_inode()
+        .create("big", FileType::File, 0o644)?
+        .write_at(0, &data(len, 3))?;
+    lfs.sync()?;
+    let stats = lfs.stats();
+    let checkpoint_seq = stats.checkpoint_seq;
+    assert!(stats.garbage_bytes >= garbage + 10 * BLKSIZE);
+    let age = lfs.super_block.read().seg_seq - seg_seq;
+    assert!(age > 0 && age < CHECKPOINT_SEGMENTS);
+    assert_eq!(stats.checkpoint_age, age);
+    assert_eq!(stats.data_bytes_written, (20 * BLKSIZE + len) as u64);
+    assert_eq!(stats.blocks_moved, 0);
+    assert!(lfs.clean(64)? > 0);
+    let stats = lfs.stats();
+    assert!(stats.blocks_moved > 0);
+    assert!(stats.garbage_bytes < garbage + 10 * BLKSIZE);
+    // checkpointed, but for the hot segment started after
+    assert!(stats.checkpoint_seq > checkpoint_seq);
+    assert!(stats.checkpoint_age <= 1);
+
+    // one line for each of the counters, then each segment
+    let text = stats.to_string();
+    assert!(text.starts_with(&format!("total_segments {}\n", stats.total_segments)));
+    assert!(text.contains(&format!("\nfree_segments {}\n", stats.free_segments)));
+    assert!(text.contains(&format!("\nblocks_moved {}\n", stats.blocks_moved)));
+    assert_eq!(
+        text.lines()
+            .filter(|line| line.starts_with("segment "))
+            .count(),
+        stats.segments.len()
+    );
+    assert_eq!(
+        text.lines()
+            .filter(|line| line.ends_with(" current"))
+            .count(),
+        1
+    );
+    drop(file);
+    drop(lfs);
+
+    // counted since it was opened
+    let lfs = reopen(&image);
+    let reopened = lfs.stats();
+    assert_eq!(reopened.data_bytes_written, 0);
+    assert_eq!(reopened.blocks_moved, 0);
+    assert_eq!(reopened.live_bytes, stats.live_bytes);
+    assert_eq!(reopened.free_segments, stats.free_segments);
+    Ok(())
+}
+
+#[test]
+fn hashed_dirs() -> Result<()> {
+    let (lfs, image) = small_lfs();
+    let root = lfs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o755)?;
+    let small = root.create("small", FileType::Dir, 0o755)?;
+    let table = |dir: &Arc<dyn INode>| -> Result<usize> {
+        let id = dir.metadata()?.inode as INodeId;
+        Ok(lfs.find_inode(id).unwrap().disk_inode.read().blocks as usize)
+    };
+    let entries = (0..300).map(|i| format!("entry-{}", i)).collect::<Vec<_>>();
+    for (i, name) in entries.iter().enumerate() {
+        dir.create(name, FileType::File, 0o644)?
+            .write_at(0, name.as_bytes())?;
+        assert_eq!(dir.metadata()?.size, (i + 3) * DIRENT_SIZE);
+    }
+    // grown to keep a quarter of the slots free
+    let blocks = table(&dir)?;
+    assert!(blocks * DIRENTS_PER_BLOCK * 3 >= 302 * 4, "{}", blocks);
+    assert!(blocks > 302 / DIRENTS_PER_BLOCK);
+    let mut expected = entries.clone();
+    expected.push(String::from("."));
+    expected.push(String::from(".."));
+    expected.sort();
+    assert_eq!(names(&dir)?, expected);
+    for name in &entries {
+        let file = dir.find(name)?;
+        assert_eq!(read_all(&file)?, name.as_bytes());
+    }
+
+    // the rest is still found past the slots removed, which are taken again
+    for name in entries.iter().step_by(2) {
+        dir.unlink(name)?;
+    }
+    assert_eq!(dir.metadata()?.size, (150 + 2) * DIRENT_SIZE);
+    for (i, name) in entries.iter().enumerate() {
+        match dir.find(name) {
+            Ok(file) if i % 2 == 1 => assert_eq!(read_all(&file)?, name.as_bytes()),
+            Err(FsError::EntryNotFound) if i % 2 == 0 => {}
+            res => panic!("{}: {:?}", name, res.map(|_| ())),
+        }
+    }
+    for name in entries.iter().step_by(2) {
+        dir.create(name, FileType::File, 0o644)?
+            .write_at(0, name.as_bytes())?;
+    }
+    assert_eq!(table(&dir)?, blocks);
+    assert_eq!(names(&dir)?, expected);
+
+    // an entry made or removed costs a block of the table, however large it is
+    let allocated = |f: &dyn Fn() -> Result<()>| -> Result<u64> {
+        lfs.sync()?;
+        let before = lfs.snapshot_stats().blocks_allocated;
+        f()?;
+        lfs.sync()?;
+        Ok(lfs.snapshot_stats().blocks_allocated - before)
+    };
+    let in_small = allocated(&|| small.create("new", FileType::File, 0o644).map(|_| ()))?;
+    let in_large = allocated(&|| dir.create("new", FileType::File, 0o644).map(|_| ()))?;
+    assert!(in_large <= in_small + 1, "{} {}", in_large, in_small);
+    let in_small = allocated(&|| small.unlink("new"))?;
+    let in_large = allocated(&|| dir.unlink("new"))?;
+    assert!(in_large <= in_small + 1, "{} {}", in_large, in_small);
+    drop(dir);
+    drop(small);
+    drop(root);
+    drop(lfs);
+
+    let lfs = reopen(&image);
+    let dir = lfs.root_inode().find("dir")?;
+    assert_eq!(names(&dir)?, expected);
+    assert_eq!(dir.metadata()?.size, (300 + 2) * DIRENT_SIZE);
+    drop(dir);
+    check_clean(&lfs)
+}
+
+#[test]
+fn trim_cleaned_segments() -> Result<()> {
+    let geometry = Geometry::new(64);
+    let size = 64 * geometry.segment_size();
+    let device = MemDevice::new(size);
+    let lfs = LogFileSystem::create_with_geometry(device.clone(), size, geometry, &CLOCK)?;
+    lfs.set_cleaner_policy(CleanerPolicy {
+        low: 0,
+        high: 0,
+        background: false,
+    });
+    let trims = || core::mem::replace(&mut *device.trims.lock().unwrap(), Vec::new());
+    let segment_size = geometry.segment_size();
+    let len = 3 * geometry.data_blks() * BLKSIZE;
+    let file = lfs.root_inode().create("file", FileType::File, 0o644)?;
+    file.write_at(0, &data(len, 1))?;
+    lfs.sync()?;
+    // all dead once written again
+    file.write_at(0, &data(len, 2))?;
+    lfs.sync()?;
+    assert!(trims().is_empty());
+
+    // discarded but for the header, which tells nothing
+    let cleaned = lfs.clean(64)?;
+    assert!(cleaned >= 3, "{}", cleaned);
+    let trimmed = trims();
+    assert_eq!(trimmed.len(), cleaned);
+    let dev: Arc<dyn Device> = device.clone();
+    for range in &trimmed {
+        let seg_id = range.start / segment_size;
+        assert!(seg_id > 0);
+        assert_eq!(
+            *range,
+            seg_id * segment_size + geometry.header_size()..(seg_id + 1) * segment_size
+        );
+        // but for the hot one started in it after
+        if lfs.segments.read()[&seg_id].meta.unused == 0 {
+            assert_eq!(seg_id, lfs.super_block.read().current_seg_id as usize);
+            continue;
+        }
+        let (segment, valid) = LogFileSystem::load_segment(&dev, &geometry, seg_id)?;
+        assert!(valid, "segment {}", seg_id);
+        assert_eq!(segment.meta.unused, 1);
+        assert_eq!(segment.meta.live_bytes, 0);
+        assert!(segment.seg_imap.read().is_empty());
+        assert!(segment.summary_map.read().is_empty());
+        assert!(segment.dir_log.read().records.is_empty());
+    }
+    assert_eq!(read_all(&file)?, data(len, 2));
+
+    // and those freed by compact
+    file.write_at(0, &data(len, 3))?;
+    lfs.sync()?;
+    drop(file);
+    lfs.compact()?;
+    let mut free = 0;
+    for range in &trims() {
+        let seg_id = range.start / segment_size;
+        assert_eq!(range.start % segment_size, geometry.header_size());
+        assert_eq!(range.end, (seg_id + 1) * segment_size);
+        // those free still, or cut off
+        match lfs.segments.read().get(&seg_id) {
+            Some(segment) if segment.meta.unused == 0 => continue,
+            _ => {}
+        }
+        let (segment, _) = LogFileSystem::load_segment(&dev, &geometry, seg_id)?;
+        assert!(segment.summary_map.read().is_empty());
+        free += 1;
+    }
+    assert!(free > 0);
+    drop(lfs);
+
+    // nothing rolled forward from the stale summaries
+    let lfs = LogFileSystem::open(device.clone())?;
+    assert_eq!(read_all(&lfs.root_inode().find("file")?)?, data(len, 3));
+    check_clean(&lfs)
+}
+
+/// The blocks of file `inode` about the ends of its direct ones and of its indirect tree,
+/// as in `content`
+fn check_indirect(inode: &INodeImpl, content: &[u8]) -> Result<()> {
+    let blocks = content.len() / BLKSIZE;
+    for &i in [
+        MAX_NBLOCK_DIRECT - 1,
+        MAX_NBLOCK_DIRECT,
+        MAX_NBLOCK_INDIRECT - 1,
+        MAX_NBLOCK_INDIRECT,
+        blocks - 1,
+    ]
+    .iter()
+    {
+        let mut buf = [0u8; BLKSIZE];
+        inode
+            .fs
+            .device
+            .read_block(inode.get_disk_block_id(i)?, 0, &mut buf)?;
+        assert_eq!(&buf[..], &content[i * BLKSIZE..(i + 1) * BLKSIZE]);
+    }
+    Ok(())
+}
+
+#[test]
+fn large_files() -> Result<()> {
+    // past 32 bits, told by a triple indirect tree at the end
+    assert!(MAX_FILE_SIZE > u32::max_value() as u64);
+    let root = Node::root(MAX_NBLOCK_DOUBLE_INDIRECT).unwrap();
+    assert_eq!((root.height, root.first), (3, MAX_NBLOCK_DOUBLE_INDIRECT));
+    assert_eq!(Node::root(MAX_NBLOCK_TRIPLE_INDIRECT - 1), Some(root));
+    assert_eq!(Node::root(MAX_NBLOCK_TRIPLE_INDIRECT), None);
+    let node = Node::above(MAX_NBLOCK_TRIPLE_INDIRECT - 1, 1);
+    assert_eq!(Node::from_entry(node.entry() as i32), Some(node));
+
+    let (lfs, image) = small_lfs();
+    let file = lfs.root_inode().create("file", FileType::File, 0o644)?;
+    #[cfg(target_pointer_width = "64")]
+    assert!(file.resize(MAX_FILE_SIZE as usize + 1).is_err());
+    // the blocks about the ends of the direct ones and of the indirect tree
+    let blocks = MAX_NBLOCK_INDIRECT + BLK_NENTRY + 2;
+    let content = data(blocks * BLKSIZE, 1);
+    file.write_at(0, &content)?;
+    let id = file.metadata()?.inode as INodeId;
+    let inode = lfs.find_inode(id).unwrap();
+    {
+        let disk_inode = inode.disk_inode.read();
+        assert_eq!(disk_inode.blocks, blocks as u64);
+        assert_ne!(disk_inode.indirect, 0);
+        assert_ne!(disk_inode.db_indirect, 0);
+        assert_eq!(disk_inode.tr_indirect, 0);
+    }
+    check_indirect(&inode, &content)?;
+    // a block in the double indirect tree written again
+    let i = MAX_NBLOCK_INDIRECT + BLK_NENTRY;
+    let block = data(BLKSIZE, 2);
+    lfs.sync()?;
+    file.write_at(i * BLKSIZE, &block)?;
+    let mut content = content;
+    content[i * BLKSIZE..(i + 1) * BLKSIZE].copy_from_slice(&block);
+    check_indirect(&inode, &content)?;
+    assert_eq!(read_all(&file)?, content);
+
+    // a size past 32 bits is kept as it is
+    let len = (1usize << 32) + 5;
+    inode.disk_inode.write().size = len as u64;
+    assert_eq!(file.metadata()?.size, len);
+    lfs.sync()?;
+    drop(inode);
+    drop(file);
+    drop(lfs);
+    let lfs = reopen(&image);
+    let file = lfs.root_inode().find("file")?;
+    assert_eq!(file.metadata()?.size, len);
+    file.resize(content.len())?;
+
+    // and the indirect blocks moved by the cleaner
+    lfs.sync()?;
+    lfs.clean(64)?;
+    let inode = lfs.find_inode(file.metadata()?.inode as INodeId).unwrap();
+    check_indirect(&inode, &content)?;
+    assert_eq!(read_all(&file)?, content);
+    drop(inode);
+    drop(file);
+    check_clean(&lfs)?;
+    drop(lfs);
+    let lfs = reopen(&image);
+    assert_eq!(read_all(&lfs.root_inode().find("file")?)?, content);
+    Ok(())
+}
+
+#[test]
+fn write_reservations() -> Result<()> {
+    // the block added and its copy, a block of inodes and one of the imap
+    assert_eq!(write_blocks(0, 0, BLKSIZE), 4);
+    let len = (MAX_NBLOCK_INDIRECT + 10) * BLKSIZE;
+    assert!(write_blocks(0, 0, len) >= 2 * (MAX_NBLOCK_INDIRECT + 10) + 2);
+    assert!(write_blocks(10, 0, BLKSIZE) < write_blocks(0, 0, BLKSIZE));
+
+    let (lfs, image) = small_lfs();
+    lfs.set_cleaner_policy(CleanerPolicy {
+        low: 0,
+        high: 0,
+        background: false,
+    });
+    let free = lfs.free_blocks();
+    let data_blks = lfs.geometry.data_blks();
+    assert!(free < lfs.segments.read().len() * data_blks - CLEAN_HEADROOM * data_blks);
+    assert_eq!(lfs.info().bavail, free);
+    // counted until dropped, and beyond the blocks free refused
+    let reservation = lfs.reserve(free / 2)?;
+    assert_eq!(lfs.free_blocks(), free - free / 2);
+    assert_eq!(lfs.info().bavail, free - free / 2);
+    match lfs.reserve(free - free / 2 + 1) {
+        Err(FsError::NoDeviceSpace) => {}
+        res => panic!("{:?}", res.map(|_| ())),
+    }
+    let file = lfs.root_inode().create("file", FileType::File, 0o644)?;
+    let content = data((free - free / 2) * BLKSIZE, 1);
+    match file.write_at(0, &content) {
+        Err(FsError::NoDeviceSpace) => {}
+        res => panic!("{:?}", res),
+    }
+    drop(reservation);
+    assert_eq!(lfs.free_blocks(), free);
+
+    // failed before anything was taken
+    let allocated = lfs.snapshot_stats().blocks_allocated;
+    match file.write_at(0, &data((free + 1) * BLKSIZE, 1)) {
+        Err(FsError::NoDeviceSpace) => {}
+        res => panic!("{:?}", res),
+    }
+    match file.resize((free + 1) * BLKSIZE) {
+        Err(FsError::NoDeviceSpace) => {}
+        res => panic!("{:?}", res),
+    }
+    assert_eq!(lfs.snapshot_stats().blocks_allocated, allocated);
+    assert_eq!(file.metadata()?.size, 0);
+    drop(file);
+    check_clean(&lfs)?;
+
+    // filled up, a file at a time: the one failing is left as it was
+    let chunk = data(16 * BLKSIZE, 2);
+    let mut files = Vec::new();
+    let root = lfs.root_inode();
+    loop {
+        let name = format!("file{}", files.len());
+        let file = match root.create(&name, FileType::File, 0o644) {
+            Ok(file) => file,
+            Err(FsError::NoDeviceSpace) => break,
+            Err(e) => return Err(e),
+        };
+        match file.write_at(0, &chunk) {
+            Ok(len) => assert_eq!(len, chunk.len()),
+            Err(FsError::NoDeviceSpace) => {
+                assert_eq!(file.metadata()?.size, 0);
+                root.unlink(&name)?;
+                break;
+            }
+            Err(e) => return Err(e),
+        }
+        files.push(name);
+    }
+    assert!(files.len() > 100, "{}", files.len());
+    match root.create("another", FileType::Dir, 0o755) {
+        Err(FsError::NoDeviceSpace) => {}
+        res => panic!("{:?}", res.map(|_| ())),
+    }
+    // but it can still be emptied, and written again
+    for name in files.drain(..files.len() / 2) {
+        root.unlink(&name)?;
+    }
+    assert!(lfs.free_blocks() > 32 * files.len() / 2);
+    root.create("again", FileType::File, 0o644)?
+        .write_at(0, &chunk)?;
+    drop(root);
+    lfs.sync()?;
+    drop(lfs);
+
+    let lfs = reopen(&image);
+    let root = lfs.root_inode();
+    for name in files.iter().chain(Some(&String::from("again"))) {
+        assert_eq!(read_all(&root.find(name)?)?, chunk);
+    }
+    drop(root);
+    check_clean(&lfs)
+}
+
+/// The inode and entry summary entry of block `blk_id` tells, if any
+fn summary_of(lfs: &LogFileSystem, blk_id: BlockId) -> Option<(i32, i32)> {
+    let seg_id = blk_id / lfs.geometry.segment_blks();
+    let segments = lfs.segments.read();
+    let summary = segments[&seg_id].summary_map.read();
+    summary
+        .get(&blk_id)
+        .map(|entry| (entry.inode_id, entry.entry_id))
+}
+
+#[test]
+fn xattrs() -> Result<()> {
+    let (lfs, image) = small_lfs();
+    lfs.set_cleaner_policy(CleanerPolicy {
+        low: 0,
+        high: 0,
+        background: false,
+    });
+    let root = lfs.root_inode();
+    let file = root.create("file", FileType::File, 0o644)?;
+    file.write_at(0, &data(3 * BLKSIZE, 1))?;
+    let id = file.metadata()?.inode as INodeId;
+    let inode = lfs.find_inode(id).unwrap();
+    let xattr_block = || inode.disk_inode.read().xattr as BlockId;
+    assert_eq!(file.list_xattr()?, Vec::<String>::new());
+    assert_eq!(xattr_block(), INVALID_BLKID);
+    match file.get_xattr("user.a") {
+        Err(FsError::EntryNotFound) => {}
+        res => panic!("{:?}", res),
+    }
+    match file.remove_xattr("user.a") {
+        Err(FsError::EntryNotFound) => {}
+        res => panic!("{:?}", res),
+    }
+    match file.set_xattr("", b"x") {
+        Err(FsError::InvalidParam) => {}
+        res => panic!("{:?}", res),
+    }
+
+    // kept in a block of the inode, written anew at each change
+    file.set_xattr("user.a", b"one")?;
+    file.set_xattr("user.b", b"")?;
+    file.set_xattr("user.c", &[7; 100])?;
+    let block = xattr_block();
+    assert_ne!(block, INVALID_BLKID);
+    assert_eq!(
+        summary_of(&lfs, block),
+        Some((id as i32, ENTRY_XATTR as i32))
+    );
+    file.set_xattr("user.a", b"two")?;
+    assert_ne!(xattr_block(), block);
+    assert_eq!(
+        summary_of(&lfs, block),
+        Some((INVALID_INO as i32, ENTRY_GARBAGE as i32))
+    );
+    assert_eq!(file.get_xattr("user.a")?, b"two");
+    assert_eq!(file.get_xattr("user.b")?, b"");
+    assert_eq!(file.get_xattr("user.c")?, vec![7; 100]);
+    file.remove_xattr("user.b")?;
+    assert_eq!(file.list_xattr()?, vec!["user.a", "user.c"]);
+    // as many as fit in the block
+    match file.set_xattr("user.d", &[1; BLKSIZE]) {
+        Err(FsError::NoDeviceSpace) => {}
+        res => panic!("{:?}", res),
+    }
+    assert_eq!(file.list_xattr()?, vec!["user.a", "user.c"]);
+    let dir = root.create("dir", FileType::Dir, 0o755)?;
+    dir.set_xattr("user.dir", b"yes")?;
+    lfs.sync()?;
+
+    // moved by the cleaner as the content
+    file.write_at(0, &data(3 * BLKSIZE, 2))?;
+    let len = lfs.geometry.data_blks() * BLKSIZE;
+    root.create("big", FileType::File, 0o644)?
+        .write_at(0, &data(2 * len, 3))?;
+    root.unlink("big")?;
+    lfs.sync()?;
+    let block = xattr_block();
+    let seg_id = block / lfs.geometry.segment_blks();
+    assert_eq!(lfs.clean_cold(|| vec![seg_id])?, 1);
+    assert_ne!(xattr_block(), block);
+    assert_eq!(
+        summary_of(&lfs, xattr_block()),
+        Some((id as i32, ENTRY_XATTR as i32))
+    );
+    assert_eq!(file.get_xattr("user.c")?, vec![7; 100]);
+    drop(inode);
+    drop(file);
+    drop(dir);
+    drop(root);
+    check_clean(&lfs)?;
+    drop(lfs);
+
+    let lfs = reopen(&image);
+    let root = lfs.root_inode();
+    let file = root.find("file")?;
+    assert_eq!(file.list_xattr()?, vec!["user.a", "user.c"]);
+    assert_eq!(file.get_xattr("user.a")?, b"two");
+    assert_eq!(root.find("dir")?.get_xattr("user.dir")?, b"yes");
+    // none left, no block
+    let inode = lfs.find_inode(file.metadata()?.inode as INodeId).unwrap();
+    file.remove_xattr("user.a")?;
+    file.remove_xattr("user.c")?;
+    assert_eq!(inode.disk_inode.read().xattr as BlockId, INVALID_BLKID);
+    // and freed with the inode
+    let dir = root.find("dir")?;
+    let block = lfs
+        .find_inode(dir.metadata()?.inode as INodeId)
+        .unwrap()
+        .disk_inode
+        .read()
+        .xattr as BlockId;
+    drop(dir);
+    root.unlink("dir")?;
+    assert_eq!(
+        summary_of(&lfs, block),
+        Some((INVALID_INO as i32, ENTRY_GARBAGE as i32))
+    );
+    drop(inode);
+    drop(file);
+    drop(root);
+    check_clean(&lfs)
+}
+
+#[test]
+fn read_only_mounts() -> Result<()> {
+    let (lfs, image) = small_lfs();
+    let root = lfs.root_inode();
+    let file = root.create("file", FileType::File, 0o644)?;
+    file.write_at(0, &data(5 * BLKSIZE, 1))?;
+    file.set_xattr("user.a", b"one")?;
+    root.create("dir", FileType::Dir, 0o755)?;
+    lfs.checkpoint()?;
+    // to be rolled forward
+    root.create("late", FileType::File, 0o644)?
+        .write_at(0, &data(BLKSIZE, 2))?;
+    lfs.sync()?;
+    let crashed = crash_image(&image);
+    drop(file);
+    drop(root);
+    drop(lfs);
+    let bytes = |image: &File| -> Vec<u8> {
+        let mut file = image.try_clone().unwrap();
+        let mut bytes = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        io::copy(&mut file, &mut bytes).unwrap();
+        bytes
+    };
+    let before = bytes(&crashed);
+
+    let device = Arc::new(Mutex::new(crashed.try_clone().unwrap()));
+    let lfs = LogFileSystem::open_readonly(device)?;
+    assert!(lfs.is_readonly());
+    let root = lfs.root_inode();
+    let file = root.find("file")?;
+    let dir = root.find("dir")?;
+    assert_eq!(read_all(&file)?, data(5 * BLKSIZE, 1));
+    assert_eq!(read_all(&root.find("late")?)?, data(BLKSIZE, 2));
+    assert_eq!(file.get_xattr("user.a")?, b"one");
+    assert_eq!(file.list_xattr()?, vec!["user.a"]);
+    assert_eq!(names(&root)?, vec![".", "..", "dir", "file", "late"]);
+    let refused: Vec<(&str, Result<()>)> = vec![
+        ("write_at", file.write_at(0, b"x").map(|_| ())),
+        ("resize", file.resize(0)),
+        ("set_metadata", file.set_metadata(&file.metadata()?)),
+        ("set_xattr", file.set_xattr("user.b", b"two")),
+        ("remove_xattr", file.remove_xattr("user.a")),
+        (
+            "create",
+            root.create("new", FileType::File, 0o644).map(|_| ()),
+        ),
+        ("link", dir.link("file", &file)),
+        ("unlink", root.unlink("late")),
+        ("move", root.move_("file", &dir, "file")),
+        ("clean", lfs.clean(64).map(|_| ())),
+        ("compact", lfs.compact().map(|_| ())),
+        ("pin_checkpoint", lfs.pin_checkpoint(0)),
+        ("fsck", fsck::check(&lfs, true).map(|_| ())),
+    ];
+    for (op, res) in refused {
+        match res {
+            Err(FsError::ReadOnlyFs) => {}
+            res => panic!("{}: {:?}", op, res),
+        }
+    }
+    // never cleaned by itself
+    assert_eq!(lfs.run_cleaner_once()?, 0);
+    assert_eq!(read_all(&file)?, data(5 * BLKSIZE, 1));
+    drop(file);
+    drop(dir);
+    drop(root);
+    lfs.sync()?;
+    drop(lfs);
+    // the image as it was, rolled forward at the next mount
+    assert!(bytes(&crashed) == before);
+    let lfs = reopen(&crashed);
+    let root = lfs.root_inode();
+    assert_eq!(read_all(&root.find("late")?)?, data(BLKSIZE, 2));
+    assert_eq!(root.find("file")?.get_xattr("user.a")?, b"one");
+    drop(root);
+    check_clean(&lfs)
+}
+
+#[test]
+fn compaction() -> Result<()> {
+    let (lfs, image) = small_lfs();
+    lfs.set_cleaner_policy(CleanerPolicy {
+        low: 0,
+        high: 0,
+        background: false,
+    });
+    let segment_size = lfs.geometry.segment_size();
+    let n_segment = lfs.super_block.read().n_segment as usize;
+    // live blocks spread over the log, between dead ones
+    let root = lfs.root_inode();
+    let len = 20 * BLKSIZE;
+    for i in 0..40 {
+        root.create(&format!("file{}", i), FileType::File, 0o644)?
+            .write_at(0, &data(len, i))?;
+    }
+    let kept = |i: usize| i % 2 == 1 && i > 12;
+    for i in (0..40).filter(|&i| !kept(i)) {
+        root.unlink(&format!("file{}", i))?;
+    }
+    let file = root.find("file13")?;
+    file.write_at(0, &data(len, 100))?;
+    lfs.sync()?;
+    assert!(lfs.used_tail().0 > 10);
+
+    // not while in use
+    match lfs.compact() {
+        Err(FsError::Busy) => {}
+        res => panic!("{:?}", res),
+    }
+    drop(file);
+    drop(root);
+    let id = lfs.checkpoints().last().unwrap().id;
+    lfs.pin_checkpoint(id)?;
+    match lfs.compact() {
+        Err(FsError::Busy) => {}
+        res => panic!("{:?}", res),
+    }
+    lfs.unpin_checkpoint(id)?;
+
+    // packed at the front, with the headroom of the cleaner after
+    let size = lfs.compact()?;
+    let (highest, free_below) = lfs.used_tail();
+    assert!(!free_below);
+    assert_eq!(size % segment_size, 0);
+    let compacted = size / segment_size;
+    assert_eq!(compacted, (highest + 1 + CLEAN_HEADROOM).max(3));
+    assert!(compacted < n_segment / 2, "{}", compacted);
+    assert_eq!(lfs.super_block.read().n_segment as usize, compacted);
+    // but for segment 0, of the super block
+    assert_eq!(lfs.segments.read().len(), compacted - 1);
+    let live: usize = lfs
+        .segments
+        .read()
+        .values()
+        .map(|seg| seg.meta.live_bytes as usize)
+        .sum();
+    assert!(live >= 13 * len);
+    assert!(highest * lfs.geometry.data_blks() * BLKSIZE < live + 2 * segment_size);
+    // once more, nothing to do
+    assert_eq!(lfs.compact()?, size);
+    drop(lfs);
+
+    // the device cut to it
+    image.set_len(size as u64).unwrap();
+    let lfs = reopen(&image);
+    assert_eq!(lfs.super_block.read().n_segment as usize, compacted);
+    let root = lfs.root_inode();
+    for i in 0..40 {
+        match root.find(&format!("file{}", i)) {
+            Ok(file) if kept(i) => {
+                let content = data(len, if i == 13 { 100 } else { i });
+                assert_eq!(read_all(&file)?, content);
+            }
+            Err(FsError::EntryNotFound) if !kept(i) => {}
+            res => panic!("file{}: {:?}", i, res.map(|_| ())),
+        }
+    }
+    drop(root);
+    check_clean(&lfs)?;
+    // and still written, cleaned in the headroom
+    let root = lfs.root_inode();
+    let file = root.find("file15")?;
+    for i in 0..4 {
+        file.write_at(0, &data(len, 200 + i))?;
+        lfs.sync()?;
+    }
+    assert_eq!(read_all(&file)?, data(len, 203));
+    drop(file);
+    drop(root);
+    check_clean(&lfs)
+}
@@ -0,0 +1,111 @@
+//! Extended attributes of LFS
+//!
+//! The attributes of an inode are kept together in a block of the log, which its inode
+//! refers to by `DiskINode::xattr`, `INVALID_BLKID` if it has none. The block holds a list
+//! of entries, each the length of its name, u16, and of its value, u16, then the name and
+//! the value, ended by a name of length 0. As any block on disk, it is not written over:
+//! setting or removing an attribute writes the list to a new block, and frees the old one.
+//! Its summary entry is `ENTRY_XATTR`, with the inode, so that the cleaner moves it as it
+//! does the blocks of the content, and it is freed with the inode. The attributes of an
+//! inode fit in a block, one more fails with `NoDeviceSpace`, as in ext2.
+use crate::*;
+
+/// Bytes before the name of an entry: the length of the name, then of the value
+const XATTR_HEADER: usize = 4;
+
+/// The attributes of list `buf`, a block of attributes
+fn decode(buf: &[u8]) -> vfs::Result<Vec<(String, Vec<u8>)>> {
+    let mut attrs = Vec::new();
+    let mut pos = 0;
+    while pos + XATTR_HEADER <= buf.len() {
+        let name_len = u16::from_le_bytes([buf[pos], buf[pos + 1]]) as usize;
+        let value_len = u16::from_le_bytes([buf[pos + 2], buf[pos + 3]]) as usize;
+        if name_len == 0 {
+            break;
+        }
+        let name = pos + XATTR_HEADER;
+        let value = name + name_len;
+        if value + value_len > buf.len() {
+            return Err(FsError::WrongFs);
+        }
+        let name = core::str::from_utf8(&buf[name..value]).map_err(|_| FsError::WrongFs)?;
+        attrs.push((String::from(name), buf[value..value + value_len].to_vec()));
+        pos = value + value_len;
+    }
+    Ok(attrs)
+}
+
+/// Block of attributes `attrs`, if they fit
+fn encode(attrs: &[(String, Vec<u8>)]) -> vfs::Result<[u8; BLKSIZE]> {
+    let mut buf = [0u8; BLKSIZE];
+    let mut pos = 0;
+    for (name, value) in attrs {
+        let end = pos + XATTR_HEADER + name.len() + value.len();
+        // with the end of the list
+        if end + XATTR_HEADER > BLKSIZE {
+            return Err(FsError::NoDeviceSpace);
+        }
+        buf[pos..pos + 2].copy_from_slice(&(name.len() as u16).to_le_bytes());
+        buf[pos + 2..pos + 4].copy_from_slice(&(value.len() as u16).to_le_bytes());
+        let name_at = pos + XATTR_HEADER;
+        buf[name_at..name_at + name.len()].copy_from_slice(name.as_bytes());
+        buf[name_at + name.len()..end].copy_from_slice(value);
+        pos = end;
+    }
+    Ok(buf)
+}
+
+impl INodeImpl {
+    /// The attributes of the inode
+    pub(crate) fn xattrs(&self) -> vfs::Result<Vec<(String, Vec<u8>)>> {
+        let block = self.disk_inode.read().xattr as BlockId;
+        if block == INVALID_BLKID {
+            return Ok(Vec::new());
+        }
+        let mut buf = [0u8; BLKSIZE];
+        self.fs.device.read_block(block, 0, &mut buf)?;
+        decode(&buf)
+    }
+
+    /// Set attribute `name` to `value`, or remove it if `value` is `None`,
+    /// writing the attributes to a new block
+    pub(crate) fn _set_xattr(&self, name: &str, value: Option<&[u8]>) -> vfs::Result<()> {
+        if name.is_empty() || name.len() > MAX_FNAME_LEN {
+            return Err(FsError::InvalidParam);
+        }
+        let mut attrs = self.xattrs()?;
+        let old = attrs.iter().position(|(n, _)| n == name);
+        match (old, value) {
+            (Some(i), Some(value)) => attrs[i].1 = value.to_vec(),
+            (None, Some(value)) => attrs.push((String::from(name), value.to_vec())),
+            (Some(i), None) => {
+                attrs.remove(i);
+            }
+            (None, None) => return Err(FsError::EntryNotFound),
+        }
+        let new_blk_id = match attrs.is_empty() {
+            true => INVALID_BLKID,
+            false => {
+                let buf = encode(&attrs)?;
+                let blk_id = self.fs.alloc_block().ok_or(FsError::NoDeviceSpace)?;
+                self.fs.device.write_block(blk_id, 0, &buf)?;
+                self.fs._record_block_summary(self.id, blk_id, ENTRY_XATTR);
+                blk_id
+            }
+        };
+        let old_blk_id = self.set_xattr_block(new_blk_id);
+        if old_blk_id != INVALID_BLKID {
+            self.fs.free_block(old_blk_id);
+        }
+        if let Some(now) = self.fs.now() {
+            self.disk_inode.write().ctime = now;
+        }
+        Ok(())
+    }
+
+    /// Keep the attributes in block `block`, return the block they were in
+    pub(crate) fn set_xattr_block(&self, block: BlockId) -> BlockId {
+        let mut disk_inode = self.disk_inode.write();
+        mem::replace(&mut disk_inode.xattr, block as u64) as BlockId
+    }
+}
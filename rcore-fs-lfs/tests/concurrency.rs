@@ -0,0 +1,207 @@
+//! Concurrency of LFS.
+//!
+//! Some threads create, write, link, rename and remove files in dirs of their own and in
+//! one they share, moving files and dirs between them, while another syncs and cleans the
+//! log, which is small enough to need it. Each thread checks its files as it goes, and at
+//! the end all of them must be as written, fsck must find no problem, and they must be so
+//! again once the filesystem is opened again.
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use rcore_fs::dev::{self, Device, TimeProvider};
+use rcore_fs::vfs::{FileSystem, FileType, INode, Result, Timespec};
+use rcore_fs_lfs::{fsck, Geometry, LogFileSystem, DIRENT_SIZE};
+
+const SPACE: usize = 64 * 64 * 4096;
+const THREADS: usize = 4;
+const ROUNDS: usize = 150;
+/// Files of each thread
+const FILES: usize = 6;
+
+struct Clock;
+
+impl TimeProvider for Clock {
+    fn current_time(&self) -> Timespec {
+        Timespec { sec: 1, nsec: 0 }
+    }
+}
+
+static CLOCK: Clock = Clock;
+
+/// An image in memory
+struct MemDevice(Mutex<Vec<u8>>);
+
+impl Device for MemDevice {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> dev::Result<usize> {
+        let image = self.0.lock().unwrap();
+        buf.copy_from_slice(&image[offset..offset + buf.len()]);
+        Ok(buf.len())
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> dev::Result<usize> {
+        let mut image = self.0.lock().unwrap();
+        image[offset..offset + buf.len()].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn sync(&self) -> dev::Result<()> {
+        Ok(())
+    }
+}
+
+/// Content of a file `len` bytes long, told apart by `seed`
+fn data(len: usize, seed: usize) -> Vec<u8> {
+    (0..len).map(|i| (i / 509 + seed * 7) as u8).collect()
+}
+
+fn read_all(file: &Arc<dyn INode>) -> Result<Vec<u8>> {
+    let len = file.metadata()?.size;
+    let mut buf = vec![0; len];
+    assert_eq!(file.read_at(0, &mut buf)?, len);
+    Ok(buf)
+}
+
+/// Make file `name` of `dir` hold `content`
+fn write_file(dir: &Arc<dyn INode>, name: &str, content: &[u8]) -> Result<()> {
+    let file = match dir.find(name) {
+        Ok(file) => file,
+        Err(_) => dir.create(name, FileType::File, 0o644)?,
+    };
+    file.resize(content.len())?;
+    file.write_at(0, content)?;
+    Ok(())
+}
+
+/// Check that `dir` holds the files of `expected`, and only them
+fn check_dir(dir: &Arc<dyn INode>, expected: &BTreeMap<String, Vec<u8>>) -> Result<()> {
+    for (name, content) in expected {
+        let file = dir.find(name)?;
+        assert!(read_all(&file)? == *content, "{} changed", name);
+    }
+    // with . and ..
+    assert_eq!(dir.metadata()?.size / DIRENT_SIZE, expected.len() + 2);
+    Ok(())
+}
+
+/// Churn the files of thread `t`, in dir `own` and the one shared, `shared`
+fn churn(
+    t: usize,
+    own: Arc<dyn INode>,
+    shared: Arc<dyn INode>,
+) -> Result<BTreeMap<String, Vec<u8>>> {
+    let mut files = BTreeMap::new();
+    for round in 0..ROUNDS {
+        let name = format!("f{}", round % FILES);
+        let seed = t * ROUNDS + round;
+        match round % 6 {
+            0 | 1 => {
+                let content = data(1000 + seed * 97 % 40000, seed);
+                write_file(&own, &name, &content)?;
+                files.insert(name.clone(), content);
+            }
+            2 if files.contains_key(&name) => {
+                // to the shared dir and back, under a name of its own
+                let away = format!("t{}-{}", t, name);
+                own.move_(&name, &shared, &away)?;
+                assert!(own.find(&name).is_err());
+                shared.move_(&away, &own, &name)?;
+            }
+            3 if files.contains_key(&name) => {
+                own.unlink(&name)?;
+                files.remove(&name);
+            }
+            4 if files.contains_key(&name) => {
+                // linked from the shared dir, and renamed while it is
+                let file = own.find(&name)?;
+                let link = format!("l{}-{}", t, name);
+                shared.link(&link, &file)?;
+                assert_eq!(file.metadata()?.nlinks, 2);
+                own.move_(&name, &own, "renamed")?;
+                own.move_("renamed", &own, &name)?;
+                assert!(shared.find(&link)?.is_same(&*own.find(&name)?)?);
+                shared.unlink(&link)?;
+                assert_eq!(file.metadata()?.nlinks, 1);
+            }
+            _ => {
+                // a dir with a file in it, moved out and back, then removed
+                let dir = own.create("d", FileType::Dir, 0o755)?;
+                write_file(&dir, "x", &data(3000, seed))?;
+                drop(dir);
+                let away = format!("d{}", t);
+                own.move_("d", &shared, &away)?;
+                let dir = shared.find(&away)?;
+                assert!(read_all(&dir.find("x")?)? == data(3000, seed));
+                shared.move_(&away, &own, "d")?;
+                dir.unlink("x")?;
+                own.unlink("d")?;
+            }
+        }
+        if let Some(content) = files.get(&name) {
+            assert!(
+                read_all(&own.find(&name)?)? == *content,
+                "{} of {}",
+                name,
+                t
+            );
+        }
+    }
+    Ok(files)
+}
+
+#[test]
+fn churn_while_cleaning() -> Result<()> {
+    let device: Arc<dyn Device> = Arc::new(MemDevice(Mutex::new(vec![0; SPACE])));
+    let lfs =
+        LogFileSystem::create_with_geometry(device.clone(), SPACE, Geometry::new(64), &CLOCK)?;
+    let root = lfs.root_inode();
+    let shared = root.create("shared", FileType::Dir, 0o755)?;
+    let done = Arc::new(AtomicBool::new(false));
+    let cleaner = {
+        let lfs = lfs.clone();
+        let done = done.clone();
+        thread::spawn(move || -> Result<usize> {
+            let mut cleaned = 0;
+            while !done.load(Ordering::SeqCst) {
+                lfs.sync()?;
+                cleaned += lfs.run_cleaner_once()?;
+                cleaned += lfs.clean(1)?;
+                // polling, as a kernel thread would, so that a sync waiting gets its turn
+                thread::sleep(Duration::from_millis(1));
+            }
+            Ok(cleaned)
+        })
+    };
+    let workers: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let own = root
+                .create(&format!("t{}", t), FileType::Dir, 0o755)
+                .unwrap();
+            let shared = shared.clone();
+            thread::spawn(move || churn(t, own, shared))
+        })
+        .collect();
+    let expected: Vec<_> = workers
+        .into_iter()
+        .map(|worker| worker.join().unwrap())
+        .collect::<Result<_>>()?;
+    done.store(true, Ordering::SeqCst);
+    assert!(cleaner.join().unwrap()? > 0, "the log was never cleaned");
+
+    let check_all = |root: &Arc<dyn INode>| -> Result<()> {
+        assert_eq!(root.find("shared")?.metadata()?.size / DIRENT_SIZE, 2);
+        for (t, files) in expected.iter().enumerate() {
+            check_dir(&root.find(&format!("t{}", t))?, files)?;
+        }
+        Ok(())
+    };
+    check_all(&root)?;
+    drop((root, shared));
+    let report = fsck::check(&lfs, false)?;
+    assert!(report.problems.is_empty(), "{:?}", report.problems);
+    drop(lfs);
+
+    let lfs = LogFileSystem::open(device)?;
+    check_all(&lfs.root_inode())?;
+    Ok(())
+}
@@ -8,11 +8,21 @@ use alloc::{
     collections::BTreeMap,
     string::String,
     sync::{Arc, Weak},
+    vec,
+    vec::Vec,
 };
+use bitflags::bitflags;
 use core::any::Any;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use rcore_fs::dcache::DCache;
 use rcore_fs::vfs::*;
-use spin::RwLock;
+use spin::{Mutex, RwLock};
 
+use self::namespace::Peers;
+pub use self::namespace::{MountNamespace, Propagation};
+
+mod namespace;
 #[cfg(test)]
 mod tests;
 
@@ -20,16 +30,80 @@ mod tests;
 pub struct MountFS {
     /// The inner file system
     inner: Arc<dyn FileSystem>,
+    /// The INode of the inner file system mounted, its root unless bound
+    root: Arc<dyn INode>,
+    /// Flags of the mount, enforced on its INodes
+    flags: MountFlags,
     /// All mounted children file systems
     mountpoints: RwLock<BTreeMap<INodeId, Arc<MountFS>>>,
     /// The mount point of this file system
     self_mountpoint: Option<Arc<MNode>>,
     /// Weak reference to self
     self_ref: Weak<MountFS>,
+    /// Cache of the directory entries in the inner file system,
+    /// shared with all the mounts of it, see `dcache_of()`
+    dcache: Arc<DCache>,
+    /// Number of its INodes alive, the mount busy if any
+    inodes: AtomicUsize,
+    /// Detached from its mount point by a lazy `umount()`
+    detached: AtomicBool,
+    /// Its peers if shared, see `namespace`
+    peers: RwLock<Option<Arc<Peers>>>,
+}
+
+bitflags! {
+    /// Flags of a mount, see `MNode::mount_with()`
+    pub struct MountFlags: u32 {
+        /// No INode under it can be changed, failing with `ReadOnlyFs`
+        const READ_ONLY = 1;
+        /// No file under it can be executed, left to the kernel to enforce
+        const NO_EXEC = 2;
+        /// The set-user-ID and set-group-ID bits under it are ignored,
+        /// left to the kernel to enforce
+        const NO_SUID = 4;
+    }
+}
+
+/// An active mount, as told by `MountFS::mounts()`
+pub struct MountInfo {
+    /// Path of its mount point from the root of all mounts, "/" for it
+    pub path: String,
+    /// The mount
+    pub fs: Arc<MountFS>,
 }
 
 type INodeId = usize;
 
+/// Max number of cached directory entries of each file system
+const DCACHE_CAPACITY: usize = 1024;
+
+lazy_static! {
+    // inner FileSystem addr -> its DCache
+    static ref DCACHES: Mutex<BTreeMap<usize, Weak<DCache>>> = Mutex::new(BTreeMap::new());
+}
+
+/// The cache of the directory entries of `fs`, one for all its mounts, so that a change made
+/// through one of them is seen through the others
+fn dcache_of(fs: &Arc<dyn FileSystem>) -> Arc<DCache> {
+    // not reused while a mount of it holds both it and the cache
+    let addr = &**fs as *const dyn FileSystem as *const u8 as usize;
+    let mut dcaches = DCACHES.lock();
+    if let Some(dcache) = dcaches.get(&addr).and_then(Weak::upgrade) {
+        return dcache;
+    }
+    let gone: Vec<_> = dcaches
+        .iter()
+        .filter(|(_, dcache)| dcache.upgrade().is_none())
+        .map(|(&addr, _)| addr)
+        .collect();
+    for addr in gone {
+        dcaches.remove(&addr);
+    }
+    let dcache = Arc::new(DCache::new(DCACHE_CAPACITY));
+    dcaches.insert(addr, Arc::downgrade(&dcache));
+    dcache
+}
+
 /// INode for `MountFS`
 pub struct MNode {
     /// The inner INode
@@ -44,10 +118,16 @@ impl MountFS {
     /// Create a `MountFS` wrapper for file system `fs`
     pub fn new(fs: Arc<dyn FileSystem>) -> Arc<Self> {
         MountFS {
+            root: fs.root_inode(),
+            dcache: dcache_of(&fs),
             inner: fs,
+            flags: MountFlags::empty(),
             mountpoints: RwLock::new(BTreeMap::new()),
             self_mountpoint: None,
             self_ref: Weak::default(),
+            inodes: AtomicUsize::new(0),
+            detached: AtomicBool::new(false),
+            peers: RwLock::new(None),
         }
         .wrap()
     }
@@ -69,18 +149,99 @@ impl MountFS {
     /// Strong type version of `root_inode`
     pub fn root_inode(&self) -> Arc<MNode> {
         MNode {
-            inode: self.inner.root_inode(),
+            inode: self.root.clone(),
             vfs: self.self_ref.upgrade().unwrap(),
             self_ref: Weak::default(),
         }
         .wrap()
     }
+
+    /// Flags of the mount
+    pub fn flags(&self) -> MountFlags {
+        self.flags
+    }
+
+    /// Is it bound from an INode other than the root of its file system?
+    pub fn is_bind(&self) -> bool {
+        self.inner.root_inode().metadata().unwrap().inode != self.root.metadata().unwrap().inode
+    }
+
+    /// The inner file system
+    pub fn inner(&self) -> Arc<dyn FileSystem> {
+        self.inner.clone()
+    }
+
+    /// Unmount the file system mounted at `path`, from the root of this mount, after it is
+    /// synced. Fails with `Busy` if any of its INodes is alive, or any file system is mounted
+    /// under it.
+    pub fn umount(&self, path: &str) -> Result<()> {
+        self._umount(path, false)
+    }
+
+    /// Detach the file system mounted at `path`, from the root of this mount, along with
+    /// those mounted under it. Its INodes alive go on working, going up from its root no
+    /// more, and it is synced once none is.
+    pub fn umount_lazy(&self, path: &str) -> Result<()> {
+        self._umount(path, true)
+    }
+
+    fn _umount(&self, path: &str, lazy: bool) -> Result<()> {
+        let root = self.root_inode() as Arc<dyn INode>;
+        let inode = root.lookup(path)?;
+        let inode = inode.downcast_ref::<MNode>().unwrap();
+        let fs = inode.vfs.clone();
+        let mountpoint = match &fs.self_mountpoint {
+            Some(mountpoint) if inode.is_root() => mountpoint,
+            _ => return Err(FsError::InvalidParam),
+        };
+        let inode_id = mountpoint.inode.metadata()?.inode;
+        // unmounted from the peers too, those of its peers mounted there
+        let mut mounts = vec![(mountpoint.vfs.clone(), fs.clone())];
+        let peers = fs.peers();
+        for vfs in mountpoint.vfs.peers() {
+            if let Some(peer) = vfs.mountpoints.read().get(&inode_id) {
+                if peers.iter().any(|fs| Arc::ptr_eq(fs, peer)) {
+                    mounts.push((vfs.clone(), peer.clone()));
+                }
+            }
+        }
+        if !lazy {
+            for (_, fs) in mounts.iter() {
+                // but `inode`
+                let inodes = match Arc::ptr_eq(fs, &inode.vfs) {
+                    true => 1,
+                    false => 0,
+                };
+                if fs.inodes.load(Ordering::SeqCst) > inodes || !fs.mountpoints.read().is_empty() {
+                    return Err(FsError::Busy);
+                }
+            }
+            fs.inner.sync()?;
+        }
+        for (vfs, fs) in mounts {
+            vfs.mountpoints.write().remove(&inode_id);
+            fs.detached.store(lazy, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    /// This mount and all mounts under it, each before those under it, as for /proc/mounts
+    pub fn mounts(&self) -> Result<Vec<MountInfo>> {
+        let this = self.self_ref.upgrade().unwrap();
+        let path = self.root_inode().path()?;
+        let mut mounts = vec![MountInfo { path, fs: this }];
+        for fs in self.mountpoints.read().values() {
+            mounts.extend(fs.mounts()?);
+        }
+        Ok(mounts)
+    }
 }
 
 impl MNode {
     /// Wrap pure `INode` with `Arc<..>`.
     /// Used in constructors.
     fn wrap(self) -> Arc<Self> {
+        self.vfs.inodes.fetch_add(1, Ordering::SeqCst);
         // Create an Arc, make a Weak from it, then put it into the struct.
         // It's a little tricky.
         let inode = Arc::new(self);
@@ -94,14 +255,61 @@ impl MNode {
 
     /// Mount file system `fs` at this INode
     pub fn mount(&self, fs: Arc<dyn FileSystem>) -> Result<Arc<MountFS>> {
+        self.mount_with(fs, MountFlags::empty())
+    }
+
+    /// Mount file system `fs` at this INode with `flags`
+    pub fn mount_with(&self, fs: Arc<dyn FileSystem>, flags: MountFlags) -> Result<Arc<MountFS>> {
+        self._mount(fs.clone(), fs.root_inode(), flags)
+    }
+
+    /// Bind `inode` at this INode with `flags`, so that it is reached from here as the root
+    /// of a mount. The mounts under `inode` are not bound with it.
+    pub fn bind(&self, inode: Arc<dyn INode>, flags: MountFlags) -> Result<Arc<MountFS>> {
+        match inode.downcast_ref::<MNode>() {
+            Some(mnode) => self._mount(mnode.inode.fs(), mnode.inode.clone(), flags),
+            None => self._mount(inode.fs(), inode, flags),
+        }
+    }
+
+    fn _mount(
+        &self,
+        fs: Arc<dyn FileSystem>,
+        root: Arc<dyn INode>,
+        flags: MountFlags,
+    ) -> Result<Arc<MountFS>> {
         let new_fs = MountFS {
+            dcache: dcache_of(&fs),
             inner: fs,
+            root,
+            flags,
             mountpoints: RwLock::new(BTreeMap::new()),
             self_mountpoint: Some(self.self_ref.upgrade().unwrap()),
             self_ref: Weak::default(),
+            inodes: AtomicUsize::new(0),
+            detached: AtomicBool::new(false),
+            peers: RwLock::new(None),
         }
         .wrap();
         let inode_id = self.inode.metadata()?.inode;
+        // mounted at the peers too, the mounts peers of each other
+        if self.vfs.propagation() == Propagation::Shared {
+            let peers = Arc::new(Peers::default());
+            peers.join(&new_fs);
+            for vfs in self.vfs.peers() {
+                if vfs.mountpoints.read().contains_key(&inode_id) {
+                    continue;
+                }
+                let mountpoint = MNode {
+                    inode: self.inode.clone(),
+                    vfs: vfs.clone(),
+                    self_ref: Weak::default(),
+                }
+                .wrap();
+                let fs = new_fs.copy(Some(mountpoint));
+                vfs.mountpoints.write().insert(inode_id, fs);
+            }
+        }
         self.vfs
             .mountpoints
             .write()
@@ -120,16 +328,63 @@ impl MNode {
         }
     }
 
-    /// Is the root INode of its FS?
+    /// Is the root INode of its mount?
     fn is_root(&self) -> bool {
-        self.inode.fs().root_inode().metadata().unwrap().inode
-            == self.inode.metadata().unwrap().inode
+        self.vfs.root.metadata().unwrap().inode == self.inode.metadata().unwrap().inode
+    }
+
+    /// Fail with `ReadOnlyFs` if its mount is read-only
+    fn check_writable(&self) -> Result<()> {
+        match self.vfs.flags.contains(MountFlags::READ_ONLY) {
+            true => Err(FsError::ReadOnlyFs),
+            false => Ok(()),
+        }
+    }
+
+    /// Path from the root of all mounts
+    pub fn path(&self) -> Result<String> {
+        let mut names = Vec::new();
+        let mut inode = self.self_ref.upgrade().unwrap();
+        loop {
+            let parent = inode.find(false, "..")?;
+            if Arc::ptr_eq(&parent.vfs, &inode.vfs)
+                && parent.inode.metadata()?.inode == inode.inode.metadata()?.inode
+            {
+                break;
+            }
+            names.push(parent.find_name_by_child(&inode)?);
+            inode = parent;
+        }
+        let mut path = String::new();
+        for name in names.iter().rev() {
+            path += "/";
+            path += name;
+        }
+        match path.is_empty() {
+            true => Ok(String::from("/")),
+            false => Ok(path),
+        }
     }
 
     /// Strong type version of `create()`
     pub fn create(&self, name: &str, type_: FileType, mode: u32) -> Result<Arc<Self>> {
+        self.create2(name, type_, mode, 0)
+    }
+
+    /// Strong type version of `create2()`
+    pub fn create2(
+        &self,
+        name: &str,
+        type_: FileType,
+        mode: u32,
+        data: usize,
+    ) -> Result<Arc<Self>> {
+        self.check_writable()?;
+        let inode = self.inode.create2(name, type_, mode, data)?;
+        let dir_id = self.inode.metadata()?.inode;
+        self.vfs.dcache.on_create(dir_id, name, &inode);
         Ok(MNode {
-            inode: self.inode.create(name, type_, mode)?,
+            inode,
             vfs: self.vfs.clone(),
             self_ref: Weak::default(),
         }
@@ -152,6 +407,9 @@ impl MNode {
                 } else if self.is_root() {
                     // Here is mountpoint.
                     match &self.vfs.self_mountpoint {
+                        Some(_) if self.vfs.detached.load(Ordering::SeqCst) => {
+                            Ok(self.self_ref.upgrade().unwrap())
+                        }
                         Some(inode) => inode.find(root, ".."),
                         // root fs
                         None => Ok(self.self_ref.upgrade().unwrap()),
@@ -169,9 +427,10 @@ impl MNode {
             _ => {
                 // Going down may trespass the filesystem border.
                 // An INode replacement is required here.
+                let dir = self.overlaid_inode();
                 Ok(MNode {
-                    inode: self.overlaid_inode().inode.find(name)?,
-                    vfs: self.vfs.clone(),
+                    inode: dir.vfs.dcache.find(&dir.inode, name)?,
+                    vfs: dir.vfs.clone(),
                     self_ref: Weak::default(),
                 }
                 .wrap()
@@ -180,6 +439,34 @@ impl MNode {
         }
     }
 
+    /// Delete hard link `name` by `unlink`, unless a file system is mounted at it
+    fn _unlink(&self, name: &str, unlink: impl FnOnce(&dyn INode) -> Result<()>) -> Result<()> {
+        self.check_writable()?;
+        let inode_id = self.inode.find(name)?.metadata()?.inode;
+        // target INode is being mounted
+        if self.vfs.mountpoints.read().contains_key(&inode_id) {
+            return Err(FsError::Busy);
+        }
+        unlink(&*self.inode)?;
+        let dir_id = self.inode.metadata()?.inode;
+        self.vfs.dcache.on_unlink(dir_id, name, inode_id);
+        Ok(())
+    }
+
+    /// Replace the metadata of entry `name`, as the INode gives it, with that of what
+    /// `find()` gives, if it is a mount point or `..` of the root of a mount
+    fn entry_metadata(&self, metadata: &mut Metadata, name: &str) -> Result<()> {
+        let crossing = match name {
+            "." => false,
+            ".." => self.is_root(),
+            _ => self.vfs.mountpoints.read().contains_key(&metadata.inode),
+        };
+        if crossing {
+            *metadata = self.find(false, name)?.metadata()?;
+        }
+        Ok(())
+    }
+
     /// If `child` is a child of `self`, return its name.
     pub fn find_name_by_child(&self, child: &Arc<MNode>) -> Result<String> {
         for index in 0.. {
@@ -202,6 +489,20 @@ impl MNode {
     }
 }
 
+impl Drop for MNode {
+    fn drop(&mut self) {
+        self.vfs.inodes.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Drop for MountFS {
+    fn drop(&mut self) {
+        if self.detached.load(Ordering::SeqCst) {
+            self.inner.sync().ok();
+        }
+    }
+}
+
 impl FileSystem for MountFS {
     fn sync(&self) -> Result<()> {
         self.inner.sync()?;
@@ -218,6 +519,30 @@ impl FileSystem for MountFS {
     fn info(&self) -> FsInfo {
         self.inner.info()
     }
+
+    fn capabilities(&self) -> FsCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn begin_batch(&self) {
+        self.inner.begin_batch()
+    }
+
+    fn end_batch(&self) -> Result<()> {
+        self.inner.end_batch()
+    }
+
+    fn wipe_free_space(&self) -> Result<usize> {
+        self.inner.wipe_free_space()
+    }
+
+    fn sync_metadata(&self) -> Result<()> {
+        self.inner.sync_metadata()
+    }
+
+    fn snapshot_stats(&self) -> FsStats {
+        self.inner.snapshot_stats()
+    }
 }
 
 // unwrap `MNode` and forward methods to inner except `find()`
@@ -227,6 +552,7 @@ impl INode for MNode {
     }
 
     fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.check_writable()?;
         self.inode.write_at(offset, buf)
     }
 
@@ -239,6 +565,7 @@ impl INode for MNode {
     }
 
     fn set_metadata(&self, metadata: &Metadata) -> Result<()> {
+        self.check_writable()?;
         self.inode.set_metadata(metadata)
     }
 
@@ -251,6 +578,7 @@ impl INode for MNode {
     }
 
     fn resize(&self, len: usize) -> Result<()> {
+        self.check_writable()?;
         self.inode.resize(len)
     }
 
@@ -258,29 +586,61 @@ impl INode for MNode {
         Ok(self.create(name, type_, mode)?)
     }
 
+    fn create2(
+        &self,
+        name: &str,
+        type_: FileType,
+        mode: u32,
+        data: usize,
+    ) -> Result<Arc<dyn INode>> {
+        Ok(self.create2(name, type_, mode, data)?)
+    }
+
+    fn dir_defaults(&self) -> Result<DirDefaults> {
+        self.inode.dir_defaults()
+    }
+
+    fn set_dir_defaults(&self, defaults: DirDefaults) -> Result<()> {
+        self.check_writable()?;
+        self.inode.set_dir_defaults(defaults)
+    }
+
     fn link(&self, name: &str, other: &Arc<dyn INode>) -> Result<()> {
-        let other = &other
-            .downcast_ref::<Self>()
-            .ok_or(FsError::NotSameFs)?
-            .inode;
-        self.inode.link(name, other)
+        let other = other.downcast_ref::<Self>().ok_or(FsError::NotSameFs)?;
+        // as from another file system, even if bound from this one
+        if !Arc::ptr_eq(&self.vfs, &other.vfs) {
+            return Err(FsError::NotSameFs);
+        }
+        self.check_writable()?;
+        let other = &other.inode;
+        self.inode.link(name, other)?;
+        let dir_id = self.inode.metadata()?.inode;
+        self.vfs.dcache.on_create(dir_id, name, other);
+        Ok(())
     }
 
     fn unlink(&self, name: &str) -> Result<()> {
-        let inode_id = self.inode.find(name)?.metadata()?.inode;
-        // target INode is being mounted
-        if self.vfs.mountpoints.read().contains_key(&inode_id) {
-            return Err(FsError::Busy);
-        }
-        self.inode.unlink(name)
+        self._unlink(name, |inode| inode.unlink(name))
+    }
+
+    fn secure_unlink(&self, name: &str) -> Result<()> {
+        self._unlink(name, |inode| inode.secure_unlink(name))
     }
 
     fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> Result<()> {
-        let target = &target
-            .downcast_ref::<Self>()
-            .ok_or(FsError::NotSameFs)?
-            .inode;
-        self.inode.move_(old_name, target, new_name)
+        let target = target.downcast_ref::<Self>().ok_or(FsError::NotSameFs)?;
+        if !Arc::ptr_eq(&self.vfs, &target.vfs) {
+            return Err(FsError::NotSameFs);
+        }
+        self.check_writable()?;
+        let target = &target.inode;
+        self.inode.move_(old_name, target, new_name)?;
+        let old_dir_id = self.inode.metadata()?.inode;
+        let new_dir_id = target.metadata()?.inode;
+        self.vfs
+            .dcache
+            .on_rename(old_dir_id, old_name, new_dir_id, new_name);
+        Ok(())
     }
 
     fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
@@ -291,7 +651,32 @@ impl INode for MNode {
         self.inode.get_entry(id)
     }
 
+    fn get_entry_from(&self, cookie: usize) -> Result<(String, usize)> {
+        self.inode.get_entry_from(cookie)
+    }
+
+    fn get_entry_with_metadata(&self, id: usize) -> Result<(Metadata, String)> {
+        let (mut metadata, name) = self.inode.get_entry_with_metadata(id)?;
+        self.entry_metadata(&mut metadata, &name)?;
+        Ok((metadata, name))
+    }
+
+    fn get_entries_with_metadata(
+        &self,
+        begin: usize,
+        max: usize,
+    ) -> Result<Vec<(Metadata, String)>> {
+        let mut entries = self.inode.get_entries_with_metadata(begin, max)?;
+        for (metadata, name) in entries.iter_mut() {
+            self.entry_metadata(metadata, name)?;
+        }
+        Ok(entries)
+    }
+
     fn io_control(&self, cmd: u32, data: usize) -> Result<()> {
+        if io_control_sets(cmd) {
+            self.check_writable()?;
+        }
         self.inode.io_control(cmd, data)
     }
 
@@ -299,10 +684,45 @@ impl INode for MNode {
         self.inode.mmap(area)
     }
 
+    fn copy_file_range(
+        &self,
+        offset: usize,
+        dst: &Arc<dyn INode>,
+        dst_offset: usize,
+        len: usize,
+    ) -> Result<usize> {
+        let dst = dst.downcast_ref::<Self>().ok_or(FsError::NotSameFs)?;
+        dst.check_writable()?;
+        self.inode
+            .copy_file_range(offset, &dst.inode, dst_offset, len)
+    }
+
+    fn get_xattr(&self, name: &str) -> Result<Vec<u8>> {
+        self.inode.get_xattr(name)
+    }
+
+    fn set_xattr(&self, name: &str, value: &[u8]) -> Result<()> {
+        self.check_writable()?;
+        self.inode.set_xattr(name, value)
+    }
+
+    fn list_xattr(&self) -> Result<Vec<String>> {
+        self.inode.list_xattr()
+    }
+
+    fn remove_xattr(&self, name: &str) -> Result<()> {
+        self.check_writable()?;
+        self.inode.remove_xattr(name)
+    }
+
     fn fs(&self) -> Arc<dyn FileSystem> {
         self.vfs.clone()
     }
 
+    fn inner_ref(&self) -> Option<&dyn INode> {
+        Some(&*self.inode)
+    }
+
     fn as_any_ref(&self) -> &dyn Any {
         self
     }
@@ -0,0 +1,147 @@
+//! Mount namespaces, as by `clone(CLONE_NEWNS)` and `unshare()`
+//!
+//! A namespace is a tree of mounts of its own, over file systems shared with others. Its copy
+//! by `MountNamespace::unshare()` has mounts of its own too, so that those mounted or unmounted
+//! in one are not in the other, but for the shared mounts: a mount made shared by
+//! `MountFS::set_propagation()` is a peer of its copies, and a file system mounted at or
+//! unmounted from an INode of one of them is at or from the others too, as in Linux.
+use alloc::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicBool, AtomicUsize};
+use spin::{Mutex, RwLock};
+
+use crate::{MNode, MountFS};
+
+/// How mounts at and from a mount are propagated to others
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Propagation {
+    /// to none, as by default
+    Private,
+    /// to its peers, and from them
+    Shared,
+}
+
+/// Peers of a shared mount, those alive
+#[derive(Default)]
+pub(crate) struct Peers(Mutex<Vec<Weak<MountFS>>>);
+
+/// A tree of mounts, see the module docs
+pub struct MountNamespace {
+    root: Arc<MountFS>,
+}
+
+impl Peers {
+    pub(crate) fn join(self: &Arc<Self>, fs: &Arc<MountFS>) {
+        let mut members = self.0.lock();
+        members.retain(|peer| peer.strong_count() != 0);
+        members.push(Arc::downgrade(fs));
+        *fs.peers.write() = Some(self.clone());
+    }
+
+    fn leave(&self, fs: &MountFS) {
+        self.0
+            .lock()
+            .retain(|peer| peer.strong_count() != 0 && !core::ptr::eq(peer.as_ptr(), fs));
+    }
+}
+
+impl MountFS {
+    /// How mounts at and from it are propagated
+    pub fn propagation(&self) -> Propagation {
+        match &*self.peers.read() {
+            Some(_) => Propagation::Shared,
+            None => Propagation::Private,
+        }
+    }
+
+    /// Set how mounts at and from it are propagated. Made private, it is a peer of none.
+    pub fn set_propagation(&self, propagation: Propagation) {
+        let this = self.self_ref.upgrade().unwrap();
+        match propagation {
+            Propagation::Shared if self.peers.read().is_none() => {
+                Arc::new(Peers::default()).join(&this);
+            }
+            Propagation::Shared => {}
+            Propagation::Private => {
+                if let Some(peers) = self.peers.write().take() {
+                    peers.leave(self);
+                }
+            }
+        }
+    }
+
+    /// Peers alive but itself, those mounts at and from it are propagated to
+    pub(crate) fn peers(&self) -> Vec<Arc<MountFS>> {
+        let peers = match &*self.peers.read() {
+            Some(peers) => peers.clone(),
+            None => return Vec::new(),
+        };
+        let members = peers.0.lock();
+        members
+            .iter()
+            .filter_map(|peer| peer.upgrade())
+            .filter(|peer| !core::ptr::eq(&**peer, self))
+            .collect()
+    }
+
+    /// Copy of it, mounted at `mountpoint`, and of the mounts under it, the shared its peers
+    pub(crate) fn copy(&self, mountpoint: Option<Arc<MNode>>) -> Arc<MountFS> {
+        let copy = MountFS {
+            inner: self.inner.clone(),
+            root: self.root.clone(),
+            flags: self.flags,
+            mountpoints: RwLock::new(BTreeMap::new()),
+            self_mountpoint: mountpoint,
+            self_ref: Weak::default(),
+            dcache: self.dcache.clone(),
+            inodes: AtomicUsize::new(0),
+            detached: AtomicBool::new(false),
+            peers: RwLock::new(None),
+        }
+        .wrap();
+        if let Some(peers) = &*self.peers.read() {
+            peers.join(&copy);
+        }
+        for (&inode_id, fs) in self.mountpoints.read().iter() {
+            let mountpoint = MNode {
+                inode: fs.self_mountpoint.as_ref().unwrap().inode.clone(),
+                vfs: copy.clone(),
+                self_ref: Weak::default(),
+            }
+            .wrap();
+            let fs = fs.copy(Some(mountpoint));
+            copy.mountpoints.write().insert(inode_id, fs);
+        }
+        copy
+    }
+}
+
+impl MountNamespace {
+    /// A namespace of `fs` as its root, none mounted on it
+    pub fn new(fs: Arc<dyn rcore_fs::vfs::FileSystem>) -> Arc<Self> {
+        Arc::new(MountNamespace {
+            root: MountFS::new(fs),
+        })
+    }
+
+    /// The mount at its root
+    pub fn root(&self) -> Arc<MountFS> {
+        self.root.clone()
+    }
+
+    /// Strong type version of `root_inode`
+    pub fn root_inode(&self) -> Arc<MNode> {
+        self.root.root_inode()
+    }
+
+    /// A copy of the namespace, for a process to have as its own. The INodes of this one, as
+    /// its working directory, are to be looked up again in the copy by their `MNode::path()`.
+    pub fn unshare(&self) -> Arc<Self> {
+        Arc::new(MountNamespace {
+            root: self.root.copy(None),
+        })
+    }
+}
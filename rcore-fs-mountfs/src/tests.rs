@@ -16,6 +16,32 @@ fn mount() {
     assert!(root.lookup("mnt/file").is_ok());
 }
 
+#[test]
+fn entries_with_metadata() {
+    let rootfs = MountFS::new(RamFS::new()) as Arc<dyn FileSystem>;
+    let root = rootfs.root_inode();
+    let mnt = root.create("mnt", FileType::Dir, 0o777).unwrap();
+    root.create("file", FileType::File, 0o777).unwrap();
+    let ramfs = RamFS::new();
+    ramfs
+        .root_inode()
+        .create("inner", FileType::File, 0o777)
+        .unwrap();
+    mnt.downcast_ref::<MNode>().unwrap().mount(ramfs).unwrap();
+
+    // a mount point and `..` of a mounted root are what `find()` gives
+    for dir in [root.clone(), root.find("mnt").unwrap()].iter() {
+        let entries = dir.get_entries_with_metadata(0, 10).unwrap();
+        assert_eq!(entries.len(), dir.list().unwrap().len());
+        for (id, (metadata, name)) in entries.iter().enumerate() {
+            let found = dir.find(name).unwrap().metadata().unwrap();
+            assert_eq!(metadata.inode, found.inode, "{}", name);
+            let (metadata, entry) = dir.get_entry_with_metadata(id).unwrap();
+            assert_eq!((metadata.inode, entry), (found.inode, name.clone()));
+        }
+    }
+}
+
 #[test]
 fn remove_busy() {
     let rootfs = MountFS::new(RamFS::new()) as Arc<dyn FileSystem>;
@@ -25,3 +51,356 @@ fn remove_busy() {
     mnt.downcast_ref::<MNode>().unwrap().mount(ramfs).unwrap();
     assert_eq!(root.unlink("mnt"), Err(FsError::Busy));
 }
+
+#[test]
+fn dcache() {
+    let rootfs = MountFS::new(RamFS::new()) as Arc<dyn FileSystem>;
+    let root = rootfs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o777).unwrap();
+
+    // negative entry is replaced on create
+    assert_eq!(root.lookup("dir/file").err(), Some(FsError::EntryNotFound));
+    let file = dir.create("file", FileType::File, 0o777).unwrap();
+    let id = file.metadata().unwrap().inode;
+    assert_eq!(
+        root.lookup("dir/file").unwrap().metadata().unwrap().inode,
+        id
+    );
+
+    dir.move_("file", &root, "file2").unwrap();
+    assert_eq!(root.lookup("dir/file").err(), Some(FsError::EntryNotFound));
+    assert_eq!(root.lookup("file2").unwrap().metadata().unwrap().inode, id);
+
+    root.unlink("file2").unwrap();
+    assert_eq!(root.lookup("file2").err(), Some(FsError::EntryNotFound));
+
+    // entries under a removed directory are dropped
+    root.unlink("dir").unwrap();
+    assert_eq!(root.lookup("dir/file").err(), Some(FsError::EntryNotFound));
+}
+
+#[test]
+fn dcache_shared_by_mounts() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.root_inode();
+    let ramfs = RamFS::new();
+    for name in ["a", "b", "c"].iter() {
+        root.create(name, FileType::Dir, 0o777).unwrap();
+    }
+    root.find(false, "a").unwrap().mount(ramfs.clone()).unwrap();
+    root.find(false, "b").unwrap().mount(ramfs.clone()).unwrap();
+    let root = root as Arc<dyn INode>;
+
+    // a change through one mount seen through the other, with the miss cached
+    assert_eq!(root.lookup("a/f").err(), Some(FsError::EntryNotFound));
+    root.lookup("b")
+        .unwrap()
+        .create("f", FileType::Dir, 0o777)
+        .unwrap();
+    let id = root.lookup("b/f").unwrap().metadata().unwrap().inode;
+    assert_eq!(root.lookup("a/f").unwrap().metadata().unwrap().inode, id);
+
+    // and through a bind mount of a dir in it
+    let c = root.lookup("c").unwrap();
+    let f = root.lookup("a/f").unwrap();
+    c.downcast_ref::<MNode>()
+        .unwrap()
+        .bind(f, MountFlags::empty())
+        .unwrap();
+    assert_eq!(root.lookup("c/g").err(), Some(FsError::EntryNotFound));
+    root.lookup("b/f")
+        .unwrap()
+        .create("g", FileType::File, 0o777)
+        .unwrap();
+    assert!(root.lookup("c/g").is_ok());
+    root.lookup("a/f").unwrap().unlink("g").unwrap();
+    assert_eq!(root.lookup("c/g").err(), Some(FsError::EntryNotFound));
+}
+
+/// `MountFS` over `MountFS` over ... `depth` times over `fs`, with `fs` also mounted at `/mnt`
+fn nested(fs: Arc<dyn FileSystem>, depth: usize) -> Vec<Arc<dyn INode>> {
+    let mut inodes = vec![fs.root_inode()];
+    let mut fs = fs;
+    for _ in 0..depth {
+        fs = MountFS::new(fs);
+        inodes.push(fs.root_inode());
+    }
+    let rootfs = MountFS::new(RamFS::new());
+    let mnt = rootfs
+        .root_inode()
+        .create("mnt", FileType::Dir, 0o777)
+        .unwrap();
+    mnt.mount(fs).unwrap();
+    inodes.push(rootfs.root_inode().find(false, "mnt").unwrap());
+    inodes
+}
+
+#[test]
+fn pass_through() {
+    let ramfs = RamFS::new();
+    let root = ramfs.root_inode();
+    let file = root.create("file", FileType::File, 0o777).unwrap();
+    file.write_at(0, b"hello").unwrap();
+    file.set_xattr("user.tag", b"blue").unwrap();
+
+    for (i, dir) in nested(ramfs, 3).into_iter().enumerate() {
+        // each level of wrappers is reached
+        let depth = core::iter::successors(Some(&*dir), |inode| inode.inner_ref()).count();
+        assert_eq!(depth, i + 1);
+        assert_eq!(dir.downcast_ref::<MNode>().is_some(), i > 0);
+
+        let file = dir.find("file").unwrap();
+        assert_eq!(file.get_xattr("user.tag").unwrap(), b"blue");
+        assert_eq!(file.list_xattr().unwrap(), ["user.tag"]);
+        assert_eq!(file.poll().unwrap().read, true);
+        assert_eq!(file.io_control(0, 0), Err(FsError::NotSupported));
+        assert_eq!(
+            dir.fs().capabilities(),
+            root.fs().capabilities(),
+            "capabilities differ at depth {}",
+            depth
+        );
+
+        // the fast path is taken, or a mount wrapper would fall back to NotSameFs
+        let copy = dir.create("copy", FileType::File, 0o777).unwrap();
+        assert_eq!(file.copy_file_range(1, &copy, 0, 10), Ok(4));
+        let mut buf = [0u8; 4];
+        copy.read_at(0, &mut buf).unwrap();
+        assert_eq!(&buf, b"ello");
+        dir.unlink("copy").unwrap();
+
+        let dev = dir
+            .create2("dev", FileType::CharDevice, 0o666, 0x103)
+            .unwrap();
+        assert_eq!(dev.metadata().unwrap().rdev, 0x103);
+        dir.unlink("dev").unwrap();
+    }
+    assert_eq!(
+        root.find("file").unwrap().get_xattr("user.tag").unwrap(),
+        b"blue"
+    );
+}
+
+#[test]
+fn dot_dot() {
+    let inodes = nested(RamFS::new(), 2);
+    let top_mnt = inodes.last().unwrap();
+    let rootfs_root = top_mnt.find("..").unwrap();
+    let sub = top_mnt.create("sub", FileType::Dir, 0o777).unwrap();
+
+    // ".." at a mount root escapes to the parent mount, "/" is the top of all mounts
+    assert!(sub.lookup("../..").unwrap().is_same(&*rootfs_root).unwrap());
+    assert!(sub.lookup("/").unwrap().is_same(&*rootfs_root).unwrap());
+    assert!(sub.lookup("/mnt/sub").unwrap().is_same(&*sub).unwrap());
+    assert!(sub.lookup("./.././sub/").unwrap().is_same(&*sub).unwrap());
+    assert!(!top_mnt.is_same(&*rootfs_root).unwrap());
+    assert_eq!(
+        rootfs_root.lookup("../..").unwrap().list().unwrap(),
+        [".", "..", "mnt"]
+    );
+
+    // inside a chroot at /mnt, ".." and "/" stop at it, even through a symlink
+    let link = sub.create("link", FileType::SymLink, 0o777).unwrap();
+    link.write_at(0, b"/../sub").unwrap();
+    let chroot = top_mnt.clone();
+    assert!(sub
+        .lookup_in(&chroot, "../../..", 0)
+        .unwrap()
+        .is_same(&*chroot)
+        .unwrap());
+    assert!(sub
+        .lookup_in(&chroot, "/..", 0)
+        .unwrap()
+        .is_same(&*chroot)
+        .unwrap());
+    let target = sub.lookup_in(&chroot, "link", 1).unwrap();
+    assert!(target.is_same(&*sub).unwrap());
+    // out of the chroot, the symlink goes to /sub, which does not exist
+    assert_eq!(
+        sub.lookup_follow("link", 1).err(),
+        Some(FsError::EntryNotFound)
+    );
+}
+
+#[test]
+fn bind() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o777).unwrap();
+    dir.create("file", FileType::File, 0o777).unwrap();
+    let mnt = root.create("mnt", FileType::Dir, 0o777).unwrap();
+    let root = root as Arc<dyn INode>;
+
+    let fs = mnt.bind(dir.clone(), MountFlags::empty()).unwrap();
+    assert!(fs.is_bind());
+    let file = root.lookup("mnt/file").unwrap();
+    file.write_at(0, b"hello").unwrap();
+    let mut buf = [0u8; 5];
+    dir.find(false, "file")
+        .unwrap()
+        .read_at(0, &mut buf)
+        .unwrap();
+    assert_eq!(&buf, b"hello");
+
+    // going up from the root of the bind crosses back to its mount point
+    let up = root.lookup("mnt/..").unwrap();
+    assert_eq!(up.metadata().unwrap().inode, root.metadata().unwrap().inode);
+}
+
+#[test]
+fn read_only() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.root_inode();
+    let mnt = root.create("mnt", FileType::Dir, 0o777).unwrap();
+    let ramfs = RamFS::new();
+    let file = ramfs.root_inode();
+    file.create("file", FileType::File, 0o777).unwrap();
+    let fs = mnt.mount_with(ramfs, MountFlags::READ_ONLY).unwrap();
+    assert_eq!(fs.flags(), MountFlags::READ_ONLY);
+    let root = root as Arc<dyn INode>;
+
+    let file = root.lookup("mnt/file").unwrap();
+    let mnt = root.lookup("mnt").unwrap();
+    assert_eq!(file.write_at(0, b"x"), Err(FsError::ReadOnlyFs));
+    assert_eq!(file.resize(1), Err(FsError::ReadOnlyFs));
+    assert_eq!(file.set_xattr("user.tag", b"x"), Err(FsError::ReadOnlyFs));
+    let flags = FS_NODUMP_FL;
+    assert_eq!(
+        file.io_control(FS_IOC_SETFLAGS, &flags as *const u32 as usize),
+        Err(FsError::ReadOnlyFs)
+    );
+    // not refused by the mount, but by RamFS
+    let mut flags = 0u32;
+    assert_eq!(
+        file.io_control(FS_IOC_GETFLAGS, &mut flags as *mut u32 as usize),
+        Err(FsError::NotSupported)
+    );
+    assert_eq!(
+        mnt.create("new", FileType::File, 0o777).err(),
+        Some(FsError::ReadOnlyFs)
+    );
+    assert_eq!(mnt.unlink("file"), Err(FsError::ReadOnlyFs));
+    assert_eq!(mnt.move_("file", &mnt, "file2"), Err(FsError::ReadOnlyFs));
+    assert_eq!(mnt.move_("file", &root, "file"), Err(FsError::NotSameFs));
+    assert_eq!(root.link("file", &file), Err(FsError::NotSameFs));
+    let mut buf = [0u8; 1];
+    assert_eq!(file.read_at(0, &mut buf), Ok(0));
+}
+
+#[test]
+fn mounts() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.root_inode();
+    let mnt = root.create("mnt", FileType::Dir, 0o777).unwrap();
+    let sub = mnt.mount(RamFS::new()).unwrap();
+    let dir = sub
+        .root_inode()
+        .create("dir", FileType::Dir, 0o777)
+        .unwrap();
+    let inner = dir.mount_with(RamFS::new(), MountFlags::NO_EXEC).unwrap();
+
+    let mounts = rootfs.mounts().unwrap();
+    let paths: Vec<&str> = mounts.iter().map(|m| m.path.as_str()).collect();
+    assert_eq!(paths, ["/", "/mnt", "/mnt/dir"]);
+    assert!(Arc::ptr_eq(&mounts[2].fs, &inner));
+    assert_eq!(mounts[2].fs.flags(), MountFlags::NO_EXEC);
+}
+
+#[test]
+fn umount() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.root_inode();
+    let mnt = root.create("mnt", FileType::Dir, 0o777).unwrap();
+    let ramfs = RamFS::new();
+    ramfs
+        .root_inode()
+        .create("file", FileType::File, 0o777)
+        .unwrap();
+    mnt.mount(ramfs).unwrap();
+    let root = root as Arc<dyn INode>;
+
+    assert_eq!(rootfs.umount("mnt/file"), Err(FsError::InvalidParam));
+    assert_eq!(rootfs.umount("/"), Err(FsError::InvalidParam));
+    let file = root.lookup("mnt/file").unwrap();
+    assert_eq!(rootfs.umount("mnt"), Err(FsError::Busy));
+    drop(file);
+    rootfs.umount("mnt").unwrap();
+    assert_eq!(root.lookup("mnt/file").err(), Some(FsError::EntryNotFound));
+    assert_eq!(rootfs.mounts().unwrap().len(), 1);
+}
+
+#[test]
+fn umount_lazy() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.root_inode();
+    let mnt = root.create("mnt", FileType::Dir, 0o777).unwrap();
+    let ramfs = RamFS::new();
+    ramfs
+        .root_inode()
+        .create("dir", FileType::Dir, 0o777)
+        .unwrap();
+    mnt.mount(ramfs).unwrap();
+    let root = root as Arc<dyn INode>;
+
+    let dir = root.lookup("mnt/dir").unwrap();
+    let top = root.lookup("mnt").unwrap();
+    assert_eq!(
+        top.find("..").unwrap().metadata().unwrap().inode,
+        root.metadata().unwrap().inode
+    );
+    rootfs.umount_lazy("mnt").unwrap();
+    assert_eq!(root.lookup("mnt/dir").err(), Some(FsError::EntryNotFound));
+
+    // still working, but no more going up from its root
+    dir.create("file", FileType::File, 0o777).unwrap();
+    let up = dir.find("..").unwrap().find("..").unwrap();
+    assert_eq!(up.metadata().unwrap().inode, top.metadata().unwrap().inode);
+    assert!(up.find("dir").is_ok());
+}
+
+#[test]
+fn namespace() {
+    let ns = MountNamespace::new(RamFS::new());
+    let root = ns.root_inode();
+    let private = root.create("private", FileType::Dir, 0o777).unwrap();
+    let shared = root.create("shared", FileType::Dir, 0o777).unwrap();
+    let fs = shared.mount(RamFS::new()).unwrap();
+    fs.set_propagation(Propagation::Shared);
+    let dir = fs.root_inode().create("dir", FileType::Dir, 0o777).unwrap();
+    drop(dir);
+
+    let copy = ns.unshare();
+    let root = root as Arc<dyn INode>;
+    let copy_root = copy.root_inode() as Arc<dyn INode>;
+    let paths = |ns: &MountNamespace| -> Vec<String> {
+        let mounts = ns.root().mounts().unwrap();
+        mounts.into_iter().map(|m| m.path).collect()
+    };
+    assert_eq!(paths(&copy), ["/", "/shared"]);
+
+    // not propagated from a private mount
+    private.mount(RamFS::new()).unwrap();
+    assert_eq!(paths(&ns), ["/", "/private", "/shared"]);
+    assert_eq!(paths(&copy), ["/", "/shared"]);
+
+    // propagated from a shared one, both ways
+    let dir = copy_root.lookup("shared/dir").unwrap();
+    dir.downcast_ref::<MNode>()
+        .unwrap()
+        .mount(RamFS::new())
+        .unwrap();
+    drop(dir);
+    assert_eq!(paths(&ns), ["/", "/private", "/shared", "/shared/dir"]);
+    assert_eq!(paths(&copy), ["/", "/shared", "/shared/dir"]);
+    ns.root().umount("shared/dir").unwrap();
+    assert_eq!(paths(&copy), ["/", "/shared"]);
+
+    // but once made private
+    fs.set_propagation(Propagation::Private);
+    let dir = root.lookup("shared/dir").unwrap();
+    dir.downcast_ref::<MNode>()
+        .unwrap()
+        .mount(RamFS::new())
+        .unwrap();
+    assert_eq!(paths(&copy), ["/", "/shared"]);
+}
@@ -0,0 +1,685 @@
+//! Overlay file system, merging a lower file system, which is only read, and an upper one,
+//! which is written, as overlayfs of Linux
+//!
+//! The dirs of the same path in both layers are merged, and any other entry of the upper
+//! layer hides the one of its name in the lower. A file of the lower layer is copied up to
+//! the upper one, along with the dirs above it, before it is changed. Removing an entry
+//! with one in the lower layer leaves a whiteout in the upper: a char device of number 0/0,
+//! as in Linux. A dir made where one was removed is opaque, by xattr `OPAQUE_XATTR`, and
+//! hides the lower dir of its name. So the upper file system needs char devices and xattrs.
+//!
+//! As in Linux, dirs of the lower layer can not be moved, failing with `NotSameFs` (EXDEV)
+//! so that `mv` copies them, and hard links of the lower layer are broken on copy-up.
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+extern crate alloc;
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    string::String,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use core::any::Any;
+use rcore_fs::vfs::*;
+use spin::{Mutex, RwLock};
+
+#[cfg(test)]
+mod tests;
+
+/// Xattr of an opaque dir of the upper layer, set to "y"
+pub const OPAQUE_XATTR: &str = "trusted.overlay.opaque";
+/// Xattr of an INode copied up, the id in the lower layer of the INode it was copied from
+const ORIGIN_XATTR: &str = "trusted.overlay.origin";
+/// Prefix of the xattrs of the overlay itself, hidden from its INodes
+const XATTR_PREFIX: &str = "trusted.overlay.";
+
+/// Overlay of a writable upper file system on a lower one
+pub struct OverlayFS {
+    lower: Arc<dyn FileSystem>,
+    upper: Arc<dyn FileSystem>,
+    /// INodes alive by id, so that all who hold an INode see it once copied up
+    inodes: RwLock<BTreeMap<usize, Weak<OverlayINode>>>,
+    /// Weak reference to self
+    self_ref: Weak<OverlayFS>,
+}
+
+/// INode for `OverlayFS`
+pub struct OverlayINode {
+    /// Id, from its INode in the lower layer if any, see `id_of()`
+    id: usize,
+    /// Its INode in the upper layer, once there
+    upper: RwLock<Option<Arc<dyn INode>>>,
+    /// Its INode in the lower layer, unless only in the upper or an opaque dir
+    lower: Option<Arc<dyn INode>>,
+    /// Where it is, to be copied up there
+    place: RwLock<Place>,
+    /// Names of the merged dir, till it is changed
+    names: Mutex<Option<Vec<String>>>,
+    fs: Arc<OverlayFS>,
+    /// Weak reference to self
+    self_ref: Weak<OverlayINode>,
+}
+
+struct Place {
+    /// The dir it is in, none for the root
+    parent: Option<Arc<OverlayINode>>,
+    name: String,
+}
+
+impl OverlayFS {
+    /// Overlay `upper` on `lower`, their root dirs merged
+    pub fn new(lower: Arc<dyn FileSystem>, upper: Arc<dyn FileSystem>) -> Arc<Self> {
+        OverlayFS {
+            lower,
+            upper,
+            inodes: RwLock::new(BTreeMap::new()),
+            self_ref: Weak::default(),
+        }
+        .wrap()
+    }
+
+    /// Wrap pure `OverlayFS` with `Arc<..>`.
+    /// Used in constructors.
+    fn wrap(self) -> Arc<Self> {
+        // Create an Arc, make a Weak from it, then put it into the struct.
+        // It's a little tricky.
+        let fs = Arc::new(self);
+        let weak = Arc::downgrade(&fs);
+        let ptr = Arc::into_raw(fs) as *mut Self;
+        unsafe {
+            (*ptr).self_ref = weak;
+            Arc::from_raw(ptr)
+        }
+    }
+
+    /// The lower file system
+    pub fn lower(&self) -> Arc<dyn FileSystem> {
+        self.lower.clone()
+    }
+
+    /// The upper file system
+    pub fn upper(&self) -> Arc<dyn FileSystem> {
+        self.upper.clone()
+    }
+
+    /// Strong type version of `root_inode`
+    pub fn root_inode(&self) -> Arc<OverlayINode> {
+        let upper = self.upper.root_inode();
+        let lower = match is_opaque(&*upper) {
+            true => None,
+            false => Some(self.lower.root_inode()),
+        };
+        let place = Place {
+            parent: None,
+            name: String::new(),
+        };
+        self.get_inode(Some(upper), lower, place)
+            .expect("failed to load the root dir")
+    }
+
+    /// The INode of `upper` and `lower` at `place`, the one alive if any
+    fn get_inode(
+        &self,
+        upper: Option<Arc<dyn INode>>,
+        lower: Option<Arc<dyn INode>>,
+        place: Place,
+    ) -> Result<Arc<OverlayINode>> {
+        let id = id_of(&upper, &lower)?;
+        let alive = self
+            .inodes
+            .read()
+            .get(&id)
+            .and_then(|inode| inode.upgrade());
+        if let Some(inode) = alive {
+            let same = match (inode.upper(), &upper) {
+                (None, None) => true,
+                (Some(inode), Some(upper)) => inode.is_same(&**upper)?,
+                _ => false,
+            };
+            if same {
+                // copied up to where it is found last
+                if inode.upper().is_none() {
+                    *inode.place.write() = place;
+                }
+                return Ok(inode);
+            }
+        }
+        let inode = OverlayINode {
+            id,
+            upper: RwLock::new(upper),
+            lower,
+            place: RwLock::new(place),
+            names: Mutex::new(None),
+            fs: self.self_ref.upgrade().unwrap(),
+            self_ref: Weak::default(),
+        }
+        .wrap();
+        let mut inodes = self.inodes.write();
+        // unless another is alive, of a hard link of the lower layer broken on copy-up
+        if inodes
+            .get(&id)
+            .map_or(true, |inode| inode.strong_count() == 0)
+        {
+            inodes.insert(id, Arc::downgrade(&inode));
+        }
+        Ok(inode)
+    }
+}
+
+/// Id of the INode of `upper` and `lower`: that in the lower layer if it is there or was
+/// copied up from, so that it is kept, even, with the lowest bit telling apart those only
+/// in the upper layer
+fn id_of(upper: &Option<Arc<dyn INode>>, lower: &Option<Arc<dyn INode>>) -> Result<usize> {
+    match (upper, lower) {
+        (_, Some(lower)) => Ok(lower.metadata()?.inode.wrapping_mul(2)),
+        (Some(upper), None) => match upper.get_xattr(ORIGIN_XATTR) {
+            Ok(ref origin) if origin.len() == 8 => {
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(origin);
+                Ok((u64::from_le_bytes(bytes) as usize).wrapping_mul(2))
+            }
+            _ => Ok(upper.metadata()?.inode.wrapping_mul(2) | 1),
+        },
+        (None, None) => Err(FsError::EntryNotFound),
+    }
+}
+
+impl OverlayINode {
+    /// Wrap pure `OverlayINode` with `Arc<..>`.
+    /// Used in constructors.
+    fn wrap(self) -> Arc<Self> {
+        // Create an Arc, make a Weak from it, then put it into the struct.
+        // It's a little tricky.
+        let inode = Arc::new(self);
+        let weak = Arc::downgrade(&inode);
+        let ptr = Arc::into_raw(inode) as *mut Self;
+        unsafe {
+            (*ptr).self_ref = weak;
+            Arc::from_raw(ptr)
+        }
+    }
+
+    /// Its INode in the upper layer, if it is there
+    pub fn upper(&self) -> Option<Arc<dyn INode>> {
+        self.upper.read().clone()
+    }
+
+    /// Its INode in the lower layer, if it is there and not hidden
+    pub fn lower(&self) -> Option<Arc<dyn INode>> {
+        self.lower.clone()
+    }
+
+    /// The INode it is seen as, in the upper layer if it is there
+    fn real(&self) -> Arc<dyn INode> {
+        match self.upper() {
+            Some(inode) => inode,
+            None => self.lower.clone().unwrap(),
+        }
+    }
+
+    fn is_dir(&self) -> Result<bool> {
+        Ok(self.real().metadata()?.type_ == FileType::Dir)
+    }
+
+    /// Copy it up to the upper layer, along with the dirs above it, unless it is there
+    fn copy_up(&self) -> Result<Arc<dyn INode>> {
+        let mut upper = self.upper.write();
+        if let Some(inode) = &*upper {
+            return Ok(inode.clone());
+        }
+        let (parent, name) = {
+            let place = self.place.read();
+            // the root is always in the upper layer
+            (place.parent.clone().unwrap(), place.name.clone())
+        };
+        let dir = parent.copy_up()?;
+        let lower = self.lower.as_ref().unwrap();
+        let inode = copy_inode(&**lower, &dir, &name)?;
+        let origin = lower.metadata()?.inode as u64;
+        match inode.set_xattr(ORIGIN_XATTR, &origin.to_le_bytes()) {
+            Ok(()) | Err(FsError::NotSupported) => {}
+            Err(err) => {
+                dir.unlink(&name).ok();
+                return Err(err);
+            }
+        }
+        *upper = Some(inode.clone());
+        Ok(inode)
+    }
+
+    /// Its entry `name`, hidden by a whiteout or merged as told in `OverlayFS`
+    fn find_child(&self, name: &str) -> Result<Arc<OverlayINode>> {
+        let upper = match self.upper() {
+            Some(dir) => found(dir.find(name))?,
+            None => None,
+        };
+        if let Some(inode) = &upper {
+            if is_whiteout(&**inode)? {
+                return Err(FsError::EntryNotFound);
+            }
+        }
+        let lower = match (&upper, &self.lower) {
+            (_, None) => None,
+            (None, Some(dir)) => found(dir.find(name))?,
+            (Some(inode), Some(dir)) => {
+                if inode.metadata()?.type_ == FileType::Dir && !is_opaque(&**inode) {
+                    match found(dir.find(name))? {
+                        Some(lower) if lower.metadata()?.type_ == FileType::Dir => Some(lower),
+                        _ => None,
+                    }
+                } else {
+                    None
+                }
+            }
+        };
+        let place = Place {
+            parent: self.self_ref.upgrade(),
+            name: String::from(name),
+        };
+        self.fs.get_inode(upper, lower, place)
+    }
+
+    /// Names of the merged dir, those of the upper layer first
+    fn names(&self) -> Result<Vec<String>> {
+        let mut cache = self.names.lock();
+        if let Some(names) = &*cache {
+            return Ok(names.clone());
+        }
+        let mut names = vec![String::from("."), String::from("..")];
+        // whiteouts among them
+        let mut upper_names = BTreeSet::new();
+        if let Some(upper) = self.upper() {
+            for name in upper.list()?.into_iter().skip(2) {
+                if !is_whiteout(&*upper.find(&name)?)? {
+                    names.push(name.clone());
+                }
+                upper_names.insert(name);
+            }
+        }
+        if let Some(lower) = &self.lower {
+            for name in lower.list()?.into_iter().skip(2) {
+                if !upper_names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        *cache = Some(names.clone());
+        Ok(names)
+    }
+
+    /// Forget the names of the dir once changed
+    fn changed(&self) {
+        *self.names.lock() = None;
+    }
+
+    /// Is there entry `name` in its lower dir?
+    fn lower_has(&self, name: &str) -> Result<bool> {
+        match &self.lower {
+            Some(dir) => Ok(found(dir.find(name))?.is_some()),
+            None => Ok(false),
+        }
+    }
+
+    /// Remove the whiteout `name` in its upper dir `dir` if any, return if there was one
+    fn clear_whiteout(&self, dir: &Arc<dyn INode>, name: &str) -> Result<bool> {
+        match found(dir.find(name))? {
+            Some(inode) if is_whiteout(&*inode)? => {
+                dir.unlink(name)?;
+                Ok(true)
+            }
+            Some(_) => Err(FsError::EntryExist),
+            None => Ok(false),
+        }
+    }
+
+    /// Remove entry `name`, which is `child`, leaving a whiteout if the lower dir has it
+    fn remove(&self, name: &str, child: &OverlayINode) -> Result<()> {
+        if child.is_dir()? && child.names()?.len() > 2 {
+            return Err(FsError::DirNotEmpty);
+        }
+        let dir = self.copy_up()?;
+        if let Some(upper) = child.upper() {
+            if child.is_dir()? {
+                // none but whiteouts are left
+                for name in upper.list()?.into_iter().skip(2) {
+                    upper.unlink(&name)?;
+                }
+            }
+            dir.unlink(name)?;
+        }
+        if self.lower_has(name)? {
+            dir.create2(name, FileType::CharDevice, 0, 0)?;
+        }
+        self.changed();
+        let mut inodes = self.fs.inodes.write();
+        if let Some(inode) = inodes.get(&child.id) {
+            if inode.ptr_eq(&child.self_ref) {
+                inodes.remove(&child.id);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Copy `lower` to entry `name` of upper dir `dir`, with its content, xattrs and metadata
+fn copy_inode(lower: &dyn INode, dir: &Arc<dyn INode>, name: &str) -> Result<Arc<dyn INode>> {
+    let metadata = lower.metadata()?;
+    let data = match metadata.type_ {
+        FileType::CharDevice | FileType::BlockDevice => metadata.rdev,
+        _ => 0,
+    };
+    let inode = dir.create2(name, metadata.type_, metadata.mode as u32, data)?;
+    let copy = || -> Result<()> {
+        if let FileType::File | FileType::SymLink = metadata.type_ {
+            if lower.copy_range(0, &inode, 0, metadata.size)? != metadata.size {
+                return Err(FsError::DeviceError);
+            }
+        }
+        match lower.list_xattr() {
+            Ok(names) => {
+                for name in names {
+                    inode.set_xattr(&name, &lower.get_xattr(&name)?)?;
+                }
+            }
+            Err(FsError::NotSupported) => {}
+            Err(err) => return Err(err),
+        }
+        let mut new = inode.metadata()?;
+        new.mode = metadata.mode;
+        new.uid = metadata.uid;
+        new.gid = metadata.gid;
+        new.atime = metadata.atime;
+        new.mtime = metadata.mtime;
+        new.ctime = metadata.ctime;
+        match inode.set_metadata(&new) {
+            Ok(()) | Err(FsError::NotSupported) => Ok(()),
+            Err(err) => Err(err),
+        }
+    };
+    if let Err(err) = copy() {
+        dir.unlink(name).ok();
+        return Err(err);
+    }
+    Ok(inode)
+}
+
+/// The INode found, none if there is none
+fn found(result: Result<Arc<dyn INode>>) -> Result<Option<Arc<dyn INode>>> {
+    match result {
+        Ok(inode) => Ok(Some(inode)),
+        Err(FsError::EntryNotFound) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+fn is_whiteout(inode: &dyn INode) -> Result<bool> {
+    let metadata = inode.metadata()?;
+    Ok(metadata.type_ == FileType::CharDevice && metadata.rdev == 0)
+}
+
+fn is_opaque(inode: &dyn INode) -> bool {
+    inode
+        .get_xattr(OPAQUE_XATTR)
+        .map_or(false, |value| value == b"y")
+}
+
+impl Drop for OverlayINode {
+    fn drop(&mut self) {
+        let mut inodes = self.fs.inodes.write();
+        // unless it is another INode of the id, found after this one was removed
+        if let Some(inode) = inodes.get(&self.id) {
+            if inode.strong_count() == 0 {
+                inodes.remove(&self.id);
+            }
+        }
+    }
+}
+
+impl FileSystem for OverlayFS {
+    fn sync(&self) -> Result<()> {
+        self.upper.sync()
+    }
+
+    fn root_inode(&self) -> Arc<dyn INode> {
+        self.root_inode()
+    }
+
+    /// Usage of the upper layer, to which all is written
+    fn info(&self) -> FsInfo {
+        self.upper.info()
+    }
+
+    fn capabilities(&self) -> FsCapabilities {
+        self.upper.capabilities()
+    }
+
+    fn sync_metadata(&self) -> Result<()> {
+        self.upper.sync_metadata()
+    }
+}
+
+impl INode for OverlayINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.real().read_at(offset, buf)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.copy_up()?.write_at(offset, buf)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        self.real().poll()
+    }
+
+    /// Metadata of the layer it is seen in, with the id of the overlay
+    fn metadata(&self) -> Result<Metadata> {
+        let mut metadata = self.real().metadata()?;
+        metadata.inode = self.id;
+        Ok(metadata)
+    }
+
+    fn set_metadata(&self, metadata: &Metadata) -> Result<()> {
+        self.copy_up()?.set_metadata(metadata)
+    }
+
+    fn sync_all(&self) -> Result<()> {
+        match self.upper() {
+            Some(inode) => inode.sync_all(),
+            None => Ok(()),
+        }
+    }
+
+    fn sync_data(&self) -> Result<()> {
+        match self.upper() {
+            Some(inode) => inode.sync_data(),
+            None => Ok(()),
+        }
+    }
+
+    fn resize(&self, len: usize) -> Result<()> {
+        self.copy_up()?.resize(len)
+    }
+
+    fn create2(
+        &self,
+        name: &str,
+        type_: FileType,
+        mode: u32,
+        data: usize,
+    ) -> Result<Arc<dyn INode>> {
+        if !self.is_dir()? {
+            return Err(FsError::NotDir);
+        }
+        match self.find_child(name) {
+            Ok(_) => return Err(FsError::EntryExist),
+            Err(FsError::EntryNotFound) => {}
+            Err(err) => return Err(err),
+        }
+        let dir = self.copy_up()?;
+        let whiteout = self.clear_whiteout(&dir, name)?;
+        self.changed();
+        let inode = dir.create2(name, type_, mode, data)?;
+        // hiding the lower dir removed
+        if type_ == FileType::Dir && whiteout {
+            if let Err(err) = inode.set_xattr(OPAQUE_XATTR, b"y") {
+                dir.unlink(name).ok();
+                dir.create2(name, FileType::CharDevice, 0, 0).ok();
+                return Err(err);
+            }
+        }
+        let place = Place {
+            parent: self.self_ref.upgrade(),
+            name: String::from(name),
+        };
+        Ok(self.fs.get_inode(Some(inode), None, place)?)
+    }
+
+    fn dir_defaults(&self) -> Result<DirDefaults> {
+        self.real().dir_defaults()
+    }
+
+    fn set_dir_defaults(&self, defaults: DirDefaults) -> Result<()> {
+        self.copy_up()?.set_dir_defaults(defaults)
+    }
+
+    fn link(&self, name: &str, other: &Arc<dyn INode>) -> Result<()> {
+        let other = other.downcast_ref::<Self>().ok_or(FsError::NotSameFs)?;
+        if !Arc::ptr_eq(&self.fs, &other.fs) {
+            return Err(FsError::NotSameFs);
+        }
+        if other.is_dir()? {
+            return Err(FsError::IsDir);
+        }
+        match self.find_child(name) {
+            Ok(_) => return Err(FsError::EntryExist),
+            Err(FsError::EntryNotFound) => {}
+            Err(err) => return Err(err),
+        }
+        let inode = other.copy_up()?;
+        let dir = self.copy_up()?;
+        self.clear_whiteout(&dir, name)?;
+        self.changed();
+        dir.link(name, &inode)
+    }
+
+    fn unlink(&self, name: &str) -> Result<()> {
+        if name == "." || name == ".." {
+            return Err(FsError::DirNotEmpty);
+        }
+        let child = self.find_child(name)?;
+        self.remove(name, &child)
+    }
+
+    fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> Result<()> {
+        let target = target.downcast_ref::<Self>().ok_or(FsError::NotSameFs)?;
+        if !Arc::ptr_eq(&self.fs, &target.fs) {
+            return Err(FsError::NotSameFs);
+        }
+        let child = self.find_child(old_name)?;
+        let is_dir = child.is_dir()?;
+        if is_dir && child.lower.is_some() {
+            return Err(FsError::NotSameFs);
+        }
+        // replacing the entry there
+        match target.find_child(new_name) {
+            Ok(ref other) if other.id == child.id => return Ok(()),
+            Ok(other) => {
+                match (is_dir, other.is_dir()?) {
+                    (true, false) => return Err(FsError::NotDir),
+                    (false, true) => return Err(FsError::IsDir),
+                    _ => {}
+                }
+                target.remove(new_name, &other)?;
+            }
+            Err(FsError::EntryNotFound) => {}
+            Err(err) => return Err(err),
+        }
+        let dir = self.copy_up()?;
+        let inode = child.copy_up()?;
+        let target_dir = target.copy_up()?;
+        let whiteout = target.clear_whiteout(&target_dir, new_name)?;
+        self.changed();
+        target.changed();
+        dir.move_(old_name, &target_dir, new_name)?;
+        if is_dir && whiteout {
+            inode.set_xattr(OPAQUE_XATTR, b"y")?;
+        }
+        if self.lower_has(old_name)? {
+            dir.create2(old_name, FileType::CharDevice, 0, 0)?;
+        }
+        *child.place.write() = Place {
+            parent: target.self_ref.upgrade(),
+            name: String::from(new_name),
+        };
+        Ok(())
+    }
+
+    fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
+        if !self.is_dir()? {
+            return Err(FsError::NotDir);
+        }
+        match name {
+            "" | "." => Ok(self.self_ref.upgrade().unwrap()),
+            ".." => match &self.place.read().parent {
+                Some(parent) => Ok(parent.clone()),
+                None => Ok(self.self_ref.upgrade().unwrap()),
+            },
+            _ => Ok(self.find_child(name)?),
+        }
+    }
+
+    fn get_entry(&self, id: usize) -> Result<String> {
+        if !self.is_dir()? {
+            return Err(FsError::NotDir);
+        }
+        self.names()?
+            .into_iter()
+            .nth(id)
+            .ok_or(FsError::EntryNotFound)
+    }
+
+    /// Commands setting a value, as `FS_IOC_SETFLAGS`, copy the INode up first
+    fn io_control(&self, cmd: u32, data: usize) -> Result<()> {
+        if io_control_sets(cmd) {
+            return self.copy_up()?.io_control(cmd, data);
+        }
+        self.real().io_control(cmd, data)
+    }
+
+    fn get_xattr(&self, name: &str) -> Result<Vec<u8>> {
+        if name.starts_with(XATTR_PREFIX) {
+            return Err(FsError::EntryNotFound);
+        }
+        self.real().get_xattr(name)
+    }
+
+    fn set_xattr(&self, name: &str, value: &[u8]) -> Result<()> {
+        if name.starts_with(XATTR_PREFIX) {
+            return Err(FsError::NotPermitted);
+        }
+        self.copy_up()?.set_xattr(name, value)
+    }
+
+    fn list_xattr(&self) -> Result<Vec<String>> {
+        let mut names = self.real().list_xattr()?;
+        names.retain(|name| !name.starts_with(XATTR_PREFIX));
+        Ok(names)
+    }
+
+    fn remove_xattr(&self, name: &str) -> Result<()> {
+        if name.starts_with(XATTR_PREFIX) {
+            return Err(FsError::NotPermitted);
+        }
+        self.copy_up()?.remove_xattr(name)
+    }
+
+    fn fs(&self) -> Arc<dyn FileSystem> {
+        self.fs.clone()
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
@@ -0,0 +1,194 @@
+use crate::*;
+use rcore_fs_ramfs::RamFS;
+use rcore_fs_sfs::SimpleFileSystem;
+use std::sync::Mutex;
+
+fn write(dir: &Arc<dyn INode>, name: &str, content: &[u8]) -> Arc<dyn INode> {
+    let file = dir.create(name, FileType::File, 0o644).unwrap();
+    file.write_at(0, content).unwrap();
+    file
+}
+
+fn read(inode: &Arc<dyn INode>) -> Vec<u8> {
+    let mut buf = vec![0u8; inode.metadata().unwrap().size];
+    let len = inode.read_at(0, &mut buf).unwrap();
+    buf.truncate(len);
+    buf
+}
+
+/// lower: /etc/{hosts, passwd}, /bin/sh, /readme
+/// upper: /etc/hosts, /new
+fn overlay() -> (Arc<RamFS>, Arc<RamFS>, Arc<dyn INode>) {
+    let lower = RamFS::new();
+    let root = lower.root_inode();
+    let etc = root.create("etc", FileType::Dir, 0o755).unwrap();
+    write(&etc, "hosts", b"lower hosts");
+    write(&etc, "passwd", b"root");
+    let bin = root.create("bin", FileType::Dir, 0o755).unwrap();
+    write(&bin, "sh", b"#!");
+    write(&root, "readme", b"lower");
+
+    let upper = RamFS::new();
+    let root = upper.root_inode();
+    let etc = root.create("etc", FileType::Dir, 0o755).unwrap();
+    write(&etc, "hosts", b"upper hosts");
+    write(&root, "new", b"new");
+
+    let fs = OverlayFS::new(lower.clone(), upper.clone());
+    (lower, upper, fs.root_inode())
+}
+
+#[test]
+fn merge() {
+    let (_, _, root) = overlay();
+    assert_eq!(
+        root.list().unwrap(),
+        [".", "..", "etc", "new", "bin", "readme"]
+    );
+    assert_eq!(
+        root.find("etc").unwrap().list().unwrap(),
+        [".", "..", "hosts", "passwd"]
+    );
+    // the upper layer hides the lower one
+    assert_eq!(read(&root.lookup("etc/hosts").unwrap()), b"upper hosts");
+    assert_eq!(read(&root.lookup("etc/passwd").unwrap()), b"root");
+    assert_eq!(read(&root.lookup("bin/sh").unwrap()), b"#!");
+    let etc = root.find("etc").unwrap();
+    assert!(etc.find("..").unwrap().is_same(&*root).unwrap());
+    assert!(root.find("..").unwrap().is_same(&*root).unwrap());
+    assert!(root.lookup("bin/../etc").unwrap().is_same(&*etc).unwrap());
+}
+
+#[test]
+fn copy_up() {
+    let (lower, upper, root) = overlay();
+    let sh = root.lookup("bin/sh").unwrap();
+    let id = sh.metadata().unwrap().inode;
+    sh.set_xattr("user.tag", b"shell").unwrap();
+    sh.write_at(2, b"/bin/sh").unwrap();
+    assert_eq!(read(&sh), b"#!/bin/sh");
+    assert_eq!(sh.metadata().unwrap().mode, 0o644);
+    // the same INode, before and after it is copied up
+    let found = root.lookup("bin/sh").unwrap();
+    assert_eq!(found.metadata().unwrap().inode, id);
+    assert_eq!(read(&found), b"#!/bin/sh");
+    assert_eq!(sh.list_xattr().unwrap(), ["user.tag"]);
+
+    // copied up with its dir, the lower layer untouched
+    let upper_sh = upper.root_inode().lookup("bin/sh").unwrap();
+    assert_eq!(read(&upper_sh), b"#!/bin/sh");
+    assert_eq!(upper_sh.get_xattr("user.tag").unwrap(), b"shell");
+    assert_eq!(read(&lower.root_inode().lookup("bin/sh").unwrap()), b"#!");
+    assert_eq!(root.find("bin").unwrap().list().unwrap(), [".", "..", "sh"]);
+
+    // the overlay's own xattrs can not be reached
+    assert_eq!(sh.set_xattr(OPAQUE_XATTR, b"y"), Err(FsError::NotPermitted));
+    assert_eq!(
+        root.find("readme").unwrap().resize(2),
+        Ok(()),
+        "copied up to be truncated"
+    );
+    assert_eq!(read(&root.find("readme").unwrap()), b"lo");
+}
+
+#[test]
+fn whiteout() {
+    let (lower, upper, root) = overlay();
+    root.unlink("readme").unwrap();
+    assert_eq!(root.find("readme").err(), Some(FsError::EntryNotFound));
+    assert_eq!(root.list().unwrap(), [".", "..", "etc", "new", "bin"]);
+    let whiteout = upper
+        .root_inode()
+        .find("readme")
+        .unwrap()
+        .metadata()
+        .unwrap();
+    assert_eq!((whiteout.type_, whiteout.rdev), (FileType::CharDevice, 0));
+    assert!(lower.root_inode().find("readme").is_ok());
+
+    // made again over the whiteout
+    write(&root, "readme", b"again");
+    assert_eq!(read(&root.find("readme").unwrap()), b"again");
+
+    // a dir of both layers is removed once empty in both
+    let etc = root.find("etc").unwrap();
+    assert_eq!(root.unlink("etc"), Err(FsError::DirNotEmpty));
+    etc.unlink("hosts").unwrap();
+    etc.unlink("passwd").unwrap();
+    assert_eq!(etc.list().unwrap(), [".", ".."]);
+    root.unlink("etc").unwrap();
+    assert_eq!(root.find("etc").err(), Some(FsError::EntryNotFound));
+
+    // and made again opaque, the lower dir hidden
+    let etc = root.create("etc", FileType::Dir, 0o755).unwrap();
+    assert_eq!(etc.list().unwrap(), [".", ".."]);
+    assert_eq!(etc.find("passwd").err(), Some(FsError::EntryNotFound));
+    assert_eq!(etc.list_xattr().unwrap(), Vec::<String>::new());
+    let upper_etc = upper.root_inode().find("etc").unwrap();
+    assert_eq!(upper_etc.get_xattr(OPAQUE_XATTR).unwrap(), b"y");
+    assert_eq!(
+        lower.root_inode().find("etc").unwrap().list().unwrap(),
+        [".", "..", "hosts", "passwd"]
+    );
+}
+
+#[test]
+fn move_and_link() {
+    let (lower, _, root) = overlay();
+    let bin = root.find("bin").unwrap();
+    let sh = bin.find("sh").unwrap();
+    let id = sh.metadata().unwrap().inode;
+    bin.move_("sh", &root, "sh").unwrap();
+    assert_eq!(bin.list().unwrap(), [".", ".."]);
+    let moved = root.find("sh").unwrap();
+    assert_eq!(moved.metadata().unwrap().inode, id);
+    assert_eq!(read(&moved), b"#!");
+    assert!(lower.root_inode().lookup("bin/sh").is_ok());
+
+    // replacing a file of the lower layer
+    root.move_("sh", &root, "readme").unwrap();
+    assert_eq!(read(&root.find("readme").unwrap()), b"#!");
+    assert_eq!(root.find("sh").err(), Some(FsError::EntryNotFound));
+
+    // dirs of the lower layer are copied, not moved
+    assert_eq!(root.move_("bin", &root, "sbin"), Err(FsError::NotSameFs));
+
+    let passwd = root.lookup("etc/passwd").unwrap();
+    root.link("passwd", &passwd).unwrap();
+    passwd.write_at(0, b"ROOT").unwrap();
+    assert_eq!(read(&root.find("passwd").unwrap()), b"ROOT");
+    assert!(root.find("passwd").unwrap().is_same(&*passwd).unwrap());
+    assert_eq!(root.link("passwd", &passwd), Err(FsError::EntryExist));
+}
+
+fn sfs() -> Arc<SimpleFileSystem> {
+    let file = tempfile::tempfile().unwrap();
+    SimpleFileSystem::create(Arc::new(Mutex::new(file)), 32 * 4096 * 4096).unwrap()
+}
+
+#[test]
+fn set_flags() {
+    let lower = sfs();
+    write(&lower.root_inode(), "file", b"lower");
+    let fs = OverlayFS::new(lower.clone(), sfs());
+    let file = fs.root_inode().find("file").unwrap();
+    let mut flags = 0u32;
+    file.io_control(FS_IOC_GETFLAGS, &mut flags as *mut u32 as usize)
+        .unwrap();
+    assert_eq!(flags, 0);
+
+    let flags = FS_NODUMP_FL;
+    file.io_control(FS_IOC_SETFLAGS, &flags as *const u32 as usize)
+        .unwrap();
+    let mut flags = 0u32;
+    file.io_control(FS_IOC_GETFLAGS, &mut flags as *mut u32 as usize)
+        .unwrap();
+    assert_eq!(flags, FS_NODUMP_FL);
+    assert_eq!(read(&file), b"lower");
+    // the lower layer is left as it is
+    let lower = lower.root_inode().find("file").unwrap();
+    lower
+        .io_control(FS_IOC_GETFLAGS, &mut flags as *mut u32 as usize)
+        .unwrap();
+    assert_eq!(flags, 0);
+}
@@ -0,0 +1,532 @@
+//! Process file system: virtual files registered by the kernel, their content made on each
+//! read, as /proc of Linux
+//!
+//! Besides what the kernel adds by `ProcFS::add()`, there are:
+//!
+//! * `self/`: the dir of the current process, filled by the kernel with nodes which tell of
+//!   the process running when they are read
+//! * `self/mounts`, `self/mountstats` and `mounts`: the mounts, see `ProcFS::add_mounts()`
+//! * `info` and `stats` of a file system, see `ProcFS::add_fs()`
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+extern crate alloc;
+
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::any::Any;
+use core::fmt::Write;
+use rcore_fs::vfs::*;
+use rcore_fs_mountfs::{MountFS, MountFlags};
+use spin::RwLock;
+
+#[cfg(test)]
+mod tests;
+
+/// A file of procfs
+pub trait ProcFile: Send + Sync {
+    /// The whole content, made anew on each read
+    fn read(&self) -> Result<Vec<u8>>;
+
+    /// Take `data` written to the file, at whatever offset
+    fn write(&self, _data: &[u8]) -> Result<()> {
+        Err(FsError::NotPermitted)
+    }
+
+    /// Can it be written? Tells its mode.
+    fn writable(&self) -> bool {
+        false
+    }
+}
+
+/// A dir of procfs whose entries are made on each lookup, e.g. one for each process
+pub trait ProcDir: Send + Sync {
+    /// Names of the entries, but "." and ".."
+    fn entries(&self) -> Vec<String>;
+
+    /// The entry `name`
+    fn find(&self, name: &str) -> Result<ProcNode>;
+}
+
+/// A node added to procfs
+#[derive(Clone)]
+pub enum ProcNode {
+    File(Arc<dyn ProcFile>),
+    Dir(Arc<dyn ProcDir>),
+    /// A symlink to a fixed target
+    SymLink(String),
+}
+
+type ReadFn = Box<dyn Fn() -> String + Send + Sync>;
+type WriteFn = Box<dyn Fn(&[u8]) -> Result<()> + Send + Sync>;
+
+/// A file of closures
+struct FnFile {
+    read: ReadFn,
+    write: Option<WriteFn>,
+}
+
+impl ProcFile for FnFile {
+    fn read(&self) -> Result<Vec<u8>> {
+        Ok((self.read)().into_bytes())
+    }
+
+    fn write(&self, data: &[u8]) -> Result<()> {
+        match &self.write {
+            Some(write) => write(data),
+            None => Err(FsError::NotPermitted),
+        }
+    }
+
+    fn writable(&self) -> bool {
+        self.write.is_some()
+    }
+}
+
+impl ProcNode {
+    /// A read-only file, its content made by `read`
+    pub fn file(read: impl Fn() -> String + Send + Sync + 'static) -> Self {
+        ProcNode::File(Arc::new(FnFile {
+            read: Box::new(read),
+            write: None,
+        }))
+    }
+
+    /// A file, its content made by `read`, taking what is written by `write`
+    pub fn file_rw(
+        read: impl Fn() -> String + Send + Sync + 'static,
+        write: impl Fn(&[u8]) -> Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        ProcNode::File(Arc::new(FnFile {
+            read: Box::new(read),
+            write: Some(Box::new(write)),
+        }))
+    }
+}
+
+/// A dir of the nodes added by `ProcFS::add()`
+#[derive(Default)]
+struct StaticDir {
+    entries: RwLock<BTreeMap<String, Entry>>,
+}
+
+#[derive(Clone)]
+enum Entry {
+    Static(Arc<StaticDir>),
+    Node(ProcNode),
+}
+
+/// Process file system
+///
+/// It should be mounted at /proc.
+/// Nodes are added or removed by the kernel through `add()` and `remove()`,
+/// and can not be created, removed or renamed from the INodes.
+pub struct ProcFS {
+    root: Arc<StaticDir>,
+    self_ref: Weak<ProcFS>,
+}
+
+impl ProcFS {
+    pub fn new() -> Arc<Self> {
+        let fs = ProcFS {
+            root: Arc::default(),
+            self_ref: Weak::default(),
+        }
+        .wrap();
+        fs.root
+            .entries
+            .write()
+            .insert(String::from("self"), Entry::Static(Arc::default()));
+        fs
+    }
+
+    /// Wrap pure ProcFS with Arc
+    /// Used in constructors
+    fn wrap(self) -> Arc<Self> {
+        // Create an Arc, make a Weak from it, then put it into the struct.
+        // It's a little tricky.
+        let fs = Arc::new(self);
+        let weak = Arc::downgrade(&fs);
+        let ptr = Arc::into_raw(fs) as *mut Self;
+        unsafe {
+            (*ptr).self_ref = weak;
+            Arc::from_raw(ptr)
+        }
+    }
+
+    /// Strong type version of `root_inode`
+    pub fn root_inode(&self) -> Arc<ProcINode> {
+        Arc::new(ProcINode {
+            entry: Entry::Static(self.root.clone()),
+            path: String::new(),
+            parent: None,
+            fs: self.self_ref.upgrade().unwrap(),
+        })
+    }
+
+    /// Add `node` at `path` from the root, e.g. "self/status", making the dirs on the way
+    pub fn add(&self, path: &str, node: ProcNode) -> Result<()> {
+        let (dir, name) = self.parent_of(path, true)?;
+        let mut entries = dir.entries.write();
+        if entries.contains_key(name) {
+            return Err(FsError::EntryExist);
+        }
+        entries.insert(String::from(name), Entry::Node(node));
+        Ok(())
+    }
+
+    /// Remove the node or dir at `path`, with all added under it
+    pub fn remove(&self, path: &str) -> Result<()> {
+        let (dir, name) = self.parent_of(path, false)?;
+        dir.entries
+            .write()
+            .remove(name)
+            .ok_or(FsError::EntryNotFound)?;
+        Ok(())
+    }
+
+    /// Add the mounts under the root told by `root`, that of the namespace of the current
+    /// process, as `self/mounts`, one `<path> <options>` per line, `self/mountstats`, with the
+    /// `FsInfo` and `FsStats` of each, and the symlink `mounts` to `self/mounts`.
+    ///
+    /// `root` should not hold the `MountFS` on which procfs is mounted,
+    /// but a `Weak` to it, or there is a cycle.
+    pub fn add_mounts(
+        &self,
+        root: impl Fn() -> Option<Arc<MountFS>> + Send + Sync + 'static,
+    ) -> Result<()> {
+        let root: Arc<RootFn> = Arc::new(root);
+        let mounts = MountsFile {
+            root: root.clone(),
+            stats: false,
+        };
+        self.add("self/mounts", ProcNode::File(Arc::new(mounts)))?;
+        let mountstats = MountsFile { root, stats: true };
+        self.add("self/mountstats", ProcNode::File(Arc::new(mountstats)))?;
+        self.add("mounts", ProcNode::SymLink(String::from("self/mounts")))
+    }
+
+    /// Add `<path>/info`, the `FsInfo` of `fs`, and `<path>/stats`, its `FsStats`.
+    /// They fail with `EntryNotFound` once `fs` is dropped.
+    pub fn add_fs(&self, path: &str, fs: &Arc<dyn FileSystem>) -> Result<()> {
+        let info = FsFile {
+            fs: Arc::downgrade(fs),
+            stats: false,
+        };
+        self.add(&format!("{}/info", path), ProcNode::File(Arc::new(info)))?;
+        let stats = FsFile {
+            fs: Arc::downgrade(fs),
+            stats: true,
+        };
+        self.add(&format!("{}/stats", path), ProcNode::File(Arc::new(stats)))
+    }
+
+    /// The static dir holding `path`, making the dirs on the way if `create`,
+    /// and the last name of it
+    fn parent_of<'a>(&self, path: &'a str, create: bool) -> Result<(Arc<StaticDir>, &'a str)> {
+        let mut names: Vec<&str> = path.split('/').filter(|name| !name.is_empty()).collect();
+        if names.iter().any(|&name| name == "." || name == "..") {
+            return Err(FsError::InvalidParam);
+        }
+        let name = names.pop().ok_or(FsError::InvalidParam)?;
+        let mut dir = self.root.clone();
+        for next in names {
+            let mut entries = dir.entries.write();
+            let child = match entries.get(next) {
+                Some(Entry::Static(child)) => child.clone(),
+                Some(Entry::Node(_)) => return Err(FsError::NotDir),
+                None if create => {
+                    let child = Arc::<StaticDir>::default();
+                    entries.insert(String::from(next), Entry::Static(child.clone()));
+                    child
+                }
+                None => return Err(FsError::EntryNotFound),
+            };
+            drop(entries);
+            dir = child;
+        }
+        Ok((dir, name))
+    }
+}
+
+type RootFn = dyn Fn() -> Option<Arc<MountFS>> + Send + Sync;
+
+/// `self/mounts`, or `self/mountstats` if `stats`
+struct MountsFile {
+    root: Arc<RootFn>,
+    stats: bool,
+}
+
+impl ProcFile for MountsFile {
+    fn read(&self) -> Result<Vec<u8>> {
+        let mut text = String::new();
+        let root = match (self.root)() {
+            Some(root) => root,
+            None => return Ok(Vec::new()),
+        };
+        for mount in root.mounts()? {
+            let path = escape(&mount.path);
+            if self.stats {
+                let fs = mount.fs.inner();
+                writeln!(text, "mounted on {}", path).unwrap();
+                write!(text, "{}{}", fs.info(), fs.snapshot_stats()).unwrap();
+                writeln!(text).unwrap();
+                continue;
+            }
+            let flags = mount.fs.flags();
+            let mut options = String::from(match flags.contains(MountFlags::READ_ONLY) {
+                true => "ro",
+                false => "rw",
+            });
+            if flags.contains(MountFlags::NO_SUID) {
+                options += ",nosuid";
+            }
+            if flags.contains(MountFlags::NO_EXEC) {
+                options += ",noexec";
+            }
+            if mount.fs.is_bind() {
+                options += ",bind";
+            }
+            writeln!(text, "{} {}", path, options).unwrap();
+        }
+        Ok(text.into_bytes())
+    }
+}
+
+/// Escape the spaces of `path`, and the other chars splitting fields or lines, as Linux
+fn escape(path: &str) -> String {
+    let mut escaped = String::new();
+    for c in path.chars() {
+        match c {
+            ' ' | '\t' | '\n' | '\\' => write!(escaped, "\\{:03o}", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// `info` of a file system, or `stats` if `stats`
+struct FsFile {
+    fs: Weak<dyn FileSystem>,
+    stats: bool,
+}
+
+impl ProcFile for FsFile {
+    fn read(&self) -> Result<Vec<u8>> {
+        let fs = self.fs.upgrade().ok_or(FsError::EntryNotFound)?;
+        let text = match self.stats {
+            true => fs.snapshot_stats().to_string(),
+            false => fs.info().to_string(),
+        };
+        Ok(text.into_bytes())
+    }
+}
+
+impl FileSystem for ProcFS {
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn root_inode(&self) -> Arc<dyn INode> {
+        self.root_inode()
+    }
+
+    fn info(&self) -> FsInfo {
+        FsInfo {
+            bsize: 0,
+            frsize: 0,
+            blocks: 0,
+            bfree: 0,
+            bavail: 0,
+            files: 0,
+            ffree: 0,
+            namemax: 0,
+        }
+    }
+}
+
+/// INode for `ProcFS`
+#[derive(Clone)]
+pub struct ProcINode {
+    entry: Entry,
+    /// Path from the root, its id made from it
+    path: String,
+    /// None for the root
+    parent: Option<Arc<ProcINode>>,
+    fs: Arc<ProcFS>,
+}
+
+/// FNV-1a
+fn hash(path: &str) -> usize {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in path.as_bytes() {
+        hash = (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3);
+    }
+    hash as usize
+}
+
+impl ProcINode {
+    fn id(&self) -> usize {
+        match self.parent {
+            None => 1,
+            // not that of the root
+            Some(_) => hash(&self.path).max(2),
+        }
+    }
+
+    fn file(&self) -> Result<&Arc<dyn ProcFile>> {
+        match &self.entry {
+            Entry::Node(ProcNode::File(file)) => Ok(file),
+            Entry::Node(ProcNode::SymLink(_)) => Err(FsError::NotFile),
+            _ => Err(FsError::IsDir),
+        }
+    }
+
+    fn names(&self) -> Result<Vec<String>> {
+        match &self.entry {
+            Entry::Static(dir) => Ok(dir.entries.read().keys().cloned().collect()),
+            Entry::Node(ProcNode::Dir(dir)) => Ok(dir.entries()),
+            _ => Err(FsError::NotDir),
+        }
+    }
+}
+
+impl INode for ProcINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let content = match &self.entry {
+            Entry::Node(ProcNode::SymLink(target)) => target.clone().into_bytes(),
+            _ => self.file()?.read()?,
+        };
+        if offset >= content.len() {
+            return Ok(0);
+        }
+        let len = buf.len().min(content.len() - offset);
+        buf[..len].copy_from_slice(&content[offset..offset + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
+        self.file()?.write(buf)?;
+        Ok(buf.len())
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        let file = self.file()?;
+        Ok(PollStatus {
+            read: true,
+            write: file.writable(),
+            error: false,
+        })
+    }
+
+    /// The size is 0, as the content is made on read
+    fn metadata(&self) -> Result<Metadata> {
+        let (type_, mode, size) = match &self.entry {
+            Entry::Static(_) | Entry::Node(ProcNode::Dir(_)) => (FileType::Dir, 0o555, 0),
+            Entry::Node(ProcNode::File(file)) => match file.writable() {
+                true => (FileType::File, 0o644, 0),
+                false => (FileType::File, 0o444, 0),
+            },
+            Entry::Node(ProcNode::SymLink(target)) => (FileType::SymLink, 0o777, target.len()),
+        };
+        Ok(Metadata {
+            dev: 0,
+            inode: self.id(),
+            size,
+            blk_size: 0,
+            blocks: 0,
+            atime: Timespec { sec: 0, nsec: 0 },
+            mtime: Timespec { sec: 0, nsec: 0 },
+            ctime: Timespec { sec: 0, nsec: 0 },
+            type_,
+            mode,
+            nlinks: match type_ {
+                FileType::Dir => 2,
+                _ => 1,
+            },
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+        })
+    }
+
+    fn sync_all(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn sync_data(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Done by opening with `O_TRUNC`, and so ignored for a file to be written
+    fn resize(&self, _len: usize) -> Result<()> {
+        match self.file()?.writable() {
+            true => Ok(()),
+            false => Err(FsError::NotPermitted),
+        }
+    }
+
+    fn create(&self, _name: &str, _type_: FileType, _mode: u32) -> Result<Arc<dyn INode>> {
+        Err(FsError::NotSupported)
+    }
+
+    fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
+        if let Entry::Node(ProcNode::File(_)) | Entry::Node(ProcNode::SymLink(_)) = self.entry {
+            return Err(FsError::NotDir);
+        }
+        let entry = match name {
+            "" | "." => return Ok(Arc::new(self.clone())),
+            ".." => {
+                return Ok(match &self.parent {
+                    Some(parent) => parent.clone(),
+                    None => Arc::new(self.clone()),
+                })
+            }
+            name => match &self.entry {
+                Entry::Static(dir) => dir
+                    .entries
+                    .read()
+                    .get(name)
+                    .cloned()
+                    .ok_or(FsError::EntryNotFound)?,
+                Entry::Node(ProcNode::Dir(dir)) => Entry::Node(dir.find(name)?),
+                _ => return Err(FsError::NotDir),
+            },
+        };
+        Ok(Arc::new(ProcINode {
+            entry,
+            path: format!("{}/{}", self.path, name),
+            parent: Some(Arc::new(self.clone())),
+            fs: self.fs.clone(),
+        }))
+    }
+
+    fn get_entry(&self, id: usize) -> Result<String> {
+        match id {
+            0 => Ok(String::from(".")),
+            1 => Ok(String::from("..")),
+            i => self
+                .names()?
+                .into_iter()
+                .nth(i - 2)
+                .ok_or(FsError::EntryNotFound),
+        }
+    }
+
+    fn io_control(&self, _cmd: u32, _data: usize) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+
+    fn fs(&self) -> Arc<dyn FileSystem> {
+        self.fs.clone()
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
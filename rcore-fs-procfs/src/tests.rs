@@ -0,0 +1,181 @@
+use crate::*;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rcore_fs_ramfs::RamFS;
+
+fn read(inode: &Arc<dyn INode>) -> String {
+    let mut buf = [0u8; 4096];
+    let len = inode.read_at(0, &mut buf).unwrap();
+    String::from_utf8(buf[..len].to_vec()).unwrap()
+}
+
+/// One entry for each of the first `count` numbers
+struct Numbers {
+    count: usize,
+}
+
+impl ProcDir for Numbers {
+    fn entries(&self) -> Vec<String> {
+        (0..self.count).map(|i| i.to_string()).collect()
+    }
+
+    fn find(&self, name: &str) -> Result<ProcNode> {
+        let i: usize = name.parse().map_err(|_| FsError::EntryNotFound)?;
+        if i >= self.count {
+            return Err(FsError::EntryNotFound);
+        }
+        Ok(ProcNode::file(move || format!("{}\n", i * i)))
+    }
+}
+
+#[test]
+fn files() {
+    let procfs = ProcFS::new();
+    let ticks = Arc::new(AtomicUsize::new(0));
+    let ticks1 = ticks.clone();
+    procfs
+        .add(
+            "uptime",
+            ProcNode::file(move || format!("{}\n", ticks1.load(Ordering::SeqCst))),
+        )
+        .unwrap();
+    let level = Arc::new(AtomicUsize::new(1));
+    let (level1, level2) = (level.clone(), level.clone());
+    procfs
+        .add(
+            "sys/kernel/level",
+            ProcNode::file_rw(
+                move || format!("{}\n", level1.load(Ordering::SeqCst)),
+                move |data| {
+                    let text = core::str::from_utf8(data).map_err(|_| FsError::InvalidParam)?;
+                    let value = text.trim().parse().map_err(|_| FsError::InvalidParam)?;
+                    level2.store(value, Ordering::SeqCst);
+                    Ok(())
+                },
+            ),
+        )
+        .unwrap();
+    let root = procfs.root_inode() as Arc<dyn INode>;
+    assert_eq!(root.list().unwrap(), [".", "..", "self", "sys", "uptime"]);
+
+    // made anew on each read
+    let uptime = root.find("uptime").unwrap();
+    assert_eq!(read(&uptime), "0\n");
+    ticks.store(42, Ordering::SeqCst);
+    assert_eq!(read(&uptime), "42\n");
+    let mut buf = [0u8; 4];
+    assert_eq!(uptime.read_at(1, &mut buf), Ok(2));
+    assert_eq!(&buf[..2], b"2\n");
+    assert_eq!(uptime.write_at(0, b"1"), Err(FsError::NotPermitted));
+    assert_eq!(uptime.metadata().unwrap().mode, 0o444);
+
+    let file = root.lookup("sys/kernel/level").unwrap();
+    assert_eq!(file.metadata().unwrap().mode, 0o644);
+    file.resize(0).unwrap();
+    assert_eq!(file.write_at(0, b"3\n"), Ok(2));
+    assert_eq!(level.load(Ordering::SeqCst), 3);
+    assert_eq!(read(&file), "3\n");
+    assert_eq!(file.write_at(0, b"x"), Err(FsError::InvalidParam));
+
+    assert_eq!(
+        root.create("new", FileType::File, 0o644).err(),
+        Some(FsError::NotSupported)
+    );
+    assert_eq!(
+        procfs.add("uptime", ProcNode::file(String::new)),
+        Err(FsError::EntryExist)
+    );
+    assert_eq!(
+        procfs.add("uptime/x", ProcNode::file(String::new)),
+        Err(FsError::NotDir)
+    );
+    procfs.remove("sys").unwrap();
+    assert_eq!(
+        root.lookup("sys/kernel/level").err(),
+        Some(FsError::EntryNotFound)
+    );
+    assert_eq!(procfs.remove("sys"), Err(FsError::EntryNotFound));
+}
+
+#[test]
+fn dynamic_dirs() {
+    let procfs = ProcFS::new();
+    procfs
+        .add("squares", ProcNode::Dir(Arc::new(Numbers { count: 3 })))
+        .unwrap();
+    procfs
+        .add("self/exe", ProcNode::SymLink(String::from("/bin/sh")))
+        .unwrap();
+    let root = procfs.root_inode() as Arc<dyn INode>;
+    let squares = root.find("squares").unwrap();
+    assert_eq!(squares.list().unwrap(), [".", "..", "0", "1", "2"]);
+    assert_eq!(read(&squares.find("2").unwrap()), "4\n");
+    assert_eq!(squares.find("3").err(), Some(FsError::EntryNotFound));
+
+    // ids are kept, by path
+    let two = root.lookup("squares/2").unwrap();
+    assert!(two.is_same(&*squares.find("2").unwrap()).unwrap());
+    assert!(!two.is_same(&*squares.find("1").unwrap()).unwrap());
+    assert!(two.find("..").is_err());
+    assert!(root
+        .lookup("squares/../self")
+        .unwrap()
+        .is_same(&*root.find("self").unwrap())
+        .unwrap());
+    assert!(root.find("..").unwrap().is_same(&*root).unwrap());
+
+    let exe = root.lookup("self/exe").unwrap();
+    let metadata = exe.metadata().unwrap();
+    assert_eq!((metadata.type_, metadata.size), (FileType::SymLink, 7));
+    assert_eq!(read(&exe), "/bin/sh");
+}
+
+#[test]
+fn mounts_and_fs() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.root_inode();
+    let procfs = ProcFS::new();
+    let weak = Arc::downgrade(&rootfs);
+    procfs.add_mounts(move || weak.upgrade()).unwrap();
+    root.create("proc", FileType::Dir, 0o555)
+        .unwrap()
+        .mount(procfs.clone())
+        .unwrap();
+    let ramfs = RamFS::new();
+    root.create("my dir", FileType::Dir, 0o777)
+        .unwrap()
+        .mount_with(ramfs.clone(), MountFlags::READ_ONLY | MountFlags::NO_EXEC)
+        .unwrap();
+    let ramfs = ramfs as Arc<dyn FileSystem>;
+    procfs.add_fs("fs/ram", &ramfs).unwrap();
+
+    let root = root as Arc<dyn INode>;
+    assert_eq!(
+        read(&root.lookup("proc/self/mounts").unwrap()),
+        "/ rw\n/proc rw\n/my\\040dir ro,noexec\n"
+    );
+    let mounts = root.lookup_follow("proc/mounts", 1).unwrap();
+    assert_eq!(
+        read(&mounts),
+        read(&root.lookup("proc/self/mounts").unwrap())
+    );
+    let mountstats = read(&root.lookup("proc/self/mountstats").unwrap());
+    let expected = format!(
+        "mounted on /my\\040dir\n{}{}\n",
+        ramfs.info(),
+        ramfs.snapshot_stats()
+    );
+    assert!(mountstats.contains(&expected));
+
+    let info = root.lookup("proc/fs/ram/info").unwrap();
+    assert_eq!(read(&info), ramfs.info().to_string());
+    assert!(read(&info).starts_with("bsize "));
+    assert_eq!(
+        read(&root.lookup("proc/fs/ram/stats").unwrap()),
+        FsStats::default().to_string()
+    );
+    drop(ramfs);
+    assert!(root.lookup("my dir").is_ok());
+    rootfs.umount("my dir").unwrap();
+    let mut buf = [0u8; 16];
+    assert_eq!(info.read_at(0, &mut buf), Err(FsError::EntryNotFound));
+}
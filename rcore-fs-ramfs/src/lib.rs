@@ -5,16 +5,54 @@ extern crate log;
 
 use alloc::{
     collections::BTreeMap,
-    string::{String, ToString},
+    string::String,
     sync::{Arc, Weak},
+    vec,
     vec::Vec,
 };
 use core::any::Any;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rcore_fs::dev::TimeProvider;
 use rcore_fs::vfs::*;
 use spin::{RwLock, RwLockWriteGuard};
 
+use self::swap::Content;
+pub use self::swap::{Swap, PAGE_SIZE};
+
+mod swap;
+#[cfg(test)]
+mod tests;
+
 pub struct RamFS {
     root: Arc<LockedINode>,
+    /// Clock for timestamps, which are left alone without it
+    time: Option<&'static dyn TimeProvider>,
+    limits: Limits,
+    /// Bytes held by the files, see `Limits::max_bytes`
+    bytes: AtomicUsize,
+    /// INodes alive, those unlinked but still open among them
+    inodes: AtomicUsize,
+    /// Where cold pages are written out to, if anywhere
+    swap: Option<Swap>,
+}
+
+/// Most a RamFS holds, so that its files can not take all the memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Bytes of the content and extended attributes of the files
+    pub max_bytes: usize,
+    /// Number of inodes, the root among them
+    pub max_inodes: usize,
+}
+
+impl Default for Limits {
+    /// No limits
+    fn default() -> Self {
+        Limits {
+            max_bytes: usize::max_value(),
+            max_inodes: usize::max_value(),
+        }
+    }
 }
 
 impl FileSystem for RamFS {
@@ -26,27 +64,75 @@ impl FileSystem for RamFS {
         Arc::clone(&self.root) as _
     }
 
+    /// Usage in bytes, as blocks of 1 byte
     fn info(&self) -> FsInfo {
+        let bfree = self.limits.max_bytes - self.bytes.load(Ordering::SeqCst);
         FsInfo {
-            bsize: 0,
-            frsize: 0,
-            blocks: 0,
-            bfree: 0,
-            bavail: 0,
-            files: 0,
-            ffree: 0,
-            namemax: 0,
+            bsize: 1,
+            frsize: 1,
+            blocks: self.limits.max_bytes,
+            bfree,
+            bavail: bfree,
+            files: self.limits.max_inodes,
+            ffree: self
+                .limits
+                .max_inodes
+                .saturating_sub(self.inodes.load(Ordering::SeqCst)),
+            namemax: usize::max_value(),
+        }
+    }
+
+    fn capabilities(&self) -> FsCapabilities {
+        FsCapabilities {
+            features: FsFeatures::SYMLINK
+                | FsFeatures::HARDLINK
+                | FsFeatures::XATTR
+                | FsFeatures::CASE_SENSITIVE,
+            namemax: usize::max_value(),
         }
     }
 }
 
 impl RamFS {
+    /// A new RamFS without a clock, so timestamps are only set by `set_metadata`
     pub fn new() -> Arc<Self> {
+        Self::_new(None, Limits::default(), None)
+    }
+
+    /// A new RamFS with timestamps from `time`
+    pub fn with_time(time: &'static dyn TimeProvider) -> Arc<Self> {
+        Self::_new(Some(time), Limits::default(), None)
+    }
+
+    /// A new RamFS holding at most `limits`, failing with `NoDeviceSpace` beyond, with
+    /// timestamps from `time` if any
+    pub fn with_limits(time: Option<&'static dyn TimeProvider>, limits: Limits) -> Arc<Self> {
+        Self::_new(time, limits, None)
+    }
+
+    /// A new RamFS as `with_limits()`, writing out its cold pages to `swap`, see `swap`
+    pub fn with_swap(
+        time: Option<&'static dyn TimeProvider>,
+        limits: Limits,
+        swap: Swap,
+    ) -> Arc<Self> {
+        Self::_new(time, limits, Some(swap))
+    }
+
+    fn _new(
+        time: Option<&'static dyn TimeProvider>,
+        limits: Limits,
+        swap: Option<Swap>,
+    ) -> Arc<Self> {
         let root = Arc::new(LockedINode(RwLock::new(RamFSINode {
             this: Weak::default(),
             parent: Weak::default(),
             children: BTreeMap::new(),
-            content: Vec::new(),
+            cookies: BTreeMap::new(),
+            next_cookie: 2,
+            content: Content::default(),
+            defaults: DirDefaults::default(),
+            xattrs: BTreeMap::new(),
             extra: Metadata {
                 dev: 0,
                 inode: new_inode_id(),
@@ -65,8 +151,20 @@ impl RamFS {
             },
             fs: Weak::default(),
         })));
-        let fs = Arc::new(RamFS { root });
+        let fs = Arc::new(RamFS {
+            root,
+            time,
+            limits,
+            bytes: AtomicUsize::new(0),
+            inodes: AtomicUsize::new(1),
+            swap,
+        });
         let mut root = fs.root.0.write();
+        if let Some(now) = fs.now() {
+            root.extra.atime = now;
+            root.extra.mtime = now;
+            root.extra.ctime = now;
+        }
         root.parent = Arc::downgrade(&fs.root);
         root.this = Arc::downgrade(&fs.root);
         root.fs = Arc::downgrade(&fs);
@@ -75,6 +173,26 @@ impl RamFS {
         drop(root);
         fs
     }
+
+    /// Current time, if there is a clock
+    fn now(&self) -> Option<Timespec> {
+        self.time.map(|time| time.current_time())
+    }
+
+    /// Take `n` more of `used`, failing with `NoDeviceSpace` beyond `max`
+    fn reserve(used: &AtomicUsize, max: usize, n: usize) -> Result<()> {
+        let mut old = used.load(Ordering::SeqCst);
+        loop {
+            let new = old
+                .checked_add(n)
+                .filter(|&new| new <= max)
+                .ok_or(FsError::NoDeviceSpace)?;
+            match used.compare_exchange_weak(old, new, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return Ok(()),
+                Err(now) => old = now,
+            }
+        }
+    }
 }
 
 struct RamFSINode {
@@ -82,29 +200,133 @@ struct RamFSINode {
     parent: Weak<LockedINode>,
     /// Reference to myself
     this: Weak<LockedINode>,
-    /// Reference to children INodes
-    children: BTreeMap<String, Arc<LockedINode>>,
+    /// Reference to children INodes with their cookies, by name
+    children: BTreeMap<String, (Arc<LockedINode>, usize)>,
+    /// Names of the children by their cookies of `get_entry_from()`, in the order added
+    cookies: BTreeMap<usize, String>,
+    /// Cookie of the next child added, those of `.` and `..` being 0 and 1
+    next_cookie: usize,
     /// Content of the file
-    content: Vec<u8>,
+    content: Content,
+    /// Defaults for entries created in the directory
+    defaults: DirDefaults,
+    /// Extended attributes
+    xattrs: BTreeMap<String, Vec<u8>>,
     /// INode metadata
     extra: Metadata,
     /// Reference to FS
     fs: Weak<RamFS>,
 }
 
+impl RamFSINode {
+    /// Current time, if the FS has a clock
+    fn now(&self) -> Option<Timespec> {
+        self.fs.upgrade().and_then(|fs| fs.now())
+    }
+
+    /// Set mtime and ctime to now, after the content is changed
+    fn touch(&mut self) {
+        if let Some(now) = self.now() {
+            self.extra.mtime = now;
+            self.extra.ctime = now;
+        }
+    }
+
+    /// Set ctime to now, after the metadata is changed
+    fn touch_ctime(&mut self) {
+        if let Some(now) = self.now() {
+            self.extra.ctime = now;
+        }
+    }
+
+    /// Take `new` bytes in place of `old`, within the limits of the FS
+    fn charge(&self, old: usize, new: usize) -> Result<()> {
+        if let Some(fs) = self.fs.upgrade() {
+            match new > old {
+                true => RamFS::reserve(&fs.bytes, fs.limits.max_bytes, new - old)?,
+                false => {
+                    fs.bytes.fetch_sub(old - new, Ordering::SeqCst);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Add child `inode` as `name`, with the next cookie
+    fn add_child(&mut self, name: &str, inode: Arc<LockedINode>) {
+        let cookie = self.next_cookie;
+        self.next_cookie += 1;
+        self.children.insert(String::from(name), (inode, cookie));
+        self.cookies.insert(cookie, String::from(name));
+    }
+
+    /// Remove child `name`, and its cookie
+    fn remove_child(&mut self, name: &str) {
+        if let Some((_, cookie)) = self.children.remove(name) {
+            self.cookies.remove(&cookie);
+        }
+    }
+
+    /// Set extended attribute `name` to `value`, within the limits of the FS
+    fn set_xattr(&mut self, name: &str, value: &[u8]) -> Result<()> {
+        let old = self
+            .xattrs
+            .get(name)
+            .map_or(0, |old| name.len() + old.len());
+        self.charge(old, name.len() + value.len())?;
+        self.xattrs.insert(String::from(name), value.to_vec());
+        Ok(())
+    }
+
+    /// Resize the content to `len`, within the limits of the FS
+    fn resize_content(&mut self, len: usize) -> Result<()> {
+        let old = self.content.len;
+        if len > old {
+            self.charge(old, len)?;
+        }
+        self.resize_pages(len)?;
+        if len < old {
+            self.charge(old, len)?;
+        }
+        Ok(())
+    }
+
+    /// Bytes of the content and extended attributes
+    fn bytes(&self) -> usize {
+        let xattrs: usize = self.xattrs.iter().map(|(k, v)| k.len() + v.len()).sum();
+        self.content.len + xattrs
+    }
+}
+
+impl Drop for RamFSINode {
+    /// Give back what it holds, once neither linked nor open
+    fn drop(&mut self) {
+        self.drop_pages();
+        if let Some(fs) = self.fs.upgrade() {
+            fs.bytes.fetch_sub(self.bytes(), Ordering::SeqCst);
+            fs.inodes.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
 struct LockedINode(RwLock<RamFSINode>);
 
 impl INode for LockedINode {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
-        let file = self.0.read();
-        if file.extra.type_ == FileType::Dir {
-            return Err(FsError::IsDir);
+        {
+            let file = self.0.read();
+            if file.extra.type_ == FileType::Dir {
+                return Err(FsError::IsDir);
+            }
+            if let Some(len) = file.content.read(offset, buf) {
+                return Ok(len);
+            }
         }
-        let start = file.content.len().min(offset);
-        let end = file.content.len().min(offset + buf.len());
-        let src = &file.content[start..end];
-        buf[0..src.len()].copy_from_slice(src);
-        Ok(src.len())
+        // its pages swapped out read back first
+        let mut file = self.0.write();
+        let len = file.content.len;
+        file.page_in(len.min(offset)..len.min(offset + buf.len()))?;
+        Ok(file.content.read(offset, buf).unwrap())
     }
 
     fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
@@ -112,12 +334,12 @@ impl INode for LockedINode {
         if file.extra.type_ == FileType::Dir {
             return Err(FsError::IsDir);
         }
-        let content = &mut file.content;
-        if offset + buf.len() > content.len() {
-            content.resize(offset + buf.len(), 0);
+        if offset + buf.len() > file.content.len {
+            file.resize_content(offset + buf.len())?;
         }
-        let target = &mut content[offset..offset + buf.len()];
-        target.copy_from_slice(buf);
+        file.page_in(offset..offset + buf.len())?;
+        file.content.write(offset, buf);
+        file.touch();
         Ok(buf.len())
     }
 
@@ -136,7 +358,7 @@ impl INode for LockedINode {
     fn metadata(&self) -> Result<Metadata> {
         let file = self.0.read();
         let mut metadata = file.extra.clone();
-        metadata.size = file.content.len();
+        metadata.size = file.content.len;
         Ok(metadata)
     }
 
@@ -162,7 +384,8 @@ impl INode for LockedINode {
     fn resize(&self, len: usize) -> Result<()> {
         let mut file = self.0.write();
         if file.extra.type_ == FileType::File {
-            file.content.resize(len, 0);
+            file.resize_content(len)?;
+            file.touch();
             Ok(())
         } else {
             Err(FsError::NotFile)
@@ -184,38 +407,75 @@ impl INode for LockedINode {
             if file.children.contains_key(name) {
                 return Err(FsError::EntryExist);
             }
+            let (mode, gid) = file.defaults.apply(&file.extra, type_, mode);
+            let defaults = match type_ {
+                FileType::Dir => file.defaults.clone(),
+                _ => DirDefaults::default(),
+            };
+            let now = file.now().unwrap_or(Timespec { sec: 0, nsec: 0 });
+            // given back by its drop
+            if let Some(fs) = file.fs.upgrade() {
+                RamFS::reserve(&fs.inodes, fs.limits.max_inodes, 1)?;
+            }
             let temp_file = Arc::new(LockedINode(RwLock::new(RamFSINode {
                 parent: Weak::clone(&file.this),
                 this: Weak::default(),
                 children: BTreeMap::new(),
-                content: Vec::new(),
+                cookies: BTreeMap::new(),
+                next_cookie: 2,
+                content: Content::default(),
+                defaults,
+                xattrs: BTreeMap::new(),
                 extra: Metadata {
                     dev: 0,
                     inode: new_inode_id(),
                     size: 0,
                     blk_size: 0,
                     blocks: 0,
-                    atime: Timespec { sec: 0, nsec: 0 },
-                    mtime: Timespec { sec: 0, nsec: 0 },
-                    ctime: Timespec { sec: 0, nsec: 0 },
+                    atime: now,
+                    mtime: now,
+                    ctime: now,
                     type_,
-                    mode: mode as u16,
+                    mode,
                     nlinks: 1,
                     uid: 0,
-                    gid: 0,
+                    gid,
                     rdev: data,
                 },
                 fs: Weak::clone(&file.fs),
             })));
-            temp_file.0.write().this = Arc::downgrade(&temp_file);
-            file.children
-                .insert(String::from(name), Arc::clone(&temp_file));
+            {
+                let mut new = temp_file.0.write();
+                new.this = Arc::downgrade(&temp_file);
+                for (name, value) in file.defaults.xattrs.iter() {
+                    new.set_xattr(name, value)?;
+                }
+            }
+            file.add_child(name, Arc::clone(&temp_file));
+            file.touch();
             Ok(temp_file)
         } else {
             Err(FsError::NotDir)
         }
     }
 
+    fn dir_defaults(&self) -> Result<DirDefaults> {
+        let file = self.0.read();
+        if file.extra.type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        Ok(file.defaults.clone())
+    }
+
+    fn set_dir_defaults(&self, defaults: DirDefaults) -> Result<()> {
+        let mut file = self.0.write();
+        if file.extra.type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        file.defaults = defaults;
+        Ok(())
+    }
+
     fn link(&self, name: &str, other: &Arc<dyn INode>) -> Result<()> {
         let other = other
             .downcast_ref::<LockedINode>()
@@ -236,9 +496,10 @@ impl INode for LockedINode {
             return Err(FsError::EntryExist);
         }
 
-        file.children
-            .insert(String::from(name), other_l.this.upgrade().unwrap());
+        file.add_child(name, other_l.this.upgrade().unwrap());
         other_l.extra.nlinks += 1;
+        file.touch();
+        other_l.touch_ctime();
         Ok(())
     }
 
@@ -250,12 +511,17 @@ impl INode for LockedINode {
         if name == "." || name == ".." {
             return Err(FsError::DirNotEmpty);
         }
-        let other = file.children.get(name).ok_or(FsError::EntryNotFound)?;
+        let (other, _) = file.children.get(name).ok_or(FsError::EntryNotFound)?;
         if other.0.read().children.len() > 0 {
             return Err(FsError::DirNotEmpty);
         }
-        other.0.write().extra.nlinks -= 1;
-        file.children.remove(name);
+        {
+            let mut other = other.0.write();
+            other.extra.nlinks -= 1;
+            other.touch_ctime();
+        }
+        file.remove_child(name);
+        file.touch();
         Ok(())
     }
 
@@ -280,7 +546,7 @@ impl INode for LockedINode {
             "." => Ok(file.this.upgrade().ok_or(FsError::EntryNotFound)?),
             ".." => Ok(file.parent.upgrade().ok_or(FsError::EntryNotFound)?),
             name => {
-                let s = file.children.get(name).ok_or(FsError::EntryNotFound)?;
+                let (s, _) = file.children.get(name).ok_or(FsError::EntryNotFound)?;
                 Ok(Arc::clone(s) as Arc<dyn INode>)
             }
         }
@@ -295,13 +561,26 @@ impl INode for LockedINode {
         match id {
             0 => Ok(String::from(".")),
             1 => Ok(String::from("..")),
-            i => {
-                if let Some(s) = file.children.keys().nth(i - 2) {
-                    Ok(s.to_string())
-                } else {
-                    Err(FsError::EntryNotFound)
-                }
-            }
+            i => match file.cookies.values().nth(i - 2) {
+                Some(name) => Ok(name.clone()),
+                None => Err(FsError::EntryNotFound),
+            },
+        }
+    }
+
+    fn get_entry_from(&self, cookie: usize) -> Result<(String, usize)> {
+        let file = self.0.read();
+        if file.extra.type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+
+        match cookie {
+            0 => Ok((String::from("."), 1)),
+            1 => Ok((String::from(".."), 2)),
+            cookie => match file.cookies.range(cookie..).next() {
+                Some((&cookie, name)) => Ok((name.clone(), cookie + 1)),
+                None => Err(FsError::EntryNotFound),
+            },
         }
     }
 
@@ -313,6 +592,51 @@ impl INode for LockedINode {
         Err(FsError::NotSupported)
     }
 
+    fn copy_file_range(
+        &self,
+        offset: usize,
+        dst: &Arc<dyn INode>,
+        dst_offset: usize,
+        len: usize,
+    ) -> Result<usize> {
+        let dst = dst
+            .downcast_ref::<LockedINode>()
+            .ok_or(FsError::NotSameFs)?;
+        // copied out first, as `dst` may be this file
+        let size = {
+            let file = self.0.read();
+            if file.extra.type_ == FileType::Dir {
+                return Err(FsError::IsDir);
+            }
+            file.content.len
+        };
+        let start = size.min(offset);
+        let mut data = vec![0; size.min(offset.saturating_add(len)) - start];
+        let read = self.read_at(start, &mut data)?;
+        dst.write_at(dst_offset, &data[..read])
+    }
+
+    fn get_xattr(&self, name: &str) -> Result<Vec<u8>> {
+        let file = self.0.read();
+        file.xattrs.get(name).cloned().ok_or(FsError::EntryNotFound)
+    }
+
+    fn set_xattr(&self, name: &str, value: &[u8]) -> Result<()> {
+        self.0.write().set_xattr(name, value)
+    }
+
+    fn list_xattr(&self) -> Result<Vec<String>> {
+        let file = self.0.read();
+        Ok(file.xattrs.keys().cloned().collect())
+    }
+
+    fn remove_xattr(&self, name: &str) -> Result<()> {
+        let mut file = self.0.write();
+        let old = file.xattrs.remove(name).ok_or(FsError::EntryNotFound)?;
+        file.charge(name.len() + old.len(), 0)?;
+        Ok(())
+    }
+
     fn fs(&self) -> Arc<dyn FileSystem> {
         Weak::upgrade(&self.0.read().fs).unwrap()
     }
@@ -326,7 +650,10 @@ impl INode for LockedINode {
 fn lock_multiple<'a>(locks: &[&'a RwLock<RamFSINode>]) -> Vec<RwLockWriteGuard<'a, RamFSINode>> {
     let mut order: Vec<usize> = (0..locks.len()).collect();
     order.sort_by_key(|&i| locks[i].read().extra.inode);
-    order.iter().map(|&i| locks[i].write()).collect()
+    let mut guards: Vec<_> = order.iter().map(|&i| (i, locks[i].write())).collect();
+    // return guards in the order of `locks`
+    guards.sort_by_key(|&(i, _)| i);
+    guards.into_iter().map(|(_, guard)| guard).collect()
 }
 
 /// Generate a new inode id
@@ -0,0 +1,308 @@
+//! Swapping of the content of the files, as tmpfs with swap
+//!
+//! The content of a file is kept in pages of `PAGE_SIZE` bytes. With a `Swap`, once more
+//! than `max_resident` pages are in memory, the coldest are written out to its device, and
+//! read back once used. Pages are taken in the order they came in, but those used since,
+//! which are passed by once, as by a clock. Those of an inode in use, locked, are passed by
+//! too, so that a page is never waited for, and the pages a call needs stay in memory until
+//! it returns.
+use alloc::{
+    collections::VecDeque,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use rcore_fs::dev::Device;
+use rcore_fs::vfs::Result;
+use spin::Mutex;
+
+use crate::{LockedINode, RamFSINode};
+
+/// Bytes of a page of the content of a file, the unit it is swapped by
+pub const PAGE_SIZE: usize = 4096;
+
+/// Where the cold pages of a RamFS are written out to, see the module docs
+pub struct Swap {
+    device: Arc<dyn Device>,
+    /// Pages kept in memory at most, but those in use
+    max_resident: usize,
+    /// Pages in memory
+    resident: AtomicUsize,
+    /// Pages in memory, the coldest first, as inode, index and id, some no longer
+    queue: Mutex<VecDeque<(Weak<LockedINode>, usize, usize)>>,
+    /// Id of the next page read in or made
+    next_id: AtomicUsize,
+    slots: Mutex<Slots>,
+}
+
+/// Slots of `PAGE_SIZE` bytes of the device
+#[derive(Default)]
+struct Slots {
+    free: Vec<usize>,
+    /// Number of slots ever used
+    used: usize,
+}
+
+/// Content of a file
+#[derive(Default)]
+pub(crate) struct Content {
+    pub len: usize,
+    pages: Vec<Page>,
+}
+
+enum Page {
+    InMemory {
+        data: Vec<u8>,
+        /// Used since it was passed by
+        referenced: AtomicBool,
+        /// Told from the pages in its place before, by the queue of the swap
+        id: usize,
+    },
+    /// Written out to a slot of the device
+    Swapped(usize),
+}
+
+impl Swap {
+    /// Write out the coldest pages to `device` once more than `max_resident` are in memory
+    pub fn new(device: Arc<dyn Device>, max_resident: usize) -> Self {
+        Swap {
+            device,
+            max_resident,
+            resident: AtomicUsize::new(0),
+            queue: Mutex::new(VecDeque::new()),
+            next_id: AtomicUsize::new(0),
+            slots: Mutex::new(Slots::default()),
+        }
+    }
+
+    /// Number of pages in memory
+    pub fn resident(&self) -> usize {
+        self.resident.load(Ordering::SeqCst)
+    }
+
+    /// A page of `data` now in memory as page `index` of `inode`
+    fn page_in(&self, inode: &Weak<LockedINode>, index: usize, data: Vec<u8>) -> Page {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let resident = self.resident.fetch_add(1, Ordering::SeqCst) + 1;
+        let mut queue = self.queue.lock();
+        queue.push_back((inode.clone(), index, id));
+        if queue.len() > 2 * resident + 64 {
+            Self::compact(&mut queue);
+        }
+        Page::InMemory {
+            data,
+            referenced: AtomicBool::new(false),
+            id,
+        }
+    }
+
+    /// Give back what `page` holds
+    fn drop_page(&self, page: Page) {
+        match page {
+            Page::InMemory { .. } => {
+                self.resident.fetch_sub(1, Ordering::SeqCst);
+            }
+            Page::Swapped(slot) => self.slots.lock().free.push(slot),
+        }
+    }
+
+    /// Drop the pages of the queue no longer in memory, keeping those of inodes in use
+    fn compact(queue: &mut VecDeque<(Weak<LockedINode>, usize, usize)>) {
+        queue.retain(|(inode, index, id)| match inode.upgrade() {
+            Some(inode) => match inode.0.try_read() {
+                Some(file) => file.content.is_in_memory(*index, *id),
+                None => true,
+            },
+            None => false,
+        });
+    }
+
+    /// Write out the coldest pages, until at most `max_resident` are in memory or all are
+    /// passed by. A page failing to be written is kept in memory.
+    pub(crate) fn evict(&self) {
+        let mut tries = 2 * self.queue.lock().len();
+        while self.resident() > self.max_resident && tries > 0 {
+            tries -= 1;
+            let (weak, index, id) = match self.queue.lock().pop_front() {
+                Some(entry) => entry,
+                None => return,
+            };
+            let inode = match weak.upgrade() {
+                Some(inode) => inode,
+                None => continue,
+            };
+            let mut file = match inode.0.try_write() {
+                Some(file) => file,
+                None => {
+                    self.queue.lock().push_back((weak, index, id));
+                    continue;
+                }
+            };
+            if !file.content.is_in_memory(index, id) {
+                continue;
+            }
+            let slot = match &file.content.pages[index] {
+                Page::InMemory {
+                    data, referenced, ..
+                } => {
+                    if referenced.swap(false, Ordering::SeqCst) {
+                        self.queue.lock().push_back((weak, index, id));
+                        continue;
+                    }
+                    let slot = self.alloc_slot();
+                    if self.device.write_at(slot * PAGE_SIZE, data).is_err() {
+                        self.slots.lock().free.push(slot);
+                        self.queue.lock().push_back((weak, index, id));
+                        return;
+                    }
+                    slot
+                }
+                Page::Swapped(_) => unreachable!(),
+            };
+            file.content.pages[index] = Page::Swapped(slot);
+            self.resident.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    fn alloc_slot(&self) -> usize {
+        let mut slots = self.slots.lock();
+        match slots.free.pop() {
+            Some(slot) => slot,
+            None => {
+                slots.used += 1;
+                slots.used - 1
+            }
+        }
+    }
+}
+
+impl Content {
+    /// Read from `offset` to `buf`, none if a page needed is swapped out
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> Option<usize> {
+        let start = self.len.min(offset);
+        let end = self.len.min(offset + buf.len());
+        let mut pos = start;
+        while pos < end {
+            let (index, begin) = (pos / PAGE_SIZE, pos % PAGE_SIZE);
+            let len = (PAGE_SIZE - begin).min(end - pos);
+            match &self.pages[index] {
+                Page::InMemory {
+                    data, referenced, ..
+                } => {
+                    referenced.store(true, Ordering::Relaxed);
+                    buf[pos - start..pos - start + len].copy_from_slice(&data[begin..begin + len]);
+                }
+                Page::Swapped(_) => return None,
+            }
+            pos += len;
+        }
+        Some(end - start)
+    }
+
+    /// Write `buf` at `offset`, within the content, its pages in memory
+    pub fn write(&mut self, offset: usize, buf: &[u8]) {
+        let mut pos = offset;
+        while pos < offset + buf.len() {
+            let (index, begin) = (pos / PAGE_SIZE, pos % PAGE_SIZE);
+            let len = (PAGE_SIZE - begin).min(offset + buf.len() - pos);
+            match &mut self.pages[index] {
+                Page::InMemory {
+                    data, referenced, ..
+                } => {
+                    referenced.store(true, Ordering::Relaxed);
+                    data[begin..begin + len]
+                        .copy_from_slice(&buf[pos - offset..pos - offset + len]);
+                }
+                Page::Swapped(_) => panic!("page {} is swapped out", index),
+            }
+            pos += len;
+        }
+    }
+
+    fn is_in_memory(&self, index: usize, id: usize) -> bool {
+        match self.pages.get(index) {
+            Some(Page::InMemory { id: page_id, .. }) => *page_id == id,
+            _ => false,
+        }
+    }
+}
+
+impl RamFSINode {
+    /// Read back the pages holding `range` of the content
+    pub(crate) fn page_in(&mut self, range: Range<usize>) -> Result<()> {
+        let fs = match self.fs.upgrade() {
+            Some(fs) => fs,
+            None => return Ok(()),
+        };
+        let swap = match &fs.swap {
+            Some(swap) => swap,
+            None => return Ok(()),
+        };
+        if range.start >= range.end {
+            return Ok(());
+        }
+        for index in range.start / PAGE_SIZE..=(range.end - 1) / PAGE_SIZE {
+            if let Page::Swapped(slot) = self.content.pages[index] {
+                let mut data = vec![0; PAGE_SIZE];
+                swap.device.read_at(slot * PAGE_SIZE, &mut data)?;
+                self.content.pages[index] = swap.page_in(&self.this, index, data);
+                swap.slots.lock().free.push(slot);
+            }
+        }
+        swap.evict();
+        Ok(())
+    }
+
+    /// Resize the pages of the content to hold `len` bytes, the bytes after it 0s
+    pub(crate) fn resize_pages(&mut self, len: usize) -> Result<()> {
+        let swap = self.fs.upgrade();
+        let swap = swap.as_ref().and_then(|fs| fs.swap.as_ref());
+        let pages = (len + PAGE_SIZE - 1) / PAGE_SIZE;
+        if len < self.content.len {
+            // zeroed, as if grown again
+            self.page_in(len..len + 1)?;
+            if let Some(Page::InMemory { data, .. }) = self.content.pages.get_mut(len / PAGE_SIZE) {
+                for byte in data[len % PAGE_SIZE..].iter_mut() {
+                    *byte = 0;
+                }
+            }
+            let dropped: Vec<Page> = self.content.pages.drain(pages..).collect();
+            if let Some(swap) = swap {
+                for page in dropped {
+                    swap.drop_page(page);
+                }
+            }
+        }
+        while self.content.pages.len() < pages {
+            let index = self.content.pages.len();
+            let data = vec![0; PAGE_SIZE];
+            let page = match swap {
+                Some(swap) => swap.page_in(&self.this, index, data),
+                None => Page::InMemory {
+                    data,
+                    referenced: AtomicBool::new(false),
+                    id: 0,
+                },
+            };
+            self.content.pages.push(page);
+        }
+        self.content.len = len;
+        if let Some(swap) = swap {
+            swap.evict();
+        }
+        Ok(())
+    }
+
+    /// Give back the pages of the content, as the inode is dropped
+    pub(crate) fn drop_pages(&mut self) {
+        if let Some(fs) = self.fs.upgrade() {
+            if let Some(swap) = &fs.swap {
+                for page in self.content.pages.drain(..) {
+                    swap.drop_page(page);
+                }
+            }
+        }
+    }
+}
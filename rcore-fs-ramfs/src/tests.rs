@@ -0,0 +1,314 @@
+use crate::*;
+use core::sync::atomic::AtomicBool;
+use rcore_fs::dev::{self, Device};
+use spin::Mutex;
+
+fn data(len: usize, seed: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + seed * 13) as u8).collect()
+}
+
+fn read_all(inode: &Arc<dyn INode>) -> Vec<u8> {
+    let mut buf = vec![0; inode.metadata().unwrap().size];
+    let len = inode.read_at(0, &mut buf).unwrap();
+    assert_eq!(len, buf.len());
+    buf
+}
+
+#[test]
+fn limits() -> Result<()> {
+    let limits = Limits {
+        max_bytes: 10000,
+        max_inodes: 4,
+    };
+    let fs = RamFS::with_limits(None, limits);
+    let info = fs.info();
+    assert_eq!(
+        (info.blocks, info.bfree, info.bavail),
+        (10000, 10000, 10000)
+    );
+    assert_eq!((info.files, info.ffree), (4, 3));
+    let root = fs.root_inode();
+
+    // bytes of the content, none taken by a write beyond
+    let file = root.create("file", FileType::File, 0o644)?;
+    file.write_at(0, &data(6000, 1))?;
+    assert_eq!(fs.info().bfree, 4000);
+    match file.write_at(8000, &data(3000, 2)) {
+        Err(FsError::NoDeviceSpace) => {}
+        res => panic!("{:?}", res),
+    }
+    assert_eq!(file.metadata()?.size, 6000);
+    assert_eq!(fs.info().bfree, 4000);
+    file.resize(10000)?;
+    assert_eq!(fs.info().bfree, 0);
+    match file.resize(10001) {
+        Err(FsError::NoDeviceSpace) => {}
+        res => panic!("{:?}", res),
+    }
+    file.resize(2000)?;
+    assert_eq!(fs.info().bavail, 8000);
+    assert_eq!(read_all(&file), data(2000, 1));
+
+    // and of the extended attributes
+    file.set_xattr("user.a", &[1; 94])?;
+    assert_eq!(fs.info().bfree, 7900);
+    file.set_xattr("user.a", &[1; 44])?;
+    assert_eq!(fs.info().bfree, 7950);
+    match file.set_xattr("user.b", &[2; 8000]) {
+        Err(FsError::NoDeviceSpace) => {}
+        res => panic!("{:?}", res),
+    }
+    assert_eq!(file.list_xattr()?, vec!["user.a".to_string()]);
+    file.remove_xattr("user.a")?;
+    assert_eq!(fs.info().bfree, 8000);
+
+    // inodes, a link taking none
+    let dir = root.create("dir", FileType::Dir, 0o755)?;
+    dir.create("inner", FileType::File, 0o644)?;
+    assert_eq!(fs.info().ffree, 0);
+    match root.create("more", FileType::File, 0o644).map(|_| ()) {
+        Err(FsError::NoDeviceSpace) => {}
+        res => panic!("{:?}", res),
+    }
+    dir.link("link", &file)?;
+    assert_eq!(fs.info().ffree, 0);
+    dir.unlink("inner")?;
+    assert_eq!(fs.info().ffree, 1);
+    root.create("more", FileType::File, 0o644)?;
+    root.unlink("more")?;
+
+    // given back once neither linked nor open
+    root.unlink("file")?;
+    dir.unlink("link")?;
+    assert_eq!(fs.info().bfree, 8000);
+    assert_eq!(fs.info().ffree, 1);
+    drop(file);
+    assert_eq!(fs.info().bfree, 10000);
+    assert_eq!(fs.info().ffree, 2);
+
+    // none by default
+    let fs = RamFS::new();
+    let info = fs.info();
+    assert_eq!(info.blocks, usize::max_value());
+    assert_eq!(info.ffree, usize::max_value() - 1);
+    fs.root_inode()
+        .create("file", FileType::File, 0o644)?
+        .write_at(0, &data(100, 3))?;
+    assert_eq!(fs.info().bfree, usize::max_value() - 100);
+    Ok(())
+}
+
+/// A swap device in memory, growing as written, whose writes may be made to fail
+#[derive(Default)]
+struct MemDevice {
+    data: Mutex<Vec<u8>>,
+    writes: AtomicUsize,
+    broken: AtomicBool,
+}
+
+impl Device for MemDevice {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> dev::Result<usize> {
+        let data = self.data.lock();
+        buf.copy_from_slice(&data[offset..offset + buf.len()]);
+        Ok(buf.len())
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> dev::Result<usize> {
+        if self.broken.load(Ordering::SeqCst) {
+            return Err(dev::DevError::Io);
+        }
+        let mut data = self.data.lock();
+        if data.len() < offset + buf.len() {
+            data.resize(offset + buf.len(), 0);
+        }
+        data[offset..offset + buf.len()].copy_from_slice(buf);
+        self.writes.fetch_add(1, Ordering::SeqCst);
+        Ok(buf.len())
+    }
+    fn sync(&self) -> dev::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn paged_content() -> Result<()> {
+    let fs = RamFS::new();
+    let file = fs.root_inode().create("file", FileType::File, 0o644)?;
+    let content = data(3 * PAGE_SIZE + 100, 1);
+    file.write_at(PAGE_SIZE - 50, &content)?;
+    assert_eq!(file.metadata()?.size, 4 * PAGE_SIZE + 50);
+    let mut buf = vec![0; 200];
+    assert_eq!(file.read_at(4 * PAGE_SIZE - 50, &mut buf)?, 100);
+    assert_eq!(&buf[..100], &content[3 * PAGE_SIZE..]);
+    // cut within a page and grown again, 0s after it
+    file.resize(2 * PAGE_SIZE + 10)?;
+    file.resize(3 * PAGE_SIZE)?;
+    let all = read_all(&file);
+    assert!(all[..PAGE_SIZE - 50].iter().all(|&byte| byte == 0));
+    assert_eq!(
+        &all[PAGE_SIZE - 50..2 * PAGE_SIZE + 10],
+        &content[..PAGE_SIZE + 60]
+    );
+    assert!(all[2 * PAGE_SIZE + 10..].iter().all(|&byte| byte == 0));
+    Ok(())
+}
+
+#[test]
+fn swap() -> Result<()> {
+    let device = Arc::new(MemDevice::default());
+    let fs = RamFS::with_swap(None, Limits::default(), Swap::new(device.clone(), 4));
+    let swap = fs.swap.as_ref().unwrap();
+    let root = fs.root_inode();
+
+    // the pages of a file in use kept, written out once another is
+    let a = root.create("a", FileType::File, 0o644)?;
+    let content = data(10 * PAGE_SIZE, 1);
+    a.write_at(0, &content)?;
+    assert_eq!(swap.resident(), 10);
+    assert_eq!(device.writes.load(Ordering::SeqCst), 0);
+    let b = root.create("b", FileType::File, 0o644)?;
+    b.write_at(0, &data(PAGE_SIZE, 2))?;
+    assert_eq!(swap.resident(), 4);
+    assert_eq!(device.writes.load(Ordering::SeqCst), 7);
+    assert_eq!(device.data.lock().len(), 7 * PAGE_SIZE);
+
+    // read back once used, the coldest written out instead
+    assert_eq!(read_all(&a), content);
+    assert_eq!(swap.resident(), 10);
+    assert_eq!(read_all(&b), data(PAGE_SIZE, 2));
+    assert_eq!(swap.resident(), 4);
+    // written to in place, the slots given back reused
+    a.write_at(PAGE_SIZE / 2, &data(2 * PAGE_SIZE, 3))?;
+    assert_eq!(read_all(&b), data(PAGE_SIZE, 2));
+    let mut content = content;
+    content[PAGE_SIZE / 2..PAGE_SIZE / 2 + 2 * PAGE_SIZE].copy_from_slice(&data(2 * PAGE_SIZE, 3));
+    assert_eq!(read_all(&a), content);
+    assert!(device.data.lock().len() <= 11 * PAGE_SIZE);
+
+    // cut while written out, 0s after it
+    b.write_at(0, &data(10, 4))?;
+    assert_eq!(swap.resident(), 4);
+    a.resize(5 * PAGE_SIZE + 1)?;
+    a.resize(6 * PAGE_SIZE)?;
+    let all = read_all(&a);
+    assert_eq!(&all[..5 * PAGE_SIZE + 1], &content[..5 * PAGE_SIZE + 1]);
+    assert!(all[5 * PAGE_SIZE + 1..].iter().all(|&byte| byte == 0));
+
+    // kept in memory while the device fails
+    device.broken.store(true, Ordering::SeqCst);
+    let c = root.create("c", FileType::File, 0o644)?;
+    c.write_at(0, &data(3 * PAGE_SIZE, 5))?;
+    assert_eq!(read_all(&c), data(3 * PAGE_SIZE, 5));
+    assert!(swap.resident() > 4);
+    device.broken.store(false, Ordering::SeqCst);
+    assert_eq!(
+        read_all(&b),
+        data(10, 4)
+            .into_iter()
+            .chain(data(PAGE_SIZE, 2).into_iter().skip(10))
+            .collect::<Vec<u8>>()
+    );
+    assert_eq!(swap.resident(), 4);
+
+    // given back once dropped
+    root.unlink("a")?;
+    root.unlink("b")?;
+    root.unlink("c")?;
+    drop((a, b, c));
+    assert_eq!(swap.resident(), 0);
+    // the slots too, for those written out next
+    let len = device.data.lock().len();
+    let d = root.create("d", FileType::File, 0o644)?;
+    d.write_at(0, &data(len + 3 * PAGE_SIZE, 6))?;
+    root.create("e", FileType::File, 0o644)?
+        .write_at(0, &data(10, 7))?;
+    assert_eq!(swap.resident(), 4);
+    assert_eq!(device.data.lock().len(), len);
+    Ok(())
+}
+
+#[test]
+fn interleaved_listings() -> Result<()> {
+    let fs = RamFS::new();
+    let dir = fs.root_inode().create("dir", FileType::Dir, 0o755)?;
+    let name = |i: usize| format!("e{:02}", i);
+    for i in 0..40 {
+        dir.create(&name(i), FileType::File, 0o644)?;
+    }
+    // entries told by a reader, from the cookie it goes on from
+    let next = |(names, cookie): &mut (Vec<String>, usize)| -> bool {
+        match dir.get_entry_from(*cookie) {
+            Ok((name, next)) => {
+                names.push(name);
+                *cookie = next;
+                true
+            }
+            Err(FsError::EntryNotFound) => false,
+            Err(err) => panic!("{:?}", err),
+        }
+    };
+    let (mut a, mut b) = ((Vec::new(), 0), (Vec::new(), 0));
+    for _ in 0..12 {
+        next(&mut a);
+    }
+    for _ in 0..7 {
+        next(&mut b);
+    }
+    assert_eq!(a.0[..2], [".", ".."]);
+    assert_eq!(a.0[11], name(9));
+    assert_eq!(b.0[6], name(4));
+
+    // changed before and after where each is
+    dir.unlink(&name(2))?;
+    dir.unlink(&name(20))?;
+    dir.create("e00x", FileType::File, 0o644)?;
+    dir.create("e05x", FileType::File, 0o644)?;
+    let (mut a_done, mut b_done) = (false, false);
+    while !a_done || !b_done {
+        a_done = a_done || !next(&mut a);
+        b_done = b_done || !next(&mut b);
+    }
+    // each in one pass, in the order added, none removed before told
+    let mut expected: Vec<String> = (0..40).filter(|&i| i != 20).map(name).collect();
+    expected.push(String::from("e00x"));
+    expected.push(String::from("e05x"));
+    assert_eq!(a.0[2..], expected[..]);
+    assert_eq!(b.0[2..], expected[..]);
+
+    // and from the start again, as it is now
+    let mut c = (Vec::new(), 0);
+    while next(&mut c) {}
+    expected.retain(|name| name != "e02");
+    assert_eq!(c.0[2..], expected[..]);
+    assert_eq!(dir.list()?[2..], expected[..]);
+    assert_eq!(dir.get_entry(2)?, name(0));
+    assert_eq!(dir.get_entry(expected.len() + 1)?, "e05x");
+    Ok(())
+}
+
+#[test]
+fn dir_defaults() -> Result<()> {
+    let fs = RamFS::new();
+    let project = fs.root_inode().create("project", FileType::Dir, 0o755)?;
+    let defaults = DirDefaults {
+        umask: 0o027,
+        xattrs: vec![(String::from("user.project"), b"rcore".to_vec())],
+    };
+    project.set_dir_defaults(defaults.clone())?;
+    assert_eq!(project.dir_defaults()?, defaults);
+
+    // set on each new entry, and inherited by new dirs
+    let file = project.create("file", FileType::File, 0o666)?;
+    assert_eq!(file.metadata()?.mode, 0o640);
+    assert_eq!(file.get_xattr("user.project")?, b"rcore");
+    let dir = project.create("dir", FileType::Dir, 0o777)?;
+    assert_eq!(dir.dir_defaults()?, defaults);
+    let inner = dir.create("inner", FileType::File, 0o666)?;
+    assert_eq!(inner.list_xattr()?, vec!["user.project".to_string()]);
+    assert_eq!(
+        fs.root_inode()
+            .create("other", FileType::File, 0o666)?
+            .list_xattr()?,
+        Vec::<String>::new()
+    );
+    Ok(())
+}
@@ -0,0 +1,145 @@
+//! Cryptographic primitives of SEFS
+
+/// Bytes of a SHA-256 hash
+pub const HASH_SIZE: usize = 32;
+
+pub type Hash = [u8; HASH_SIZE];
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256 of data given in parts
+pub struct Sha256 {
+    state: [u32; 8],
+    /// the part of a block not hashed yet
+    buf: [u8; 64],
+    /// bytes given
+    len: u64,
+}
+
+impl Sha256 {
+    pub fn new() -> Self {
+        Sha256 {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+                0x5be0cd19,
+            ],
+            buf: [0; 64],
+            len: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let pos = self.len as usize % 64;
+            let n = (64 - pos).min(data.len());
+            self.buf[pos..pos + n].copy_from_slice(&data[..n]);
+            self.len += n as u64;
+            data = &data[n..];
+            if pos + n == 64 {
+                let block = self.buf;
+                self.compress(&block);
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> Hash {
+        let bits = self.len * 8;
+        self.update(&[0x80]);
+        while self.len % 64 != 56 {
+            self.update(&[0]);
+        }
+        self.update(&bits.to_be_bytes());
+        let mut hash = [0u8; HASH_SIZE];
+        for (i, word) in self.state.iter().enumerate() {
+            hash[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+        }
+        hash
+    }
+
+    fn compress(&mut self, block: &[u8; 64]) {
+        let mut w = [0u32; 64];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes([
+                block[i * 4],
+                block[i * 4 + 1],
+                block[i * 4 + 2],
+                block[i * 4 + 3],
+            ]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+        let mut s = self.state;
+        for i in 0..64 {
+            let s1 = s[4].rotate_right(6) ^ s[4].rotate_right(11) ^ s[4].rotate_right(25);
+            let ch = (s[4] & s[5]) ^ (!s[4] & s[6]);
+            let t1 = s[7]
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = s[0].rotate_right(2) ^ s[0].rotate_right(13) ^ s[0].rotate_right(22);
+            let maj = (s[0] & s[1]) ^ (s[0] & s[2]) ^ (s[1] & s[2]);
+            let t2 = s0.wrapping_add(maj);
+            s = [
+                t1.wrapping_add(t2),
+                s[0],
+                s[1],
+                s[2],
+                s[3].wrapping_add(t1),
+                s[4],
+                s[5],
+                s[6],
+            ];
+        }
+        for i in 0..8 {
+            self.state[i] = self.state[i].wrapping_add(s[i]);
+        }
+    }
+}
+
+/// SHA-256 of `data`
+pub fn sha256(data: &[u8]) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hasher.finish()
+}
+
+/// HMAC-SHA256 under `key` of the parts of `data` put together
+pub fn hmac_sha256(key: &[u8], data: &[&[u8]]) -> Hash {
+    let mut block = [0u8; 64];
+    match key.len() > 64 {
+        true => block[..HASH_SIZE].copy_from_slice(&sha256(key)),
+        false => block[..key.len()].copy_from_slice(key),
+    }
+    let pad = |byte: u8| {
+        let mut pad = block;
+        for b in pad.iter_mut() {
+            *b ^= byte;
+        }
+        pad
+    };
+    let mut inner = Sha256::new();
+    inner.update(&pad(0x36));
+    for part in data {
+        inner.update(part);
+    }
+    let mut outer = Sha256::new();
+    outer.update(&pad(0x5c));
+    outer.update(&inner.finish());
+    outer.finish()
+}
@@ -0,0 +1,205 @@
+//! Batching of the calls to the files of a storage
+//!
+//! A call to a file of the storage may cross the enclave boundary, which costs far more than
+//! the bytes it moves, while SEFS reads and writes its files a block or a chunk at a time.
+//! `BatchedStorage` wraps a storage so that these calls are batched: a file read reads
+//! `READAHEAD` bytes ahead, serving the reads after from them, and adjacent writes are kept
+//! until they reach `BATCH` bytes, a read or write elsewhere needs them, or the file is
+//! flushed. A file is shared by all who open it, and kept open once closed, the `capacity`
+//! most recently used, so that an inode loaded again does not open it again.
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc, vec::Vec};
+
+use spin::Mutex;
+
+use super::{DevResult, File, Storage};
+use crate::key::Key;
+
+/// Bytes read ahead by a read
+pub const READAHEAD: usize = 0x8000;
+/// Bytes of adjacent writes kept before they are written
+pub const BATCH: usize = 0x8000;
+
+pub struct BatchedStorage<S: Storage> {
+    storage: S,
+    /// files open, the most recently used first
+    files: Mutex<VecDeque<OpenFile>>,
+    capacity: usize,
+}
+
+struct OpenFile {
+    id: usize,
+    key: Option<Key>,
+    file: Arc<BatchedFile>,
+}
+
+impl<S: Storage> BatchedStorage<S> {
+    pub fn new(storage: S, capacity: usize) -> Self {
+        BatchedStorage {
+            storage,
+            files: Mutex::new(VecDeque::new()),
+            capacity,
+        }
+    }
+
+    fn insert(&self, id: usize, key: Option<&Key>, file: Box<dyn File>) -> Box<dyn File> {
+        let file = Arc::new(BatchedFile::new(file));
+        let mut files = self.files.lock();
+        files.push_front(OpenFile {
+            id,
+            key: key.cloned(),
+            file: file.clone(),
+        });
+        // written back once dropped by all who opened them
+        files.truncate(self.capacity);
+        Box::new(file)
+    }
+
+    fn take(&self, id: usize) -> Option<OpenFile> {
+        let mut files = self.files.lock();
+        let i = files.iter().position(|open| open.id == id)?;
+        files.remove(i)
+    }
+}
+
+impl<S: Storage> Storage for BatchedStorage<S> {
+    fn open(&self, file_id: usize, key: Option<&Key>) -> DevResult<Box<dyn File>> {
+        if let Some(open) = self.take(file_id) {
+            if open.key.as_ref() == key {
+                let file = open.file.clone();
+                self.files.lock().push_front(open);
+                return Ok(Box::new(file));
+            }
+        }
+        let file = self.storage.open(file_id, key)?;
+        Ok(self.insert(file_id, key, file))
+    }
+
+    fn create(&self, file_id: usize, key: Option<&Key>) -> DevResult<Box<dyn File>> {
+        if let Some(open) = self.take(file_id) {
+            open.file.discard();
+        }
+        let file = self.storage.create(file_id, key)?;
+        Ok(self.insert(file_id, key, file))
+    }
+
+    fn remove(&self, file_id: usize) -> DevResult<()> {
+        if let Some(open) = self.take(file_id) {
+            open.file.discard();
+        }
+        self.storage.remove(file_id)
+    }
+}
+
+/// A file of the storage whose calls are batched
+pub struct BatchedFile {
+    file: Box<dyn File>,
+    bufs: Mutex<Bufs>,
+}
+
+#[derive(Default)]
+struct Bufs {
+    /// bytes read ahead from `read_at`, fewer at the end of the file
+    read: Vec<u8>,
+    read_at: usize,
+    /// bytes written from `write_at`, not yet to the file
+    write: Vec<u8>,
+    write_at: usize,
+}
+
+impl BatchedFile {
+    fn new(file: Box<dyn File>) -> Self {
+        BatchedFile {
+            file,
+            bufs: Mutex::new(Bufs::default()),
+        }
+    }
+
+    fn write_back(&self, bufs: &mut Bufs) -> DevResult<()> {
+        if !bufs.write.is_empty() {
+            self.file.write_all_at(&bufs.write, bufs.write_at)?;
+            bufs.write.clear();
+        }
+        Ok(())
+    }
+
+    /// Drop what is kept, the file being removed or made again
+    fn discard(&self) {
+        *self.bufs.lock() = Bufs::default();
+    }
+}
+
+impl File for Arc<BatchedFile> {
+    fn read_at(&self, buf: &mut [u8], offset: usize) -> DevResult<usize> {
+        let bufs = &mut *self.bufs.lock();
+        let end = offset + buf.len();
+        // writes at or after it may tell where the file ends
+        if !bufs.write.is_empty() && bufs.write_at + bufs.write.len() > offset {
+            self.write_back(bufs)?;
+        }
+        if offset < bufs.read_at || end > bufs.read_at + bufs.read.len() {
+            if buf.len() >= READAHEAD {
+                return self.file.read_at(buf, offset);
+            }
+            bufs.read.resize(READAHEAD, 0);
+            let len = self.file.read_at(&mut bufs.read, offset)?;
+            bufs.read.truncate(len);
+            bufs.read_at = offset;
+        }
+        let begin = offset - bufs.read_at;
+        let len = buf.len().min(bufs.read.len() - begin);
+        buf[..len].copy_from_slice(&bufs.read[begin..begin + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: usize) -> DevResult<usize> {
+        let bufs = &mut *self.bufs.lock();
+        let end = offset + buf.len();
+        // what is read ahead is kept up to date
+        let read_end = bufs.read_at + bufs.read.len();
+        let (from, to) = (offset.max(bufs.read_at), end.min(read_end));
+        if from < to {
+            bufs.read[from - bufs.read_at..to - bufs.read_at]
+                .copy_from_slice(&buf[from - offset..to - offset]);
+        }
+        let write_end = bufs.write_at + bufs.write.len();
+        let adjacent = !bufs.write.is_empty()
+            && offset >= bufs.write_at
+            && offset <= write_end
+            && end.max(write_end) - bufs.write_at <= BATCH;
+        if !adjacent {
+            self.write_back(bufs)?;
+            if buf.len() >= BATCH {
+                return self.file.write_at(buf, offset);
+            }
+            bufs.write_at = offset;
+        }
+        let begin = offset - bufs.write_at;
+        if begin + buf.len() > bufs.write.len() {
+            bufs.write.resize(begin + buf.len(), 0);
+        }
+        bufs.write[begin..begin + buf.len()].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn set_len(&self, len: usize) -> DevResult<()> {
+        let bufs = &mut *self.bufs.lock();
+        self.write_back(bufs)?;
+        if len < bufs.read_at + bufs.read.len() {
+            let keep = len.saturating_sub(bufs.read_at);
+            bufs.read.truncate(keep);
+        }
+        self.file.set_len(len)
+    }
+
+    fn flush(&self) -> DevResult<()> {
+        self.write_back(&mut self.bufs.lock())?;
+        self.file.flush()
+    }
+}
+
+impl Drop for BatchedFile {
+    fn drop(&mut self) {
+        self.write_back(&mut self.bufs.lock())
+            .expect("failed to write back");
+    }
+}
@@ -2,9 +2,13 @@ use alloc::boxed::Box;
 
 use rcore_fs::vfs::FsError;
 
+use crate::key::Key;
+
+pub use self::batched::BatchedStorage;
 #[cfg(any(test, feature = "std"))]
 pub use self::std_impl::*;
 
+pub mod batched;
 pub mod std_impl;
 
 /// A file stores a normal file or directory.
@@ -35,9 +39,11 @@ pub trait File: Send + Sync {
 }
 
 /// The collection of all files in the FS.
+///
+/// A file is encrypted with the key it is opened with, or kept in plaintext if it has none.
 pub trait Storage: Send + Sync {
-    fn open(&self, file_id: usize) -> DevResult<Box<dyn File>>;
-    fn create(&self, file_id: usize) -> DevResult<Box<dyn File>>;
+    fn open(&self, file_id: usize, key: Option<&Key>) -> DevResult<Box<dyn File>>;
+    fn create(&self, file_id: usize, key: Option<&Key>) -> DevResult<Box<dyn File>>;
     fn remove(&self, file_id: usize) -> DevResult<()>;
 }
 
@@ -1,11 +1,13 @@
 #![cfg(any(test, feature = "std"))]
 
 use super::{DevResult, DeviceError};
+use crate::key::Key;
 use spin::Mutex;
 use std::fs::{remove_file, File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 
+/// Files of a dir of the host, kept in plaintext whatever their keys, for tools and tests
 pub struct StdStorage {
     path: PathBuf,
 }
@@ -20,14 +22,14 @@ impl StdStorage {
 }
 
 impl super::Storage for StdStorage {
-    fn open(&self, file_id: usize) -> DevResult<Box<dyn super::File>> {
+    fn open(&self, file_id: usize, _key: Option<&Key>) -> DevResult<Box<dyn super::File>> {
         let mut path = self.path.to_path_buf();
         path.push(format!("{}", file_id));
         let file = OpenOptions::new().read(true).write(true).open(path)?;
         Ok(Box::new(Mutex::new(file)))
     }
 
-    fn create(&self, file_id: usize) -> DevResult<Box<dyn super::File>> {
+    fn create(&self, file_id: usize, _key: Option<&Key>) -> DevResult<Box<dyn super::File>> {
         let mut path = self.path.to_path_buf();
         path.push(format!("{}", file_id));
         let file = OpenOptions::new()
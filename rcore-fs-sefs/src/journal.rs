@@ -0,0 +1,412 @@
+//! Journal of the metadata of SEFS
+//!
+//! The metadata file, holding the free map and the inodes, and the files of the directories
+//! are changed in place, so a host process killed while they are written leaves them
+//! neither as they were nor as they were to be, and their roots then tell them corrupted,
+//! see `merkle`. So they are `JournaledFile`s, whose writes are kept in memory until
+//! `SEFS::sync()` commits them all at once: the blocks written, the super block among them,
+//! are written to the journal first, the file `JOURNAL_FILE` of the storage, with the key of
+//! the metadata, followed by their hash, then to their files, and the journal is emptied.
+//! At open, a journal whose hash matches is replayed, while one cut short by a crash is
+//! dropped, no file changed yet. A crash between syncs leaves them as at the last one, and
+//! so the files removed since are only removed from the storage once committed; a crash
+//! after leaves them there, unused. The other files are written in place, see `merkle`.
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::mem::size_of;
+
+use rcore_fs::vfs;
+use spin::{Mutex, MutexGuard};
+
+use crate::crypto::{sha256, HASH_SIZE};
+use crate::dev::{DevResult, File, Storage};
+use crate::structs::*;
+
+/// "JRNL", starting a record of the journal
+const JOURNAL_MAGIC: u32 = 0x4c4e_524a;
+/// Bytes of the header of a file in a record: its id, least and last length, number of
+/// blocks, whether it is encrypted and its key
+const FILE_HEADER_SIZE: usize = 32 + size_of::<WrappedKey>();
+/// Bytes of a block of a record: its id and content
+const ENTRY_SIZE: usize = 8 + BLKSIZE;
+
+pub struct Journal {
+    journal: Box<dyn File>,
+    /// files written since the last commit
+    dirty: Mutex<BTreeMap<usize, Arc<Inner>>>,
+    /// files removed since the last commit
+    removed: Mutex<BTreeSet<usize>>,
+}
+
+/// A file of the storage whose writes are committed by its journal, see the module docs
+pub struct JournaledFile {
+    inner: Arc<Inner>,
+    journal: Arc<Journal>,
+}
+
+struct Inner {
+    id: usize,
+    /// its key, to be opened by at replay, none if it is in plaintext
+    key: Option<WrappedKey>,
+    file: Box<dyn File>,
+    state: Mutex<State>,
+}
+
+struct State {
+    /// blocks written since the last commit
+    blocks: BTreeMap<BlockId, Vec<u8>>,
+    /// length of the file in the storage
+    file_len: usize,
+    /// least length since the last commit, beyond which the storage holds nothing of it
+    min_len: usize,
+    len: usize,
+}
+
+/// What a record tells of a file
+struct Record<'a> {
+    id: usize,
+    key: Option<WrappedKey>,
+    min_len: usize,
+    len: usize,
+    blocks: Vec<(BlockId, &'a [u8])>,
+}
+
+impl Journal {
+    /// The journal in `journal`, empty
+    pub fn create(journal: Box<dyn File>) -> DevResult<Arc<Self>> {
+        journal.set_len(0)?;
+        Ok(Self::new(journal))
+    }
+
+    /// The journal in `journal`, replayed if it holds a whole record, each file opened by
+    /// `open` with its id and key
+    pub fn open(
+        journal: Box<dyn File>,
+        open: impl Fn(usize, Option<&WrappedKey>) -> vfs::Result<Box<dyn File>>,
+    ) -> vfs::Result<Arc<Self>> {
+        let data = read_all(&*journal)?;
+        if let Some(records) = decode(&data) {
+            warn!("replay the journal of {} files", records.len());
+            for record in records {
+                let file = open(record.id, record.key.as_ref())?;
+                apply(
+                    &*file,
+                    record.min_len,
+                    record.len,
+                    record.blocks.into_iter(),
+                )?;
+            }
+            journal.set_len(0)?;
+            journal.flush()?;
+        }
+        Ok(Self::new(journal))
+    }
+
+    fn new(journal: Box<dyn File>) -> Arc<Self> {
+        Arc::new(Journal {
+            journal,
+            dirty: Mutex::new(BTreeMap::new()),
+            removed: Mutex::new(BTreeSet::new()),
+        })
+    }
+
+    /// File `id` of the storage with key `key`, as written since the last commit, opened by
+    /// `open` if it was not
+    pub fn file(
+        self: &Arc<Self>,
+        id: usize,
+        key: Option<&WrappedKey>,
+        open: impl FnOnce() -> DevResult<Box<dyn File>>,
+    ) -> DevResult<JournaledFile> {
+        let inner = match self.dirty.lock().get(&id) {
+            Some(inner) => inner.clone(),
+            None => {
+                let file = open()?;
+                let len = len_of(&*file)?;
+                Arc::new(Inner {
+                    id,
+                    key: key.cloned(),
+                    file,
+                    state: Mutex::new(State {
+                        blocks: BTreeMap::new(),
+                        file_len: len,
+                        min_len: len,
+                        len,
+                    }),
+                })
+            }
+        };
+        Ok(JournaledFile {
+            inner,
+            journal: self.clone(),
+        })
+    }
+
+    /// Drop what was written to file `id` since the last commit
+    pub fn forget(&self, id: usize) {
+        self.dirty.lock().remove(&id);
+    }
+
+    /// Remove file `id` from the storage once committed, dropping what was written to it
+    pub fn remove(&self, id: usize) {
+        self.forget(id);
+        self.removed.lock().insert(id);
+    }
+
+    /// File `id` made again, no longer to be removed
+    pub fn keep(&self, id: usize) {
+        self.removed.lock().remove(&id);
+    }
+
+    /// Commit all written since the last time, then remove the files removed since, see the
+    /// module docs
+    pub fn commit(&self, storage: &dyn Storage) -> vfs::Result<()> {
+        let dirty = core::mem::take(&mut *self.dirty.lock());
+        // kept locked, so that no write is lost between
+        let mut files: Vec<(&Inner, MutexGuard<State>)> = dirty
+            .values()
+            .map(|inner| (&**inner, inner.state.lock()))
+            .filter(|(_, state)| state.changed())
+            .collect();
+        if !files.is_empty() {
+            let data = encode(&files);
+            self.journal.write_all_at(&data, 0)?;
+            self.journal.flush()?;
+            for (inner, state) in files.iter_mut() {
+                apply(
+                    &*inner.file,
+                    state.min_len,
+                    state.len,
+                    state.blocks.iter().map(|(&id, block)| (id, &block[..])),
+                )?;
+                state.blocks.clear();
+                state.file_len = state.len;
+                state.min_len = state.len;
+            }
+            self.journal.set_len(0)?;
+            self.journal.flush()?;
+        }
+        drop(files);
+        let removed = core::mem::take(&mut *self.removed.lock());
+        for id in removed {
+            storage.remove(id)?;
+        }
+        Ok(())
+    }
+}
+
+impl State {
+    fn changed(&self) -> bool {
+        !self.blocks.is_empty() || self.min_len != self.file_len || self.len != self.file_len
+    }
+}
+
+impl Inner {
+    fn _read_at(&self, state: &State, buf: &mut [u8], offset: usize) -> DevResult<usize> {
+        if offset >= state.len {
+            return Ok(0);
+        }
+        let end = (offset + buf.len()).min(state.len);
+        let buf = &mut buf[..end - offset];
+        // what the storage holds, then what is written since
+        let stored = state.file_len.min(state.min_len).min(end);
+        let mut read = 0;
+        if offset < stored {
+            read = stored - offset;
+            self.file.read_exact_at(&mut buf[..read], offset)?;
+        }
+        for byte in buf[read..].iter_mut() {
+            *byte = 0;
+        }
+        for (&id, block) in state.blocks.range(offset / BLKSIZE..=(end - 1) / BLKSIZE) {
+            let begin = id * BLKSIZE;
+            let (from, to) = (offset.max(begin), end.min(begin + BLKSIZE));
+            buf[from - offset..to - offset].copy_from_slice(&block[from - begin..to - begin]);
+        }
+        Ok(end - offset)
+    }
+
+    /// Block `id` to be written, as it is now
+    fn block<'a>(&self, state: &'a mut State, id: BlockId) -> DevResult<&'a mut Vec<u8>> {
+        if !state.blocks.contains_key(&id) {
+            let mut block = vec![0; BLKSIZE];
+            self._read_at(state, &mut block, id * BLKSIZE)?;
+            state.blocks.insert(id, block);
+        }
+        Ok(state.blocks.get_mut(&id).unwrap())
+    }
+}
+
+impl JournaledFile {
+    fn written(&self) {
+        self.journal
+            .dirty
+            .lock()
+            .insert(self.inner.id, self.inner.clone());
+    }
+}
+
+impl File for JournaledFile {
+    fn read_at(&self, buf: &mut [u8], offset: usize) -> DevResult<usize> {
+        self.inner._read_at(&self.inner.state.lock(), buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: usize) -> DevResult<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let state = &mut *self.inner.state.lock();
+        let end = offset + buf.len();
+        for id in offset / BLKSIZE..=(end - 1) / BLKSIZE {
+            let begin = id * BLKSIZE;
+            let (from, to) = (offset.max(begin), end.min(begin + BLKSIZE));
+            let block = self.inner.block(state, id)?;
+            block[from - begin..to - begin].copy_from_slice(&buf[from - offset..to - offset]);
+        }
+        state.len = state.len.max(end);
+        self.written();
+        Ok(buf.len())
+    }
+
+    fn set_len(&self, len: usize) -> DevResult<()> {
+        let state = &mut *self.inner.state.lock();
+        if len < state.len {
+            // the block cut is kept with the rest zeroed, those after dropped
+            if len % BLKSIZE != 0 {
+                let block = self.inner.block(state, len / BLKSIZE)?;
+                for byte in block[len % BLKSIZE..].iter_mut() {
+                    *byte = 0;
+                }
+            }
+            state.blocks.split_off(&((len + BLKSIZE - 1) / BLKSIZE));
+            state.min_len = state.min_len.min(len);
+        }
+        state.len = len;
+        self.written();
+        Ok(())
+    }
+
+    /// Written by `Journal::commit()`
+    fn flush(&self) -> DevResult<()> {
+        Ok(())
+    }
+}
+
+/// Cut `file` to `min_len`, write `blocks` to it, then set it to `len`
+fn apply<'a>(
+    file: &dyn File,
+    min_len: usize,
+    len: usize,
+    blocks: impl Iterator<Item = (BlockId, &'a [u8])>,
+) -> DevResult<()> {
+    file.set_len(min_len)?;
+    for (id, block) in blocks {
+        let begin = id * BLKSIZE;
+        file.write_all_at(&block[..BLKSIZE.min(len - begin)], begin)?;
+    }
+    file.set_len(len)?;
+    file.flush()
+}
+
+fn encode(files: &[(&Inner, MutexGuard<State>)]) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(&JOURNAL_MAGIC.to_le_bytes());
+    data.extend_from_slice(&(files.len() as u32).to_le_bytes());
+    for (inner, state) in files.iter() {
+        let key = inner.key.unwrap_or_default();
+        data.extend_from_slice(&(inner.id as u64).to_le_bytes());
+        data.extend_from_slice(&(state.min_len as u64).to_le_bytes());
+        data.extend_from_slice(&(state.len as u64).to_le_bytes());
+        data.extend_from_slice(&(state.blocks.len() as u32).to_le_bytes());
+        data.extend_from_slice(&(inner.key.is_some() as u32).to_le_bytes());
+        data.extend_from_slice(key.as_buf());
+        for (&id, block) in state.blocks.iter() {
+            data.extend_from_slice(&(id as u64).to_le_bytes());
+            data.extend_from_slice(block);
+        }
+    }
+    let hash = sha256(&data);
+    data.extend_from_slice(&hash);
+    data
+}
+
+/// The files of a whole record, none if it was cut short
+fn decode(data: &[u8]) -> Option<Vec<Record<'_>>> {
+    let u32_at = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+    let u64_at = |i: usize| u64::from(u32_at(i)) | u64::from(u32_at(i + 4)) << 32;
+    if data.len() < 8 + HASH_SIZE || u32_at(0) != JOURNAL_MAGIC {
+        return None;
+    }
+    let mut records = Vec::new();
+    let mut at = 8;
+    for _ in 0..u32_at(4) {
+        if data.len() < at + FILE_HEADER_SIZE + HASH_SIZE {
+            return None;
+        }
+        let count = u32_at(at + 24) as usize;
+        let mut key = WrappedKey::default();
+        key.as_buf_mut()
+            .copy_from_slice(&data[at + 32..at + FILE_HEADER_SIZE]);
+        let blocks_at = at + FILE_HEADER_SIZE;
+        if data.len() < blocks_at + count * ENTRY_SIZE + HASH_SIZE {
+            return None;
+        }
+        records.push(Record {
+            id: u64_at(at) as usize,
+            key: match u32_at(at + 28) {
+                0 => None,
+                _ => Some(key),
+            },
+            min_len: u64_at(at + 8) as usize,
+            len: u64_at(at + 16) as usize,
+            blocks: (0..count)
+                .map(|i| {
+                    let at = blocks_at + i * ENTRY_SIZE;
+                    (u64_at(at) as BlockId, &data[at + 8..at + ENTRY_SIZE])
+                })
+                .collect(),
+        });
+        at = blocks_at + count * ENTRY_SIZE;
+    }
+    match sha256(&data[..at])[..] == data[at..at + HASH_SIZE] {
+        true => Some(records),
+        false => None,
+    }
+}
+
+fn read_all(file: &dyn File) -> DevResult<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 0x1000];
+    loop {
+        let len = file.read_at(&mut buf, data.len())?;
+        data.extend_from_slice(&buf[..len]);
+        if len < buf.len() {
+            return Ok(data);
+        }
+    }
+}
+
+/// Length of `file`, found by reading a byte at a time
+fn len_of(file: &dyn File) -> DevResult<usize> {
+    let mut byte = [0u8; 1];
+    let mut has = |pos: usize| file.read_at(&mut byte, pos).map(|len| len == 1);
+    // the byte before `end` is the first not found, if any is
+    let mut end = 1;
+    while has(end - 1)? {
+        end *= 2;
+    }
+    let (mut lo, mut hi) = (end / 2, end - 1);
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        match has(mid)? {
+            true => lo = mid + 1,
+            false => hi = mid,
+        }
+    }
+    Ok(lo)
+}
@@ -0,0 +1,244 @@
+//! Keys of SEFS
+//!
+//! The storage encrypts each file with a key of its own. The key of a file is derived when
+//! it is made from the master key and a number never used before, `SuperBlock::key_seq`, by
+//! HMAC-SHA256 under the master key, and kept wrapped in its inode: XORed with a pad derived
+//! the same way from another number, with a tag binding it to the file, and the version of
+//! the master key it was wrapped with. The key of the metadata file is kept so in the key
+//! file, `KEY_FILE`, in plaintext, as it is read before the rest.
+//!
+//! The master key is told by a `KeyProvider`, so that the enclave can have it delivered once
+//! attested rather than built in. To rotate it, the provider tells a new version, then
+//! `SEFS::rotate_keys()` wraps the keys again under it, leaving the files as they are
+//! encrypted; the old version can be retired once it returns. A key whose master key is no
+//! longer told fails with `NotPermitted`, and a wrapped key not matching its tag with
+//! `Corrupted(id)`, `id` naming its file. `SEFS::open()` and `SEFS::create()` take a
+//! `FixedKey` of 0s, as the key built in before.
+//!
+//! In `Mode::Mac` nothing is encrypted, and the key of the metadata file is the key of the
+//! MACs of all files instead, see `merkle`.
+//!
+//! A file left in plaintext, see `INodeImpl::set_encrypted()`, has no key: its inode holds
+//! a `WrappedKey` of 0s, and rotation passes it by.
+//!
+//! A provider with a source of randomness gives random keys to the files instead, marked
+//! `FLAG_RANDOM_KEY`: the key of such a file is nowhere but in its inode, which is zeroed
+//! when the file is removed, so its data left on the host can no longer be read, see
+//! `INode::secure_unlink()`. Copies of the metadata taken before still hold it wrapped,
+//! until the master key it was wrapped with is retired.
+use alloc::sync::Arc;
+use core::mem::size_of;
+
+use rcore_fs::vfs::{self, FsError};
+
+use crate::crypto::hmac_sha256;
+use crate::dev::Storage;
+use crate::structs::*;
+use crate::SEFS;
+
+/// Bytes of a key
+pub const KEY_SIZE: usize = 16;
+
+pub type Key = [u8; KEY_SIZE];
+
+/// Where the master key of SEFS comes from
+pub trait KeyProvider: Send + Sync {
+    /// Version of the master key the keys are wrapped with from now on
+    fn version(&self) -> u32;
+    /// Master key of version `version`, `None` once it is retired
+    fn master_key(&self, version: u32) -> Option<Key>;
+    /// A random key for a new file, `None` if there is no source of randomness, then the key
+    /// is derived from the master key
+    fn random_key(&self) -> Option<Key> {
+        None
+    }
+}
+
+/// A master key which never changes, its version 0
+pub struct FixedKey(pub Key);
+
+impl KeyProvider for FixedKey {
+    fn version(&self) -> u32 {
+        0
+    }
+    fn master_key(&self, version: u32) -> Option<Key> {
+        match version {
+            0 => Some(self.0),
+            _ => None,
+        }
+    }
+    #[cfg(any(test, feature = "std"))]
+    fn random_key(&self) -> Option<Key> {
+        use std::io::Read;
+        let mut key = [0u8; KEY_SIZE];
+        std::fs::File::open("/dev/urandom")
+            .and_then(|mut file| file.read_exact(&mut key))
+            .ok()?;
+        Some(key)
+    }
+}
+
+/// Key derived by `label` and number `seq` from master key `master`
+fn derive(master: &Key, label: &[u8], seq: u64, file_id: usize) -> Key {
+    let hash = hmac_sha256(
+        master,
+        &[label, &seq.to_le_bytes(), &(file_id as u64).to_le_bytes()],
+    );
+    let mut key = [0u8; KEY_SIZE];
+    key.copy_from_slice(&hash[..KEY_SIZE]);
+    key
+}
+
+/// Tag binding `wrapped`, wrapped with number `seq`, to file `file_id`
+fn tag(master: &Key, wrapped: &Key, seq: u64, file_id: usize) -> Key {
+    let hash = hmac_sha256(
+        master,
+        &[
+            b"tag",
+            &seq.to_le_bytes(),
+            &(file_id as u64).to_le_bytes(),
+            wrapped,
+        ],
+    );
+    let mut tag = [0u8; KEY_SIZE];
+    tag.copy_from_slice(&hash[..KEY_SIZE]);
+    tag
+}
+
+fn xor(a: &Key, b: &Key) -> Key {
+    let mut key = *a;
+    for (x, y) in key.iter_mut().zip(b.iter()) {
+        *x ^= y;
+    }
+    key
+}
+
+/// Key of a new file `file_id`, from number `seq`
+pub(crate) fn new_key(keys: &dyn KeyProvider, file_id: usize, seq: u64) -> vfs::Result<Key> {
+    let master = keys
+        .master_key(keys.version())
+        .ok_or(FsError::NotPermitted)?;
+    Ok(derive(&master, b"key", seq, file_id))
+}
+
+/// Key `key` of file `file_id` wrapped under the master key now, with number `seq`
+pub(crate) fn wrap(
+    keys: &dyn KeyProvider,
+    file_id: usize,
+    key: &Key,
+    seq: u64,
+) -> vfs::Result<WrappedKey> {
+    let version = keys.version();
+    let master = keys.master_key(version).ok_or(FsError::NotPermitted)?;
+    let wrapped = xor(key, &derive(&master, b"wrap", seq, file_id));
+    Ok(WrappedKey {
+        seq,
+        version,
+        wrapped,
+        tag: tag(&master, &wrapped, seq, file_id),
+    })
+}
+
+/// The key of file `file_id` wrapped as `wrapped`
+pub(crate) fn unwrap(
+    keys: &dyn KeyProvider,
+    file_id: usize,
+    wrapped: &WrappedKey,
+) -> vfs::Result<Key> {
+    let master = keys
+        .master_key(wrapped.version)
+        .ok_or(FsError::NotPermitted)?;
+    if tag(&master, &wrapped.wrapped, wrapped.seq, file_id) != wrapped.tag {
+        error!("the key of file {} does not match its tag", file_id);
+        return Err(FsError::Corrupted(file_id));
+    }
+    Ok(xor(
+        &wrapped.wrapped,
+        &derive(&master, b"wrap", wrapped.seq, file_id),
+    ))
+}
+
+/// The key of the metadata file, kept in the key file, with the mode of the files, read
+/// before the super block telling it
+pub(crate) fn load_meta_key(device: &dyn Storage) -> vfs::Result<(WrappedKey, Mode)> {
+    let file = device.open(KEY_FILE, None)?;
+    let mut wrapped: WrappedKey = unsafe { core::mem::zeroed() };
+    file.read_exact_at(wrapped.as_buf_mut(), 0)?;
+    let mut mode = 0u32;
+    file.read_exact_at(mode.as_buf_mut(), size_of::<WrappedKey>())?;
+    let mode = Mode::from_u32(mode).ok_or(FsError::WrongFs)?;
+    Ok((wrapped, mode))
+}
+
+/// Keep `wrapped`, the key of the metadata file, in the key file, with `mode`
+pub(crate) fn store_meta_key(
+    device: &dyn Storage,
+    wrapped: &WrappedKey,
+    mode: Mode,
+) -> vfs::Result<()> {
+    let file = device.create(KEY_FILE, None)?;
+    file.write_all_at(wrapped.as_buf(), 0)?;
+    file.write_all_at((mode as u32).as_buf(), size_of::<WrappedKey>())?;
+    file.flush()?;
+    Ok(())
+}
+
+impl SEFS {
+    /// A number never used to wrap a key before
+    pub(crate) fn next_key_seq(&self) -> u64 {
+        let mut super_block = self.super_block.write();
+        super_block.key_seq += 1;
+        super_block.key_seq
+    }
+
+    /// A key for file `file_id`, never used before, with it wrapped and the flags of the
+    /// inode telling where it is from
+    pub(crate) fn make_key(&self, file_id: usize) -> vfs::Result<(Key, WrappedKey, u32)> {
+        let seq = self.next_key_seq();
+        let (key, flags) = match self.keys.random_key() {
+            Some(key) => (key, FLAG_RANDOM_KEY),
+            None => (new_key(&*self.keys, file_id, seq)?, 0),
+        };
+        Ok((key, wrap(&*self.keys, file_id, &key, seq)?, flags))
+    }
+
+    /// Wrap the keys of all files and of the metadata under the version of the master key
+    /// the provider tells now, see `key`. Return the number of keys wrapped again.
+    pub fn rotate_keys(&self) -> vfs::Result<usize> {
+        let keys: &dyn KeyProvider = &*self.keys;
+        let version = keys.version();
+        let ids: alloc::vec::Vec<usize> = {
+            let free_map = self.free_map.read();
+            (BLKN_ROOT..free_map.len())
+                .filter(|&id| !free_map[id] && id % BLKBITS != BLKN_FREEMAP)
+                .collect()
+        };
+        let mut count = 0;
+        for id in ids {
+            let inode = self.get_inode(id)?;
+            let mut disk_inode = inode.disk_inode.write();
+            if disk_inode.encrypted() && disk_inode.key.version != version {
+                let key = unwrap(keys, id, &disk_inode.key)?;
+                disk_inode.key = wrap(keys, id, &key, self.next_key_seq())?;
+                count += 1;
+            }
+        }
+        // the inodes are written before the key of the metadata, each wrapped under a
+        // version told until it returns
+        vfs::FileSystem::sync(self)?;
+        let mut meta_key = self.meta_key.write();
+        if meta_key.version != version {
+            let key = unwrap(keys, 0, &meta_key)?;
+            *meta_key = wrap(keys, 0, &key, self.next_key_seq())?;
+            vfs::FileSystem::sync(self)?;
+            store_meta_key(&*self.device, &meta_key, self.mode)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// The provider of the master key
+    pub fn key_provider(&self) -> Arc<dyn KeyProvider> {
+        self.keys.clone()
+    }
+}
@@ -1,6 +1,8 @@
 #![cfg_attr(not(any(test, feature = "std")), no_std)]
 
 extern crate alloc;
+#[macro_use]
+extern crate log;
 
 use alloc::{
     boxed::Box,
@@ -11,7 +13,6 @@ use alloc::{
 };
 use core::any::Any;
 use core::fmt::{Debug, Error, Formatter};
-use core::mem::MaybeUninit;
 
 use bitvec::prelude::*;
 use rcore_fs::dev::TimeProvider;
@@ -20,36 +21,23 @@ use rcore_fs::vfs::{self, FileSystem, FsError, INode, MMapArea, Timespec};
 use spin::RwLock;
 
 use self::dev::*;
+use self::journal::Journal;
+use self::key::{load_meta_key, new_key, store_meta_key, unwrap, wrap};
+use self::merkle::HashedFile;
 use self::structs::*;
 
+pub use self::crypto::{Hash, HASH_SIZE};
+pub use self::key::{FixedKey, Key, KeyProvider, KEY_SIZE};
+pub use self::structs::Mode;
+
+mod crypto;
 pub mod dev;
+mod journal;
+mod key;
+mod merkle;
 mod structs;
-
-/// Helper methods for `File`
-impl dyn File {
-    fn read_block(&self, id: BlockId, buf: &mut [u8]) -> DevResult<()> {
-        assert!(buf.len() <= BLKSIZE);
-        self.read_exact_at(buf, id * BLKSIZE)
-    }
-    fn write_block(&self, id: BlockId, buf: &[u8]) -> DevResult<()> {
-        assert!(buf.len() <= BLKSIZE);
-        self.write_all_at(buf, id * BLKSIZE)
-    }
-    fn read_direntry(&self, id: usize) -> DevResult<DiskEntry> {
-        let mut direntry: DiskEntry = unsafe { MaybeUninit::uninit().assume_init() };
-        self.read_exact_at(direntry.as_buf_mut(), DIRENT_SIZE * id)?;
-        Ok(direntry)
-    }
-    fn write_direntry(&self, id: usize, direntry: &DiskEntry) -> DevResult<()> {
-        self.write_all_at(direntry.as_buf(), DIRENT_SIZE * id)
-    }
-    /// Load struct `T` from given block in device
-    fn load_struct<T: AsBuf>(&self, id: BlockId) -> DevResult<T> {
-        let mut s: T = unsafe { MaybeUninit::uninit().assume_init() };
-        self.read_block(id, s.as_buf_mut())?;
-        Ok(s)
-    }
-}
+#[cfg(test)]
+mod tests;
 
 /// inode for SEFS
 pub struct INodeImpl {
@@ -58,7 +46,7 @@ pub struct INodeImpl {
     /// on-disk inode
     disk_inode: RwLock<Dirty<DiskINode>>,
     /// back file
-    file: Box<dyn File>,
+    file: HashedFile,
     /// Reference to FS
     fs: Arc<SEFS>,
 }
@@ -75,18 +63,20 @@ impl Debug for INodeImpl {
 
 impl INodeImpl {
     /// Only for Dir
-    fn get_file_inode_and_entry_id(&self, name: &str) -> Option<(INodeId, usize)> {
-        (0..self.disk_inode.read().blocks as usize)
-            .map(|i| {
-                let entry = self.file.read_direntry(i).unwrap();
-                (entry, i)
-            })
-            .find(|(entry, _)| entry.name.as_ref() == name)
-            .map(|(entry, id)| (entry.id as INodeId, id))
-    }
-    fn get_file_inode_id(&self, name: &str) -> Option<INodeId> {
-        self.get_file_inode_and_entry_id(name)
-            .map(|(inode_id, _)| inode_id)
+    /// Fails with `Corrupted` if an entry before it was changed on the host, see `merkle`
+    fn get_file_inode_and_entry_id(&self, name: &str) -> vfs::Result<Option<(INodeId, usize)>> {
+        for i in 0..self.disk_inode.read().blocks as usize {
+            let entry = self.file.read_direntry(i)?;
+            if entry.name.as_ref() == name {
+                return Ok(Some((entry.id as INodeId, i)));
+            }
+        }
+        Ok(None)
+    }
+    fn get_file_inode_id(&self, name: &str) -> vfs::Result<Option<INodeId>> {
+        Ok(self
+            .get_file_inode_and_entry_id(name)?
+            .map(|(inode_id, _)| inode_id))
     }
     /// Init dir content. Insert 2 init entries.
     /// This do not init nlinks, please modify the nlinks in the invoker.
@@ -137,6 +127,49 @@ impl INodeImpl {
         assert!(disk_inode.nlinks > 0);
         disk_inode.nlinks -= 1;
     }
+    /// Whether its file is encrypted, see `set_encrypted()`
+    pub fn is_encrypted(&self) -> bool {
+        self.disk_inode.read().encrypted()
+    }
+    /// Set whether its file is encrypted, so that public data need not pay for it, which
+    /// the entries created in a directory take from it. It is kept in the inode, covered by
+    /// the tree of the metadata, see `merkle`. Only an empty file or directory can change it,
+    /// failing with `InvalidParam` or `DirNotEmpty` otherwise, and none in `Mode::Mac`,
+    /// failing with `NotSupported`.
+    pub fn set_encrypted(&self, encrypted: bool) -> vfs::Result<()> {
+        let mut disk_inode = self.disk_inode.write();
+        if disk_inode.encrypted() == encrypted {
+            return Ok(());
+        }
+        if self.fs.mode == Mode::Mac {
+            return Err(FsError::NotSupported);
+        }
+        let parent = match disk_inode.type_ {
+            FileType::Dir if disk_inode.blocks > 2 => return Err(FsError::DirNotEmpty),
+            FileType::Dir => Some(self.file.read_direntry(1)?.id as INodeId),
+            _ if disk_inode.size > 0 => return Err(FsError::InvalidParam),
+            _ => None,
+        };
+        let (key, wrapped, flags) = match encrypted {
+            true => {
+                let (key, wrapped, flags) = self.fs.make_key(self.id)?;
+                (Some(key), wrapped, flags)
+            }
+            false => (None, WrappedKey::default(), FLAG_PLAINTEXT),
+        };
+        // made again in its place, with the key or without
+        self.fs.journal.forget(self.id);
+        self.fs.device.remove(self.id)?;
+        disk_inode.key = wrapped;
+        disk_inode.flags = disk_inode.flags & !(FLAG_PLAINTEXT | FLAG_RANDOM_KEY) | flags;
+        self.file
+            .reset(self.fs.file_of(self.id, &disk_inode, key.as_ref(), true)?)?;
+        drop(disk_inode);
+        if let Some(parent) = parent {
+            self.dirent_init(parent)?;
+        }
+        Ok(())
+    }
 }
 
 impl vfs::INode for INodeImpl {
@@ -212,6 +245,10 @@ impl vfs::INode for INodeImpl {
     }
     fn sync_all(&self) -> vfs::Result<()> {
         let mut disk_inode = self.disk_inode.write();
+        let hash = self.file.root();
+        if disk_inode.hash != hash {
+            disk_inode.hash = hash;
+        }
         if disk_inode.dirty() {
             self.fs
                 .meta_file
@@ -255,12 +292,12 @@ impl vfs::INode for INodeImpl {
         }
 
         // Ensure the name is not exist
-        if !self.get_file_inode_id(name).is_none() {
+        if self.get_file_inode_id(name)?.is_some() {
             return Err(FsError::EntryExist);
         }
 
         // Create new INode
-        let inode = self.fs.new_inode(type_, mode as u16)?;
+        let inode = self.fs.new_inode(type_, mode as u16, self.is_encrypted())?;
         if type_ == FileType::Dir {
             inode.dirent_init(self.id)?;
         }
@@ -295,9 +332,9 @@ impl vfs::INode for INodeImpl {
         }
 
         let (inode_id, entry_id) = self
-            .get_file_inode_and_entry_id(name)
+            .get_file_inode_and_entry_id(name)?
             .ok_or(FsError::EntryNotFound)?;
-        let inode = self.fs.get_inode(inode_id);
+        let inode = self.fs.get_inode(inode_id)?;
 
         let type_ = inode.disk_inode.read().type_;
         if type_ == FileType::Dir {
@@ -316,6 +353,18 @@ impl vfs::INode for INodeImpl {
 
         Ok(())
     }
+    /// Only a file with a random key can be erased, see `key`
+    fn secure_unlink(&self, name: &str) -> vfs::Result<()> {
+        let inode_id = self
+            .get_file_inode_id(name)?
+            .ok_or(FsError::EntryNotFound)?;
+        if self.fs.get_inode(inode_id)?.disk_inode.read().flags & FLAG_RANDOM_KEY == 0 {
+            return Err(FsError::NotSupported);
+        }
+        self.unlink(name)?;
+        // its inode zeroed when freed, unless still in use
+        self.fs.sync()
+    }
     fn link(&self, name: &str, other: &Arc<dyn INode>) -> vfs::Result<()> {
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
@@ -324,7 +373,7 @@ impl vfs::INode for INodeImpl {
         if info.nlinks <= 0 {
             return Err(FsError::DirRemoved);
         }
-        if !self.get_file_inode_id(name).is_none() {
+        if self.get_file_inode_id(name)?.is_some() {
             return Err(FsError::EntryExist);
         }
         let child = other
@@ -372,12 +421,12 @@ impl vfs::INode for INodeImpl {
         if dest_info.nlinks <= 0 {
             return Err(FsError::DirRemoved);
         }
-        if dest.get_file_inode_id(new_name).is_some() {
+        if dest.get_file_inode_id(new_name)?.is_some() {
             return Err(FsError::EntryExist);
         }
 
         let (inode_id, entry_id) = self
-            .get_file_inode_and_entry_id(old_name)
+            .get_file_inode_and_entry_id(old_name)?
             .ok_or(FsError::EntryNotFound)?;
         if info.inode == dest_info.inode {
             // rename: in place modify name
@@ -388,7 +437,7 @@ impl vfs::INode for INodeImpl {
             self.file.write_direntry(entry_id, &entry)?;
         } else {
             // move
-            let inode = self.fs.get_inode(inode_id);
+            let inode = self.fs.get_inode(inode_id)?;
 
             let entry = DiskEntry {
                 id: inode_id as u32,
@@ -410,8 +459,10 @@ impl vfs::INode for INodeImpl {
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
         }
-        let inode_id = self.get_file_inode_id(name).ok_or(FsError::EntryNotFound)?;
-        Ok(self.fs.get_inode(inode_id))
+        let inode_id = self
+            .get_file_inode_id(name)?
+            .ok_or(FsError::EntryNotFound)?;
+        Ok(self.fs.get_inode(inode_id)?)
     }
     fn get_entry(&self, id: usize) -> vfs::Result<String> {
         if self.disk_inode.read().type_ != FileType::Dir {
@@ -444,8 +495,13 @@ impl Drop for INodeImpl {
             .expect("Failed to sync when dropping the SEFS Inode");
         if self.disk_inode.read().nlinks <= 0 {
             self.disk_inode.write().sync();
+            // with its key, see `key`
+            self.fs
+                .meta_file
+                .write_block(self.id, &[0; BLKSIZE])
+                .expect("Failed to erase the SEFS Inode");
             self.fs.free_block(self.id);
-            self.fs.device.remove(self.id).unwrap();
+            self.fs.journal.remove(self.id);
         }
     }
 }
@@ -461,7 +517,17 @@ pub struct SEFS {
     /// device
     device: Box<dyn Storage>,
     /// metadata file
-    meta_file: Box<dyn File>,
+    meta_file: HashedFile,
+    /// journal of the metadata and the directories
+    journal: Arc<Journal>,
+    /// key of the metadata file, as kept in the key file
+    meta_key: RwLock<WrappedKey>,
+    /// provider of the master key
+    keys: Arc<dyn KeyProvider>,
+    /// what it does to its files
+    mode: Mode,
+    /// key of the MACs of the files, in `Mode::Mac`, see `merkle`
+    mac: Option<Key>,
     /// Time provider
     time_provider: &'static dyn TimeProvider,
     /// Pointer to self, used by INodes
@@ -474,11 +540,62 @@ impl SEFS {
         device: Box<dyn Storage>,
         time_provider: &'static dyn TimeProvider,
     ) -> vfs::Result<Arc<Self>> {
-        let meta_file = device.open(0)?;
-        let super_block = meta_file.load_struct::<SuperBlock>(BLKN_SUPER)?;
+        Self::_open(
+            device,
+            time_provider,
+            Arc::new(FixedKey([0; KEY_SIZE])),
+            None,
+        )
+    }
+    /// Load SEFS, with the master key told by `keys`, see `key`
+    pub fn open_with_keys(
+        device: Box<dyn Storage>,
+        time_provider: &'static dyn TimeProvider,
+        keys: Arc<dyn KeyProvider>,
+    ) -> vfs::Result<Arc<Self>> {
+        Self::_open(device, time_provider, keys, None)
+    }
+    /// Load SEFS, failing with `Corrupted(0)` unless the root of its metadata is `root`,
+    /// as told by `root_hash()` once synced, so that it is not an older copy, see `merkle`
+    pub fn open_verified(
+        device: Box<dyn Storage>,
+        time_provider: &'static dyn TimeProvider,
+        keys: Arc<dyn KeyProvider>,
+        root: &Hash,
+    ) -> vfs::Result<Arc<Self>> {
+        Self::_open(device, time_provider, keys, Some(root))
+    }
+    fn _open(
+        device: Box<dyn Storage>,
+        time_provider: &'static dyn TimeProvider,
+        keys: Arc<dyn KeyProvider>,
+        root: Option<&Hash>,
+    ) -> vfs::Result<Arc<Self>> {
+        let (meta_key, mode) = load_meta_key(&*device)?;
+        let (key, mac) = Self::meta_keys(&*keys, &meta_key, mode)?;
+        let journal = Journal::open(device.open(JOURNAL_FILE, key.as_ref())?, |id, wrapped| {
+            let key = match wrapped {
+                Some(wrapped) => Some(unwrap(&*keys, id, wrapped)?),
+                None => None,
+            };
+            Ok(device.open(id, key.as_ref())?)
+        })?;
+        let wrapped = key.map(|_| &meta_key);
+        let file = journal.file(0, wrapped, || device.open(0, key.as_ref()))?;
+        let mut super_block: SuperBlock = unsafe { core::mem::MaybeUninit::uninit().assume_init() };
+        file.read_exact_at(super_block.as_buf_mut(), BLKSIZE * BLKN_SUPER)?;
         if !super_block.check() {
             return Err(FsError::WrongFs);
         }
+        if super_block.mode != mode as u32 {
+            error!("the mode of the files is not the one of the key file");
+            return Err(FsError::Corrupted(0));
+        }
+        if root.map_or(false, |root| *root != super_block.meta_root) {
+            error!("the metadata is not the one expected");
+            return Err(FsError::Corrupted(0));
+        }
+        let meta_file = HashedFile::open(Box::new(file), 0, super_block.meta_root, BLKSIZE, mac);
 
         // load free map
         let mut free_map = BitVec::with_capacity(BLKBITS * super_block.groups as usize);
@@ -499,6 +616,11 @@ impl SEFS {
             inodes: RwLock::new(BTreeMap::new()),
             device,
             meta_file,
+            journal,
+            meta_key: RwLock::new(meta_key),
+            keys,
+            mode,
+            mac,
             time_provider,
             self_ptr: Weak::default(),
         }
@@ -508,6 +630,23 @@ impl SEFS {
     pub fn create(
         device: Box<dyn Storage>,
         time_provider: &'static dyn TimeProvider,
+    ) -> vfs::Result<Arc<Self>> {
+        Self::create_with_keys(device, time_provider, Arc::new(FixedKey([0; KEY_SIZE])))
+    }
+    /// Create a new SEFS, with the master key told by `keys`, see `key`
+    pub fn create_with_keys(
+        device: Box<dyn Storage>,
+        time_provider: &'static dyn TimeProvider,
+        keys: Arc<dyn KeyProvider>,
+    ) -> vfs::Result<Arc<Self>> {
+        Self::create_with_mode(device, time_provider, keys, Mode::Encrypted)
+    }
+    /// Create a new SEFS whose files are in `mode`, recorded in the super block
+    pub fn create_with_mode(
+        device: Box<dyn Storage>,
+        time_provider: &'static dyn TimeProvider,
+        keys: Arc<dyn KeyProvider>,
+        mode: Mode,
     ) -> vfs::Result<Arc<Self>> {
         let blocks = BLKBITS;
 
@@ -516,6 +655,9 @@ impl SEFS {
             blocks: blocks as u32,
             unused_blocks: blocks as u32 - 2,
             groups: 1,
+            meta_root: [0; HASH_SIZE],
+            key_seq: 1,
+            mode: mode as u32,
         };
         let free_map = {
             let mut bitset = BitVec::with_capacity(BLKBITS);
@@ -525,7 +667,14 @@ impl SEFS {
             }
             bitset
         };
-        let meta_file = device.create(0)?;
+        // the key of the metadata file from number 0, wrapped with 1
+        let meta_key = wrap(&*keys, 0, &new_key(&*keys, 0, 0)?, 1)?;
+        store_meta_key(&*device, &meta_key, mode)?;
+        let (key, mac) = Self::meta_keys(&*keys, &meta_key, mode)?;
+        let journal = Journal::create(device.create(JOURNAL_FILE, key.as_ref())?)?;
+        let wrapped = key.map(|_| &meta_key);
+        let file = journal.file(0, wrapped, || device.create(0, key.as_ref()))?;
+        let meta_file = HashedFile::create(Box::new(file), 0, BLKSIZE, mac)?;
         meta_file.set_len(blocks * BLKSIZE)?;
 
         let sefs = SEFS {
@@ -534,13 +683,18 @@ impl SEFS {
             inodes: RwLock::new(BTreeMap::new()),
             device,
             meta_file,
+            journal,
+            meta_key: RwLock::new(meta_key),
+            keys,
+            mode,
+            mac,
             time_provider,
             self_ptr: Weak::default(),
         }
         .wrap();
 
         // Init root INode
-        let root = sefs.new_inode(FileType::Dir, 0o777)?;
+        let root = sefs.new_inode(FileType::Dir, 0o777, mode == Mode::Encrypted)?;
         assert_eq!(root.id, BLKN_ROOT);
         root.dirent_init(BLKN_ROOT)?;
         root.nlinks_inc(); //for .
@@ -549,6 +703,19 @@ impl SEFS {
 
         Ok(sefs)
     }
+    /// The key of the metadata file and the key of the MACs, as the one wrapped as `meta_key`
+    /// is used in `mode`
+    fn meta_keys(
+        keys: &dyn KeyProvider,
+        meta_key: &WrappedKey,
+        mode: Mode,
+    ) -> vfs::Result<(Option<Key>, Option<Key>)> {
+        let key = unwrap(keys, 0, meta_key)?;
+        Ok(match mode {
+            Mode::Encrypted => (Some(key), None),
+            Mode::Mac => (None, Some(key)),
+        })
+    }
     /// Wrap pure SEFS with Arc
     /// Used in constructors
     fn wrap(self) -> Arc<Self> {
@@ -600,38 +767,80 @@ impl SEFS {
         id: INodeId,
         disk_inode: Dirty<DiskINode>,
         create: bool,
-    ) -> Arc<INodeImpl> {
+    ) -> vfs::Result<Arc<INodeImpl>> {
+        let key = match disk_inode.encrypted() {
+            true => Some(unwrap(&*self.keys, id, &disk_inode.key)?),
+            false => None,
+        };
+        let file = self.file_of(id, &disk_inode, key.as_ref(), create)?;
+        let file = match create {
+            true => HashedFile::create(file, id, 0, self.mac)?,
+            false => HashedFile::open(file, id, disk_inode.hash, 0, self.mac),
+        };
         let inode = Arc::new(INodeImpl {
             id,
             disk_inode: RwLock::new(disk_inode),
-            file: match create {
-                true => self.device.create(id).unwrap(),
-                false => self.device.open(id).unwrap(),
-            },
+            file,
             fs: self.self_ptr.upgrade().unwrap(),
         });
         self.inodes.write().insert(id, Arc::downgrade(&inode));
-        inode
+        Ok(inode)
+    }
+    /// File of inode `id` in the storage, made if `create`, journaled if of a directory,
+    /// see `journal`
+    fn file_of(
+        &self,
+        id: INodeId,
+        disk_inode: &DiskINode,
+        key: Option<&Key>,
+        create: bool,
+    ) -> vfs::Result<Box<dyn File>> {
+        let open = || match create {
+            true => {
+                self.journal.keep(id);
+                self.device.create(id, key)
+            }
+            false => self.device.open(id, key),
+        };
+        Ok(match disk_inode.type_ {
+            FileType::Dir => {
+                let wrapped = key.map(|_| &disk_inode.key);
+                Box::new(self.journal.file(id, wrapped, open)?)
+            }
+            _ => open()?,
+        })
     }
     /// Get inode by id. Load if not in memory.
     /// ** Must ensure it's a valid INode **
-    fn get_inode(&self, id: INodeId) -> Arc<INodeImpl> {
+    fn get_inode(&self, id: INodeId) -> vfs::Result<Arc<INodeImpl>> {
         assert!(!self.free_map.read()[id]);
 
         // In the BTreeSet and not weak.
         if let Some(inode) = self.inodes.read().get(&id) {
             if let Some(inode) = inode.upgrade() {
-                return inode;
+                return Ok(inode);
             }
         }
         // Load if not in set, or is weak ref.
-        let disk_inode = Dirty::new(self.meta_file.load_struct::<DiskINode>(id).unwrap());
+        let disk_inode = Dirty::new(self.meta_file.load_struct::<DiskINode>(id)?);
         self._new_inode(id, disk_inode, false)
     }
-    /// Create a new INode file
-    fn new_inode(&self, type_: FileType, mode: u16) -> vfs::Result<Arc<INodeImpl>> {
+    /// Create a new INode file, encrypted or in plaintext
+    fn new_inode(
+        &self,
+        type_: FileType,
+        mode: u16,
+        encrypted: bool,
+    ) -> vfs::Result<Arc<INodeImpl>> {
         let id = self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
         let time = self.time_provider.current_time().sec as u32;
+        let (key, flags) = match encrypted {
+            true => {
+                let (_, key, flags) = self.make_key(id)?;
+                (key, flags)
+            }
+            false => (WrappedKey::default(), FLAG_PLAINTEXT),
+        };
         let disk_inode = Dirty::new_dirty(DiskINode {
             size: 0,
             type_,
@@ -643,8 +852,11 @@ impl SEFS {
             atime: time,
             mtime: time,
             ctime: time,
+            hash: [0; HASH_SIZE],
+            key,
+            flags,
         });
-        Ok(self._new_inode(id, disk_inode, true))
+        self._new_inode(id, disk_inode, true)
     }
     fn flush_weak_inodes(&self) {
         let mut inodes = self.inodes.write();
@@ -660,27 +872,31 @@ impl SEFS {
     fn get_freemap_block_id_of_group(group_id: usize) -> usize {
         BLKBITS * group_id + BLKN_FREEMAP
     }
+    /// What it does to its files, as chosen at `create_with_mode()`
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+    /// Root of the Merkle tree of the metadata as last synced, see `merkle`
+    pub fn root_hash(&self) -> Hash {
+        self.super_block.read().meta_root
+    }
 }
 
 impl vfs::FileSystem for SEFS {
     /// Write back super block if dirty
     fn sync(&self) -> vfs::Result<()> {
-        // sync super_block
-        let mut super_block = self.super_block.write();
-        if super_block.dirty() {
-            self.meta_file
-                .write_all_at(super_block.as_buf(), BLKSIZE * BLKN_SUPER)?;
-            super_block.sync();
-        }
         // sync free_map
-        let mut free_map = self.free_map.write();
-        if free_map.dirty() {
-            for i in 0..super_block.groups as usize {
-                let slice = &free_map.as_slice()[BLKSIZE * i..BLKSIZE * (i + 1)];
-                self.meta_file
-                    .write_all_at(slice, BLKSIZE * Self::get_freemap_block_id_of_group(i))?;
+        {
+            let mut free_map = self.free_map.write();
+            if free_map.dirty() {
+                let groups = self.super_block.read().groups as usize;
+                for i in 0..groups {
+                    let slice = &free_map.as_slice()[BLKSIZE * i..BLKSIZE * (i + 1)];
+                    self.meta_file
+                        .write_at(slice, BLKSIZE * Self::get_freemap_block_id_of_group(i))?;
+                }
+                free_map.sync();
             }
-            free_map.sync();
         }
         // sync all INodes
         self.flush_weak_inodes();
@@ -689,12 +905,25 @@ impl vfs::FileSystem for SEFS {
                 inode.sync_all()?;
             }
         }
+        // sync super_block last, with the root of the metadata written
+        let mut super_block = self.super_block.write();
+        let meta_root = self.meta_file.root();
+        if super_block.meta_root != meta_root {
+            super_block.meta_root = meta_root;
+        }
+        if super_block.dirty() {
+            self.meta_file
+                .write_raw(super_block.as_buf(), BLKSIZE * BLKN_SUPER)?;
+            super_block.sync();
+        }
+        // all committed at once, see `journal`
         self.meta_file.flush()?;
-        Ok(())
+        self.journal.commit(&*self.device)
     }
 
     fn root_inode(&self) -> Arc<dyn vfs::INode> {
         self.get_inode(BLKN_ROOT)
+            .expect("failed to load the root inode")
     }
 
     fn info(&self) -> vfs::FsInfo {
@@ -710,6 +939,15 @@ impl vfs::FileSystem for SEFS {
             namemax: MAX_FNAME_LEN,
         }
     }
+
+    fn capabilities(&self) -> vfs::FsCapabilities {
+        vfs::FsCapabilities {
+            features: vfs::FsFeatures::SYMLINK
+                | vfs::FsFeatures::HARDLINK
+                | vfs::FsFeatures::CASE_SENSITIVE,
+            namemax: MAX_FNAME_LEN,
+        }
+    }
 }
 
 impl Drop for SEFS {
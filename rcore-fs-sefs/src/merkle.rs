@@ -0,0 +1,429 @@
+//! Integrity of SEFS
+//!
+//! The storage keeps each file confidential, but whoever holds the host files can still
+//! change them, or put back an older copy of one. So each file is read and written through
+//! a `HashedFile`, which covers it with a Merkle tree: it is cut in chunks of `CHUNK_SIZE`
+//! bytes, the last one shorter, each hashed with its index, and the tree of these hashes is
+//! hashed with the length of the file into its root. The root of the file of an inode is
+//! kept in the inode, in the metadata file, itself covered by a tree whose root is kept in
+//! the super block, which is left out of it. A file is hashed whole the first time it is
+//! read or written, and checked against its root, then each chunk read is checked against
+//! its hash, and each chunk written is hashed again, with the path up to the root: a file
+//! changed on the host fails with `Corrupted(id)`, `id` naming it, as does the metadata if
+//! the super block tells another root. The roots are written with the metadata at sync, so
+//! files written since the last one fail so after a crash, but for the directories, which
+//! are journaled with it, see `journal`.
+//!
+//! The super block, kept by the storage as the other files are, could still be put back
+//! whole with the rest. `SEFS::root_hash()` tells the root it holds, which the enclave can
+//! keep where the host can not roll it back, e.g. sealed with a monotonic counter, and give
+//! back to `SEFS::open_verified()`.
+//!
+//! The hashes alone tell a change only as long as the storage keeps the files from the host.
+//! In `Mode::Mac`, where it keeps them in plaintext, the hash of each chunk is a MAC, keyed
+//! with the key of the metadata, so that the host can read the files, but not change them
+//! and hash them again.
+use alloc::{boxed::Box, vec, vec::Vec};
+
+use rcore_fs::vfs::{self, FsError};
+use spin::RwLock;
+
+use crate::crypto::{hmac_sha256, sha256, Hash, Sha256, HASH_SIZE};
+use crate::dev::File;
+use crate::key::Key;
+use crate::structs::*;
+
+/// Bytes of a chunk of a file, each hashed on its own
+pub const CHUNK_SIZE: usize = 0x1000;
+
+/// Hash of chunk `index` of file `id`, whose content hashes to `data`, its MAC under `mac` if
+/// any
+fn leaf(mac: Option<&Key>, id: usize, index: usize, data: &Hash) -> Hash {
+    if let Some(key) = mac {
+        return hmac_sha256(
+            key,
+            &[
+                &[0],
+                &(id as u64).to_le_bytes(),
+                &(index as u64).to_le_bytes(),
+                data,
+            ],
+        );
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(&[0]);
+    hasher.update(&(index as u64).to_le_bytes());
+    hasher.update(data);
+    hasher.finish()
+}
+
+/// Hash of the node above `left` and `right`, which the last one of a level may lack
+fn node(left: &Hash, right: Option<&Hash>) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(&[1]);
+    hasher.update(left);
+    if let Some(right) = right {
+        hasher.update(right);
+    }
+    hasher.finish()
+}
+
+/// Merkle tree of the hashes of the chunks of a file
+struct MerkleTree {
+    /// hashes of each level, from the chunks up to the root
+    levels: Vec<Vec<Hash>>,
+}
+
+impl MerkleTree {
+    fn new() -> Self {
+        MerkleTree {
+            levels: vec![Vec::new()],
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.levels[0].len()
+    }
+
+    /// Hash of the tree, 0s if it is empty
+    fn top(&self) -> Hash {
+        match self.levels.last().unwrap().first() {
+            Some(hash) => *hash,
+            None => [0; HASH_SIZE],
+        }
+    }
+
+    /// Set the hash of chunk `i`, one of them or the one after the last
+    fn set(&mut self, i: usize, hash: Hash) {
+        match i < self.len() {
+            true => self.levels[0][i] = hash,
+            false => self.levels[0].push(hash),
+        }
+        self.update_path(i);
+    }
+
+    /// Keep the first `n` chunks
+    fn truncate(&mut self, n: usize) {
+        self.levels[0].truncate(n);
+        for level in 1..self.levels.len() {
+            let below = self.levels[level - 1].len();
+            self.levels[level].truncate((below + 1) / 2);
+        }
+        match n {
+            0 => self.levels.truncate(1),
+            n => self.update_path(n - 1),
+        }
+    }
+
+    /// Hash again the nodes above chunk `i`
+    fn update_path(&mut self, mut i: usize) {
+        let mut level = 0;
+        while self.levels[level].len() > 1 {
+            let parent = i / 2;
+            let hash = {
+                let nodes = &self.levels[level];
+                node(&nodes[parent * 2], nodes.get(parent * 2 + 1))
+            };
+            if level + 1 == self.levels.len() {
+                self.levels.push(Vec::new());
+            }
+            let up = &mut self.levels[level + 1];
+            match parent < up.len() {
+                true => up[parent] = hash,
+                false => up.push(hash),
+            }
+            i = parent;
+            level += 1;
+        }
+        self.levels.truncate(level + 1);
+    }
+}
+
+/// What a `HashedFile` knows of its file once hashed
+struct Hashed {
+    tree: MerkleTree,
+    /// bytes of the file
+    len: usize,
+}
+
+impl Hashed {
+    /// Root of a file of `len` bytes, whose chunks `tree` is of
+    fn root(&self) -> Hash {
+        let mut hasher = Sha256::new();
+        hasher.update(&[2]);
+        hasher.update(&(self.len as u64).to_le_bytes());
+        hasher.update(&self.tree.top());
+        hasher.finish()
+    }
+}
+
+/// A file of the storage checked against the root of its Merkle tree, see the module docs
+pub struct HashedFile {
+    file: RwLock<Box<dyn File>>,
+    /// its name in the storage, told by the errors
+    id: usize,
+    /// bytes at its start left out of the hashes, written by `write_raw()`
+    skip: usize,
+    /// the root it must have, until it is hashed
+    root: Hash,
+    /// key of the MACs of its chunks, in `Mode::Mac`
+    mac: Option<Key>,
+    hashed: RwLock<Option<Hashed>>,
+}
+
+impl HashedFile {
+    /// A file of the storage whose root is `root`, hashed once used
+    pub fn open(file: Box<dyn File>, id: usize, root: Hash, skip: usize, mac: Option<Key>) -> Self {
+        HashedFile {
+            file: RwLock::new(file),
+            id,
+            skip,
+            root,
+            mac,
+            hashed: RwLock::new(None),
+        }
+    }
+
+    /// A file just made in the storage, empty
+    pub fn create(
+        file: Box<dyn File>,
+        id: usize,
+        skip: usize,
+        mac: Option<Key>,
+    ) -> vfs::Result<Self> {
+        file.set_len(0)?;
+        let hashed = Hashed {
+            tree: MerkleTree::new(),
+            len: 0,
+        };
+        Ok(HashedFile {
+            file: RwLock::new(file),
+            id,
+            skip,
+            root: hashed.root(),
+            mac,
+            hashed: RwLock::new(Some(hashed)),
+        })
+    }
+
+    /// Start over with `file`, made again in the storage in its place, e.g. with another key
+    pub fn reset(&self, file: Box<dyn File>) -> vfs::Result<()> {
+        let mut hashed = self.hashed.write();
+        file.set_len(0)?;
+        *self.file.write() = file;
+        *hashed = Some(Hashed {
+            tree: MerkleTree::new(),
+            len: 0,
+        });
+        Ok(())
+    }
+
+    /// The root of its content now
+    pub fn root(&self) -> Hash {
+        match &*self.hashed.read() {
+            Some(hashed) => hashed.root(),
+            None => self.root,
+        }
+    }
+
+    pub fn read_at(&self, buf: &mut [u8], offset: usize) -> vfs::Result<usize> {
+        self.load()?;
+        let hashed = self.hashed.read();
+        let hashed = hashed.as_ref().unwrap();
+        let end = (offset + buf.len()).min(hashed.len);
+        if offset >= end {
+            return Ok(0);
+        }
+        for index in offset / CHUNK_SIZE..=(end - 1) / CHUNK_SIZE {
+            let begin = index * CHUNK_SIZE;
+            let chunk = self.read_chunk(hashed, index)?;
+            let from = offset.max(begin);
+            let to = end.min(begin + chunk.len());
+            buf[from - offset..to - offset].copy_from_slice(&chunk[from - begin..to - begin]);
+        }
+        Ok(end - offset)
+    }
+
+    pub fn write_at(&self, buf: &[u8], offset: usize) -> vfs::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.load()?;
+        let mut hashed = self.hashed.write();
+        let hashed = hashed.as_mut().unwrap();
+        if offset > hashed.len {
+            self._set_len(hashed, offset)?;
+        }
+        let end = offset + buf.len();
+        for index in offset / CHUNK_SIZE..=(end - 1) / CHUNK_SIZE {
+            let begin = index * CHUNK_SIZE;
+            // what it holds is checked before it is hashed again with what is written
+            let mut chunk = match index < hashed.tree.len() {
+                true => self.read_chunk(hashed, index)?,
+                false => Vec::new(),
+            };
+            let to = end.min(begin + CHUNK_SIZE);
+            if chunk.len() < to - begin {
+                chunk.resize(to - begin, 0);
+            }
+            let from = offset.max(begin);
+            chunk[from - begin..to - begin].copy_from_slice(&buf[from - offset..to - offset]);
+            self.file
+                .read()
+                .write_all_at(&buf[from - offset..to - offset], from)?;
+            hashed.tree.set(index, self.leaf(index, &chunk));
+        }
+        hashed.len = hashed.len.max(end);
+        Ok(buf.len())
+    }
+
+    pub fn set_len(&self, len: usize) -> vfs::Result<()> {
+        self.load()?;
+        let mut hashed = self.hashed.write();
+        self._set_len(hashed.as_mut().unwrap(), len)
+    }
+
+    pub fn flush(&self) -> vfs::Result<()> {
+        self.file.read().flush()?;
+        Ok(())
+    }
+
+    /// Write bytes of the part left out of the hashes
+    pub fn write_raw(&self, buf: &[u8], offset: usize) -> vfs::Result<()> {
+        assert!(offset + buf.len() <= self.skip);
+        self.file.read().write_all_at(buf, offset)?;
+        Ok(())
+    }
+
+    /// Hash the file whole, if it was not, and check its root
+    fn load(&self) -> vfs::Result<()> {
+        if self.hashed.read().is_some() {
+            return Ok(());
+        }
+        let mut hashed = self.hashed.write();
+        if hashed.is_some() {
+            return Ok(());
+        }
+        let mut loaded = Hashed {
+            tree: MerkleTree::new(),
+            len: 0,
+        };
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        loop {
+            let index = loaded.tree.len();
+            let len = self.file.read().read_at(&mut chunk, index * CHUNK_SIZE)?;
+            if len == 0 {
+                break;
+            }
+            loaded.tree.set(index, self.leaf(index, &chunk[..len]));
+            loaded.len += len;
+            if len < CHUNK_SIZE {
+                break;
+            }
+        }
+        if loaded.root() != self.root {
+            error!("file {} does not match its hash", self.id);
+            return Err(FsError::Corrupted(self.id));
+        }
+        *hashed = Some(loaded);
+        Ok(())
+    }
+
+    /// Chunk `index` of the file, checked against its hash
+    fn read_chunk(&self, hashed: &Hashed, index: usize) -> vfs::Result<Vec<u8>> {
+        let begin = index * CHUNK_SIZE;
+        let mut chunk = vec![0u8; hashed.len.min(begin + CHUNK_SIZE) - begin];
+        self.file.read().read_exact_at(&mut chunk, begin)?;
+        if self.leaf(index, &chunk) != hashed.tree.levels[0][index] {
+            error!(
+                "chunk {} of file {} does not match its hash",
+                index, self.id
+            );
+            return Err(FsError::Corrupted(self.id));
+        }
+        Ok(chunk)
+    }
+
+    /// Hash of chunk `index`, holding `data`, with the part left out as 0s
+    fn leaf(&self, index: usize, data: &[u8]) -> Hash {
+        let begin = index * CHUNK_SIZE;
+        if begin >= self.skip {
+            return leaf(self.mac.as_ref(), self.id, index, &sha256(data));
+        }
+        let mut data = data.to_vec();
+        let skip = (self.skip - begin).min(data.len());
+        for byte in data[..skip].iter_mut() {
+            *byte = 0;
+        }
+        leaf(self.mac.as_ref(), self.id, index, &sha256(&data))
+    }
+
+    fn _set_len(&self, hashed: &mut Hashed, len: usize) -> vfs::Result<()> {
+        let old_len = hashed.len;
+        if len == old_len {
+            return Ok(());
+        }
+        // the chunk cut or grown, checked before it is hashed again
+        let last = len.min(old_len) / CHUNK_SIZE;
+        let mut chunk = match last < hashed.tree.len() {
+            true => self.read_chunk(hashed, last)?,
+            false => Vec::new(),
+        };
+        self.file.read().set_len(len)?;
+        hashed.tree.truncate(last);
+        hashed.len = len;
+        let chunks = (len + CHUNK_SIZE - 1) / CHUNK_SIZE;
+        let zeros = sha256(&[0u8; CHUNK_SIZE]);
+        for index in last..chunks {
+            let size = len.min((index + 1) * CHUNK_SIZE) - index * CHUNK_SIZE;
+            let hash = match index == last || size < CHUNK_SIZE {
+                true => {
+                    chunk.resize(size, 0);
+                    let hash = self.leaf(index, &chunk);
+                    chunk.clear();
+                    hash
+                }
+                // a chunk of 0s is hashed once
+                false => leaf(self.mac.as_ref(), self.id, index, &zeros),
+            };
+            hashed.tree.set(index, hash);
+        }
+        Ok(())
+    }
+
+    pub fn read_block(&self, id: BlockId, buf: &mut [u8]) -> vfs::Result<()> {
+        assert!(buf.len() <= BLKSIZE);
+        self.read_exact_at(buf, id * BLKSIZE)
+    }
+
+    pub fn write_block(&self, id: BlockId, buf: &[u8]) -> vfs::Result<()> {
+        assert!(buf.len() <= BLKSIZE);
+        self.write_at(buf, id * BLKSIZE)?;
+        Ok(())
+    }
+
+    pub fn read_direntry(&self, id: usize) -> vfs::Result<DiskEntry> {
+        let mut direntry: DiskEntry = unsafe { core::mem::MaybeUninit::uninit().assume_init() };
+        self.read_exact_at(direntry.as_buf_mut(), DIRENT_SIZE * id)?;
+        Ok(direntry)
+    }
+
+    pub fn write_direntry(&self, id: usize, direntry: &DiskEntry) -> vfs::Result<()> {
+        self.write_at(direntry.as_buf(), DIRENT_SIZE * id)?;
+        Ok(())
+    }
+
+    /// Load struct `T` from given block in device
+    pub fn load_struct<T: AsBuf>(&self, id: BlockId) -> vfs::Result<T> {
+        let mut s: T = unsafe { core::mem::MaybeUninit::uninit().assume_init() };
+        self.read_block(id, s.as_buf_mut())?;
+        Ok(s)
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offset: usize) -> vfs::Result<()> {
+        match self.read_at(buf, offset)? == buf.len() {
+            true => Ok(()),
+            false => Err(FsError::DeviceError),
+        }
+    }
+}
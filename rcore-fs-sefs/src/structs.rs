@@ -6,6 +6,9 @@ use core::mem::{size_of, size_of_val};
 use core::slice;
 use static_assertions::const_assert;
 
+use crate::crypto::HASH_SIZE;
+use crate::key::KEY_SIZE;
+
 /// On-disk superblock
 #[repr(C)]
 #[derive(Debug)]
@@ -18,6 +21,12 @@ pub struct SuperBlock {
     pub unused_blocks: u32,
     /// number of block groups
     pub groups: u32,
+    /// root of the Merkle tree of the metadata file, see `merkle`
+    pub meta_root: [u8; HASH_SIZE],
+    /// number the last key was wrapped with, see `key`
+    pub key_seq: u64,
+    /// `Mode` of the files
+    pub mode: u32,
 }
 
 /// On-disk inode
@@ -40,6 +49,36 @@ pub struct DiskINode {
     pub atime: u32,
     pub mtime: u32,
     pub ctime: u32,
+    /// root of the Merkle tree of its file, see `merkle`
+    pub hash: [u8; HASH_SIZE],
+    /// key of its file, none if it is in plaintext
+    pub key: WrappedKey,
+    /// FLAG_*
+    pub flags: u32,
+}
+
+/// A key wrapped under the master key, see `key`
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct WrappedKey {
+    /// number it was wrapped with, never used twice
+    pub seq: u64,
+    /// version of the master key it was wrapped with
+    pub version: u32,
+    /// the key XORed with its pad
+    pub wrapped: [u8; KEY_SIZE],
+    /// tag binding it to its file
+    pub tag: [u8; KEY_SIZE],
+}
+
+/// What SEFS does to its files, chosen at `SEFS::create_with_mode()`
+#[repr(u32)]
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Mode {
+    /// encrypted by the storage, but those set in plaintext, see `INodeImpl::set_encrypted()`
+    Encrypted = 0,
+    /// kept in plaintext, the metadata too, each chunk authenticated by a MAC, see `merkle`
+    Mac = 1,
 }
 
 /// On-disk file entry
@@ -82,6 +121,22 @@ impl SuperBlock {
     }
 }
 
+impl Mode {
+    pub fn from_u32(mode: u32) -> Option<Self> {
+        match mode {
+            0 => Some(Mode::Encrypted),
+            1 => Some(Mode::Mac),
+            _ => None,
+        }
+    }
+}
+
+impl DiskINode {
+    pub fn encrypted(&self) -> bool {
+        self.flags & FLAG_PLAINTEXT == 0
+    }
+}
+
 /// Convert structs to [u8] slice
 pub trait AsBuf {
     fn as_buf(&self) -> &[u8] {
@@ -98,6 +153,8 @@ impl AsBuf for DiskINode {}
 
 impl AsBuf for DiskEntry {}
 
+impl AsBuf for WrappedKey {}
+
 impl AsBuf for u32 {}
 
 /*
@@ -107,8 +164,8 @@ impl AsBuf for u32 {}
 pub type BlockId = usize;
 pub type INodeId = BlockId;
 
-/// magic number for sfs
-pub const MAGIC: u32 = 0x2f8dbe2a;
+/// magic number for sefs, changed when the files were hashed
+pub const MAGIC: u32 = 0x2f8dbe2b;
 /// size of block
 pub const BLKSIZE: usize = 1usize << BLKSIZE_LOG2;
 /// log2( size of block )
@@ -121,10 +178,19 @@ pub const BLKN_SUPER: BlockId = 0;
 pub const BLKN_ROOT: BlockId = 2;
 /// 1st block of the freemap
 pub const BLKN_FREEMAP: BlockId = 1;
+/// file holding the key of the metadata file, named as no inode is, as its block is the
+/// 1st of the freemap
+pub const KEY_FILE: usize = 1;
+/// journal of the metadata, see `journal`, named as the 1st block of the freemap of group 1
+pub const JOURNAL_FILE: usize = BLKBITS + BLKN_FREEMAP;
 /// number of bits in a block
 pub const BLKBITS: usize = BLKSIZE * 8;
 /// size of a dirent used in the size field
 pub const DIRENT_SIZE: usize = 260;
+/// the file of the inode is kept in plaintext, as are the files created in it if a dir
+pub const FLAG_PLAINTEXT: u32 = 1;
+/// the key of the file is random, not derived from the master key, see `key`
+pub const FLAG_RANDOM_KEY: u32 = 2;
 
 /// file types
 #[repr(u16)]
@@ -0,0 +1,846 @@
+extern crate std;
+
+use crate::merkle::CHUNK_SIZE;
+use crate::*;
+use rcore_fs::vfs::{FileType, Result};
+use spin::Mutex;
+
+struct Clock;
+
+impl TimeProvider for Clock {
+    fn current_time(&self) -> Timespec {
+        Timespec { sec: 1, nsec: 0 }
+    }
+}
+
+static CLOCK: Clock = Clock;
+
+/// What the host holds of each file of a storage, by its id
+type Image = BTreeMap<usize, Vec<u8>>;
+
+/// A storage in memory, standing for the host. A file is "encrypted" by XORing it with the
+/// key it is opened with, so that what the host holds tells whether it is, and a file read
+/// with another key reads garbage.
+#[derive(Clone, Default)]
+struct MemStorage {
+    files: Arc<Mutex<Image>>,
+    /// the images at each commit of a journal, as a kill just after it would leave them
+    commits: Arc<Mutex<Vec<Image>>>,
+}
+
+impl MemStorage {
+    fn from_image(image: Image) -> Self {
+        MemStorage {
+            files: Arc::new(Mutex::new(image)),
+            ..MemStorage::default()
+        }
+    }
+
+    fn image(&self) -> Image {
+        self.files.lock().clone()
+    }
+
+    /// Host bytes of file `id`
+    fn host(&self, id: usize) -> Vec<u8> {
+        self.files.lock()[&id].clone()
+    }
+
+    /// Change the host bytes of file `id` by `f`
+    fn tamper(&self, id: usize, f: impl FnOnce(&mut Vec<u8>)) {
+        f(self.files.lock().get_mut(&id).unwrap());
+    }
+}
+
+impl Storage for MemStorage {
+    fn open(&self, file_id: usize, key: Option<&Key>) -> DevResult<Box<dyn File>> {
+        if !self.files.lock().contains_key(&file_id) {
+            return Err(DeviceError);
+        }
+        Ok(Box::new(MemFile {
+            storage: self.clone(),
+            id: file_id,
+            key: key.cloned(),
+        }))
+    }
+
+    fn create(&self, file_id: usize, key: Option<&Key>) -> DevResult<Box<dyn File>> {
+        self.files.lock().insert(file_id, Vec::new());
+        self.open(file_id, key)
+    }
+
+    fn remove(&self, file_id: usize) -> DevResult<()> {
+        self.files.lock().remove(&file_id).ok_or(DeviceError)?;
+        Ok(())
+    }
+}
+
+struct MemFile {
+    storage: MemStorage,
+    id: usize,
+    key: Option<Key>,
+}
+
+impl MemFile {
+    fn xor(&self, buf: &mut [u8], offset: usize) {
+        if let Some(key) = &self.key {
+            for (i, byte) in buf.iter_mut().enumerate() {
+                *byte ^= key[(offset + i) % KEY_SIZE];
+            }
+        }
+    }
+}
+
+impl File for MemFile {
+    fn read_at(&self, buf: &mut [u8], offset: usize) -> DevResult<usize> {
+        let files = self.storage.files.lock();
+        let data = files.get(&self.id).ok_or(DeviceError)?;
+        let begin = offset.min(data.len());
+        let len = buf.len().min(data.len() - begin);
+        buf[..len].copy_from_slice(&data[begin..begin + len]);
+        self.xor(&mut buf[..len], offset);
+        Ok(len)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: usize) -> DevResult<usize> {
+        let mut bytes = buf.to_vec();
+        self.xor(&mut bytes, offset);
+        let mut files = self.storage.files.lock();
+        let data = files.get_mut(&self.id).ok_or(DeviceError)?;
+        if data.len() < offset + buf.len() {
+            data.resize(offset + buf.len(), 0);
+        }
+        data[offset..offset + buf.len()].copy_from_slice(&bytes);
+        Ok(buf.len())
+    }
+
+    fn set_len(&self, len: usize) -> DevResult<()> {
+        let mut files = self.storage.files.lock();
+        let data = files.get_mut(&self.id).ok_or(DeviceError)?;
+        let old = data.len();
+        data.resize(len, 0);
+        drop(files);
+        // what is grown reads 0s, whatever the key
+        if len > old {
+            self.write_at(&vec![0; len - old], old)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> DevResult<()> {
+        let files = self.storage.files.lock();
+        if self.id == JOURNAL_FILE && !files[&self.id].is_empty() {
+            self.storage.commits.lock().push(files.clone());
+        }
+        Ok(())
+    }
+}
+
+/// Content `len` bytes long, told apart by `seed`
+fn data(len: usize, seed: usize) -> Vec<u8> {
+    (0..len).map(|i| (i / 509 + seed * 7) as u8).collect()
+}
+
+fn read_all(file: &Arc<dyn INode>) -> Result<Vec<u8>> {
+    let mut buf = vec![0; file.metadata()?.size];
+    let len = file.read_at(0, &mut buf)?;
+    assert_eq!(len, buf.len());
+    Ok(buf)
+}
+
+fn inode_of(inode: &Arc<dyn INode>) -> &INodeImpl {
+    inode.downcast_ref::<INodeImpl>().unwrap()
+}
+
+#[test]
+fn merkle_trees() -> Result<()> {
+    let storage = MemStorage::default();
+    let sefs = SEFS::create(Box::new(storage.clone()), &CLOCK)?;
+    let root = sefs.root_inode();
+    let file = root.create("file", FileType::File, 0o644)?;
+    let content = data(5 * CHUNK_SIZE + 100, 1);
+    file.write_at(0, &content)?;
+    let id = file.metadata()?.inode;
+    sefs.sync()?;
+    assert_eq!(storage.host(id).len(), content.len());
+    assert_ne!(storage.host(id)[..CHUNK_SIZE], content[..CHUNK_SIZE]);
+    // kept in the inode, and the root of the metadata in the super block
+    assert_eq!(
+        inode_of(&file).disk_inode.read().hash,
+        inode_of(&file).file.root()
+    );
+    let meta_root = sefs.root_hash();
+    assert_eq!(meta_root, sefs.meta_file.root());
+    // updated chunk by chunk
+    file.write_at(2 * CHUNK_SIZE, b"changed")?;
+    assert_ne!(
+        inode_of(&file).file.root(),
+        inode_of(&file).disk_inode.read().hash
+    );
+    sefs.sync()?;
+    assert_ne!(sefs.root_hash(), meta_root);
+    let old = storage.image();
+    let old_root = sefs.root_hash();
+    file.write_at(0, b"newer")?;
+    drop(file);
+    drop(root);
+    drop(sefs);
+    let mut content = content;
+    content[2 * CHUNK_SIZE..2 * CHUNK_SIZE + 7].copy_from_slice(b"changed");
+    content[..5].copy_from_slice(b"newer");
+
+    let open = |storage: &MemStorage| SEFS::open(Box::new(storage.clone()), &CLOCK);
+    let sefs = open(&storage)?;
+    let root_hash = sefs.root_hash();
+    assert_eq!(read_all(&sefs.root_inode().find("file")?)?, content);
+    drop(sefs);
+
+    // a chunk of a file changed on the host
+    let tampered = MemStorage::from_image(storage.image());
+    tampered.tamper(id, |bytes| bytes[3 * CHUNK_SIZE + 1] ^= 1);
+    let sefs = open(&tampered)?;
+    let file = sefs.root_inode().find("file")?;
+    let mut buf = [0u8; 16];
+    match file.read_at(3 * CHUNK_SIZE, &mut buf) {
+        Err(FsError::Corrupted(bad)) => assert_eq!(bad, id),
+        res => panic!("{:?}", res),
+    }
+    drop(file);
+    drop(sefs);
+    // or cut
+    let tampered = MemStorage::from_image(storage.image());
+    tampered.tamper(id, |bytes| bytes.truncate(CHUNK_SIZE));
+    let sefs = open(&tampered)?;
+    match sefs.root_inode().find("file")?.read_at(0, &mut buf) {
+        Err(FsError::Corrupted(bad)) => assert_eq!(bad, id),
+        res => panic!("{:?}", res),
+    }
+    drop(sefs);
+    // or an older copy of it put back
+    let tampered = MemStorage::from_image(storage.image());
+    tampered.tamper(id, |bytes| *bytes = old[&id].clone());
+    let sefs = open(&tampered)?;
+    match sefs.root_inode().find("file")?.read_at(0, &mut buf) {
+        Err(FsError::Corrupted(bad)) => assert_eq!(bad, id),
+        res => panic!("{:?}", res),
+    }
+    drop(sefs);
+    // the metadata changed
+    let tampered = MemStorage::from_image(storage.image());
+    tampered.tamper(0, |bytes| bytes[BLKN_ROOT * BLKSIZE + 2] ^= 1);
+    match open(&tampered).map(|_| ()) {
+        Err(FsError::Corrupted(0)) => {}
+        res => panic!("{:?}", res),
+    }
+
+    // all put back whole, told by the root kept apart
+    let keys: Arc<dyn KeyProvider> = Arc::new(FixedKey([0; KEY_SIZE]));
+    let rolled_back = MemStorage::from_image(old);
+    let verified = |storage: &MemStorage, root: &Hash| {
+        SEFS::open_verified(Box::new(storage.clone()), &CLOCK, keys.clone(), root)
+    };
+    match verified(&rolled_back, &root_hash).map(|_| ()) {
+        Err(FsError::Corrupted(0)) => {}
+        res => panic!("{:?}", res),
+    }
+    let sefs = verified(&rolled_back, &old_root)?;
+    let file = sefs.root_inode().find("file")?;
+    assert_eq!(&read_all(&file)?[..5], &data(5, 1)[..]);
+    drop(file);
+    drop(sefs);
+    let sefs = verified(&storage, &root_hash)?;
+    assert_eq!(read_all(&sefs.root_inode().find("file")?)?, content);
+    Ok(())
+}
+
+/// Master keys by version, the one told now set by the test, with no randomness
+#[derive(Default)]
+struct Keys {
+    state: Mutex<(u32, BTreeMap<u32, Key>)>,
+}
+
+impl Keys {
+    fn new(version: u32, key: Key) -> Arc<Self> {
+        let keys = Arc::new(Keys::default());
+        keys.rotate(version, key);
+        keys
+    }
+
+    /// Tell `key` as version `version` from now on
+    fn rotate(&self, version: u32, key: Key) {
+        let mut state = self.state.lock();
+        state.0 = version;
+        state.1.insert(version, key);
+    }
+
+    fn retire(&self, version: u32) {
+        self.state.lock().1.remove(&version);
+    }
+}
+
+impl KeyProvider for Keys {
+    fn version(&self) -> u32 {
+        self.state.lock().0
+    }
+    fn master_key(&self, version: u32) -> Option<Key> {
+        self.state.lock().1.get(&version).cloned()
+    }
+}
+
+#[test]
+fn key_rotation() -> Result<()> {
+    let storage = MemStorage::default();
+    let keys = Keys::new(0, [1; KEY_SIZE]);
+    let sefs = SEFS::create_with_keys(Box::new(storage.clone()), &CLOCK, keys.clone())?;
+    let root = sefs.root_inode();
+    let file = root.create("file", FileType::File, 0o644)?;
+    file.write_at(0, &data(3 * BLKSIZE, 1))?;
+    let dir = root.create("dir", FileType::Dir, 0o755)?;
+    dir.create("inner", FileType::File, 0o644)?
+        .write_at(0, &data(100, 2))?;
+    let id = file.metadata()?.inode;
+    let version = |inode: &Arc<dyn INode>| inode_of(inode).disk_inode.read().key.version;
+    assert_eq!(version(&file), 0);
+    assert_eq!(inode_of(&file).disk_inode.read().flags & FLAG_RANDOM_KEY, 0);
+    sefs.sync()?;
+    let host = storage.host(id);
+
+    // the keys of the 4 inodes and of the metadata wrapped again, the data left as it is
+    keys.rotate(1, [2; KEY_SIZE]);
+    assert_eq!(sefs.rotate_keys()?, 5);
+    assert_eq!(storage.host(id), host);
+    assert_eq!(version(&file), 1);
+    assert_eq!(version(&dir), 1);
+    assert_eq!(version(&root), 1);
+    assert_eq!(sefs.rotate_keys()?, 0);
+    let new = root.create("new", FileType::File, 0o644)?;
+    new.write_at(0, &data(10, 3))?;
+    assert_eq!(version(&new), 1);
+    drop(new);
+    drop(file);
+    drop(dir);
+    drop(root);
+    drop(sefs);
+
+    // the old version retired
+    keys.retire(0);
+    let sefs = SEFS::open_with_keys(Box::new(storage.clone()), &CLOCK, keys.clone())?;
+    let root = sefs.root_inode();
+    assert_eq!(read_all(&root.find("file")?)?, data(3 * BLKSIZE, 1));
+    assert_eq!(read_all(&root.find("dir")?.find("inner")?)?, data(100, 2));
+    assert_eq!(read_all(&root.find("new")?)?, data(10, 3));
+    drop(root);
+    drop(sefs);
+    // unless the version it is wrapped with is told, right
+    let open =
+        |keys: Arc<Keys>| SEFS::open_with_keys(Box::new(storage.clone()), &CLOCK, keys).map(|_| ());
+    match open(Keys::new(0, [1; KEY_SIZE])) {
+        Err(FsError::NotPermitted) => {}
+        res => panic!("{:?}", res),
+    }
+    match open(Keys::new(1, [3; KEY_SIZE])) {
+        Err(FsError::Corrupted(0)) => {}
+        res => panic!("{:?}", res),
+    }
+
+    // a wrapped key bound to its file
+    let key = [9; KEY_SIZE];
+    let wrapped = wrap(&*keys, id, &key, 100)?;
+    assert_eq!(unwrap(&*keys, id, &wrapped)?, key);
+    match unwrap(&*keys, id + 1, &wrapped) {
+        Err(FsError::Corrupted(bad)) => assert_eq!(bad, id + 1),
+        res => panic!("{:?}", res),
+    }
+    let mut tampered = wrapped;
+    tampered.wrapped[0] ^= 1;
+    match unwrap(&*keys, id, &tampered) {
+        Err(FsError::Corrupted(bad)) => assert_eq!(bad, id),
+        res => panic!("{:?}", res),
+    }
+    Ok(())
+}
+
+#[test]
+fn plaintext_files() -> Result<()> {
+    let storage = MemStorage::default();
+    let sefs = SEFS::create(Box::new(storage.clone()), &CLOCK)?;
+    let root = sefs.root_inode();
+    assert!(inode_of(&root).is_encrypted());
+    let public = root.create("public", FileType::Dir, 0o755)?;
+    inode_of(&public).set_encrypted(false)?;
+    inode_of(&public).set_encrypted(false)?;
+    assert!(!inode_of(&public).is_encrypted());
+    assert_eq!(public.find("..")?.metadata()?.inode, root.metadata()?.inode);
+
+    // taken by the entries made in it
+    let asset = public.create("asset", FileType::File, 0o644)?;
+    assert!(!inode_of(&asset).is_encrypted());
+    assert_ne!(inode_of(&asset).disk_inode.read().flags & FLAG_PLAINTEXT, 0);
+    let content = data(3 * BLKSIZE, 1);
+    asset.write_at(0, &content)?;
+    let secret = root.create("secret", FileType::File, 0o644)?;
+    assert!(inode_of(&secret).is_encrypted());
+    secret.write_at(0, &content)?;
+    // but for one set apart, while empty
+    let sealed = public.create("sealed", FileType::File, 0o644)?;
+    inode_of(&sealed).set_encrypted(true)?;
+    sealed.write_at(0, &content)?;
+    match inode_of(&sealed).set_encrypted(false) {
+        Err(FsError::InvalidParam) => {}
+        res => panic!("{:?}", res),
+    }
+    match inode_of(&public).set_encrypted(true) {
+        Err(FsError::DirNotEmpty) => {}
+        res => panic!("{:?}", res),
+    }
+    sefs.sync()?;
+    let ids: Vec<usize> = [&public, &asset, &secret, &sealed]
+        .iter()
+        .map(|inode| inode.metadata().unwrap().inode)
+        .collect();
+    assert_eq!(storage.host(ids[1]), content);
+    assert_ne!(storage.host(ids[2]), content);
+    assert_ne!(storage.host(ids[3]), content);
+    // the entries of the dir too
+    let host = storage.host(ids[0]);
+    assert!(host.windows(5).any(|name| name == b"asset"));
+    drop(asset);
+    drop(secret);
+    drop(sealed);
+    drop(public);
+    drop(root);
+    drop(sefs);
+
+    // as told by the inodes
+    let sefs = SEFS::open(Box::new(storage.clone()), &CLOCK)?;
+    let root = sefs.root_inode();
+    let public = root.find("public")?;
+    assert!(!inode_of(&public).is_encrypted());
+    let asset = public.find("asset")?;
+    assert!(!inode_of(&asset).is_encrypted());
+    assert_eq!(read_all(&asset)?, content);
+    let sealed = public.find("sealed")?;
+    assert!(inode_of(&sealed).is_encrypted());
+    assert_eq!(read_all(&sealed)?, content);
+    assert_eq!(read_all(&root.find("secret")?)?, content);
+    // and covered by the tree of the metadata as the rest
+    drop(asset);
+    drop(sealed);
+    drop(public);
+    drop(root);
+    drop(sefs);
+    let tampered = MemStorage::from_image(storage.image());
+    tampered.tamper(ids[1], |bytes| bytes[0] ^= 1);
+    let sefs = SEFS::open(Box::new(tampered), &CLOCK)?;
+    let asset = sefs.root_inode().find("public")?.find("asset")?;
+    match asset.read_at(0, &mut [0; 16]) {
+        Err(FsError::Corrupted(bad)) => assert_eq!(bad, ids[1]),
+        res => panic!("{:?}", res),
+    }
+    Ok(())
+}
+
+#[test]
+fn secure_unlink() -> Result<()> {
+    let storage = MemStorage::default();
+    let sefs = SEFS::create(Box::new(storage.clone()), &CLOCK)?;
+    let root = sefs.root_inode();
+    let meta_block = |id: usize| -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; BLKSIZE];
+        sefs.meta_file.read_block(id, &mut buf)?;
+        Ok(buf)
+    };
+    let file = root.create("file", FileType::File, 0o644)?;
+    file.write_at(0, &data(2 * BLKSIZE, 1))?;
+    let id = file.metadata()?.inode;
+    assert_ne!(inode_of(&file).disk_inode.read().flags & FLAG_RANDOM_KEY, 0);
+    drop(file);
+    sefs.sync()?;
+    assert!(meta_block(id)?.iter().any(|&byte| byte != 0));
+    assert!(storage.files.lock().contains_key(&id));
+
+    // its inode, with its key, zeroed, and its file gone
+    root.secure_unlink("file")?;
+    assert!(meta_block(id)?.iter().all(|&byte| byte == 0));
+    assert!(sefs.free_map.read()[id]);
+    assert!(!storage.files.lock().contains_key(&id));
+    match root.find("file") {
+        Err(FsError::EntryNotFound) => {}
+        res => panic!("{:?}", res.map(|_| ())),
+    }
+    match root.secure_unlink("file") {
+        Err(FsError::EntryNotFound) => {}
+        res => panic!("{:?}", res),
+    }
+
+    // once no longer in use
+    let file = root.create("open", FileType::File, 0o644)?;
+    file.write_at(0, &data(BLKSIZE, 2))?;
+    let id = file.metadata()?.inode;
+    root.secure_unlink("open")?;
+    assert!(meta_block(id)?.iter().any(|&byte| byte != 0));
+    assert_eq!(read_all(&file)?, data(BLKSIZE, 2));
+    drop(file);
+    assert!(meta_block(id)?.iter().all(|&byte| byte == 0));
+    sefs.sync()?;
+    assert!(!storage.files.lock().contains_key(&id));
+    drop(root);
+    drop(sefs);
+
+    // a key derived from the master key can be derived again
+    let storage = MemStorage::default();
+    let keys = Keys::new(0, [1; KEY_SIZE]);
+    let sefs = SEFS::create_with_keys(Box::new(storage.clone()), &CLOCK, keys)?;
+    let root = sefs.root_inode();
+    root.create("file", FileType::File, 0o644)?;
+    match root.secure_unlink("file") {
+        Err(FsError::NotSupported) => {}
+        res => panic!("{:?}", res),
+    }
+    root.find("file")?;
+    Ok(())
+}
+
+/// A call to a file of a storage: what, to which file, at which offset, of how many bytes
+type Call = (&'static str, usize, usize, usize);
+
+/// A storage in memory recording the calls to its files
+#[derive(Clone, Default)]
+struct CountingStorage {
+    storage: MemStorage,
+    calls: Arc<Mutex<Vec<Call>>>,
+}
+
+impl CountingStorage {
+    fn take(&self) -> Vec<Call> {
+        core::mem::take(&mut *self.calls.lock())
+    }
+}
+
+impl Storage for CountingStorage {
+    fn open(&self, file_id: usize, key: Option<&Key>) -> DevResult<Box<dyn File>> {
+        self.calls.lock().push(("open", file_id, 0, 0));
+        let file = self.storage.open(file_id, key)?;
+        Ok(Box::new(CountingFile {
+            file,
+            id: file_id,
+            calls: self.calls.clone(),
+        }))
+    }
+
+    fn create(&self, file_id: usize, key: Option<&Key>) -> DevResult<Box<dyn File>> {
+        self.storage.create(file_id, key)?;
+        self.open(file_id, key)
+    }
+
+    fn remove(&self, file_id: usize) -> DevResult<()> {
+        self.storage.remove(file_id)
+    }
+}
+
+struct CountingFile {
+    file: Box<dyn File>,
+    id: usize,
+    calls: Arc<Mutex<Vec<Call>>>,
+}
+
+impl File for CountingFile {
+    fn read_at(&self, buf: &mut [u8], offset: usize) -> DevResult<usize> {
+        self.calls.lock().push(("read", self.id, offset, buf.len()));
+        self.file.read_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: usize) -> DevResult<usize> {
+        self.calls
+            .lock()
+            .push(("write", self.id, offset, buf.len()));
+        self.file.write_at(buf, offset)
+    }
+
+    fn set_len(&self, len: usize) -> DevResult<()> {
+        self.calls.lock().push(("set_len", self.id, len, 0));
+        self.file.set_len(len)
+    }
+
+    fn flush(&self) -> DevResult<()> {
+        self.calls.lock().push(("flush", self.id, 0, 0));
+        self.file.flush()
+    }
+}
+
+#[test]
+fn batched_storage() -> Result<()> {
+    let counting = CountingStorage::default();
+    let storage = BatchedStorage::new(counting.clone(), 2);
+    let file = storage.create(10, None)?;
+    counting.take();
+
+    // adjacent writes kept, and written back at once before a read after them
+    file.write_all_at(&data(100, 1), 0)?;
+    file.write_all_at(&data(BLKSIZE - 100, 2), 100)?;
+    file.write_all_at(&data(BLKSIZE, 3), BLKSIZE)?;
+    file.write_all_at(b"over", 50)?;
+    assert_eq!(counting.take(), vec![]);
+    let mut buf = [0u8; 4];
+    file.read_exact_at(&mut buf, 50)?;
+    assert_eq!(&buf, b"over");
+    assert_eq!(
+        counting.take(),
+        vec![
+            ("write", 10, 0, 2 * BLKSIZE),
+            ("read", 10, 50, batched::READAHEAD)
+        ]
+    );
+    // read ahead, kept up to date by the writes
+    file.write_all_at(b"next", BLKSIZE)?;
+    file.read_exact_at(&mut buf, BLKSIZE + 8)?;
+    assert_eq!(&buf[..], &data(BLKSIZE, 3)[8..12]);
+    assert_eq!(counting.take(), vec![]);
+    file.read_exact_at(&mut buf, BLKSIZE)?;
+    assert_eq!(&buf, b"next");
+    assert_eq!(counting.take(), vec![("write", 10, BLKSIZE, 4)]);
+
+    // in order: a write elsewhere writes back those before
+    file.write_all_at(b"aaaa", 3 * BLKSIZE)?;
+    file.write_all_at(b"bbbb", 0)?;
+    file.write_all_at(b"cccc", 3 * BLKSIZE + 2)?;
+    assert_eq!(
+        counting.take(),
+        vec![("write", 10, 3 * BLKSIZE, 4), ("write", 10, 0, 4)]
+    );
+    // and a cut or a flush
+    file.set_len(3 * BLKSIZE + 4)?;
+    file.write_all_at(b"dd", 10)?;
+    file.flush()?;
+    assert_eq!(
+        counting.take(),
+        vec![
+            ("write", 10, 3 * BLKSIZE + 2, 4),
+            ("set_len", 10, 3 * BLKSIZE + 4, 0),
+            ("write", 10, 10, 2),
+            ("flush", 10, 0, 0)
+        ]
+    );
+    let host = counting.storage.host(10);
+    assert_eq!(host.len(), 3 * BLKSIZE + 4);
+    assert_eq!(&host[..4], b"bbbb");
+    assert_eq!(&host[10..12], b"dd");
+    assert_eq!(&host[3 * BLKSIZE..], b"aacc");
+    // up to a batch at once
+    for i in 0..batched::BATCH / BLKSIZE + 1 {
+        file.write_all_at(&data(BLKSIZE, i), i * BLKSIZE)?;
+    }
+    assert_eq!(counting.take(), vec![("write", 10, 0, batched::BATCH)]);
+
+    // shared by all who open it, and kept open for the next
+    let other = storage.open(10, None)?;
+    assert_eq!(counting.take(), vec![]);
+    other.read_exact_at(&mut buf, batched::BATCH)?;
+    assert_eq!(&buf[..], &data(BLKSIZE, batched::BATCH / BLKSIZE)[..4]);
+    counting.take();
+    drop(other);
+    storage.create(11, None)?.write_all_at(b"eeee", 0)?;
+    storage.create(12, None)?;
+    counting.take();
+    // the least recently used closed, written back once dropped by all
+    storage.open(10, None)?;
+    assert_eq!(
+        counting.take(),
+        vec![("open", 10, 0, 0), ("write", 11, 0, 4)]
+    );
+    assert_eq!(&counting.storage.host(11)[..], b"eeee");
+    file.write_all_at(b"ffff", 0)?;
+    assert_eq!(counting.take(), vec![]);
+    drop(file);
+    assert_eq!(counting.take(), vec![("write", 10, 0, 4)]);
+
+    // under SEFS
+    let storage = MemStorage::default();
+    let sefs = SEFS::create(Box::new(BatchedStorage::new(storage.clone(), 4)), &CLOCK)?;
+    let file = sefs.root_inode().create("file", FileType::File, 0o644)?;
+    file.write_at(0, &data(10 * BLKSIZE, 4))?;
+    drop(file);
+    drop(sefs);
+    let sefs = SEFS::open(Box::new(BatchedStorage::new(storage, 4)), &CLOCK)?;
+    assert_eq!(
+        read_all(&sefs.root_inode().find("file")?)?,
+        data(10 * BLKSIZE, 4)
+    );
+    Ok(())
+}
+
+#[test]
+fn journal_replay() -> Result<()> {
+    let storage = MemStorage::default();
+    let sefs = SEFS::create(Box::new(storage.clone()), &CLOCK)?;
+    let root = sefs.root_inode();
+    let old = root.create("old", FileType::File, 0o644)?;
+    old.write_at(0, &data(2 * BLKSIZE, 1))?;
+    let old_id = old.metadata()?.inode;
+    drop(old);
+    sefs.sync()?;
+    let synced = storage.commits.lock().len();
+
+    // many blocks of the metadata and of the dirs changed by one commit
+    let dir = root.create("dir", FileType::Dir, 0o755)?;
+    for i in 0..20 {
+        let file = dir.create(&format!("file{}", i), FileType::File, 0o644)?;
+        file.write_at(0, &data(BLKSIZE + i, i))?;
+    }
+    root.unlink("old")?;
+    root.link("new", &dir.find("file0")?)?;
+    drop(dir);
+    sefs.sync()?;
+    drop(root);
+    drop(sefs);
+    let commits = storage.commits.lock().clone();
+    assert_eq!(commits.len(), synced + 1);
+    let committed = storage.image();
+    assert!(committed[&JOURNAL_FILE].is_empty());
+    assert!(!committed.contains_key(&old_id));
+
+    // killed with the journal written, none of the files
+    let journaled = commits.last().unwrap().clone();
+    assert!(!journaled[&JOURNAL_FILE].is_empty());
+    let changed: Vec<usize> = committed
+        .iter()
+        .filter(|(id, content)| journaled.get(id) != Some(content))
+        .map(|(&id, _)| id)
+        .collect();
+    assert!(changed.contains(&0));
+    let crashed = MemStorage::from_image(journaled.clone());
+    let sefs = SEFS::open(Box::new(crashed.clone()), &CLOCK)?;
+    let replayed = crashed.image();
+    assert!(replayed[&JOURNAL_FILE].is_empty());
+    for (id, content) in committed.iter() {
+        assert_eq!(&replayed[id], content, "file {}", id);
+    }
+    // but for the file removed, left unused
+    assert!(replayed.contains_key(&old_id));
+    let root = sefs.root_inode();
+    assert!(root.find("old").is_err());
+    let dir = root.find("dir")?;
+    for i in 0..20 {
+        let file = dir.find(&format!("file{}", i))?;
+        assert_eq!(read_all(&file)?, data(BLKSIZE + i, i));
+    }
+    assert_eq!(read_all(&root.find("new")?)?, data(BLKSIZE, 0));
+    assert_eq!(root.find("new")?.metadata()?.nlinks, 2);
+    root.create("after", FileType::File, 0o644)?;
+    sefs.sync()?;
+    drop(dir);
+    drop(root);
+    drop(sefs);
+    let sefs = SEFS::open(Box::new(crashed), &CLOCK)?;
+    sefs.root_inode().find("after")?;
+
+    // killed while the journal was written: dropped, as at the sync before
+    let mut torn = journaled;
+    let len = torn[&JOURNAL_FILE].len();
+    torn.get_mut(&JOURNAL_FILE).unwrap().truncate(len - 1);
+    let crashed = MemStorage::from_image(torn.clone());
+    let sefs = SEFS::open(Box::new(crashed.clone()), &CLOCK)?;
+    for id in changed.iter().filter(|&&id| id != JOURNAL_FILE) {
+        assert_eq!(crashed.host(*id), torn[id], "file {}", id);
+    }
+    let root = sefs.root_inode();
+    assert!(root.find("dir").is_err());
+    assert!(root.find("new").is_err());
+    assert_eq!(read_all(&root.find("old")?)?, data(2 * BLKSIZE, 1));
+    // and the journal taken over by the next commit
+    root.create("after", FileType::File, 0o644)?;
+    sefs.sync()?;
+    drop(root);
+    drop(sefs);
+    assert!(crashed.host(JOURNAL_FILE).is_empty());
+    let sefs = SEFS::open(Box::new(crashed), &CLOCK)?;
+    sefs.root_inode().find("after")?;
+    Ok(())
+}
+
+#[test]
+fn mac_mode() -> Result<()> {
+    let storage = MemStorage::default();
+    let keys = Keys::new(0, [1; KEY_SIZE]);
+    let sefs = SEFS::create_with_mode(Box::new(storage.clone()), &CLOCK, keys.clone(), Mode::Mac)?;
+    assert_eq!(sefs.mode(), Mode::Mac);
+    let root = sefs.root_inode();
+    assert!(!inode_of(&root).is_encrypted());
+    let file = root.create("file", FileType::File, 0o644)?;
+    assert!(!inode_of(&file).is_encrypted());
+    let content = data(3 * CHUNK_SIZE + 10, 1);
+    file.write_at(0, &content)?;
+    match inode_of(&file).set_encrypted(true) {
+        Err(FsError::NotSupported) => {}
+        res => panic!("{:?}", res),
+    }
+    let dir = root.create("dir", FileType::Dir, 0o755)?;
+    match inode_of(&dir).set_encrypted(true) {
+        Err(FsError::NotSupported) => {}
+        res => panic!("{:?}", res),
+    }
+    let id = file.metadata()?.inode;
+    drop(file);
+    drop(dir);
+    drop(root);
+    sefs.sync()?;
+    drop(sefs);
+
+    // all in plaintext on the host, the metadata and the entries too
+    assert_eq!(storage.host(id), content);
+    let meta = storage.host(0);
+    assert_eq!(
+        &meta[BLKN_SUPER * BLKSIZE..BLKN_SUPER * BLKSIZE + 4],
+        &MAGIC.to_le_bytes()
+    );
+    let entries = storage.host(BLKN_ROOT);
+    assert!(entries.windows(4).any(|name| name == b"file"));
+    let open = |storage: &MemStorage| {
+        SEFS::open_with_keys(Box::new(storage.clone()), &CLOCK, keys.clone())
+    };
+    let sefs = open(&storage)?;
+    assert_eq!(sefs.mode(), Mode::Mac);
+    let root = sefs.root_inode();
+    assert!(!inode_of(&root.find("dir")?).is_encrypted());
+    assert_eq!(read_all(&root.find("file")?)?, content);
+    drop(root);
+    drop(sefs);
+
+    // but told changed by their MACs
+    let tampered = MemStorage::from_image(storage.image());
+    tampered.tamper(id, |bytes| bytes[2 * CHUNK_SIZE + 3] ^= 1);
+    let sefs = open(&tampered)?;
+    let mut buf = [0u8; 16];
+    match sefs
+        .root_inode()
+        .find("file")?
+        .read_at(2 * CHUNK_SIZE, &mut buf)
+    {
+        Err(FsError::Corrupted(bad)) => assert_eq!(bad, id),
+        res => panic!("{:?}", res),
+    }
+    drop(sefs);
+    let tampered = MemStorage::from_image(storage.image());
+    tampered.tamper(BLKN_ROOT, |bytes| {
+        let at = bytes.windows(4).position(|name| name == b"file").unwrap();
+        bytes[at..at + 4].copy_from_slice(b"evil");
+    });
+    let sefs = open(&tampered)?;
+    match sefs.root_inode().find("evil").map(|_| ()) {
+        Err(FsError::Corrupted(bad)) => assert_eq!(bad, BLKN_ROOT),
+        res => panic!("{:?}", res),
+    }
+    drop(sefs);
+    let tampered = MemStorage::from_image(storage.image());
+    tampered.tamper(0, |bytes| bytes[BLKN_ROOT * BLKSIZE + 2] ^= 1);
+    match open(&tampered).map(|_| ()) {
+        Err(FsError::Corrupted(0)) => {}
+        res => panic!("{:?}", res),
+    }
+    // or read with another master key
+    let other = Keys::new(0, [2; KEY_SIZE]);
+    match SEFS::open_with_keys(Box::new(storage.clone()), &CLOCK, other).map(|_| ()) {
+        Err(FsError::Corrupted(0)) => {}
+        res => panic!("{:?}", res),
+    }
+    Ok(())
+}
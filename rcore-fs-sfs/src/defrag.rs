@@ -0,0 +1,158 @@
+//! Defragmentation of SFS
+//!
+//! Each file and dir in the tree from root whose blocks are not next to each other is
+//! copied to the first free extent which holds them all, so it can be read in one run,
+//! and the image is compacted towards its start. Holes and indirect blocks stay as they are.
+//! The copy is in use on disk before the inode refers to it, and the old blocks are only
+//! freed at the next sync, so a crash leaves each file as it was before or after.
+//! Files sharing blocks with others are left as they are, as a copy would take more room.
+use crate::*;
+
+/// Blocks copied at a time
+const COPY_BLOCKS: usize = 64;
+
+/// What `SimpleFileSystem::defragment()` did
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Report {
+    /// Files and dirs looked at
+    pub files: usize,
+    /// Files and dirs moved into one extent
+    pub moved: usize,
+    /// Blocks moved
+    pub blocks: usize,
+    /// Files and dirs left fragmented, as no free extent holds them
+    pub fragmented: usize,
+    /// Files left as they are, as they share blocks with others
+    pub shared: usize,
+}
+
+/// What was done to a file
+enum Defrag {
+    /// Its blocks were next to each other
+    Contiguous,
+    /// Its blocks were moved, this many
+    Moved(usize),
+    /// There was no room to move its blocks
+    NoRoom,
+    /// Some of its blocks are shared
+    Shared,
+}
+
+impl SimpleFileSystem {
+    /// Move the blocks of each file and dir into one extent, see the module docs.
+    /// `progress` is called after each with the number done and the total.
+    pub fn defragment(&self, mut progress: impl FnMut(usize, usize)) -> vfs::Result<Report> {
+        self.sync()?;
+        let ids = self.tree_ids()?;
+        let mut report = Report::default();
+        for (i, &id) in ids.iter().enumerate() {
+            report.files += 1;
+            match self.get_inode(id).defragment()? {
+                Defrag::Contiguous => {}
+                Defrag::Moved(blocks) => {
+                    report.moved += 1;
+                    report.blocks += blocks;
+                }
+                Defrag::NoRoom => report.fragmented += 1,
+                Defrag::Shared => report.shared += 1,
+            }
+            progress(i + 1, ids.len());
+        }
+        self.sync()?;
+        Ok(report)
+    }
+    /// Ids of the inodes in the tree from root, each once
+    fn tree_ids(&self) -> vfs::Result<Vec<INodeId>> {
+        let mut ids = vec![BLKN_ROOT];
+        let mut seen = BTreeSet::new();
+        seen.insert(BLKN_ROOT);
+        let mut i = 0;
+        while i < ids.len() {
+            let inode = self.get_inode(ids[i]);
+            i += 1;
+            let _lock = inode.lock.read();
+            let (type_, size) = {
+                let disk_inode = inode.disk_inode.read();
+                (disk_inode.type_, disk_inode.size())
+            };
+            if type_ != FileType::Dir {
+                continue;
+            }
+            // skip '.' and '..'
+            for entry in 2..size / DIRENT_SIZE {
+                let id = inode.read_direntry(entry)?.id as INodeId;
+                if seen.insert(id) {
+                    ids.push(id);
+                }
+            }
+        }
+        Ok(ids)
+    }
+}
+
+impl INodeImpl {
+    /// Copy its blocks into one extent if they are not next to each other
+    fn defragment(&self) -> vfs::Result<Defrag> {
+        let _lock = self.lock.write();
+        let blocks = {
+            let disk_inode = self.disk_inode.read();
+            match disk_inode.is_inline() {
+                true => 0,
+                false => disk_inode.blocks as usize,
+            }
+        };
+        let mut old = Vec::new();
+        for i in 0..blocks {
+            match self.get_disk_block_id(i)? {
+                0 => {}
+                block => old.push((i, block)),
+            }
+        }
+        if old.windows(2).all(|pair| pair[1].1 == pair[0].1 + 1) {
+            return Ok(Defrag::Contiguous);
+        }
+        if old.iter().any(|&(_, block)| self.fs.is_shared(block)) {
+            return Ok(Defrag::Shared);
+        }
+        let extent = match self.fs.alloc_extent(old.len()) {
+            Some(extent) => extent,
+            // the blocks held back since the last sync may make room
+            None if !self.fs.unreleased.read().is_empty() => {
+                self.fs.sync()?;
+                match self.fs.alloc_extent(old.len()) {
+                    Some(extent) => extent,
+                    None => return Ok(Defrag::NoRoom),
+                }
+            }
+            None => return Ok(Defrag::NoRoom),
+        };
+        if let Err(err) = self.copy_blocks(&old, extent.start) {
+            for block in extent {
+                self.fs.free_block(block);
+            }
+            return Err(err);
+        }
+        for (&(i, _), block) in old.iter().zip(extent) {
+            self.set_disk_block_id(i, block)?;
+        }
+        self.sync_all()?;
+        for &(_, block) in old.iter() {
+            self.fs.free_block(block);
+        }
+        Ok(Defrag::Moved(old.len()))
+    }
+    /// Copy blocks `old` of it to the blocks from `to`, which are marked in use on disk first
+    fn copy_blocks(&self, old: &[(BlockId, BlockId)], to: BlockId) -> vfs::Result<()> {
+        self.fs.write_super_and_freemap()?;
+        let device = self.content_device();
+        let mut buf = vec![0u8; COPY_BLOCKS * BLKSIZE];
+        for (i, chunk) in old.chunks(COPY_BLOCKS).enumerate() {
+            for (j, &(_, block)) in chunk.iter().enumerate() {
+                device.read_block(block, 0, &mut buf[j * BLKSIZE..(j + 1) * BLKSIZE])?;
+            }
+            let offset = (to + i * COPY_BLOCKS) * BLKSIZE;
+            device.write_vectored_exact(offset, &[&buf[..chunk.len() * BLKSIZE]])?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,150 @@
+//! Free blocks of SFS
+//!
+//! The bitmap on disk is kept as it is, with a tree of the free extents in it,
+//! which is rebuilt when the image is opened. A block is found by the tree in
+//! O(log n) wherever it is, rather than by a scan of the bitmap from its start.
+use alloc::collections::{BTreeMap, BTreeSet};
+use core::ops::{Index, Range};
+
+use bitvec::prelude::*;
+
+use crate::{BlockId, BLKBITS};
+
+/// Bitmap of free blocks, with blocks in use marked 0
+pub struct FreeMap {
+    bits: BitVec<Lsb0, u8>,
+    /// Free extents, from the begin to the end of each
+    extents: BTreeMap<BlockId, BlockId>,
+    /// Blocks of the bitmap on disk changed since they are written, by index
+    changed: BTreeSet<usize>,
+}
+
+impl FreeMap {
+    pub fn new(bits: BitVec<Lsb0, u8>) -> Self {
+        let mut extents = BTreeMap::new();
+        let mut begin = None;
+        for (id, free) in bits.iter().enumerate() {
+            match (begin, *free) {
+                (None, true) => begin = Some(id),
+                (Some(b), false) => {
+                    extents.insert(b, id);
+                    begin = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(b) = begin {
+            extents.insert(b, bits.len());
+        }
+        FreeMap {
+            bits,
+            extents,
+            changed: BTreeSet::new(),
+        }
+    }
+
+    /// Number of blocks in the bitmap
+    pub fn len(&self) -> usize {
+        self.bits.len()
+    }
+
+    /// Bytes of the bitmap on disk
+    pub fn as_buf(&self) -> &[u8] {
+        self.bits.as_ref()
+    }
+
+    /// Add `len - self.len()` blocks in use to the end
+    pub fn grow(&mut self, len: usize) {
+        let more = len - self.bits.len();
+        self.bits.extend(core::iter::repeat(false).take(more));
+        self.change_all();
+    }
+
+    /// Mark all blocks of the bitmap changed, e.g. to write them elsewhere
+    pub fn change_all(&mut self) {
+        self.changed
+            .extend(0..(self.bits.len() + BLKBITS - 1) / BLKBITS);
+    }
+
+    /// Take the indices of the blocks of the bitmap changed
+    pub fn take_changed(&mut self) -> BTreeSet<usize> {
+        core::mem::replace(&mut self.changed, BTreeSet::new())
+    }
+
+    /// Mark block `id` free or in use
+    pub fn set(&mut self, id: BlockId, free: bool) {
+        if self.bits[id] == free {
+            return;
+        }
+        self.bits.set(id, free);
+        self.changed.insert(id / BLKBITS);
+        if free {
+            let end = self.extents.remove(&(id + 1)).unwrap_or(id + 1);
+            if let Some((_, prev_end)) = self.extents.range_mut(..id).next_back() {
+                if *prev_end == id {
+                    *prev_end = end;
+                    return;
+                }
+            }
+            self.extents.insert(id, end);
+        } else {
+            let (&begin, &end) = self.extents.range(..=id).next_back().unwrap();
+            match begin == id {
+                true => self.extents.remove(&begin),
+                false => self.extents.insert(begin, id),
+            };
+            if id + 1 < end {
+                self.extents.insert(id + 1, end);
+            }
+        }
+    }
+
+    /// Take the first free block at `goal` or after it in `range`, or else the first in `range`
+    pub fn alloc_in(&mut self, range: Range<BlockId>, goal: BlockId) -> Option<BlockId> {
+        let goal = goal.max(range.start).min(range.end);
+        let id = self
+            .find(goal..range.end)
+            .or_else(|| self.find(range.start..goal))?;
+        self.set(id, false);
+        Some(id)
+    }
+
+    /// Take the first `len` free blocks next to each other in `range`
+    pub fn alloc_extent(&mut self, range: Range<BlockId>, len: usize) -> Option<Range<BlockId>> {
+        let begin = self
+            .extents
+            .iter()
+            .map(|(&begin, &end)| begin.max(range.start)..end.min(range.end))
+            .find(|extent| extent.end >= extent.start + len)?
+            .start;
+        for id in begin..begin + len {
+            self.set(id, false);
+        }
+        Some(begin..begin + len)
+    }
+
+    /// The first free block in `range`
+    fn find(&self, range: Range<BlockId>) -> Option<BlockId> {
+        if range.start >= range.end {
+            return None;
+        }
+        if let Some((_, &end)) = self.extents.range(..=range.start).next_back() {
+            if end > range.start {
+                return Some(range.start);
+            }
+        }
+        let (&begin, _) = self.extents.range(range.start..).next()?;
+        match begin < range.end {
+            true => Some(begin),
+            false => None,
+        }
+    }
+}
+
+impl Index<BlockId> for FreeMap {
+    type Output = bool;
+
+    fn index(&self, id: BlockId) -> &bool {
+        &self.bits[id]
+    }
+}
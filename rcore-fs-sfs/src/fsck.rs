@@ -0,0 +1,599 @@
+//! Consistency check of SFS, like `fsck` of other file systems
+//!
+//! The dir tree is walked from root on disk, finding the blocks in use and the entries
+//! referring to each inode. They are compared with the free map, the super block and
+//! the link counts, which can then be repaired.
+use crate::*;
+
+/// A problem found by `check()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// The super block does not fit the image, nothing else is checked
+    BadSuperBlock,
+    /// The number of unused blocks in the super block is wrong
+    UnusedBlocks { recorded: usize, actual: usize },
+    /// The number of inodes in use in the super block is wrong
+    UsedINodes { recorded: usize, actual: usize },
+    /// A block in use is marked free in the free map
+    UsedBlockFree(BlockId),
+    /// A block marked in use is used by nothing
+    LeakedBlock(BlockId),
+    /// An inode of the inode table marked in use is in no dir
+    OrphanINode(INodeId),
+    /// A block is used twice, by the same inode or by two inodes
+    DuplicateBlock { inode: INodeId, block: BlockId },
+    /// A block map of an inode refers to a block out of the data area
+    BadBlock { inode: INodeId, block: BlockId },
+    /// An entry of a dir refers to something which is not an inode, has a bad name
+    /// or the name of another entry, or refers to a dir in another dir
+    BadEntry { dir: INodeId, index: usize },
+    /// A dir has wrong '.' or '..' entries, or is not in the dir its '..' refers to
+    BadDir(INodeId),
+    /// The entries of a dir are not sorted by name, in an image with sorted dirs
+    UnsortedDir(INodeId),
+    /// The link count of an inode is not the number of entries referring to it
+    LinkCount {
+        inode: INodeId,
+        recorded: usize,
+        actual: usize,
+    },
+    /// The number of references to a block in the refcount table is not the number of
+    /// inodes referring to it, see `reflink`
+    RefCount {
+        block: BlockId,
+        recorded: usize,
+        actual: usize,
+    },
+    /// The inode of the refcount table in the super block is not an inode
+    BadRefCountTable,
+}
+
+impl Problem {
+    /// Whether `check()` can repair it
+    pub fn can_repair(&self) -> bool {
+        match self {
+            Problem::UnusedBlocks { .. }
+            | Problem::UsedINodes { .. }
+            | Problem::UsedBlockFree(_)
+            | Problem::LeakedBlock(_)
+            | Problem::OrphanINode(_)
+            | Problem::BadEntry { .. }
+            | Problem::UnsortedDir(_)
+            | Problem::LinkCount { .. }
+            | Problem::RefCount { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+/// Result of `check()`
+#[derive(Debug)]
+pub struct Report {
+    pub problems: Vec<Problem>,
+    /// Whether the problems are repaired
+    pub repaired: bool,
+}
+
+/// Check `fs`, and repair it if `repair` is set and all problems can be repaired:
+/// the free map and the counts of unused blocks and inodes in use follow those found,
+/// orphan inodes are freed, bad entries are removed, dirs are sorted,
+/// and link counts and the references to shared blocks are corrected.
+///
+/// `fs` is synced first. It must not be in use, so that no inode is open,
+/// or `Busy` is returned.
+pub fn check(fs: &SimpleFileSystem, repair: bool) -> vfs::Result<Report> {
+    fs.sync()?;
+    fs.flush_weak_inodes();
+    if fs
+        .inodes
+        .read()
+        .values()
+        .any(|inode| inode.upgrade().is_some())
+    {
+        return Err(FsError::Busy);
+    }
+    let mut checker = match Checker::new(fs) {
+        Some(checker) => checker,
+        None => {
+            return Ok(Report {
+                problems: vec![Problem::BadSuperBlock],
+                repaired: false,
+            })
+        }
+    };
+    checker.walk()?;
+    checker.check_free_map()?;
+    checker.check_links();
+    checker.check_inode_count();
+    checker.check_refcounts();
+    let repaired = repair && checker.problems.iter().all(Problem::can_repair);
+    if repaired && !checker.problems.is_empty() {
+        checker.repair()?;
+    }
+    Ok(Report {
+        problems: checker.problems,
+        repaired,
+    })
+}
+
+struct Checker<'a> {
+    fs: &'a SimpleFileSystem,
+    /// Number of blocks of the image
+    blocks: usize,
+    /// The blocks which may be used by inodes
+    data: Range<BlockId>,
+    /// Blocks found in use
+    used: Vec<bool>,
+    /// Whether files may have holes, see `FEATURE_SPARSE_FILES`
+    sparse: bool,
+    /// Whether inodes are packed in the inode table, see `FEATURE_PACKED_INODES`
+    packed: bool,
+    /// Blocks found with inodes in use
+    inode_blocks: BTreeSet<BlockId>,
+    /// Slots of the packed inode table in use by no inode found
+    orphan_slots: Vec<INodeId>,
+    /// Entries found referring to each inode, and its link count
+    links: BTreeMap<INodeId, (usize, usize)>,
+    /// Bad entries, by dir and index
+    bad_entries: Vec<(INodeId, usize)>,
+    /// Entries referring to dirs not in the dir of the entry, by the dir referred to,
+    /// and the dir and index of the entry
+    stray_entries: Vec<(INodeId, INodeId, usize)>,
+    /// Dirs to be sorted
+    unsorted: Vec<INodeId>,
+    /// References to each shared block in the refcount table
+    recorded_refs: BTreeMap<BlockId, u32>,
+    /// References found to the blocks in the refcount table referred to more than once
+    refs: BTreeMap<BlockId, usize>,
+    problems: Vec<Problem>,
+}
+
+impl<'a> Checker<'a> {
+    fn new(fs: &'a SimpleFileSystem) -> Option<Self> {
+        let super_block = fs.super_block.read();
+        let blocks = super_block.blocks();
+        let begin = BLKN_FREEMAP + super_block.freemap_blocks as usize;
+        let end = blocks.checked_sub(super_block.journal_blocks())?;
+        let ext = super_block.freemap_ext();
+        if super_block.freemap_len() * BLKBITS < blocks
+            || begin + super_block.inode_blocks as usize >= end
+            || super_block.unused_blocks() > blocks
+            || (!ext.is_empty()
+                && (ext.start < begin + super_block.inode_blocks as usize || ext.end > end))
+        {
+            return None;
+        }
+        let mut used = vec![true; blocks];
+        for block in used[begin..end].iter_mut() {
+            *block = false;
+        }
+        for block in used[ext].iter_mut() {
+            *block = true;
+        }
+        let mut links = BTreeMap::new();
+        links.insert(BLKN_ROOT, (0, 0));
+        Some(Checker {
+            fs,
+            blocks,
+            data: begin..end,
+            used,
+            sparse: super_block.sparse_files(),
+            packed: super_block.packed_inodes() && super_block.inode_blocks != 0,
+            inode_blocks: BTreeSet::new(),
+            orphan_slots: Vec::new(),
+            links,
+            bad_entries: Vec::new(),
+            stray_entries: Vec::new(),
+            unsorted: Vec::new(),
+            recorded_refs: fs.shared.read().clone(),
+            refs: BTreeMap::new(),
+            problems: Vec::new(),
+        })
+    }
+
+    /// Read `block`, which may be cut short by the end of the image
+    fn read_block(&self, block: BlockId, buf: &mut [u8]) -> vfs::Result<()> {
+        self.fs.device.read_at(block * BLKSIZE, buf)?;
+        Ok(())
+    }
+
+    /// Mark `block` used by `inode`. Return whether it can be read.
+    fn mark(&mut self, inode: INodeId, block: BlockId) -> bool {
+        if !self.data.contains(&block) {
+            self.problems.push(Problem::BadBlock { inode, block });
+            return false;
+        }
+        if self.used[block] {
+            self.problems.push(Problem::DuplicateBlock { inode, block });
+        }
+        self.used[block] = true;
+        true
+    }
+
+    /// Mark data block `block` of inode `id`, which is a hole if 0 and `holes`,
+    /// and may be used again if it is in the refcount table
+    fn mark_data(&mut self, inode: INodeId, block: BlockId, holes: bool) -> bool {
+        if holes && block == 0 {
+            return true;
+        }
+        if self.data.contains(&block) && self.used[block] && self.recorded_refs.contains_key(&block)
+        {
+            *self.refs.entry(block).or_insert(1) += 1;
+            return true;
+        }
+        self.mark(inode, block)
+    }
+
+    /// Load inode `id` if it is one
+    fn load_inode(&self, id: INodeId) -> vfs::Result<Option<DiskINode>> {
+        let (block, range) = self.fs.inode_location(id);
+        if id != BLKN_ROOT && !self.data.contains(&block) {
+            return Ok(None);
+        }
+        let mut buf = [0u8; BLKSIZE];
+        self.read_block(block, &mut buf)?;
+        // the type is checked before it is read as `FileType`
+        let type_ = u16::from_ne_bytes([buf[range.start + 4], buf[range.start + 5]]);
+        if type_ == FileType::Invalid as u16 || type_ > FileType::Socket as u16 {
+            return Ok(None);
+        }
+        let mut disk_inode = self.fs.load_disk_inode(id)?;
+        let map = self.fs.block_map();
+        let max_blocks = match self.fs.super_block.read().large_file() {
+            true => map.max_triple_indirect(),
+            false => {
+                disk_inode.size_hi = 0;
+                disk_inode.tri_indirect = 0;
+                map.max_double_indirect()
+            }
+        };
+        if !self.fs.super_block.read().block_ids64() {
+            disk_inode.block_ids_hi = [0; NDIRECT + 3];
+        }
+        if disk_inode.blocks as usize > max_blocks.min(self.blocks) {
+            return Ok(None);
+        }
+        Ok(Some(disk_inode))
+    }
+
+    /// Mark the blocks of inode `id`, and return its data blocks in order,
+    /// or `None` if some can not be found
+    fn map_blocks(
+        &mut self,
+        id: INodeId,
+        disk_inode: &DiskINode,
+    ) -> vfs::Result<Option<Vec<BlockId>>> {
+        let count = disk_inode.blocks as usize;
+        let holes = self.sparse && disk_inode.type_ == FileType::File;
+        let mut blocks = Vec::with_capacity(count);
+        let mut ok = true;
+        for i in 0..count.min(NDIRECT) {
+            let block = disk_inode.direct(i);
+            ok &= self.mark_data(id, block, holes);
+            blocks.push(block);
+        }
+        let map = self.fs.block_map();
+        let levels = [
+            (disk_inode.indirect(), MAX_NBLOCK_DIRECT, 1),
+            (disk_inode.db_indirect(), map.max_indirect(), 2),
+            (disk_inode.tri_indirect(), map.max_double_indirect(), 3),
+        ];
+        for &(block, begin, level) in levels.iter() {
+            if count > begin {
+                ok &= self.map_indirect(id, block, count - begin, level, holes, &mut blocks)?;
+            }
+        }
+        Ok(match ok {
+            true => Some(blocks),
+            false => None,
+        })
+    }
+
+    /// Mark `count` data blocks under indirect `block` of `level`, and those between,
+    /// pushing the data blocks to `blocks`. Return whether all can be found.
+    fn map_indirect(
+        &mut self,
+        id: INodeId,
+        block: BlockId,
+        count: usize,
+        level: u32,
+        holes: bool,
+        blocks: &mut Vec<BlockId>,
+    ) -> vfs::Result<bool> {
+        if !self.mark(id, block) {
+            return Ok(false);
+        }
+        let mut buf = [0u8; BLKSIZE];
+        self.read_block(block, &mut buf)?;
+        let map = self.fs.block_map();
+        let per_entry = map.entries().pow(level - 1);
+        let mut ok = true;
+        for (i, entry) in buf.chunks(map.entry_size).enumerate() {
+            if i * per_entry >= count {
+                break;
+            }
+            let entry = map.read(entry);
+            if level == 1 {
+                ok &= self.mark_data(id, entry, holes);
+                blocks.push(entry);
+            } else {
+                let count = (count - i * per_entry).min(per_entry);
+                ok &= self.map_indirect(id, entry, count, level - 1, holes, blocks)?;
+            }
+        }
+        Ok(ok)
+    }
+
+    /// Walk the dir tree from root
+    fn walk(&mut self) -> vfs::Result<()> {
+        // the refcount table is in no dir, but kept by the super block
+        let refcount_inode = self.fs.super_block.read().refcount_inode();
+        if let Some(id) = refcount_inode {
+            match self.load_inode(id)? {
+                Some(disk_inode) if disk_inode.type_ == FileType::File => {
+                    let block = self.fs.inode_location(id).0;
+                    if self.inode_blocks.insert(block) {
+                        self.mark(id, block);
+                    }
+                    self.links.insert(id, (1, disk_inode.nlinks as usize));
+                    self.map_blocks(id, &disk_inode)?;
+                }
+                _ => self.problems.push(Problem::BadRefCountTable),
+            }
+        }
+        let mut dirs = vec![(BLKN_ROOT, BLKN_ROOT)];
+        while let Some((id, parent)) = dirs.pop() {
+            let disk_inode = match self.load_inode(id)? {
+                Some(disk_inode) => disk_inode,
+                None => {
+                    self.problems.push(Problem::BadDir(id));
+                    continue;
+                }
+            };
+            self.links.get_mut(&id).unwrap().1 = disk_inode.nlinks as usize;
+            let blocks = match self.map_blocks(id, &disk_inode)? {
+                Some(blocks) => blocks,
+                None => continue,
+            };
+            let size = disk_inode.size();
+            if size % DIRENT_SIZE != 0 || size > blocks.len() * BLKSIZE {
+                self.problems.push(Problem::BadDir(id));
+                continue;
+            }
+            let mut content = vec![0u8; blocks.len() * BLKSIZE];
+            for (&block, buf) in blocks.iter().zip(content.chunks_mut(BLKSIZE)) {
+                self.read_block(block, buf)?;
+            }
+            let (sorted, fold) = {
+                let super_block = self.fs.super_block.read();
+                (super_block.sorted_dirs(), super_block.case_fold())
+            };
+            let mut last_name = None;
+            let mut names = BTreeSet::new();
+            for (index, entry) in content[..size].chunks(DIRENT_SIZE).enumerate() {
+                let target = u32::from_ne_bytes([entry[0], entry[1], entry[2], entry[3]]);
+                let target = target as INodeId;
+                let name = entry_name(&entry[ENTRY_SIZE..]);
+                if index < 2 {
+                    let (dots, expected) = match index {
+                        0 => (".", id),
+                        _ => ("..", parent),
+                    };
+                    if name != Some(dots) || target != expected {
+                        self.problems.push(Problem::BadDir(id));
+                        continue;
+                    }
+                    self.links.get_mut(&target).unwrap().0 += 1;
+                    continue;
+                }
+                if name.map_or(true, |name| name.is_empty() || name.contains('/')) {
+                    self.problems.push(Problem::BadEntry { dir: id, index });
+                    self.bad_entries.push((id, index));
+                    continue;
+                }
+                // as compared by lookups
+                let name = fold_name(fold, name.unwrap());
+                // left twice by a crash while entries are moved
+                if !names.insert(name.clone()) {
+                    self.problems.push(Problem::BadEntry { dir: id, index });
+                    self.bad_entries.push((id, index));
+                    continue;
+                }
+                if sorted && last_name.as_ref() >= Some(&name) && self.unsorted.last() != Some(&id)
+                {
+                    self.problems.push(Problem::UnsortedDir(id));
+                    self.unsorted.push(id);
+                }
+                last_name = Some(name);
+                let child = match self.load_inode(target)? {
+                    Some(child) => child,
+                    None => {
+                        self.problems.push(Problem::BadEntry { dir: id, index });
+                        self.bad_entries.push((id, index));
+                        continue;
+                    }
+                };
+                if child.type_ == FileType::Dir {
+                    // a dir is only in the one its '..' refers to,
+                    // but a crash while it is moved can leave it in another too
+                    if self.links.contains_key(&target) || self.parent_of(&child)? != Some(id) {
+                        self.stray_entries.push((target, id, index));
+                        continue;
+                    }
+                } else if let Some(links) = self.links.get_mut(&target) {
+                    links.0 += 1;
+                    continue;
+                }
+                let block = self.fs.inode_location(target).0;
+                if self.inode_blocks.insert(block) {
+                    self.mark(target, block);
+                }
+                self.links.insert(target, (1, child.nlinks as usize));
+                match child.type_ {
+                    FileType::Dir => dirs.push((target, id)),
+                    _ => {
+                        self.map_blocks(target, &child)?;
+                    }
+                }
+            }
+        }
+        let mut lost_dirs = BTreeSet::new();
+        for &(target, dir, index) in self.stray_entries.iter() {
+            match self.links.contains_key(&target) {
+                true => {
+                    self.problems.push(Problem::BadEntry { dir, index });
+                    self.bad_entries.push((dir, index));
+                }
+                false => {
+                    lost_dirs.insert(target);
+                }
+            }
+        }
+        self.problems
+            .extend(lost_dirs.into_iter().map(Problem::BadDir));
+        Ok(())
+    }
+
+    /// The dir which the '..' entry of dir `disk_inode` refers to, if it has one
+    fn parent_of(&self, disk_inode: &DiskINode) -> vfs::Result<Option<INodeId>> {
+        let block = disk_inode.direct(0);
+        if disk_inode.size() < 2 * DIRENT_SIZE || !self.data.contains(&block) {
+            return Ok(None);
+        }
+        let mut buf = [0u8; BLKSIZE];
+        self.read_block(block, &mut buf)?;
+        let entry = &buf[DIRENT_SIZE..2 * DIRENT_SIZE];
+        let parent = u32::from_ne_bytes([entry[0], entry[1], entry[2], entry[3]]);
+        match entry_name(&entry[ENTRY_SIZE..]) {
+            Some("..") => Ok(Some(parent as INodeId)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Compare the blocks in use with the free map and the super block,
+    /// and the slots in use of a packed inode table with the inodes found
+    fn check_free_map(&mut self) -> vfs::Result<()> {
+        let free_map = self.fs.free_map.read();
+        let table = self.fs.inode_table().unwrap_or(0..0);
+        if self.packed {
+            for block in table.clone().filter(|&block| !free_map[block]) {
+                let mut buf = [0u8; BLKSIZE];
+                self.read_block(block, &mut buf)?;
+                let mask = slot_mask(&buf);
+                for slot in (0..INODES_PER_BLOCK).filter(|&slot| mask & 1 << slot != 0) {
+                    let id = block * INODES_PER_BLOCK + slot;
+                    if !self.links.contains_key(&id) {
+                        self.problems.push(Problem::OrphanINode(id));
+                        self.orphan_slots.push(id);
+                    }
+                }
+            }
+        }
+        for id in 0..self.blocks {
+            match (self.used[id], free_map[id]) {
+                (true, true) => self.problems.push(Problem::UsedBlockFree(id)),
+                (false, false) if table.contains(&id) && !self.packed => {
+                    self.problems.push(Problem::OrphanINode(id))
+                }
+                (false, false) => self.problems.push(Problem::LeakedBlock(id)),
+                _ => {}
+            }
+        }
+        let recorded = self.fs.super_block.read().unused_blocks();
+        let actual = self.used.iter().filter(|&&used| !used).count();
+        if recorded != actual {
+            self.problems
+                .push(Problem::UnusedBlocks { recorded, actual });
+        }
+        Ok(())
+    }
+
+    fn check_links(&mut self) {
+        for (&inode, &(actual, recorded)) in self.links.iter() {
+            if actual != recorded {
+                self.problems.push(Problem::LinkCount {
+                    inode,
+                    recorded,
+                    actual,
+                });
+            }
+        }
+    }
+
+    /// Compare the inodes found with the count in the super block, if it keeps one
+    fn check_inode_count(&mut self) {
+        let recorded = match self.fs.super_block.read().used_inodes() {
+            Some(recorded) => recorded,
+            None => return,
+        };
+        let actual = self.links.len();
+        if recorded != actual {
+            self.problems.push(Problem::UsedINodes { recorded, actual });
+        }
+    }
+
+    /// Compare the references found to the blocks in the refcount table with it
+    fn check_refcounts(&mut self) {
+        for (&block, &recorded) in self.recorded_refs.iter() {
+            let actual = match self.refs.get(&block) {
+                Some(&refs) => refs,
+                None if self.data.contains(&block) && self.used[block] => 1,
+                None => 0,
+            };
+            if recorded as usize != actual {
+                self.problems.push(Problem::RefCount {
+                    block,
+                    recorded: recorded as usize,
+                    actual,
+                });
+            }
+        }
+    }
+
+    fn repair(&mut self) -> vfs::Result<()> {
+        {
+            let mut free_map = self.fs.free_map.write();
+            for (id, &used) in self.used.iter().enumerate() {
+                free_map.set(id, !used);
+            }
+            let mut super_block = self.fs.super_block.write();
+            super_block.set_unused_blocks(self.used.iter().filter(|&&used| !used).count());
+            super_block.set_used_inodes(self.links.len());
+        }
+        for &id in self.orphan_slots.iter() {
+            let (block, range) = self.fs.inode_location(id);
+            self.fs
+                .device
+                .write_block(block, range.start, &ZEROS[..INODE_SIZE])?;
+        }
+        *self.fs.inode_slots.write() = INodeSlots::default();
+        // from the last, as the last entry of a dir, or all after it, is moved to the one removed
+        self.bad_entries.sort();
+        for &(dir, index) in self.bad_entries.iter().rev() {
+            self.fs.get_inode(dir).remove_direntry(index)?;
+        }
+        for &dir in self.unsorted.iter() {
+            self.fs.get_inode(dir).sort_direntries()?;
+        }
+        for (&id, &(actual, recorded)) in self.links.iter() {
+            if actual != recorded {
+                self.fs.get_inode(id).disk_inode.write().nlinks = actual as u16;
+            }
+        }
+        if self.problems.iter().any(|problem| match problem {
+            Problem::RefCount { .. } => true,
+            _ => false,
+        }) {
+            let mut shared = self.fs.shared.write();
+            shared.clear();
+            shared.extend(self.refs.iter().map(|(&block, &refs)| (block, refs as u32)));
+        }
+        self.fs.sync()
+    }
+}
+
+/// Name of an entry, if it is valid
+fn entry_name(buf: &[u8]) -> Option<&str> {
+    let len = buf.iter().position(|&b| b == 0)?;
+    core::str::from_utf8(&buf[..len]).ok()
+}
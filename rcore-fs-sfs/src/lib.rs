@@ -5,7 +5,7 @@ extern crate alloc;
 extern crate log;
 
 use alloc::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     string::String,
     sync::{Arc, Weak},
     vec,
@@ -14,17 +14,28 @@ use alloc::{
 use core::any::Any;
 use core::fmt::{Debug, Error, Formatter};
 use core::mem::MaybeUninit;
+use core::ops::Range;
+use core::sync::atomic::{self, AtomicBool, AtomicUsize};
 
 use bitvec::prelude::*;
 use spin::RwLock;
 
-use rcore_fs::dev::Device;
+use rcore_fs::dev::journaled::{JournalConfig, JournaledDevice, WriteThrough};
+use rcore_fs::dev::{Device, TimeProvider};
 use rcore_fs::dirty::Dirty;
+use rcore_fs::stats::Stats;
 use rcore_fs::util::*;
-use rcore_fs::vfs::{self, FileSystem, FsError, INode, MMapArea, Metadata};
+use rcore_fs::vfs::{self, DirDefaults, FileSystem, FsError, INode, MMapArea, Metadata};
+use rcore_fs::writeback::{DirtyTracker, Flusher, WritebackPolicy};
 
+use self::free_map::FreeMap;
 pub use self::structs::*;
 
+pub mod defrag;
+mod free_map;
+pub mod fsck;
+mod readdir;
+mod reflink;
 mod structs;
 #[cfg(test)]
 mod tests;
@@ -46,16 +57,46 @@ trait DeviceExt: Device {
             _ => panic!("cannot write block {} offset {} to device", id, offset),
         }
     }
+    /// Read bytes at `offset` into `bufs` one after another
+    fn read_vectored_exact(&self, offset: usize, bufs: &mut [&mut [u8]]) -> vfs::Result<()> {
+        let len: usize = bufs.iter().map(|buf| buf.len()).sum();
+        info!("offset\t{}\tlen\t{}\t0", offset, len);
+        match self.read_vectored(offset, bufs) {
+            Ok(read) if read == len => Ok(()),
+            _ => panic!("cannot read {} bytes at {} from device", len, offset),
+        }
+    }
+    /// Write `bufs` at `offset` one after another
+    fn write_vectored_exact(&self, offset: usize, bufs: &[&[u8]]) -> vfs::Result<()> {
+        let len: usize = bufs.iter().map(|buf| buf.len()).sum();
+        info!("offset\t{}\tlen\t{}\t1", offset, len);
+        match self.write_vectored(offset, bufs) {
+            Ok(written) if written == len => Ok(()),
+            _ => panic!("cannot write {} bytes at {} to device", len, offset),
+        }
+    }
     /// Load struct `T` from given block in device
     fn load_struct<T: AsBuf>(&self, id: BlockId) -> vfs::Result<T> {
         let mut s: T = unsafe { MaybeUninit::uninit().assume_init() };
         self.read_block(id, 0, s.as_buf_mut())?;
         Ok(s)
     }
+    /// A block must be written without touching its neighbours
+    fn check_sector_size(&self) -> vfs::Result<()> {
+        if self.sector_size_log2() > BLKSIZE_LOG2 {
+            return Err(FsError::InvalidParam);
+        }
+        Ok(())
+    }
 }
 
 impl DeviceExt for dyn Device {}
 
+static ZEROS: [u8; BLKSIZE] = [0; BLKSIZE];
+
+/// Number of places in a block group for files to start at
+const GROUP_COLORS: usize = 16;
+
 /// INode for SFS
 pub struct INodeImpl {
     /// INode number
@@ -64,9 +105,15 @@ pub struct INodeImpl {
     disk_inode: RwLock<Dirty<DiskINode>>,
     /// Reference to SFS, used by almost all operations
     fs: Arc<SimpleFileSystem>,
-    /// Char/block device id (major, minor)
+    /// Device number of a char/block device, made by `make_rdev()`,
+    /// which is its key in `SimpleFileSystem::device_inodes`
     /// e.g. crw-rw-rw- 1 root wheel 3, 2 May 13 16:40 /dev/null
-    device_inode_id: usize,
+    rdev: usize,
+    /// Block to allocate the next block of it at if free, 0 if not known yet
+    alloc_hint: AtomicUsize,
+    /// Held across an operation on the content, shared to read it and exclusive to change it,
+    /// while `disk_inode` is only held within each step
+    lock: RwLock<()>,
 }
 
 impl Debug for INodeImpl {
@@ -80,87 +127,125 @@ impl Debug for INodeImpl {
 }
 
 impl INodeImpl {
-    /// Map file block id to disk block id
+    /// Map file block id to disk block id, 0 if it is a hole
     fn get_disk_block_id(&self, file_block_id: BlockId) -> vfs::Result<BlockId> {
+        let map = self.fs.block_map();
         let disk_inode = self.disk_inode.read();
         match file_block_id {
             id if id >= disk_inode.blocks as BlockId => Err(FsError::InvalidParam),
-            id if id < MAX_NBLOCK_DIRECT => Ok(disk_inode.direct[id] as BlockId),
-            id if id < MAX_NBLOCK_INDIRECT => {
-                let mut disk_block_id: u32 = 0;
-                self.fs.device.read_block(
-                    disk_inode.indirect as usize,
-                    ENTRY_SIZE * (id - NDIRECT),
-                    disk_block_id.as_buf_mut(),
-                )?;
-                Ok(disk_block_id as BlockId)
-            }
-            id if id < MAX_NBLOCK_DOUBLE_INDIRECT => {
+            id if id < MAX_NBLOCK_DIRECT => Ok(disk_inode.direct(id)),
+            id if id < map.max_indirect() => self.read_entry(disk_inode.indirect(), id - NDIRECT),
+            id if id < map.max_double_indirect() => {
                 // double indirect
-                let indirect_id = id - MAX_NBLOCK_INDIRECT;
-                let mut indirect_block_id: u32 = 0;
-                self.fs.device.read_block(
-                    disk_inode.db_indirect as usize,
-                    ENTRY_SIZE * (indirect_id / BLK_NENTRY),
-                    indirect_block_id.as_buf_mut(),
-                )?;
+                let indirect_id = id - map.max_indirect();
+                let indirect_block_id =
+                    self.read_entry(disk_inode.db_indirect(), indirect_id / map.entries())?;
                 assert!(indirect_block_id > 0);
-                let mut disk_block_id: u32 = 0;
-                self.fs.device.read_block(
-                    indirect_block_id as usize,
-                    ENTRY_SIZE * (indirect_id as usize % BLK_NENTRY),
-                    disk_block_id.as_buf_mut(),
-                )?;
-                assert!(disk_block_id > 0);
-                Ok(disk_block_id as BlockId)
+                self.read_entry(indirect_block_id, indirect_id % map.entries())
             }
-            _ => unimplemented!("triple indirect blocks is not supported"),
+            id if id < map.max_triple_indirect() => {
+                let (i2, i1, i0) = triple_indirect_path(map, id);
+                let db_indirect = self.read_entry(disk_inode.tri_indirect(), i2)?;
+                let indirect = self.read_entry(db_indirect, i1)?;
+                self.read_entry(indirect, i0)
+            }
+            _ => unreachable!("beyond triple indirect blocks"),
         }
     }
     fn set_disk_block_id(&self, file_block_id: BlockId, disk_block_id: BlockId) -> vfs::Result<()> {
+        let map = self.fs.block_map();
         match file_block_id {
             id if id >= self.disk_inode.read().blocks as BlockId => Err(FsError::InvalidParam),
             id if id < MAX_NBLOCK_DIRECT => {
-                self.disk_inode.write().direct[id] = disk_block_id as u32;
+                self.disk_inode.write().set_direct(id, disk_block_id);
                 Ok(())
             }
-            id if id < MAX_NBLOCK_INDIRECT => {
-                let disk_block_id = disk_block_id as u32;
-                self.fs.device.write_block(
-                    self.disk_inode.read().indirect as usize,
-                    ENTRY_SIZE * (id - NDIRECT),
-                    disk_block_id.as_buf(),
-                )?;
-                Ok(())
+            id if id < map.max_indirect() => {
+                let indirect = self.disk_inode.read().indirect();
+                self.write_entry(indirect, id - NDIRECT, disk_block_id)
             }
-            id if id < MAX_NBLOCK_DOUBLE_INDIRECT => {
+            id if id < map.max_double_indirect() => {
                 // double indirect
-                let indirect_id = id - MAX_NBLOCK_INDIRECT;
-                let mut indirect_block_id: u32 = 0;
-                self.fs.device.read_block(
-                    self.disk_inode.read().db_indirect as usize,
-                    ENTRY_SIZE * (indirect_id / BLK_NENTRY),
-                    indirect_block_id.as_buf_mut(),
-                )?;
+                let indirect_id = id - map.max_indirect();
+                let db_indirect = self.disk_inode.read().db_indirect();
+                let indirect_block_id =
+                    self.read_entry(db_indirect, indirect_id / map.entries())?;
                 assert!(indirect_block_id > 0);
-                let disk_block_id = disk_block_id as u32;
-                self.fs.device.write_block(
-                    indirect_block_id as usize,
-                    ENTRY_SIZE * (indirect_id as usize % BLK_NENTRY),
-                    disk_block_id.as_buf(),
-                )?;
-                Ok(())
+                self.write_entry(
+                    indirect_block_id,
+                    indirect_id % map.entries(),
+                    disk_block_id,
+                )
             }
-            _ => unimplemented!("triple indirect blocks is not supported"),
+            id if id < map.max_triple_indirect() => {
+                let (i2, i1, i0) = triple_indirect_path(map, id);
+                let tri_indirect = self.disk_inode.read().tri_indirect();
+                let db_indirect = self.read_entry(tri_indirect, i2)?;
+                let indirect = self.read_entry(db_indirect, i1)?;
+                self.write_entry(indirect, i0, disk_block_id)
+            }
+            _ => unreachable!("beyond triple indirect blocks"),
         }
     }
+    /// Read entry `index` of indirect block `block`
+    fn read_entry(&self, block: BlockId, index: usize) -> vfs::Result<BlockId> {
+        let map = self.fs.block_map();
+        let mut entry = [0u8; 8];
+        let entry = &mut entry[..map.entry_size];
+        self.fs
+            .device
+            .read_block(block, map.entry_size * index, entry)?;
+        Ok(map.read(entry))
+    }
+    /// Write entry `index` of indirect block `block`
+    fn write_entry(&self, block: BlockId, index: usize, entry: BlockId) -> vfs::Result<()> {
+        let map = self.fs.block_map();
+        self.fs.device.write_block(
+            block,
+            map.entry_size * index,
+            &map.write(entry)[..map.entry_size],
+        )
+    }
     /// Only for Dir
     fn get_file_inode_and_entry_id(&self, name: &str) -> Option<(INodeId, usize)> {
-        (0..self.disk_inode.read().size as usize / DIRENT_SIZE)
+        let (sorted, fold) = {
+            let super_block = self.fs.super_block.read();
+            (super_block.sorted_dirs(), super_block.case_fold())
+        };
+        if sorted {
+            return self.search_direntry(name).ok();
+        }
+        let name = fold_name(fold, name);
+        (0..self.disk_inode.read().size() / DIRENT_SIZE)
             .map(|i| (self.read_direntry(i as usize).unwrap(), i))
-            .find(|(entry, _)| entry.name.as_ref() == name)
+            .find(|(entry, _)| fold_name(fold, entry.name.as_ref()) == name)
             .map(|(entry, id)| (entry.id as INodeId, id as usize))
     }
+    /// Binary search `name` in a sorted dir, folded if the image is case-insensitive.
+    /// Return its inode and entry id, or else the entry id to insert it at.
+    fn search_direntry(&self, name: &str) -> Result<(INodeId, usize), usize> {
+        use core::cmp::Ordering;
+        let fold = self.fs.super_block.read().case_fold();
+        let count = self.disk_inode.read().size() / DIRENT_SIZE;
+        // '.' and '..' are always the first
+        if let Some(id) = [".", ".."].iter().position(|&dots| dots == name) {
+            if id < count {
+                return Ok((self.read_direntry(id).unwrap().id as INodeId, id));
+            }
+        }
+        let name = fold_name(fold, name);
+        let (mut begin, mut end) = (2.min(count), count);
+        while begin < end {
+            let mid = (begin + end) / 2;
+            let entry = self.read_direntry(mid).unwrap();
+            match fold_name(fold, entry.name.as_ref()).cmp(&name) {
+                Ordering::Equal => return Ok((entry.id as INodeId, mid)),
+                Ordering::Less => begin = mid + 1,
+                Ordering::Greater => end = mid,
+            }
+        }
+        Err(begin)
+    }
     fn get_file_inode_id(&self, name: &str) -> Option<INodeId> {
         self.get_file_inode_and_entry_id(name)
             .map(|(inode_id, _)| inode_id)
@@ -195,183 +280,573 @@ impl INodeImpl {
         self._write_at(DIRENT_SIZE * id, direntry.as_buf())?;
         Ok(())
     }
-    fn append_direntry(&self, direntry: &DiskEntry) -> vfs::Result<()> {
-        let size = self.disk_inode.read().size as usize;
+    /// Add a direntry at the end, or in the order of names if dirs are sorted.
+    /// The size is written once the last one is copied past the end,
+    /// and before any is moved, so that a crash loses none of them.
+    fn add_direntry(&self, direntry: &DiskEntry) -> vfs::Result<()> {
+        let size = self.disk_inode.read().size();
         let dirent_count = size / DIRENT_SIZE;
+        let id = match self.fs.super_block.read().sorted_dirs() {
+            true => match self.search_direntry(direntry.name.as_ref()) {
+                Ok((_, id)) | Err(id) => id,
+            },
+            false => dirent_count,
+        };
         self._resize(size + DIRENT_SIZE)?;
-        self.write_direntry(dirent_count, direntry)?;
+        match id < dirent_count {
+            true => self.write_direntry(dirent_count, &self.read_direntry(dirent_count - 1)?)?,
+            false => self.write_direntry(id, direntry)?,
+        }
+        self.touch();
+        self.sync_all()?;
+        if id < dirent_count {
+            self.move_direntries(id..dirent_count - 1, id + 1)?;
+            self.write_direntry(id, direntry)?;
+        }
         Ok(())
     }
     /// remove a direntry in middle of file and insert the last one here, useful for direntry remove
-    /// should be only used in unlink
+    /// should be only used in unlink.
+    /// The ones after it are moved back instead if dirs are sorted.
+    /// The size is written after, so that a crash loses none of the others.
     fn remove_direntry(&self, id: usize) -> vfs::Result<()> {
-        let size = self.disk_inode.read().size as usize;
+        let size = self.disk_inode.read().size();
         let dirent_count = size / DIRENT_SIZE;
         debug_assert!(id < dirent_count);
-        let last_dirent = self.read_direntry(dirent_count - 1)?;
-        self.write_direntry(id, &last_dirent)?;
+        if self.fs.super_block.read().sorted_dirs() {
+            self.move_direntries(id + 1..dirent_count, id)?;
+        } else {
+            let last_dirent = self.read_direntry(dirent_count - 1)?;
+            self.write_direntry(id, &last_dirent)?;
+        }
         self._resize(size - DIRENT_SIZE)?;
+        self.touch();
+        self.sync_all()
+    }
+    /// Move direntries in `range` to start at `to`, a block on disk at a time,
+    /// from the end if moved forward, so that none is overwritten before moved.
+    /// A crash leaves each at least once, though the one across the border of the blocks
+    /// moved and not may be left with the start of one and the end of the next.
+    fn move_direntries(&self, range: Range<usize>, to: usize) -> vfs::Result<()> {
+        let begin = to * DIRENT_SIZE;
+        let end = begin + range.len() * DIRENT_SIZE;
+        let mut chunks: Vec<Range<usize>> = (begin / BLKSIZE * BLKSIZE..end)
+            .step_by(BLKSIZE)
+            .map(|block| block.max(begin)..(block + BLKSIZE).min(end))
+            .collect();
+        if to > range.start {
+            chunks.reverse();
+        }
+        let mut buf = [0u8; BLKSIZE];
+        for chunk in chunks {
+            let buf = &mut buf[..chunk.len()];
+            self._read_at(chunk.start - begin + range.start * DIRENT_SIZE, buf)?;
+            self._write_at(chunk.start, buf)?;
+        }
+        Ok(())
+    }
+    /// Sort direntries after '.' and '..' by name, folded if the image is case-insensitive
+    fn sort_direntries(&self) -> vfs::Result<()> {
+        let fold = self.fs.super_block.read().case_fold();
+        let count = self.disk_inode.read().size() / DIRENT_SIZE;
+        let mut entries = Vec::with_capacity(count.saturating_sub(2));
+        for id in 2..count {
+            entries.push(self.read_direntry(id)?);
+        }
+        entries.sort_by_cached_key(|entry| fold_name(fold, entry.name.as_ref()).into_owned());
+        for (i, entry) in entries.iter().enumerate() {
+            self.write_direntry(i + 2, entry)?;
+        }
+        Ok(())
+    }
+    /// Set where to allocate block `blocks` of it if not yet: after the block before,
+    /// or else for a file at one of the places for files to start at in the block group of it,
+    /// so that files growing at once do not take turns in the same blocks
+    fn init_alloc_hint(&self, blocks: usize) -> vfs::Result<()> {
+        if self.alloc_hint.load(atomic::Ordering::Relaxed) != 0 {
+            return Ok(());
+        }
+        let last = match blocks {
+            0 => 0,
+            _ => self.get_disk_block_id(blocks - 1)?,
+        };
+        let hint = match last {
+            0 if self.disk_inode.read().type_ == FileType::File => {
+                let span = BLKBITS.min(self.fs.super_block.read().blocks());
+                let group = self.fs.inode_location(self.id).0 / BLKBITS * BLKBITS;
+                group + self.id % GROUP_COLORS * (span / GROUP_COLORS)
+            }
+            0 => self.fs.inode_location(self.id).0 + 1,
+            _ => last + 1,
+        };
+        self.alloc_hint.store(hint, atomic::Ordering::Relaxed);
+        Ok(())
+    }
+    /// Whether blocks for it may be taken from the reserved ones.
+    /// The vfs does not know who calls, so its owner is taken to be privileged if root.
+    fn privileged(&self) -> bool {
+        self.disk_inode.read().uid == 0
+    }
+    /// `vfs::FS_*_FL` bits of it, none if the image has no flags
+    fn flags(&self) -> u32 {
+        match self.fs.super_block.read().inode_flags() {
+            true => self.disk_inode.read().flags,
+            false => 0,
+        }
+    }
+    /// Fail with `NotPermitted` if it has any of `flags`
+    fn check_flags(&self, flags: u32) -> vfs::Result<()> {
+        match self.flags() & flags {
+            0 => Ok(()),
+            _ => Err(FsError::NotPermitted),
+        }
+    }
+    /// Set its `vfs::FS_*_FL` bits, see `FEATURE_INODE_FLAGS`
+    fn set_flags(&self, flags: u32) -> vfs::Result<()> {
+        if !self.fs.super_block.read().inode_flags() {
+            return Err(FsError::NotSupported);
+        }
+        if flags & !INODE_FLAGS != 0 {
+            return Err(FsError::InvalidParam);
+        }
+        let _lock = self.lock.write();
+        self.disk_inode.write().flags = flags;
         Ok(())
     }
+    /// Allocate a block for it, at the hint if free, from the reserved blocks if `reserve`
+    fn alloc_block(&self, reserve: bool) -> Option<BlockId> {
+        let hint = self.alloc_hint.load(atomic::Ordering::Relaxed);
+        let id = self.fs.alloc_block(hint, reserve)?;
+        self.alloc_hint.store(id + 1, atomic::Ordering::Relaxed);
+        Some(id)
+    }
     /// Resize content size, no matter what type it is.
     fn _resize(&self, len: usize) -> vfs::Result<()> {
-        if len > MAX_FILE_SIZE {
+        let map = self.fs.block_map();
+        let max_blocks = match self.fs.super_block.read().large_file() {
+            true => map.max_triple_indirect(),
+            false if len > MAX_FILE_SIZE => return Err(FsError::InvalidParam),
+            false => map.max_double_indirect(),
+        };
+        let blocks = len / BLKSIZE + (len % BLKSIZE != 0) as usize;
+        if blocks > max_blocks {
             return Err(FsError::InvalidParam);
         }
-        let blocks = ((len + BLKSIZE - 1) / BLKSIZE) as u32;
-        if blocks > MAX_NBLOCK_DOUBLE_INDIRECT as u32 {
-            return Err(FsError::InvalidParam);
+        let blocks = blocks as u32;
+        let mut inline = None;
+        if self.disk_inode.read().is_inline() {
+            let mut disk_inode = self.disk_inode.write();
+            let size = disk_inode.size();
+            if len <= MAX_INLINE_SYMLINK && self.fs.super_block.read().inline_symlinks() {
+                for byte in disk_inode.inline_data_mut()[len.min(size)..].iter_mut() {
+                    *byte = 0;
+                }
+                disk_inode.set_size(len);
+                return Ok(());
+            }
+            // moved to blocks, and written back after they are allocated
+            inline = Some(disk_inode.inline_data()[..size].to_vec());
+            for byte in disk_inode.inline_data_mut().iter_mut() {
+                *byte = 0;
+            }
+            disk_inode.set_size(0);
         }
         use core::cmp::Ordering;
         let old_blocks = self.disk_inode.read().blocks;
         match blocks.cmp(&old_blocks) {
             Ordering::Equal => {
-                self.disk_inode.write().size = len as u32;
+                let shrink = len < self.disk_inode.read().size();
+                self.disk_inode.write().set_size(len);
+                if shrink && self.fs.zero_on_free() {
+                    self._clean_slack()?;
+                }
             }
             Ordering::Greater => {
+                let holes = self.sparse();
+                let reserve = self.privileged();
+                // fail early rather than allocate blocks until the device is full,
+                // only the indirect blocks if the new ones are holes
+                let needed = match holes {
+                    true => (blocks - old_blocks) as usize / map.entries(),
+                    false => (blocks - old_blocks) as usize,
+                };
+                if needed > self.fs.available_blocks(reserve) {
+                    return Err(FsError::NoDeviceSpace);
+                }
+                self.init_alloc_hint(old_blocks as usize)?;
                 let mut disk_inode = self.disk_inode.write();
                 disk_inode.blocks = blocks;
                 // allocate indirect block if needed
                 if old_blocks < MAX_NBLOCK_DIRECT as u32 && blocks >= MAX_NBLOCK_DIRECT as u32 {
-                    disk_inode.indirect = self.fs.alloc_block().expect("no space") as u32;
+                    disk_inode.set_indirect(self.alloc_block(reserve).expect("no space"));
                 }
                 // allocate double indirect block if needed
-                if blocks >= MAX_NBLOCK_INDIRECT as u32 {
-                    if disk_inode.db_indirect == 0 {
-                        disk_inode.db_indirect = self.fs.alloc_block().expect("no space") as u32;
+                if blocks as usize >= map.max_indirect() {
+                    if disk_inode.db_indirect() == 0 {
+                        disk_inode.set_db_indirect(self.alloc_block(reserve).expect("no space"));
                     }
                     let indirect_begin = {
-                        if (old_blocks as usize) < MAX_NBLOCK_INDIRECT {
+                        if (old_blocks as usize) < map.max_indirect() {
                             0
                         } else {
-                            (old_blocks as usize - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1
+                            (old_blocks as usize - map.max_indirect()) / map.entries() + 1
                         }
                     };
-                    let indirect_end = (blocks as usize - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1;
+                    let indirect_end = (blocks as usize - map.max_indirect()) / map.entries() + 1;
+                    let indirect_begin = indirect_begin.min(map.entries());
+                    let indirect_end = indirect_end.min(map.entries());
                     for i in indirect_begin..indirect_end {
-                        let indirect = self.fs.alloc_block().expect("no space") as u32;
-                        self.fs.device.write_block(
-                            disk_inode.db_indirect as usize,
-                            ENTRY_SIZE * i,
-                            indirect.as_buf(),
-                        )?;
+                        let indirect = self.alloc_block(reserve).expect("no space");
+                        self.write_entry(disk_inode.db_indirect(), i, indirect)?;
+                    }
+                }
+                // allocate triple indirect block and the blocks under it if needed
+                if blocks as usize > map.max_double_indirect() {
+                    if disk_inode.tri_indirect() == 0 {
+                        disk_inode.set_tri_indirect(self.alloc_block(reserve).expect("no space"));
+                    }
+                    let tri_indirect = disk_inode.tri_indirect();
+                    let (begin, end) = triple_indirect_blocks(map, old_blocks, blocks);
+                    for i in begin..end {
+                        // a double indirect block for every `map.entries()` indirect blocks
+                        let db_indirect = match i % map.entries() {
+                            0 => {
+                                let db_indirect = self.alloc_block(reserve).expect("no space");
+                                self.write_entry(tri_indirect, i / map.entries(), db_indirect)?;
+                                db_indirect
+                            }
+                            _ => self.read_entry(tri_indirect, i / map.entries())?,
+                        };
+                        let indirect = self.alloc_block(reserve).expect("no space");
+                        self.write_entry(db_indirect, i % map.entries(), indirect)?;
                     }
                 }
                 drop(disk_inode);
-                // allocate extra blocks
+                // allocate extra blocks, or mark them holes,
+                // as entries past the old blocks may be left from a shrink
                 for i in old_blocks..blocks {
-                    let disk_block_id = self.fs.alloc_block().expect("no space");
+                    let disk_block_id = match holes {
+                        true => 0,
+                        false => self.alloc_block(reserve).expect("no space"),
+                    };
                     self.set_disk_block_id(i as usize, disk_block_id)?;
                 }
                 // clean up
                 let mut disk_inode = self.disk_inode.write();
-                let old_size = disk_inode.size as usize;
-                disk_inode.size = len as u32;
+                if !holes && self.fs.super_block.read().sparse_files() {
+                    disk_inode.data_blocks += blocks - old_blocks;
+                }
+                let old_size = disk_inode.size();
+                disk_inode.set_size(len);
                 drop(disk_inode);
                 self._clean_at(old_size, len)?;
             }
             Ordering::Less => {
+                // to grow again after the last block left
+                self.alloc_hint.store(0, atomic::Ordering::Relaxed);
                 // free extra blocks
+                let mut freed = 0;
                 for i in blocks..old_blocks {
                     let disk_block_id = self.get_disk_block_id(i as usize)?;
-                    self.fs.free_block(disk_block_id);
+                    if disk_block_id != 0 {
+                        self.fs.free_block(disk_block_id);
+                        freed += 1;
+                    }
                 }
                 let mut disk_inode = self.disk_inode.write();
+                if self.fs.super_block.read().sparse_files() {
+                    disk_inode.data_blocks -= freed;
+                }
                 // free indirect block if needed
                 if blocks < MAX_NBLOCK_DIRECT as u32
                     && disk_inode.blocks >= MAX_NBLOCK_DIRECT as u32
                 {
-                    self.fs.free_block(disk_inode.indirect as usize);
-                    disk_inode.indirect = 0;
+                    self.fs.free_block(disk_inode.indirect());
+                    disk_inode.set_indirect(0);
                 }
                 // free double indirect block if needed
-                if disk_inode.blocks >= MAX_NBLOCK_INDIRECT as u32 {
+                if disk_inode.blocks as usize >= map.max_indirect() {
                     let indirect_begin = {
-                        if (blocks as usize) < MAX_NBLOCK_INDIRECT {
+                        if (blocks as usize) < map.max_indirect() {
                             0
                         } else {
-                            (blocks as usize - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1
+                            (blocks as usize - map.max_indirect()) / map.entries() + 1
                         }
                     };
                     let indirect_end =
-                        (disk_inode.blocks as usize - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1;
+                        (disk_inode.blocks as usize - map.max_indirect()) / map.entries() + 1;
+                    let indirect_begin = indirect_begin.min(map.entries());
+                    let indirect_end = indirect_end.min(map.entries());
                     for i in indirect_begin..indirect_end {
-                        let mut indirect: u32 = 0;
-                        self.fs.device.read_block(
-                            disk_inode.db_indirect as usize,
-                            ENTRY_SIZE * i,
-                            indirect.as_buf_mut(),
-                        )?;
+                        let indirect = self.read_entry(disk_inode.db_indirect(), i)?;
                         assert!(indirect > 0);
-                        self.fs.free_block(indirect as usize);
+                        self.fs.free_block(indirect);
+                    }
+                    if (blocks as usize) < map.max_indirect() {
+                        assert!(disk_inode.db_indirect() > 0);
+                        self.fs.free_block(disk_inode.db_indirect());
+                        disk_inode.set_db_indirect(0);
                     }
-                    if blocks < MAX_NBLOCK_INDIRECT as u32 {
-                        assert!(disk_inode.db_indirect > 0);
-                        self.fs.free_block(disk_inode.db_indirect as usize);
-                        disk_inode.db_indirect = 0;
+                }
+                // free triple indirect block and the blocks under it if needed
+                if disk_inode.blocks as usize > map.max_double_indirect() {
+                    let tri_indirect = disk_inode.tri_indirect();
+                    let (begin, end) = triple_indirect_blocks(map, blocks, disk_inode.blocks);
+                    for i in begin..end {
+                        let db_indirect = self.read_entry(tri_indirect, i / map.entries())?;
+                        self.fs
+                            .free_block(self.read_entry(db_indirect, i % map.entries())?);
+                    }
+                    let begin = (begin + map.entries() - 1) / map.entries();
+                    let end = (end + map.entries() - 1) / map.entries();
+                    for i in begin..end {
+                        self.fs.free_block(self.read_entry(tri_indirect, i)?);
+                    }
+                    if blocks as usize <= map.max_double_indirect() {
+                        self.fs.free_block(tri_indirect);
+                        disk_inode.set_tri_indirect(0);
                     }
                 }
                 disk_inode.blocks = blocks;
-                disk_inode.size = len as u32;
+                disk_inode.set_size(len);
+                drop(disk_inode);
+                if self.fs.zero_on_free() {
+                    self._clean_slack()?;
+                }
+            }
+        }
+        if let Some(data) = inline {
+            self._write_at(0, &data)?;
+        }
+        Ok(())
+    }
+    /// Zero the rest of the last block after the end of content
+    fn _clean_slack(&self) -> vfs::Result<()> {
+        let size = self.disk_inode.read().size();
+        if size % BLKSIZE != 0 && !self.disk_inode.read().is_inline() {
+            let block = self.get_disk_block_id(size / BLKSIZE)?;
+            let begin = size % BLKSIZE;
+            // the rest of a shared block may be content of the others
+            if block != 0 && !self.fs.is_shared(block) {
+                self.fs
+                    .device
+                    .write_block(block, begin, &ZEROS[..BLKSIZE - begin])?;
             }
         }
         Ok(())
     }
+    /// Zero the slack space of this INode and all INodes under it
+    fn _wipe_slack(&self) -> vfs::Result<()> {
+        // the INode block or slot is not filled by DiskINode
+        let (block, range) = self.fs.inode_location(self.id);
+        let begin = range.start + core::mem::size_of::<DiskINode>();
+        self.fs
+            .device
+            .write_block(block, begin, &ZEROS[..range.end - begin])?;
+        self._clean_slack()?;
+        if self.disk_inode.read().type_ != FileType::Dir {
+            return Ok(());
+        }
+        let count = self.disk_inode.read().size() / DIRENT_SIZE;
+        // skip '.' and '..'
+        for i in 2..count {
+            let entry = self.read_direntry(i)?;
+            self.fs.get_inode(entry.id as INodeId)._wipe_slack()?;
+        }
+        Ok(())
+    }
     // Note: the _\w*_at method always return begin>size?0:begin<end?0:(min(size,end)-begin) when success
-    /// Read/Write content, no matter what type it is
+    /// Read/Write content, no matter what type it is.
+    /// `f` is called once for each run of blocks next to each other on the device,
+    /// with the offset on the device and the range of the content in it.
+    /// Holes are skipped.
     fn _io_at<F>(&self, begin: usize, end: usize, mut f: F) -> vfs::Result<usize>
     where
-        F: FnMut(&Arc<dyn Device>, &BlockRange, usize) -> vfs::Result<()>,
+        F: FnMut(&Arc<dyn Device>, usize, Range<usize>) -> vfs::Result<()>,
     {
-        let size = self.disk_inode.read().size as usize;
+        let size = self.disk_inode.read().size();
         let iter = BlockIter {
             begin: size.min(begin),
             end: size.min(end),
             block_size_log2: BLKSIZE_LOG2,
         };
 
+        let device = self.content_device();
+
         // For each block
         let mut buf_offset = 0usize;
-        for mut range in iter {
-            range.block = self.get_disk_block_id(range.block)?;
-            f(&self.fs.device, &range, buf_offset)?;
+        let mut run: Option<(usize, Range<usize>)> = None;
+        for range in iter {
+            let block = self.get_disk_block_id(range.block)?;
+            if block == 0 {
+                if let Some((run_offset, run_range)) = run.take() {
+                    f(device, run_offset, run_range)?;
+                }
+                buf_offset += range.len();
+                continue;
+            }
+            let offset = block * BLKSIZE + range.begin;
+            match &mut run {
+                Some((run_offset, run_range)) if *run_offset + run_range.len() == offset => {
+                    run_range.end += range.len();
+                }
+                _ => {
+                    if let Some((run_offset, run_range)) = run.take() {
+                        f(device, run_offset, run_range)?;
+                    }
+                    run = Some((offset, buf_offset..buf_offset + range.len()));
+                }
+            }
             buf_offset += range.len();
         }
+        if let Some((run_offset, run_range)) = run {
+            f(device, run_offset, run_range)?;
+        }
         Ok(buf_offset)
     }
+    /// Device its content is on: file data is not journaled, but the refcount table is
+    fn content_device(&self) -> &Arc<dyn Device> {
+        let refcount_inode = self.fs.super_block.read().refcount_inode();
+        match self.disk_inode.read().type_ {
+            FileType::File if refcount_inode != Some(self.id) => &self.fs.data_device,
+            _ => &self.fs.device,
+        }
+    }
     /// Read content, no matter what type it is
     fn _read_at(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<usize> {
-        self._io_at(offset, offset + buf.len(), |device, range, offset| {
-            device.read_block(
-                range.block,
-                range.begin,
-                &mut buf[offset..offset + range.len()],
-            )
+        {
+            let disk_inode = self.disk_inode.read();
+            if disk_inode.is_inline() {
+                let data = &disk_inode.inline_data()[..disk_inode.size()];
+                let range = offset.min(data.len())..(offset + buf.len()).min(data.len());
+                buf[..range.len()].copy_from_slice(&data[range.clone()]);
+                return Ok(range.len());
+            }
+        }
+        if self.has_holes() {
+            for byte in buf.iter_mut() {
+                *byte = 0;
+            }
+        }
+        self._io_at(offset, offset + buf.len(), |device, offset, range| {
+            device.read_vectored_exact(offset, &mut [&mut buf[range]])
         })
     }
     /// Write content, no matter what type it is
     fn _write_at(&self, offset: usize, buf: &[u8]) -> vfs::Result<usize> {
-        self._io_at(offset, offset + buf.len(), |device, range, offset| {
-            device.write_block(range.block, range.begin, &buf[offset..offset + range.len()])
+        {
+            let mut disk_inode = self.disk_inode.write();
+            if disk_inode.is_inline() {
+                let size = disk_inode.size();
+                let range = offset.min(size)..(offset + buf.len()).min(size);
+                let len = range.len();
+                disk_inode.inline_data_mut()[range].copy_from_slice(&buf[..len]);
+                return Ok(len);
+            }
+        }
+        if self.has_holes() {
+            self.fill_holes(offset, offset + buf.len())?;
+        }
+        self.unshare_blocks(offset, offset + buf.len())?;
+        self._io_at(offset, offset + buf.len(), |device, offset, range| {
+            device.write_vectored_exact(offset, &[&buf[range]])
         })
     }
+    /// Whether blocks of it are only allocated when written, see `FEATURE_SPARSE_FILES`
+    fn sparse(&self) -> bool {
+        self.disk_inode.read().type_ == FileType::File && self.fs.super_block.read().sparse_files()
+    }
+    /// Whether some blocks of it are holes
+    fn has_holes(&self) -> bool {
+        let (data_blocks, blocks) = {
+            let disk_inode = self.disk_inode.read();
+            (disk_inode.data_blocks, disk_inode.blocks)
+        };
+        data_blocks < blocks && self.sparse()
+    }
+    /// Allocate the holes in content `begin..end` to be written,
+    /// zeroing the rest of the content in their blocks
+    fn fill_holes(&self, begin: usize, end: usize) -> vfs::Result<()> {
+        let size = self.disk_inode.read().size();
+        let end = end.min(size);
+        if begin >= end {
+            return Ok(());
+        }
+        let reserve = self.privileged();
+        for i in begin / BLKSIZE..(end + BLKSIZE - 1) / BLKSIZE {
+            if self.get_disk_block_id(i)? != 0 {
+                continue;
+            }
+            self.init_alloc_hint(i)?;
+            let block = self.alloc_block(reserve).ok_or(FsError::NoDeviceSpace)?;
+            let (block_begin, block_end) = (i * BLKSIZE, ((i + 1) * BLKSIZE).min(size));
+            if block_begin < begin {
+                self.fs
+                    .data_device
+                    .write_block(block, 0, &ZEROS[..begin - block_begin])?;
+            }
+            if end < block_end {
+                self.fs.data_device.write_block(
+                    block,
+                    end - block_begin,
+                    &ZEROS[..block_end - end],
+                )?;
+            }
+            self.set_disk_block_id(i, block)?;
+            self.disk_inode.write().data_blocks += 1;
+        }
+        Ok(())
+    }
     /// Clean content, no matter what type it is
     fn _clean_at(&self, begin: usize, end: usize) -> vfs::Result<usize> {
-        static ZEROS: [u8; BLKSIZE] = [0; BLKSIZE];
-        self._io_at(begin, end, |device, range, _| {
-            device.write_block(range.block, range.begin, &ZEROS[..range.len()])
+        self.unshare_blocks(begin, end)?;
+        self._io_at(begin, end, |device, offset, range| {
+            let end = range.end;
+            let bufs: Vec<&[u8]> = range
+                .step_by(BLKSIZE)
+                .map(|i| &ZEROS[..(end - i).min(BLKSIZE)])
+                .collect();
+            device.write_vectored_exact(offset, &bufs)
         })
     }
     fn nlinks_inc(&self) {
-        self.disk_inode.write().nlinks += 1;
+        let mut disk_inode = self.disk_inode.write();
+        disk_inode.nlinks += 1;
+        if let Some(now) = self.fs.now() {
+            disk_inode.ctime = now;
+        }
     }
     fn nlinks_dec(&self) {
         let mut disk_inode = self.disk_inode.write();
         assert!(disk_inode.nlinks > 0);
         disk_inode.nlinks -= 1;
+        if let Some(now) = self.fs.now() {
+            disk_inode.ctime = now;
+        }
+    }
+    /// Set mtime and ctime to now, after the content is changed
+    fn touch(&self) {
+        if let Some(now) = self.fs.now() {
+            let mut disk_inode = self.disk_inode.write();
+            disk_inode.mtime = now;
+            disk_inode.ctime = now;
+        }
+    }
+
+    /// Add `entry` to `child`, whose link count is counted and written first,
+    /// so that a crash does not leave the count short of the entries
+    fn add_link(&self, entry: &DiskEntry, child: &INodeImpl) -> vfs::Result<()> {
+        child.nlinks_inc();
+        child.sync_all()?;
+        if let Err(e) = self.add_direntry(entry) {
+            child.nlinks_dec();
+            return Err(e);
+        }
+        Ok(())
     }
 
     pub fn link_inodeimpl(&self, name: &str, other: &Arc<INodeImpl>) -> vfs::Result<()> {
+        let _lock = self.lock.write();
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
@@ -393,23 +868,19 @@ impl INodeImpl {
             id: child.id as u32,
             name: Str256::from(name),
         };
-        let disk_inode = self.disk_inode.write();
-        let old_size = disk_inode.size as usize;
-        self._resize(old_size + BLKSIZE)?;
-        self._write_at(old_size, entry.as_buf()).unwrap();
-        child.nlinks_inc();
-        Ok(())
+        self.add_link(&entry, child)
     }
 }
 
 impl vfs::INode for INodeImpl {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<usize> {
+        let _lock = self.lock.read();
         match self.disk_inode.read().type_ {
             FileType::File => self._read_at(offset, buf),
             FileType::SymLink => self._read_at(offset, buf),
-            FileType::CharDevice => {
+            FileType::CharDevice | FileType::BlockDevice => {
                 let device_inodes = self.fs.device_inodes.read();
-                let device_inode = device_inodes.get(&self.device_inode_id);
+                let device_inode = device_inodes.get(&self.rdev);
                 match device_inode {
                     Some(device) => device.read_at(offset, buf),
                     None => Err(FsError::DeviceError),
@@ -419,18 +890,33 @@ impl vfs::INode for INodeImpl {
         }
     }
     fn write_at(&self, offset: usize, buf: &[u8]) -> vfs::Result<usize> {
-        let DiskINode { type_, size, .. } = **self.disk_inode.read();
+        let _lock = self.lock.write();
+        let (type_, size) = {
+            let disk_inode = self.disk_inode.read();
+            (disk_inode.type_, disk_inode.size())
+        };
         match type_ {
             FileType::File | FileType::SymLink => {
                 let end_offset = offset + buf.len();
-                if (size as usize) < end_offset {
+                if type_ == FileType::SymLink && end_offset > vfs::PATH_MAX {
+                    return Err(FsError::InvalidParam);
+                }
+                self.check_flags(vfs::FS_IMMUTABLE_FL)?;
+                if offset != size {
+                    self.check_flags(vfs::FS_APPEND_FL)?;
+                }
+                if size < end_offset {
                     self._resize(end_offset)?;
                 }
-                self._write_at(offset, buf)
+                let len = self._write_at(offset, buf)?;
+                self.touch();
+                drop(_lock);
+                self.fs.count_write(offset, len)?;
+                Ok(len)
             }
-            FileType::CharDevice => {
+            FileType::CharDevice | FileType::BlockDevice => {
                 let device_inodes = self.fs.device_inodes.write();
-                let device_inode = device_inodes.get(&self.device_inode_id);
+                let device_inode = device_inodes.get(&self.rdev);
                 match device_inode {
                     Some(device) => device.write_at(offset, buf),
                     None => Err(FsError::DeviceError),
@@ -448,43 +934,28 @@ impl vfs::INode for INodeImpl {
     }
     /// the size returned here is logical size(entry num for directory), not the disk space used.
     fn metadata(&self) -> vfs::Result<vfs::Metadata> {
-        let disk_inode = self.disk_inode.read();
-        Ok(vfs::Metadata {
-            dev: 0,
-            inode: self.id,
-            size: match disk_inode.type_ {
-                FileType::File | FileType::SymLink => disk_inode.size as usize,
-                FileType::Dir => disk_inode.size as usize,
-                FileType::CharDevice => 0,
-                FileType::BlockDevice => 0,
-                _ => panic!("Unknown file type"),
-            },
-            mode: 0o777,
-            type_: vfs::FileType::from(disk_inode.type_.clone()),
-            blocks: disk_inode.blocks as usize,
-            atime: disk_inode.atime,
-            mtime: disk_inode.mtime,
-            ctime: disk_inode.ctime,
-            nlinks: disk_inode.nlinks as usize,
-            uid: 0,
-            gid: 0,
-            blk_size: BLKSIZE,
-            rdev: self.device_inode_id,
-        })
+        Ok(self.fs.disk_metadata(self.id, &self.disk_inode.read()))
     }
     fn set_metadata(&self, metadata: &vfs::Metadata) -> vfs::Result<()> {
+        self.check_flags(vfs::FS_IMMUTABLE_FL)?;
         let mut disk_inode = self.disk_inode.write();
         disk_inode.atime = metadata.atime;
         disk_inode.mtime = metadata.mtime;
         disk_inode.ctime = metadata.ctime;
+        disk_inode.mode = metadata.mode | MODE_SET;
+        disk_inode.uid = metadata.uid as u32;
+        disk_inode.gid = metadata.gid as u32;
         Ok(())
     }
     fn sync_all(&self) -> vfs::Result<()> {
         let mut disk_inode = self.disk_inode.write();
         if disk_inode.dirty() {
+            // so that the blocks it refers to are in use on disk
+            self.fs.write_super_and_freemap()?;
+            let (block, range) = self.fs.inode_location(self.id);
             self.fs
                 .device
-                .write_block(self.id, 0, disk_inode.as_buf())?;
+                .write_block(block, range.start, disk_inode.as_buf())?;
             disk_inode.sync();
         }
         Ok(())
@@ -493,20 +964,28 @@ impl vfs::INode for INodeImpl {
         self.sync_all()
     }
     fn resize(&self, len: usize) -> vfs::Result<()> {
+        let _lock = self.lock.write();
         if self.disk_inode.read().type_ != FileType::File
             && self.disk_inode.read().type_ != FileType::SymLink
         {
             return Err(FsError::NotFile);
         }
-        self._resize(len)
+        if self.disk_inode.read().type_ == FileType::SymLink && len > vfs::PATH_MAX {
+            return Err(FsError::InvalidParam);
+        }
+        self.check_flags(vfs::FS_IMMUTABLE_FL | vfs::FS_APPEND_FL)?;
+        self._resize(len)?;
+        self.touch();
+        Ok(())
     }
     fn create2(
         &self,
         name: &str,
         type_: vfs::FileType,
-        _mode: u32,
+        mode: u32,
         data: usize,
     ) -> vfs::Result<Arc<dyn vfs::INode>> {
+        let _lock = self.lock.write();
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
@@ -514,36 +993,92 @@ impl vfs::INode for INodeImpl {
         if info.nlinks <= 0 {
             return Err(FsError::DirRemoved);
         }
+        self.check_flags(vfs::FS_IMMUTABLE_FL)?;
 
         // Ensure the name is not exist
         if !self.get_file_inode_id(name).is_none() {
             return Err(FsError::EntryExist);
         }
 
-        // Create new INode
+        // Create new INode, as privileged as the dir
+        let reserve = self.privileged();
         let inode = match type_ {
-            vfs::FileType::File => self.fs.new_inode_file()?,
-            vfs::FileType::SymLink => self.fs.new_inode_symlink()?,
-            vfs::FileType::Dir => self.fs.new_inode_dir(self.id)?,
-            vfs::FileType::CharDevice => self.fs.new_inode_chardevice(data)?,
-            _ => return Err(vfs::FsError::InvalidParam),
+            vfs::FileType::File => self.fs.new_inode_file(reserve)?,
+            vfs::FileType::SymLink => self.fs.new_inode_symlink(reserve)?,
+            vfs::FileType::Dir => self.fs.new_inode_dir(self.id, reserve)?,
+            vfs::FileType::CharDevice => {
+                self.fs
+                    .new_inode_device(FileType::CharDevice, data, reserve)?
+            }
+            vfs::FileType::BlockDevice => {
+                self.fs
+                    .new_inode_device(FileType::BlockDevice, data, reserve)?
+            }
+            vfs::FileType::NamedPipe => self.fs.new_inode_fifo(reserve)?,
+            vfs::FileType::Socket => self.fs.new_inode_socket(reserve)?,
         };
+        let umask = self.disk_inode.read().umask;
+        let defaults = DirDefaults {
+            umask,
+            ..DirDefaults::default()
+        };
+        let (mode, gid) = defaults.apply(&info, type_, mode);
+        {
+            let mut disk_inode = inode.disk_inode.write();
+            disk_inode.mode = mode | MODE_SET;
+            disk_inode.gid = gid as u32;
+            if type_ == vfs::FileType::Dir {
+                disk_inode.umask = umask;
+            }
+        }
 
-        // Write new entry
-        self.append_direntry(&DiskEntry {
+        if type_ == vfs::FileType::Dir {
+            inode.nlinks_inc(); //for .
+        }
+        // Write new entry, after the inode
+        let entry = DiskEntry {
             id: inode.id as u32,
             name: Str256::from(name),
-        })?;
-        inode.nlinks_inc();
+        };
+        if let Err(e) = self.add_link(&entry, &inode) {
+            if type_ == vfs::FileType::Dir {
+                inode.nlinks_dec();
+            }
+            return Err(e);
+        }
         if type_ == vfs::FileType::Dir {
-            inode.nlinks_inc(); //for .
             self.nlinks_inc(); //for ..
         }
 
         Ok(inode)
     }
 
+    fn dir_defaults(&self) -> vfs::Result<DirDefaults> {
+        let disk_inode = self.disk_inode.read();
+        if disk_inode.type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        Ok(DirDefaults {
+            umask: disk_inode.umask,
+            ..DirDefaults::default()
+        })
+    }
+
+    /// Default xattrs are not supported, as xattrs are not
+    fn set_dir_defaults(&self, defaults: DirDefaults) -> vfs::Result<()> {
+        let mut disk_inode = self.disk_inode.write();
+        if disk_inode.type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        if !defaults.xattrs.is_empty() {
+            return Err(FsError::NotSupported);
+        }
+        disk_inode.umask = defaults.umask;
+        Ok(())
+    }
+
     fn link(&self, name: &str, other: &Arc<dyn INode>) -> vfs::Result<()> {
+        let _lock = self.lock.write();
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
@@ -563,14 +1098,19 @@ impl vfs::INode for INodeImpl {
         if child.metadata()?.type_ == vfs::FileType::Dir {
             return Err(FsError::IsDir);
         }
-        self.append_direntry(&DiskEntry {
-            id: child.id as u32,
-            name: Str256::from(name),
-        })?;
-        child.nlinks_inc();
-        Ok(())
+        self.check_flags(vfs::FS_IMMUTABLE_FL)?;
+        child.check_flags(vfs::FS_IMMUTABLE_FL | vfs::FS_APPEND_FL)?;
+        self.add_link(
+            &DiskEntry {
+                id: child.id as u32,
+                name: Str256::from(name),
+            },
+            child,
+        )
     }
     fn unlink(&self, name: &str) -> vfs::Result<()> {
+        let _rename_lock = self.fs.rename_lock.read();
+        let _lock = self.lock.write();
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
@@ -589,24 +1129,43 @@ impl vfs::INode for INodeImpl {
             .get_file_inode_and_entry_id(name)
             .ok_or(FsError::EntryNotFound)?;
         let inode = self.fs.get_inode(inode_id);
+        self.check_flags(vfs::FS_IMMUTABLE_FL | vfs::FS_APPEND_FL)?;
+        inode.check_flags(vfs::FS_IMMUTABLE_FL | vfs::FS_APPEND_FL)?;
 
         let type_ = inode.disk_inode.read().type_;
+        // so that no entry is added to it meanwhile
+        let _child_lock = match type_ {
+            FileType::Dir => Some(inode.lock.write()),
+            _ => None,
+        };
         if type_ == FileType::Dir {
             // only . and ..
-            if inode.disk_inode.read().size as usize / DIRENT_SIZE > 2 {
+            if inode.disk_inode.read().size() / DIRENT_SIZE > 2 {
                 return Err(FsError::DirNotEmpty);
             }
         }
-        inode.nlinks_dec();
         if type_ == FileType::Dir {
-            inode.nlinks_dec(); //for .
             self.nlinks_dec(); //for ..
         }
+        // the entry is removed on disk before the link count
         self.remove_direntry(entry_id)?;
+        inode.nlinks_dec();
+        if type_ == FileType::Dir {
+            inode.nlinks_dec(); //for .
+        }
+        inode.sync_all()?;
+        if inode.disk_inode.read().nlinks <= 0 {
+            let mut batch = self.fs.batch.write();
+            if batch.0 > 0 {
+                batch.1.push(inode.clone());
+            }
+        }
 
         Ok(())
     }
     fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> vfs::Result<()> {
+        let _rename_lock = self.fs.rename_lock.write();
+        let _lock = self.lock.write();
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
@@ -631,17 +1190,45 @@ impl vfs::INode for INodeImpl {
         if dest_info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
         }
+        let _dest_lock = match info.inode == dest_info.inode {
+            true => None,
+            false => Some(dest.lock.write()),
+        };
         if dest_info.nlinks <= 0 {
             return Err(FsError::DirRemoved);
         }
-        if let Some((_, id)) = dest.get_file_inode_and_entry_id(new_name) {
-            dest.remove_direntry(id)?;
+        let inode_id = self
+            .get_file_inode_id(old_name)
+            .ok_or(FsError::EntryNotFound)?;
+        if inode_id == dest.id {
+            return Err(FsError::InvalidParam);
         }
+        let protected = vfs::FS_IMMUTABLE_FL | vfs::FS_APPEND_FL;
+        self.check_flags(protected)?;
+        dest.check_flags(vfs::FS_IMMUTABLE_FL)?;
+        self.fs.get_inode(inode_id).check_flags(protected)?;
+        let old_entry_id = self
+            .get_file_inode_and_entry_id(old_name)
+            .ok_or(FsError::EntryNotFound)?
+            .1;
+        // the same entry if only the case of its name is changed in a case-insensitive image
+        let same_entry = match dest.get_file_inode_and_entry_id(new_name) {
+            Some((_, id)) if info.inode == dest_info.inode && id == old_entry_id => true,
+            Some((replaced, id)) => {
+                self.fs.get_inode(replaced).check_flags(protected)?;
+                dest.remove_direntry(id)?;
+                false
+            }
+            None => false,
+        };
 
         let (inode_id, entry_id) = self
             .get_file_inode_and_entry_id(old_name)
             .ok_or(FsError::EntryNotFound)?;
-        if info.inode == dest_info.inode {
+        // a new name may be out of order in a sorted dir, unless it folds to the old one
+        if info.inode == dest_info.inode
+            && (same_entry || !self.fs.super_block.read().sorted_dirs())
+        {
             // rename: in place modify name
             self.write_direntry(
                 entry_id,
@@ -650,23 +1237,47 @@ impl vfs::INode for INodeImpl {
                     name: Str256::from(new_name),
                 },
             )?;
+            self.touch();
         } else {
-            // move
-            dest.append_direntry(&DiskEntry {
+            // move, adding the new entry before the old one is removed,
+            // so that a crash leaves it in at least one of them
+            let inode = self.fs.get_inode(inode_id);
+            let moved_dir =
+                inode.disk_inode.read().type_ == FileType::Dir && info.inode != dest_info.inode;
+            if moved_dir {
+                dest.nlinks_inc();
+            }
+            dest.add_direntry(&DiskEntry {
                 id: inode_id as u32,
                 name: Str256::from(new_name),
             })?;
-            self.remove_direntry(entry_id)?;
-
-            let inode = self.fs.get_inode(inode_id);
-            if inode.metadata()?.type_ == vfs::FileType::Dir {
+            if moved_dir {
+                // a dir is taken to be in the one its '..' refers to
+                let _child_lock = inode.lock.write();
+                inode.write_direntry(
+                    1,
+                    &DiskEntry {
+                        id: dest.id as u32,
+                        name: Str256::from(".."),
+                    },
+                )?;
                 self.nlinks_dec();
-                dest.nlinks_inc();
             }
+            // moved by the one added if in the same dir
+            let entry_id = match info.inode == dest_info.inode {
+                true => {
+                    self.get_file_inode_and_entry_id(old_name)
+                        .ok_or(FsError::EntryNotFound)?
+                        .1
+                }
+                false => entry_id,
+            };
+            self.remove_direntry(entry_id)?;
         }
         Ok(())
     }
     fn find(&self, name: &str) -> vfs::Result<Arc<dyn vfs::INode>> {
+        let _lock = self.lock.read();
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
@@ -675,29 +1286,63 @@ impl vfs::INode for INodeImpl {
         Ok(self.fs.get_inode(inode_id))
     }
     fn get_entry(&self, id: usize) -> vfs::Result<String> {
+        let _lock = self.lock.read();
         if self.disk_inode.read().type_ != FileType::Dir {
             return Err(FsError::NotDir);
         }
-        if id >= self.disk_inode.read().size as usize / DIRENT_SIZE {
+        if id >= self.disk_inode.read().size() / DIRENT_SIZE {
             return Err(FsError::EntryNotFound);
         };
         let entry = self.read_direntry(id)?;
         Ok(String::from(entry.name.as_ref()))
     }
-    fn io_control(&self, _cmd: u32, _data: usize) -> vfs::Result<()> {
-        if self.metadata().unwrap().type_ != vfs::FileType::CharDevice {
-            return Err(FsError::IOCTLError);
+    fn get_entry_with_metadata(&self, id: usize) -> vfs::Result<(Metadata, String)> {
+        self.entries_with_metadata(id, 1)?
+            .pop()
+            .ok_or(FsError::EntryNotFound)
+    }
+    fn get_entries_with_metadata(
+        &self,
+        begin: usize,
+        max: usize,
+    ) -> vfs::Result<Vec<(Metadata, String)>> {
+        self.entries_with_metadata(begin, max)
+    }
+    /// `vfs::FS_IOC_GETFLAGS` and `vfs::FS_IOC_SETFLAGS` take the address of a u32 as `data`,
+    /// other commands are passed to a char device
+    fn io_control(&self, cmd: u32, data: usize) -> vfs::Result<()> {
+        match cmd {
+            vfs::FS_IOC_GETFLAGS => {
+                unsafe { *(data as *mut u32) = self.flags() };
+                return Ok(());
+            }
+            vfs::FS_IOC_SETFLAGS => return self.set_flags(unsafe { *(data as *const u32) }),
+            _ => {}
+        }
+        match self.disk_inode.read().type_ {
+            FileType::CharDevice | FileType::BlockDevice => {}
+            _ => return Err(FsError::IOCTLError),
         }
         let device_inodes = self.fs.device_inodes.read();
-        let device_inode = device_inodes.get(&self.device_inode_id);
+        let device_inode = device_inodes.get(&self.rdev);
         match device_inode {
-            Some(x) => x.io_control(_cmd, _data),
+            Some(x) => x.io_control(cmd, data),
             None => {
                 warn!("cannot find corresponding device inode in call_inoctl");
                 Err(FsError::IOCTLError)
             }
         }
     }
+    fn copy_file_range(
+        &self,
+        offset: usize,
+        dst: &Arc<dyn vfs::INode>,
+        dst_offset: usize,
+        len: usize,
+    ) -> vfs::Result<usize> {
+        let dst = dst.downcast_ref::<INodeImpl>().ok_or(FsError::NotSameFs)?;
+        self.copy_to(offset, dst, dst_offset, len)
+    }
     fn mmap(&self, _area: MMapArea) -> vfs::Result<()> {
         Err(FsError::NotSupported)
     }
@@ -709,6 +1354,24 @@ impl vfs::INode for INodeImpl {
     }
 }
 
+/// Indices in the triple indirect block, a double indirect block under it,
+/// and an indirect block under that, of file block `id`
+fn triple_indirect_path(map: BlockMap, id: BlockId) -> (usize, usize, usize) {
+    let id = id - map.max_double_indirect();
+    let n = map.entries();
+    (id / (n * n), id / n % n, id % n)
+}
+
+/// Indirect blocks under the triple indirect block, counted from 0, used by
+/// blocks in `begin..end` but not by blocks before `begin`
+fn triple_indirect_blocks(map: BlockMap, begin: u32, end: u32) -> (usize, usize) {
+    let count = |blocks: u32| {
+        let blocks = (blocks as usize).max(map.max_double_indirect()) - map.max_double_indirect();
+        (blocks + map.entries() - 1) / map.entries()
+    };
+    (count(begin), count(end))
+}
+
 impl Drop for INodeImpl {
     /// Auto sync when drop
     fn drop(&mut self) {
@@ -717,92 +1380,301 @@ impl Drop for INodeImpl {
         if self.disk_inode.read().nlinks <= 0 {
             self._resize(0).unwrap();
             self.disk_inode.write().sync();
-            self.fs.free_block(self.id);
+            self.fs.free_inode(self.id);
         }
     }
 }
 
+/// Slots in use of the blocks of a packed inode table, see `FEATURE_PACKED_INODES`
+#[derive(Default)]
+struct INodeSlots {
+    /// Mask of the slots in use of each block in use, loaded when it is needed
+    masks: BTreeMap<BlockId, u16>,
+    /// Blocks in `masks` with a free slot
+    partial: BTreeSet<BlockId>,
+    /// Whether all blocks with a slot in use are in `masks`
+    loaded: bool,
+}
+
+impl INodeSlots {
+    /// Set the mask of `block`
+    fn set(&mut self, block: BlockId, mask: u16) {
+        self.masks.insert(block, mask);
+        if mask.count_ones() as usize == INODES_PER_BLOCK {
+            self.partial.remove(&block);
+        } else {
+            self.partial.insert(block);
+        }
+    }
+}
+
+/// Mask of the slots in use of a block of a packed inode table, by their types
+fn slot_mask(block: &[u8]) -> u16 {
+    block
+        .chunks(INODE_SIZE)
+        .enumerate()
+        .filter(|(_, slot)| u16::from_ne_bytes([slot[4], slot[5]]) != FileType::Invalid as u16)
+        .fold(0, |mask, (i, _)| mask | 1 << i)
+}
+
 /// filesystem for sfs
 ///
 /// ## 内部可变性
 /// 为了方便协调外部及INode对SFS的访问，并为日后并行化做准备，
 /// 将SFS设置为内部可变，即对外接口全部是&self，struct的全部field用RwLock包起来
 /// 这样其内部各field均可独立访问
+///
+/// ## Write ordering
+/// Assuming the device does writes in the order they are issued, a crash leaves
+/// the image with problems `fsck::check()` repairs, such as leaks, wrong link counts
+/// and entries left twice, but none of the files not being changed lost:
+/// - an inode is written after the free map, so blocks are in use on disk before referred to
+/// - a new inode is written before the entry referring to it
+/// - a dir grows on disk before its entries are moved, and shrinks after
+/// - a moved entry is added before the old one is removed, and a dir is taken to be
+///   in the one its '..' refers to
+/// - blocks freed are held back until a sync writes the inodes, then freed on disk
 pub struct SimpleFileSystem {
     /// on-disk superblock
     super_block: RwLock<Dirty<SuperBlock>>,
     /// blocks in use are mared 0
-    free_map: RwLock<Dirty<BitVec<Lsb0, u8>>>,
+    free_map: RwLock<Dirty<FreeMap>>,
     /// inode list
     inodes: RwLock<BTreeMap<INodeId, Weak<INodeImpl>>>,
-    /// device
+    /// device, journaling the metadata written if the image has a journal
     device: Arc<dyn Device>,
+    /// device to write file data to, past the journal if there is one
+    data_device: Arc<dyn Device>,
     /// Pointer to self, used by INodes
     self_ptr: Weak<SimpleFileSystem>,
     /// device inode
     device_inodes: RwLock<BTreeMap<usize, Arc<DeviceINode>>>,
+    /// Slots in use of the inode table, if it is packed
+    inode_slots: RwLock<INodeSlots>,
+    /// Held by renames exclusively, and by unlinks, which may lock a dir under the one
+    /// they change, so that no two operations each wait for a dir the other holds
+    rename_lock: RwLock<()>,
+    /// Depth of nested batches, and the removed inodes whose release is deferred until the
+    /// outermost ends
+    batch: RwLock<(usize, Vec<Arc<INodeImpl>>)>,
+    /// Zero blocks and slack space when they are freed
+    zero_on_free: AtomicBool,
+    /// Writes to files since the last sync, counted against the writeback policy
+    dirty: DirtyTracker,
+    /// Blocks freed since the last sync, discarded on the device after it
+    freed: RwLock<BTreeSet<BlockId>>,
+    /// Blocks freed since the last sync, which are only reused after it,
+    /// as the inodes on disk may still refer to them
+    unreleased: RwLock<Vec<BlockId>>,
+    /// Blocks referred to more than once, with their number of references, see `reflink`
+    shared: RwLock<Dirty<BTreeMap<BlockId, u32>>>,
+    /// Counters, shared with the device if it keeps any
+    stats: Arc<Stats>,
+    /// Clock for timestamps, which are left alone without it
+    time: Option<&'static dyn TimeProvider>,
 }
 
 impl SimpleFileSystem {
-    /// Load SFS from device
+    /// Load SFS from device without a clock, so timestamps are only set by `set_metadata`
     pub fn open(device: Arc<dyn Device>) -> vfs::Result<Arc<Self>> {
+        Self::_open(device, None)
+    }
+    /// Load SFS from device, with timestamps from `time`
+    pub fn open_with_time(
+        device: Arc<dyn Device>,
+        time: &'static dyn TimeProvider,
+    ) -> vfs::Result<Arc<Self>> {
+        Self::_open(device, Some(time))
+    }
+    fn _open(
+        device: Arc<dyn Device>,
+        time: Option<&'static dyn TimeProvider>,
+    ) -> vfs::Result<Arc<Self>> {
+        device.check_sector_size()?;
         let super_block = device.load_struct::<SuperBlock>(BLKN_SUPER)?;
         if !super_block.check() {
             return Err(FsError::WrongFs);
         }
-        let mut freemap_disk = vec![0u8; BLKSIZE * super_block.freemap_blocks as usize];
-        for i in 0..super_block.freemap_blocks as usize {
+        // a later layout
+        if super_block.version > VERSION || super_block.features() & !FEATURES != 0 {
+            return Err(FsError::NotSupported);
+        }
+        let (device, data_device) = journal(device, &super_block, false)?;
+        // as replayed from the journal
+        let super_block = device.load_struct::<SuperBlock>(BLKN_SUPER)?;
+        let mut freemap_disk = vec![0u8; BLKSIZE * super_block.freemap_len()];
+        for i in 0..super_block.freemap_len() {
             device.read_block(
-                BLKN_FREEMAP + i,
+                super_block.freemap_block(i),
                 0,
                 &mut freemap_disk[i * BLKSIZE..(i + 1) * BLKSIZE],
             )?;
         }
 
-        Ok(SimpleFileSystem {
+        let stats = device.stats().unwrap_or_default();
+        let sfs = SimpleFileSystem {
             super_block: RwLock::new(Dirty::new(super_block)),
-            free_map: RwLock::new(Dirty::new(BitVec::from(freemap_disk.as_slice()))),
+            free_map: RwLock::new(Dirty::new(FreeMap::new(BitVec::from(
+                freemap_disk.as_slice(),
+            )))),
             inodes: RwLock::new(BTreeMap::new()),
             device,
+            data_device,
             self_ptr: Weak::default(),
             device_inodes: RwLock::new(BTreeMap::new()),
+            inode_slots: RwLock::new(INodeSlots::default()),
+            rename_lock: RwLock::new(()),
+            batch: RwLock::new((0, Vec::new())),
+            zero_on_free: AtomicBool::new(false),
+            dirty: DirtyTracker::new(),
+            freed: RwLock::new(BTreeSet::new()),
+            unreleased: RwLock::new(Vec::new()),
+            shared: RwLock::new(Dirty::new(BTreeMap::new())),
+            stats,
+            time,
         }
-        .wrap())
+        .wrap();
+        sfs.load_refcounts()?;
+        Ok(sfs)
     }
-    /// Create a new SFS on blank disk
+    /// Create a new SFS on blank disk without a clock
     pub fn create(device: Arc<dyn Device>, space: usize) -> vfs::Result<Arc<Self>> {
+        Self::_create(device, space, 0, 0, None)
+    }
+    /// Create a new SFS on blank disk, with an inode table of one inode per `inode_ratio` bytes.
+    /// All inodes except root are allocated from the table, which is never used for data,
+    /// and `INODES_PER_BLOCK` of them are packed in each of its blocks.
+    /// There is no inode table if `inode_ratio` is 0.
+    pub fn create_with_inode_ratio(
+        device: Arc<dyn Device>,
+        space: usize,
+        inode_ratio: usize,
+    ) -> vfs::Result<Arc<Self>> {
+        Self::_create(device, space, inode_ratio, 0, None)
+    }
+    /// Create a new SFS on blank disk like `create_with_inode_ratio`,
+    /// with timestamps from `time`
+    pub fn create_with_time(
+        device: Arc<dyn Device>,
+        space: usize,
+        inode_ratio: usize,
+        time: &'static dyn TimeProvider,
+    ) -> vfs::Result<Arc<Self>> {
+        Self::_create(device, space, inode_ratio, 0, Some(time))
+    }
+    /// Create a new SFS on blank disk like `create_with_time`,
+    /// with a journal of metadata in its last `journal_blocks` blocks, or none if it is 0.
+    /// A crash then leaves the metadata as of the last sync.
+    pub fn create_with_journal(
+        device: Arc<dyn Device>,
+        space: usize,
+        inode_ratio: usize,
+        journal_blocks: usize,
+        time: &'static dyn TimeProvider,
+    ) -> vfs::Result<Arc<Self>> {
+        Self::_create(device, space, inode_ratio, journal_blocks, Some(time))
+    }
+    fn _create(
+        device: Arc<dyn Device>,
+        space: usize,
+        inode_ratio: usize,
+        journal_blocks: usize,
+        time: Option<&'static dyn TimeProvider>,
+    ) -> vfs::Result<Arc<Self>> {
+        device.check_sector_size()?;
         let blocks = (space + BLKSIZE - 1) / BLKSIZE;
         let freemap_blocks = (space + BLKBITS * BLKSIZE - 1) / BLKBITS / BLKSIZE;
         assert!(blocks >= 16, "space too small");
+        // the journal keeps u32 block ids
+        if blocks > MAX_BLOCKS64 || (journal_blocks != 0 && blocks > MAX_BLOCKS) {
+            return Err(FsError::InvalidParam);
+        }
+        let inodes = match inode_ratio {
+            0 => 0,
+            ratio => space / ratio,
+        };
+        // ids of packed inodes are u32 on disk too
+        let packed = inodes != 0 && blocks <= u32::max_value() as usize / INODES_PER_BLOCK;
+        let inode_blocks = match packed {
+            true => (inodes + INODES_PER_BLOCK - 1) / INODES_PER_BLOCK,
+            false => inodes,
+        };
+        let mut features = FEATURE_SORTED_DIRS
+            | FEATURE_INLINE_SYMLINKS
+            | FEATURE_SPARSE_FILES
+            | FEATURE_INODE_FLAGS;
+        if packed {
+            features |= FEATURE_PACKED_INODES;
+        }
+        // a journal has a header and at least a block,
+        // and the ids of inodes in the table are u32 on disk
+        if journal_blocks == 1
+            || inode_blocks + journal_blocks >= blocks - BLKN_FREEMAP - freemap_blocks
+            || (!packed && BLKN_FREEMAP + freemap_blocks + inode_blocks > MAX_BLOCKS)
+        {
+            return Err(FsError::InvalidParam);
+        }
 
-        let super_block = SuperBlock {
+        let mut super_block = SuperBlock {
             magic: MAGIC,
-            blocks: blocks as u32,
-            unused_blocks: (blocks - BLKN_FREEMAP - freemap_blocks) as u32,
+            blocks: 0,
+            unused_blocks: 0,
             info: Str32::from(DEFAULT_INFO),
             freemap_blocks: freemap_blocks as u32,
+            inode_blocks: inode_blocks as u32,
+            version: VERSION,
+            journal_blocks: journal_blocks as u32,
+            freemap_ext: 0,
+            freemap_ext_blocks: 0,
+            features,
+            reserved_blocks: 0,
+            blocks_hi: 0,
+            unused_blocks_hi: 0,
+            // root
+            used_inodes: 1,
+            refcount_inode: 0,
+            freemap_ext_hi: 0,
+            reserved_blocks_hi: 0,
         };
+        super_block.set_blocks(blocks);
+        super_block.set_reserved_blocks(blocks * DEFAULT_RESERVED_PERCENT / 100);
+        super_block.set_unused_blocks(blocks - BLKN_FREEMAP - freemap_blocks - journal_blocks);
         let free_map = {
             let mut bitset = BitVec::with_capacity(freemap_blocks * BLKBITS);
             bitset.extend(core::iter::repeat(false).take(freemap_blocks * BLKBITS));
-            for i in (BLKN_FREEMAP + freemap_blocks)..blocks {
+            for i in (BLKN_FREEMAP + freemap_blocks)..blocks - journal_blocks {
                 bitset.set(i, true);
             }
-            bitset
+            let mut free_map = FreeMap::new(bitset);
+            free_map.change_all();
+            free_map
         };
+        let (device, data_device) = journal(device, &super_block, true)?;
 
+        let stats = device.stats().unwrap_or_default();
         let sfs = SimpleFileSystem {
             super_block: RwLock::new(Dirty::new_dirty(super_block)),
             free_map: RwLock::new(Dirty::new_dirty(free_map)),
             inodes: RwLock::new(BTreeMap::new()),
             device,
+            data_device,
             self_ptr: Weak::default(),
             device_inodes: RwLock::new(BTreeMap::new()),
+            inode_slots: RwLock::new(INodeSlots::default()),
+            rename_lock: RwLock::new(()),
+            batch: RwLock::new((0, Vec::new())),
+            zero_on_free: AtomicBool::new(false),
+            dirty: DirtyTracker::new(),
+            freed: RwLock::new(BTreeSet::new()),
+            unreleased: RwLock::new(Vec::new()),
+            shared: RwLock::new(Dirty::new(BTreeMap::new())),
+            stats,
+            time,
         }
         .wrap();
 
         // Init root INode
-        let root = sfs._new_inode(BLKN_ROOT, Dirty::new_dirty(DiskINode::new_dir()));
+        let root = sfs._new_inode(BLKN_ROOT, sfs.new_disk_inode(DiskINode::new_dir()));
         root.init_direntry(BLKN_ROOT)?;
         root.nlinks_inc(); //for .
         root.nlinks_inc(); //for ..(root's parent is itself)
@@ -824,57 +1696,527 @@ impl SimpleFileSystem {
         unsafe { Arc::from_raw(ptr) }
     }
 
-    /// Allocate a block, return block id
-    fn alloc_block(&self) -> Option<usize> {
+    /// The blocks of inode table, if any
+    fn inode_table(&self) -> Option<Range<BlockId>> {
+        let super_block = self.super_block.read();
+        if super_block.inode_blocks == 0 {
+            return None;
+        }
+        let begin = BLKN_FREEMAP + super_block.freemap_blocks as usize;
+        Some(begin..begin + super_block.inode_blocks as usize)
+    }
+    /// Whether inodes are packed in the blocks of the inode table
+    fn packed_inodes(&self) -> bool {
+        let super_block = self.super_block.read();
+        super_block.packed_inodes() && super_block.inode_blocks != 0
+    }
+    /// The block of inode `id`, and the bytes of it taken by the inode
+    fn inode_location(&self, id: INodeId) -> (BlockId, Range<usize>) {
+        if id == BLKN_ROOT || !self.packed_inodes() {
+            return (id, 0..BLKSIZE);
+        }
+        let begin = id % INODES_PER_BLOCK * INODE_SIZE;
+        (id / INODES_PER_BLOCK, begin..begin + INODE_SIZE)
+    }
+    /// Read inode `id` from disk
+    /// Layout of the indirect blocks of files
+    fn block_map(&self) -> BlockMap {
+        self.super_block.read().block_map()
+    }
+    fn load_disk_inode(&self, id: INodeId) -> vfs::Result<DiskINode> {
+        let (block, range) = self.inode_location(id);
+        let mut disk_inode: DiskINode = unsafe { MaybeUninit::uninit().assume_init() };
+        self.device
+            .read_block(block, range.start, disk_inode.as_buf_mut())?;
+        Ok(disk_inode)
+    }
+    /// Mask of the slots in use of `block` of the packed inode table
+    fn load_slot_mask(&self, block: BlockId) -> vfs::Result<u16> {
+        let mut buf = [0u8; BLKSIZE];
+        self.device.read_block(block, 0, &mut buf)?;
+        Ok(slot_mask(&buf))
+    }
+    /// Load the masks of the blocks in use of the packed inode table, if not loaded
+    fn load_inode_slots(&self, slots: &mut INodeSlots) -> vfs::Result<()> {
+        if slots.loaded {
+            return Ok(());
+        }
+        let used: Vec<BlockId> = {
+            let free_map = self.free_map.read();
+            let table = self.inode_table().unwrap_or(0..0);
+            table.filter(|&block| !free_map[block]).collect()
+        };
+        for block in used {
+            if slots.masks.contains_key(&block) {
+                continue;
+            }
+            // a block with no slot in use is not taken for new inodes,
+            // since it may be freed but held back for the journal
+            let mask = self.load_slot_mask(block)?;
+            if mask != 0 {
+                slots.set(block, mask);
+            }
+        }
+        slots.loaded = true;
+        Ok(())
+    }
+    /// Get id of all inodes in use, in the order on disk.
+    /// Return `None` if there is no inode table.
+    pub fn inode_ids(&self) -> Option<Vec<INodeId>> {
+        let table = self.inode_table()?;
+        let mut ids = vec![BLKN_ROOT];
+        if self.packed_inodes() {
+            let mut slots = self.inode_slots.write();
+            self.load_inode_slots(&mut slots).ok()?;
+            for (&block, &mask) in slots.masks.iter() {
+                let used = (0..INODES_PER_BLOCK).filter(|&i| mask & 1 << i != 0);
+                ids.extend(used.map(|i| block * INODES_PER_BLOCK + i));
+            }
+            return Some(ids);
+        }
+        let free_map = self.free_map.read();
+        ids.extend(table.filter(|&id| !free_map[id]));
+        Some(ids)
+    }
+    /// Grow the image to `space` bytes, e.g. after its disk is enlarged.
+    /// If the freemap does not cover the blocks added, the rest of it is taken from them,
+    /// replacing any taken by an earlier resize, so they must be more than it.
+    ///
+    /// An image can not shrink, nor grow if it has a journal, which is at its end,
+    /// or past its freemap if it is made before `VERSION_FREEMAP_EXT`.
+    pub fn resize(&self, space: usize) -> vfs::Result<()> {
+        {
+            let mut free_map = self.free_map.write();
+            let mut super_block = self.super_block.write();
+            let old_blocks = super_block.blocks();
+            let blocks = (space + BLKSIZE - 1) / BLKSIZE;
+            if blocks < old_blocks || blocks > super_block.max_blocks() {
+                return Err(FsError::InvalidParam);
+            }
+            if super_block.journal_blocks() != 0 {
+                return Err(FsError::NotSupported);
+            }
+            let freemap_len = (blocks + BLKBITS - 1) / BLKBITS;
+            let ext = match freemap_len > super_block.freemap_len() {
+                true => {
+                    if super_block.version < VERSION_FREEMAP_EXT {
+                        return Err(FsError::NotSupported);
+                    }
+                    let ext_blocks = freemap_len - super_block.freemap_blocks as usize;
+                    if ext_blocks > blocks - old_blocks {
+                        return Err(FsError::NoDeviceSpace);
+                    }
+                    Some(old_blocks..old_blocks + ext_blocks)
+                }
+                false => None,
+            };
+
+            if ext.is_some() {
+                free_map.grow(freemap_len * BLKBITS);
+            }
+            for id in old_blocks..blocks {
+                free_map.set(id, true);
+            }
+            let mut unused = blocks - old_blocks;
+            if let Some(ext) = ext {
+                for id in ext.clone() {
+                    free_map.set(id, false);
+                }
+                let old_ext = super_block.freemap_ext();
+                unused = unused - ext.len() + old_ext.len();
+                for id in old_ext {
+                    free_map.set(id, true);
+                }
+                super_block.set_freemap_ext(ext);
+            }
+            let unused = super_block.unused_blocks() + unused;
+            super_block.set_blocks(blocks);
+            super_block.set_unused_blocks(unused);
+        }
+        self.sync_metadata()
+    }
+    /// Keep `blocks` unused blocks for privileged use: only files and dirs owned by root
+    /// may take them, so that a full image can still be cleaned up.
+    /// An image made before `VERSION_FEATURES` is not supported.
+    pub fn set_reserved_blocks(&self, blocks: usize) -> vfs::Result<()> {
+        {
+            let mut super_block = self.super_block.write();
+            if super_block.version < VERSION_FEATURES {
+                return Err(FsError::NotSupported);
+            }
+            if blocks > super_block.blocks() {
+                return Err(FsError::InvalidParam);
+            }
+            super_block.version = super_block.version.max(VERSION_RESERVED);
+            super_block.set_reserved_blocks(blocks);
+        }
+        self.sync_metadata()
+    }
+    /// Number of unused blocks kept for privileged use, see `set_reserved_blocks()`
+    pub fn reserved_blocks(&self) -> usize {
+        self.super_block.read().reserved_blocks()
+    }
+    /// Look up names case-insensitively as folded by `fold`, still keeping them as created,
+    /// or case-sensitively if it is `None`, see `FEATURE_CASE_FOLD`.
+    /// Fails with `EntryExist`, changing nothing, if two names in a dir would be the same.
+    /// An image made before `VERSION_FEATURES` is not supported.
+    ///
+    /// No inode may be open. It is not atomic, so a crash meanwhile can leave dirs unsorted.
+    pub fn set_case_fold(&self, fold: Option<CaseFold>) -> vfs::Result<()> {
+        {
+            let super_block = self.super_block.read();
+            if super_block.version < VERSION_FEATURES {
+                return Err(FsError::NotSupported);
+            }
+            if super_block.case_fold() == fold {
+                return Ok(());
+            }
+        }
+        self.sync()?;
+        self.flush_weak_inodes();
+        if !self.inodes.read().is_empty() {
+            return Err(FsError::Busy);
+        }
+
+        // all dirs, checked before any is changed
+        let mut dirs = vec![BLKN_ROOT];
+        let mut i = 0;
+        while i < dirs.len() {
+            let dir = self.get_inode(dirs[i]);
+            let mut names = BTreeSet::new();
+            for index in 2..dir.disk_inode.read().size() / DIRENT_SIZE {
+                let entry = dir.read_direntry(index)?;
+                if !names.insert(fold_name(fold, entry.name.as_ref()).into_owned()) {
+                    return Err(FsError::EntryExist);
+                }
+                let id = entry.id as INodeId;
+                if self.get_inode(id).disk_inode.read().type_ == FileType::Dir {
+                    dirs.push(id);
+                }
+            }
+            i += 1;
+        }
+        {
+            let mut super_block = self.super_block.write();
+            super_block.features &= !(FEATURE_CASE_FOLD | FEATURE_CASE_FOLD_UTF8);
+            super_block.features |= fold.map_or(0, CaseFold::features);
+        }
+        if self.super_block.read().sorted_dirs() {
+            for &dir in dirs.iter() {
+                self.get_inode(dir).sort_direntries()?;
+            }
+        }
+        self.sync()
+    }
+    /// Pack the inodes of an image made without `FEATURE_PACKED_INODES` in its inode table,
+    /// `INODES_PER_BLOCK` in a block, and free the blocks of the table left for more inodes.
+    ///
+    /// No inode may be open. It is not atomic, so a crash meanwhile can leave the image broken.
+    pub fn pack_inodes(&self) -> vfs::Result<()> {
+        let table = self.inode_table().ok_or(FsError::NotSupported)?;
+        {
+            let super_block = self.super_block.read();
+            if super_block.version < VERSION_FEATURES
+                || table.end > u32::max_value() as usize / INODES_PER_BLOCK
+            {
+                return Err(FsError::NotSupported);
+            }
+            if super_block.packed_inodes() {
+                return Ok(());
+            }
+        }
+        self.sync()?;
+        self.flush_weak_inodes();
+        if !self.inodes.read().is_empty() {
+            return Err(FsError::Busy);
+        }
+
+        let old_ids = self.inode_ids().unwrap();
+        let new_ids: BTreeMap<INodeId, INodeId> = old_ids
+            .iter()
+            .skip(1)
+            .enumerate()
+            .map(|(i, &id)| (id, table.start * INODES_PER_BLOCK + i))
+            .chain(Some((BLKN_ROOT, BLKN_ROOT)))
+            .collect();
+        // refer to the new ids in the entries of dirs
+        for &id in old_ids.iter() {
+            let inode = self.get_inode(id);
+            if inode.disk_inode.read().type_ != FileType::Dir {
+                continue;
+            }
+            let count = inode.disk_inode.read().size() / DIRENT_SIZE;
+            for i in 0..count {
+                let mut entry = inode.read_direntry(i)?;
+                entry.id = new_ids[&(entry.id as usize)] as u32;
+                inode.write_direntry(i, &entry)?;
+            }
+        }
+        self.sync()?;
+        self.inodes.write().clear();
+
+        let disk_inodes = old_ids[1..]
+            .iter()
+            .map(|&id| self.device.load_struct::<DiskINode>(id))
+            .collect::<vfs::Result<Vec<_>>>()?;
+        let packed_blocks = (disk_inodes.len() + INODES_PER_BLOCK - 1) / INODES_PER_BLOCK;
+        for (i, chunk) in disk_inodes.chunks(INODES_PER_BLOCK).enumerate() {
+            let mut buf = [0u8; BLKSIZE];
+            for (slot, disk_inode) in chunk.iter().enumerate() {
+                let begin = slot * INODE_SIZE;
+                let data = disk_inode.as_buf();
+                buf[begin..begin + data.len()].copy_from_slice(data);
+            }
+            self.device.write_block(table.start + i, 0, &buf)?;
+        }
+        {
+            let mut free_map = self.free_map.write();
+            let mut super_block = self.super_block.write();
+            for block in table.clone() {
+                free_map.set(block, block >= table.start + packed_blocks);
+            }
+            let unused = super_block.unused_blocks() + disk_inodes.len() - packed_blocks;
+            super_block.set_unused_blocks(unused);
+            super_block.features |= FEATURE_PACKED_INODES;
+            if let Some(id) = super_block.refcount_inode() {
+                super_block.refcount_inode = new_ids[&id] as u32;
+            }
+        }
+        *self.inode_slots.write() = INodeSlots::default();
+        self.sync_metadata()
+    }
+    /// Allocate a block for data at `goal` or the first free after it, return block id
+    fn alloc_block(&self, goal: BlockId, reserve: bool) -> Option<usize> {
+        let begin = match self.inode_table() {
+            Some(table) => table.end,
+            None => 0,
+        };
+        let end = self.free_map.read().len();
+        self.alloc_block_in(begin..end, goal, reserve, false)
+    }
+    /// Allocate an inode, return its id
+    fn alloc_inode(&self, reserve: bool) -> Option<INodeId> {
+        let table = match self.inode_table() {
+            Some(table) => table,
+            None => {
+                // ids of inodes in entries are u32
+                let end = self.free_map.read().len().min(MAX_BLOCKS);
+                return self.alloc_block_in(0..end, 0, reserve, true);
+            }
+        };
+        if !self.packed_inodes() {
+            return self.alloc_block_in(table, 0, reserve, true);
+        }
+        let mut slots = self.inode_slots.write();
+        if slots.partial.is_empty() {
+            self.load_inode_slots(&mut slots).ok()?;
+        }
+        let block = match slots.partial.iter().next() {
+            Some(&block) => block,
+            None => {
+                let block = self.alloc_block_in(table, 0, reserve, false)?;
+                self.device.write_block(block, 0, &ZEROS).ok()?;
+                block
+            }
+        };
+        // the slot is written when the inode is synced
+        let mask = slots.masks.get(&block).copied().unwrap_or(0);
+        let slot = (!mask).trailing_zeros() as usize;
+        slots.set(block, mask | 1 << slot);
+        self.super_block.write().count_inode(true);
+        Some(block * INODES_PER_BLOCK + slot)
+    }
+    /// Free inode `id`, and the block of it if no other inode is in the block
+    fn free_inode(&self, id: INodeId) {
+        let (block, range) = self.inode_location(id);
+        if range.len() == BLKSIZE {
+            self.super_block.write().count_inode(false);
+            return self.free_block(block);
+        }
+        let mut slots = self.inode_slots.write();
+        self.super_block.write().count_inode(false);
+        let mask = match slots.masks.get(&block) {
+            Some(&mask) => mask,
+            None => self.load_slot_mask(block).unwrap(),
+        };
+        self.device
+            .write_block(block, range.start, &ZEROS[..INODE_SIZE])
+            .unwrap();
+        let mask = mask & !(1 << (range.start / INODE_SIZE));
+        if mask != 0 {
+            slots.set(block, mask);
+            return;
+        }
+        slots.masks.remove(&block);
+        slots.partial.remove(&block);
+        drop(slots);
+        self.free_block(block);
+    }
+    /// Allocate a block in `range`, at `goal` or the first free after it, return block id.
+    /// The reserved blocks are only taken if `reserve`, and it is counted as an inode
+    /// in use if `inode`.
+    fn alloc_block_in(
+        &self,
+        range: Range<BlockId>,
+        goal: BlockId,
+        reserve: bool,
+        inode: bool,
+    ) -> Option<usize> {
         let mut free_map = self.free_map.write();
-        let id = free_map.alloc();
+        let id = free_map.alloc_in(range, goal);
         if let Some(block_id) = id {
             let mut super_block = self.super_block.write();
-            if super_block.unused_blocks == 0 {
+            let reserved = match reserve {
+                true => 0,
+                false => super_block.reserved_blocks(),
+            };
+            let unused = super_block.unused_blocks();
+            if unused <= reserved {
                 free_map.set(block_id, true);
                 return None;
             }
-            super_block.unused_blocks -= 1; // will not underflow
+            super_block.set_unused_blocks(unused - 1); // will not underflow
+            if inode {
+                super_block.count_inode(true);
+            }
+            self.freed.write().remove(&block_id);
+            self.stats.update(|s| s.blocks_allocated += 1);
             trace!("alloc block {:#x}", block_id);
-        } else {
-            let super_block = self.super_block.read();
-            panic!("{:?}", super_block)
         }
         id
     }
-    /// Free a block
-    fn free_block(&self, block_id: usize) {
+    /// Allocate the first `len` free blocks for data next to each other, not the reserved ones
+    fn alloc_extent(&self, len: usize) -> Option<Range<BlockId>> {
+        let begin = match self.inode_table() {
+            Some(table) => table.end,
+            None => 0,
+        };
         let mut free_map = self.free_map.write();
-        assert!(!free_map[block_id]);
-        free_map.set(block_id, true);
-        self.super_block.write().unused_blocks += 1;
+        let end = free_map.len();
+        let mut super_block = self.super_block.write();
+        let unused = super_block.unused_blocks();
+        if unused < len + super_block.reserved_blocks() {
+            return None;
+        }
+        let extent = free_map.alloc_extent(begin..end, len)?;
+        super_block.set_unused_blocks(unused - len);
+        let mut freed = self.freed.write();
+        for id in extent.clone() {
+            freed.remove(&id);
+        }
+        self.stats.update(|s| s.blocks_allocated += len as u64);
+        trace!("alloc extent {:#x?}", extent);
+        Some(extent)
+    }
+    /// Number of unused blocks which may be allocated, with the reserved ones if `reserve`
+    fn available_blocks(&self, reserve: bool) -> usize {
+        let super_block = self.super_block.read();
+        match reserve {
+            true => super_block.unused_blocks(),
+            false => super_block
+                .unused_blocks()
+                .saturating_sub(super_block.reserved_blocks()),
+        }
+    }
+    /// Free a block, which is held back until the next sync, see `release_blocks()`.
+    /// A shared block only loses a reference, until the last, see `reflink`.
+    fn free_block(&self, block_id: usize) {
+        assert!(!self.free_map.read()[block_id]);
+        if self.unshare_block(block_id) {
+            return;
+        }
+        self.unreleased.write().push(block_id);
+        self.freed.write().insert(block_id);
+        self.stats.update(|s| s.blocks_freed += 1);
         trace!("free block {:#x}", block_id);
     }
 
-    pub fn new_device_inode(&self, device_inode_id: usize, device_inode: Arc<DeviceINode>) {
-        self.device_inodes
-            .write()
-            .insert(device_inode_id, device_inode);
+    /// Enable or disable zeroing of blocks and slack space when they are freed
+    pub fn set_zero_on_free(&self, enable: bool) {
+        self.zero_on_free.store(enable, atomic::Ordering::Relaxed);
+    }
+    fn zero_on_free(&self) -> bool {
+        self.zero_on_free.load(atomic::Ordering::Relaxed)
+    }
+    /// Write back dirty inodes and blocks by `policy` rather than only when synced,
+    /// `None` for the latter, which is the default
+    pub fn set_writeback(&self, policy: Option<WritebackPolicy>) {
+        self.dirty.set_policy(policy);
+    }
+    /// Count a write of `len` bytes at `offset` of a file,
+    /// and sync if it passes a limit of the writeback policy
+    fn count_write(&self, offset: usize, len: usize) -> vfs::Result<()> {
+        let blocks = (offset + len + BLKSIZE - 1) / BLKSIZE - offset / BLKSIZE;
+        if self.dirty.record(blocks, len) {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Metadata of inode `id`, kept in `disk_inode`
+    fn disk_metadata(&self, id: INodeId, disk_inode: &DiskINode) -> vfs::Metadata {
+        vfs::Metadata {
+            dev: 0,
+            inode: id,
+            size: match disk_inode.type_ {
+                FileType::File | FileType::SymLink => disk_inode.size(),
+                FileType::Dir => disk_inode.size(),
+                FileType::CharDevice => 0,
+                FileType::BlockDevice => 0,
+                FileType::NamedPipe | FileType::Socket => 0,
+                _ => panic!("Unknown file type"),
+            },
+            mode: match disk_inode.mode & MODE_SET {
+                0 => 0o777,
+                _ => disk_inode.mode & !MODE_SET,
+            },
+            type_: vfs::FileType::from(disk_inode.type_.clone()),
+            blocks: match self.super_block.read().sparse_files() {
+                true => disk_inode.data_blocks as usize,
+                false => disk_inode.blocks as usize,
+            },
+            atime: disk_inode.atime,
+            mtime: disk_inode.mtime,
+            ctime: disk_inode.ctime,
+            nlinks: disk_inode.nlinks as usize,
+            uid: disk_inode.uid as usize,
+            gid: disk_inode.gid as usize,
+            blk_size: BLKSIZE,
+            rdev: match disk_inode.type_ {
+                FileType::CharDevice | FileType::BlockDevice => disk_inode.rdev,
+                _ => 0,
+            },
+        }
+    }
+    /// Serve the device nodes of device number `rdev` by `device_inode`
+    pub fn new_device_inode(&self, rdev: usize, device_inode: Arc<DeviceINode>) {
+        self.device_inodes.write().insert(rdev, device_inode);
     }
 
     /// Create a new INode struct, then insert it to self.inodes
     /// Private used for load or create INode
     fn _new_inode(&self, id: INodeId, disk_inode: Dirty<DiskINode>) -> Arc<INodeImpl> {
-        let device_inode_id = disk_inode.device_inode_id;
-        let inode = Arc::new(INodeImpl {
+        let inode = self.make_inode(id, disk_inode);
+        self.inodes.write().insert(id, Arc::downgrade(&inode));
+        inode
+    }
+    fn make_inode(&self, id: INodeId, disk_inode: Dirty<DiskINode>) -> Arc<INodeImpl> {
+        let rdev = disk_inode.rdev;
+        Arc::new(INodeImpl {
             id,
             disk_inode: RwLock::new(disk_inode),
             fs: self.self_ptr.upgrade().unwrap(),
-            device_inode_id,
-        });
-        self.inodes.write().insert(id, Arc::downgrade(&inode));
-        inode
+            rdev,
+            alloc_hint: AtomicUsize::new(0),
+            lock: RwLock::new(()),
+        })
     }
 
     /// Get inode by id. Load if not in memory.
     /// ** Must ensure it's a valid INode **
     fn get_inode(&self, id: INodeId) -> Arc<INodeImpl> {
-        assert!(!self.free_map.read()[id]);
+        let block = self.inode_location(id).0;
+        assert!(!self.free_map.read()[block]);
 
         // In the BTreeSet and not weak.
         if let Some(inode) = self.inodes.read().get(&id) {
@@ -883,36 +2225,124 @@ impl SimpleFileSystem {
             }
         }
         // Load if not in set, or is weak ref.
-        let disk_inode = Dirty::new(self.device.load_struct::<DiskINode>(id).unwrap());
-        self._new_inode(id, disk_inode)
+        let mut disk_inode = self.load_disk_inode(id).unwrap();
+        if !self.super_block.read().large_file() {
+            // not kept in images of earlier versions
+            disk_inode.size_hi = 0;
+            disk_inode.tri_indirect = 0;
+        }
+        if !self.super_block.read().block_ids64() {
+            disk_inode.block_ids_hi = [0; NDIRECT + 3];
+        }
+        let mut inodes = self.inodes.write();
+        // loaded by another thread meanwhile
+        if let Some(inode) = inodes.get(&id).and_then(Weak::upgrade) {
+            return inode;
+        }
+        let inode = self.make_inode(id, Dirty::new(disk_inode));
+        inodes.insert(id, Arc::downgrade(&inode));
+        inode
+    }
+    /// Current time, if there is a clock
+    fn now(&self) -> Option<vfs::Timespec> {
+        self.time.map(|time| time.current_time())
+    }
+    /// A new disk inode created now
+    fn new_disk_inode(&self, mut disk_inode: DiskINode) -> Dirty<DiskINode> {
+        if let Some(now) = self.now() {
+            disk_inode.atime = now;
+            disk_inode.mtime = now;
+            disk_inode.ctime = now;
+        }
+        Dirty::new_dirty(disk_inode)
     }
     /// Create a new INode file
-    fn new_inode_file(&self) -> vfs::Result<Arc<INodeImpl>> {
-        let id = self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
-        let disk_inode = Dirty::new_dirty(DiskINode::new_file());
+    fn new_inode_file(&self, reserve: bool) -> vfs::Result<Arc<INodeImpl>> {
+        let id = self.alloc_inode(reserve).ok_or(FsError::NoDeviceSpace)?;
+        let disk_inode = self.new_disk_inode(DiskINode::new_file());
         Ok(self._new_inode(id, disk_inode))
     }
     /// Create a new INode symlink
-    fn new_inode_symlink(&self) -> vfs::Result<Arc<INodeImpl>> {
-        let id = self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
-        let disk_inode = Dirty::new_dirty(DiskINode::new_symlink());
+    fn new_inode_symlink(&self, reserve: bool) -> vfs::Result<Arc<INodeImpl>> {
+        let id = self.alloc_inode(reserve).ok_or(FsError::NoDeviceSpace)?;
+        let disk_inode = self.new_disk_inode(DiskINode::new_symlink());
         Ok(self._new_inode(id, disk_inode))
     }
     /// Create a new INode dir
-    fn new_inode_dir(&self, parent: INodeId) -> vfs::Result<Arc<INodeImpl>> {
-        let id = self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
-        let disk_inode = Dirty::new_dirty(DiskINode::new_dir());
+    fn new_inode_dir(&self, parent: INodeId, reserve: bool) -> vfs::Result<Arc<INodeImpl>> {
+        let id = self.alloc_inode(reserve).ok_or(FsError::NoDeviceSpace)?;
+        let disk_inode = self.new_disk_inode(DiskINode::new_dir());
         let inode = self._new_inode(id, disk_inode);
         inode.init_direntry(parent)?;
         Ok(inode)
     }
-    /// Create a new INode chardevice
-    pub fn new_inode_chardevice(&self, device_inode_id: usize) -> vfs::Result<Arc<INodeImpl>> {
-        let id = self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
-        let disk_inode = Dirty::new_dirty(DiskINode::new_chardevice(device_inode_id));
+    /// Create a new INode fifo
+    fn new_inode_fifo(&self, reserve: bool) -> vfs::Result<Arc<INodeImpl>> {
+        let id = self.alloc_inode(reserve).ok_or(FsError::NoDeviceSpace)?;
+        let disk_inode = self.new_disk_inode(DiskINode::new_fifo());
+        Ok(self._new_inode(id, disk_inode))
+    }
+    /// Create a new INode socket
+    fn new_inode_socket(&self, reserve: bool) -> vfs::Result<Arc<INodeImpl>> {
+        let id = self.alloc_inode(reserve).ok_or(FsError::NoDeviceSpace)?;
+        let disk_inode = self.new_disk_inode(DiskINode::new_socket());
+        Ok(self._new_inode(id, disk_inode))
+    }
+    /// Create a new INode char/block device of device number `rdev`
+    pub fn new_inode_device(
+        &self,
+        type_: FileType,
+        rdev: usize,
+        reserve: bool,
+    ) -> vfs::Result<Arc<INodeImpl>> {
+        let id = self.alloc_inode(reserve).ok_or(FsError::NoDeviceSpace)?;
+        let disk_inode = self.new_disk_inode(DiskINode::new_device(type_, rdev));
         let new_inode = self._new_inode(id, disk_inode);
         Ok(new_inode)
     }
+    /// Free the blocks held back since the last sync.
+    /// Only called after the inodes are written, when nothing on disk refers to them.
+    fn release_blocks(&self) -> vfs::Result<()> {
+        let blocks = core::mem::take(&mut *self.unreleased.write());
+        if blocks.is_empty() {
+            return Ok(());
+        }
+        if self.zero_on_free() {
+            for &block_id in blocks.iter() {
+                self.device.write_block(block_id, 0, &ZEROS)?;
+            }
+        }
+        let mut free_map = self.free_map.write();
+        for &block_id in blocks.iter() {
+            free_map.set(block_id, true);
+        }
+        let mut super_block = self.super_block.write();
+        let unused = super_block.unused_blocks() + blocks.len();
+        super_block.set_unused_blocks(unused);
+        Ok(())
+    }
+    /// Write back super block and free map if dirty
+    fn write_super_and_freemap(&self) -> vfs::Result<()> {
+        // in the order the allocator takes them
+        let mut free_map = self.free_map.write();
+        let mut super_block = self.super_block.write();
+        // the freemap first, so that the super block never refers to a part of it not written
+        if free_map.dirty() {
+            for i in free_map.take_changed() {
+                self.device.write_at(
+                    BLKSIZE * super_block.freemap_block(i),
+                    &free_map.as_buf()[i * BLKSIZE..(i + 1) * BLKSIZE],
+                )?;
+            }
+            free_map.sync();
+        }
+        if super_block.dirty() {
+            self.device
+                .write_at(BLKSIZE * BLKN_SUPER, super_block.as_buf())?;
+            super_block.sync();
+        }
+        Ok(())
+    }
     fn flush_weak_inodes(&self) {
         let mut inodes = self.inodes.write();
         let remove_ids: Vec<_> = inodes
@@ -924,28 +2354,32 @@ impl SimpleFileSystem {
             inodes.remove(&id);
         }
     }
+    /// Discard blocks freed since the last sync.
+    /// Only called after a sync, when nothing on disk refers to them.
+    fn trim_freed(&self) -> vfs::Result<()> {
+        let freed = core::mem::replace(&mut *self.freed.write(), BTreeSet::new());
+        let mut iter = freed.into_iter().peekable();
+        while let Some(begin) = iter.next() {
+            let mut end = begin + 1;
+            while iter.peek() == Some(&end) {
+                iter.next();
+                end += 1;
+            }
+            self.device.trim(begin * BLKSIZE..end * BLKSIZE)?;
+        }
+        Ok(())
+    }
 }
 
 impl vfs::FileSystem for SimpleFileSystem {
     /// Write back super block if dirty
     fn sync(&self) -> vfs::Result<()> {
-        let mut super_block = self.super_block.write();
-        if super_block.dirty() {
-            self.device
-                .write_at(BLKSIZE * BLKN_SUPER, super_block.as_buf())?;
-            super_block.sync();
-        }
-        let mut free_map = self.free_map.write();
-        if free_map.dirty() {
-            let data = free_map.as_buf();
-            for i in 0..super_block.freemap_blocks as usize {
-                self.device.write_at(
-                    BLKSIZE * (BLKN_FREEMAP + i),
-                    &data[i * BLKSIZE..(i + 1) * BLKSIZE],
-                )?;
-            }
-            free_map.sync();
-        }
+        self.dirty.clear();
+        // taking blocks for itself, so before they are written
+        self.write_refcounts()?;
+        // blocks taken are in use on disk before the inodes refer to them,
+        // and blocks freed are free on disk after the inodes no longer do
+        self.write_super_and_freemap()?;
         self.flush_weak_inodes();
         for inode in self.inodes.read().values() {
             if let Some(inode) = inode.upgrade() {
@@ -953,6 +2387,11 @@ impl vfs::FileSystem for SimpleFileSystem {
             }
         }
         self.device.sync()?;
+        self.release_blocks()?;
+        self.write_super_and_freemap()?;
+        self.device.sync()?;
+        self.trim_freed()?;
+        self.device.flush()?;
         Ok(())
     }
 
@@ -963,50 +2402,157 @@ impl vfs::FileSystem for SimpleFileSystem {
         // return root;
     }
 
+    /// Free blocks and inodes are those of the last allocation or free, where blocks freed
+    /// are free before they are reused after the next sync, but not available.
     fn info(&self) -> vfs::FsInfo {
+        let unreleased = self.unreleased.read().len();
+        let used_inodes = self.super_block.read().used_inodes();
+        let (files, ffree) = match (self.inode_table(), used_inodes) {
+            (Some(table), Some(used)) => {
+                let files = match self.packed_inodes() {
+                    true => table.len() * INODES_PER_BLOCK + 1,
+                    false => table.len() + 1,
+                };
+                (files, files.saturating_sub(used))
+            }
+            // each free block may take an inode
+            (None, Some(used)) => {
+                let free = self.super_block.read().unused_blocks() + unreleased;
+                (used + free, free)
+            }
+            // counted, in an image made before `VERSION_INODE_COUNT`
+            (Some(table), None) if self.packed_inodes() => {
+                let mut slots = self.inode_slots.write();
+                self.load_inode_slots(&mut slots).ok();
+                let used: u32 = slots.masks.values().map(|mask| mask.count_ones()).sum();
+                let files = table.len() * INODES_PER_BLOCK;
+                (files + 1, files - used as usize)
+            }
+            (Some(table), None) => {
+                let free_map = self.free_map.read();
+                let free = table.clone().filter(|&id| free_map[id]).count();
+                (table.len() + 1, free)
+            }
+            (None, None) => {
+                let sb = self.super_block.read();
+                (sb.blocks(), sb.unused_blocks()) // inaccurate
+            }
+        };
         let sb = self.super_block.read();
         vfs::FsInfo {
             bsize: BLKSIZE,
             frsize: BLKSIZE,
-            blocks: sb.blocks as usize,
-            bfree: sb.unused_blocks as usize,
-            bavail: sb.unused_blocks as usize,
-            files: sb.blocks as usize,        // inaccurate
-            ffree: sb.unused_blocks as usize, // inaccurate
+            blocks: sb.blocks(),
+            bfree: sb.unused_blocks() + unreleased,
+            bavail: sb.unused_blocks().saturating_sub(sb.reserved_blocks()),
+            files,
+            ffree,
             namemax: MAX_FNAME_LEN,
         }
     }
-}
 
-impl Drop for SimpleFileSystem {
-    /// Auto sync when drop
-    fn drop(&mut self) {
+    fn sync_metadata(&self) -> vfs::Result<()> {
+        self.write_super_and_freemap()?;
+        self.device.sync()?;
+        Ok(())
+    }
+
+    fn snapshot_stats(&self) -> vfs::FsStats {
+        self.stats.snapshot()
+    }
+
+    fn capabilities(&self) -> vfs::FsCapabilities {
+        let super_block = self.super_block.read();
+        let mut features = vfs::FsFeatures::SYMLINK | vfs::FsFeatures::HARDLINK;
+        if super_block.case_fold().is_none() {
+            features |= vfs::FsFeatures::CASE_SENSITIVE;
+        }
+        if super_block.sparse_files() {
+            features |= vfs::FsFeatures::SPARSE;
+        }
+        vfs::FsCapabilities {
+            features,
+            namemax: MAX_FNAME_LEN,
+        }
+    }
+
+    fn begin_batch(&self) {
+        self.batch.write().0 += 1;
+    }
+
+    /// Free all inodes removed in the batch, then sync once, unless it is nested in another
+    fn end_batch(&self) -> vfs::Result<()> {
+        let removed = {
+            let mut batch = self.batch.write();
+            batch.0 = batch.0.saturating_sub(1);
+            if batch.0 > 0 {
+                return Ok(());
+            }
+            core::mem::take(&mut batch.1)
+        };
+        drop(removed);
         self.sync()
-            .expect("Failed to sync when dropping the SimpleFileSystem");
     }
-}
 
-trait BitsetAlloc {
-    fn alloc(&mut self) -> Option<usize>;
+    fn wipe_free_space(&self) -> vfs::Result<usize> {
+        // write back freed inodes first, so their blocks are wiped too
+        self.sync()?;
+        let mut count = 0;
+        {
+            let free_map = self.free_map.read();
+            let super_block = self.super_block.read();
+            let begin = BLKN_FREEMAP + super_block.freemap_blocks as usize;
+            for id in begin..super_block.blocks() {
+                if free_map[id] {
+                    self.data_device.write_block(id, 0, &ZEROS)?;
+                    count += 1;
+                }
+            }
+        }
+        self.get_inode(BLKN_ROOT)._wipe_slack()?;
+        self.device.sync()?;
+        Ok(count)
+    }
 }
 
-impl BitsetAlloc for BitVec<Lsb0, u8> {
-    fn alloc(&mut self) -> Option<usize> {
-        // TODO: more efficient
-        let id = (0..self.len()).find(|&i| self[i]);
-        if let Some(id) = id {
-            self.set(id, false);
-        }
-        id
+/// Devices for metadata and for file data, journaling the metadata if `super_block` has a journal.
+/// The journal is replayed, or cleared on a new image.
+fn journal(
+    device: Arc<dyn Device>,
+    super_block: &SuperBlock,
+    create: bool,
+) -> vfs::Result<(Arc<dyn Device>, Arc<dyn Device>)> {
+    let config = JournalConfig {
+        journal_blocks: super_block.journal_blocks(),
+        // only on sync, unless the journal is full
+        commit_interval: usize::max_value(),
+    };
+    if config.journal_blocks == 0 {
+        return Ok((device.clone(), device));
     }
+    let size = super_block.blocks() * BLKSIZE;
+    let journaled = Arc::new(match create {
+        true => JournaledDevice::create(device, size, BLKSIZE_LOG2, config)?,
+        false => JournaledDevice::open(device, size, BLKSIZE_LOG2, config)?,
+    });
+    let data_device = Arc::new(WriteThrough(journaled.clone()));
+    Ok((journaled, data_device))
 }
 
-impl AsBuf for BitVec<Lsb0, u8> {
-    fn as_buf(&self) -> &[u8] {
-        self.as_ref()
+impl Flusher for SimpleFileSystem {
+    fn needs_flush(&self, now: vfs::Timespec) -> bool {
+        self.dirty.needs_flush(now)
+    }
+    fn flush(&self) -> vfs::Result<()> {
+        self.sync()
     }
-    fn as_buf_mut(&mut self) -> &mut [u8] {
-        self.as_mut()
+}
+
+impl Drop for SimpleFileSystem {
+    /// Auto sync when drop
+    fn drop(&mut self) {
+        self.sync()
+            .expect("Failed to sync when dropping the SimpleFileSystem");
     }
 }
 
@@ -1020,6 +2566,8 @@ impl From<FileType> for vfs::FileType {
             FileType::Dir => vfs::FileType::Dir,
             FileType::CharDevice => vfs::FileType::CharDevice,
             FileType::BlockDevice => vfs::FileType::BlockDevice,
+            FileType::NamedPipe => vfs::FileType::NamedPipe,
+            FileType::Socket => vfs::FileType::Socket,
             _ => panic!("unknown file type"),
         }
     }
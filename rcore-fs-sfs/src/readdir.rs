@@ -0,0 +1,103 @@
+//! Batched readdir of SFS, like readdirplus
+//!
+//! The entries asked for are read from the dir in one pass. The inodes they refer to
+//! which are not in memory are then read in the order of their blocks, each run of
+//! blocks next to each other at once, rather than loaded one by one.
+use crate::*;
+
+/// Max blocks of inodes read at once
+const RUN_BLOCKS: usize = 16;
+
+impl INodeImpl {
+    /// Up to `max` entries of the dir from `begin`, with the metadata of their inodes
+    pub(crate) fn entries_with_metadata(
+        &self,
+        begin: usize,
+        max: usize,
+    ) -> vfs::Result<Vec<(Metadata, String)>> {
+        let _lock = self.lock.read();
+        let size = {
+            let disk_inode = self.disk_inode.read();
+            if disk_inode.type_ != FileType::Dir {
+                return Err(FsError::NotDir);
+            }
+            disk_inode.size()
+        };
+        let end = (size / DIRENT_SIZE).min(begin.saturating_add(max));
+        if begin >= end {
+            return Ok(Vec::new());
+        }
+        let mut buf = vec![0u8; (end - begin) * DIRENT_SIZE];
+        self._read_at(begin * DIRENT_SIZE, &mut buf)?;
+        let entries: Vec<(INodeId, String)> = buf
+            .chunks(DIRENT_SIZE)
+            .map(|chunk| {
+                let mut entry: DiskEntry = unsafe { MaybeUninit::uninit().assume_init() };
+                entry.as_buf_mut().copy_from_slice(chunk);
+                (entry.id as INodeId, String::from(entry.name.as_ref()))
+            })
+            .collect();
+        let ids: Vec<INodeId> = entries.iter().map(|&(id, _)| id).collect();
+        let metadata = self.fs.metadata_of(&ids)?;
+        Ok(entries
+            .into_iter()
+            .map(|(id, name)| (metadata[&id].clone(), name))
+            .collect())
+    }
+}
+
+impl SimpleFileSystem {
+    /// Metadata of inodes `ids`, from memory if they are loaded, see the module docs
+    fn metadata_of(&self, ids: &[INodeId]) -> vfs::Result<BTreeMap<INodeId, Metadata>> {
+        let mut metadata = BTreeMap::new();
+        let mut loaded = Vec::new();
+        // ids of the inodes to read, by their blocks
+        let mut blocks: BTreeMap<BlockId, Vec<INodeId>> = BTreeMap::new();
+        {
+            let inodes = self.inodes.read();
+            for &id in ids {
+                match inodes.get(&id).and_then(Weak::upgrade) {
+                    Some(inode) => loaded.push(inode),
+                    None => blocks
+                        .entry(self.inode_location(id).0)
+                        .or_insert_with(Vec::new)
+                        .push(id),
+                }
+            }
+        }
+        for inode in loaded {
+            metadata.insert(inode.id, inode.metadata()?);
+        }
+
+        let blocks: Vec<(BlockId, Vec<INodeId>)> = blocks.into_iter().collect();
+        let mut buf = vec![0u8; RUN_BLOCKS * BLKSIZE];
+        let mut i = 0;
+        while i < blocks.len() {
+            let begin = blocks[i].0;
+            let mut len = 1;
+            while i + len < blocks.len() && len < RUN_BLOCKS && blocks[i + len].0 == begin + len {
+                len += 1;
+            }
+            self.device
+                .read_vectored_exact(begin * BLKSIZE, &mut [&mut buf[..len * BLKSIZE]])?;
+            for (block, ids) in blocks[i..i + len].iter() {
+                let data = &buf[(block - begin) * BLKSIZE..(block - begin + 1) * BLKSIZE];
+                for &id in ids {
+                    let start = self.inode_location(id).1.start;
+                    let mut disk_inode: DiskINode = unsafe { MaybeUninit::uninit().assume_init() };
+                    let len = disk_inode.as_buf().len();
+                    disk_inode
+                        .as_buf_mut()
+                        .copy_from_slice(&data[start..start + len]);
+                    if !self.super_block.read().large_file() {
+                        // not kept in images of earlier versions
+                        disk_inode.size_hi = 0;
+                    }
+                    metadata.insert(id, self.disk_metadata(id, &disk_inode));
+                }
+            }
+            i += len;
+        }
+        Ok(metadata)
+    }
+}
@@ -0,0 +1,266 @@
+//! Blocks of files shared copy-on-write, like reflink of other file systems
+//!
+//! `copy_file_range()` between files at offsets the same within a block shares the whole
+//! blocks of the range rather than copying them, and copies the rest. A block referred to
+//! more than once has its number of references kept in the refcount table, a hidden file of
+//! `(block, refs)` pairs sorted by block, see `SuperBlock::refcount_inode`. A shared block is
+//! copied to a new one before it is written, and freeing it only drops a reference, until
+//! the last. The table is journaled like other metadata, and written at each sync before
+//! the inodes referring to the blocks.
+use crate::*;
+
+/// Bytes of the number of references of an entry of the refcount table, after the block,
+/// which is an entry of `BlockMap`
+const REFS_SIZE: usize = 4;
+
+/// Bytes copied at a time where blocks can not be shared
+const COPY_SIZE: usize = 16 * BLKSIZE;
+
+impl SimpleFileSystem {
+    /// Load the refcount table, if the image has one
+    pub(crate) fn load_refcounts(&self) -> vfs::Result<()> {
+        let id = match self.super_block.read().refcount_inode() {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+        let inode = self.get_inode(id);
+        let mut buf = vec![0u8; inode.disk_inode.read().size()];
+        inode._read_at(0, &mut buf)?;
+        let map = self.block_map();
+        let shared = buf
+            .chunks_exact(map.entry_size + REFS_SIZE)
+            .map(|entry| {
+                let refs = &entry[map.entry_size..];
+                let refs = u32::from_ne_bytes([refs[0], refs[1], refs[2], refs[3]]);
+                (map.read(entry), refs)
+            })
+            .collect();
+        *self.shared.write() = Dirty::new(shared);
+        Ok(())
+    }
+    /// Write the refcount table if it changed, making the file of it if there is none yet
+    pub(crate) fn write_refcounts(&self) -> vfs::Result<()> {
+        let map = self.block_map();
+        let buf = {
+            let mut shared = self.shared.write();
+            if !shared.dirty() {
+                return Ok(());
+            }
+            let mut buf = Vec::with_capacity(shared.len() * (map.entry_size + REFS_SIZE));
+            for (&block, &refs) in shared.iter() {
+                buf.extend_from_slice(&map.write(block)[..map.entry_size]);
+                buf.extend_from_slice(&refs.to_ne_bytes());
+            }
+            shared.sync();
+            buf
+        };
+        let write = || -> vfs::Result<()> {
+            let id = self.super_block.read().refcount_inode();
+            let inode = match id {
+                Some(id) => self.get_inode(id),
+                None => {
+                    let inode = self.new_inode_file(true)?;
+                    // kept by the super block rather than an entry
+                    inode.nlinks_inc();
+                    self.super_block.write().refcount_inode = inode.id as u32;
+                    inode
+                }
+            };
+            let _lock = inode.lock.write();
+            inode._resize(buf.len())?;
+            inode._write_at(0, &buf)?;
+            inode.sync_all()
+        };
+        write().map_err(|err| {
+            self.shared.write().turn_dirty();
+            err
+        })
+    }
+    /// Whether `block` is referred to more than once
+    pub(crate) fn is_shared(&self, block: BlockId) -> bool {
+        self.shared.read().contains_key(&block)
+    }
+    /// Add a reference to data block `block`
+    fn share_block(&self, block: BlockId) {
+        let mut shared = self.shared.write();
+        *shared.entry(block).or_insert(1) += 1;
+    }
+    /// Drop a reference to `block` if it is shared, return whether others are left
+    pub(crate) fn unshare_block(&self, block: BlockId) -> bool {
+        let mut shared = self.shared.write();
+        match shared.get(&block) {
+            None => false,
+            Some(&2) => {
+                shared.remove(&block);
+                true
+            }
+            Some(&refs) => {
+                shared.insert(block, refs - 1);
+                true
+            }
+        }
+    }
+}
+
+impl INodeImpl {
+    /// Make its content that of file `src`, sharing all the blocks of it, see the module docs
+    pub fn reflink(&self, src: &Arc<dyn vfs::INode>) -> vfs::Result<()> {
+        let src = src.downcast_ref::<INodeImpl>().ok_or(FsError::NotSameFs)?;
+        if src.id == self.id {
+            return Ok(());
+        }
+        self.resize(0)?;
+        src.copy_to(0, self, 0, usize::max_value())?;
+        Ok(())
+    }
+    /// Copy `len` bytes at `offset` to `dst` at `dst_offset`, sharing the whole blocks
+    /// if the offsets are the same within a block. Return the number of bytes copied.
+    pub(crate) fn copy_to(
+        &self,
+        offset: usize,
+        dst: &INodeImpl,
+        dst_offset: usize,
+        len: usize,
+    ) -> vfs::Result<usize> {
+        if !Arc::ptr_eq(&self.fs, &dst.fs) {
+            return Err(FsError::NotSameFs);
+        }
+        // copied by the caller, reading and writing
+        if self.id == dst.id {
+            return Err(FsError::NotSupported);
+        }
+        // in the order of ids, so that two copies the other way round do not wait for each other
+        let (_lock, _dst_lock) = match self.id < dst.id {
+            true => {
+                let lock = self.lock.read();
+                (lock, dst.lock.write())
+            }
+            false => {
+                let dst_lock = dst.lock.write();
+                (self.lock.read(), dst_lock)
+            }
+        };
+        for inode in [self, dst].iter() {
+            match inode.disk_inode.read().type_ {
+                FileType::File => {}
+                FileType::Dir => return Err(FsError::IsDir),
+                _ => return Err(FsError::NotFile),
+            }
+        }
+        let size = self.disk_inode.read().size();
+        let dst_size = dst.disk_inode.read().size();
+        dst.check_flags(vfs::FS_IMMUTABLE_FL)?;
+        if dst_offset != dst_size {
+            dst.check_flags(vfs::FS_APPEND_FL)?;
+        }
+        if offset >= size || len == 0 {
+            return Ok(0);
+        }
+        let len = len.min(size - offset);
+        let end = dst_offset + len;
+        if dst_size < end {
+            dst._resize(end)?;
+        }
+        let dst_size = dst_size.max(end);
+
+        // the whole blocks, and the last one if the rest of it is past the end of both
+        let shareable = offset % BLKSIZE == dst_offset % BLKSIZE
+            && self.fs.super_block.read().version >= VERSION_REFLINK;
+        let (first, last) = match shareable {
+            true => {
+                let last = match offset + len == size && end == dst_size {
+                    true => (size + BLKSIZE - 1) / BLKSIZE,
+                    false => (offset + len) / BLKSIZE,
+                };
+                ((offset + BLKSIZE - 1) / BLKSIZE, last)
+            }
+            false => (0, 0),
+        };
+        let shared = first * BLKSIZE..(last * BLKSIZE).min(offset + len);
+        if shared.start >= shared.end {
+            self.copy_data(offset, dst, dst_offset, len)?;
+        } else {
+            let to = dst_offset + shared.start - offset;
+            self.copy_data(offset, dst, dst_offset, shared.start - offset)?;
+            self.share_blocks(first..last, dst, to / BLKSIZE)?;
+            self.copy_data(
+                shared.end,
+                dst,
+                to + shared.len(),
+                offset + len - shared.end,
+            )?;
+        }
+        dst.touch();
+        Ok(len)
+    }
+    /// Copy `len` bytes at `offset` to `dst` at `dst_offset`, which is large enough
+    fn copy_data(
+        &self,
+        offset: usize,
+        dst: &INodeImpl,
+        dst_offset: usize,
+        len: usize,
+    ) -> vfs::Result<()> {
+        let mut buf = vec![0u8; COPY_SIZE.min(len)];
+        let mut copied = 0;
+        while copied < len {
+            let chunk = (len - copied).min(buf.len());
+            self._read_at(offset + copied, &mut buf[..chunk])?;
+            dst._write_at(dst_offset + copied, &buf[..chunk])?;
+            copied += chunk;
+        }
+        Ok(())
+    }
+    /// Share its blocks `blocks` with `dst` from block `to`, dropping those `dst` had
+    fn share_blocks(
+        &self,
+        blocks: Range<BlockId>,
+        dst: &INodeImpl,
+        to: BlockId,
+    ) -> vfs::Result<()> {
+        let sparse = self.fs.super_block.read().sparse_files();
+        for (i, j) in blocks.zip(to..) {
+            let block = self.get_disk_block_id(i)?;
+            let old = dst.get_disk_block_id(j)?;
+            if block == old {
+                continue;
+            }
+            if block != 0 {
+                self.fs.share_block(block);
+            }
+            dst.set_disk_block_id(j, block)?;
+            if old != 0 {
+                self.fs.free_block(old);
+            }
+            if sparse {
+                let mut disk_inode = dst.disk_inode.write();
+                disk_inode.data_blocks += (block != 0) as u32;
+                disk_inode.data_blocks -= (old != 0) as u32;
+            }
+        }
+        Ok(())
+    }
+    /// Copy the shared blocks in content `begin..end` to be written to new blocks of its own
+    pub(crate) fn unshare_blocks(&self, begin: usize, end: usize) -> vfs::Result<()> {
+        let end = end.min(self.disk_inode.read().size());
+        if begin >= end || self.fs.shared.read().is_empty() {
+            return Ok(());
+        }
+        let reserve = self.privileged();
+        let device = self.content_device();
+        let mut buf = [0u8; BLKSIZE];
+        for i in begin / BLKSIZE..(end + BLKSIZE - 1) / BLKSIZE {
+            let block = self.get_disk_block_id(i)?;
+            if block == 0 || !self.fs.is_shared(block) {
+                continue;
+            }
+            self.init_alloc_hint(i)?;
+            let copy = self.alloc_block(reserve).ok_or(FsError::NoDeviceSpace)?;
+            device.read_block(block, 0, &mut buf)?;
+            device.write_block(copy, 0, &buf)?;
+            self.set_disk_block_id(i, copy)?;
+            self.fs.free_block(block);
+        }
+        Ok(())
+    }
+}
@@ -1,10 +1,13 @@
 //! On-disk structures in SFS
 
 use crate::vfs;
+use alloc::borrow::Cow;
 use alloc::str;
+use alloc::string::String;
 
 use core::fmt::{Debug, Error, Formatter};
 use core::mem::{size_of, size_of_val};
+use core::ops::Range;
 use core::slice;
 use rcore_fs::vfs::Timespec;
 use static_assertions::const_assert;
@@ -15,21 +18,51 @@ use static_assertions::const_assert;
 pub struct SuperBlock {
     /// magic number, should be SFS_MAGIC
     pub magic: u32,
-    /// number of blocks in fs
+    /// number of blocks in fs, low 32 bits since `VERSION_BLOCKS64`
     pub blocks: u32,
-    /// number of unused blocks in fs
+    /// number of unused blocks in fs, low 32 bits since `VERSION_BLOCKS64`
     pub unused_blocks: u32,
     /// information for sfs
     pub info: Str32,
     /// number of freemap blocks
     pub freemap_blocks: u32,
+    /// number of blocks in the inode table after freemap, 0 if there is no inode table
+    pub inode_blocks: u32,
+    /// layout version, 0 in images made before it is kept
+    pub version: u32,
+    /// number of blocks of the metadata journal at the end of fs since `VERSION_JOURNAL`,
+    /// 0 if there is no journal
+    pub journal_blocks: u32,
+    /// first block of the rest of the freemap since `VERSION_FREEMAP_EXT`,
+    /// which is taken from the blocks added by a resize, low 32 bits since `VERSION_BLOCK_IDS64`
+    pub freemap_ext: u32,
+    /// number of blocks of the rest of the freemap, 0 if there is none
+    pub freemap_ext_blocks: u32,
+    /// FEATURE_* bits since `VERSION_FEATURES`
+    pub features: u32,
+    /// number of unused blocks only allocated with the override since `VERSION_RESERVED`,
+    /// low 32 bits since `VERSION_BLOCK_IDS64`
+    pub reserved_blocks: u32,
+    /// high 32 bits of `blocks` since `VERSION_BLOCKS64`
+    pub blocks_hi: u32,
+    /// high 32 bits of `unused_blocks` since `VERSION_BLOCKS64`
+    pub unused_blocks_hi: u32,
+    /// number of inodes in use, with root, since `VERSION_INODE_COUNT`
+    pub used_inodes: u32,
+    /// inode of the table of blocks shared by files since `VERSION_REFLINK`,
+    /// 0 if no block has been shared
+    pub refcount_inode: u32,
+    /// high 32 bits of `freemap_ext` since `VERSION_BLOCK_IDS64`
+    pub freemap_ext_hi: u32,
+    /// high 32 bits of `reserved_blocks` since `VERSION_BLOCK_IDS64`
+    pub reserved_blocks_hi: u32,
 }
 
 /// inode (on disk)
 #[repr(C)]
 #[derive(Debug)]
 pub struct DiskINode {
-    /// size of the file (in bytes)
+    /// size of the file (in bytes), low 32 bits since `VERSION_LARGE_FILE`
     /// undefined in dir (256 * #entries ?)
     pub size: u32,
     /// one of SYS_TYPE_* above
@@ -39,20 +72,42 @@ pub struct DiskINode {
     pub nlinks: u16,
     /// number of blocks
     pub blocks: u32,
-    /// direct blocks
+    /// direct blocks, low 32 bits since `VERSION_BLOCK_IDS64`, see `direct()`
     pub direct: [u32; NDIRECT],
-    /// indirect blocks
+    /// indirect blocks, low 32 bits since `VERSION_BLOCK_IDS64`
     pub indirect: u32,
-    /// double indirect blocks
+    /// double indirect blocks, low 32 bits since `VERSION_BLOCK_IDS64`
     pub db_indirect: u32,
-    /// device inode id for char/block device (major, minor)
-    pub device_inode_id: usize,
+    /// device number of a char/block device, made by `make_rdev()`,
+    /// `NODEVICE` for other types
+    pub rdev: usize,
     /// Time of last access
     pub atime: Timespec,
     /// Time of last modification
     pub mtime: Timespec,
     /// Time of last change
     pub ctime: Timespec,
+    /// permission bits with `MODE_SET`, 0 in images made before it is kept
+    pub mode: u16,
+    /// permission bits cleared from entries created in this dir
+    pub umask: u16,
+    /// owner
+    pub uid: u32,
+    /// group
+    pub gid: u32,
+    /// high 32 bits of size since `VERSION_LARGE_FILE`
+    pub size_hi: u32,
+    /// triple indirect blocks since `VERSION_LARGE_FILE`,
+    /// low 32 bits since `VERSION_BLOCK_IDS64`
+    pub tri_indirect: u32,
+    /// number of blocks allocated for the content, fewer than `blocks` by its holes,
+    /// kept since `FEATURE_SPARSE_FILES`
+    pub data_blocks: u32,
+    /// `vfs::FS_*_FL` bits kept since `FEATURE_INODE_FLAGS`
+    pub flags: u32,
+    /// high 32 bits of `direct`, `indirect`, `db_indirect` and `tri_indirect`,
+    /// in this order, since `VERSION_BLOCK_IDS64`
+    pub block_ids_hi: [u32; NDIRECT + 3],
 }
 
 /*
@@ -69,6 +124,14 @@ pub struct IndirectBlock {
     pub entries: [u32; BLK_NENTRY],
 }
 
+/// Layout of the indirect blocks of files, by the version of the image:
+/// their entries are u64 since `VERSION_BLOCK_IDS64`, and u32 before
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct BlockMap {
+    /// size of an entry, `ENTRY_SIZE` or `ENTRY_SIZE64`
+    pub entry_size: usize,
+}
+
 /// file entry (on disk)
 #[repr(C)]
 #[derive(Debug)]
@@ -131,9 +194,228 @@ impl SuperBlock {
     pub fn check(&self) -> bool {
         self.magic == MAGIC
     }
+    /// Number of blocks in fs, see `VERSION_BLOCKS64`
+    pub fn blocks(&self) -> usize {
+        match self.version >= VERSION_BLOCKS64 {
+            true => join_hi(self.blocks, self.blocks_hi),
+            false => self.blocks as usize,
+        }
+    }
+    pub fn set_blocks(&mut self, blocks: usize) {
+        self.blocks = blocks as u32;
+        if self.version >= VERSION_BLOCKS64 {
+            self.blocks_hi = (blocks as u64 >> 32) as u32;
+        }
+    }
+    /// Number of unused blocks in fs, see `VERSION_BLOCKS64`
+    pub fn unused_blocks(&self) -> usize {
+        match self.version >= VERSION_BLOCKS64 {
+            true => join_hi(self.unused_blocks, self.unused_blocks_hi),
+            false => self.unused_blocks as usize,
+        }
+    }
+    pub fn set_unused_blocks(&mut self, blocks: usize) {
+        self.unused_blocks = blocks as u32;
+        if self.version >= VERSION_BLOCKS64 {
+            self.unused_blocks_hi = (blocks as u64 >> 32) as u32;
+        }
+    }
+    /// Whether files can be larger than 4GB, see `VERSION_LARGE_FILE`
+    pub fn large_file(&self) -> bool {
+        self.version >= VERSION_LARGE_FILE
+    }
+    /// Number of blocks of the journal, 0 if there is none, see `VERSION_JOURNAL`
+    pub fn journal_blocks(&self) -> usize {
+        match self.version >= VERSION_JOURNAL {
+            true => self.journal_blocks as usize,
+            false => 0,
+        }
+    }
+    /// FEATURE_* bits of the image, see `VERSION_FEATURES`
+    pub fn features(&self) -> u32 {
+        match self.version >= VERSION_FEATURES {
+            true => self.features,
+            false => 0,
+        }
+    }
+    /// Number of inodes in use, if the image keeps it, see `VERSION_INODE_COUNT`
+    pub fn used_inodes(&self) -> Option<usize> {
+        match self.version >= VERSION_INODE_COUNT {
+            true => Some(self.used_inodes as usize),
+            false => None,
+        }
+    }
+    pub fn set_used_inodes(&mut self, inodes: usize) {
+        if self.version >= VERSION_INODE_COUNT {
+            self.used_inodes = inodes as u32;
+        }
+    }
+    /// Count an inode allocated, or freed if not `allocated`, if the image keeps the count
+    pub fn count_inode(&mut self, allocated: bool) {
+        if let Some(used) = self.used_inodes() {
+            match allocated {
+                true => self.set_used_inodes(used + 1),
+                false => self.set_used_inodes(used.saturating_sub(1)),
+            }
+        }
+    }
+    /// Inode of the table of shared blocks, if there is one, see `VERSION_REFLINK`
+    pub fn refcount_inode(&self) -> Option<INodeId> {
+        match self.version >= VERSION_REFLINK && self.refcount_inode != 0 {
+            true => Some(self.refcount_inode as INodeId),
+            false => None,
+        }
+    }
+    /// Number of unused blocks kept for privileged use, see `VERSION_RESERVED`
+    pub fn reserved_blocks(&self) -> usize {
+        match self.version >= VERSION_RESERVED {
+            true if self.block_ids64() => join_hi(self.reserved_blocks, self.reserved_blocks_hi),
+            true => self.reserved_blocks as usize,
+            false => 0,
+        }
+    }
+    pub fn set_reserved_blocks(&mut self, blocks: usize) {
+        self.reserved_blocks = blocks as u32;
+        if self.block_ids64() {
+            self.reserved_blocks_hi = (blocks as u64 >> 32) as u32;
+        }
+    }
+    /// Whether block ids are u64 in inodes and indirect blocks, see `VERSION_BLOCK_IDS64`
+    pub fn block_ids64(&self) -> bool {
+        self.version >= VERSION_BLOCK_IDS64
+    }
+    /// Max number of blocks of the image, see `VERSION_BLOCK_IDS64`
+    pub fn max_blocks(&self) -> usize {
+        match self.block_ids64() {
+            true => MAX_BLOCKS64,
+            false => MAX_BLOCKS,
+        }
+    }
+    /// Layout of the indirect blocks of files
+    pub fn block_map(&self) -> BlockMap {
+        BlockMap::new(self.version)
+    }
+    /// Whether entries of dirs are sorted by name, see `FEATURE_SORTED_DIRS`
+    pub fn sorted_dirs(&self) -> bool {
+        self.features() & FEATURE_SORTED_DIRS != 0
+    }
+    /// Whether short symlinks are kept in their inodes, see `FEATURE_INLINE_SYMLINKS`
+    pub fn inline_symlinks(&self) -> bool {
+        self.features() & FEATURE_INLINE_SYMLINKS != 0
+    }
+    /// Whether files may have holes, see `FEATURE_SPARSE_FILES`
+    pub fn sparse_files(&self) -> bool {
+        self.features() & FEATURE_SPARSE_FILES != 0
+    }
+    /// Whether the inode table has `INODES_PER_BLOCK` inodes in a block,
+    /// see `FEATURE_PACKED_INODES`
+    pub fn packed_inodes(&self) -> bool {
+        self.features() & FEATURE_PACKED_INODES != 0
+    }
+    /// Whether inodes have flags, see `FEATURE_INODE_FLAGS`
+    pub fn inode_flags(&self) -> bool {
+        self.features() & FEATURE_INODE_FLAGS != 0
+    }
+    /// How names are folded to be looked up, none if case-sensitive,
+    /// see `FEATURE_CASE_FOLD`
+    pub fn case_fold(&self) -> Option<CaseFold> {
+        let features = self.features();
+        match (
+            features & FEATURE_CASE_FOLD != 0,
+            features & FEATURE_CASE_FOLD_UTF8 != 0,
+        ) {
+            (false, _) => None,
+            (true, false) => Some(CaseFold::Ascii),
+            (true, true) => Some(CaseFold::Utf8),
+        }
+    }
+    /// Blocks of the freemap after the first `freemap_blocks`, see `VERSION_FREEMAP_EXT`
+    pub fn freemap_ext(&self) -> Range<BlockId> {
+        match self.version >= VERSION_FREEMAP_EXT {
+            true => {
+                let begin = match self.block_ids64() {
+                    true => join_hi(self.freemap_ext, self.freemap_ext_hi),
+                    false => self.freemap_ext as usize,
+                };
+                begin..begin + self.freemap_ext_blocks as usize
+            }
+            false => 0..0,
+        }
+    }
+    pub fn set_freemap_ext(&mut self, ext: Range<BlockId>) {
+        self.freemap_ext = ext.start as u32;
+        self.freemap_ext_blocks = ext.len() as u32;
+        if self.block_ids64() {
+            self.freemap_ext_hi = (ext.start as u64 >> 32) as u32;
+        }
+    }
+    /// Number of blocks of the whole freemap
+    pub fn freemap_len(&self) -> usize {
+        self.freemap_blocks as usize + self.freemap_ext().len()
+    }
+    /// Block `i` of the whole freemap
+    pub fn freemap_block(&self, i: usize) -> BlockId {
+        match i.checked_sub(self.freemap_blocks as usize) {
+            Some(j) => self.freemap_ext().start + j,
+            None => BLKN_FREEMAP + i,
+        }
+    }
 }
 
 impl DiskINode {
+    /// Size of the file in bytes
+    pub fn size(&self) -> usize {
+        join_hi(self.size, self.size_hi)
+    }
+    pub fn set_size(&mut self, size: usize) {
+        self.size = size as u32;
+        self.size_hi = (size as u64 >> 32) as u32;
+    }
+    /// Direct block `i`, see `VERSION_BLOCK_IDS64`
+    pub fn direct(&self, i: usize) -> BlockId {
+        join_hi(self.direct[i], self.block_ids_hi[i])
+    }
+    pub fn set_direct(&mut self, i: usize, block: BlockId) {
+        self.direct[i] = block as u32;
+        self.block_ids_hi[i] = (block as u64 >> 32) as u32;
+    }
+    /// Indirect block, 0 if there is none
+    pub fn indirect(&self) -> BlockId {
+        join_hi(self.indirect, self.block_ids_hi[NDIRECT])
+    }
+    pub fn set_indirect(&mut self, block: BlockId) {
+        self.indirect = block as u32;
+        self.block_ids_hi[NDIRECT] = (block as u64 >> 32) as u32;
+    }
+    /// Double indirect block, 0 if there is none
+    pub fn db_indirect(&self) -> BlockId {
+        join_hi(self.db_indirect, self.block_ids_hi[NDIRECT + 1])
+    }
+    pub fn set_db_indirect(&mut self, block: BlockId) {
+        self.db_indirect = block as u32;
+        self.block_ids_hi[NDIRECT + 1] = (block as u64 >> 32) as u32;
+    }
+    /// Triple indirect block, 0 if there is none
+    pub fn tri_indirect(&self) -> BlockId {
+        join_hi(self.tri_indirect, self.block_ids_hi[NDIRECT + 2])
+    }
+    pub fn set_tri_indirect(&mut self, block: BlockId) {
+        self.tri_indirect = block as u32;
+        self.block_ids_hi[NDIRECT + 2] = (block as u64 >> 32) as u32;
+    }
+    /// Whether the content is kept in `inline_data`, as a symlink without blocks
+    pub fn is_inline(&self) -> bool {
+        self.type_ == FileType::SymLink && self.blocks == 0
+    }
+    /// Bytes of `direct`, `indirect` and `db_indirect`, which keep the content if `is_inline`
+    pub fn inline_data(&self) -> &[u8] {
+        let begin = self.direct.as_ptr() as usize - self as *const _ as usize;
+        &self.as_buf()[begin..begin + MAX_INLINE_SYMLINK]
+    }
+    pub fn inline_data_mut(&mut self) -> &mut [u8] {
+        let begin = self.direct.as_ptr() as usize - self as *const _ as usize;
+        &mut self.as_buf_mut()[begin..begin + MAX_INLINE_SYMLINK]
+    }
     pub const fn new_file() -> Self {
         DiskINode {
             size: 0,
@@ -143,10 +425,19 @@ impl DiskINode {
             direct: [0; NDIRECT],
             indirect: 0,
             db_indirect: 0,
-            device_inode_id: NODEVICE,
+            rdev: NODEVICE,
             atime: Timespec { sec: 0, nsec: 0 },
             mtime: Timespec { sec: 0, nsec: 0 },
             ctime: Timespec { sec: 0, nsec: 0 },
+            mode: 0,
+            umask: 0,
+            uid: 0,
+            gid: 0,
+            size_hi: 0,
+            tri_indirect: 0,
+            data_blocks: 0,
+            flags: 0,
+            block_ids_hi: [0; NDIRECT + 3],
         }
     }
     pub const fn new_symlink() -> Self {
@@ -158,10 +449,19 @@ impl DiskINode {
             direct: [0; NDIRECT],
             indirect: 0,
             db_indirect: 0,
-            device_inode_id: NODEVICE,
+            rdev: NODEVICE,
             atime: Timespec { sec: 0, nsec: 0 },
             mtime: Timespec { sec: 0, nsec: 0 },
             ctime: Timespec { sec: 0, nsec: 0 },
+            mode: 0,
+            umask: 0,
+            uid: 0,
+            gid: 0,
+            size_hi: 0,
+            tri_indirect: 0,
+            data_blocks: 0,
+            flags: 0,
+            block_ids_hi: [0; NDIRECT + 3],
         }
     }
     pub const fn new_dir() -> Self {
@@ -173,27 +473,146 @@ impl DiskINode {
             direct: [0; NDIRECT],
             indirect: 0,
             db_indirect: 0,
-            device_inode_id: NODEVICE,
+            rdev: NODEVICE,
+            atime: Timespec { sec: 0, nsec: 0 },
+            mtime: Timespec { sec: 0, nsec: 0 },
+            ctime: Timespec { sec: 0, nsec: 0 },
+            mode: 0,
+            umask: 0,
+            uid: 0,
+            gid: 0,
+            size_hi: 0,
+            tri_indirect: 0,
+            data_blocks: 0,
+            flags: 0,
+            block_ids_hi: [0; NDIRECT + 3],
+        }
+    }
+    pub const fn new_fifo() -> Self {
+        DiskINode {
+            size: 0,
+            type_: FileType::NamedPipe,
+            nlinks: 0,
+            blocks: 0,
+            direct: [0; NDIRECT],
+            indirect: 0,
+            db_indirect: 0,
+            rdev: NODEVICE,
+            atime: Timespec { sec: 0, nsec: 0 },
+            mtime: Timespec { sec: 0, nsec: 0 },
+            ctime: Timespec { sec: 0, nsec: 0 },
+            mode: 0,
+            umask: 0,
+            uid: 0,
+            gid: 0,
+            size_hi: 0,
+            tri_indirect: 0,
+            data_blocks: 0,
+            flags: 0,
+            block_ids_hi: [0; NDIRECT + 3],
+        }
+    }
+    pub const fn new_socket() -> Self {
+        DiskINode {
+            size: 0,
+            type_: FileType::Socket,
+            nlinks: 0,
+            blocks: 0,
+            direct: [0; NDIRECT],
+            indirect: 0,
+            db_indirect: 0,
+            rdev: NODEVICE,
             atime: Timespec { sec: 0, nsec: 0 },
             mtime: Timespec { sec: 0, nsec: 0 },
             ctime: Timespec { sec: 0, nsec: 0 },
+            mode: 0,
+            umask: 0,
+            uid: 0,
+            gid: 0,
+            size_hi: 0,
+            tri_indirect: 0,
+            data_blocks: 0,
+            flags: 0,
+            block_ids_hi: [0; NDIRECT + 3],
         }
     }
-    pub const fn new_chardevice(device_inode_id: usize) -> Self {
+    /// `type_` is `CharDevice` or `BlockDevice`
+    pub const fn new_device(type_: FileType, rdev: usize) -> Self {
         DiskINode {
             size: 0,
-            type_: FileType::CharDevice,
+            type_,
             nlinks: 0,
             blocks: 0,
             direct: [0; NDIRECT],
             indirect: 0,
             db_indirect: 0,
-            device_inode_id,
+            rdev,
             atime: Timespec { sec: 0, nsec: 0 },
             mtime: Timespec { sec: 0, nsec: 0 },
             ctime: Timespec { sec: 0, nsec: 0 },
+            mode: 0,
+            umask: 0,
+            uid: 0,
+            gid: 0,
+            size_hi: 0,
+            tri_indirect: 0,
+            data_blocks: 0,
+            flags: 0,
+            block_ids_hi: [0; NDIRECT + 3],
+        }
+    }
+}
+
+impl BlockMap {
+    /// Layout of images of `version`
+    pub fn new(version: u32) -> Self {
+        let entry_size = match version >= VERSION_BLOCK_IDS64 {
+            true => ENTRY_SIZE64,
+            false => ENTRY_SIZE,
+        };
+        BlockMap { entry_size }
+    }
+    /// Number of entries in an indirect block
+    pub fn entries(self) -> usize {
+        BLKSIZE / self.entry_size
+    }
+    /// Max number of blocks with indirect blocks
+    pub fn max_indirect(self) -> usize {
+        NDIRECT + self.entries()
+    }
+    /// Max number of blocks with double indirect blocks
+    pub fn max_double_indirect(self) -> usize {
+        self.max_indirect() + self.entries() * self.entries()
+    }
+    /// Max number of blocks with triple indirect blocks
+    pub fn max_triple_indirect(self) -> usize {
+        self.max_double_indirect() + self.entries().pow(3)
+    }
+    /// Block id kept in `entry`, `entry_size` bytes
+    pub fn read(self, entry: &[u8]) -> BlockId {
+        let mut bytes = [0u8; 8];
+        bytes[..self.entry_size].copy_from_slice(&entry[..self.entry_size]);
+        match self.entry_size {
+            ENTRY_SIZE64 => u64::from_ne_bytes(bytes) as BlockId,
+            _ => u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as BlockId,
         }
     }
+    /// `block` as an entry, of which the first `entry_size` bytes are used
+    pub fn write(self, block: BlockId) -> [u8; 8] {
+        match self.entry_size {
+            ENTRY_SIZE64 => (block as u64).to_ne_bytes(),
+            _ => {
+                let mut bytes = [0u8; 8];
+                bytes[..ENTRY_SIZE].copy_from_slice(&(block as u32).to_ne_bytes());
+                bytes
+            }
+        }
+    }
+}
+
+/// A u64 of the low and high 32 bits of it kept apart
+fn join_hi(lo: u32, hi: u32) -> usize {
+    ((hi as u64) << 32 | lo as u64) as usize
 }
 
 /// Convert structs to [u8] slice
@@ -221,24 +640,91 @@ impl AsBuf for u32 {}
 pub type BlockId = usize;
 pub type INodeId = BlockId;
 
+/// `DiskINode::rdev` of an INode which is not a device
 pub const NODEVICE: usize = 100;
 
+/// Marks `DiskINode::mode` as stored, an INode without it has mode 0o777
+pub const MODE_SET: u16 = 0x8000;
+
 /// magic number for sfs
 pub const MAGIC: u32 = 0x2f8dbe2b;
+/// layout version of new images, an image of a later version is not opened
+pub const VERSION: u32 = 10;
+/// first version with u64 size and triple indirect blocks
+pub const VERSION_LARGE_FILE: u32 = 2;
+/// first version with a metadata journal
+pub const VERSION_JOURNAL: u32 = 3;
+/// first version whose freemap can be extended out of its place, to grow the image
+pub const VERSION_FREEMAP_EXT: u32 = 4;
+/// first version with feature bits in the super block
+pub const VERSION_FEATURES: u32 = 5;
+/// first version with blocks reserved for privileged use, so a full image can still be used
+/// by root to recover
+pub const VERSION_RESERVED: u32 = 6;
+/// first version with u64 block counts in the super block
+pub const VERSION_BLOCKS64: u32 = 7;
+/// first version counting the inodes in use in the super block
+pub const VERSION_INODE_COUNT: u32 = 8;
+/// first version whose files can share blocks, copied on write, see `SuperBlock::refcount_inode`
+pub const VERSION_REFLINK: u32 = 9;
+/// first version with u64 block ids in inodes, indirect blocks, the refcount table
+/// and the super block, so that an image can have more than `MAX_BLOCKS` blocks
+pub const VERSION_BLOCK_IDS64: u32 = 10;
+/// max number of blocks of an image before `VERSION_BLOCK_IDS64`, as block ids were u32,
+/// so 16TB. Ids of inodes in entries are still u32, so inodes are kept below it.
+pub const MAX_BLOCKS: usize = u32::max_value() as usize;
+/// max number of blocks of an image since `VERSION_BLOCK_IDS64`, as many as can be addressed
+pub const MAX_BLOCKS64: usize = usize::max_value() / BLKSIZE;
+/// percent of the blocks reserved in new images, like ext2
+pub const DEFAULT_RESERVED_PERCENT: usize = 5;
+/// entries of dirs after '.' and '..' are sorted by name, to be found by binary search
+pub const FEATURE_SORTED_DIRS: u32 = 1;
+/// targets of symlinks up to `MAX_INLINE_SYMLINK` bytes are kept in their inodes
+pub const FEATURE_INLINE_SYMLINKS: u32 = 2;
+/// blocks of files are only allocated when written, and those not yet are holes of id 0,
+/// which are read as zeros
+pub const FEATURE_SPARSE_FILES: u32 = 4;
+/// the inode table has `INODES_PER_BLOCK` inodes in each block, in slots of `INODE_SIZE` bytes,
+/// with id `block * INODES_PER_BLOCK + slot`, and a block of it is in use if any slot is
+pub const FEATURE_PACKED_INODES: u32 = 8;
+/// inodes have immutable, append-only and no-dump flags, see `INODE_FLAGS`
+pub const FEATURE_INODE_FLAGS: u32 = 16;
+/// names are looked up case-insensitively, but kept as created: names the same after
+/// `CaseFold::fold()` are the same entry, and sorted dirs are in the order of the folded names
+pub const FEATURE_CASE_FOLD: u32 = 32;
+/// with `FEATURE_CASE_FOLD`, all of Unicode is folded, not only ASCII
+pub const FEATURE_CASE_FOLD_UTF8: u32 = 64;
+/// FEATURE_* bits known to this version, an image with others can not be opened
+pub const FEATURES: u32 = FEATURE_SORTED_DIRS
+    | FEATURE_INLINE_SYMLINKS
+    | FEATURE_SPARSE_FILES
+    | FEATURE_PACKED_INODES
+    | FEATURE_INODE_FLAGS
+    | FEATURE_CASE_FOLD
+    | FEATURE_CASE_FOLD_UTF8;
+/// `vfs::FS_*_FL` bits kept in `DiskINode::flags`
+pub const INODE_FLAGS: u32 = vfs::FS_IMMUTABLE_FL | vfs::FS_APPEND_FL | vfs::FS_NODUMP_FL;
 /// size of block
 pub const BLKSIZE: usize = 1usize << BLKSIZE_LOG2;
 /// log2( size of block )
 pub const BLKSIZE_LOG2: u8 = 12;
 /// number of direct blocks in inode
 pub const NDIRECT: usize = 12;
+/// size of a slot of a packed inode table
+pub const INODE_SIZE: usize = 256;
+/// number of inodes in a block of a packed inode table
+pub const INODES_PER_BLOCK: usize = BLKSIZE / INODE_SIZE;
+/// max length of a symlink kept in its inode, in place of its block ids
+pub const MAX_INLINE_SYMLINK: usize = (NDIRECT + 2) * ENTRY_SIZE;
 /// default sfs infomation string
 pub const DEFAULT_INFO: &str = "simple file system";
 /// max length of infomation
 pub const MAX_INFO_LEN: usize = 31;
 /// max length of filename
 pub const MAX_FNAME_LEN: usize = 255;
-/// max file size in theory (48KB + 4MB + 4GB)
-/// however, the file size is stored in u32
+/// max file size before `VERSION_LARGE_FILE`, as the file size was stored in u32.
+/// Since then it is 48KB + 4MB + 4GB + 4TB by triple indirect blocks,
+/// and 48KB + 2MB + 1GB + 512GB since `VERSION_BLOCK_IDS64`, as entries are u64.
 pub const MAX_FILE_SIZE: usize = 0xffffffff;
 /// block the superblock lives in
 pub const BLKN_SUPER: BlockId = 0;
@@ -248,18 +734,24 @@ pub const BLKN_ROOT: BlockId = 1;
 pub const BLKN_FREEMAP: BlockId = 2;
 /// number of bits in a block
 pub const BLKBITS: usize = BLKSIZE * 8;
-/// size of one entry
+/// size of one entry, an inode id of a dir entry or a block id of an indirect block
+/// before `VERSION_BLOCK_IDS64`
 pub const ENTRY_SIZE: usize = 4;
-/// number of entries in a block
+/// size of an entry of an indirect block since `VERSION_BLOCK_IDS64`
+pub const ENTRY_SIZE64: usize = 8;
+/// number of entries in a block before `VERSION_BLOCK_IDS64`, see `BlockMap`
 pub const BLK_NENTRY: usize = BLKSIZE / ENTRY_SIZE;
 /// size of a dirent used in the size field
 pub const DIRENT_SIZE: usize = MAX_FNAME_LEN + 1 + ENTRY_SIZE;
 /// max number of blocks with direct blocks
 pub const MAX_NBLOCK_DIRECT: usize = NDIRECT;
-/// max number of blocks with indirect blocks
+/// max number of blocks with indirect blocks before `VERSION_BLOCK_IDS64`, see `BlockMap`
 pub const MAX_NBLOCK_INDIRECT: usize = NDIRECT + BLK_NENTRY;
-/// max number of blocks with double indirect blocks
+/// max number of blocks with double indirect blocks before `VERSION_BLOCK_IDS64`, see `BlockMap`
 pub const MAX_NBLOCK_DOUBLE_INDIRECT: usize = NDIRECT + BLK_NENTRY + BLK_NENTRY * BLK_NENTRY;
+/// max number of blocks with triple indirect blocks before `VERSION_BLOCK_IDS64`, see `BlockMap`
+pub const MAX_NBLOCK_TRIPLE_INDIRECT: usize =
+    MAX_NBLOCK_DOUBLE_INDIRECT + BLK_NENTRY * BLK_NENTRY * BLK_NENTRY;
 
 /// file types
 #[repr(u16)]
@@ -271,10 +763,47 @@ pub enum FileType {
     SymLink = 3,
     CharDevice = 4,
     BlockDevice = 5,
+    NamedPipe = 6,
+    Socket = 7,
+}
+
+/// Normalization of names in a case-insensitive image, see `FEATURE_CASE_FOLD`
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum CaseFold {
+    /// 'A' to 'Z' are taken as 'a' to 'z', other chars are kept
+    Ascii,
+    /// each char is taken as its Unicode lowercase mapping, as `char::to_lowercase()`
+    Utf8,
+}
+
+impl CaseFold {
+    /// `name` normalized: names the same after it are taken to be the same
+    pub fn fold(self, name: &str) -> String {
+        match self {
+            CaseFold::Ascii => name.to_ascii_lowercase(),
+            CaseFold::Utf8 => name.chars().flat_map(char::to_lowercase).collect(),
+        }
+    }
+    /// FEATURE_* bits of it
+    pub fn features(self) -> u32 {
+        match self {
+            CaseFold::Ascii => FEATURE_CASE_FOLD,
+            CaseFold::Utf8 => FEATURE_CASE_FOLD | FEATURE_CASE_FOLD_UTF8,
+        }
+    }
+}
+
+/// `name` as it is compared to the names of entries by `fold`, see `SuperBlock::case_fold()`
+pub fn fold_name(fold: Option<CaseFold>, name: &str) -> Cow<'_, str> {
+    match fold {
+        Some(fold) => Cow::Owned(fold.fold(name)),
+        None => Cow::Borrowed(name),
+    }
 }
 
 const_assert!(o1; size_of::<SuperBlock>() <= BLKSIZE);
-const_assert!(o2; size_of::<DiskINode>() <= BLKSIZE);
+const_assert!(o2; size_of::<DiskINode>() <= INODE_SIZE);
 const_assert!(o3; size_of::<DiskEntry>() <= BLKSIZE);
 const_assert!(o4; size_of::<IndirectBlock>() == BLKSIZE);
 const_assert!(o5; DEFAULT_INFO.len() <= MAX_INFO_LEN);
+const_assert!(o6; INODES_PER_BLOCK <= 16);
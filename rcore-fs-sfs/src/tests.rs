@@ -57,7 +57,7 @@ fn create_file() -> Result<()> {
             gid: 0,
             blk_size: 4096,
             dev: 0,
-            rdev: 100, // dummo why 100 here, maybe legacy data?
+            rdev: 0,
         }
     );
 
@@ -291,6 +291,7 @@ fn test_symlinks() -> Result<()> {
 fn test_double_indirect_blocks() -> Result<()> {
     let sfs = _create_new_sfs();
     let root = sfs.root_inode();
+    let map = sfs.block_map();
 
     let file1 = root
         .create("file1", FileType::File, 0o777)
@@ -304,22 +305,22 @@ fn test_double_indirect_blocks() -> Result<()> {
     file1.resize(MAX_NBLOCK_DIRECT * BLKSIZE).unwrap();
     // force usage of indirect block
     file1.resize((MAX_NBLOCK_DIRECT + 1) * BLKSIZE).unwrap();
-    file1.resize(MAX_NBLOCK_INDIRECT * BLKSIZE).unwrap();
+    file1.resize(map.max_indirect() * BLKSIZE).unwrap();
     // force usage of double indirect block
-    file1.resize((MAX_NBLOCK_INDIRECT + 1) * BLKSIZE).unwrap();
-    file1.resize((MAX_NBLOCK_INDIRECT + 2) * BLKSIZE).unwrap();
+    file1.resize((map.max_indirect() + 1) * BLKSIZE).unwrap();
+    file1.resize((map.max_indirect() + 2) * BLKSIZE).unwrap();
 
     // resize up and down
     file1.resize(0).unwrap();
-    file1.resize((MAX_NBLOCK_INDIRECT + 2) * BLKSIZE).unwrap();
+    file1.resize((map.max_indirect() + 2) * BLKSIZE).unwrap();
     file1.resize(MAX_NBLOCK_DIRECT * BLKSIZE).unwrap();
     file1.resize((MAX_NBLOCK_DIRECT + 1) * BLKSIZE).unwrap();
     file1.resize(MAX_NBLOCK_DIRECT * BLKSIZE).unwrap();
     file1.resize(0).unwrap();
-    file1.resize((MAX_NBLOCK_INDIRECT + 1) * BLKSIZE).unwrap();
+    file1.resize((map.max_indirect() + 1) * BLKSIZE).unwrap();
     file1.resize(MAX_NBLOCK_DIRECT * BLKSIZE).unwrap();
-    file1.resize((MAX_NBLOCK_INDIRECT + 2) * BLKSIZE).unwrap();
-    file1.resize((MAX_NBLOCK_INDIRECT + 1) * BLKSIZE).unwrap();
+    file1.resize((map.max_indirect() + 2) * BLKSIZE).unwrap();
+    file1.resize((map.max_indirect() + 1) * BLKSIZE).unwrap();
     file1.resize(0).unwrap();
 
     sfs.sync()?;
@@ -566,3 +567,1788 @@ fn create_then_get_entry() -> Result<()> {
     sfs.sync()?;
     Ok(())
 }
+
+#[test]
+fn remove_dir_all() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let bfree = sfs.info().bfree;
+
+    let dir1 = root.create("dir1", FileType::Dir, 0o777)?;
+    let dir2 = dir1.create("dir2", FileType::Dir, 0o777)?;
+    let file1 = dir1.create("file1", FileType::File, 0o777)?;
+    file1.write_at(0, &[1u8; 0x3000])?;
+    dir2.create("file2", FileType::File, 0o777)?;
+    dir2.link("file1_link", &file1)?;
+    let symlink = dir2.create("symlink", FileType::SymLink, 0o777)?;
+    symlink.write_at(0, b"/")?;
+    drop(dir2);
+    drop(symlink);
+
+    vfs::remove_dir_all(&dir1)?;
+    assert_eq!(dir1.list()?, vec![".", ".."]);
+    assert_eq!(file1.metadata()?.nlinks, 0);
+    assert!(root.lookup("dir1/dir2").is_err());
+    assert!(root.lookup("dir1/file1").is_err());
+
+    drop(file1);
+    root.unlink("dir1")?;
+    drop(dir1);
+    sfs.sync()?;
+    assert_eq!(
+        sfs.info().bfree,
+        bfree,
+        "blocks leaked after remove_dir_all"
+    );
+    Ok(())
+}
+
+#[test]
+fn fifo_and_socket() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let fifo = root.create("fifo", FileType::NamedPipe, 0o666)?;
+    let socket = root.create("socket", FileType::Socket, 0o666)?;
+    assert_eq!(fifo.metadata()?.type_, FileType::NamedPipe);
+    assert_eq!(socket.metadata()?.type_, FileType::Socket);
+    assert_eq!(fifo.read_at(0, &mut [0u8; 4]), Err(FsError::NotFile));
+
+    // reload them from disk
+    drop(fifo);
+    drop(socket);
+    sfs.sync()?;
+    assert_eq!(root.lookup("fifo")?.metadata()?.type_, FileType::NamedPipe);
+    assert_eq!(root.lookup("socket")?.metadata()?.type_, FileType::Socket);
+    Ok(())
+}
+
+#[test]
+fn devices() -> Result<()> {
+    use rcore_fs::vfs::{make_rdev, rdev_major, rdev_minor};
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let null = root.create_device("null", FileType::CharDevice, 0o666, 1, 3)?;
+    // a minor of more than 8 bits
+    let disk = root.create_device("disk", FileType::BlockDevice, 0o660, 259, 0x12345)?;
+    assert_eq!(null.metadata()?.rdev, 0x103);
+    assert_eq!(
+        root.create_device("file", FileType::File, 0o666, 1, 3)
+            .err(),
+        Some(FsError::InvalidParam)
+    );
+
+    // served by the device inode of its number
+    let backing = root.create("backing", FileType::File, 0o666)?;
+    backing.write_at(0, b"data")?;
+    sfs.new_device_inode(make_rdev(259, 0x12345), backing);
+    let mut buf = [0u8; 4];
+    assert_eq!(disk.read_at(0, &mut buf), Ok(4));
+    assert_eq!(&buf, b"data");
+    assert_eq!(null.read_at(0, &mut buf), Err(FsError::DeviceError));
+
+    // reload them from disk
+    drop(null);
+    drop(disk);
+    sfs.sync()?;
+    let meta = root.lookup("disk")?.metadata()?;
+    assert_eq!(meta.type_, FileType::BlockDevice);
+    assert_eq!(
+        (rdev_major(meta.rdev), rdev_minor(meta.rdev)),
+        (259, 0x12345)
+    );
+    assert_eq!(root.lookup("null")?.metadata()?.rdev, make_rdev(1, 3));
+    assert_eq!(root.lookup("backing")?.metadata()?.rdev, 0);
+    Ok(())
+}
+
+#[test]
+fn wipe_free_space() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    let data = [0xccu8; 3 * BLKSIZE];
+    file1.write_at(0, &data)?;
+    let block = file1
+        .downcast_ref::<INodeImpl>()
+        .unwrap()
+        .get_disk_block_id(2)?;
+    file1.resize(BLKSIZE + 100)?;
+    drop(file1);
+    sfs.sync()?;
+
+    let count = sfs.wipe_free_space()?;
+    assert_eq!(count, sfs.info().bfree);
+    let mut buf = [0xffu8; BLKSIZE];
+    sfs.device.read_block(block, 0, &mut buf)?;
+    assert!(buf.iter().all(|&b| b == 0), "free block is not zeroed");
+
+    // slack after the end of file
+    let file1 = root.lookup("file1")?;
+    let inode = file1.downcast_ref::<INodeImpl>().unwrap();
+    sfs.device
+        .read_block(inode.get_disk_block_id(1)?, 0, &mut buf)?;
+    assert!(buf[..100].iter().all(|&b| b == 0xcc));
+    assert!(buf[100..].iter().all(|&b| b == 0), "slack is not zeroed");
+    Ok(())
+}
+
+#[test]
+fn zero_on_free() -> Result<()> {
+    let sfs = _create_new_sfs();
+    sfs.set_zero_on_free(true);
+    let root = sfs.root_inode();
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    file1.write_at(0, &[0xccu8; 2 * BLKSIZE])?;
+    let inode = file1.downcast_ref::<INodeImpl>().unwrap();
+    let block = inode.get_disk_block_id(1)?;
+    file1.resize(BLKSIZE / 2)?;
+    // released by the sync after the inode is written
+    sfs.sync()?;
+
+    let mut buf = [0xffu8; BLKSIZE];
+    sfs.device.read_block(block, 0, &mut buf)?;
+    assert!(buf.iter().all(|&b| b == 0), "freed block is not zeroed");
+    sfs.device
+        .read_block(inode.get_disk_block_id(0)?, 0, &mut buf)?;
+    assert!(
+        buf[BLKSIZE / 2..].iter().all(|&b| b == 0),
+        "slack is not zeroed"
+    );
+    Ok(())
+}
+
+#[test]
+fn snapshot_stats() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let before = sfs.snapshot_stats();
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    file1.write_at(0, &[1; 2 * BLKSIZE])?;
+    let stats = sfs.snapshot_stats();
+    // inode block and two data blocks
+    assert_eq!(stats.blocks_allocated - before.blocks_allocated, 3);
+    assert_eq!(stats.blocks_freed, before.blocks_freed);
+
+    file1.resize(0)?;
+    let stats = sfs.snapshot_stats();
+    assert_eq!(stats.blocks_freed - before.blocks_freed, 2);
+    Ok(())
+}
+
+#[test]
+fn sync_recursive() -> Result<()> {
+    let file = tempfile::tempfile().expect("failed to create file");
+    let device = Mutex::new(file.try_clone().expect("failed to clone file"));
+    let sfs = SimpleFileSystem::create(Arc::new(device), 32 * 4096 * 4096)?;
+    let root = sfs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    let file1 = dir.create("file1", FileType::File, 0o777)?;
+    file1.write_at(0, b"hello")?;
+    dir.sync_recursive()?;
+
+    // another instance sees the subtree without a full sync
+    let device = Mutex::new(file.try_clone().expect("failed to clone file"));
+    let sfs2 = SimpleFileSystem::open(Arc::new(device))?;
+    let file2 = sfs2.root_inode().lookup("dir/file1")?;
+    let mut buf = [0u8; 5];
+    assert_eq!(file2.read_at(0, &mut buf)?, 5);
+    assert_eq!(&buf, b"hello");
+    Ok(())
+}
+
+#[test]
+fn sync_recursive_file() -> Result<()> {
+    let file = tempfile::tempfile().expect("failed to create file");
+    let device = Mutex::new(file.try_clone().expect("failed to clone file"));
+    let sfs = SimpleFileSystem::create(Arc::new(device), 32 * 4096 * 4096)?;
+    let root = sfs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    let sub = dir.create("sub", FileType::Dir, 0o777)?;
+    let other = root.create("other", FileType::Dir, 0o777)?;
+    let file1 = sub.create("file1", FileType::File, 0o777)?;
+    file1.write_at(0, b"hello")?;
+    // in two dirs, both of which are synced with the dirs containing them
+    other.link("file2", &file1)?;
+    let dirty = |inode: &Arc<dyn INode>| {
+        let inode = inode.downcast_ref::<INodeImpl>().unwrap();
+        let dirty = inode.disk_inode.read().dirty();
+        dirty
+    };
+    let changed = Timespec { sec: 1234, nsec: 0 };
+    for inode in [&dir, &sub, &other].iter() {
+        let mut metadata = inode.metadata()?;
+        metadata.mtime = changed;
+        inode.set_metadata(&metadata)?;
+        assert!(dirty(inode));
+    }
+    file1.sync_recursive()?;
+    for inode in [&root, &dir, &sub, &other, &file1].iter() {
+        assert!(!dirty(inode));
+    }
+
+    // another instance sees both links without a full sync
+    let device = Mutex::new(file.try_clone().expect("failed to clone file"));
+    let sfs2 = SimpleFileSystem::open(Arc::new(device))?;
+    let root2 = sfs2.root_inode();
+    for path in ["dir", "dir/sub", "other"].iter() {
+        assert_eq!(root2.lookup(path)?.metadata()?.mtime, changed);
+    }
+    for path in ["dir/sub/file1", "other/file2"].iter() {
+        let file2 = root2.lookup(path)?;
+        let mut buf = [0u8; 5];
+        assert_eq!(file2.read_at(0, &mut buf)?, 5);
+        assert_eq!(&buf, b"hello");
+    }
+    Ok(())
+}
+
+#[test]
+fn move_path_across_fs() -> Result<()> {
+    let sfs1 = _create_new_sfs();
+    let sfs2 = _create_new_sfs();
+    let root1 = sfs1.root_inode();
+    let root2 = sfs2.root_inode();
+    let dir = root1.create("dir", FileType::Dir, 0o777)?;
+    let file1 = dir.create("file1", FileType::File, 0o777)?;
+    file1.write_at(0, &[0xcc; 5000])?;
+    let mut meta = file1.metadata()?;
+    meta.mtime = Timespec { sec: 42, nsec: 0 };
+    file1.set_metadata(&meta)?;
+    let symlink = dir.create("symlink", FileType::SymLink, 0o777)?;
+    symlink.write_at(0, b"file1")?;
+    drop(file1);
+    drop(symlink);
+    drop(dir);
+
+    vfs::move_path(&root1, "dir", &root2, "dir2")?;
+    assert!(root1.find("dir").is_err());
+    let file2 = root2.lookup("dir2/file1")?;
+    assert_eq!(file2.metadata()?.size, 5000);
+    assert_eq!(file2.metadata()?.mtime.sec, 42);
+    let mut buf = [0u8; 5000];
+    file2.read_at(0, &mut buf)?;
+    assert!(buf.iter().all(|&b| b == 0xcc));
+    let symlink = root2.lookup("dir2/symlink")?;
+    assert_eq!(symlink.metadata()?.type_, FileType::SymLink);
+    assert_eq!(symlink.read_at(0, &mut buf)?, 5);
+    assert_eq!(&buf[..5], b"file1");
+
+    // an existing destination is not replaced
+    root1.create("file3", FileType::File, 0o777)?;
+    root2.create("file3", FileType::File, 0o777)?;
+    assert_eq!(
+        vfs::move_path(&root1, "file3", &root2, "file3"),
+        Err(FsError::EntryExist)
+    );
+    assert!(root1.find("file3").is_ok());
+
+    // what is copied of a tree is removed again if it does not fit
+    let file = tempfile::tempfile().expect("failed to create file");
+    let sfs3 = SimpleFileSystem::create(Arc::new(Mutex::new(file)), 64 * BLKSIZE)?;
+    let root3 = sfs3.root_inode();
+    let free = sfs3.info().bfree;
+    let dir = root1.create("dir", FileType::Dir, 0o777)?;
+    let mut inner = dir;
+    for depth in 0..8 {
+        inner = inner.create(&format!("dir{}", depth), FileType::Dir, 0o777)?;
+    }
+    inner
+        .create("big", FileType::File, 0o777)?
+        .resize(128 * BLKSIZE)?;
+    drop(inner);
+    assert_eq!(
+        vfs::move_path(&root1, "dir", &root3, "dir"),
+        Err(FsError::NoDeviceSpace)
+    );
+    assert!(root3.find("dir").is_err());
+    assert_eq!(sfs3.info().bfree, free);
+    assert!(root1
+        .lookup("dir/dir0/dir1/dir2/dir3/dir4/dir5/dir6/dir7/big")
+        .is_ok());
+    Ok(())
+}
+
+#[test]
+fn inode_table() -> Result<()> {
+    let file = tempfile::tempfile().expect("failed to create file");
+    // 64 blocks with a block of 16 inodes after freemap
+    let sfs = SimpleFileSystem::create_with_inode_ratio(
+        Arc::new(Mutex::new(file)),
+        64 * BLKSIZE,
+        4 * BLKSIZE,
+    )?;
+    let table_begin = (BLKN_FREEMAP + 1) * INODES_PER_BLOCK;
+    let root = sfs.root_inode();
+    assert_eq!(sfs.info().files, 17);
+    for i in 0..16 {
+        let file = root.create(&format!("file{}", i), FileType::File, 0o777)?;
+        file.write_at(0, &[0xcc; 5000])?;
+        assert_eq!(file.metadata()?.inode, table_begin + i);
+    }
+    assert_eq!(sfs.info().ffree, 0);
+    assert_eq!(
+        root.create("file16", FileType::File, 0o777).err(),
+        Some(FsError::NoDeviceSpace)
+    );
+    let mut ids = vec![BLKN_ROOT];
+    ids.extend(table_begin..table_begin + 16);
+    assert_eq!(sfs.inode_ids(), Some(ids));
+
+    root.unlink("file1")?;
+    assert_eq!(sfs.info().ffree, 1);
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    assert_eq!(dir.metadata()?.inode, table_begin + 1);
+    Ok(())
+}
+
+/// Record the ranges trimmed
+struct TrimDevice {
+    file: Mutex<std::fs::File>,
+    trimmed: Mutex<Vec<Range<usize>>>,
+}
+
+impl Device for TrimDevice {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> rcore_fs::dev::Result<usize> {
+        self.file.read_at(offset, buf)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> rcore_fs::dev::Result<usize> {
+        self.file.write_at(offset, buf)
+    }
+    fn sync(&self) -> rcore_fs::dev::Result<()> {
+        self.file.sync()
+    }
+    fn trim(&self, range: Range<usize>) -> rcore_fs::dev::Result<()> {
+        self.trimmed.lock().unwrap().push(range);
+        Ok(())
+    }
+}
+
+#[test]
+fn trim_freed_blocks() -> Result<()> {
+    let device = Arc::new(TrimDevice {
+        file: Mutex::new(tempfile::tempfile().expect("failed to create file")),
+        trimmed: Mutex::new(Vec::new()),
+    });
+    let sfs = SimpleFileSystem::create(device.clone(), 32 * 4096 * 4096)?;
+    let root = sfs.root_inode();
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    file1.write_at(0, &[1; 3 * BLKSIZE])?;
+    let inode = file1.downcast_ref::<INodeImpl>().unwrap();
+    let blocks: Vec<_> = (0..3)
+        .map(|i| inode.get_disk_block_id(i).unwrap())
+        .collect();
+    file1.resize(BLKSIZE)?;
+    // a freed block is not allocated again before the sync
+    file1.write_at(BLKSIZE, &[1; BLKSIZE])?;
+    assert_ne!(inode.get_disk_block_id(1)?, blocks[1]);
+    assert!(device.trimmed.lock().unwrap().is_empty());
+
+    sfs.sync()?;
+    assert_eq!(
+        *device.trimmed.lock().unwrap(),
+        vec![blocks[1] * BLKSIZE..(blocks[2] + 1) * BLKSIZE]
+    );
+    Ok(())
+}
+
+#[test]
+fn dir_defaults() -> Result<()> {
+    let file = tempfile::tempfile().expect("failed to create file");
+    let device = Mutex::new(file.try_clone().expect("failed to clone file"));
+    let sfs = SimpleFileSystem::create(Arc::new(device), 32 * 4096 * 4096)?;
+    let root = sfs.root_inode();
+    let project = root.create("project", FileType::Dir, 0o775)?;
+    let mut metadata = project.metadata()?;
+    metadata.mode |= vfs::S_ISGID;
+    metadata.gid = 100;
+    project.set_metadata(&metadata)?;
+    project.set_dir_defaults(vfs::DirDefaults {
+        umask: 0o027,
+        ..vfs::DirDefaults::default()
+    })?;
+
+    let dir = project.create("dir", FileType::Dir, 0o777)?;
+    let file1 = dir.create("file1", FileType::File, 0o666)?;
+    sfs.sync()?;
+    drop((root, project, dir, file1));
+    drop(sfs);
+
+    let sfs = SimpleFileSystem::open(Arc::new(Mutex::new(file)))?;
+    let dir = sfs.root_inode().lookup("project/dir")?;
+    let metadata = dir.metadata()?;
+    assert_eq!((metadata.mode, metadata.gid), (0o2750, 100));
+    assert_eq!(dir.dir_defaults()?.umask, 0o027);
+    let metadata = dir.find("file1")?.metadata()?;
+    assert_eq!((metadata.mode, metadata.gid), (0o640, 100));
+    // not inherited without the set-group-ID bit
+    let metadata = sfs
+        .root_inode()
+        .create("file2", FileType::File, 0o666)?
+        .metadata()?;
+    assert_eq!((metadata.mode, metadata.gid), (0o666, 0));
+    Ok(())
+}
+
+#[test]
+fn sector_size() -> Result<()> {
+    use rcore_fs::dev::{cached::CachedBlockDevice, eviction::EvictionPolicy};
+    let file = tempfile::tempfile().expect("failed to create file");
+    // a 8K sector is larger than a block
+    let device = Mutex::new(file.try_clone().expect("failed to clone file"));
+    let device = CachedBlockDevice::new(device, 13, 16, EvictionPolicy::Lru);
+    let sfs = SimpleFileSystem::create(Arc::new(device), 32 * 4096 * 4096);
+    assert_eq!(sfs.err(), Some(FsError::InvalidParam));
+
+    let device = CachedBlockDevice::new(Mutex::new(file), 12, 16, EvictionPolicy::Lru);
+    let sfs = SimpleFileSystem::create(Arc::new(device), 32 * 4096 * 4096)?;
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn rollback() -> Result<()> {
+    use rcore_fs::dev::std_impl::MemDevice;
+    let device = Arc::new(MemDevice::with_size(32 * 4096 * 4096));
+    let sfs = SimpleFileSystem::create(device.clone(), 32 * 4096 * 4096)?;
+    sfs.root_inode().create("kept", FileType::File, 0o777)?;
+    sfs.sync()?;
+    drop(sfs);
+    let snapshot = device.snapshot();
+
+    let sfs = SimpleFileSystem::open(device.clone())?;
+    let root = sfs.root_inode();
+    root.unlink("kept")?;
+    root.create("lost", FileType::File, 0o777)?;
+    sfs.sync()?;
+    drop(sfs);
+
+    device.restore(&snapshot);
+    let sfs = SimpleFileSystem::open(device)?;
+    let root = sfs.root_inode();
+    assert!(root.find("kept").is_ok());
+    assert_eq!(root.find("lost").err(), Some(FsError::EntryNotFound));
+    Ok(())
+}
+
+/// A device which loses writes after `left` more writes, as if it crashed
+struct CrashDevice {
+    device: rcore_fs::dev::std_impl::MemDevice<rcore_fs::dev::std_impl::HeapAllocator>,
+    left: Mutex<usize>,
+}
+
+impl Device for CrashDevice {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> rcore_fs::dev::Result<usize> {
+        self.device.read_at(offset, buf)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> rcore_fs::dev::Result<usize> {
+        let mut left = self.left.lock().unwrap();
+        if *left == 0 {
+            return Ok(buf.len());
+        }
+        *left -= 1;
+        self.device.write_at(offset, buf)
+    }
+    fn sync(&self) -> rcore_fs::dev::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn journal() -> Result<()> {
+    use rcore_fs::dev::std_impl::{MemDevice, StdTimeProvider};
+    let device = Arc::new(CrashDevice {
+        device: MemDevice::with_size(4096 * 4096),
+        left: Mutex::new(usize::max_value()),
+    });
+    let sfs = SimpleFileSystem::create_with_journal(
+        device.clone(),
+        4096 * 4096,
+        0,
+        64,
+        &StdTimeProvider,
+    )?;
+    let kept = sfs.root_inode().create("kept", FileType::File, 0o777)?;
+    kept.write_at(0, &[1; 3 * BLKSIZE])?;
+    drop(kept);
+    drop(sfs);
+    let snapshot = device.device.snapshot();
+
+    let mut buf = [0u8; BLKSIZE];
+    for writes in 0..8 {
+        device.device.restore(&snapshot);
+        let sfs = SimpleFileSystem::open(device.clone())?;
+        let root = sfs.root_inode();
+        root.unlink("kept")?;
+        let new = root.create("new", FileType::File, 0o777)?;
+        new.write_at(0, &[2; 3 * BLKSIZE])?;
+        drop(new);
+        // crash after some writes of the sync
+        *device.left.lock().unwrap() = writes;
+        sfs.sync()?;
+        drop(root);
+        drop(sfs);
+        *device.left.lock().unwrap() = usize::max_value();
+
+        let sfs = SimpleFileSystem::open(device.clone())?;
+        let root = sfs.root_inode();
+        // committed once the journal and its header are written
+        let (name, lost, byte) = match writes >= 2 {
+            true => ("new", "kept", 2),
+            false => ("kept", "new", 1),
+        };
+        root.find(name)?.read_at(2 * BLKSIZE, &mut buf)?;
+        assert_eq!(
+            buf[..],
+            [byte; BLKSIZE][..],
+            "crash after {} writes",
+            writes
+        );
+        assert_eq!(root.find(lost).err(), Some(FsError::EntryNotFound));
+        let free_map = sfs.free_map.read();
+        let free = (0..free_map.len()).filter(|&id| free_map[id]).count();
+        assert_eq!(free, sfs.super_block.read().unused_blocks());
+    }
+    Ok(())
+}
+
+/// Record the number of buffers of each vectored read and write
+struct VectoredDevice {
+    file: Mutex<std::fs::File>,
+    reads: Mutex<Vec<usize>>,
+    writes: Mutex<Vec<usize>>,
+}
+
+impl Device for VectoredDevice {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> rcore_fs::dev::Result<usize> {
+        self.file.read_at(offset, buf)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> rcore_fs::dev::Result<usize> {
+        self.file.write_at(offset, buf)
+    }
+    fn sync(&self) -> rcore_fs::dev::Result<()> {
+        self.file.sync()
+    }
+    fn read_vectored(&self, offset: usize, bufs: &mut [&mut [u8]]) -> rcore_fs::dev::Result<usize> {
+        self.reads.lock().unwrap().push(bufs.len());
+        self.file.read_vectored(offset, bufs)
+    }
+    fn write_vectored(&self, offset: usize, bufs: &[&[u8]]) -> rcore_fs::dev::Result<usize> {
+        self.writes.lock().unwrap().push(bufs.len());
+        self.file.write_vectored(offset, bufs)
+    }
+}
+
+#[test]
+fn vectored_io() -> Result<()> {
+    let device = Arc::new(VectoredDevice {
+        file: Mutex::new(tempfile::tempfile().expect("failed to create file")),
+        reads: Mutex::new(Vec::new()),
+        writes: Mutex::new(Vec::new()),
+    });
+    let sfs = SimpleFileSystem::create(device.clone(), 32 * 4096 * 4096)?;
+    let file = sfs.root_inode().create("file", FileType::File, 0o777)?;
+    let data: Vec<u8> = (0..3 * BLKSIZE).map(|i| (i % 251) as u8).collect();
+    device.writes.lock().unwrap().clear();
+    file.write_at(0, &data)?;
+    // the blocks are allocated when written, next to each other, so they are written at once
+    assert_eq!(*device.writes.lock().unwrap(), [1]);
+
+    device.reads.lock().unwrap().clear();
+    let mut buf = vec![0u8; data.len() - 100];
+    assert_eq!(file.read_at(50, &mut buf)?, buf.len());
+    assert_eq!(&buf[..], &data[50..data.len() - 50]);
+    assert_eq!(*device.reads.lock().unwrap(), [1]);
+    Ok(())
+}
+
+/// A clock set by the test
+struct FakeClock(std::sync::atomic::AtomicI64);
+
+impl TimeProvider for FakeClock {
+    fn current_time(&self) -> Timespec {
+        Timespec {
+            sec: self.0.load(std::sync::atomic::Ordering::SeqCst),
+            nsec: 0,
+        }
+    }
+}
+
+#[test]
+fn timestamps() -> Result<()> {
+    static CLOCK: FakeClock = FakeClock(std::sync::atomic::AtomicI64::new(100));
+    let set_time = |sec| CLOCK.0.store(sec, std::sync::atomic::Ordering::SeqCst);
+    let file = tempfile::tempfile().expect("failed to create file");
+    let device = Arc::new(Mutex::new(file));
+    let sfs = SimpleFileSystem::create_with_time(device.clone(), 32 * 4096 * 4096, 0, &CLOCK)?;
+    let root = sfs.root_inode();
+    assert_eq!(root.metadata()?.ctime.sec, 100);
+
+    set_time(200);
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    let meta = file1.metadata()?;
+    assert_eq!(
+        (meta.atime.sec, meta.mtime.sec, meta.ctime.sec),
+        (200, 200, 200)
+    );
+    assert_eq!(root.metadata()?.mtime.sec, 200);
+
+    set_time(300);
+    file1.write_at(0, b"hello")?;
+    assert_eq!(file1.metadata()?.mtime.sec, 300);
+    set_time(400);
+    root.link("file2", &file1)?;
+    let meta = file1.metadata()?;
+    assert_eq!((meta.mtime.sec, meta.ctime.sec), (300, 400));
+    assert_eq!(root.metadata()?.mtime.sec, 400);
+    sfs.sync()?;
+    drop(file1);
+    drop(root);
+    drop(sfs);
+
+    // kept on disk
+    let sfs = SimpleFileSystem::open(device)?;
+    let meta = sfs.root_inode().lookup("file1")?.metadata()?;
+    assert_eq!(
+        (meta.atime.sec, meta.mtime.sec, meta.ctime.sec),
+        (200, 300, 400)
+    );
+    Ok(())
+}
+
+#[test]
+fn layout_version() -> Result<()> {
+    let file = tempfile::tempfile().expect("failed to create file");
+    let device = Arc::new(Mutex::new(file));
+    let sfs = SimpleFileSystem::create(device.clone(), 32 * 4096 * 4096)?;
+    assert_eq!(sfs.super_block.read().version, VERSION);
+    let mut disk_inode = DiskINode::new_file();
+    disk_inode.set_size(MAX_FILE_SIZE + 5);
+    assert_eq!((disk_inode.size, disk_inode.size_hi), (4, 1));
+    assert_eq!(disk_inode.size(), MAX_FILE_SIZE + 5);
+    let file1 = sfs.root_inode().create("file1", FileType::File, 0o777)?;
+    // beyond triple indirect blocks
+    assert_eq!(
+        file1.resize(sfs.block_map().max_triple_indirect() * BLKSIZE + 1),
+        Err(FsError::InvalidParam)
+    );
+
+    // an image made before the version is kept
+    sfs.super_block.write().version = 0;
+    sfs.sync()?;
+    drop(file1);
+    drop(sfs);
+    let sfs = SimpleFileSystem::open(device.clone())?;
+    let file1 = sfs.root_inode().lookup("file1")?;
+    assert_eq!(file1.resize(MAX_FILE_SIZE + 1), Err(FsError::InvalidParam));
+    file1.write_at(0, b"hello")?;
+
+    // a later version is not opened
+    sfs.super_block.write().version = VERSION + 1;
+    sfs.sync()?;
+    drop(file1);
+    drop(sfs);
+    assert!(SimpleFileSystem::open(device).err() == Some(FsError::NotSupported));
+    Ok(())
+}
+
+#[test]
+fn large_image() -> Result<()> {
+    use crate::fsck::check;
+    use rcore_fs::dev::std_impl::StdTimeProvider;
+    let file = tempfile::tempfile().expect("failed to create file");
+    let device = Arc::new(Mutex::new(file));
+    // block ids are u32 in the journal
+    assert_eq!(
+        SimpleFileSystem::create_with_journal(
+            device.clone(),
+            (MAX_BLOCKS + 1) * BLKSIZE,
+            0,
+            8,
+            &StdTimeProvider,
+        )
+        .err(),
+        Some(FsError::InvalidParam)
+    );
+    // sparse, only the metadata is written
+    let space = 5 << 30;
+    let sfs = SimpleFileSystem::create(device.clone(), space)?;
+    sfs.root_inode()
+        .create("file1", FileType::File, 0o777)?
+        .write_at(0, b"hello")?;
+
+    // block counts are u64, but only u32 in an image made before the version
+    {
+        let mut super_block = sfs.super_block.write();
+        let unused = super_block.unused_blocks();
+        super_block.set_unused_blocks(1 << 33);
+        assert_eq!(
+            (super_block.unused_blocks, super_block.unused_blocks_hi),
+            (0, 2)
+        );
+        assert_eq!(super_block.unused_blocks(), 1 << 33);
+        super_block.version = VERSION_RESERVED;
+        assert_eq!(super_block.unused_blocks(), 0);
+        super_block.version = VERSION;
+        super_block.set_unused_blocks(unused);
+    }
+
+    // a super block of more than 2^32 blocks, whose block ids are u64
+    {
+        let mut super_block = sfs.super_block.write();
+        let (blocks, reserved) = (super_block.blocks(), super_block.reserved_blocks());
+        super_block.set_blocks((1 << 33) + 5);
+        super_block.set_reserved_blocks((1 << 32) + 1);
+        super_block.set_freemap_ext((1 << 32) + 7..(1 << 32) + 9);
+        assert_eq!(super_block.blocks(), (1 << 33) + 5);
+        assert_eq!(super_block.reserved_blocks(), (1 << 32) + 1);
+        assert_eq!(
+            (super_block.reserved_blocks, super_block.reserved_blocks_hi),
+            (1, 1)
+        );
+        assert_eq!(super_block.freemap_ext(), (1 << 32) + 7..(1 << 32) + 9);
+        assert_eq!(
+            (super_block.freemap_ext, super_block.freemap_ext_hi),
+            (7, 1)
+        );
+        assert_eq!(
+            super_block.freemap_len(),
+            super_block.freemap_blocks as usize + 2
+        );
+        assert_eq!(
+            super_block.freemap_block(super_block.freemap_len() - 1),
+            (1 << 32) + 8
+        );
+        assert_eq!(super_block.max_blocks(), MAX_BLOCKS64);
+        // only the low 32 bits in an image made before the version
+        super_block.version = VERSION_REFLINK;
+        assert_eq!(super_block.reserved_blocks(), 1);
+        assert_eq!(super_block.freemap_ext(), 7..9);
+        assert_eq!(super_block.max_blocks(), MAX_BLOCKS);
+        super_block.version = VERSION;
+        super_block.set_blocks(blocks);
+        super_block.set_reserved_blocks(reserved);
+        super_block.set_freemap_ext(0..0);
+    }
+    // an image made before the version can not grow past them
+    sfs.super_block.write().version = VERSION_REFLINK;
+    assert_eq!(
+        sfs.resize((MAX_BLOCKS + 1) * BLKSIZE),
+        Err(FsError::InvalidParam)
+    );
+    sfs.super_block.write().version = VERSION;
+    sfs.sync()?;
+    drop(sfs);
+    let sfs = SimpleFileSystem::open(device)?;
+    assert_eq!(sfs.info().blocks, space / BLKSIZE);
+    let mut buf = [0u8; 5];
+    sfs.root_inode().lookup("file1")?.read_at(0, &mut buf)?;
+    assert_eq!(&buf, b"hello");
+    assert_eq!(check(&sfs, false)?.problems, []);
+    Ok(())
+}
+
+#[test]
+fn block_ids64() -> Result<()> {
+    use crate::fsck::check;
+    let file = tempfile::tempfile().expect("failed to create file");
+    let device = Arc::new(Mutex::new(file));
+    let sfs = SimpleFileSystem::create(device.clone(), 1024 * BLKSIZE)?;
+    let map = sfs.block_map();
+    assert_eq!(map.entry_size, ENTRY_SIZE64);
+    assert_eq!(
+        map.max_triple_indirect(),
+        NDIRECT + 512 + 512 * 512 + 512 * 512 * 512
+    );
+    assert_eq!(
+        BlockMap::new(VERSION_REFLINK).max_indirect(),
+        MAX_NBLOCK_INDIRECT
+    );
+    let file1 = sfs.root_inode().create("file1", FileType::File, 0o777)?;
+    // holes, but for the indirect blocks, up to the triple indirect ones
+    let blocks = map.max_double_indirect() + 2;
+    let unused = sfs.info().bfree;
+    file1.resize(blocks * BLKSIZE)?;
+    // the indirect and double indirect blocks with all the indirect blocks under it,
+    // and the triple indirect block with a double indirect and an indirect block under it
+    assert_eq!(unused - sfs.info().bfree, 2 + map.entries() + 3);
+    let inode = sfs.get_inode(file1.metadata()?.inode);
+    // a direct, an indirect, a double indirect and a triple indirect block past 2^32
+    let ids = [
+        0,
+        NDIRECT,
+        map.max_indirect() + map.entries() + 1,
+        blocks - 1,
+    ];
+    for (i, &id) in ids.iter().enumerate() {
+        inode.set_disk_block_id(id, (1 << 32) + i)?;
+    }
+    {
+        let disk_inode = inode.disk_inode.read();
+        assert_eq!((disk_inode.direct[0], disk_inode.block_ids_hi[0]), (0, 1));
+    }
+    inode.sync_all()?;
+    drop(inode);
+    drop(file1);
+    sfs.sync()?;
+    drop(sfs);
+
+    let sfs = SimpleFileSystem::open(device)?;
+    let file1 = sfs.root_inode().lookup("file1")?;
+    let inode = sfs.get_inode(file1.metadata()?.inode);
+    for (i, &id) in ids.iter().enumerate() {
+        assert_eq!(inode.get_disk_block_id(id)?, (1 << 32) + i);
+        inode.set_disk_block_id(id, 0)?;
+    }
+    drop(inode);
+    // the indirect blocks are freed as they are mapped
+    file1.resize(0)?;
+    assert_eq!(sfs.info().bfree, unused);
+    drop(file1);
+    assert_eq!(check(&sfs, false)?.problems, []);
+    Ok(())
+}
+
+#[test]
+fn reserved_blocks() -> Result<()> {
+    let file = tempfile::tempfile().expect("failed to create file");
+    let device = Arc::new(Mutex::new(file));
+    let sfs = SimpleFileSystem::create(device.clone(), 1024 * 4096)?;
+    assert_eq!(sfs.reserved_blocks(), 1024 * DEFAULT_RESERVED_PERCENT / 100);
+    sfs.set_reserved_blocks(100)?;
+    let root = sfs.root_inode();
+    let user = root.create("user", FileType::Dir, 0o755)?;
+    let mut metadata = user.metadata()?;
+    metadata.uid = 1000;
+    user.set_metadata(&metadata)?;
+    let file1 = user.create("file1", FileType::File, 0o644)?;
+    file1.set_metadata(&metadata)?;
+    let bavail = sfs.info().bavail;
+    assert_eq!(bavail, sfs.info().bfree - 100);
+
+    // a file not owned by root can not take the reserved blocks
+    let data = vec![1u8; BLKSIZE];
+    let mut written = 0;
+    while file1.write_at(written, &data).is_ok() {
+        written += BLKSIZE;
+    }
+    assert_eq!(sfs.info().bavail, 0);
+    assert_eq!(sfs.info().bfree, 100);
+    assert!(user.create("file2", FileType::File, 0o644).is_err());
+
+    // but root can
+    let file2 = root.create("file2", FileType::File, 0o644)?;
+    file2.write_at(0, &data[..10])?;
+    user.unlink("file1")?;
+    drop(file1);
+    sfs.sync()?;
+    assert!(sfs.info().bavail >= bavail - 1);
+
+    // kept in the image
+    drop(file2);
+    drop(user);
+    drop(root);
+    drop(sfs);
+    let sfs = SimpleFileSystem::open(device)?;
+    assert_eq!(sfs.reserved_blocks(), 100);
+    assert_eq!(sfs.set_reserved_blocks(1025), Err(FsError::InvalidParam));
+    Ok(())
+}
+
+#[test]
+fn writeback() -> Result<()> {
+    use rcore_fs::writeback::{Flusher, WritebackPolicy};
+    let sfs = _create_new_sfs();
+    let file1 = sfs.root_inode().create("file1", FileType::File, 0o777)?;
+    let id = file1.metadata()?.inode;
+    let size_on_disk = || sfs.load_disk_inode(id).map(|disk_inode| disk_inode.size());
+    let at = |sec| Timespec { sec, nsec: 0 };
+    let data = [1u8; 4096];
+
+    // only written back when synced
+    file1.write_at(0, &data)?;
+    assert_eq!(size_on_disk()?, 0);
+    assert!(!sfs.needs_flush(at(1000)));
+
+    // by the write passing a limit
+    sfs.set_writeback(Some(WritebackPolicy {
+        dirty_bytes: 3 * 4096,
+        ..WritebackPolicy::default()
+    }));
+    file1.write_at(4096, &data)?;
+    assert_eq!(size_on_disk()?, 0);
+    file1.write_at(8192, &data)?;
+    assert_eq!(size_on_disk()?, 3 * 4096);
+
+    // by the flusher, when a limit is passed or the writes are old enough
+    sfs.set_writeback(Some(WritebackPolicy {
+        dirty_blocks: 2,
+        max_age_ms: 30_000,
+        background: true,
+        ..WritebackPolicy::default()
+    }));
+    file1.write_at(3 * 4096, &data)?;
+    assert!(!sfs.flush_if_needed(at(0))?);
+    file1.write_at(4 * 4096, &data)?;
+    assert_eq!(size_on_disk()?, 3 * 4096);
+    assert!(sfs.flush_if_needed(at(0))?);
+    assert_eq!(size_on_disk()?, 5 * 4096);
+    file1.write_at(5 * 4096, &data)?;
+    assert!(!sfs.flush_if_needed(at(10))?);
+    assert!(!sfs.flush_if_needed(at(39))?);
+    assert!(sfs.flush_if_needed(at(40))?);
+    assert_eq!(size_on_disk()?, 6 * 4096);
+    assert!(!sfs.flush_if_needed(at(1000))?);
+    Ok(())
+}
+
+#[test]
+fn inode_flags() -> Result<()> {
+    use rcore_fs::vfs::{
+        FS_APPEND_FL, FS_IMMUTABLE_FL, FS_IOC_GETFLAGS, FS_IOC_SETFLAGS, FS_NODUMP_FL,
+    };
+    let get_flags = |inode: &Arc<dyn INode>| -> Result<u32> {
+        let mut flags = 0u32;
+        inode.io_control(FS_IOC_GETFLAGS, &mut flags as *mut u32 as usize)?;
+        Ok(flags)
+    };
+    let set_flags = |inode: &Arc<dyn INode>, flags: u32| {
+        inode.io_control(FS_IOC_SETFLAGS, &flags as *const u32 as usize)
+    };
+    let file = tempfile::tempfile().expect("failed to create file");
+    let device = Arc::new(Mutex::new(file));
+    let sfs = SimpleFileSystem::create(device.clone(), 32 * 4096 * 4096)?;
+    let root = sfs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    let file1 = dir.create("file1", FileType::File, 0o777)?;
+    file1.write_at(0, b"hello")?;
+    assert_eq!(get_flags(&file1)?, 0);
+    assert_eq!(set_flags(&file1, 0x1000), Err(FsError::InvalidParam));
+
+    // immutable
+    set_flags(&file1, FS_IMMUTABLE_FL | FS_NODUMP_FL)?;
+    assert_eq!(get_flags(&file1)?, FS_IMMUTABLE_FL | FS_NODUMP_FL);
+    let denied = Err(FsError::NotPermitted);
+    assert_eq!(file1.write_at(0, b"j").map(|_| ()), denied);
+    assert_eq!(file1.write_at(5, b"!").map(|_| ()), denied);
+    assert_eq!(file1.resize(0), denied);
+    assert_eq!(file1.set_metadata(&file1.metadata()?), denied);
+    assert_eq!(dir.unlink("file1"), denied);
+    assert_eq!(dir.move_("file1", &dir, "file2"), denied);
+    assert_eq!(root.link("file2", &file1), denied);
+    dir.create("file2", FileType::File, 0o777)?;
+    assert_eq!(dir.move_("file2", &dir, "file1"), denied);
+
+    // append-only
+    set_flags(&file1, FS_APPEND_FL)?;
+    assert_eq!(file1.write_at(0, b"j").map(|_| ()), denied);
+    assert_eq!(file1.write_at(5, b" world")?, 6);
+    assert_eq!(file1.resize(5), denied);
+    assert_eq!(dir.unlink("file1"), denied);
+
+    // a dir
+    set_flags(&dir, FS_IMMUTABLE_FL)?;
+    assert!(dir.create("file3", FileType::File, 0o777).err() == Some(FsError::NotPermitted));
+    assert_eq!(dir.unlink("file2"), denied);
+    set_flags(&dir, FS_APPEND_FL)?;
+    dir.create("file3", FileType::File, 0o777)?;
+    assert_eq!(dir.unlink("file3"), denied);
+    assert_eq!(dir.move_("file3", &root, "file3"), denied);
+
+    // kept on disk
+    drop((file1, dir, root));
+    sfs.sync()?;
+    drop(sfs);
+    let sfs = SimpleFileSystem::open(device.clone())?;
+    let dir = sfs.root_inode().find("dir")?;
+    assert_eq!(get_flags(&dir)?, FS_APPEND_FL);
+    let file1 = dir.find("file1")?;
+    assert_eq!(get_flags(&file1)?, FS_APPEND_FL);
+    set_flags(&file1, 0)?;
+    set_flags(&dir, 0)?;
+    dir.unlink("file1")?;
+
+    // an image made before flags are kept has none
+    sfs.super_block.write().features &= !FEATURE_INODE_FLAGS;
+    assert_eq!(get_flags(&dir)?, 0);
+    assert_eq!(set_flags(&dir, FS_IMMUTABLE_FL), Err(FsError::NotSupported));
+    Ok(())
+}
+
+#[test]
+fn usage() -> Result<()> {
+    for &inode_ratio in [0, 64 * 4096].iter() {
+        let file = tempfile::tempfile().expect("failed to create file");
+        let sfs = SimpleFileSystem::create_with_inode_ratio(
+            Arc::new(Mutex::new(file)),
+            32 * 4096 * 4096,
+            inode_ratio,
+        )?;
+        let root = sfs.root_inode();
+        let info = sfs.info();
+        assert_eq!(info.files - info.ffree, 1);
+        let files: Vec<_> = (0..10)
+            .map(|i| root.create(&format!("file{}", i), FileType::File, 0o777))
+            .collect::<Result<_>>()?;
+        files[0].write_at(0, &[1; 4 * BLKSIZE])?;
+        let used = sfs.info();
+        assert_eq!(used.files - used.ffree, 11);
+        if inode_ratio != 0 {
+            assert_eq!(used.files, info.files);
+        }
+
+        // free at once, but only available after a sync
+        drop(files);
+        root.unlink("file0")?;
+        let info = sfs.info();
+        assert_eq!(info.files - info.ffree, 10);
+        assert_eq!(info.bfree, used.bfree + 4 + (inode_ratio == 0) as usize);
+        assert_eq!(info.bavail, used.bavail);
+        sfs.sync()?;
+        assert_eq!(
+            sfs.info().bavail,
+            used.bavail + 4 + (inode_ratio == 0) as usize
+        );
+
+        // kept on disk
+        drop(root);
+        assert_eq!(crate::fsck::check(&sfs, false)?.problems, []);
+        assert_eq!(sfs.super_block.read().used_inodes(), Some(10));
+        sfs.super_block.write().version = VERSION_INODE_COUNT - 1;
+        assert_eq!(sfs.super_block.read().used_inodes(), None);
+        assert_eq!(crate::fsck::check(&sfs, false)?.problems, []);
+    }
+    Ok(())
+}
+
+#[test]
+fn fsck() -> Result<()> {
+    use crate::fsck::{check, Problem};
+    let file = tempfile::tempfile().expect("failed to create file");
+    let sfs = SimpleFileSystem::create_with_inode_ratio(
+        Arc::new(Mutex::new(file)),
+        32 * 4096 * 4096,
+        64 * 4096,
+    )?;
+    let (file1, orphan, block) = {
+        let root = sfs.root_inode();
+        let dir = root.create("dir", FileType::Dir, 0o777)?;
+        let file1 = dir.create("file1", FileType::File, 0o777)?;
+        file1.write_at(0, &[1; 2 * BLKSIZE])?;
+        root.link("link", &file1)?;
+        root.create("orphan", FileType::File, 0o777)?;
+        let file1 = file1.metadata()?.inode;
+        let block = sfs.get_inode(file1).get_disk_block_id(1)?;
+        (file1, root.find("orphan")?.metadata()?.inode, block)
+    };
+    let report = check(&sfs, false)?;
+    assert_eq!(report.problems, []);
+
+    {
+        let root = sfs.get_inode(BLKN_ROOT);
+        let (_, index) = root.get_file_inode_and_entry_id("orphan").unwrap();
+        root.remove_direntry(index)?;
+        root.add_direntry(&DiskEntry {
+            id: BLKN_SUPER as u32,
+            name: Str256::from("bad"),
+        })?;
+        sfs.get_inode(file1).disk_inode.write().nlinks = 5;
+    }
+    sfs.free_map.write().set(block, true);
+    sfs.super_block.write().unused_blocks -= 1;
+    let report = check(&sfs, false)?;
+    let unused = sfs.super_block.read().unused_blocks();
+    assert_eq!(
+        report.problems,
+        [
+            // in the order of names
+            Problem::BadEntry {
+                dir: BLKN_ROOT,
+                index: 2
+            },
+            Problem::OrphanINode(orphan),
+            Problem::UsedBlockFree(block),
+            // the block taken from the count is not free, but the block of the orphan is in use
+            Problem::UnusedBlocks {
+                recorded: unused,
+                actual: unused + 1,
+            },
+            Problem::LinkCount {
+                inode: file1,
+                recorded: 5,
+                actual: 2,
+            },
+            // root, dir and file1
+            Problem::UsedINodes {
+                recorded: 4,
+                actual: 3,
+            },
+        ]
+    );
+    assert!(!report.repaired);
+
+    let report = check(&sfs, true)?;
+    assert!(report.repaired);
+    assert_eq!(check(&sfs, false)?.problems, []);
+    let root = sfs.root_inode();
+    assert_eq!(root.find("bad").err(), Some(FsError::EntryNotFound));
+    let mut buf = [0u8; 2 * BLKSIZE];
+    root.find("link")?.read_at(0, &mut buf)?;
+    assert_eq!(buf[..], [1; 2 * BLKSIZE][..]);
+    assert_eq!(check(&sfs, false).err(), Some(FsError::Busy));
+    Ok(())
+}
+
+#[test]
+fn resize_fs() -> Result<()> {
+    use crate::fsck::check;
+    use rcore_fs::dev::std_impl::StdTimeProvider;
+    let file = tempfile::tempfile().expect("failed to create file");
+    let device = Arc::new(Mutex::new(file));
+    let sfs = SimpleFileSystem::create(device.clone(), 64 * BLKSIZE)?;
+    sfs.root_inode()
+        .create("file1", FileType::File, 0o777)?
+        .write_at(0, &[1; 2 * BLKSIZE])?;
+    let unused = sfs.info().bfree;
+    assert_eq!(sfs.resize(32 * BLKSIZE), Err(FsError::InvalidParam));
+
+    // covered by the freemap
+    sfs.resize(BLKBITS * BLKSIZE)?;
+    assert_eq!(sfs.info().blocks, BLKBITS);
+    assert_eq!(sfs.info().bfree, unused + BLKBITS - 64);
+    // the rest of the freemap is in the blocks added
+    sfs.resize(3 * BLKBITS * BLKSIZE)?;
+    assert_eq!(sfs.super_block.read().freemap_ext(), BLKBITS..BLKBITS + 2);
+    assert_eq!(sfs.info().bfree, unused + 3 * BLKBITS - 64 - 2);
+    assert_eq!(check(&sfs, false)?.problems, []);
+    drop(sfs);
+
+    let sfs = SimpleFileSystem::open(device.clone())?;
+    assert_eq!(sfs.info().blocks, 3 * BLKBITS);
+    // too few blocks added for the freemap
+    assert_eq!(
+        sfs.resize(3 * BLKBITS * BLKSIZE + 2 * BLKSIZE),
+        Err(FsError::NoDeviceSpace)
+    );
+    // replacing the rest of the freemap
+    sfs.resize(5 * BLKBITS * BLKSIZE)?;
+    let ext = 3 * BLKBITS..3 * BLKBITS + 4;
+    assert_eq!(sfs.super_block.read().freemap_ext(), ext);
+    assert_eq!(sfs.info().bfree, unused + 5 * BLKBITS - 64 - 4);
+    assert_eq!(check(&sfs, false)?.problems, []);
+    let mut buf = [0u8; 2 * BLKSIZE];
+    sfs.root_inode().find("file1")?.read_at(0, &mut buf)?;
+    assert_eq!(buf[..], [1; 2 * BLKSIZE][..]);
+
+    // an image made before the freemap could be extended
+    sfs.super_block.write().version = VERSION_JOURNAL;
+    assert_eq!(
+        sfs.resize(6 * BLKBITS * BLKSIZE),
+        Err(FsError::NotSupported)
+    );
+    drop(sfs);
+
+    let file = tempfile::tempfile().expect("failed to create file");
+    let sfs = SimpleFileSystem::create_with_journal(
+        Arc::new(Mutex::new(file)),
+        64 * BLKSIZE,
+        0,
+        8,
+        &StdTimeProvider,
+    )?;
+    assert_eq!(sfs.resize(128 * BLKSIZE), Err(FsError::NotSupported));
+    Ok(())
+}
+
+#[test]
+fn sorted_dirs() -> Result<()> {
+    use crate::fsck::{check, Problem};
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    assert!(sfs.super_block.read().sorted_dirs());
+    for i in (0..100).map(|i| i * 37 % 100) {
+        root.create(&format!("file{:02}", i), FileType::File, 0o777)?;
+    }
+    let names = |dir: &Arc<dyn INode>| -> Vec<String> {
+        (2..)
+            .map(|i| dir.get_entry(i))
+            .take_while(|entry| entry.is_ok())
+            .map(|entry| entry.unwrap())
+            .collect()
+    };
+    let mut expected: Vec<_> = (0..100).map(|i| format!("file{:02}", i)).collect();
+    assert_eq!(names(&root), expected);
+    assert_eq!(
+        root.create("file42", FileType::File, 0o777).err(),
+        Some(FsError::EntryExist)
+    );
+    assert!(root.find("file42").is_ok());
+    assert_eq!(root.find("file100").err(), Some(FsError::EntryNotFound));
+    assert_eq!(root.find("..")?.metadata()?.inode, BLKN_ROOT);
+
+    root.unlink("file00")?;
+    root.move_("file50", &root, "a")?;
+    root.move_("file51", &root, "z")?;
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    root.move_("file52", &dir, "file52")?;
+    expected.retain(|name| !["file00", "file50", "file51", "file52"].contains(&name.as_str()));
+    expected.insert(0, String::from("a"));
+    expected.insert(1, String::from("dir"));
+    expected.push(String::from("z"));
+    assert_eq!(names(&root), expected);
+    assert!(root.find("z")?.metadata()?.inode > 0);
+    drop(dir);
+    drop(root);
+    assert_eq!(check(&sfs, false)?.problems, []);
+
+    // out of order, so some can not be found
+    {
+        let root = sfs.get_inode(BLKN_ROOT);
+        let first = root.read_direntry(2)?;
+        let last = root.read_direntry(expected.len() + 1)?;
+        root.write_direntry(2, &last)?;
+        root.write_direntry(expected.len() + 1, &first)?;
+    }
+    let report = check(&sfs, true)?;
+    assert_eq!(report.problems, [Problem::UnsortedDir(BLKN_ROOT)]);
+    assert!(report.repaired);
+    assert_eq!(names(&sfs.root_inode()), expected);
+    Ok(())
+}
+
+#[test]
+fn contiguous_files() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    let file2 = root.create("file2", FileType::File, 0o777)?;
+    // growing at once
+    for i in 0..8 {
+        file1.write_at(i * BLKSIZE, &[1; BLKSIZE])?;
+        file2.write_at(i * BLKSIZE, &[2; BLKSIZE])?;
+    }
+    let blocks = |file: &Arc<dyn INode>| -> Vec<BlockId> {
+        let inode = file.downcast_ref::<INodeImpl>().unwrap();
+        (0..inode.disk_inode.read().blocks as usize)
+            .map(|i| inode.get_disk_block_id(i).unwrap())
+            .collect()
+    };
+    for file in [&file1, &file2].iter() {
+        let blocks = blocks(file);
+        assert!(blocks.windows(2).all(|pair| pair[1] == pair[0] + 1));
+    }
+
+    // after a block freed before it, and after loaded again
+    file1.resize(4 * BLKSIZE)?;
+    sfs.sync()?;
+    root.create("file3", FileType::File, 0o777)?
+        .write_at(0, &[3; BLKSIZE])?;
+    let id = file1.metadata()?.inode;
+    drop(file1);
+    sfs.flush_weak_inodes();
+    let file1 = sfs.get_inode(id) as Arc<dyn INode>;
+    file1.write_at(4 * BLKSIZE, &[1; 4 * BLKSIZE])?;
+    let blocks = blocks(&file1);
+    assert!(blocks.windows(2).all(|pair| pair[1] == pair[0] + 1));
+    Ok(())
+}
+
+#[test]
+fn defragment() -> Result<()> {
+    use crate::fsck::check;
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let blocks = |file: &Arc<dyn INode>| -> Vec<BlockId> {
+        let inode = file.downcast_ref::<INodeImpl>().unwrap();
+        (0..inode.disk_inode.read().blocks as usize)
+            .map(|i| inode.get_disk_block_id(i).unwrap())
+            .collect()
+    };
+    let contiguous = |blocks: Vec<BlockId>| blocks.windows(2).all(|pair| pair[1] == pair[0] + 1);
+    // the blocks of a dir are among the inodes of its files
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    for i in 0..60 {
+        dir.create(&format!("file{}", i), FileType::File, 0o777)?
+            .write_at(0, &[i as u8; 100])?;
+    }
+    assert!(!contiguous(blocks(&dir)));
+    // a file with blocks out of order
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    for i in 0..8 {
+        file1.write_at(i * BLKSIZE, &[i as u8; BLKSIZE])?;
+    }
+    let inode = file1.downcast_ref::<INodeImpl>().unwrap();
+    let (block0, block1) = (inode.get_disk_block_id(0)?, inode.get_disk_block_id(1)?);
+    inode.set_disk_block_id(0, block1)?;
+    inode.set_disk_block_id(1, block0)?;
+    let mut content = vec![0; 8 * BLKSIZE];
+    file1.read_at(0, &mut content)?;
+    assert!(!contiguous(blocks(&file1)));
+
+    let mut calls = 0;
+    let report = sfs.defragment(|done, total| {
+        calls += 1;
+        assert_eq!(done, calls);
+        assert_eq!(total, 63);
+    })?;
+    assert_eq!(calls, 63);
+    assert_eq!(report.files, 63);
+    assert_eq!((report.moved, report.fragmented), (2, 0));
+    assert!(contiguous(blocks(&dir)));
+    assert!(contiguous(blocks(&file1)));
+    let mut buf = vec![0; 8 * BLKSIZE];
+    file1.read_at(0, &mut buf)?;
+    assert!(buf == content);
+    for i in 0..60 {
+        let mut buf = [0; 100];
+        dir.find(&format!("file{}", i))?.read_at(0, &mut buf)?;
+        assert_eq!(buf, [i as u8; 100]);
+    }
+
+    // nothing more to do
+    let report = sfs.defragment(|_, _| {})?;
+    assert_eq!((report.moved, report.blocks), (0, 0));
+    drop((root, dir, file1));
+    assert_eq!(check(&sfs, false)?.problems, []);
+    Ok(())
+}
+
+#[test]
+fn concurrent_access() -> Result<()> {
+    use crate::fsck::check;
+    use std::thread;
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let tmp = root.create("tmp", FileType::Dir, 0o777)?;
+    let files: Vec<_> = (0..4)
+        .map(|i| root.create(&format!("file{}", i), FileType::File, 0o777))
+        .collect::<Result<_>>()?;
+
+    let mut threads = Vec::new();
+    for (i, file) in files.iter().enumerate() {
+        // rewriting a file while another thread reads it
+        let writer = file.clone();
+        threads.push(thread::spawn(move || -> Result<()> {
+            for round in 0..250u8 {
+                writer.write_at(0, &[round; 3 * BLKSIZE])?;
+                if round % 5 == 0 {
+                    writer.resize(BLKSIZE)?;
+                }
+            }
+            Ok(())
+        }));
+        let reader = file.clone();
+        threads.push(thread::spawn(move || -> Result<()> {
+            let mut buf = [0u8; 3 * BLKSIZE];
+            for _ in 0..250 {
+                let len = reader.read_at(0, &mut buf)?;
+                assert!(buf[..len].iter().all(|&b| b == buf[0]));
+            }
+            Ok(())
+        }));
+        // adding and removing entries of one dir
+        let (root, tmp) = (root.clone(), tmp.clone());
+        threads.push(thread::spawn(move || -> Result<()> {
+            for k in 0..100 {
+                let name = format!("t{}_{}", i, k);
+                tmp.create(&name, FileType::File, 0o777)?
+                    .write_at(0, &[1; BLKSIZE])?;
+                let dir = tmp.create(&format!("d{}_{}", i, k), FileType::Dir, 0o777)?;
+                root.create(&name, FileType::File, 0o777)?;
+                root.move_(&name, &dir, &name)?;
+                assert!(tmp.find(&name).is_ok());
+                tmp.unlink(&name)?;
+                dir.unlink(&name)?;
+                tmp.unlink(&format!("d{}_{}", i, k))?;
+            }
+            Ok(())
+        }));
+    }
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+
+    assert_eq!(tmp.metadata()?.size, 2 * DIRENT_SIZE);
+    assert_eq!(tmp.metadata()?.nlinks, 2);
+    let mut buf = [0u8; 3 * BLKSIZE];
+    for file in files.iter() {
+        assert_eq!(file.read_at(0, &mut buf)?, 3 * BLKSIZE);
+        assert!(buf.iter().all(|&b| b == 249));
+    }
+    drop((files, tmp, root));
+    sfs.sync()?;
+    assert_eq!(check(&sfs, false)?.problems, []);
+    Ok(())
+}
+
+#[test]
+fn inline_symlinks() -> Result<()> {
+    use crate::fsck::check;
+    let file = tempfile::tempfile().expect("failed to create file");
+    let device = Arc::new(Mutex::new(file));
+    let sfs = SimpleFileSystem::create(device.clone(), 32 * 4096 * 4096)?;
+    let root = sfs.root_inode();
+    let short = root.create("short", FileType::SymLink, 0o777)?;
+    let unused = sfs.info().bfree;
+    let target = [b'x'; MAX_INLINE_SYMLINK];
+    short.resize(target.len())?;
+    short.write_at(0, &target)?;
+    assert_eq!(short.metadata()?.blocks, 0);
+    assert_eq!(sfs.info().bfree, unused);
+    let mut buf = [0u8; 2 * MAX_INLINE_SYMLINK];
+    assert_eq!(short.read_at(0, &mut buf)?, MAX_INLINE_SYMLINK);
+    assert_eq!(buf[..MAX_INLINE_SYMLINK], target[..]);
+    assert_eq!(short.read_at(10, &mut buf)?, MAX_INLINE_SYMLINK - 10);
+
+    // moved to a block when it is longer
+    short.write_at(MAX_INLINE_SYMLINK, b"y")?;
+    assert_eq!(short.metadata()?.blocks, 1);
+    assert_eq!(short.read_at(0, &mut buf)?, MAX_INLINE_SYMLINK + 1);
+    assert_eq!(buf[..MAX_INLINE_SYMLINK], target[..]);
+    assert_eq!(buf[MAX_INLINE_SYMLINK], b'y');
+
+    // followed through a target longer than a name
+    let (a, b) = ("a".repeat(200), "b".repeat(200));
+    root.create(&a, FileType::Dir, 0o777)?
+        .create(&b, FileType::Dir, 0o777)?
+        .create("file", FileType::File, 0o777)?;
+    let long = root.create("long", FileType::SymLink, 0o777)?;
+    long.write_at(0, format!("{}/{}", a, b).as_bytes())?;
+    assert!(root.lookup_follow("long/file", 1).is_ok());
+    assert_eq!(long.resize(vfs::PATH_MAX + 1), Err(FsError::InvalidParam));
+    drop((short, long, root));
+    sfs.sync()?;
+    assert_eq!(check(&sfs, false)?.problems, []);
+    drop(sfs);
+
+    let sfs = SimpleFileSystem::open(device.clone())?;
+    let mut buf = [0u8; MAX_INLINE_SYMLINK];
+    let link = sfs.root_inode().create("link", FileType::SymLink, 0o777)?;
+    link.write_at(0, b"short")?;
+    drop(link);
+    sfs.sync()?;
+    drop(sfs);
+    let sfs = SimpleFileSystem::open(device.clone())?;
+    let link = sfs.root_inode().find("link")?;
+    assert_eq!(link.read_at(0, &mut buf)?, 5);
+    assert_eq!(&buf[..5], b"short");
+    link.resize(2)?;
+    assert_eq!(link.read_at(0, &mut buf)?, 2);
+    sfs.root_inode().unlink("link")?;
+    drop(link);
+
+    // an image with a feature not known is not opened
+    sfs.super_block.write().features |= 1 << 31;
+    sfs.sync()?;
+    drop(sfs);
+    assert_eq!(
+        SimpleFileSystem::open(device).err(),
+        Some(FsError::NotSupported)
+    );
+    Ok(())
+}
+
+#[test]
+fn sparse_files() -> Result<()> {
+    use crate::fsck::check;
+    let sfs = _create_new_sfs();
+    assert!(sfs
+        .capabilities()
+        .features
+        .contains(vfs::FsFeatures::SPARSE));
+    let root = sfs.root_inode();
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    let unused = sfs.info().bfree;
+    // in the double indirect blocks
+    let offset = (sfs.block_map().max_indirect() + 5) * BLKSIZE;
+    file1.write_at(offset, b"end")?;
+    assert_eq!(file1.metadata()?.size, offset + 3);
+    assert_eq!(file1.metadata()?.blocks, 1);
+    // the block written, and the indirect, double indirect and one indirect under it
+    assert_eq!(unused - sfs.info().bfree, 4);
+    let mut buf = vec![0xffu8; 2 * BLKSIZE];
+    assert_eq!(file1.read_at(0, &mut buf)?, buf.len());
+    assert!(buf.iter().all(|&b| b == 0));
+    let mut buf = [0xffu8; 8];
+    assert_eq!(file1.read_at(offset - 5, &mut buf)?, 8);
+    assert_eq!(&buf, b"\0\0\0\0\0end");
+
+    // in the middle of a hole, the rest of the block is zeroed
+    file1.write_at(BLKSIZE + 100, b"mid")?;
+    assert_eq!(file1.metadata()?.blocks, 2);
+    let mut buf = vec![0xffu8; BLKSIZE];
+    file1.read_at(BLKSIZE, &mut buf)?;
+    assert_eq!(&buf[100..103], b"mid");
+    assert!(buf[..100].iter().chain(&buf[103..]).all(|&b| b == 0));
+
+    // grown again over blocks freed by a shrink
+    let file2 = root.create("file2", FileType::File, 0o777)?;
+    file2.write_at(0, &[1; 3 * BLKSIZE])?;
+    file2.resize(0)?;
+    file2.resize(3 * BLKSIZE)?;
+    assert_eq!(file2.metadata()?.blocks, 0);
+    let mut buf = vec![0xffu8; 3 * BLKSIZE];
+    assert_eq!(file2.read_at(0, &mut buf)?, buf.len());
+    assert!(buf.iter().all(|&b| b == 0));
+
+    file1.resize(2 * BLKSIZE)?;
+    assert_eq!(file1.metadata()?.blocks, 1);
+    drop((file1, file2, root));
+    sfs.sync()?;
+    assert_eq!(check(&sfs, false)?.problems, []);
+    Ok(())
+}
+
+#[test]
+fn pack_inodes() -> Result<()> {
+    use crate::fsck::check;
+    let file = tempfile::tempfile().expect("failed to create file");
+    let device = Arc::new(Mutex::new(file));
+    let sfs =
+        SimpleFileSystem::create_with_inode_ratio(device.clone(), 256 * BLKSIZE, BLKSIZE / 4)?;
+    // as made before inodes are packed, with an inode in each block of the table
+    sfs.super_block.write().features &= !FEATURE_PACKED_INODES;
+    let table = sfs.inode_table().unwrap();
+    let root = sfs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    assert_eq!(dir.metadata()?.inode, table.start);
+    for i in 0..20 {
+        let file = dir.create(&format!("file{}", i), FileType::File, 0o777)?;
+        file.write_at(0, &[i as u8; 100])?;
+    }
+    assert_eq!(sfs.info().ffree, table.len() - 21);
+    let unused = sfs.info().bfree;
+    drop(dir);
+    assert_eq!(sfs.pack_inodes(), Err(FsError::Busy));
+    drop(root);
+
+    sfs.pack_inodes()?;
+    assert!(sfs.super_block.read().packed_inodes());
+    assert_eq!(check(&sfs, false)?.problems, []);
+    // 21 inodes in 2 blocks
+    assert_eq!(sfs.info().bfree, unused + 19);
+    assert_eq!(sfs.info().ffree, table.len() * INODES_PER_BLOCK - 21);
+    let dir = sfs.root_inode().find("dir")?;
+    let packed = table.start * INODES_PER_BLOCK;
+    assert_eq!(dir.metadata()?.inode, packed);
+    assert_eq!(dir.find("file19")?.metadata()?.inode, packed + 20);
+    let mut buf = [0u8; 100];
+    dir.find("file19")?.read_at(0, &mut buf)?;
+    assert_eq!(buf[..], [19; 100][..]);
+
+    // a block of the table is freed with its last inode, and taken again for more
+    for i in 15..20 {
+        dir.unlink(&format!("file{}", i))?;
+    }
+    sfs.sync()?;
+    assert_eq!(sfs.info().bfree, unused + 19 + 6);
+    let file = dir.create("file20", FileType::File, 0o777)?;
+    assert_eq!(file.metadata()?.inode, packed + INODES_PER_BLOCK);
+    drop((file, dir));
+    sfs.sync()?;
+    drop(sfs);
+
+    let sfs = SimpleFileSystem::open(device)?;
+    let dir = sfs.root_inode().find("dir")?;
+    dir.find("file3")?.read_at(0, &mut buf)?;
+    assert_eq!(buf[..], [3; 100][..]);
+    drop(dir);
+    assert_eq!(check(&sfs, false)?.problems, []);
+    Ok(())
+}
+
+#[test]
+fn entries_with_metadata() -> Result<()> {
+    for &inode_ratio in [0, 64 * 4096].iter() {
+        let file = tempfile::tempfile().expect("failed to create file");
+        let sfs = SimpleFileSystem::create_with_inode_ratio(
+            Arc::new(Mutex::new(file)),
+            32 * 4096 * 4096,
+            inode_ratio,
+        )?;
+        let root = sfs.root_inode();
+        let dir = root.create("dir", FileType::Dir, 0o755)?;
+        for i in 0..40 {
+            let file = dir.create(&format!("file{}", i), FileType::File, 0o644)?;
+            file.write_at(0, &vec![1; i * 100])?;
+        }
+        dir.create("sub", FileType::Dir, 0o700)?;
+        dir.link("hard", &dir.find("file1")?)?;
+        // open, and changed since loaded
+        let open = dir.find("file2")?;
+        open.resize(12345)?;
+
+        let entries = dir.get_entries_with_metadata(0, usize::max_value())?;
+        assert_eq!(entries.len(), 44);
+        for (i, (info, name)) in entries.iter().enumerate() {
+            assert_eq!(name, &dir.get_entry(i)?);
+            assert_eq!(info, &dir.find(name)?.metadata()?);
+        }
+        let (info, _) = entries.iter().find(|(_, name)| name == "file2").unwrap();
+        assert_eq!(info.size, 12345);
+        assert_eq!(dir.get_entries_with_metadata(40, 10)?, entries[40..]);
+        assert_eq!(dir.get_entry_with_metadata(43)?, entries[43]);
+        assert_eq!(dir.get_entries_with_metadata(44, 10)?, []);
+        assert!(dir.get_entry_with_metadata(44).is_err());
+        assert!(open.get_entries_with_metadata(0, 10).is_err());
+    }
+    Ok(())
+}
+
+#[test]
+fn case_fold() -> Result<()> {
+    use crate::fsck::check;
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let dir = root.create("Dir", FileType::Dir, 0o777)?;
+    for name in ["Readme.TXT", "b", "C", "ä"].iter() {
+        dir.create(name, FileType::File, 0o777)?;
+    }
+    let clash = root.create("clash", FileType::Dir, 0o777)?;
+    clash.create("a", FileType::File, 0o777)?;
+    clash.create("A", FileType::File, 0o777)?;
+    drop((dir, clash));
+    assert_eq!(sfs.set_case_fold(Some(CaseFold::Ascii)), Err(FsError::Busy));
+    drop(root);
+    assert_eq!(
+        sfs.set_case_fold(Some(CaseFold::Ascii)),
+        Err(FsError::EntryExist)
+    );
+    assert_eq!(sfs.super_block.read().case_fold(), None);
+    sfs.root_inode().find("clash")?.unlink("A")?;
+    sfs.set_case_fold(Some(CaseFold::Ascii))?;
+    assert!(!sfs
+        .capabilities()
+        .features
+        .contains(vfs::FsFeatures::CASE_SENSITIVE));
+
+    // looked up in any case, kept as created, sorted by the folded names
+    let root = sfs.root_inode();
+    let dir = root.find("DIR")?;
+    let readme = dir.find("readme.txt")?;
+    assert_eq!(
+        dir.find("README.txt")?.metadata()?.inode,
+        readme.metadata()?.inode
+    );
+    let names: Vec<String> = (2..6).map(|i| dir.get_entry(i)).collect::<Result<_>>()?;
+    assert_eq!(names, ["b", "C", "Readme.TXT", "ä"]);
+    assert_eq!(
+        dir.create("README.txt", FileType::File, 0o777).err(),
+        Some(FsError::EntryExist)
+    );
+    assert!(dir.find("Ä").is_err());
+
+    // only the case changed in place
+    dir.move_("Readme.TXT", &dir, "README.TXT")?;
+    assert_eq!(dir.get_entry(4)?, "README.TXT");
+    assert_eq!(
+        dir.find("readme.txt")?.metadata()?.inode,
+        readme.metadata()?.inode
+    );
+    let clash = root.find("Clash")?;
+    dir.move_("readme.txt", &clash, "Readme")?;
+    assert_eq!(
+        clash.find("README")?.metadata()?.inode,
+        readme.metadata()?.inode
+    );
+    assert!(dir.find("readme.txt").is_err());
+
+    drop((root, dir, readme, clash));
+    sfs.set_case_fold(Some(CaseFold::Utf8))?;
+    let dir = sfs.root_inode().find("dir")?;
+    assert_eq!(
+        dir.find("Ä")?.metadata()?.inode,
+        dir.find("ä")?.metadata()?.inode
+    );
+    drop(dir);
+    assert_eq!(check(&sfs, false)?.problems, []);
+    sfs.set_case_fold(None)?;
+    assert!(sfs.root_inode().find("dir").is_err());
+    assert_eq!(check(&sfs, false)?.problems, []);
+    Ok(())
+}
+
+#[test]
+fn reflink() -> Result<()> {
+    use crate::fsck::{check, Problem};
+    let file = tempfile::tempfile().expect("failed to create file");
+    let device = Arc::new(Mutex::new(file));
+    let sfs = SimpleFileSystem::create(device.clone(), 32 * 4096 * 4096)?;
+    let root = sfs.root_inode();
+    let data: Vec<u8> = (0..BLKSIZE * 7 / 2).map(|i| (i % 251) as u8).collect();
+    let a = root.create("a", FileType::File, 0o777)?;
+    a.write_at(0, &data)?;
+    let read = |inode: &Arc<dyn INode>| -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; inode.metadata()?.size];
+        inode.read_at(0, &mut buf)?;
+        Ok(buf)
+    };
+
+    // all blocks shared, the last one too as it is at the end of both
+    let b = root.create("b", FileType::File, 0o777)?;
+    let free = sfs.info().bfree;
+    assert_eq!(a.copy_file_range(0, &b, 0, usize::max_value())?, data.len());
+    assert_eq!(read(&b)?, data);
+    assert_eq!(sfs.info().bfree, free);
+    assert_eq!(b.metadata()?.blocks, 4);
+
+    // copied on write
+    b.write_at(BLKSIZE + 10, b"changed")?;
+    assert_eq!(read(&a)?, data);
+    assert_eq!(&read(&b)?[BLKSIZE + 10..BLKSIZE + 17], b"changed");
+    assert_eq!(sfs.info().bfree, free - 1);
+
+    // only whole blocks at the same offset within a block are shared, the rest is copied
+    let c = root.create("c", FileType::File, 0o777)?;
+    c.write_at(0, &[1; 4 * BLKSIZE])?;
+    assert_eq!(
+        a.copy_file_range(BLKSIZE / 2, &c, BLKSIZE / 2, 2 * BLKSIZE)?,
+        2 * BLKSIZE
+    );
+    let mut expected = vec![1; 4 * BLKSIZE];
+    expected[BLKSIZE / 2..BLKSIZE * 5 / 2].copy_from_slice(&data[BLKSIZE / 2..BLKSIZE * 5 / 2]);
+    assert_eq!(read(&c)?, expected);
+    let d = root.create("d", FileType::File, 0o777)?;
+    assert_eq!(a.copy_file_range(1, &d, 0, 5000)?, 5000);
+    assert_eq!(read(&d)?, &data[1..5001]);
+    sfs.root_inode()
+        .find("d")?
+        .downcast_ref::<INodeImpl>()
+        .unwrap()
+        .reflink(&a)?;
+    assert_eq!(read(&d)?, data);
+
+    // kept on disk, and a block is only freed with the last file referring to it
+    drop((root, a, b, c, d));
+    sfs.sync()?;
+    assert_eq!(check(&sfs, false)?.problems, []);
+    drop(sfs);
+    let sfs = SimpleFileSystem::open(device)?;
+    let root = sfs.root_inode();
+    assert_eq!(read(&root.find("d")?)?, data);
+    root.unlink("a")?;
+    assert_eq!(read(&root.find("b")?)?[..BLKSIZE], data[..BLKSIZE]);
+    let block = root
+        .find("c")?
+        .downcast_ref::<INodeImpl>()
+        .unwrap()
+        .get_disk_block_id(1)?;
+    assert!(sfs.is_shared(block));
+    drop(root);
+    sfs.sync()?;
+    assert_eq!(check(&sfs, false)?.problems, []);
+
+    sfs.shared.write().insert(block, 3);
+    assert_eq!(
+        check(&sfs, true)?.problems,
+        [Problem::RefCount {
+            block,
+            recorded: 3,
+            actual: 2
+        }]
+    );
+    sfs.root_inode().unlink("d")?;
+    assert!(!sfs.is_shared(block));
+    sfs.sync()?;
+    assert_eq!(check(&sfs, false)?.problems, []);
+    Ok(())
+}
@@ -0,0 +1,240 @@
+//! Crash consistency of SFS.
+//!
+//! The writes of some operations to the device are logged, and each prefix of them is
+//! replayed on the image as it was before, as if the machine crashed there. The image
+//! left must open, fsck must find only problems it can repair, and no file which the
+//! operations did not change may be lost.
+use std::sync::{Arc, Mutex};
+
+use rcore_fs::dev::{self, Device};
+use rcore_fs::vfs::{FileSystem, FileType, INode, Result};
+use rcore_fs_sfs::fsck::{check, Problem};
+use rcore_fs_sfs::SimpleFileSystem;
+
+const SPACE: usize = 1024 * 4096;
+
+/// An image in memory, logging the writes to it
+struct LogDevice {
+    image: Mutex<Vec<u8>>,
+    log: Mutex<Vec<(usize, Vec<u8>)>>,
+}
+
+impl LogDevice {
+    fn new(image: Vec<u8>) -> Self {
+        LogDevice {
+            image: Mutex::new(image),
+            log: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl Device for LogDevice {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> dev::Result<usize> {
+        let image = self.image.lock().unwrap();
+        let end = (offset + buf.len()).min(image.len()).max(offset);
+        buf[..end - offset].copy_from_slice(&image[offset..end]);
+        Ok(end - offset)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> dev::Result<usize> {
+        let mut image = self.image.lock().unwrap();
+        image[offset..offset + buf.len()].copy_from_slice(buf);
+        self.log.lock().unwrap().push((offset, buf.to_vec()));
+        Ok(buf.len())
+    }
+    fn sync(&self) -> dev::Result<()> {
+        Ok(())
+    }
+}
+
+/// Run `op` on an image with an inode table of one inode per `inode_ratio` bytes,
+/// after `setup` and a sync, then sync and crash after each write of it, and check
+/// the files left with `expect`
+fn crash_test(
+    inode_ratio: usize,
+    setup: impl Fn(&Arc<dyn INode>) -> Result<()>,
+    op: impl Fn(&Arc<dyn INode>) -> Result<()>,
+    expect: impl Fn(&Arc<dyn INode>) -> Result<()>,
+) -> Result<()> {
+    let device = Arc::new(LogDevice::new(vec![0; SPACE]));
+    let sfs = SimpleFileSystem::create_with_inode_ratio(device.clone(), SPACE, inode_ratio)?;
+    setup(&sfs.root_inode())?;
+    sfs.sync()?;
+    let base = device.image.lock().unwrap().clone();
+    device.log.lock().unwrap().clear();
+    op(&sfs.root_inode())?;
+    sfs.sync()?;
+    let log = std::mem::replace(&mut *device.log.lock().unwrap(), Vec::new());
+
+    let mut image = base;
+    for written in 0..=log.len() {
+        if written != 0 {
+            let (offset, data) = &log[written - 1];
+            image[*offset..*offset + data.len()].copy_from_slice(data);
+        }
+        let sfs = SimpleFileSystem::open(Arc::new(LogDevice::new(image.clone())))
+            .unwrap_or_else(|e| panic!("failed to open after {} writes: {:?}", written, e));
+        let problems = check(&sfs, false)?.problems;
+        if written == log.len() {
+            assert_eq!(problems, []);
+        }
+        // a block in use free on disk could be taken twice
+        let safe = |problem: &Problem| match problem {
+            Problem::UsedBlockFree(_) => false,
+            problem => problem.can_repair(),
+        };
+        assert!(
+            problems.iter().all(safe),
+            "after {} of {} writes: {:?}",
+            written,
+            log.len(),
+            problems
+        );
+        assert!(check(&sfs, true)?.repaired);
+        assert_eq!(check(&sfs, false)?.problems, []);
+        expect(&sfs.root_inode())?;
+    }
+    Ok(())
+}
+
+/// Create `name` in `dir` with `len` bytes of the byte of its length
+fn create_file(dir: &Arc<dyn INode>, name: &str, len: usize) -> Result<()> {
+    let file = dir.create(name, FileType::File, 0o644)?;
+    file.write_at(0, &vec![name.len() as u8; len])?;
+    Ok(())
+}
+
+/// Check `name` in `dir` is as created by `create_file()`
+fn check_file(dir: &Arc<dyn INode>, name: &str, len: usize) -> Result<()> {
+    check_moved(dir, name, name.len(), len)
+}
+
+/// Check `name` in `dir` is as created by `create_file()` with a name of `name_len` bytes
+fn check_moved(dir: &Arc<dyn INode>, name: &str, name_len: usize, len: usize) -> Result<()> {
+    let file = dir.find(name)?;
+    let mut buf = vec![0; file.metadata()?.size];
+    file.read_at(0, &mut buf)?;
+    assert_eq!(buf, vec![name_len as u8; len], "{}", name);
+    Ok(())
+}
+
+/// Names of the files made by `setup_dir()`, in two blocks of entries
+fn names() -> impl Iterator<Item = String> {
+    (0..30).map(|i| format!("file{:02}", i * 2))
+}
+
+fn setup_dir(root: &Arc<dyn INode>) -> Result<()> {
+    let dir = root.create("dir", FileType::Dir, 0o755)?;
+    for name in names() {
+        create_file(&dir, &name, 100)?;
+    }
+    create_file(root, "other", 3 * 4096)
+}
+
+fn expect_dir(root: &Arc<dyn INode>, except: &[&str]) -> Result<()> {
+    let dir = root.find("dir")?;
+    for name in names().filter(|name| !except.contains(&name.as_str())) {
+        check_file(&dir, &name, 100)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn create() -> Result<()> {
+    for &inode_ratio in [0, 16 * 4096].iter() {
+        crash_test(
+            inode_ratio,
+            setup_dir,
+            |root| {
+                let dir = root.find("dir")?;
+                create_file(&dir, "file01", 5000)?;
+                create_file(&dir, "file99", 10)?;
+                let sub = dir.create("file31", FileType::Dir, 0o755)?;
+                create_file(&sub, "inner", 10)
+            },
+            |root| {
+                expect_dir(root, &[])?;
+                check_file(root, "other", 3 * 4096)
+            },
+        )?;
+    }
+    Ok(())
+}
+
+#[test]
+fn unlink() -> Result<()> {
+    for &inode_ratio in [0, 16 * 4096].iter() {
+        crash_test(
+            inode_ratio,
+            |root| {
+                setup_dir(root)?;
+                root.find("dir")?.create("sub", FileType::Dir, 0o755)?;
+                Ok(())
+            },
+            |root| {
+                let dir = root.find("dir")?;
+                dir.unlink("file02")?;
+                dir.unlink("file40")?;
+                dir.unlink("sub")?;
+                // the blocks freed are not taken by another file meanwhile
+                create_file(&dir, "new", 8 * 4096)
+            },
+            |root| {
+                expect_dir(root, &["file02", "file40"])?;
+                check_file(root, "other", 3 * 4096)
+            },
+        )?;
+    }
+    Ok(())
+}
+
+#[test]
+fn rename() -> Result<()> {
+    crash_test(
+        0,
+        |root| {
+            setup_dir(root)?;
+            let sub = root.create("sub", FileType::Dir, 0o755)?;
+            create_file(&sub, "inner", 10)
+        },
+        |root| {
+            let dir = root.find("dir")?;
+            dir.move_("file10", &dir, "file55")?;
+            dir.move_("file20", root, "moved")?;
+            root.move_("sub", &dir, "sub")
+        },
+        |root| {
+            let dir = root.find("dir")?;
+            expect_dir(root, &["file10", "file20"])?;
+            check_file(root, "other", 3 * 4096)?;
+            // in at least one of the dirs
+            check_file(&dir, "file10", 100).or_else(|_| check_file(&dir, "file55", 100))?;
+            check_file(&dir, "file20", 100).or_else(|_| check_moved(root, "moved", 6, 100))?;
+            let sub = root.find("sub").or_else(|_| dir.find("sub"))?;
+            check_file(&sub, "inner", 10)
+        },
+    )
+}
+
+#[test]
+fn truncate() -> Result<()> {
+    crash_test(
+        0,
+        setup_dir,
+        |root| {
+            // synced while it is open, so that it is written by the sync
+            let other = root.find("other")?;
+            other.resize(100)?;
+            root.fs().sync()?;
+            // not in the blocks of the old content, which may still be on disk
+            create_file(root, "new", 4 * 4096)
+        },
+        |root| {
+            expect_dir(root, &[])?;
+            let file = root.find("other")?;
+            let mut buf = vec![0; file.metadata()?.size];
+            file.read_at(0, &mut buf)?;
+            assert!(buf.iter().all(|&b| b == "other".len() as u8));
+            Ok(())
+        },
+    )
+}
@@ -0,0 +1,67 @@
+//! Log-append workload: many small sequential writes into the same blocks.
+//!
+//! `direct` does a read-modify-write on the device for every record,
+//! `cached` lets `BlockCache` coalesce them into whole-block writes.
+
+#![feature(test)]
+
+extern crate test;
+
+use rcore_fs::dev::block_cache::BlockCache;
+use rcore_fs::dev::{BlockDevice, BlockId, Device, Result};
+use std::sync::Mutex;
+use test::Bencher;
+
+const BLOCK_SIZE_LOG2: u8 = 9;
+const BLOCKS: usize = 256;
+const RECORD_SIZE: usize = 48;
+
+/// In-memory block device
+struct MemDevice(Mutex<Vec<u8>>);
+
+impl MemDevice {
+    fn new() -> Self {
+        MemDevice(Mutex::new(vec![0; BLOCKS << BLOCK_SIZE_LOG2]))
+    }
+}
+
+impl BlockDevice for MemDevice {
+    const BLOCK_SIZE_LOG2: u8 = BLOCK_SIZE_LOG2;
+    fn read_at(&self, block_id: BlockId, buf: &mut [u8]) -> Result<()> {
+        let begin = block_id << BLOCK_SIZE_LOG2;
+        let end = begin + (1 << BLOCK_SIZE_LOG2);
+        buf[..end - begin].copy_from_slice(&self.0.lock().unwrap()[begin..end]);
+        Ok(())
+    }
+    fn write_at(&self, block_id: BlockId, buf: &[u8]) -> Result<()> {
+        let begin = block_id << BLOCK_SIZE_LOG2;
+        let end = begin + (1 << BLOCK_SIZE_LOG2);
+        self.0.lock().unwrap()[begin..end].copy_from_slice(&buf[..end - begin]);
+        Ok(())
+    }
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn log_append(device: &impl Device) {
+    let record = [0x5a; RECORD_SIZE];
+    let mut offset = 0;
+    while offset + RECORD_SIZE <= BLOCKS << BLOCK_SIZE_LOG2 {
+        device.write_at(offset, &record).unwrap();
+        offset += RECORD_SIZE;
+    }
+    device.sync().unwrap();
+}
+
+#[bench]
+fn log_append_direct(b: &mut Bencher) {
+    let device = MemDevice::new();
+    b.iter(|| log_append(&device));
+}
+
+#[bench]
+fn log_append_cached(b: &mut Bencher) {
+    let device = BlockCache::new(MemDevice::new(), 16);
+    b.iter(|| log_append(&device));
+}
@@ -0,0 +1,310 @@
+//! A cache of directory entries, to speed up walking paths
+//!
+//! Entries are keyed by (parent inode id, name) inside one file system.
+//! A missing name is cached too, as a negative entry.
+//! Once full, the least recently used entry is evicted for a new one.
+//! The owner of the cache must call the `on_*` hooks after modifying a directory.
+
+use crate::vfs::{FsError, INode, Result};
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use spin::Mutex;
+
+pub struct DCache {
+    inner: Mutex<DCacheInner>,
+    capacity: usize,
+}
+
+struct DCacheInner {
+    /// `None` for a negative entry, with the tick it was last used at
+    entries: BTreeMap<(usize, String), (Option<Arc<dyn INode>>, usize)>,
+    /// Keys of `entries` by the tick they were last used at, the least recently used first
+    lru: BTreeMap<usize, (usize, String)>,
+    /// Bumped on every use of an entry
+    tick: usize,
+    /// Bumped on every invalidation, so that a slow `find()` does not insert a stale entry
+    generation: usize,
+}
+
+impl DCache {
+    /// Create a cache holding at most `capacity` entries
+    pub fn new(capacity: usize) -> Self {
+        DCache {
+            inner: Mutex::new(DCacheInner {
+                entries: BTreeMap::new(),
+                lru: BTreeMap::new(),
+                tick: 0,
+                generation: 0,
+            }),
+            capacity,
+        }
+    }
+
+    /// Find `name` in directory `dir`, through the cache.
+    /// "." and ".." are never cached.
+    pub fn find(&self, dir: &Arc<dyn INode>, name: &str) -> Result<Arc<dyn INode>> {
+        if name == "." || name == ".." {
+            return dir.find(name);
+        }
+        let dir_id = dir.metadata()?.inode;
+        let key = (dir_id, String::from(name));
+        let generation = {
+            let mut inner = self.inner.lock();
+            if let Some(entry) = inner.touch(&key) {
+                return entry.ok_or(FsError::EntryNotFound);
+            }
+            inner.generation
+        };
+        let result = dir.find(name);
+        let entry = match &result {
+            Ok(inode) => Some(inode.clone()),
+            Err(FsError::EntryNotFound) => None,
+            Err(_) => return result,
+        };
+        let mut inner = self.inner.lock();
+        if inner.generation == generation {
+            inner.insert(key, entry, self.capacity);
+        }
+        result
+    }
+
+    /// `inode` is created or linked as `name` in directory `dir_id`
+    pub fn on_create(&self, dir_id: usize, name: &str, inode: &Arc<dyn INode>) {
+        let mut inner = self.inner.lock();
+        inner.generation += 1;
+        inner.insert(
+            (dir_id, String::from(name)),
+            Some(inode.clone()),
+            self.capacity,
+        );
+    }
+
+    /// `name` of inode `inode_id` is removed from directory `dir_id`
+    pub fn on_unlink(&self, dir_id: usize, name: &str, inode_id: usize) {
+        let mut inner = self.inner.lock();
+        inner.generation += 1;
+        inner.insert((dir_id, String::from(name)), None, self.capacity);
+        // the id may be reused if it was a directory
+        let children: Vec<_> = inner
+            .entries
+            .range((inode_id, String::new())..)
+            .take_while(|((id, _), _)| *id == inode_id)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in children {
+            inner.remove(&key);
+        }
+    }
+
+    /// `old_name` in directory `old_dir_id` is moved to `new_name` in directory `new_dir_id`
+    pub fn on_rename(&self, old_dir_id: usize, old_name: &str, new_dir_id: usize, new_name: &str) {
+        let mut inner = self.inner.lock();
+        inner.generation += 1;
+        inner.insert((old_dir_id, String::from(old_name)), None, self.capacity);
+        inner.remove(&(new_dir_id, String::from(new_name)));
+    }
+
+    /// Drop all entries
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock();
+        inner.generation += 1;
+        inner.entries.clear();
+        inner.lru.clear();
+    }
+}
+
+impl DCacheInner {
+    /// The entry of `key` if cached, now the most recently used
+    fn touch(&mut self, key: &(usize, String)) -> Option<Option<Arc<dyn INode>>> {
+        let tick = self.tick;
+        let (entry, used) = self.entries.get_mut(key)?;
+        let entry = entry.clone();
+        let key = self.lru.remove(used).unwrap();
+        *used = tick;
+        self.lru.insert(tick, key);
+        self.tick += 1;
+        Some(entry)
+    }
+
+    /// Cache `entry` as `key`, evicting the least recently used one if full
+    fn insert(&mut self, key: (usize, String), entry: Option<Arc<dyn INode>>, capacity: usize) {
+        self.remove(&key);
+        if capacity == 0 {
+            return;
+        }
+        if self.entries.len() >= capacity {
+            let (&used, _) = self.lru.iter().next().unwrap();
+            let victim = self.lru.remove(&used).unwrap();
+            self.entries.remove(&victim);
+        }
+        self.entries.insert(key.clone(), (entry, self.tick));
+        self.lru.insert(self.tick, key);
+        self.tick += 1;
+    }
+
+    fn remove(&mut self, key: &(usize, String)) {
+        if let Some((_, used)) = self.entries.remove(key) {
+            self.lru.remove(&used);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::vfs::{FileType, Metadata, PollStatus, Timespec};
+    use core::any::Any;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A directory of directories, counting the calls to `find()`
+    struct Dir {
+        id: usize,
+        children: Mutex<BTreeMap<String, Arc<dyn INode>>>,
+        finds: AtomicUsize,
+    }
+
+    impl Dir {
+        fn new(id: usize) -> Arc<Self> {
+            Arc::new(Dir {
+                id,
+                children: Mutex::new(BTreeMap::new()),
+                finds: AtomicUsize::new(0),
+            })
+        }
+
+        fn finds(&self) -> usize {
+            self.finds.load(Ordering::SeqCst)
+        }
+    }
+
+    impl INode for Dir {
+        fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize> {
+            Err(FsError::IsDir)
+        }
+        fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+            Err(FsError::IsDir)
+        }
+        fn poll(&self) -> Result<PollStatus> {
+            Err(FsError::IsDir)
+        }
+        fn metadata(&self) -> Result<Metadata> {
+            let time = Timespec { sec: 0, nsec: 0 };
+            Ok(Metadata {
+                dev: 0,
+                inode: self.id,
+                size: 0,
+                blk_size: 0,
+                blocks: 0,
+                atime: time,
+                mtime: time,
+                ctime: time,
+                type_: FileType::Dir,
+                mode: 0o755,
+                nlinks: 2,
+                uid: 0,
+                gid: 0,
+                rdev: 0,
+            })
+        }
+        fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
+            self.finds.fetch_add(1, Ordering::SeqCst);
+            let children = self.children.lock();
+            children.get(name).cloned().ok_or(FsError::EntryNotFound)
+        }
+        fn as_any_ref(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    fn id(inode: &Arc<dyn INode>) -> usize {
+        inode.metadata().unwrap().inode
+    }
+
+    /// A cache of `capacity` entries, and a dir with children "a", "b" and "c"
+    fn setup(capacity: usize) -> (DCache, Arc<Dir>, Arc<dyn INode>) {
+        let dir = Dir::new(1);
+        for (i, name) in ["a", "b", "c"].iter().enumerate() {
+            let child = Dir::new(10 + i) as Arc<dyn INode>;
+            dir.children.lock().insert(String::from(*name), child);
+        }
+        let inode = dir.clone() as Arc<dyn INode>;
+        (DCache::new(capacity), dir, inode)
+    }
+
+    #[test]
+    fn hit() {
+        let (cache, dir, inode) = setup(8);
+        assert_eq!(id(&cache.find(&inode, "a").unwrap()), 10);
+        assert_eq!(id(&cache.find(&inode, "a").unwrap()), 10);
+        assert_eq!(dir.finds(), 1);
+        // but for "." and ".."
+        cache.find(&inode, ".").ok();
+        cache.find(&inode, ".").ok();
+        assert_eq!(dir.finds(), 3);
+    }
+
+    #[test]
+    fn negative_hit() {
+        let (cache, dir, inode) = setup(8);
+        assert_eq!(cache.find(&inode, "x").err(), Some(FsError::EntryNotFound));
+        assert_eq!(cache.find(&inode, "x").err(), Some(FsError::EntryNotFound));
+        assert_eq!(dir.finds(), 1);
+    }
+
+    #[test]
+    fn eviction() {
+        let (cache, dir, inode) = setup(2);
+        cache.find(&inode, "a").unwrap();
+        cache.find(&inode, "b").unwrap();
+        // "a" used last, so "b" makes room for "c"
+        cache.find(&inode, "a").unwrap();
+        cache.find(&inode, "c").unwrap();
+        assert_eq!(dir.finds(), 3);
+        cache.find(&inode, "a").unwrap();
+        cache.find(&inode, "c").unwrap();
+        assert_eq!(dir.finds(), 3);
+        cache.find(&inode, "b").unwrap();
+        assert_eq!(dir.finds(), 4);
+        // nothing kept without room
+        let (cache, dir, inode) = setup(0);
+        cache.find(&inode, "a").unwrap();
+        cache.find(&inode, "a").unwrap();
+        assert_eq!(dir.finds(), 2);
+    }
+
+    #[test]
+    fn invalidation() {
+        let (cache, dir, inode) = setup(8);
+        // created in place of a negative entry
+        assert!(cache.find(&inode, "x").is_err());
+        let x = Dir::new(20) as Arc<dyn INode>;
+        dir.children.lock().insert(String::from("x"), x.clone());
+        cache.on_create(1, "x", &x);
+        assert_eq!(id(&cache.find(&inode, "x").unwrap()), 20);
+        assert_eq!(dir.finds(), 1);
+
+        // unlinked, with the entries under it
+        let a = cache.find(&inode, "a").unwrap();
+        dir.children.lock().remove("a");
+        let a_dir = a.downcast_ref::<Dir>().unwrap();
+        a_dir
+            .children
+            .lock()
+            .insert(String::from("y"), Dir::new(30));
+        cache.find(&a, "y").unwrap();
+        cache.on_unlink(1, "a", 10);
+        assert_eq!(cache.find(&inode, "a").err(), Some(FsError::EntryNotFound));
+        assert_eq!(dir.finds(), 2);
+        cache.find(&a, "y").unwrap();
+        assert_eq!(a_dir.finds(), 2);
+
+        // renamed over a negative entry
+        cache.find(&inode, "b").unwrap();
+        assert!(cache.find(&inode, "z").is_err());
+        let b = dir.children.lock().remove("b").unwrap();
+        dir.children.lock().insert(String::from("z"), b);
+        cache.on_rename(1, "b", 1, "z");
+        assert_eq!(cache.find(&inode, "b").err(), Some(FsError::EntryNotFound));
+        assert_eq!(id(&cache.find(&inode, "z").unwrap()), 11);
+        assert_eq!(dir.finds(), 5);
+    }
+}
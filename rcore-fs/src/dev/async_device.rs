@@ -0,0 +1,141 @@
+//! Devices which complete requests asynchronously, e.g. interrupt-driven virtio-blk
+//!
+//! A request is submitted with a callback, which is called when it completes,
+//! maybe in an interrupt handler. `SyncDevice` turns such a device into a `Device`,
+//! waiting for completions in a way the kernel chooses instead of spinning.
+use super::*;
+use alloc::{boxed::Box, vec::Vec};
+use spin::Mutex;
+
+/// Called with the data read
+pub type ReadCallback = Box<dyn FnOnce(Result<Vec<u8>>) + Send>;
+/// Called with the length written, or 0 for sync
+pub type WriteCallback = Box<dyn FnOnce(Result<usize>) + Send>;
+
+/// Interface for FS to read & write without blocking
+pub trait AsyncDevice: Send + Sync {
+    /// Read `len` bytes at `offset`
+    fn submit_read(&self, offset: usize, len: usize, callback: ReadCallback);
+    /// Write `data` at `offset`
+    fn submit_write(&self, offset: usize, data: Vec<u8>, callback: WriteCallback);
+    /// Flush all completed writes to the storage
+    fn submit_sync(&self, callback: WriteCallback);
+}
+
+/// How a thread waits for the completion of a request
+pub trait Wait: Send + Sync {
+    /// Block until `done()` returns true
+    fn wait(&self, done: &dyn Fn() -> bool);
+    /// Wake up the waiters, called by the completion callback
+    fn notify(&self);
+}
+
+/// Wait by spinning, for kernels without a scheduler
+pub struct SpinWait;
+
+impl Wait for SpinWait {
+    fn wait(&self, done: &dyn Fn() -> bool) {
+        while !done() {
+            crate::util::spin_loop();
+        }
+    }
+    fn notify(&self) {}
+}
+
+/// Adapter from `AsyncDevice` to the blocking `Device`
+pub struct SyncDevice<D: AsyncDevice, W: Wait + 'static> {
+    device: D,
+    waiter: Arc<W>,
+}
+
+impl<D: AsyncDevice, W: Wait + 'static> SyncDevice<D, W> {
+    pub fn new(device: D, waiter: Arc<W>) -> Self {
+        SyncDevice { device, waiter }
+    }
+
+    /// Submit a request by `submit`, then wait for its result
+    fn block_on<T: Send + 'static>(
+        &self,
+        submit: impl FnOnce(Box<dyn FnOnce(Result<T>) + Send>),
+    ) -> Result<T> {
+        let slot = Arc::new(Mutex::new(None));
+        let slot1 = slot.clone();
+        let waiter = self.waiter.clone();
+        submit(Box::new(move |result| {
+            *slot1.lock() = Some(result);
+            waiter.notify();
+        }));
+        self.waiter.wait(&|| slot.lock().is_some());
+        let result = slot.lock().take();
+        result.unwrap()
+    }
+}
+
+impl<D: AsyncDevice, W: Wait + 'static> Device for SyncDevice<D, W> {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let data =
+            self.block_on(|callback| self.device.submit_read(offset, buf.len(), callback))?;
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        let data = buf.to_vec();
+        self.block_on(|callback| self.device.submit_write(offset, data, callback))
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.block_on(|callback| self.device.submit_sync(callback))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Complete requests in another thread, a while later
+    struct DelayedDevice {
+        data: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl AsyncDevice for DelayedDevice {
+        fn submit_read(&self, offset: usize, len: usize, callback: ReadCallback) {
+            let data = self.data.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(10));
+                callback(Ok(data.lock().unwrap()[offset..offset + len].to_vec()));
+            });
+        }
+        fn submit_write(&self, offset: usize, buf: Vec<u8>, callback: WriteCallback) {
+            let data = self.data.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(10));
+                data.lock().unwrap()[offset..offset + buf.len()].copy_from_slice(&buf);
+                callback(Ok(buf.len()));
+            });
+        }
+        fn submit_sync(&self, callback: WriteCallback) {
+            thread::spawn(move || callback(Ok(0)));
+        }
+    }
+
+    #[test]
+    fn sync_device() {
+        let device = SyncDevice::new(
+            DelayedDevice {
+                data: Arc::new(Mutex::new(vec![0; 16])),
+            },
+            Arc::new(SpinWait),
+        );
+        assert_eq!(device.write_at(4, &[1, 2, 3]), Ok(3));
+        let mut buf = [0u8; 6];
+        assert_eq!(device.read_at(2, &mut buf), Ok(6));
+        assert_eq!(buf, [0, 0, 1, 2, 3, 0]);
+        assert_eq!(device.sync(), Ok(()));
+    }
+}
@@ -0,0 +1,336 @@
+//! Bad block remapping for flaky flash, e.g. a raw NAND or a worn SD card
+//!
+//! A block which keeps failing is replaced by a spare one from a reserve area,
+//! so the FS on it sees a device without holes.
+use super::*;
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+use spin::Mutex;
+
+/// Remap blocks of `1 << block_size_log2` bytes of `device` which keep failing.
+///
+/// The device has the data blocks, then the spare blocks, then the remap table
+/// of one `u32` per spare block: 1 + the data block it replaces, or 0 if it is free.
+///
+/// A request to a block is tried `retries` times. When a write still fails,
+/// the block is remapped and written to its spare. When a read still fails,
+/// the error is returned, and the block is remapped when it is written next.
+pub struct BadBlockDevice<D: Device> {
+    device: D,
+    block_size_log2: u8,
+    /// Number of data blocks
+    blocks: usize,
+    /// Number of spare blocks
+    spares: usize,
+    retries: usize,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    /// Spare of each remapped block
+    remap: BTreeMap<BlockId, usize>,
+    /// Whether each spare is used
+    used: Vec<bool>,
+    /// Blocks failed to read, to be remapped when written
+    suspect: Vec<BlockId>,
+}
+
+impl<D: Device> BadBlockDevice<D> {
+    /// Remap blocks of `device` of `size` bytes with `spares` spare blocks,
+    /// loading the remap table on it.
+    pub fn new(
+        device: D,
+        size: usize,
+        block_size_log2: u8,
+        spares: usize,
+        retries: usize,
+    ) -> Result<Self> {
+        let table_blocks = (spares * 4 + (1 << block_size_log2) - 1) >> block_size_log2;
+        let total = size >> block_size_log2;
+        assert!(total > spares + table_blocks, "device too small");
+        let blocks = total - spares - table_blocks;
+        let mut table = vec![0u8; spares * 4];
+        let offset = (blocks + spares) << block_size_log2;
+        if device.read_at(offset, &mut table)? != table.len() {
+            return Err(DevError::Io);
+        }
+        let mut remap = BTreeMap::new();
+        let mut used = vec![false; spares];
+        for (spare, entry) in table.chunks(4).enumerate() {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(entry);
+            match u32::from_le_bytes(bytes) as usize {
+                0 => {}
+                block if block <= blocks => {
+                    remap.insert(block - 1, spare);
+                    used[spare] = true;
+                }
+                _ => return Err(DevError::Io),
+            }
+        }
+        Ok(BadBlockDevice {
+            device,
+            block_size_log2,
+            blocks,
+            spares,
+            retries: retries.max(1),
+            inner: Mutex::new(Inner {
+                remap,
+                used,
+                suspect: Vec::new(),
+            }),
+        })
+    }
+
+    /// Size of data in bytes
+    pub fn size(&self) -> usize {
+        self.blocks << self.block_size_log2
+    }
+
+    /// Remapped blocks and their spares
+    pub fn remapped(&self) -> Vec<(BlockId, usize)> {
+        let inner = self.inner.lock();
+        inner
+            .remap
+            .iter()
+            .map(|(&block, &spare)| (block, spare))
+            .collect()
+    }
+
+    /// Number of spare blocks not used yet
+    pub fn spares_left(&self) -> usize {
+        let inner = self.inner.lock();
+        inner.used.iter().filter(|&&used| !used).count()
+    }
+
+    /// Remap `block` now, e.g. when it is known to be worn.
+    /// Its data is copied to the spare, or zeroed if it can not be read.
+    pub fn mark_bad(&self, block: BlockId) -> Result<()> {
+        if block >= self.blocks {
+            return Err(DevError::Io);
+        }
+        let mut inner = self.inner.lock();
+        if inner.remap.contains_key(&block) {
+            return Ok(());
+        }
+        let mut buf = vec![0u8; 1 << self.block_size_log2];
+        if self.retry(|| self.read_exact(block << self.block_size_log2, &mut buf)) != Ok(()) {
+            buf.iter_mut().for_each(|b| *b = 0);
+        }
+        let spare = self.remap(&mut inner, block)?;
+        self.write_exact(self.spare_offset(spare), &buf)
+    }
+
+    fn spare_offset(&self, spare: usize) -> usize {
+        (self.blocks + spare) << self.block_size_log2
+    }
+
+    /// Offset of `block` on the device
+    fn locate(&self, inner: &Inner, block: BlockId) -> usize {
+        match inner.remap.get(&block) {
+            Some(&spare) => self.spare_offset(spare),
+            None => block << self.block_size_log2,
+        }
+    }
+
+    /// Take a spare for `block`, and record it in the table
+    fn remap(&self, inner: &mut Inner, block: BlockId) -> Result<usize> {
+        let spare = inner
+            .used
+            .iter()
+            .position(|&used| !used)
+            .ok_or(DevError::Io)?;
+        let entry = (block as u32 + 1).to_le_bytes();
+        let table = (self.blocks + self.spares) << self.block_size_log2;
+        self.write_exact(table + spare * 4, &entry)?;
+        inner.used[spare] = true;
+        inner.remap.insert(block, spare);
+        inner.suspect.retain(|&b| b != block);
+        Ok(spare)
+    }
+
+    /// Run `op` until it succeeds, at most `retries` times
+    fn retry(&self, mut op: impl FnMut() -> Result<()>) -> Result<()> {
+        let mut result = op();
+        for _ in 1..self.retries {
+            if result.is_ok() {
+                break;
+            }
+            result = op();
+        }
+        result
+    }
+
+    fn read_exact(&self, offset: usize, buf: &mut [u8]) -> Result<()> {
+        match self.device.read_at(offset, buf)? == buf.len() {
+            true => Ok(()),
+            false => Err(DevError::Io),
+        }
+    }
+
+    fn write_exact(&self, offset: usize, buf: &[u8]) -> Result<()> {
+        match self.device.write_at(offset, buf)? == buf.len() {
+            true => Ok(()),
+            false => Err(DevError::Io),
+        }
+    }
+
+    /// Write `data` into `range` of a block which failed, by its spare
+    fn write_spare(&self, inner: &mut Inner, range: &BlockRange, data: &[u8]) -> Result<()> {
+        let mut buf = vec![0u8; 1 << self.block_size_log2];
+        if !range.is_full() {
+            // the rest of the block is kept if it can still be read
+            let offset = range.block << self.block_size_log2;
+            if self.retry(|| self.read_exact(offset, &mut buf)) != Ok(()) {
+                buf.iter_mut().for_each(|b| *b = 0);
+            }
+        }
+        buf[range.begin..range.end].copy_from_slice(data);
+        let spare = self.remap(inner, range.block)?;
+        self.write_exact(self.spare_offset(spare), &buf)
+    }
+}
+
+impl<D: Device> Device for BadBlockDevice<D> {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let end = (offset + buf.len()).min(self.size());
+        if offset >= end {
+            return Ok(0);
+        }
+        let iter = BlockIter {
+            begin: offset,
+            end,
+            block_size_log2: self.block_size_log2,
+        };
+        let mut inner = self.inner.lock();
+        for range in iter {
+            let buf = &mut buf[range.origin_begin() - offset..range.origin_end() - offset];
+            let offset = self.locate(&inner, range.block) + range.begin;
+            if let Err(e) = self.retry(|| self.read_exact(offset, buf)) {
+                if !inner.remap.contains_key(&range.block) {
+                    inner.suspect.push(range.block);
+                }
+                return Err(e);
+            }
+        }
+        Ok(end - offset)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        let end = (offset + buf.len()).min(self.size());
+        if offset >= end {
+            return Ok(0);
+        }
+        let iter = BlockIter {
+            begin: offset,
+            end,
+            block_size_log2: self.block_size_log2,
+        };
+        let mut inner = self.inner.lock();
+        for range in iter {
+            let data = &buf[range.origin_begin() - offset..range.origin_end() - offset];
+            let remapped = inner.remap.contains_key(&range.block);
+            if !remapped && inner.suspect.contains(&range.block) {
+                self.write_spare(&mut inner, &range, data)?;
+                continue;
+            }
+            let offset = self.locate(&inner, range.block) + range.begin;
+            match self.retry(|| self.write_exact(offset, data)) {
+                Ok(()) => {}
+                Err(_) if !remapped => self.write_spare(&mut inner, &range, data)?,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(end - offset)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.device.sync()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.device.flush()
+    }
+
+    fn stats(&self) -> Option<Arc<Stats>> {
+        self.device.stats()
+    }
+
+    fn sector_size_log2(&self) -> u8 {
+        self.block_size_log2.max(self.device.sector_size_log2())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeSet;
+    use std::sync::Mutex;
+
+    /// Fail reads of the blocks of 16 bytes in `bad_reads`, and writes in `bad_writes`
+    struct FlakyDevice {
+        data: Mutex<Vec<u8>>,
+        bad_reads: Mutex<BTreeSet<usize>>,
+        bad_writes: Mutex<BTreeSet<usize>>,
+    }
+
+    impl Device for &FlakyDevice {
+        fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+            if self.bad_reads.lock().unwrap().contains(&(offset / 16)) {
+                return Err(DevError::Io);
+            }
+            let data = self.data.lock().unwrap();
+            let len = buf.len().min(data.len().saturating_sub(offset));
+            buf[..len].copy_from_slice(&data[offset..offset + len]);
+            Ok(len)
+        }
+        fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+            if self.bad_writes.lock().unwrap().contains(&(offset / 16)) {
+                return Err(DevError::Io);
+            }
+            let mut data = self.data.lock().unwrap();
+            let len = buf.len().min(data.len().saturating_sub(offset));
+            data[offset..offset + len].copy_from_slice(&buf[..len]);
+            Ok(len)
+        }
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn remap() {
+        // 12 data blocks, 3 spares and the table
+        let flaky = FlakyDevice {
+            data: Mutex::new(vec![0; 256]),
+            bad_reads: Mutex::new(BTreeSet::new()),
+            bad_writes: Mutex::new(BTreeSet::new()),
+        };
+        let device = BadBlockDevice::new(&flaky, 256, 4, 3, 2).unwrap();
+        assert_eq!(device.size(), 12 * 16);
+        device.write_at(0, &[1; 64]).unwrap();
+
+        // a write to a bad block goes to a spare, keeping the rest of the block
+        flaky.bad_writes.lock().unwrap().insert(2);
+        assert_eq!(device.write_at(36, &[2; 4]), Ok(4));
+        assert_eq!(device.remapped(), [(2, 0)]);
+        // a block failing to read is remapped when written
+        flaky.bad_reads.lock().unwrap().insert(3);
+        let mut buf = [0u8; 8];
+        assert_eq!(device.read_at(44, &mut buf), Err(DevError::Io));
+        assert_eq!(device.write_at(48, &[3; 16]), Ok(16));
+        device.mark_bad(5).unwrap();
+        assert_eq!(device.spares_left(), 0);
+
+        // the table is kept on the device
+        flaky.bad_reads.lock().unwrap().clear();
+        let device = BadBlockDevice::new(&flaky, 256, 4, 3, 2).unwrap();
+        assert_eq!(device.remapped(), [(2, 0), (3, 1), (5, 2)]);
+        assert_eq!(device.read_at(32, &mut buf), Ok(8));
+        assert_eq!(buf, [1, 1, 1, 1, 2, 2, 2, 2]);
+        assert_eq!(device.read_at(44, &mut buf), Ok(8));
+        assert_eq!(buf, [1, 1, 1, 1, 3, 3, 3, 3]);
+        // no spare left
+        flaky.bad_writes.lock().unwrap().insert(6);
+        assert_eq!(device.write_at(96, &[4]), Err(DevError::Io));
+    }
+}
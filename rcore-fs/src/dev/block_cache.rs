@@ -1,12 +1,17 @@
-//! A naive LRU cache layer for `BlockDevice`
+//! A write-back cache layer for `BlockDevice`, evicting by LRU or CLOCK
+//!
+//! Partial writes to a block which is not cached are buffered without reading the block,
+//! adjacent ones are coalesced, and the block is only read when it is written back.
+use super::eviction::{Eviction, EvictionPolicy};
 use super::*;
-use alloc::{vec, vec::Vec};
+use alloc::{sync::Arc, vec, vec::Vec};
 use spin::{Mutex, MutexGuard};
 
 pub struct BlockCache<T: BlockDevice> {
     device: T,
     bufs: Vec<Mutex<Buf>>,
-    lru: Mutex<LRU>,
+    eviction: Mutex<Eviction>,
+    stats: Arc<Stats>,
 }
 
 struct Buf {
@@ -21,25 +26,42 @@ enum BufStatus {
     Valid(BlockId),
     /// buffer needs to be written to disk
     Dirty(BlockId),
+    /// only bytes in `begin..end` are written, the rest has not been read from disk
+    Partial(BlockId, usize, usize),
 }
 
 impl<T: BlockDevice> BlockCache<T> {
     pub fn new(device: T, capacity: usize) -> Self {
+        Self::with_policy(device, capacity, EvictionPolicy::Lru)
+    }
+
+    pub fn with_policy(device: T, capacity: usize, policy: EvictionPolicy) -> Self {
         let mut bufs = Vec::new();
         bufs.resize_with(capacity, || {
             Mutex::new(Buf {
                 status: BufStatus::Unused,
-                data: vec![0; 1 << T::BLOCK_SIZE_LOG2 as usize],
+                data: vec![0; 1 << device.block_size_log2() as usize],
             })
         });
-        let lru = Mutex::new(LRU::new(capacity));
-        BlockCache { device, bufs, lru }
+        let eviction = Mutex::new(Eviction::new(policy, capacity));
+        // share counters with the device, e.g. one recording its operations
+        let stats = BlockDevice::stats(&device).unwrap_or_default();
+        BlockCache {
+            device,
+            bufs,
+            eviction,
+            stats,
+        }
     }
 
     /// Get a buffer for `block_id` with any status
     fn get_buf(&self, block_id: BlockId) -> MutexGuard<Buf> {
         let (i, buf) = self._get_buf(block_id);
-        self.lru.lock().visit(i);
+        self.eviction.lock().visit(i);
+        self.stats.update(|s| match buf.status {
+            BufStatus::Unused => s.cache_misses += 1,
+            _ => s.cache_hits += 1,
+        });
         buf
     }
 
@@ -49,6 +71,7 @@ impl<T: BlockDevice> BlockCache<T> {
                 match lock.status {
                     BufStatus::Valid(id) if id == block_id => return (i, lock),
                     BufStatus::Dirty(id) if id == block_id => return (i, lock),
+                    BufStatus::Partial(id, ..) if id == block_id => return (i, lock),
                     _ => {}
                 }
             }
@@ -65,18 +88,42 @@ impl<T: BlockDevice> BlockCache<T> {
                 }
             }
         }
-        let victim_id = self.lru.lock().victim();
+        let victim_id = self.eviction.lock().victim();
         let mut victim = self.bufs[victim_id].lock();
         self.write_back(&mut victim).expect("failed to write back");
         victim.status = BufStatus::Unused;
         (victim_id, victim)
     }
 
+    /// Complete a partial buffer with the rest of the block on disk
+    fn fill(&self, buf: &mut Buf) -> Result<()> {
+        if let BufStatus::Partial(block_id, begin, end) = buf.status {
+            if begin != 0 || end != buf.data.len() {
+                let mut data = vec![0; buf.data.len()];
+                self.device.read_at(block_id, &mut data)?;
+                data[begin..end].copy_from_slice(&buf.data[begin..end]);
+                buf.data = data;
+            }
+            buf.status = BufStatus::Dirty(block_id);
+        }
+        Ok(())
+    }
+
+    /// Write back all dirty buffers, without syncing the device
+    pub fn flush(&self) -> Result<()> {
+        for buf in self.bufs.iter() {
+            self.write_back(&mut buf.lock())?;
+        }
+        Ok(())
+    }
+
     /// Write back data if buffer is dirty
     fn write_back(&self, buf: &mut Buf) -> Result<()> {
+        self.fill(buf)?;
         if let BufStatus::Dirty(block_id) = buf.status {
             self.device.write_at(block_id, &buf.data)?;
             buf.status = BufStatus::Valid(block_id);
+            self.stats.update(|s| s.cache_writebacks += 1);
         }
         Ok(())
     }
@@ -91,6 +138,10 @@ impl<T: BlockDevice> Drop for BlockCache<T> {
 impl<T: BlockDevice> BlockDevice for BlockCache<T> {
     const BLOCK_SIZE_LOG2: u8 = T::BLOCK_SIZE_LOG2;
 
+    fn block_size_log2(&self) -> u8 {
+        self.device.block_size_log2()
+    }
+
     fn read_at(&self, block_id: BlockId, buffer: &mut [u8]) -> Result<()> {
         let mut buf = self.get_buf(block_id);
         match buf.status {
@@ -99,9 +150,10 @@ impl<T: BlockDevice> BlockDevice for BlockCache<T> {
                 self.device.read_at(block_id, &mut buf.data)?;
                 buf.status = BufStatus::Valid(block_id);
             }
+            BufStatus::Partial(..) => self.fill(&mut buf)?,
             _ => {}
         }
-        let len = 1 << Self::BLOCK_SIZE_LOG2 as usize;
+        let len = 1 << self.block_size_log2() as usize;
         buffer[..len].copy_from_slice(&buf.data);
         Ok(())
     }
@@ -109,56 +161,141 @@ impl<T: BlockDevice> BlockDevice for BlockCache<T> {
     fn write_at(&self, block_id: BlockId, buffer: &[u8]) -> Result<()> {
         let mut buf = self.get_buf(block_id);
         buf.status = BufStatus::Dirty(block_id);
-        let len = 1 << Self::BLOCK_SIZE_LOG2 as usize;
+        let len = 1 << self.block_size_log2() as usize;
         buf.data.copy_from_slice(&buffer[..len]);
         Ok(())
     }
 
-    fn sync(&self) -> Result<()> {
-        for buf in self.bufs.iter() {
-            self.write_back(&mut buf.lock())?;
+    fn write_partial(&self, block_id: BlockId, offset: usize, buffer: &[u8]) -> Result<()> {
+        let mut buf = self.get_buf(block_id);
+        let end = offset + buffer.len();
+        match buf.status {
+            BufStatus::Unused => buf.status = BufStatus::Partial(block_id, offset, end),
+            // coalesce with the written range if they touch
+            BufStatus::Partial(_, begin0, end0) if offset <= end0 && begin0 <= end => {
+                buf.status = BufStatus::Partial(block_id, begin0.min(offset), end0.max(end));
+            }
+            BufStatus::Partial(..) => {
+                self.fill(&mut buf)?;
+            }
+            BufStatus::Valid(_) | BufStatus::Dirty(_) => buf.status = BufStatus::Dirty(block_id),
         }
+        buf.data[offset..end].copy_from_slice(buffer);
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.flush()?;
         self.device.sync()?;
         Ok(())
     }
-}
 
-/// Doubly circular linked list LRU manager
-struct LRU {
-    prev: Vec<usize>,
-    next: Vec<usize>,
+    /// Drop the buffers of `blocks` without writing them back, then discard them
+    fn trim(&self, blocks: Range<BlockId>) -> Result<()> {
+        for buf in self.bufs.iter() {
+            let mut buf = buf.lock();
+            match buf.status {
+                BufStatus::Valid(id) | BufStatus::Dirty(id) | BufStatus::Partial(id, ..)
+                    if blocks.contains(&id) =>
+                {
+                    buf.status = BufStatus::Unused;
+                }
+                _ => {}
+            }
+        }
+        self.device.trim(blocks)
+    }
+
+    fn stats(&self) -> Option<Arc<Stats>> {
+        Some(self.stats.clone())
+    }
 }
 
-impl LRU {
-    fn new(size: usize) -> Self {
-        LRU {
-            prev: (size - 1..size).chain(0..size - 1).collect(),
-            next: (1..size).chain(0..1).collect(),
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    /// 4 blocks of 4 bytes, counting reads
+    struct CountingDevice {
+        data: Mutex<[u8; 16]>,
+        reads: AtomicUsize,
+    }
+
+    impl BlockDevice for CountingDevice {
+        const BLOCK_SIZE_LOG2: u8 = 2;
+        fn read_at(&self, block_id: BlockId, buf: &mut [u8]) -> Result<()> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            let begin = block_id << 2;
+            buf[..4].copy_from_slice(&self.data.lock().unwrap()[begin..begin + 4]);
+            Ok(())
+        }
+        fn write_at(&self, block_id: BlockId, buf: &[u8]) -> Result<()> {
+            let begin = block_id << 2;
+            self.data.lock().unwrap()[begin..begin + 4].copy_from_slice(&buf[..4]);
+            Ok(())
+        }
+        fn sync(&self) -> Result<()> {
+            Ok(())
         }
     }
-    /// Visit element `id`, move it to head.
-    fn visit(&mut self, id: usize) {
-        if id == 0 || id >= self.prev.len() {
-            return;
+
+    #[test]
+    fn coalesce_partial_writes() {
+        let cache = BlockCache::new(
+            CountingDevice {
+                data: Mutex::new([0xff; 16]),
+                reads: AtomicUsize::new(0),
+            },
+            2,
+        );
+
+        // appending byte by byte never reads the device
+        for i in 0..8u8 {
+            assert_eq!(Device::write_at(&cache, i as usize, &[i]), Ok(1));
         }
-        self._list_remove(id);
-        self._list_insert_head(id);
-    }
-    /// Get a victim at tail.
-    fn victim(&self) -> usize {
-        self.prev[0]
-    }
-    fn _list_remove(&mut self, id: usize) {
-        let prev = self.prev[id];
-        let next = self.next[id];
-        self.prev[next] = prev;
-        self.next[prev] = next;
-    }
-    fn _list_insert_head(&mut self, id: usize) {
-        let head = self.next[0];
-        self.prev[id] = 0;
-        self.next[id] = head;
-        self.next[0] = id;
-        self.prev[head] = id;
+        let mut buf = [0u8; 8];
+        assert_eq!(Device::read_at(&cache, 0, &mut buf), Ok(8));
+        assert_eq!(buf, [0, 1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(cache.device.reads.load(Ordering::SeqCst), 0);
+        BlockDevice::sync(&cache).unwrap();
+        assert_eq!(
+            cache.device.data.lock().unwrap()[..8],
+            [0, 1, 2, 3, 4, 5, 6, 7]
+        );
+
+        // disjoint writes in one block need the rest of it
+        assert_eq!(Device::write_at(&cache, 12, &[12]), Ok(1));
+        assert_eq!(Device::write_at(&cache, 14, &[14]), Ok(1));
+        BlockDevice::sync(&cache).unwrap();
+        assert_eq!(cache.device.reads.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            cache.device.data.lock().unwrap()[12..],
+            [12, 0xff, 14, 0xff]
+        );
+    }
+
+    #[test]
+    fn stats() {
+        let cache = BlockCache::new(
+            CountingDevice {
+                data: Mutex::new([0; 16]),
+                reads: AtomicUsize::new(0),
+            },
+            2,
+        );
+        let mut buf = [0u8; 4];
+        BlockDevice::read_at(&cache, 0, &mut buf).unwrap();
+        BlockDevice::read_at(&cache, 0, &mut buf).unwrap();
+        BlockDevice::write_at(&cache, 1, &buf).unwrap();
+        // evicts the dirty block 1
+        BlockDevice::read_at(&cache, 2, &mut buf).unwrap();
+        BlockDevice::sync(&cache).unwrap();
+
+        let stats = Device::stats(&cache).unwrap().snapshot();
+        assert_eq!(stats.cache_hits, 1);
+        assert_eq!(stats.cache_misses, 3);
+        assert_eq!(stats.cache_writebacks, 1);
     }
 }
@@ -0,0 +1,256 @@
+//! A write-back block cache in front of a byte-addressed `Device`
+//!
+//! Unlike `BlockCache`, the block size is chosen at runtime and the result is a `Device`,
+//! so it can be put under any file system, e.g. SFS with 4K blocks on an SD card.
+use super::eviction::{Eviction, EvictionPolicy};
+use super::*;
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+use spin::Mutex;
+
+pub struct CachedBlockDevice<T: Device> {
+    device: T,
+    block_size_log2: u8,
+    inner: Mutex<Inner>,
+    stats: Arc<Stats>,
+}
+
+struct Inner {
+    bufs: Vec<Buf>,
+    /// block id -> index in `bufs`
+    map: BTreeMap<BlockId, usize>,
+    eviction: Eviction,
+}
+
+struct Buf {
+    block: Option<BlockId>,
+    dirty: bool,
+    data: Vec<u8>,
+}
+
+impl<T: Device> CachedBlockDevice<T> {
+    /// Cache at most `capacity` blocks of `1 << block_size_log2` bytes
+    pub fn new(device: T, block_size_log2: u8, capacity: usize, policy: EvictionPolicy) -> Self {
+        assert!(capacity > 0, "capacity must be positive");
+        let mut bufs = Vec::new();
+        bufs.resize_with(capacity, || Buf {
+            block: None,
+            dirty: false,
+            data: vec![0; 1 << block_size_log2 as usize],
+        });
+        // share counters with the device, e.g. one recording its operations
+        let stats = device.stats().unwrap_or_default();
+        CachedBlockDevice {
+            device,
+            block_size_log2,
+            inner: Mutex::new(Inner {
+                bufs,
+                map: BTreeMap::new(),
+                eviction: Eviction::new(policy, capacity),
+            }),
+            stats,
+        }
+    }
+
+    /// Write back all dirty blocks, without syncing the device
+    pub fn flush(&self) -> Result<()> {
+        let mut inner = self.inner.lock();
+        for buf in inner.bufs.iter_mut() {
+            self.write_back(buf)?;
+        }
+        Ok(())
+    }
+
+    /// Get the index of the buffer of `block`.
+    /// If `fill` is false, the content of a missed block is not read from the device.
+    fn get_buf(&self, inner: &mut Inner, block: BlockId, fill: bool) -> Result<usize> {
+        if let Some(&i) = inner.map.get(&block) {
+            inner.eviction.visit(i);
+            self.stats.update(|s| s.cache_hits += 1);
+            return Ok(i);
+        }
+        self.stats.update(|s| s.cache_misses += 1);
+        let i = match inner.bufs.iter().position(|buf| buf.block.is_none()) {
+            Some(i) => i,
+            None => {
+                let i = inner.eviction.victim();
+                self.write_back(&mut inner.bufs[i])?;
+                let old = inner.bufs[i].block.take().unwrap();
+                inner.map.remove(&old);
+                i
+            }
+        };
+        let buf = &mut inner.bufs[i];
+        if fill {
+            let offset = block << self.block_size_log2;
+            let len = self.device.read_at(offset, &mut buf.data)?;
+            // beyond the end of device
+            for b in buf.data[len..].iter_mut() {
+                *b = 0;
+            }
+        }
+        buf.block = Some(block);
+        buf.dirty = false;
+        inner.map.insert(block, i);
+        inner.eviction.visit(i);
+        Ok(i)
+    }
+
+    /// Write back data if buffer is dirty
+    fn write_back(&self, buf: &mut Buf) -> Result<()> {
+        if let (Some(block), true) = (buf.block, buf.dirty) {
+            let offset = block << self.block_size_log2;
+            self.device.write_at(offset, &buf.data)?;
+            buf.dirty = false;
+            self.stats.update(|s| s.cache_writebacks += 1);
+        }
+        Ok(())
+    }
+}
+
+impl<T: Device> Drop for CachedBlockDevice<T> {
+    fn drop(&mut self) {
+        self.flush().expect("failed to flush");
+    }
+}
+
+impl<T: Device> Device for CachedBlockDevice<T> {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let iter = BlockIter {
+            begin: offset,
+            end: offset + buf.len(),
+            block_size_log2: self.block_size_log2,
+        };
+        let mut inner = self.inner.lock();
+        for range in iter {
+            let i = self.get_buf(&mut inner, range.block, true)?;
+            let dst = &mut buf[range.origin_begin() - offset..range.origin_end() - offset];
+            dst.copy_from_slice(&inner.bufs[i].data[range.begin..range.end]);
+        }
+        Ok(buf.len())
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        let iter = BlockIter {
+            begin: offset,
+            end: offset + buf.len(),
+            block_size_log2: self.block_size_log2,
+        };
+        let mut inner = self.inner.lock();
+        for range in iter {
+            // a full block will be overwritten, no need to read it
+            let i = self.get_buf(&mut inner, range.block, !range.is_full())?;
+            let src = &buf[range.origin_begin() - offset..range.origin_end() - offset];
+            let cached = &mut inner.bufs[i];
+            cached.data[range.begin..range.end].copy_from_slice(src);
+            cached.dirty = true;
+        }
+        Ok(buf.len())
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.flush()?;
+        self.device.sync()
+    }
+
+    /// Drop the buffers of blocks inside `range` without writing them back, then discard it
+    fn trim(&self, range: Range<usize>) -> Result<()> {
+        let block_size = 1 << self.block_size_log2;
+        let begin = (range.start + block_size - 1) >> self.block_size_log2;
+        let end = range.end >> self.block_size_log2;
+        let mut inner = self.inner.lock();
+        let inner = &mut *inner;
+        for buf in inner.bufs.iter_mut() {
+            if let Some(block) = buf.block.filter(|&block| begin <= block && block < end) {
+                buf.block = None;
+                buf.dirty = false;
+                inner.map.remove(&block);
+            }
+        }
+        self.device.trim(range)
+    }
+
+    fn stats(&self) -> Option<Arc<Stats>> {
+        Some(self.stats.clone())
+    }
+
+    fn sector_size_log2(&self) -> u8 {
+        self.block_size_log2.max(self.device.sector_size_log2())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    /// 64 bytes in memory, counting writes
+    struct MemDevice {
+        data: Mutex<[u8; 64]>,
+        writes: AtomicUsize,
+    }
+
+    impl Device for MemDevice {
+        fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+            let data = self.data.lock().unwrap();
+            let len = buf.len().min(data.len().saturating_sub(offset));
+            buf[..len].copy_from_slice(&data[offset..offset + len]);
+            Ok(len)
+        }
+        fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            self.data.lock().unwrap()[offset..offset + buf.len()].copy_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn new_cache(policy: EvictionPolicy) -> CachedBlockDevice<MemDevice> {
+        let device = MemDevice {
+            data: Mutex::new([0; 64]),
+            writes: AtomicUsize::new(0),
+        };
+        // 2 blocks of 16 bytes
+        CachedBlockDevice::new(device, 4, 2, policy)
+    }
+
+    #[test]
+    fn write_back() {
+        for &policy in [EvictionPolicy::Lru, EvictionPolicy::Clock].iter() {
+            let cache = new_cache(policy);
+            let data: Vec<u8> = (0..24).collect();
+            assert_eq!(cache.write_at(4, &data), Ok(24));
+            assert_eq!(cache.device.writes.load(Ordering::SeqCst), 0);
+
+            let mut buf = [0u8; 24];
+            assert_eq!(cache.read_at(4, &mut buf), Ok(24));
+            assert_eq!(buf[..], data[..]);
+
+            // the 3rd block evicts a dirty one
+            assert_eq!(cache.write_at(40, &[0xff]), Ok(1));
+            assert_eq!(cache.device.writes.load(Ordering::SeqCst), 1);
+
+            cache.sync().unwrap();
+            assert_eq!(cache.device.data.lock().unwrap()[4..28], data[..]);
+            assert_eq!(cache.device.data.lock().unwrap()[40], 0xff);
+            let stats = cache.stats().unwrap().snapshot();
+            assert_eq!(stats.cache_writebacks, 3);
+        }
+    }
+
+    #[test]
+    fn trim() {
+        let cache = new_cache(EvictionPolicy::Lru);
+        assert_eq!(cache.write_at(0, &[1; 32]), Ok(32));
+        // the first block is dropped, the second is only partly inside
+        cache.trim(0..20).unwrap();
+        cache.sync().unwrap();
+        assert_eq!(cache.device.writes.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            cache.device.data.lock().unwrap()[..32],
+            [[0; 16], [1; 16]].concat()[..]
+        );
+    }
+}
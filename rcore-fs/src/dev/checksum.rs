@@ -0,0 +1,218 @@
+//! End-to-end integrity for any `Device`, e.g. an SD card which may return bad data silently
+use super::*;
+use alloc::{vec, vec::Vec};
+use spin::Mutex;
+
+/// Check every block of `1 << block_size_log2` bytes by its CRC-32C.
+///
+/// The checksums are kept in a shadow table after the data blocks.
+/// A block not matching its checksum fails with `DevError::Corrupted`.
+/// A zero checksum means the block has never been written, and is not checked.
+pub struct ChecksumDevice<D: Device> {
+    device: D,
+    block_size_log2: u8,
+    /// Number of data blocks
+    blocks: usize,
+    /// Serialize requests, so that data and checksums match
+    lock: Mutex<()>,
+}
+
+impl<D: Device> ChecksumDevice<D> {
+    /// Check `device` of `size` bytes, part of which is taken by the table
+    pub fn new(device: D, size: usize, block_size_log2: u8) -> Self {
+        let block_size = 1usize << block_size_log2;
+        // data blocks and the checksum table must fit
+        let table_blocks = |blocks: usize| (blocks * 4 + block_size - 1) / block_size;
+        let mut blocks = size / (block_size + 4);
+        while blocks > 0 && (blocks + table_blocks(blocks)) * block_size > size {
+            blocks -= 1;
+        }
+        ChecksumDevice {
+            device,
+            block_size_log2,
+            blocks,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Size of data in bytes
+    pub fn size(&self) -> usize {
+        self.blocks << self.block_size_log2
+    }
+
+    /// Verify all blocks, return those which do not match their checksums.
+    /// Unlike `read_at()`, a bad block does not stop it.
+    pub fn scrub(&self) -> Result<Vec<BlockId>> {
+        let mut bad = Vec::new();
+        let mut buf = vec![0u8; 1 << self.block_size_log2];
+        for block in 0..self.blocks {
+            let _lock = self.lock.lock();
+            match self.read_block(block, &mut buf) {
+                Ok(()) => {}
+                Err(DevError::Corrupted(block)) => bad.push(block),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(bad)
+    }
+
+    /// Offset of the checksum of `block`
+    fn checksum_offset(&self, block: BlockId) -> usize {
+        self.size() + block * 4
+    }
+
+    fn read_block(&self, block: BlockId, buf: &mut [u8]) -> Result<()> {
+        let mut sum = [0u8; 4];
+        if self.device.read_at(block << self.block_size_log2, buf)? != buf.len()
+            || self.device.read_at(self.checksum_offset(block), &mut sum)? != 4
+        {
+            return Err(DevError::Io);
+        }
+        let sum = u32::from_le_bytes(sum);
+        if sum != 0 && sum != crc32c(buf) {
+            return Err(DevError::Corrupted(block));
+        }
+        Ok(())
+    }
+
+    fn write_block(&self, block: BlockId, buf: &[u8]) -> Result<()> {
+        let sum = crc32c(buf).to_le_bytes();
+        if self.device.write_at(block << self.block_size_log2, buf)? != buf.len()
+            || self.device.write_at(self.checksum_offset(block), &sum)? != 4
+        {
+            return Err(DevError::Io);
+        }
+        Ok(())
+    }
+}
+
+impl<D: Device> Device for ChecksumDevice<D> {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let end = (offset + buf.len()).min(self.size());
+        if offset >= end {
+            return Ok(0);
+        }
+        let iter = BlockIter {
+            begin: offset,
+            end,
+            block_size_log2: self.block_size_log2,
+        };
+        let _lock = self.lock.lock();
+        let mut block_buf = vec![0u8; 1 << self.block_size_log2];
+        for range in iter {
+            self.read_block(range.block, &mut block_buf)?;
+            buf[range.origin_begin() - offset..range.origin_end() - offset]
+                .copy_from_slice(&block_buf[range.begin..range.end]);
+        }
+        Ok(end - offset)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        let end = (offset + buf.len()).min(self.size());
+        if offset >= end {
+            return Ok(0);
+        }
+        let iter = BlockIter {
+            begin: offset,
+            end,
+            block_size_log2: self.block_size_log2,
+        };
+        let _lock = self.lock.lock();
+        let mut block_buf = vec![0u8; 1 << self.block_size_log2];
+        for range in iter {
+            let data = &buf[range.origin_begin() - offset..range.origin_end() - offset];
+            if range.is_full() {
+                self.write_block(range.block, data)?;
+            } else {
+                self.read_block(range.block, &mut block_buf)?;
+                block_buf[range.begin..range.end].copy_from_slice(data);
+                self.write_block(range.block, &block_buf)?;
+            }
+        }
+        Ok(end - offset)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.device.sync()
+    }
+
+    /// Clear the checksums of blocks inside `range`, so that they are not checked,
+    /// then discard them
+    fn trim(&self, range: Range<usize>) -> Result<()> {
+        let block_size = 1 << self.block_size_log2;
+        let begin = (range.start + block_size - 1) >> self.block_size_log2;
+        let end = (range.end >> self.block_size_log2).min(self.blocks);
+        if begin >= end {
+            return Ok(());
+        }
+        let _lock = self.lock.lock();
+        let zeros = vec![0u8; (end - begin) * 4];
+        if self.device.write_at(self.checksum_offset(begin), &zeros)? != zeros.len() {
+            return Err(DevError::Io);
+        }
+        self.device
+            .trim(begin << self.block_size_log2..end << self.block_size_log2)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.device.flush()
+    }
+
+    fn stats(&self) -> Option<Arc<Stats>> {
+        self.device.stats()
+    }
+
+    fn sector_size_log2(&self) -> u8 {
+        self.block_size_log2.max(self.device.sector_size_log2())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    struct MemDevice(Mutex<Vec<u8>>);
+
+    impl Device for MemDevice {
+        fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+            let data = self.0.lock().unwrap();
+            let len = buf.len().min(data.len().saturating_sub(offset));
+            buf[..len].copy_from_slice(&data[offset..offset + len]);
+            Ok(len)
+        }
+        fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+            let mut data = self.0.lock().unwrap();
+            let len = buf.len().min(data.len().saturating_sub(offset));
+            data[offset..offset + len].copy_from_slice(&buf[..len]);
+            Ok(len)
+        }
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn detect_corruption() {
+        let device = ChecksumDevice::new(MemDevice(Mutex::new(vec![0; 1024])), 1024, 4);
+        assert_eq!(device.size(), 51 * 16);
+        let data: Vec<u8> = (0..100).collect();
+        assert_eq!(device.write_at(10, &data), Ok(100));
+        let mut buf = [0u8; 100];
+        assert_eq!(device.read_at(10, &mut buf), Ok(100));
+        assert_eq!(&buf[..], &data[..]);
+        assert_eq!(device.scrub(), Ok(vec![]));
+
+        // flip bits of block 2 and 5 underneath
+        device.device.0.lock().unwrap()[40] ^= 1;
+        device.device.0.lock().unwrap()[90] ^= 0x80;
+        assert_eq!(device.read_at(10, &mut buf), Err(DevError::Corrupted(2)));
+        assert_eq!(device.read_at(60, &mut buf[..20]), Ok(20));
+        assert_eq!(device.scrub(), Ok(vec![2, 5]));
+
+        // a rewritten block is good again, a trimmed one is no longer checked
+        assert_eq!(device.write_at(32, &[7; 16]), Ok(16));
+        assert_eq!(device.trim(80..96), Ok(()));
+        assert_eq!(device.scrub(), Ok(vec![]));
+    }
+}
@@ -0,0 +1,597 @@
+//! Transparent compression of blocks for any `Device`
+//!
+//! Each logical block is compressed on its own, and stored in a run of 512-byte slots
+//! found through a mapping table. Zero blocks take no space at all,
+//! and blocks which do not compress are stored as they are.
+//!
+//! Layout on the underlying device:
+//! [ header | mapping table | slots ... ]
+//!
+//! The mapping table is written on `sync()`, and the slots of a rewritten block are reused
+//! at once, so the content is only consistent after a sync.
+use super::*;
+use alloc::{collections::BTreeSet, vec, vec::Vec};
+use core::mem::size_of;
+use spin::Mutex;
+
+/// Compression algorithm of a `CompressedDevice`
+pub trait Compressor: Send + Sync {
+    /// Compress `src` into `dst`, return the length, or `None` if it does not fit
+    fn compress(&self, src: &[u8], dst: &mut [u8]) -> Option<usize>;
+    /// Decompress `src` into `dst`, return the length, or `None` if `src` is corrupted
+    fn decompress(&self, src: &[u8], dst: &mut [u8]) -> Option<usize>;
+}
+
+const MAGIC: u32 = 0x524d_4f43; // "COMR"
+const SLOT_SIZE_LOG2: u8 = 9;
+const SLOT_SIZE: usize = 1 << SLOT_SIZE_LOG2;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    magic: u32,
+    block_size_log2: u32,
+    /// Number of logical blocks
+    blocks: u32,
+    /// Number of slots for data
+    slots: u32,
+}
+
+/// Where a logical block is stored
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct Entry {
+    /// The first slot
+    slot: u32,
+    /// Bytes stored: 0 for a zero block, the block size for an uncompressed one
+    len: u32,
+}
+
+impl Entry {
+    fn slots(&self) -> usize {
+        (self.len as usize + SLOT_SIZE - 1) >> SLOT_SIZE_LOG2
+    }
+}
+
+pub struct CompressedDevice<D: Device, C: Compressor> {
+    device: D,
+    compressor: C,
+    block_size_log2: u8,
+    /// Offset of the first slot
+    data_begin: usize,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    table: Vec<Entry>,
+    /// Whether each slot is free
+    free: Vec<bool>,
+    /// Entries not written to the device yet
+    dirty: BTreeSet<usize>,
+    /// Where to start looking for free slots
+    cursor: usize,
+}
+
+/// Offset of the mapping table
+const TABLE_BEGIN: usize = SLOT_SIZE;
+
+impl<D: Device, C: Compressor> CompressedDevice<D, C> {
+    /// Format `space` bytes of `device` to hold `blocks` blocks of `1 << block_size_log2` bytes.
+    /// `blocks` may exceed what `space` holds uncompressed.
+    pub fn create(
+        device: D,
+        space: usize,
+        block_size_log2: u8,
+        blocks: usize,
+        compressor: C,
+    ) -> Result<Self> {
+        assert!(
+            block_size_log2 > SLOT_SIZE_LOG2,
+            "block must be larger than a slot"
+        );
+        let data_begin = Self::data_begin(blocks);
+        if data_begin >= space {
+            return Err(DevError::Io);
+        }
+        let header = Header {
+            magic: MAGIC,
+            block_size_log2: block_size_log2 as u32,
+            blocks: blocks as u32,
+            slots: ((space - data_begin) >> SLOT_SIZE_LOG2) as u32,
+        };
+        write_struct(&device, 0, &header)?;
+        let table = vec![0u8; data_begin - TABLE_BEGIN];
+        if device.write_at(TABLE_BEGIN, &table)? != table.len() {
+            return Err(DevError::Io);
+        }
+        device.sync()?;
+        let table = vec![Entry::default(); blocks];
+        Ok(Self::new(device, compressor, &header, table))
+    }
+
+    /// Load a device made by `create()`
+    pub fn open(device: D, compressor: C) -> Result<Self> {
+        let header: Header = read_struct(&device, 0)?;
+        if header.magic != MAGIC {
+            return Err(DevError::Io);
+        }
+        let mut table = Vec::with_capacity(header.blocks as usize);
+        for i in 0..header.blocks as usize {
+            table.push(read_struct(&device, TABLE_BEGIN + i * size_of::<Entry>())?);
+        }
+        Ok(Self::new(device, compressor, &header, table))
+    }
+
+    fn new(device: D, compressor: C, header: &Header, table: Vec<Entry>) -> Self {
+        let mut free = vec![true; header.slots as usize];
+        for entry in table.iter() {
+            for slot in entry.slot as usize..entry.slot as usize + entry.slots() {
+                free[slot] = false;
+            }
+        }
+        CompressedDevice {
+            device,
+            compressor,
+            block_size_log2: header.block_size_log2 as u8,
+            data_begin: Self::data_begin(header.blocks as usize),
+            inner: Mutex::new(Inner {
+                table,
+                free,
+                dirty: BTreeSet::new(),
+                cursor: 0,
+            }),
+        }
+    }
+
+    fn data_begin(blocks: usize) -> usize {
+        let table_end = TABLE_BEGIN + blocks * size_of::<Entry>();
+        (table_end + SLOT_SIZE - 1) & !(SLOT_SIZE - 1)
+    }
+
+    /// Bytes of the underlying device taken by data
+    pub fn used_size(&self) -> usize {
+        let inner = self.inner.lock();
+        inner.free.iter().filter(|&&free| !free).count() << SLOT_SIZE_LOG2
+    }
+
+    /// Write the dirty part of the mapping table
+    fn flush(&self) -> Result<()> {
+        let mut inner = self.inner.lock();
+        let dirty = core::mem::replace(&mut inner.dirty, BTreeSet::new());
+        for id in dirty {
+            let offset = TABLE_BEGIN + id * size_of::<Entry>();
+            write_struct(&self.device, offset, &inner.table[id])?;
+        }
+        Ok(())
+    }
+
+    fn read_block(&self, inner: &Inner, block: BlockId, buf: &mut [u8]) -> Result<()> {
+        let entry = inner.table[block];
+        let offset = self.data_begin + ((entry.slot as usize) << SLOT_SIZE_LOG2);
+        match entry.len as usize {
+            0 => {
+                for b in buf.iter_mut() {
+                    *b = 0;
+                }
+            }
+            len if len == buf.len() => read_exact(&self.device, offset, buf)?,
+            len => {
+                let mut compressed = vec![0u8; len];
+                read_exact(&self.device, offset, &mut compressed)?;
+                let out = self.compressor.decompress(&compressed, buf);
+                if out != Some(buf.len()) {
+                    return Err(DevError::Io);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_block(&self, inner: &mut Inner, block: BlockId, buf: &[u8]) -> Result<()> {
+        // stored as is, unless at least one slot is saved
+        let mut compressed = vec![0u8; buf.len() - SLOT_SIZE];
+        let data = match buf.iter().all(|&b| b == 0) {
+            true => &[][..],
+            false => match self.compressor.compress(buf, &mut compressed) {
+                Some(len) => &compressed[..len],
+                None => buf,
+            },
+        };
+        let old = inner.table[block];
+        for slot in old.slot as usize..old.slot as usize + old.slots() {
+            inner.free[slot] = true;
+        }
+        let mut entry = Entry {
+            slot: 0,
+            len: data.len() as u32,
+        };
+        let slot = match inner.alloc(entry.slots()) {
+            Some(slot) => slot,
+            None => {
+                for slot in old.slot as usize..old.slot as usize + old.slots() {
+                    inner.free[slot] = false;
+                }
+                return Err(DevError::Io);
+            }
+        };
+        entry.slot = slot as u32;
+        let offset = self.data_begin + (slot << SLOT_SIZE_LOG2);
+        if self.device.write_at(offset, data)? != data.len() {
+            return Err(DevError::Io);
+        }
+        inner.table[block] = entry;
+        inner.dirty.insert(block);
+        Ok(())
+    }
+}
+
+impl Inner {
+    /// Allocate `n` contiguous slots, return the first one
+    fn alloc(&mut self, n: usize) -> Option<usize> {
+        if n == 0 {
+            return Some(0);
+        }
+        let len = self.free.len();
+        let mut run = 0;
+        for i in (self.cursor..len).chain(0..len) {
+            if i == 0 {
+                run = 0;
+            }
+            match self.free[i] {
+                true => run += 1,
+                false => run = 0,
+            }
+            if run == n {
+                let begin = i + 1 - n;
+                for slot in begin..=i {
+                    self.free[slot] = false;
+                }
+                self.cursor = i + 1;
+                return Some(begin);
+            }
+        }
+        None
+    }
+}
+
+impl<D: Device, C: Compressor> Drop for CompressedDevice<D, C> {
+    fn drop(&mut self) {
+        self.flush().expect("failed to flush");
+    }
+}
+
+impl<D: Device, C: Compressor> Device for CompressedDevice<D, C> {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let inner = self.inner.lock();
+        let size = inner.table.len() << self.block_size_log2;
+        let end = (offset + buf.len()).min(size);
+        if offset >= end {
+            return Ok(0);
+        }
+        let iter = BlockIter {
+            begin: offset,
+            end,
+            block_size_log2: self.block_size_log2,
+        };
+        let mut block_buf = vec![0u8; 1 << self.block_size_log2];
+        for range in iter {
+            self.read_block(&inner, range.block, &mut block_buf)?;
+            buf[range.origin_begin() - offset..range.origin_end() - offset]
+                .copy_from_slice(&block_buf[range.begin..range.end]);
+        }
+        Ok(end - offset)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        let mut inner = self.inner.lock();
+        let size = inner.table.len() << self.block_size_log2;
+        let end = (offset + buf.len()).min(size);
+        if offset >= end {
+            return Ok(0);
+        }
+        let iter = BlockIter {
+            begin: offset,
+            end,
+            block_size_log2: self.block_size_log2,
+        };
+        let mut block_buf = vec![0u8; 1 << self.block_size_log2];
+        for range in iter {
+            let data = &buf[range.origin_begin() - offset..range.origin_end() - offset];
+            if range.is_full() {
+                self.write_block(&mut inner, range.block, data)?;
+            } else {
+                self.read_block(&inner, range.block, &mut block_buf)?;
+                block_buf[range.begin..range.end].copy_from_slice(data);
+                self.write_block(&mut inner, range.block, &block_buf)?;
+            }
+        }
+        Ok(end - offset)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.flush()?;
+        self.device.sync()
+    }
+
+    /// Release the slots of blocks inside `range`, which read as zero afterwards.
+    /// The slots are not discarded on the underlying device, as the mapping table
+    /// on it may still refer to them until the next sync.
+    fn trim(&self, range: Range<usize>) -> Result<()> {
+        let mut inner = self.inner.lock();
+        let block_size = 1 << self.block_size_log2;
+        let begin = (range.start + block_size - 1) >> self.block_size_log2;
+        let end = (range.end >> self.block_size_log2).min(inner.table.len());
+        for block in begin..end {
+            let old = core::mem::replace(&mut inner.table[block], Entry::default());
+            for slot in old.slot as usize..old.slot as usize + old.slots() {
+                inner.free[slot] = true;
+            }
+            inner.dirty.insert(block);
+        }
+        Ok(())
+    }
+
+    fn stats(&self) -> Option<Arc<Stats>> {
+        self.device.stats()
+    }
+
+    fn sector_size_log2(&self) -> u8 {
+        self.block_size_log2.max(self.device.sector_size_log2())
+    }
+}
+
+fn read_exact(device: &impl Device, offset: usize, buf: &mut [u8]) -> Result<()> {
+    match device.read_at(offset, buf)? == buf.len() {
+        true => Ok(()),
+        false => Err(DevError::Io),
+    }
+}
+
+fn read_struct<T: Copy>(device: &impl Device, offset: usize) -> Result<T> {
+    let mut value = core::mem::MaybeUninit::<T>::uninit();
+    let buf =
+        unsafe { core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>()) };
+    read_exact(device, offset, buf)?;
+    Ok(unsafe { value.assume_init() })
+}
+
+fn write_struct<T: Copy>(device: &impl Device, offset: usize, value: &T) -> Result<()> {
+    let buf =
+        unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+    match device.write_at(offset, buf)? == buf.len() {
+        true => Ok(()),
+        false => Err(DevError::Io),
+    }
+}
+
+/// The LZ4 block format, without the frame
+pub struct Lz4;
+
+const LZ4_MIN_MATCH: usize = 4;
+/// The last literals, after the last match
+const LZ4_LAST_LITERALS: usize = 5;
+/// A match must start at least this far from the end
+const LZ4_MF_LIMIT: usize = 12;
+const LZ4_HASH_LOG2: u32 = 12;
+
+impl Compressor for Lz4 {
+    fn compress(&self, src: &[u8], dst: &mut [u8]) -> Option<usize> {
+        let mut out = Output { buf: dst, len: 0 };
+        let mut table = [0usize; 1 << LZ4_HASH_LOG2];
+        let read_u32 = |i: usize| u32::from_le_bytes([src[i], src[i + 1], src[i + 2], src[i + 3]]);
+        let mut anchor = 0;
+        let mut i = 0;
+        while src.len() > LZ4_MF_LIMIT && i < src.len() - LZ4_MF_LIMIT {
+            let seq = read_u32(i);
+            let hash = (seq.wrapping_mul(2_654_435_761) >> (32 - LZ4_HASH_LOG2)) as usize;
+            // position + 1, 0 for none
+            let candidate = table[hash];
+            table[hash] = i + 1;
+            if candidate == 0 || i - (candidate - 1) > 0xffff || read_u32(candidate - 1) != seq {
+                i += 1;
+                continue;
+            }
+            let m = candidate - 1;
+            let mut len = LZ4_MIN_MATCH;
+            while i + len < src.len() - LZ4_LAST_LITERALS && src[m + len] == src[i + len] {
+                len += 1;
+            }
+            out.sequence(&src[anchor..i], Some((i - m, len)))?;
+            i += len;
+            anchor = i;
+        }
+        out.sequence(&src[anchor..], None)?;
+        Some(out.len)
+    }
+
+    fn decompress(&self, src: &[u8], dst: &mut [u8]) -> Option<usize> {
+        let mut i = 0;
+        let mut o = 0;
+        loop {
+            let token = *src.get(i)?;
+            i += 1;
+            let literals = read_length(src, &mut i, (token >> 4) as usize)?;
+            dst.get_mut(o..o + literals)?
+                .copy_from_slice(src.get(i..i + literals)?);
+            i += literals;
+            o += literals;
+            if i == src.len() {
+                return Some(o);
+            }
+            let offset = u16::from_le_bytes([*src.get(i)?, *src.get(i + 1)?]) as usize;
+            i += 2;
+            if offset == 0 || offset > o {
+                return None;
+            }
+            let len = read_length(src, &mut i, (token & 0xf) as usize)? + LZ4_MIN_MATCH;
+            if o + len > dst.len() {
+                return None;
+            }
+            // may overlap, so byte by byte
+            for k in o..o + len {
+                dst[k] = dst[k - offset];
+            }
+            o += len;
+        }
+    }
+}
+
+/// Read the rest of a length starting with `len` in the token
+fn read_length(src: &[u8], i: &mut usize, mut len: usize) -> Option<usize> {
+    if len == 0xf {
+        loop {
+            let b = *src.get(*i)?;
+            *i += 1;
+            len += b as usize;
+            if b != 0xff {
+                break;
+            }
+        }
+    }
+    Some(len)
+}
+
+struct Output<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Output<'_> {
+    fn push(&mut self, b: u8) -> Option<()> {
+        *self.buf.get_mut(self.len)? = b;
+        self.len += 1;
+        Some(())
+    }
+
+    /// Write the rest of a length after `0xf` in the token
+    fn length(&mut self, len: usize) -> Option<()> {
+        if len >= 0xf {
+            let mut rest = len - 0xf;
+            while rest >= 0xff {
+                self.push(0xff)?;
+                rest -= 0xff;
+            }
+            self.push(rest as u8)?;
+        }
+        Some(())
+    }
+
+    /// Write `literals` followed by a match of `(offset, len)`
+    fn sequence(&mut self, literals: &[u8], matched: Option<(usize, usize)>) -> Option<()> {
+        let match_len = matched.map_or(0, |(_, len)| len - LZ4_MIN_MATCH);
+        self.push((literals.len().min(0xf) << 4 | match_len.min(0xf)) as u8)?;
+        self.length(literals.len())?;
+        self.buf
+            .get_mut(self.len..self.len + literals.len())?
+            .copy_from_slice(literals);
+        self.len += literals.len();
+        if let Some((offset, _)) = matched {
+            self.push(offset as u8)?;
+            self.push((offset >> 8) as u8)?;
+            self.length(match_len)?;
+        }
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    struct MemDevice(Mutex<Vec<u8>>);
+
+    impl Device for MemDevice {
+        fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+            let data = self.0.lock().unwrap();
+            let len = buf.len().min(data.len().saturating_sub(offset));
+            buf[..len].copy_from_slice(&data[offset..offset + len]);
+            Ok(len)
+        }
+        fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+            let mut data = self.0.lock().unwrap();
+            let len = buf.len().min(data.len().saturating_sub(offset));
+            data[offset..offset + len].copy_from_slice(&buf[..len]);
+            Ok(len)
+        }
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Bytes from a xorshift generator, which do not compress
+    fn random(len: usize) -> Vec<u8> {
+        let mut x = 0x1234_5678u32;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lz4() {
+        // 'a', then a match of 5 at offset 1, then 5 literals
+        let block = [0x11, b'a', 1, 0, 0x50, b'a', b'a', b'a', b'a', b'a'];
+        let mut buf = [0u8; 16];
+        assert_eq!(Lz4.decompress(&block, &mut buf), Some(11));
+        assert_eq!(&buf[..11], b"aaaaaaaaaaa");
+        assert_eq!(Lz4.decompress(&block[..3], &mut buf), None);
+
+        let mut text = Vec::new();
+        for i in 0..300 {
+            text.extend_from_slice(format!("line {} of the text\n", i % 7).as_bytes());
+        }
+        for data in [text.clone(), random(1000), vec![1, 2, 3], vec![0; 100]].iter() {
+            let mut compressed = vec![0u8; data.len() + 16];
+            let len = Lz4.compress(data, &mut compressed).unwrap();
+            let mut out = vec![0u8; data.len()];
+            assert_eq!(
+                Lz4.decompress(&compressed[..len], &mut out),
+                Some(data.len())
+            );
+            assert_eq!(&out[..], &data[..]);
+        }
+        let mut compressed = vec![0u8; text.len()];
+        assert!(Lz4.compress(&text, &mut compressed).unwrap() < text.len() / 10);
+        assert_eq!(Lz4.compress(&random(1000), &mut compressed[..1000]), None);
+    }
+
+    #[test]
+    fn compressed() {
+        let mem = MemDevice(Mutex::new(vec![0; 64 * 1024]));
+        // 32 blocks of 4K on 64K
+        let device = CompressedDevice::create(mem, 64 * 1024, 12, 32, Lz4).unwrap();
+        let text: Vec<u8> = (0..32 * 4096).map(|i| b"hello, world\n"[i % 13]).collect();
+        assert_eq!(device.write_at(0, &text), Ok(32 * 4096));
+        assert!(device.used_size() < 32 * 1024);
+        let noise = random(5000);
+        assert_eq!(device.write_at(100, &noise), Ok(5000));
+        assert_eq!(device.write_at(8192, &[0; 4096]), Ok(4096));
+
+        let mut expected = text.clone();
+        expected[100..5100].copy_from_slice(&noise);
+        expected[8192..12288].copy_from_slice(&[0; 4096]);
+        let mut buf = vec![0u8; 32 * 4096 + 10];
+        assert_eq!(device.read_at(0, &mut buf), Ok(32 * 4096));
+        assert_eq!(&buf[..32 * 4096], &expected[..]);
+
+        // the mapping table is persisted on sync
+        device.sync().unwrap();
+        let mem = MemDevice(Mutex::new(device.device.0.lock().unwrap().clone()));
+        let device = CompressedDevice::open(mem, Lz4).unwrap();
+        let mut buf = vec![0u8; 32 * 4096];
+        assert_eq!(device.read_at(0, &mut buf), Ok(32 * 4096));
+        assert_eq!(&buf[..], &expected[..]);
+
+        let used = device.used_size();
+        device.trim(4000..3 * 4096).unwrap();
+        assert!(device.used_size() < used);
+        assert_eq!(device.read_at(0, &mut buf), Ok(32 * 4096));
+        assert_eq!(&buf[..4096], &expected[..4096]);
+        assert!(buf[4096..3 * 4096].iter().all(|&b| b == 0));
+    }
+}
@@ -0,0 +1,242 @@
+//! Encryption at rest for any `Device`, with AES-XTS or another 128-bit block cipher
+//!
+//! Each block of the device is a XTS data unit, tweaked by its block id,
+//! so the image can be read and written at random like a plain one.
+//! The cipher is provided by the user, e.g. from a crate or the hardware,
+//! keeping this crate free of crypto dependencies.
+use super::*;
+use alloc::vec;
+use spin::Mutex;
+
+/// A block cipher with 128-bit blocks, such as AES
+pub trait BlockCipher: Send + Sync {
+    fn encrypt_block(&self, block: &mut [u8; 16]);
+    fn decrypt_block(&self, block: &mut [u8; 16]);
+}
+
+/// XTS mode encryption of blocks of `1 << block_size_log2` bytes.
+///
+/// The key is two ciphers: one for data and one for tweaks, as in XTS-AES.
+/// Blocks never written are read as garbage, so the FS must be created on it.
+pub struct EncryptedDevice<D: Device, C: BlockCipher> {
+    device: D,
+    block_size_log2: u8,
+    data_cipher: C,
+    tweak_cipher: C,
+    /// Serialize writes, for read-modify-write of partial blocks
+    lock: Mutex<()>,
+}
+
+impl<D: Device, C: BlockCipher> EncryptedDevice<D, C> {
+    pub fn new(device: D, block_size_log2: u8, data_cipher: C, tweak_cipher: C) -> Self {
+        assert!(block_size_log2 >= 4, "block must be at least 16 bytes");
+        EncryptedDevice {
+            device,
+            block_size_log2,
+            data_cipher,
+            tweak_cipher,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Apply XTS to the whole `block` by `f`, which is encryption or decryption
+    fn xts(&self, block: BlockId, buf: &mut [u8], f: impl Fn(&C, &mut [u8; 16])) {
+        let mut tweak = [0u8; 16];
+        tweak[..8].copy_from_slice(&(block as u64).to_le_bytes());
+        self.tweak_cipher.encrypt_block(&mut tweak);
+        for chunk in buf.chunks_mut(16) {
+            let mut x = [0u8; 16];
+            for i in 0..16 {
+                x[i] = chunk[i] ^ tweak[i];
+            }
+            f(&self.data_cipher, &mut x);
+            for i in 0..16 {
+                chunk[i] = x[i] ^ tweak[i];
+            }
+            mul_alpha(&mut tweak);
+        }
+    }
+
+    /// Read and decrypt `block`, return false if it is beyond the end of device
+    fn read_block(&self, block: BlockId, buf: &mut [u8]) -> Result<bool> {
+        let len = self.device.read_at(block << self.block_size_log2, buf)?;
+        if len < buf.len() {
+            return Ok(false);
+        }
+        self.xts(block, buf, |cipher, x| cipher.decrypt_block(x));
+        Ok(true)
+    }
+
+    /// Encrypt and write `block`, return false if it is beyond the end of device
+    fn write_block(&self, block: BlockId, buf: &mut [u8]) -> Result<bool> {
+        self.xts(block, buf, |cipher, x| cipher.encrypt_block(x));
+        let len = self.device.write_at(block << self.block_size_log2, buf)?;
+        Ok(len == buf.len())
+    }
+}
+
+/// Multiply the tweak by the primitive element of GF(2^128), in little endian
+fn mul_alpha(tweak: &mut [u8; 16]) {
+    let mut carry = 0;
+    for b in tweak.iter_mut() {
+        let next = *b >> 7;
+        *b = (*b << 1) | carry;
+        carry = next;
+    }
+    if carry != 0 {
+        tweak[0] ^= 0x87;
+    }
+}
+
+impl<D: Device, C: BlockCipher> Device for EncryptedDevice<D, C> {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let iter = BlockIter {
+            begin: offset,
+            end: offset + buf.len(),
+            block_size_log2: self.block_size_log2,
+        };
+        let mut block_buf = vec![0u8; 1 << self.block_size_log2];
+        for range in iter {
+            if !self.read_block(range.block, &mut block_buf)? {
+                return Ok(range.origin_begin() - offset);
+            }
+            buf[range.origin_begin() - offset..range.origin_end() - offset]
+                .copy_from_slice(&block_buf[range.begin..range.end]);
+        }
+        Ok(buf.len())
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        let iter = BlockIter {
+            begin: offset,
+            end: offset + buf.len(),
+            block_size_log2: self.block_size_log2,
+        };
+        let _lock = self.lock.lock();
+        let mut block_buf = vec![0u8; 1 << self.block_size_log2];
+        for range in iter {
+            let data = &buf[range.origin_begin() - offset..range.origin_end() - offset];
+            if !range.is_full() && !self.read_block(range.block, &mut block_buf)? {
+                return Ok(range.origin_begin() - offset);
+            }
+            block_buf[range.begin..range.end].copy_from_slice(data);
+            if !self.write_block(range.block, &mut block_buf)? {
+                return Ok(range.origin_begin() - offset);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.device.sync()
+    }
+
+    fn trim(&self, range: Range<usize>) -> Result<()> {
+        self.device.trim(range)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.device.flush()
+    }
+
+    fn stats(&self) -> Option<Arc<Stats>> {
+        self.device.stats()
+    }
+
+    fn sector_size_log2(&self) -> u8 {
+        self.block_size_log2.max(self.device.sector_size_log2())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec::Vec;
+    use std::sync::Mutex;
+
+    /// Not secure at all, only to check the mode of operation
+    struct XorCipher(u8);
+
+    impl BlockCipher for XorCipher {
+        fn encrypt_block(&self, block: &mut [u8; 16]) {
+            block.rotate_left(1);
+            for b in block.iter_mut() {
+                *b ^= self.0;
+            }
+        }
+        fn decrypt_block(&self, block: &mut [u8; 16]) {
+            for b in block.iter_mut() {
+                *b ^= self.0;
+            }
+            block.rotate_right(1);
+        }
+    }
+
+    struct MemDevice(Mutex<Vec<u8>>);
+
+    impl Device for MemDevice {
+        fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+            let data = self.0.lock().unwrap();
+            let len = buf.len().min(data.len().saturating_sub(offset));
+            buf[..len].copy_from_slice(&data[offset..offset + len]);
+            Ok(len)
+        }
+        fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+            let mut data = self.0.lock().unwrap();
+            let len = buf.len().min(data.len().saturating_sub(offset));
+            data[offset..offset + len].copy_from_slice(&buf[..len]);
+            Ok(len)
+        }
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn mul_alpha_carry() {
+        let mut tweak = [0u8; 16];
+        tweak[15] = 0x80;
+        tweak[0] = 0x01;
+        mul_alpha(&mut tweak);
+        let mut expected = [0u8; 16];
+        expected[0] = 0x02 ^ 0x87;
+        assert_eq!(tweak, expected);
+    }
+
+    #[test]
+    fn encrypted() {
+        // 4 blocks of 32 bytes
+        let device = EncryptedDevice::new(
+            MemDevice(Mutex::new(vec![0; 128])),
+            5,
+            XorCipher(0x5a),
+            XorCipher(0xa5),
+        );
+        let zeros = [0u8; 64];
+        assert_eq!(device.write_at(0, &zeros), Ok(64));
+        assert_eq!(device.write_at(64, &zeros), Ok(64));
+        {
+            let raw = device.device.0.lock().unwrap();
+            // same plaintext differs in every 16 bytes and every block
+            let chunks: Vec<_> = raw.chunks(16).collect();
+            for i in 0..chunks.len() {
+                for j in 0..i {
+                    assert_ne!(chunks[i], chunks[j]);
+                }
+            }
+        }
+
+        let data: Vec<u8> = (0..50).collect();
+        assert_eq!(device.write_at(20, &data), Ok(50));
+        let mut buf = [0u8; 128];
+        assert_eq!(device.read_at(0, &mut buf), Ok(128));
+        assert_eq!(buf[..20], zeros[..20]);
+        assert_eq!(buf[20..70], data[..]);
+        assert_eq!(buf[70..], zeros[..58]);
+
+        // beyond the end of device
+        assert_eq!(device.write_at(120, &data), Ok(8));
+        assert_eq!(device.read_at(120, &mut buf), Ok(8));
+        assert_eq!(buf[..8], data[..8]);
+    }
+}
@@ -0,0 +1,127 @@
+//! Replacement policies of block caches
+
+use alloc::{vec, vec::Vec};
+
+/// Which buffer to evict when a block cache is full
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum EvictionPolicy {
+    /// Least recently used
+    Lru,
+    /// Second chance, cheaper than LRU on every access
+    Clock,
+}
+
+/// Replacement state of a cache with fixed number of buffers
+pub(crate) enum Eviction {
+    Lru(LRU),
+    Clock(Clock),
+}
+
+impl Eviction {
+    pub fn new(policy: EvictionPolicy, size: usize) -> Self {
+        match policy {
+            EvictionPolicy::Lru => Eviction::Lru(LRU::new(size)),
+            EvictionPolicy::Clock => Eviction::Clock(Clock::new(size)),
+        }
+    }
+    /// Buffer `id` is accessed
+    pub fn visit(&mut self, id: usize) {
+        match self {
+            Eviction::Lru(lru) => lru.visit(id),
+            Eviction::Clock(clock) => clock.visit(id),
+        }
+    }
+    /// Choose a buffer to evict
+    pub fn victim(&mut self) -> usize {
+        match self {
+            Eviction::Lru(lru) => lru.victim(),
+            Eviction::Clock(clock) => clock.victim(),
+        }
+    }
+}
+
+/// Doubly circular linked list LRU manager
+pub(crate) struct LRU {
+    prev: Vec<usize>,
+    next: Vec<usize>,
+}
+
+impl LRU {
+    fn new(size: usize) -> Self {
+        LRU {
+            prev: (size - 1..size).chain(0..size - 1).collect(),
+            next: (1..size).chain(0..1).collect(),
+        }
+    }
+    /// Visit element `id`, move it to head.
+    fn visit(&mut self, id: usize) {
+        if id == 0 || id >= self.prev.len() {
+            return;
+        }
+        self._list_remove(id);
+        self._list_insert_head(id);
+    }
+    /// Get a victim at tail.
+    fn victim(&self) -> usize {
+        self.prev[0]
+    }
+    fn _list_remove(&mut self, id: usize) {
+        let prev = self.prev[id];
+        let next = self.next[id];
+        self.prev[next] = prev;
+        self.next[prev] = next;
+    }
+    fn _list_insert_head(&mut self, id: usize) {
+        let head = self.next[0];
+        self.prev[id] = 0;
+        self.next[id] = head;
+        self.next[0] = id;
+        self.prev[head] = id;
+    }
+}
+
+/// CLOCK manager, a hand sweeps over the buffers and clears their reference bits
+pub(crate) struct Clock {
+    referenced: Vec<bool>,
+    hand: usize,
+}
+
+impl Clock {
+    fn new(size: usize) -> Self {
+        Clock {
+            referenced: vec![false; size],
+            hand: 0,
+        }
+    }
+    fn visit(&mut self, id: usize) {
+        self.referenced[id] = true;
+    }
+    /// Get the first unreferenced element from the hand.
+    fn victim(&mut self) -> usize {
+        loop {
+            let id = self.hand;
+            self.hand = (self.hand + 1) % self.referenced.len();
+            if !self.referenced[id] {
+                return id;
+            }
+            self.referenced[id] = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn clock() {
+        let mut clock = Eviction::new(EvictionPolicy::Clock, 3);
+        clock.visit(0);
+        clock.visit(2);
+        assert_eq!(clock.victim(), 1);
+        // 2 is given a second chance, 0 has used up its own
+        assert_eq!(clock.victim(), 0);
+        clock.visit(1);
+        assert_eq!(clock.victim(), 2);
+    }
+}
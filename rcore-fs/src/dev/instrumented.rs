@@ -0,0 +1,168 @@
+//! A `Device` wrapper recording every operation in the `Stats` of the device,
+//! to tune the FS, e.g. LFS cleaning, without debug prints
+//!
+//! Put it under a block cache to count the real I/O: the cache shares its `Stats`,
+//! so cache hits and device operations are found in one snapshot.
+use super::*;
+use crate::vfs::FsStats;
+
+/// Count operations, bytes and latency of `device`.
+///
+/// Latency is measured by the monotonic time of the `TimeProvider`,
+/// so its resolution is that of the clock.
+pub struct InstrumentedDevice<D: Device, T: TimeProvider> {
+    device: D,
+    time: T,
+    stats: Arc<Stats>,
+}
+
+impl<D: Device, T: TimeProvider> InstrumentedDevice<D, T> {
+    pub fn new(device: D, time: T) -> Self {
+        let stats = device.stats().unwrap_or_default();
+        InstrumentedDevice {
+            device,
+            time,
+            stats,
+        }
+    }
+
+    fn now_ns(&self) -> u64 {
+        let time = self.time.monotonic_time();
+        time.sec as u64 * 1_000_000_000 + time.nsec as u64
+    }
+
+    /// Run `op` and record it by `f` with its result and latency
+    fn record<R>(
+        &self,
+        op: impl FnOnce() -> Result<R>,
+        f: impl FnOnce(&mut FsStats, &R, u64),
+    ) -> Result<R> {
+        let start = self.now_ns();
+        let result = op();
+        let ns = self.now_ns().saturating_sub(start);
+        self.stats.update(|s| match &result {
+            Ok(r) => f(s, r, ns),
+            Err(_) => s.io_errors += 1,
+        });
+        result
+    }
+}
+
+impl<D: Device, T: TimeProvider> Device for InstrumentedDevice<D, T> {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.record(
+            || self.device.read_at(offset, buf),
+            |s, &len, ns| {
+                s.reads += 1;
+                s.bytes_read += len as u64;
+                s.read_latency.record(ns);
+            },
+        )
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.record(
+            || self.device.write_at(offset, buf),
+            |s, &len, ns| {
+                s.writes += 1;
+                s.bytes_written += len as u64;
+                s.write_latency.record(ns);
+            },
+        )
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.record(
+            || self.device.sync(),
+            |s, _, ns| {
+                s.syncs += 1;
+                s.sync_latency.record(ns);
+            },
+        )
+    }
+
+    fn trim(&self, range: Range<usize>) -> Result<()> {
+        self.record(|| self.device.trim(range), |s, _, _| s.trims += 1)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.record(
+            || self.device.flush(),
+            |s, _, ns| {
+                s.syncs += 1;
+                s.sync_latency.record(ns);
+            },
+        )
+    }
+
+    fn stats(&self) -> Option<Arc<Stats>> {
+        Some(self.stats.clone())
+    }
+
+    fn sector_size_log2(&self) -> u8 {
+        self.device.sector_size_log2()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::cached::CachedBlockDevice;
+    use super::eviction::EvictionPolicy;
+    use super::*;
+    use core::sync::atomic::{AtomicI32, Ordering};
+    use std::sync::Mutex;
+
+    struct MemDevice(Mutex<Vec<u8>>);
+
+    impl Device for MemDevice {
+        fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+            let data = self.0.lock().unwrap();
+            let len = buf.len().min(data.len().saturating_sub(offset));
+            buf[..len].copy_from_slice(&data[offset..offset + len]);
+            Ok(len)
+        }
+        fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+            let mut data = self.0.lock().unwrap();
+            let len = buf.len().min(data.len().saturating_sub(offset));
+            data[offset..offset + len].copy_from_slice(&buf[..len]);
+            Ok(len)
+        }
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A clock advancing 3us each time it is read
+    #[derive(Default)]
+    struct FakeClock(AtomicI32);
+
+    impl TimeProvider for FakeClock {
+        fn current_time(&self) -> Timespec {
+            Timespec {
+                sec: 0,
+                nsec: self.0.fetch_add(3000, Ordering::SeqCst),
+            }
+        }
+    }
+
+    #[test]
+    fn stats() {
+        let device =
+            InstrumentedDevice::new(MemDevice(Mutex::new(vec![0; 64])), FakeClock::default());
+        let cache = CachedBlockDevice::new(device, 4, 2, EvictionPolicy::Lru);
+        let mut buf = [0u8; 8];
+        cache.write_at(0, &[1; 16]).unwrap();
+        cache.read_at(4, &mut buf).unwrap();
+        cache.read_at(20, &mut buf).unwrap();
+        cache.sync().unwrap();
+
+        let stats = cache.stats().unwrap().snapshot();
+        assert_eq!(stats.cache_hits, 1);
+        assert_eq!(stats.cache_misses, 2);
+        assert_eq!((stats.reads, stats.writes, stats.syncs), (1, 1, 1));
+        assert_eq!((stats.bytes_read, stats.bytes_written), (16, 16));
+        // 3us is counted in the bucket from 2us to 4us
+        assert_eq!(stats.read_latency.0[2], 1);
+        assert_eq!(stats.write_latency.count(), 1);
+    }
+}
@@ -0,0 +1,476 @@
+//! Crash consistency for a FS which updates blocks in place, e.g. SFS
+//!
+//! Blocks written are kept in memory as a transaction. On commit, they are written
+//! to the journal first, then a header listing them, and only then to their home.
+//! A crash leaves either a whole transaction in the journal, which is replayed
+//! by `JournaledDevice::open()`, or a torn one, which is ignored. So the FS on it
+//! sees all or none of the writes between two commits, without changing its format.
+//!
+//! A FS may write file data by `write_through()` instead, so that only its metadata
+//! is journaled, and the data reaches the storage before the metadata referring to it.
+use super::*;
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+use spin::Mutex;
+
+/// "JRNL"
+const MAGIC: u32 = 0x4c4e_524a;
+/// Magic, count and checksum before the block ids in the header
+const HEADER_SIZE: usize = 12;
+
+/// Size and commit policy of the journal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JournalConfig {
+    /// Blocks taken by the journal at the end of the device, including its header
+    pub journal_blocks: usize,
+    /// Commit when this many blocks are waiting, besides on each `flush()` and `sync()`.
+    /// It is limited by the size of the journal.
+    pub commit_interval: usize,
+}
+
+impl Default for JournalConfig {
+    fn default() -> Self {
+        JournalConfig {
+            journal_blocks: 257,
+            commit_interval: 256,
+        }
+    }
+}
+
+/// Journal all writes to `device` in blocks of `1 << block_size_log2` bytes.
+///
+/// A transaction larger than the journal is split, so only the writes between
+/// two `flush()` fitting in the journal are atomic.
+pub struct JournaledDevice<D: Device> {
+    device: D,
+    block_size_log2: u8,
+    /// Number of data blocks
+    blocks: usize,
+    /// Max blocks in a transaction
+    capacity: usize,
+    commit_interval: usize,
+    /// Blocks written since the last commit
+    pending: Mutex<BTreeMap<BlockId, Vec<u8>>>,
+}
+
+impl<D: Device> JournaledDevice<D> {
+    /// Journal `device` of `size` bytes, the end of which is taken by the journal.
+    /// A transaction committed before a crash is replayed.
+    pub fn open(
+        device: D,
+        size: usize,
+        block_size_log2: u8,
+        config: JournalConfig,
+    ) -> Result<Self> {
+        let journaled = Self::new(device, size, block_size_log2, config);
+        journaled.replay()?;
+        Ok(journaled)
+    }
+
+    /// Journal `device` like `open()`, discarding anything in the journal, e.g. on a new image
+    pub fn create(
+        device: D,
+        size: usize,
+        block_size_log2: u8,
+        config: JournalConfig,
+    ) -> Result<Self> {
+        let journaled = Self::new(device, size, block_size_log2, config);
+        journaled.clear()?;
+        Ok(journaled)
+    }
+
+    fn new(device: D, size: usize, block_size_log2: u8, config: JournalConfig) -> Self {
+        let block_size = 1usize << block_size_log2;
+        assert!(block_size > HEADER_SIZE, "block too small");
+        assert!(config.journal_blocks >= 2, "journal too small");
+        assert!(
+            size >> block_size_log2 > config.journal_blocks,
+            "device too small"
+        );
+        let capacity = (config.journal_blocks - 1).min((block_size - HEADER_SIZE) / 4);
+        JournaledDevice {
+            device,
+            block_size_log2,
+            blocks: (size >> block_size_log2) - config.journal_blocks,
+            capacity,
+            commit_interval: config.commit_interval.max(1).min(capacity),
+            pending: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Size of data in bytes
+    pub fn size(&self) -> usize {
+        self.blocks << self.block_size_log2
+    }
+
+    /// Commit the blocks written so far as one transaction
+    pub fn commit(&self) -> Result<()> {
+        self.commit_pending(&mut self.pending.lock())
+    }
+
+    /// Write `buf` at `offset` to its home at once, out of any transaction,
+    /// e.g. file data of a FS which only journals its metadata.
+    /// It reaches the storage before the next commit does.
+    pub fn write_through(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        let end = (offset + buf.len()).min(self.size());
+        if offset >= end {
+            return Ok(0);
+        }
+        let iter = BlockIter {
+            begin: offset,
+            end,
+            block_size_log2: self.block_size_log2,
+        };
+        let mut pending = self.pending.lock();
+        for range in iter {
+            let data = &buf[range.origin_begin() - offset..range.origin_end() - offset];
+            match pending.remove(&range.block) {
+                // the waiting write of the block must not be replayed over it later
+                Some(mut block_buf) => {
+                    block_buf[range.begin..range.end].copy_from_slice(data);
+                    self.write_exact(range.block << self.block_size_log2, &block_buf)?;
+                }
+                None => self.write_exact(range.origin_begin(), data)?,
+            }
+        }
+        Ok(end - offset)
+    }
+
+    /// Offset of block `i` of the journal, the header is block 0
+    fn journal_offset(&self, i: usize) -> usize {
+        (self.blocks + i) << self.block_size_log2
+    }
+
+    fn read_exact(&self, offset: usize, buf: &mut [u8]) -> Result<()> {
+        match self.device.read_at(offset, buf)? == buf.len() {
+            true => Ok(()),
+            false => Err(DevError::Io),
+        }
+    }
+
+    fn write_exact(&self, offset: usize, buf: &[u8]) -> Result<()> {
+        match self.device.write_at(offset, buf)? == buf.len() {
+            true => Ok(()),
+            false => Err(DevError::Io),
+        }
+    }
+
+    /// Read `block`, as written by the FS
+    fn read_block(
+        &self,
+        pending: &BTreeMap<BlockId, Vec<u8>>,
+        block: BlockId,
+        buf: &mut [u8],
+    ) -> Result<()> {
+        match pending.get(&block) {
+            Some(data) => {
+                buf.copy_from_slice(data);
+                Ok(())
+            }
+            None => self.read_exact(block << self.block_size_log2, buf),
+        }
+    }
+
+    fn commit_pending(&self, pending: &mut BTreeMap<BlockId, Vec<u8>>) -> Result<()> {
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let mut header = vec![0u8; 1 << self.block_size_log2];
+        let mut data = Vec::with_capacity(pending.len() << self.block_size_log2);
+        for (i, (&block, buf)) in pending.iter().enumerate() {
+            let offset = HEADER_SIZE + i * 4;
+            header[offset..offset + 4].copy_from_slice(&(block as u32).to_le_bytes());
+            data.extend_from_slice(buf);
+        }
+        let ids = &header[HEADER_SIZE..HEADER_SIZE + pending.len() * 4];
+        let sum = checksum(ids, &data);
+        header[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        header[4..8].copy_from_slice(&(pending.len() as u32).to_le_bytes());
+        header[8..12].copy_from_slice(&sum.to_le_bytes());
+
+        // the header is the commit record, so it must not reach the storage before the data
+        self.write_exact(self.journal_offset(1), &data)?;
+        self.device.flush()?;
+        self.write_exact(self.journal_offset(0), &header)?;
+        self.device.flush()?;
+        for (&block, buf) in pending.iter() {
+            self.write_exact(block << self.block_size_log2, buf)?;
+        }
+        self.device.flush()?;
+        pending.clear();
+        self.clear()
+    }
+
+    /// Write a committed transaction in the journal to its home
+    fn replay(&self) -> Result<()> {
+        let mut header = vec![0u8; 1 << self.block_size_log2];
+        self.read_exact(self.journal_offset(0), &mut header)?;
+        let word = |i: usize| {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&header[i * 4..i * 4 + 4]);
+            u32::from_le_bytes(bytes) as usize
+        };
+        let count = word(1);
+        if word(0) != MAGIC as usize || count == 0 || count > self.capacity {
+            return Ok(());
+        }
+        let mut data = vec![0u8; count << self.block_size_log2];
+        self.read_exact(self.journal_offset(1), &mut data)?;
+        let ids = &header[HEADER_SIZE..HEADER_SIZE + count * 4];
+        if word(2) != checksum(ids, &data) as usize {
+            // torn, it was not committed
+            return Ok(());
+        }
+        let block_size = 1 << self.block_size_log2;
+        for (i, buf) in data.chunks(block_size).enumerate() {
+            let block = word(HEADER_SIZE / 4 + i);
+            if block < self.blocks {
+                self.write_exact(block << self.block_size_log2, buf)?;
+            }
+        }
+        self.device.flush()?;
+        self.clear()
+    }
+
+    /// Invalidate the transaction in the journal, once it is at its home
+    fn clear(&self) -> Result<()> {
+        self.write_exact(self.journal_offset(0), &[0; 4])?;
+        self.device.flush()
+    }
+}
+
+/// Checksum of a transaction
+fn checksum(ids: &[u8], data: &[u8]) -> u32 {
+    crc32c(ids) ^ crc32c(data)
+}
+
+impl<D: Device> Drop for JournaledDevice<D> {
+    fn drop(&mut self) {
+        self.commit().expect("failed to commit");
+    }
+}
+
+impl<D: Device> Device for JournaledDevice<D> {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let end = (offset + buf.len()).min(self.size());
+        if offset >= end {
+            return Ok(0);
+        }
+        let iter = BlockIter {
+            begin: offset,
+            end,
+            block_size_log2: self.block_size_log2,
+        };
+        let pending = self.pending.lock();
+        let last = (end - 1) >> self.block_size_log2;
+        if pending
+            .range(offset >> self.block_size_log2..=last)
+            .next()
+            .is_none()
+        {
+            // nothing written since the last commit
+            self.read_exact(offset, &mut buf[..end - offset])?;
+            return Ok(end - offset);
+        }
+        let mut block_buf = vec![0u8; 1 << self.block_size_log2];
+        for range in iter {
+            self.read_block(&pending, range.block, &mut block_buf)?;
+            buf[range.origin_begin() - offset..range.origin_end() - offset]
+                .copy_from_slice(&block_buf[range.begin..range.end]);
+        }
+        Ok(end - offset)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        let end = (offset + buf.len()).min(self.size());
+        if offset >= end {
+            return Ok(0);
+        }
+        let iter = BlockIter {
+            begin: offset,
+            end,
+            block_size_log2: self.block_size_log2,
+        };
+        let mut pending = self.pending.lock();
+        for range in iter {
+            let data = &buf[range.origin_begin() - offset..range.origin_end() - offset];
+            let block_buf = if range.is_full() {
+                data.to_vec()
+            } else {
+                let mut block_buf = vec![0u8; 1 << self.block_size_log2];
+                self.read_block(&pending, range.block, &mut block_buf)?;
+                block_buf[range.begin..range.end].copy_from_slice(data);
+                block_buf
+            };
+            pending.insert(range.block, block_buf);
+            if pending.len() >= self.commit_interval {
+                self.commit_pending(&mut pending)?;
+            }
+        }
+        Ok(end - offset)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.commit()?;
+        self.device.sync()
+    }
+
+    /// Drop the waiting writes to blocks inside `range`, then discard them
+    fn trim(&self, range: Range<usize>) -> Result<()> {
+        let block_size = 1 << self.block_size_log2;
+        let begin = (range.start + block_size - 1) >> self.block_size_log2;
+        let end = (range.end >> self.block_size_log2).min(self.blocks);
+        if begin >= end {
+            return Ok(());
+        }
+        let mut pending = self.pending.lock();
+        let trimmed: Vec<BlockId> = pending.range(begin..end).map(|(&block, _)| block).collect();
+        for block in trimmed {
+            pending.remove(&block);
+        }
+        self.device
+            .trim(begin << self.block_size_log2..end << self.block_size_log2)
+    }
+
+    /// Commit, which orders writes before and after it
+    fn flush(&self) -> Result<()> {
+        self.commit()
+    }
+
+    fn stats(&self) -> Option<Arc<Stats>> {
+        self.device.stats()
+    }
+
+    fn sector_size_log2(&self) -> u8 {
+        self.block_size_log2.max(self.device.sector_size_log2())
+    }
+}
+
+/// A view of a `JournaledDevice` writing through it, see `write_through()`.
+/// It reads the blocks waiting in the transaction, and commits it on `flush()` and `sync()`.
+pub struct WriteThrough<D: Device>(pub Arc<JournaledDevice<D>>);
+
+impl<D: Device> Device for WriteThrough<D> {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.0.read_at(offset, buf)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.0.write_through(offset, buf)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.0.sync()
+    }
+
+    fn trim(&self, range: Range<usize>) -> Result<()> {
+        self.0.trim(range)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.0.flush()
+    }
+
+    fn stats(&self) -> Option<Arc<Stats>> {
+        self.0.stats()
+    }
+
+    fn sector_size_log2(&self) -> u8 {
+        self.0.sector_size_log2()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// A device which loses writes after `.1` more writes, as if it crashed
+    struct MemDevice(Mutex<Vec<u8>>, Mutex<usize>);
+
+    impl Device for &MemDevice {
+        fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+            let data = self.0.lock().unwrap();
+            let len = buf.len().min(data.len().saturating_sub(offset));
+            buf[..len].copy_from_slice(&data[offset..offset + len]);
+            Ok(len)
+        }
+        fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+            let mut left = self.1.lock().unwrap();
+            if *left == 0 {
+                return Ok(buf.len());
+            }
+            *left -= 1;
+            let mut data = self.0.lock().unwrap();
+            let len = buf.len().min(data.len().saturating_sub(offset));
+            data[offset..offset + len].copy_from_slice(&buf[..len]);
+            Ok(len)
+        }
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn crash() {
+        let config = JournalConfig {
+            journal_blocks: 4,
+            commit_interval: 3,
+        };
+        let mut buf = [0u8; 4];
+        for writes in 0..8 {
+            let mem = MemDevice(Mutex::new(vec![0; 256]), Mutex::new(usize::max_value()));
+            {
+                let device = JournaledDevice::open(&mem, 256, 5, config).unwrap();
+                assert_eq!(device.size(), 128);
+                device.write_at(16, &[1; 32]).unwrap();
+                device.sync().unwrap();
+                // crash after some writes of the commit
+                *mem.1.lock().unwrap() = writes;
+                device.write_at(0, &[2; 4]).unwrap();
+                device.write_at(80, &[2; 4]).unwrap();
+                device.flush().unwrap();
+                *mem.1.lock().unwrap() = 0;
+            }
+            *mem.1.lock().unwrap() = usize::max_value();
+            let device = JournaledDevice::open(&mem, 256, 5, config).unwrap();
+            // once the journal and its header are written, the transaction is committed
+            let expected = if writes >= 2 { [2; 4] } else { [0; 4] };
+            device.read_at(0, &mut buf).unwrap();
+            assert_eq!(buf, expected, "crash after {} writes", writes);
+            device.read_at(80, &mut buf).unwrap();
+            assert_eq!(buf, expected, "crash after {} writes", writes);
+            device.read_at(44, &mut buf).unwrap();
+            assert_eq!(buf, [1; 4]);
+        }
+    }
+
+    #[test]
+    fn write_through() {
+        let config = JournalConfig {
+            journal_blocks: 4,
+            commit_interval: 3,
+        };
+        let mem = MemDevice(Mutex::new(vec![0xff; 256]), Mutex::new(usize::max_value()));
+        let mut buf = [0u8; 4];
+        {
+            let device = JournaledDevice::create(&mem, 256, 5, config).unwrap();
+            device.write_at(0, &[1; 4]).unwrap();
+            device.write_at(32, &[1; 4]).unwrap();
+            // with the waiting write of its block
+            assert_eq!(device.write_through(36, &[2; 4]), Ok(4));
+            assert_eq!(device.write_through(64, &[2; 4]), Ok(4));
+            device.read_at(64, &mut buf).unwrap();
+            assert_eq!(buf, [2; 4]);
+            // crash before the commit
+            *mem.1.lock().unwrap() = 0;
+        }
+        *mem.1.lock().unwrap() = usize::max_value();
+        let device = JournaledDevice::open(&mem, 256, 5, config).unwrap();
+        device.read_at(0, &mut buf).unwrap();
+        assert_eq!(buf, [0xff; 4]);
+        for &(offset, byte) in [(32, 1), (36, 2), (64, 2)].iter() {
+            device.read_at(offset, &mut buf).unwrap();
+            assert_eq!(buf, [byte; 4]);
+        }
+    }
+}
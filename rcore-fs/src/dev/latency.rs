@@ -0,0 +1,180 @@
+//! A `Device` wrapper emulating the speed of real storage, for benchmarking on a host
+//!
+//! Without it, an image file is served by the host page cache, so a benchmark
+//! measures memory copies rather than the SD card or disk the FS will run on.
+#![cfg(any(test, feature = "std"))]
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::*;
+
+/// Cost of operations on a kind of storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyProfile {
+    /// Fixed cost of each read
+    pub read_latency: Duration,
+    /// Fixed cost of each write
+    pub write_latency: Duration,
+    /// Cost of each sync
+    pub sync_latency: Duration,
+    /// Bytes per second read, 0 for unlimited
+    pub read_bandwidth: u64,
+    /// Bytes per second written, 0 for unlimited
+    pub write_bandwidth: u64,
+}
+
+impl LatencyProfile {
+    /// No delay at all
+    pub const NONE: Self = LatencyProfile {
+        read_latency: Duration::from_micros(0),
+        write_latency: Duration::from_micros(0),
+        sync_latency: Duration::from_micros(0),
+        read_bandwidth: 0,
+        write_bandwidth: 0,
+    };
+    /// A class 10 SD card, as found on most boards
+    pub const SD_CARD: Self = LatencyProfile {
+        read_latency: Duration::from_micros(500),
+        write_latency: Duration::from_micros(2000),
+        sync_latency: Duration::from_micros(5000),
+        read_bandwidth: 20 << 20,
+        write_bandwidth: 10 << 20,
+    };
+    /// A PCIe 3.0 NVMe SSD
+    pub const NVME: Self = LatencyProfile {
+        read_latency: Duration::from_micros(80),
+        write_latency: Duration::from_micros(20),
+        sync_latency: Duration::from_micros(500),
+        read_bandwidth: 2 << 30,
+        write_bandwidth: 1 << 30,
+    };
+}
+
+/// Delay every operation on `device` as specified by a `LatencyProfile`.
+///
+/// The emulated storage serves one operation at a time,
+/// so concurrent requests queue up as they would on the real one.
+pub struct LatencyDevice<D: Device> {
+    device: D,
+    profile: LatencyProfile,
+    /// When the emulated storage finishes the operations so far
+    busy_until: Mutex<Instant>,
+}
+
+impl<D: Device> LatencyDevice<D> {
+    pub fn new(device: D, profile: LatencyProfile) -> Self {
+        LatencyDevice {
+            device,
+            profile,
+            busy_until: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Occupy the storage for an operation of `cost`, then wait for its completion
+    fn delay(&self, cost: Duration) {
+        if cost == Duration::from_micros(0) {
+            return;
+        }
+        let done = {
+            let mut busy_until = self.busy_until.lock().unwrap();
+            let now = Instant::now();
+            let start = if *busy_until > now { *busy_until } else { now };
+            *busy_until = start + cost;
+            *busy_until
+        };
+        let now = Instant::now();
+        if done > now {
+            thread::sleep(done - now);
+        }
+    }
+}
+
+/// Time to transfer `len` bytes at `bandwidth` bytes per second
+fn transfer_time(len: usize, bandwidth: u64) -> Duration {
+    match bandwidth {
+        0 => Duration::from_micros(0),
+        _ => Duration::from_nanos(len as u64 * 1_000_000_000 / bandwidth),
+    }
+}
+
+impl<D: Device> Device for LatencyDevice<D> {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let len = self.device.read_at(offset, buf)?;
+        self.delay(self.profile.read_latency + transfer_time(len, self.profile.read_bandwidth));
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        let len = self.device.write_at(offset, buf)?;
+        self.delay(self.profile.write_latency + transfer_time(len, self.profile.write_bandwidth));
+        Ok(len)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.device.sync()?;
+        self.delay(self.profile.sync_latency);
+        Ok(())
+    }
+
+    fn trim(&self, range: Range<usize>) -> Result<()> {
+        self.device.trim(range)?;
+        self.delay(self.profile.write_latency);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.device.flush()?;
+        self.delay(self.profile.sync_latency);
+        Ok(())
+    }
+
+    fn stats(&self) -> Option<Arc<Stats>> {
+        self.device.stats()
+    }
+
+    fn sector_size_log2(&self) -> u8 {
+        self.device.sector_size_log2()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct NullDevice;
+
+    impl Device for NullDevice {
+        fn read_at(&self, _offset: usize, buf: &mut [u8]) -> Result<usize> {
+            Ok(buf.len())
+        }
+        fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
+            Ok(buf.len())
+        }
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn latency() {
+        let profile = LatencyProfile {
+            read_latency: Duration::from_millis(10),
+            write_latency: Duration::from_millis(5),
+            sync_latency: Duration::from_millis(20),
+            read_bandwidth: 0,
+            write_bandwidth: 1 << 20,
+        };
+        let device = LatencyDevice::new(NullDevice, profile);
+        let start = Instant::now();
+        let mut buf = vec![0u8; 1 << 18];
+        // 10ms
+        device.read_at(0, &mut buf).unwrap();
+        // 5ms + 250ms
+        device.write_at(0, &buf).unwrap();
+        // 20ms
+        device.sync().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(285));
+    }
+}
@@ -0,0 +1,198 @@
+//! A device in memory, with copy-on-write snapshots of the whole device
+//!
+//! Data is kept in frames from a `FrameAllocator`. A snapshot shares all frames
+//! with the device, and a frame is copied when it is written while shared,
+//! so taking a snapshot and rolling back are cheap.
+use super::*;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// Size of a frame in bytes
+pub const FRAME_SIZE: usize = 4096;
+const FRAME_SIZE_LOG2: u8 = 12;
+
+/// Source of the memory of `MemDevice`, e.g. the physical frame allocator of a kernel
+pub trait FrameAllocator: Send + Sync {
+    /// A frame of `FRAME_SIZE` bytes, given back to the allocator when dropped
+    type Frame: AsRef<[u8]> + AsMut<[u8]> + Send + Sync;
+
+    /// Allocate a frame of any content, `None` if out of memory
+    fn alloc(&self) -> Option<Self::Frame>;
+}
+
+/// Frames of a device, `None` for one never written, which reads as zeros
+type Frames<F> = Vec<Option<Arc<F>>>;
+
+/// A device of `size` bytes in memory. Frames are allocated when they are written.
+pub struct MemDevice<A: FrameAllocator> {
+    allocator: A,
+    size: usize,
+    frames: Mutex<Frames<A::Frame>>,
+}
+
+/// State of a `MemDevice` at some time, see `MemDevice::snapshot()`
+pub struct Snapshot<F> {
+    frames: Frames<F>,
+}
+
+impl<A: FrameAllocator> MemDevice<A> {
+    pub fn new(allocator: A, size: usize) -> Self {
+        let frames = (size + FRAME_SIZE - 1) / FRAME_SIZE;
+        MemDevice {
+            allocator,
+            size,
+            frames: Mutex::new((0..frames).map(|_| None).collect()),
+        }
+    }
+
+    /// Size in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Take a snapshot of the device, sharing all frames with it
+    pub fn snapshot(&self) -> Snapshot<A::Frame> {
+        Snapshot {
+            frames: self.frames.lock().clone(),
+        }
+    }
+
+    /// Roll back the device to `snapshot`. The snapshot can be restored again later.
+    pub fn restore(&self, snapshot: &Snapshot<A::Frame>) {
+        *self.frames.lock() = snapshot.frames.clone();
+    }
+
+    /// Number of frames in use by the device only, i.e. not shared with any snapshot
+    pub fn private_frames(&self) -> usize {
+        self.frames
+            .lock()
+            .iter()
+            .filter(|frame| match frame {
+                Some(frame) => Arc::strong_count(frame) == 1,
+                None => false,
+            })
+            .count()
+    }
+
+    /// Get frame `id` to write, copying it if it is shared
+    fn frame_mut<'a>(&self, frames: &'a mut Frames<A::Frame>, id: usize) -> Result<&'a mut [u8]> {
+        let slot = &mut frames[id];
+        let private = match slot {
+            Some(frame) => Arc::get_mut(frame).is_some(),
+            None => false,
+        };
+        if !private {
+            let mut new = self.allocator.alloc().ok_or(DevError::Io)?;
+            match slot {
+                Some(old) => new.as_mut().copy_from_slice((**old).as_ref()),
+                None => new.as_mut().iter_mut().for_each(|b| *b = 0),
+            }
+            *slot = Some(Arc::new(new));
+        }
+        Ok(Arc::get_mut(slot.as_mut().unwrap()).unwrap().as_mut())
+    }
+}
+
+impl<A: FrameAllocator> Device for MemDevice<A> {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let end = (offset + buf.len()).min(self.size);
+        if offset >= end {
+            return Ok(0);
+        }
+        let frames = self.frames.lock();
+        let iter = BlockIter {
+            begin: offset,
+            end,
+            block_size_log2: FRAME_SIZE_LOG2,
+        };
+        for range in iter {
+            let buf = &mut buf[range.origin_begin() - offset..range.origin_end() - offset];
+            match &frames[range.block] {
+                Some(frame) => buf.copy_from_slice(&(**frame).as_ref()[range.begin..range.end]),
+                None => buf.iter_mut().for_each(|b| *b = 0),
+            }
+        }
+        Ok(end - offset)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        let end = (offset + buf.len()).min(self.size);
+        if offset >= end {
+            return Ok(0);
+        }
+        let mut frames = self.frames.lock();
+        let iter = BlockIter {
+            begin: offset,
+            end,
+            block_size_log2: FRAME_SIZE_LOG2,
+        };
+        for range in iter {
+            let data = &buf[range.origin_begin() - offset..range.origin_end() - offset];
+            self.frame_mut(&mut frames, range.block)?[range.begin..range.end].copy_from_slice(data);
+        }
+        Ok(end - offset)
+    }
+
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Free the frames inside `range`
+    fn trim(&self, range: Range<usize>) -> Result<()> {
+        let begin = (range.start + FRAME_SIZE - 1) / FRAME_SIZE;
+        let end = range.end.min(self.size) / FRAME_SIZE;
+        let mut frames = self.frames.lock();
+        for frame in frames[begin.min(end)..end].iter_mut() {
+            *frame = None;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Allocate at most `.0` frames in total
+    struct LimitedAllocator(Mutex<usize>);
+
+    impl FrameAllocator for LimitedAllocator {
+        type Frame = Vec<u8>;
+        fn alloc(&self) -> Option<Vec<u8>> {
+            let mut left = self.0.lock();
+            *left = left.checked_sub(1)?;
+            Some(vec![0xcc; FRAME_SIZE])
+        }
+    }
+
+    #[test]
+    fn snapshot() {
+        let device = MemDevice::new(LimitedAllocator(Mutex::new(3)), 4 * FRAME_SIZE + 10);
+        let mut buf = [0u8; 8];
+        assert_eq!(device.write_at(FRAME_SIZE - 4, &[1; 8]), Ok(8));
+        assert_eq!(device.private_frames(), 2);
+
+        let snapshot = device.snapshot();
+        assert_eq!(device.private_frames(), 0);
+        // only the written frame is copied
+        assert_eq!(device.write_at(FRAME_SIZE, &[2; 4]), Ok(4));
+        assert_eq!(device.private_frames(), 1);
+        assert_eq!(device.read_at(FRAME_SIZE - 4, &mut buf), Ok(8));
+        assert_eq!(buf, [1, 1, 1, 1, 2, 2, 2, 2]);
+        // out of frames
+        assert_eq!(device.write_at(0, &[3]), Err(DevError::Io));
+
+        device.restore(&snapshot);
+        assert_eq!(device.read_at(FRAME_SIZE - 4, &mut buf), Ok(8));
+        assert_eq!(buf, [1; 8]);
+        drop(snapshot);
+        assert_eq!(device.private_frames(), 2);
+        assert_eq!(device.write_at(0, &[3]), Ok(1));
+
+        // unwritten and trimmed frames read as zeros, the end is clamped
+        device.trim(0..3 * FRAME_SIZE).unwrap();
+        assert_eq!(device.private_frames(), 0);
+        assert_eq!(device.read_at(4 * FRAME_SIZE + 4, &mut buf), Ok(6));
+        assert_eq!(buf[..6], [0; 6]);
+    }
+}
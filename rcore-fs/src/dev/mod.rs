@@ -1,12 +1,36 @@
+use crate::stats::Stats;
 use crate::util::*;
 use crate::vfs::Timespec;
+use alloc::{sync::Arc, vec};
+use core::ops::Range;
 
+pub mod async_device;
+pub mod bad_block;
 pub mod block_cache;
+pub mod cached;
+pub mod checksum;
+pub mod compressed;
+pub mod encrypted;
+pub mod eviction;
+pub mod instrumented;
+pub mod journaled;
+pub mod latency;
+pub mod mem;
+pub mod overlay;
+pub mod partition;
+pub mod raid;
 pub mod std_impl;
 
-/// A current time provider
+/// A current time provider, e.g. the RTC and the timer of a kernel
 pub trait TimeProvider: Send + Sync {
+    /// Wall-clock time since the Unix epoch, for timestamps of files
     fn current_time(&self) -> Timespec;
+
+    /// Time since some point in the past which never goes backwards,
+    /// for measuring durations. By default the wall-clock time.
+    fn monotonic_time(&self) -> Timespec {
+        self.current_time()
+    }
 }
 
 /// Interface for FS to read & write
@@ -14,19 +38,149 @@ pub trait Device: Send + Sync {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize>;
     fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize>;
     fn sync(&self) -> Result<()>;
+
+    /// Read into `bufs` one after another from `offset`, return the number of bytes read.
+    /// A device with scatter-gather DMA can do it in one request, without a bounce buffer.
+    /// By default each buffer is read in turn.
+    fn read_vectored(&self, offset: usize, bufs: &mut [&mut [u8]]) -> Result<usize> {
+        let mut done = 0;
+        for buf in bufs.iter_mut() {
+            let len = self.read_at(offset + done, buf)?;
+            done += len;
+            if len < buf.len() {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    /// Write `bufs` one after another from `offset`, return the number of bytes written.
+    /// See `read_vectored()`.
+    fn write_vectored(&self, offset: usize, bufs: &[&[u8]]) -> Result<usize> {
+        let mut done = 0;
+        for buf in bufs.iter() {
+            let len = self.write_at(offset + done, buf)?;
+            done += len;
+            if len < buf.len() {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    /// Discard the bytes in `range`, which are no longer used by the FS.
+    /// Their content is undefined afterwards. A device may only discard whole blocks
+    /// inside the range, or ignore it, which is the default.
+    fn trim(&self, _range: Range<usize>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Write barrier: all writes done before reach the storage before any write after it.
+    /// By default it is `sync()`, which is stronger.
+    fn flush(&self) -> Result<()> {
+        self.sync()
+    }
+
+    /// Counters kept by the device, e.g. a block cache.
+    /// The FS on it should record its own counters here too.
+    fn stats(&self) -> Option<Arc<Stats>> {
+        None
+    }
+
+    /// Accesses aligned to `1 << sector_size_log2()` bytes are done without read-modify-write.
+    /// A FS should refuse to mount if its blocks are smaller, as writing a block
+    /// would then touch its neighbours. By default any byte can be written alone.
+    fn sector_size_log2(&self) -> u8 {
+        0
+    }
+}
+
+/// A shared device, e.g. `Arc<dyn Device>` under a wrapper
+impl<D: Device + ?Sized> Device for Arc<D> {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        (**self).read_at(offset, buf)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        (**self).write_at(offset, buf)
+    }
+
+    fn sync(&self) -> Result<()> {
+        (**self).sync()
+    }
+
+    fn read_vectored(&self, offset: usize, bufs: &mut [&mut [u8]]) -> Result<usize> {
+        (**self).read_vectored(offset, bufs)
+    }
+
+    fn write_vectored(&self, offset: usize, bufs: &[&[u8]]) -> Result<usize> {
+        (**self).write_vectored(offset, bufs)
+    }
+
+    fn trim(&self, range: Range<usize>) -> Result<()> {
+        (**self).trim(range)
+    }
+
+    fn flush(&self) -> Result<()> {
+        (**self).flush()
+    }
+
+    fn stats(&self) -> Option<Arc<Stats>> {
+        (**self).stats()
+    }
+
+    fn sector_size_log2(&self) -> u8 {
+        (**self).sector_size_log2()
+    }
 }
 
 /// Device which can only R/W in blocks
 pub trait BlockDevice: Send + Sync {
+    /// Size of blocks if it is known at compile time, see `block_size_log2()`
     const BLOCK_SIZE_LOG2: u8;
     fn read_at(&self, block_id: BlockId, buf: &mut [u8]) -> Result<()>;
     fn write_at(&self, block_id: BlockId, buf: &[u8]) -> Result<()>;
     fn sync(&self) -> Result<()>;
+
+    /// Size of blocks, e.g. 512 bytes or 4K as found by a disk driver at runtime.
+    /// By default it is `BLOCK_SIZE_LOG2`.
+    fn block_size_log2(&self) -> u8 {
+        Self::BLOCK_SIZE_LOG2
+    }
+
+    /// Write `buf` into block `block_id` starting at byte `offset`.
+    /// By default the whole block is read, patched, then written back.
+    fn write_partial(&self, block_id: BlockId, offset: usize, buf: &[u8]) -> Result<()> {
+        let mut block_buf = vec![0u8; 1 << self.block_size_log2()];
+        BlockDevice::read_at(self, block_id, &mut block_buf)?;
+        block_buf[offset..offset + buf.len()].copy_from_slice(buf);
+        BlockDevice::write_at(self, block_id, &block_buf)
+    }
+
+    /// Discard `blocks`, see `Device::trim()`
+    fn trim(&self, _blocks: Range<BlockId>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Write barrier, see `Device::flush()`
+    fn flush(&self) -> Result<()> {
+        self.sync()
+    }
+
+    /// Counters kept by the device, see `Device::stats()`
+    fn stats(&self) -> Option<Arc<Stats>> {
+        None
+    }
 }
 
 /// The error type for device.
 #[derive(Debug, PartialEq, Eq)]
-pub struct DevError;
+pub enum DevError {
+    /// Failed to access the device, or the data on it is invalid
+    Io,
+    /// The block does not match its checksum
+    Corrupted(BlockId),
+}
 
 /// A specialized `Result` type for device.
 pub type Result<T> = core::result::Result<T, DevError>;
@@ -44,13 +198,15 @@ macro_rules! try0 {
 /// Helper functions to R/W BlockDevice in bytes
 impl<T: BlockDevice> Device for T {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let block_size_log2 = self.block_size_log2();
         let iter = BlockIter {
             begin: offset,
             end: offset + buf.len(),
-            block_size_log2: Self::BLOCK_SIZE_LOG2,
+            block_size_log2,
         };
 
         // For each block
+        let mut block_buf = vec![];
         for range in iter {
             let len = range.origin_begin() - offset;
             let buf = &mut buf[range.origin_begin() - offset..range.origin_end() - offset];
@@ -58,9 +214,7 @@ impl<T: BlockDevice> Device for T {
                 // Read to target buf directly
                 try0!(len, BlockDevice::read_at(self, range.block, buf));
             } else {
-                use core::mem::MaybeUninit;
-                let mut block_buf: [u8; 1 << 10] = unsafe { MaybeUninit::uninit().assume_init() };
-                assert!(Self::BLOCK_SIZE_LOG2 <= 10);
+                block_buf.resize(1 << block_size_log2, 0);
                 // Read to local buf first
                 try0!(len, BlockDevice::read_at(self, range.block, &mut block_buf));
                 // Copy to target buf then
@@ -74,7 +228,7 @@ impl<T: BlockDevice> Device for T {
         let iter = BlockIter {
             begin: offset,
             end: offset + buf.len(),
-            block_size_log2: Self::BLOCK_SIZE_LOG2,
+            block_size_log2: self.block_size_log2(),
         };
 
         // For each block
@@ -85,15 +239,11 @@ impl<T: BlockDevice> Device for T {
                 // Write to target buf directly
                 try0!(len, BlockDevice::write_at(self, range.block, buf));
             } else {
-                use core::mem::MaybeUninit;
-                let mut block_buf: [u8; 1 << 10] = unsafe { MaybeUninit::uninit().assume_init() };
-                assert!(Self::BLOCK_SIZE_LOG2 <= 10);
-                // Read to local buf first
-                try0!(len, BlockDevice::read_at(self, range.block, &mut block_buf));
-                // Write to local buf
-                block_buf[range.begin..range.end].copy_from_slice(buf);
-                // Write back to target buf
-                try0!(len, BlockDevice::write_at(self, range.block, &block_buf));
+                // Let the device merge it into the block
+                try0!(
+                    len,
+                    BlockDevice::write_partial(self, range.block, range.begin, buf)
+                );
             }
         }
         Ok(buf.len())
@@ -102,6 +252,30 @@ impl<T: BlockDevice> Device for T {
     fn sync(&self) -> Result<()> {
         BlockDevice::sync(self)
     }
+
+    fn trim(&self, range: Range<usize>) -> Result<()> {
+        // only blocks inside the range
+        let block_size_log2 = self.block_size_log2();
+        let size = 1 << block_size_log2;
+        let begin = (range.start + size - 1) >> block_size_log2;
+        let end = range.end >> block_size_log2;
+        if begin < end {
+            BlockDevice::trim(self, begin..end)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        BlockDevice::flush(self)
+    }
+
+    fn stats(&self) -> Option<Arc<Stats>> {
+        BlockDevice::stats(self)
+    }
+
+    fn sector_size_log2(&self) -> u8 {
+        self.block_size_log2()
+    }
 }
 
 #[cfg(test)]
@@ -113,7 +287,7 @@ mod test {
         const BLOCK_SIZE_LOG2: u8 = 2;
         fn read_at(&self, block_id: BlockId, buf: &mut [u8]) -> Result<()> {
             if block_id >= 4 {
-                return Err(DevError);
+                return Err(DevError::Io);
             }
             let begin = block_id << 2;
             buf[..4].copy_from_slice(&mut self.lock().unwrap()[begin..begin + 4]);
@@ -121,7 +295,7 @@ mod test {
         }
         fn write_at(&self, block_id: BlockId, buf: &[u8]) -> Result<()> {
             if block_id >= 4 {
-                return Err(DevError);
+                return Err(DevError::Io);
             }
             let begin = block_id << 2;
             self.lock().unwrap()[begin..begin + 4].copy_from_slice(&buf[..4]);
@@ -130,6 +304,40 @@ mod test {
         fn sync(&self) -> Result<()> {
             Ok(())
         }
+        fn trim(&self, blocks: Range<BlockId>) -> Result<()> {
+            for b in self.lock().unwrap()[blocks.start << 2..blocks.end << 2].iter_mut() {
+                *b = 0;
+            }
+            Ok(())
+        }
+    }
+
+    /// A disk whose block size is found at runtime
+    struct Disk {
+        block_size_log2: u8,
+        data: Mutex<Vec<u8>>,
+    }
+
+    impl BlockDevice for Disk {
+        const BLOCK_SIZE_LOG2: u8 = 9;
+        fn read_at(&self, block_id: BlockId, buf: &mut [u8]) -> Result<()> {
+            let size = 1 << self.block_size_log2;
+            assert_eq!(buf.len(), size);
+            buf.copy_from_slice(&self.data.lock().unwrap()[block_id * size..][..size]);
+            Ok(())
+        }
+        fn write_at(&self, block_id: BlockId, buf: &[u8]) -> Result<()> {
+            let size = 1 << self.block_size_log2;
+            assert_eq!(buf.len(), size);
+            self.data.lock().unwrap()[block_id * size..][..size].copy_from_slice(buf);
+            Ok(())
+        }
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+        fn block_size_log2(&self) -> u8 {
+            self.block_size_log2
+        }
     }
 
     #[test]
@@ -183,4 +391,51 @@ mod test {
             [0, 0, 0, 3, 4, 5, 6, 7, 8, 0, 0, 3, 4, 5, 6, 7]
         );
     }
+
+    #[test]
+    fn trim() {
+        let buf: Mutex<[u8; 16]> = Mutex::new([1; 16]);
+
+        // only whole blocks inside
+        assert_eq!(Device::trim(&buf, 3..13), Ok(()));
+        assert_eq!(
+            *buf.lock().unwrap(),
+            [1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1]
+        );
+    }
+
+    #[test]
+    fn runtime_block_size() {
+        let disk = Disk {
+            block_size_log2: 12,
+            data: Mutex::new(vec![0; 4 * 4096]),
+        };
+        assert_eq!(disk.sector_size_log2(), 12);
+
+        // partial blocks on both ends
+        let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        assert_eq!(Device::write_at(&disk, 4000, &data), Ok(5000));
+        let mut buf = vec![0; 5002];
+        assert_eq!(Device::read_at(&disk, 3999, &mut buf), Ok(5002));
+        assert_eq!(buf[0], 0);
+        assert_eq!(&buf[1..5001], &data[..]);
+        assert_eq!(buf[5001], 0);
+    }
+
+    #[test]
+    fn vectored() {
+        let buf: Mutex<[u8; 16]> = Mutex::new([0; 16]);
+        assert_eq!(
+            Device::write_vectored(&buf, 2, &[&[1, 2, 3], &[4; 5]]),
+            Ok(8)
+        );
+        let (mut a, mut b) = ([0u8; 3], [0u8; 8]);
+        // short at the end
+        assert_eq!(
+            Device::read_vectored(&buf, 3, &mut [&mut a, &mut b]),
+            Ok(11)
+        );
+        assert_eq!(a, [2, 3, 4]);
+        assert_eq!(b, [4, 4, 4, 4, 0, 0, 0, 0]);
+    }
 }
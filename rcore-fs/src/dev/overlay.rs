@@ -0,0 +1,238 @@
+//! Copy-on-write over a read-only image, like a qcow2 file with a backing file
+//!
+//! A VM image or a test fixture is kept as the base, which is never written,
+//! and the changes go to a sparse delta. Dropping the delta resets the device.
+use super::*;
+use alloc::{vec, vec::Vec};
+use spin::Mutex;
+
+/// Read blocks of `1 << block_size_log2` bytes from `base` until they are written,
+/// and keep the written ones in `delta`.
+///
+/// The delta has the data at the same offsets as the base, so that it is sparse
+/// when it is a file, followed by a bitmap of the blocks in it.
+pub struct OverlayDevice<B: Device, D: Device> {
+    base: B,
+    delta: D,
+    block_size_log2: u8,
+    /// Number of blocks
+    blocks: usize,
+    /// Whether each block is in the delta, as on the delta
+    bitmap: Mutex<Vec<u8>>,
+}
+
+impl<B: Device, D: Device> OverlayDevice<B, D> {
+    /// Overlay `delta` on `base` of `size` bytes, loading the bitmap on the delta.
+    /// A new delta should be zeroed, e.g. an empty file.
+    pub fn new(base: B, delta: D, size: usize, block_size_log2: u8) -> Result<Self> {
+        let blocks = size >> block_size_log2;
+        let mut bitmap = vec![0u8; (blocks + 7) / 8];
+        let len = delta.read_at(blocks << block_size_log2, &mut bitmap)?;
+        // beyond the end of a new delta file
+        for byte in bitmap[len..].iter_mut() {
+            *byte = 0;
+        }
+        Ok(OverlayDevice {
+            base,
+            delta,
+            block_size_log2,
+            blocks,
+            bitmap: Mutex::new(bitmap),
+        })
+    }
+
+    /// Size in bytes
+    pub fn size(&self) -> usize {
+        self.blocks << self.block_size_log2
+    }
+
+    /// Number of blocks in the delta
+    pub fn modified_blocks(&self) -> usize {
+        let bitmap = self.bitmap.lock();
+        bitmap.iter().map(|byte| byte.count_ones() as usize).sum()
+    }
+
+    /// Drop all changes, so that the device is the base again
+    pub fn reset(&self) -> Result<()> {
+        let mut bitmap = self.bitmap.lock();
+        for byte in bitmap.iter_mut() {
+            *byte = 0;
+        }
+        self.write_bitmap(&bitmap, 0..bitmap.len())?;
+        self.delta.trim(0..self.size())
+    }
+
+    fn in_delta(bitmap: &[u8], block: BlockId) -> bool {
+        bitmap[block / 8] & (1 << (block % 8)) != 0
+    }
+
+    /// The device to read `block` from
+    fn source(&self, bitmap: &[u8], block: BlockId) -> &dyn Device {
+        match Self::in_delta(bitmap, block) {
+            true => &self.delta,
+            false => &self.base,
+        }
+    }
+
+    /// Write `bytes` of the bitmap to the delta
+    fn write_bitmap(&self, bitmap: &[u8], bytes: Range<usize>) -> Result<()> {
+        write_exact(&self.delta, self.size() + bytes.start, &bitmap[bytes])
+    }
+}
+
+fn read_exact(device: &dyn Device, offset: usize, buf: &mut [u8]) -> Result<()> {
+    match device.read_at(offset, buf)? == buf.len() {
+        true => Ok(()),
+        false => Err(DevError::Io),
+    }
+}
+
+fn write_exact(device: &dyn Device, offset: usize, buf: &[u8]) -> Result<()> {
+    match device.write_at(offset, buf)? == buf.len() {
+        true => Ok(()),
+        false => Err(DevError::Io),
+    }
+}
+
+impl<B: Device, D: Device> Device for OverlayDevice<B, D> {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let end = (offset + buf.len()).min(self.size());
+        if offset >= end {
+            return Ok(0);
+        }
+        let iter = BlockIter {
+            begin: offset,
+            end,
+            block_size_log2: self.block_size_log2,
+        };
+        let bitmap = self.bitmap.lock();
+        for range in iter {
+            let buf = &mut buf[range.origin_begin() - offset..range.origin_end() - offset];
+            let source = self.source(&bitmap, range.block);
+            read_exact(source, range.origin_begin(), buf)?;
+        }
+        Ok(end - offset)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        let end = (offset + buf.len()).min(self.size());
+        if offset >= end {
+            return Ok(0);
+        }
+        let iter = BlockIter {
+            begin: offset,
+            end,
+            block_size_log2: self.block_size_log2,
+        };
+        let mut bitmap = self.bitmap.lock();
+        let mut block_buf = vec![0u8; 1 << self.block_size_log2];
+        for range in iter {
+            let data = &buf[range.origin_begin() - offset..range.origin_end() - offset];
+            if Self::in_delta(&bitmap, range.block) {
+                write_exact(&self.delta, range.origin_begin(), data)?;
+                continue;
+            }
+            // copy the block up with the data, then mark it in the bitmap
+            let block_offset = range.block << self.block_size_log2;
+            read_exact(&self.base, block_offset, &mut block_buf)?;
+            block_buf[range.begin..range.end].copy_from_slice(data);
+            write_exact(&self.delta, block_offset, &block_buf)?;
+            bitmap[range.block / 8] |= 1 << (range.block % 8);
+            self.write_bitmap(&bitmap, range.block / 8..range.block / 8 + 1)?;
+        }
+        Ok(end - offset)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.delta.sync()
+    }
+
+    /// Drop the blocks inside `range` from the delta, they read as the base again
+    fn trim(&self, range: Range<usize>) -> Result<()> {
+        let block_size = 1 << self.block_size_log2;
+        let begin = (range.start + block_size - 1) >> self.block_size_log2;
+        let end = (range.end >> self.block_size_log2).min(self.blocks);
+        if begin >= end {
+            return Ok(());
+        }
+        let mut bitmap = self.bitmap.lock();
+        for block in begin..end {
+            bitmap[block / 8] &= !(1 << (block % 8));
+        }
+        self.write_bitmap(&bitmap, begin / 8..(end + 7) / 8)?;
+        self.delta
+            .trim(begin << self.block_size_log2..end << self.block_size_log2)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.delta.flush()
+    }
+
+    fn stats(&self) -> Option<Arc<Stats>> {
+        self.delta.stats()
+    }
+
+    fn sector_size_log2(&self) -> u8 {
+        self.block_size_log2.max(self.delta.sector_size_log2())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Grow when written beyond the end, like a file
+    struct MemDevice(Mutex<Vec<u8>>);
+
+    impl Device for &MemDevice {
+        fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+            let data = self.0.lock().unwrap();
+            let data = data.get(offset..).unwrap_or(&[]);
+            let len = buf.len().min(data.len());
+            buf[..len].copy_from_slice(&data[..len]);
+            Ok(len)
+        }
+        fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+            let mut data = self.0.lock().unwrap();
+            if data.len() < offset + buf.len() {
+                data.resize(offset + buf.len(), 0);
+            }
+            data[offset..offset + buf.len()].copy_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn overlay() {
+        let base = MemDevice(Mutex::new((0..64).collect()));
+        // a new delta file is empty
+        let delta = MemDevice(Mutex::new(Vec::new()));
+        let mut buf = [0u8; 6];
+        {
+            let device = OverlayDevice::new(&base, &delta, 64, 3).unwrap();
+            assert_eq!(device.write_at(6, &[0xff; 4]), Ok(4));
+            assert_eq!(device.modified_blocks(), 2);
+            assert_eq!(device.read_at(4, &mut buf), Ok(6));
+            assert_eq!(buf, [4, 5, 0xff, 0xff, 0xff, 0xff]);
+        }
+        // the base is never written
+        assert_eq!(*base.0.lock().unwrap(), (0..64).collect::<Vec<u8>>());
+
+        // changes are loaded from the delta
+        let device = OverlayDevice::new(&base, &delta, 64, 3).unwrap();
+        assert_eq!(device.modified_blocks(), 2);
+        assert_eq!(device.read_at(8, &mut buf), Ok(6));
+        assert_eq!(buf, [0xff, 0xff, 10, 11, 12, 13]);
+
+        device.trim(8..16).unwrap();
+        assert_eq!(device.modified_blocks(), 1);
+        device.reset().unwrap();
+        assert_eq!(device.modified_blocks(), 0);
+        assert_eq!(device.read_at(4, &mut buf), Ok(6));
+        assert_eq!(buf, [4, 5, 6, 7, 8, 9]);
+    }
+}
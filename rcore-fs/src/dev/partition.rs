@@ -0,0 +1,313 @@
+//! Partitions of a disk, found in its MBR or GPT
+//!
+//! Only primary partitions of an MBR are listed. A protective MBR leads to the GPT,
+//! whose header and entries are checked by their CRC-32.
+use super::*;
+use alloc::{vec, vec::Vec};
+
+const SECTOR_SIZE: usize = 512;
+/// Type of the single MBR entry covering a GPT disk
+const MBR_TYPE_GPT: u8 = 0xee;
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PartitionType {
+    /// System id of an MBR entry
+    Mbr(u8),
+    /// Type GUID of a GPT entry, as stored on disk
+    Gpt([u8; 16]),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Partition {
+    /// Number of the partition from 1, as `sda2` is partition 2
+    pub index: usize,
+    /// Offset in bytes
+    pub begin: usize,
+    /// Size in bytes
+    pub size: usize,
+    pub type_: PartitionType,
+}
+
+/// List partitions on `device`
+pub fn partitions(device: &dyn Device) -> Result<Vec<Partition>> {
+    let mut mbr = [0u8; SECTOR_SIZE];
+    read_exact(device, 0, &mut mbr)?;
+    if mbr[510..] != [0x55, 0xaa] {
+        return Err(DevError::Io);
+    }
+    let mut list = Vec::new();
+    for i in 0..4 {
+        let entry = &mbr[0x1be + i * 16..0x1be + (i + 1) * 16];
+        let type_ = entry[4];
+        if type_ == MBR_TYPE_GPT {
+            return gpt_partitions(device);
+        }
+        let first = le32(&entry[8..]) as usize;
+        let sectors = le32(&entry[12..]) as usize;
+        if type_ != 0 && sectors != 0 {
+            list.push(Partition {
+                index: i + 1,
+                begin: first * SECTOR_SIZE,
+                size: sectors * SECTOR_SIZE,
+                type_: PartitionType::Mbr(type_),
+            });
+        }
+    }
+    Ok(list)
+}
+
+fn gpt_partitions(device: &dyn Device) -> Result<Vec<Partition>> {
+    let mut header = [0u8; SECTOR_SIZE];
+    read_exact(device, SECTOR_SIZE, &mut header)?;
+    let header_size = le32(&header[12..]) as usize;
+    if &header[..8] != GPT_SIGNATURE || header_size < 92 || header_size > SECTOR_SIZE {
+        return Err(DevError::Io);
+    }
+    let crc = le32(&header[16..]);
+    header[16..20].copy_from_slice(&[0; 4]);
+    if crc32(&header[..header_size]) != crc {
+        return Err(DevError::Io);
+    }
+    let entries_lba = le64(&header[72..]) as usize;
+    let entries = le32(&header[80..]) as usize;
+    let entry_size = le32(&header[84..]) as usize;
+    if entry_size < 128 {
+        return Err(DevError::Io);
+    }
+    let mut table = vec![0u8; entries * entry_size];
+    read_exact(device, entries_lba * SECTOR_SIZE, &mut table)?;
+    if crc32(&table) != le32(&header[88..]) {
+        return Err(DevError::Io);
+    }
+    let mut list = Vec::new();
+    for (i, entry) in table.chunks(entry_size).enumerate() {
+        let mut guid = [0u8; 16];
+        guid.copy_from_slice(&entry[..16]);
+        if guid == [0; 16] {
+            continue;
+        }
+        let first = le64(&entry[32..]) as usize;
+        let last = le64(&entry[40..]) as usize;
+        if last < first {
+            return Err(DevError::Io);
+        }
+        list.push(Partition {
+            index: i + 1,
+            begin: first * SECTOR_SIZE,
+            size: (last - first + 1) * SECTOR_SIZE,
+            type_: PartitionType::Gpt(guid),
+        });
+    }
+    Ok(list)
+}
+
+/// One partition of a disk as a `Device`, no access goes beyond it
+pub struct PartitionDevice<D: Device> {
+    device: D,
+    begin: usize,
+    size: usize,
+}
+
+impl<D: Device> PartitionDevice<D> {
+    pub fn new(device: D, partition: &Partition) -> Self {
+        PartitionDevice {
+            device,
+            begin: partition.begin,
+            size: partition.size,
+        }
+    }
+
+    /// The whole disk, for one without partition table
+    pub fn whole(device: D) -> Self {
+        Self::range(device, 0..usize::max_value())
+    }
+
+    /// Bytes in `range` of the disk, e.g. after a reserved boot area
+    pub fn range(device: D, range: Range<usize>) -> Self {
+        PartitionDevice {
+            device,
+            begin: range.start,
+            size: range.end.saturating_sub(range.start),
+        }
+    }
+
+    /// Open partition `index` of the disk `device`
+    pub fn open(device: D, index: usize) -> Result<Self> {
+        let partition = partitions(&device)?
+            .into_iter()
+            .find(|partition| partition.index == index)
+            .ok_or(DevError::Io)?;
+        Ok(Self::new(device, &partition))
+    }
+
+    /// Size in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Length of an access of `len` bytes at `offset` inside the partition
+    fn clamp(&self, offset: usize, len: usize) -> usize {
+        len.min(self.size.saturating_sub(offset))
+    }
+}
+
+impl<D: Device> Device for PartitionDevice<D> {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let len = self.clamp(offset, buf.len());
+        if len == 0 {
+            return Ok(0);
+        }
+        self.device.read_at(self.begin + offset, &mut buf[..len])
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        let len = self.clamp(offset, buf.len());
+        if len == 0 {
+            return Ok(0);
+        }
+        self.device.write_at(self.begin + offset, &buf[..len])
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.device.sync()
+    }
+
+    fn trim(&self, range: Range<usize>) -> Result<()> {
+        let end = range.end.min(self.size);
+        if range.start >= end {
+            return Ok(());
+        }
+        self.device.trim(self.begin + range.start..self.begin + end)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.device.flush()
+    }
+
+    fn stats(&self) -> Option<Arc<Stats>> {
+        self.device.stats()
+    }
+
+    fn sector_size_log2(&self) -> u8 {
+        self.device.sector_size_log2()
+    }
+}
+
+fn read_exact(device: &dyn Device, offset: usize, buf: &mut [u8]) -> Result<()> {
+    match device.read_at(offset, buf)? == buf.len() {
+        true => Ok(()),
+        false => Err(DevError::Io),
+    }
+}
+
+fn le32(buf: &[u8]) -> u32 {
+    u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+fn le64(buf: &[u8]) -> u64 {
+    le32(buf) as u64 | (le32(&buf[4..]) as u64) << 32
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    struct MemDevice(Mutex<Vec<u8>>);
+
+    impl Device for MemDevice {
+        fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+            let data = self.0.lock().unwrap();
+            let len = buf.len().min(data.len().saturating_sub(offset));
+            buf[..len].copy_from_slice(&data[offset..offset + len]);
+            Ok(len)
+        }
+        fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+            let mut data = self.0.lock().unwrap();
+            let len = buf.len().min(data.len().saturating_sub(offset));
+            data[offset..offset + len].copy_from_slice(&buf[..len]);
+            Ok(len)
+        }
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Set MBR entry `i` to `type_` from sector `first` for `sectors`
+    fn mbr_entry(disk: &mut [u8], i: usize, type_: u8, first: u32, sectors: u32) {
+        let entry = &mut disk[0x1be + i * 16..0x1be + (i + 1) * 16];
+        entry[4] = type_;
+        entry[8..12].copy_from_slice(&first.to_le_bytes());
+        entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+        disk[510] = 0x55;
+        disk[511] = 0xaa;
+    }
+
+    #[test]
+    fn mbr() {
+        let mut disk = vec![0u8; 64 * SECTOR_SIZE];
+        mbr_entry(&mut disk, 0, 0x83, 2, 8);
+        mbr_entry(&mut disk, 2, 0x0c, 16, 4);
+        let device = MemDevice(Mutex::new(disk));
+        let list = partitions(&device).unwrap();
+        assert_eq!(list.len(), 2);
+        assert_eq!(
+            list[1],
+            Partition {
+                index: 3,
+                begin: 16 * SECTOR_SIZE,
+                size: 4 * SECTOR_SIZE,
+                type_: PartitionType::Mbr(0x0c),
+            }
+        );
+
+        // accesses are limited to the partition
+        let part = PartitionDevice::open(device, 3).unwrap();
+        let data = vec![0xccu8; 3 * SECTOR_SIZE];
+        assert_eq!(part.write_at(SECTOR_SIZE, &data), Ok(3 * SECTOR_SIZE));
+        assert_eq!(part.write_at(3 * SECTOR_SIZE, &data), Ok(SECTOR_SIZE));
+        assert_eq!(part.write_at(4 * SECTOR_SIZE, &data), Ok(0));
+        let disk = part.device.0.lock().unwrap();
+        assert!(disk[17 * SECTOR_SIZE..20 * SECTOR_SIZE]
+            .iter()
+            .all(|&b| b == 0xcc));
+        assert_eq!(disk[20 * SECTOR_SIZE], 0);
+    }
+
+    #[test]
+    fn gpt() {
+        let mut disk = vec![0u8; 64 * SECTOR_SIZE];
+        mbr_entry(&mut disk, 0, MBR_TYPE_GPT, 1, 63);
+        // 4 entries of 128 bytes at LBA 2, the second one used
+        let entry = &mut disk[2 * SECTOR_SIZE + 128..2 * SECTOR_SIZE + 256];
+        entry[..16].copy_from_slice(&[0xaf; 16]);
+        entry[32..40].copy_from_slice(&10u64.to_le_bytes());
+        entry[40..48].copy_from_slice(&19u64.to_le_bytes());
+        let table_crc = crc32(&disk[2 * SECTOR_SIZE..2 * SECTOR_SIZE + 512]);
+        let header = &mut disk[SECTOR_SIZE..2 * SECTOR_SIZE];
+        header[..8].copy_from_slice(GPT_SIGNATURE);
+        header[12..16].copy_from_slice(&92u32.to_le_bytes());
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&4u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        header[88..92].copy_from_slice(&table_crc.to_le_bytes());
+        let crc = crc32(&header[..92]);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+
+        let device = MemDevice(Mutex::new(disk));
+        assert_eq!(
+            partitions(&device),
+            Ok(vec![Partition {
+                index: 2,
+                begin: 10 * SECTOR_SIZE,
+                size: 10 * SECTOR_SIZE,
+                type_: PartitionType::Gpt([0xaf; 16]),
+            }])
+        );
+
+        // a corrupted header is rejected
+        device.0.lock().unwrap()[SECTOR_SIZE + 80] = 5;
+        assert_eq!(partitions(&device), Err(DevError::Io));
+    }
+}
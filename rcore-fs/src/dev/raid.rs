@@ -0,0 +1,459 @@
+//! Devices combining several `Device`s: striping (RAID-0) and mirroring (RAID-1)
+//!
+//! `Device` does not know its size, so each member is given with its size in bytes.
+use super::*;
+use alloc::{vec, vec::Vec};
+use spin::Mutex;
+
+/// RAID-0: data is split into stripes, spread over all members in turn.
+///
+/// Members of different sizes are divided into zones like Linux md:
+/// the first zone stripes over all members up to the size of the smallest one,
+/// the next over the remaining members up to the next size, and so on.
+pub struct StripedDevice {
+    devices: Vec<Arc<dyn Device>>,
+    stripe_size: usize,
+    zones: Vec<Zone>,
+}
+
+struct Zone {
+    /// Offset of the zone on the striped device
+    begin: usize,
+    /// Offset of the zone on each member
+    dev_begin: usize,
+    /// Index of the members in this zone
+    members: Vec<usize>,
+}
+
+impl StripedDevice {
+    /// Combine `devices`, given as `(device, size)`, with stripes of `stripe_size` bytes
+    pub fn new(devices: Vec<(Arc<dyn Device>, usize)>, stripe_size: usize) -> Self {
+        assert!(stripe_size > 0, "stripe size must be positive");
+        // the tail less than a stripe is not used
+        let sizes: Vec<usize> = devices
+            .iter()
+            .map(|(_, size)| size / stripe_size * stripe_size)
+            .collect();
+        let mut bounds = sizes.clone();
+        bounds.sort();
+        bounds.dedup();
+        let mut zones = Vec::new();
+        let mut begin = 0;
+        let mut dev_begin = 0;
+        for &dev_end in bounds.iter().filter(|&&size| size > 0) {
+            let members: Vec<usize> = (0..sizes.len()).filter(|&i| sizes[i] >= dev_end).collect();
+            let len = (dev_end - dev_begin) * members.len();
+            zones.push(Zone {
+                begin,
+                dev_begin,
+                members,
+            });
+            begin += len;
+            dev_begin = dev_end;
+        }
+        zones.push(Zone {
+            begin,
+            dev_begin,
+            members: Vec::new(),
+        });
+        StripedDevice {
+            devices: devices.into_iter().map(|(device, _)| device).collect(),
+            stripe_size,
+            zones,
+        }
+    }
+
+    /// Total size in bytes
+    pub fn size(&self) -> usize {
+        self.zones.last().unwrap().begin
+    }
+
+    /// Map `offset` to `(member, offset on member, bytes left in the stripe)`
+    fn map(&self, offset: usize) -> Option<(usize, usize, usize)> {
+        let zone = self.zones.iter().rev().find(|zone| zone.begin <= offset)?;
+        if zone.members.is_empty() {
+            return None;
+        }
+        let stripe = (offset - zone.begin) / self.stripe_size;
+        let in_stripe = (offset - zone.begin) % self.stripe_size;
+        let n = zone.members.len();
+        let dev_offset = zone.dev_begin + stripe / n * self.stripe_size + in_stripe;
+        Some((
+            zone.members[stripe % n],
+            dev_offset,
+            self.stripe_size - in_stripe,
+        ))
+    }
+}
+
+impl Device for StripedDevice {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let mut done = 0;
+        while done < buf.len() {
+            let (dev, dev_offset, left) = match self.map(offset + done) {
+                Some(map) => map,
+                None => break,
+            };
+            let len = left.min(buf.len() - done);
+            let read = self.devices[dev].read_at(dev_offset, &mut buf[done..done + len])?;
+            done += read;
+            if read < len {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        let mut done = 0;
+        while done < buf.len() {
+            let (dev, dev_offset, left) = match self.map(offset + done) {
+                Some(map) => map,
+                None => break,
+            };
+            let len = left.min(buf.len() - done);
+            let written = self.devices[dev].write_at(dev_offset, &buf[done..done + len])?;
+            done += written;
+            if written < len {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    fn sync(&self) -> Result<()> {
+        for device in self.devices.iter() {
+            device.sync()?;
+        }
+        Ok(())
+    }
+
+    fn trim(&self, range: Range<usize>) -> Result<()> {
+        let mut offset = range.start;
+        while offset < range.end {
+            let (dev, dev_offset, left) = match self.map(offset) {
+                Some(map) => map,
+                None => break,
+            };
+            let len = left.min(range.end - offset);
+            self.devices[dev].trim(dev_offset..dev_offset + len)?;
+            offset += len;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        for device in self.devices.iter() {
+            device.flush()?;
+        }
+        Ok(())
+    }
+
+    fn sector_size_log2(&self) -> u8 {
+        self.devices
+            .iter()
+            .map(|device| device.sector_size_log2())
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// RAID-1: every block is written to all members, with its CRC-32.
+///
+/// The checksums are kept in a table after the data on each member.
+/// A block is read from the members in turn until one matches its checksum,
+/// then the members which did not match are repaired with it.
+/// A zero checksum means the block has never been written, and is not checked.
+pub struct MirroredDevice {
+    devices: Vec<Arc<dyn Device>>,
+    block_size_log2: u8,
+    /// Number of data blocks
+    blocks: usize,
+    /// Serialize requests, so that data and checksums of all members match
+    lock: Mutex<()>,
+}
+
+impl MirroredDevice {
+    /// Combine `devices`, given as `(device, size)`, checking every `1 << block_size_log2` bytes.
+    /// The size is limited by the smallest member.
+    pub fn new(devices: Vec<(Arc<dyn Device>, usize)>, block_size_log2: u8) -> Self {
+        assert!(!devices.is_empty(), "no device to mirror");
+        let block_size = 1usize << block_size_log2;
+        let size = devices.iter().map(|(_, size)| *size).min().unwrap();
+        // data blocks and the checksum table must fit
+        let table_blocks = |blocks: usize| (blocks * 4 + block_size - 1) / block_size;
+        let mut blocks = size / (block_size + 4);
+        while blocks > 0 && (blocks + table_blocks(blocks)) * block_size > size {
+            blocks -= 1;
+        }
+        MirroredDevice {
+            devices: devices.into_iter().map(|(device, _)| device).collect(),
+            block_size_log2,
+            blocks,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Total size in bytes
+    pub fn size(&self) -> usize {
+        self.blocks << self.block_size_log2
+    }
+
+    /// Offset of the checksum of `block` on members
+    fn checksum_offset(&self, block: BlockId) -> usize {
+        self.size() + block * 4
+    }
+
+    /// Read `block` from one member, return whether it matches the checksum
+    fn read_member(&self, device: &Arc<dyn Device>, block: BlockId, buf: &mut [u8]) -> bool {
+        let mut sum = [0u8; 4];
+        let ok = device.read_at(block << self.block_size_log2, buf) == Ok(buf.len())
+            && device.read_at(self.checksum_offset(block), &mut sum) == Ok(4);
+        let sum = u32::from_le_bytes(sum);
+        ok && (sum == 0 || sum == crc32(buf))
+    }
+
+    /// Write `block` with its checksum to one member
+    fn write_member(&self, device: &Arc<dyn Device>, block: BlockId, buf: &[u8]) -> Result<()> {
+        let sum = crc32(buf).to_le_bytes();
+        if device.write_at(block << self.block_size_log2, buf)? != buf.len()
+            || device.write_at(self.checksum_offset(block), &sum)? != 4
+        {
+            return Err(DevError::Io);
+        }
+        Ok(())
+    }
+
+    /// Read `block` from the first good member, and repair the bad ones
+    fn read_block(&self, block: BlockId, buf: &mut [u8]) -> Result<()> {
+        let mut bad = Vec::new();
+        for device in self.devices.iter() {
+            if self.read_member(device, block, buf) {
+                for device in bad {
+                    self.write_member(device, block, buf)?;
+                }
+                return Ok(());
+            }
+            bad.push(device);
+        }
+        Err(DevError::Io)
+    }
+
+    fn write_block(&self, block: BlockId, buf: &[u8]) -> Result<()> {
+        for device in self.devices.iter() {
+            self.write_member(device, block, buf)?;
+        }
+        Ok(())
+    }
+}
+
+impl Device for MirroredDevice {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let end = (offset + buf.len()).min(self.size());
+        if offset >= end {
+            return Ok(0);
+        }
+        let iter = BlockIter {
+            begin: offset,
+            end,
+            block_size_log2: self.block_size_log2,
+        };
+        let _lock = self.lock.lock();
+        let mut block_buf = vec![0u8; 1 << self.block_size_log2];
+        for range in iter {
+            self.read_block(range.block, &mut block_buf)?;
+            buf[range.origin_begin() - offset..range.origin_end() - offset]
+                .copy_from_slice(&block_buf[range.begin..range.end]);
+        }
+        Ok(end - offset)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        let end = (offset + buf.len()).min(self.size());
+        if offset >= end {
+            return Ok(0);
+        }
+        let iter = BlockIter {
+            begin: offset,
+            end,
+            block_size_log2: self.block_size_log2,
+        };
+        let _lock = self.lock.lock();
+        let mut block_buf = vec![0u8; 1 << self.block_size_log2];
+        for range in iter {
+            let data = &buf[range.origin_begin() - offset..range.origin_end() - offset];
+            if range.is_full() {
+                self.write_block(range.block, data)?;
+            } else {
+                self.read_block(range.block, &mut block_buf)?;
+                block_buf[range.begin..range.end].copy_from_slice(data);
+                self.write_block(range.block, &block_buf)?;
+            }
+        }
+        Ok(end - offset)
+    }
+
+    fn sync(&self) -> Result<()> {
+        for device in self.devices.iter() {
+            device.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Clear the checksums of blocks inside `range`, so that they are not checked,
+    /// then discard them on all members
+    fn trim(&self, range: Range<usize>) -> Result<()> {
+        let block_size = 1 << self.block_size_log2;
+        let begin = (range.start + block_size - 1) >> self.block_size_log2;
+        let end = (range.end >> self.block_size_log2).min(self.blocks);
+        if begin >= end {
+            return Ok(());
+        }
+        let _lock = self.lock.lock();
+        let zeros = vec![0u8; (end - begin) * 4];
+        for device in self.devices.iter() {
+            if device.write_at(self.checksum_offset(begin), &zeros)? != zeros.len() {
+                return Err(DevError::Io);
+            }
+            device.trim(begin << self.block_size_log2..end << self.block_size_log2)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        for device in self.devices.iter() {
+            device.flush()?;
+        }
+        Ok(())
+    }
+
+    fn sector_size_log2(&self) -> u8 {
+        self.devices
+            .iter()
+            .map(|device| device.sector_size_log2())
+            .fold(self.block_size_log2, u8::max)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    struct MemDevice(Mutex<Vec<u8>>);
+
+    impl Device for MemDevice {
+        fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+            let data = self.0.lock().unwrap();
+            let len = buf.len().min(data.len().saturating_sub(offset));
+            buf[..len].copy_from_slice(&data[offset..offset + len]);
+            Ok(len)
+        }
+        fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+            let mut data = self.0.lock().unwrap();
+            let len = buf.len().min(data.len().saturating_sub(offset));
+            data[offset..offset + len].copy_from_slice(&buf[..len]);
+            Ok(len)
+        }
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+        /// Trimmed bytes read as garbage
+        fn trim(&self, range: Range<usize>) -> Result<()> {
+            for b in self.0.lock().unwrap()[range].iter_mut() {
+                *b = 0xff;
+            }
+            Ok(())
+        }
+    }
+
+    fn mem(size: usize) -> Arc<MemDevice> {
+        Arc::new(MemDevice(Mutex::new(vec![0; size])))
+    }
+
+    #[test]
+    fn striped() {
+        let (a, b) = (mem(16), mem(8));
+        let device = StripedDevice::new(
+            vec![
+                (a.clone() as Arc<dyn Device>, 16),
+                (b.clone() as Arc<dyn Device>, 8),
+            ],
+            4,
+        );
+        assert_eq!(device.size(), 24);
+        let data: Vec<u8> = (0..24).collect();
+        assert_eq!(device.write_at(0, &data), Ok(24));
+        // zone 0 interleaves both, zone 1 is on `a` only
+        assert_eq!(
+            a.0.lock().unwrap()[..],
+            [0, 1, 2, 3, 8, 9, 10, 11, 16, 17, 18, 19, 20, 21, 22, 23]
+        );
+        assert_eq!(b.0.lock().unwrap()[..], [4, 5, 6, 7, 12, 13, 14, 15]);
+        let mut buf = [0u8; 24];
+        assert_eq!(device.read_at(2, &mut buf), Ok(22));
+        assert_eq!(buf[..22], data[2..]);
+    }
+
+    #[test]
+    fn mirrored_read_repair() {
+        let (a, b) = (mem(64), mem(80));
+        let device = MirroredDevice::new(
+            vec![
+                (a.clone() as Arc<dyn Device>, 64),
+                (b.clone() as Arc<dyn Device>, 80),
+            ],
+            4,
+        );
+        // 3 blocks of data and 1 block of checksums
+        assert_eq!(device.size(), 48);
+        assert_eq!(device.write_at(5, b"hello"), Ok(5));
+
+        // corrupt the first mirror
+        a.0.lock().unwrap()[6] = b'E';
+        let mut buf = [0u8; 5];
+        assert_eq!(device.read_at(5, &mut buf), Ok(5));
+        assert_eq!(&buf, b"hello");
+        assert_eq!(&a.0.lock().unwrap()[5..10], b"hello");
+
+        // both are corrupted
+        a.0.lock().unwrap()[6] = b'E';
+        b.0.lock().unwrap()[6] = b'E';
+        assert_eq!(device.read_at(5, &mut buf), Err(DevError::Io));
+    }
+
+    #[test]
+    fn trim() {
+        let (a, b) = (mem(16), mem(8));
+        let device = StripedDevice::new(
+            vec![
+                (a.clone() as Arc<dyn Device>, 16),
+                (b.clone() as Arc<dyn Device>, 8),
+            ],
+            4,
+        );
+        device.trim(6..18).unwrap();
+        assert_eq!(
+            a.0.lock().unwrap()[..],
+            [0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            b.0.lock().unwrap()[..],
+            [0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]
+        );
+
+        let device = MirroredDevice::new(
+            vec![
+                (mem(32) as Arc<dyn Device>, 32),
+                (mem(32) as Arc<dyn Device>, 32),
+            ],
+            1,
+        );
+        assert_eq!(device.write_at(0, &[1, 2, 3, 4]), Ok(4));
+        // only the second block is inside
+        device.trim(1..4).unwrap();
+        let mut buf = [0u8; 4];
+        assert_eq!(device.read_at(0, &mut buf), Ok(4));
+        assert_eq!(buf[..2], [1, 2]);
+    }
+}
@@ -5,6 +5,8 @@ use std::io::{Error, Read, Seek, SeekFrom, Write};
 use std::sync::Mutex;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+pub use super::mem::MemDevice;
+use super::mem::{FrameAllocator, FRAME_SIZE};
 use super::*;
 
 impl Device for Mutex<File> {
@@ -29,6 +31,515 @@ impl Device for Mutex<File> {
         file.sync_all()?;
         Ok(())
     }
+
+    /// Punch a hole in the file, so that the host can discard the blocks
+    #[cfg(target_os = "linux")]
+    fn trim(&self, range: Range<usize>) -> Result<()> {
+        punch_hole(&self.lock().unwrap(), range)
+    }
+
+    fn flush(&self) -> Result<()> {
+        let file = self.lock().unwrap();
+        file.sync_data()?;
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn punch_hole(file: &File, range: Range<usize>) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+    if range.start >= range.end {
+        return Ok(());
+    }
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            range.start as libc::off_t,
+            (range.end - range.start) as libc::off_t,
+        )
+    };
+    if ret != 0 && Error::last_os_error().raw_os_error() != Some(libc::EOPNOTSUPP) {
+        return Err(DevError::Io);
+    }
+    Ok(())
+}
+
+#[cfg(all(target_os = "linux", feature = "uring"))]
+pub use self::uring::UringDevice;
+
+#[cfg(all(target_os = "linux", feature = "uring"))]
+mod uring {
+    use super::*;
+    use core::mem::size_of;
+    use core::ptr;
+    use core::sync::atomic::{AtomicU32, Ordering};
+    use std::io::ErrorKind;
+    use std::os::unix::io::{AsRawFd, RawFd};
+
+    /// Entries of the queues, the most requests submitted at once
+    const ENTRIES: usize = 32;
+    /// Bytes of each request, a large R/W is split into them
+    const CHUNK_SIZE: usize = 128 * 1024;
+
+    // from linux/io_uring.h, as the crates for it need a newer toolchain.
+    // The syscalls added since 5.1 have the same numbers on every arch Rust has a Linux
+    // target for; an older kernel fails `io_uring_setup` with ENOSYS, which
+    // `UringDevice::new()` returns. `test::syscalls` checks them against libc.
+    const SYS_IO_URING_SETUP: libc::c_long = 425;
+    const SYS_IO_URING_ENTER: libc::c_long = 426;
+    const IORING_OFF_SQ_RING: libc::off_t = 0;
+    const IORING_OFF_CQ_RING: libc::off_t = 0x800_0000;
+    const IORING_OFF_SQES: libc::off_t = 0x1000_0000;
+    const IORING_ENTER_GETEVENTS: libc::c_uint = 1;
+    const IORING_OP_READ: u8 = 22;
+    const IORING_OP_WRITE: u8 = 23;
+
+    /// Offsets of the fields of the submission queue in its mapping
+    #[repr(C)]
+    #[derive(Default)]
+    struct SqOffsets {
+        head: u32,
+        tail: u32,
+        ring_mask: u32,
+        ring_entries: u32,
+        flags: u32,
+        dropped: u32,
+        array: u32,
+        resv: [u32; 3],
+    }
+
+    /// Offsets of the fields of the completion queue in its mapping
+    #[repr(C)]
+    #[derive(Default)]
+    struct CqOffsets {
+        head: u32,
+        tail: u32,
+        ring_mask: u32,
+        ring_entries: u32,
+        overflow: u32,
+        cqes: u32,
+        flags: u32,
+        resv: [u32; 3],
+    }
+
+    /// `struct io_uring_params`
+    #[repr(C)]
+    #[derive(Default)]
+    struct Params {
+        sq_entries: u32,
+        cq_entries: u32,
+        flags: u32,
+        sq_thread_cpu: u32,
+        sq_thread_idle: u32,
+        features: u32,
+        wq_fd: u32,
+        resv: [u32; 3],
+        sq_off: SqOffsets,
+        cq_off: CqOffsets,
+    }
+
+    /// A request, `struct io_uring_sqe`
+    #[repr(C)]
+    #[derive(Default)]
+    struct Sqe {
+        opcode: u8,
+        flags: u8,
+        ioprio: u16,
+        fd: i32,
+        off: u64,
+        addr: u64,
+        len: u32,
+        rw_flags: u32,
+        user_data: u64,
+        pad: [u64; 3],
+    }
+
+    /// A completion, `struct io_uring_cqe`
+    #[repr(C)]
+    struct Cqe {
+        user_data: u64,
+        res: i32,
+        flags: u32,
+    }
+
+    /// A part of the ring mapped into memory
+    struct Mmap {
+        ptr: *mut u8,
+        len: usize,
+    }
+
+    impl Mmap {
+        fn new(fd: RawFd, offset: libc::off_t, len: usize) -> std::io::Result<Self> {
+            let ptr = unsafe {
+                libc::mmap(
+                    ptr::null_mut(),
+                    len,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED | libc::MAP_POPULATE,
+                    fd,
+                    offset,
+                )
+            };
+            if ptr == libc::MAP_FAILED {
+                return Err(Error::last_os_error());
+            }
+            Ok(Mmap {
+                ptr: ptr as *mut u8,
+                len,
+            })
+        }
+
+        /// The `T` at byte `offset`
+        unsafe fn at<T>(&self, offset: u32) -> *mut T {
+            self.ptr.add(offset as usize) as *mut T
+        }
+    }
+
+    impl Drop for Mmap {
+        fn drop(&mut self) {
+            unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+        }
+    }
+
+    /// An io_uring, with its queues mapped
+    struct Ring {
+        fd: RawFd,
+        params: Params,
+        sq: Mmap,
+        cq: Mmap,
+        sqes: Mmap,
+        /// requests submitted whose completions are not taken yet
+        in_flight: u32,
+        /// fail the next `enter()` after submitting at most this many entries
+        #[cfg(test)]
+        fail_enter: Option<u32>,
+    }
+
+    // only used under the lock of `UringDevice`
+    unsafe impl Send for Ring {}
+
+    impl Ring {
+        fn new(entries: u32) -> std::io::Result<Self> {
+            let mut params = Params::default();
+            let fd =
+                unsafe { libc::syscall(SYS_IO_URING_SETUP, entries, &mut params as *mut Params) };
+            if fd < 0 {
+                return Err(Error::last_os_error());
+            }
+            let fd = fd as RawFd;
+            let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+            let cq_len =
+                params.cq_off.cqes as usize + params.cq_entries as usize * size_of::<Cqe>();
+            let sqes_len = params.sq_entries as usize * size_of::<Sqe>();
+            let maps = (|| -> std::io::Result<_> {
+                Ok((
+                    Mmap::new(fd, IORING_OFF_SQ_RING, sq_len)?,
+                    Mmap::new(fd, IORING_OFF_CQ_RING, cq_len)?,
+                    Mmap::new(fd, IORING_OFF_SQES, sqes_len)?,
+                ))
+            })();
+            match maps {
+                Ok((sq, cq, sqes)) => Ok(Ring {
+                    fd,
+                    params,
+                    sq,
+                    cq,
+                    sqes,
+                    in_flight: 0,
+                    #[cfg(test)]
+                    fail_enter: None,
+                }),
+                Err(e) => {
+                    unsafe { libc::close(fd) };
+                    Err(e)
+                }
+            }
+        }
+
+        /// Queue `sqe`. At most `ENTRIES` are queued before `submit_and_wait()`.
+        fn push(&mut self, sqe: Sqe) {
+            let off = &self.params.sq_off;
+            unsafe {
+                let tail = &*self.sq.at::<AtomicU32>(off.tail);
+                let index = {
+                    // only changed by us
+                    let tail = tail.load(Ordering::Relaxed);
+                    tail & *self.sq.at::<u32>(off.ring_mask)
+                };
+                ptr::write(self.sqes.at::<Sqe>(0).add(index as usize), sqe);
+                *self.sq.at::<u32>(off.array).add(index as usize) = index;
+                tail.fetch_add(1, Ordering::Release);
+            }
+        }
+
+        /// Completions ready to be taken
+        fn ready(&self) -> u32 {
+            let off = &self.params.cq_off;
+            unsafe {
+                let head = (*self.cq.at::<AtomicU32>(off.head)).load(Ordering::Relaxed);
+                let tail = (*self.cq.at::<AtomicU32>(off.tail)).load(Ordering::Acquire);
+                tail.wrapping_sub(head)
+            }
+        }
+
+        /// Submit up to `to_submit` of the entries queued, and wait for `wait` completions.
+        /// Return the entries submitted.
+        fn enter(&mut self, to_submit: u32, wait: u32) -> std::io::Result<u32> {
+            #[cfg(test)]
+            {
+                if let Some(max) = self.fail_enter.take() {
+                    // as if the kernel failed after taking some of them
+                    self.enter(to_submit.min(max), 0)?;
+                    return Err(Error::from_raw_os_error(libc::EIO));
+                }
+            }
+            loop {
+                let ret = unsafe {
+                    libc::syscall(
+                        SYS_IO_URING_ENTER,
+                        self.fd,
+                        to_submit,
+                        wait,
+                        IORING_ENTER_GETEVENTS,
+                        ptr::null::<libc::sigset_t>(),
+                        0usize,
+                    )
+                };
+                if ret >= 0 {
+                    self.in_flight += ret as u32;
+                    return Ok(ret as u32);
+                }
+                let err = Error::last_os_error();
+                if err.kind() != ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+        }
+
+        /// Submit the `count` entries queued, and wait for as many completions.
+        /// If it fails, none is left queued or in flight, see `cancel()`.
+        fn submit_and_wait(&mut self, count: u32) -> std::io::Result<()> {
+            let mut submitted = 0;
+            while submitted < count || self.ready() < count {
+                let wait = count.saturating_sub(self.ready());
+                match self.enter(count - submitted, wait) {
+                    Ok(n) => submitted += n,
+                    Err(err) => {
+                        self.cancel();
+                        return Err(err);
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        /// Drop the entries queued but not submitted, and wait for those in flight, dropping
+        /// their completions, as the buffers they point to are the caller's once we return
+        fn cancel(&mut self) {
+            let off = &self.params.sq_off;
+            unsafe {
+                // the kernel takes entries only in `enter()`, so the rest are still ours
+                let head = (*self.sq.at::<AtomicU32>(off.head)).load(Ordering::Acquire);
+                (*self.sq.at::<AtomicU32>(off.tail)).store(head, Ordering::Release);
+            }
+            while self.ready() < self.in_flight {
+                let wait = self.in_flight - self.ready();
+                if let Err(err) = self.enter(0, wait) {
+                    match err.raw_os_error() {
+                        Some(libc::EAGAIN) | Some(libc::EBUSY) => {}
+                        // we can not tell when the kernel is done with the buffers
+                        _ => std::process::abort(),
+                    }
+                }
+            }
+            self.complete(|_, _| {});
+        }
+
+        /// Take the completions ready, each as `(user_data, res)`
+        fn complete(&mut self, mut f: impl FnMut(u64, i32)) {
+            let off = &self.params.cq_off;
+            unsafe {
+                let head = &*self.cq.at::<AtomicU32>(off.head);
+                let tail = (*self.cq.at::<AtomicU32>(off.tail)).load(Ordering::Acquire);
+                let mask = *self.cq.at::<u32>(off.ring_mask);
+                let first = head.load(Ordering::Relaxed);
+                let mut next = first;
+                while next != tail {
+                    let cqe = &*self.cq.at::<Cqe>(off.cqes).add((next & mask) as usize);
+                    f(cqe.user_data, cqe.res);
+                    next = next.wrapping_add(1);
+                }
+                head.store(next, Ordering::Release);
+                self.in_flight -= next.wrapping_sub(first);
+            }
+        }
+    }
+
+    impl Drop for Ring {
+        fn drop(&mut self) {
+            unsafe { libc::close(self.fd) };
+        }
+    }
+
+    /// A file R/W by io_uring.
+    ///
+    /// The chunks of a large R/W are submitted in batches by one system call,
+    /// so the host can do them in parallel, unlike `Mutex<File>`.
+    pub struct UringDevice {
+        file: File,
+        ring: Mutex<Ring>,
+    }
+
+    impl UringDevice {
+        pub fn new(file: File) -> std::io::Result<Self> {
+            Ok(UringDevice {
+                file,
+                ring: Mutex::new(Ring::new(ENTRIES as u32)?),
+            })
+        }
+
+        /// Do `len` bytes at `offset` by requests made by `entry(offset, len)` of each chunk.
+        /// Return the bytes done before the first short one.
+        fn run(
+            &self,
+            offset: usize,
+            len: usize,
+            entry: impl Fn(usize, usize) -> Sqe,
+        ) -> Result<usize> {
+            let chunks = (len + CHUNK_SIZE - 1) / CHUNK_SIZE;
+            let chunk_len = |i: usize| CHUNK_SIZE.min(len - i * CHUNK_SIZE);
+            let mut done = vec![0usize; chunks];
+            let mut ring = self.ring.lock().unwrap();
+            for batch in (0..chunks).step_by(ENTRIES) {
+                let batch = batch..(batch + ENTRIES).min(chunks);
+                for i in batch.clone() {
+                    // the buffer outlives the request, as we wait for it below
+                    ring.push(Sqe {
+                        user_data: i as u64,
+                        ..entry(offset + i * CHUNK_SIZE, chunk_len(i))
+                    });
+                }
+                ring.submit_and_wait(batch.len() as u32)?;
+                let mut failed = false;
+                ring.complete(|i, ret| match ret {
+                    ret if ret < 0 => failed = true,
+                    ret => done[i as usize] = ret as usize,
+                });
+                if failed {
+                    return Err(DevError::Io);
+                }
+            }
+            let mut total = 0;
+            for (i, &n) in done.iter().enumerate() {
+                total += n;
+                if n < chunk_len(i) {
+                    break;
+                }
+            }
+            Ok(total)
+        }
+    }
+
+    impl Device for UringDevice {
+        fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+            let ptr = buf.as_mut_ptr();
+            self.run(offset, buf.len(), |pos, len| Sqe {
+                opcode: IORING_OP_READ,
+                fd: self.file.as_raw_fd(),
+                off: pos as u64,
+                addr: unsafe { ptr.add(pos - offset) } as u64,
+                len: len as u32,
+                ..Sqe::default()
+            })
+        }
+
+        fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+            let ptr = buf.as_ptr();
+            self.run(offset, buf.len(), |pos, len| Sqe {
+                opcode: IORING_OP_WRITE,
+                fd: self.file.as_raw_fd(),
+                off: pos as u64,
+                addr: unsafe { ptr.add(pos - offset) } as u64,
+                len: len as u32,
+                ..Sqe::default()
+            })
+        }
+
+        fn sync(&self) -> Result<()> {
+            self.file.sync_all()?;
+            Ok(())
+        }
+
+        fn trim(&self, range: Range<usize>) -> Result<()> {
+            punch_hole(&self.file, range)
+        }
+
+        fn flush(&self) -> Result<()> {
+            self.file.sync_data()?;
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn layout() {
+            assert_eq!(size_of::<Params>(), 120);
+            assert_eq!(size_of::<Sqe>(), 64);
+            assert_eq!(size_of::<Cqe>(), 16);
+        }
+
+        #[test]
+        fn syscalls() {
+            assert_eq!(SYS_IO_URING_SETUP, libc::SYS_io_uring_setup);
+            assert_eq!(SYS_IO_URING_ENTER, libc::SYS_io_uring_enter);
+        }
+
+        #[test]
+        fn read_write() {
+            let file = tempfile::tempfile().unwrap();
+            let device = UringDevice::new(file).unwrap();
+            // more chunks than a batch
+            let data: Vec<u8> = (0..(ENTRIES + 3) * CHUNK_SIZE + 5)
+                .map(|i| (i % 251) as u8)
+                .collect();
+            assert_eq!(device.write_at(7, &data), Ok(data.len()));
+            let mut buf = vec![0u8; data.len() + 100];
+            // short at the end of file
+            assert_eq!(device.read_at(7, &mut buf), Ok(data.len()));
+            assert_eq!(&buf[..data.len()], &data[..]);
+        }
+
+        #[test]
+        fn failed_enter() {
+            let file = tempfile::tempfile().unwrap();
+            let device = UringDevice::new(file).unwrap();
+            let data: Vec<u8> = (0..ENTRIES * CHUNK_SIZE).map(|i| (i % 251) as u8).collect();
+            assert_eq!(device.write_at(0, &data), Ok(data.len()));
+            // with 5 of the batch submitted, and the rest still queued
+            device.ring.lock().unwrap().fail_enter = Some(5);
+            let mut buf = vec![0u8; data.len()];
+            assert_eq!(device.read_at(0, &mut buf), Err(DevError::Io));
+            {
+                let ring = device.ring.lock().unwrap();
+                assert_eq!(ring.in_flight, 0);
+                assert_eq!(ring.ready(), 0);
+                let off = &ring.params.sq_off;
+                let (head, tail) =
+                    unsafe { (*ring.sq.at::<u32>(off.head), *ring.sq.at::<u32>(off.tail)) };
+                assert_eq!(head, tail);
+            }
+            // those submitted done before it returned, and the rest never
+            assert_eq!(&buf[..5 * CHUNK_SIZE], &data[..5 * CHUNK_SIZE]);
+            let mut again = vec![0u8; data.len()];
+            assert_eq!(device.read_at(0, &mut again), Ok(data.len()));
+            assert_eq!(again, data);
+            assert!(buf[5 * CHUNK_SIZE..].iter().all(|&byte| byte == 0));
+        }
+    }
 }
 
 pub struct StdTimeProvider;
@@ -41,10 +552,40 @@ impl TimeProvider for StdTimeProvider {
             nsec: duration.subsec_nanos() as i32,
         }
     }
+
+    #[cfg(unix)]
+    fn monotonic_time(&self) -> Timespec {
+        let mut time = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time) };
+        Timespec {
+            sec: time.tv_sec as i64,
+            nsec: time.tv_nsec as i32,
+        }
+    }
+}
+
+/// Frames from the heap of the process
+pub struct HeapAllocator;
+
+impl FrameAllocator for HeapAllocator {
+    type Frame = Box<[u8]>;
+    fn alloc(&self) -> Option<Box<[u8]>> {
+        Some(vec![0; FRAME_SIZE].into_boxed_slice())
+    }
+}
+
+impl MemDevice<HeapAllocator> {
+    /// A device of `size` bytes in the heap, for tests
+    pub fn with_size(size: usize) -> Self {
+        MemDevice::new(HeapAllocator, size)
+    }
 }
 
 impl From<Error> for DevError {
     fn from(_: Error) -> Self {
-        DevError
+        DevError::Io
     }
 }
@@ -0,0 +1,218 @@
+//! Leases on files, so that several holders can cache file data safely,
+//! e.g. the local kernel and clients of a FUSE or network export
+//!
+//! A holder of a read lease may cache file data, and a holder of a write lease
+//! may also cache writes. A request conflicting with the leases of other holders
+//! breaks them: their break callbacks are called, and the request fails with
+//! `FsError::Again` until they are released or downgraded.
+//! Leases are keyed by inode id inside one file system.
+
+use crate::vfs::{FsError, Result};
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use spin::Mutex;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LeaseType {
+    Read,
+    Write,
+}
+
+impl LeaseType {
+    fn conflicts(self, other: LeaseType) -> bool {
+        self == LeaseType::Write || other == LeaseType::Write
+    }
+}
+
+/// Identify the holder of a lease, e.g. a client connection
+pub type HolderId = usize;
+
+/// Called with the inode id and the lease the holder may keep, `None` for nothing.
+/// The holder should write back its cache, then `downgrade()` or `release()`.
+/// It must not block waiting for the new request.
+pub type BreakCallback = Arc<dyn Fn(usize, Option<LeaseType>) + Send + Sync>;
+
+struct Lease {
+    holder: HolderId,
+    type_: LeaseType,
+    on_break: BreakCallback,
+    /// The lease it is being broken to
+    breaking: Option<Option<LeaseType>>,
+}
+
+#[derive(Default)]
+pub struct LeaseManager {
+    leases: Mutex<BTreeMap<usize, Vec<Lease>>>,
+}
+
+impl LeaseManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Acquire a lease on `inode` for `holder`, or change the one it holds.
+    /// Return `FsError::Again` if leases of other holders are being broken.
+    pub fn acquire(
+        &self,
+        inode: usize,
+        holder: HolderId,
+        type_: LeaseType,
+        on_break: BreakCallback,
+    ) -> Result<()> {
+        if self.break_conflicts(inode, Some(holder), type_) {
+            let mut leases = self.leases.lock();
+            let list = leases.entry(inode).or_insert_with(Vec::new);
+            list.retain(|lease| lease.holder != holder);
+            list.push(Lease {
+                holder,
+                type_,
+                on_break,
+                breaking: None,
+            });
+            return Ok(());
+        }
+        Err(FsError::Again)
+    }
+
+    /// Break leases conflicting with an access of `type_` without a lease,
+    /// e.g. a write by the local kernel.
+    /// Return `FsError::Again` if they are not released or downgraded yet.
+    pub fn break_leases(&self, inode: usize, type_: LeaseType) -> Result<()> {
+        match self.break_conflicts(inode, None, type_) {
+            true => Ok(()),
+            false => Err(FsError::Again),
+        }
+    }
+
+    /// Downgrade the write lease of `holder` to a read lease
+    pub fn downgrade(&self, inode: usize, holder: HolderId) {
+        let mut leases = self.leases.lock();
+        if let Some(lease) = leases
+            .get_mut(&inode)
+            .and_then(|list| list.iter_mut().find(|lease| lease.holder == holder))
+        {
+            lease.type_ = LeaseType::Read;
+            if lease.breaking == Some(Some(LeaseType::Read)) {
+                lease.breaking = None;
+            }
+        }
+    }
+
+    /// Release the lease of `holder`, also used to revoke one whose holder does not respond
+    pub fn release(&self, inode: usize, holder: HolderId) {
+        let mut leases = self.leases.lock();
+        if let Some(list) = leases.get_mut(&inode) {
+            list.retain(|lease| lease.holder != holder);
+            if list.is_empty() {
+                leases.remove(&inode);
+            }
+        }
+    }
+
+    /// Release all leases of `holder`, e.g. when a client disconnects
+    pub fn release_all(&self, holder: HolderId) {
+        let mut leases = self.leases.lock();
+        for list in leases.values_mut() {
+            list.retain(|lease| lease.holder != holder);
+        }
+        leases.retain(|_, list| !list.is_empty());
+    }
+
+    /// The lease `holder` has on `inode`
+    pub fn lease(&self, inode: usize, holder: HolderId) -> Option<LeaseType> {
+        let leases = self.leases.lock();
+        let list = leases.get(&inode)?;
+        list.iter()
+            .find(|lease| lease.holder == holder)
+            .map(|lease| lease.type_)
+    }
+
+    /// Break leases of holders other than `holder` which conflict with `type_`,
+    /// return whether there is none left.
+    /// Callbacks are called without the lock, so they may release leases at once.
+    fn break_conflicts(&self, inode: usize, holder: Option<HolderId>, type_: LeaseType) -> bool {
+        let to = match type_ {
+            LeaseType::Read => Some(LeaseType::Read),
+            LeaseType::Write => None,
+        };
+        let callbacks: Vec<BreakCallback> = {
+            let mut leases = self.leases.lock();
+            let list = match leases.get_mut(&inode) {
+                Some(list) => list,
+                None => return true,
+            };
+            list.iter_mut()
+                .filter(|lease| Some(lease.holder) != holder && lease.type_.conflicts(type_))
+                // do not break again unless it is to a weaker lease
+                .filter(|lease| {
+                    lease
+                        .breaking
+                        .map_or(true, |old| old.is_some() && to.is_none())
+                })
+                .map(|lease| {
+                    lease.breaking = Some(to);
+                    lease.on_break.clone()
+                })
+                .collect()
+        };
+        for callback in callbacks {
+            callback(inode, to);
+        }
+        let leases = self.leases.lock();
+        leases.get(&inode).map_or(true, |list| {
+            !list
+                .iter()
+                .any(|lease| Some(lease.holder) != holder && lease.type_.conflicts(type_))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn ignore() -> BreakCallback {
+        Arc::new(|_, _| {})
+    }
+
+    #[test]
+    fn shared_read() {
+        let leases = LeaseManager::new();
+        leases.acquire(1, 1, LeaseType::Read, ignore()).unwrap();
+        leases.acquire(1, 2, LeaseType::Read, ignore()).unwrap();
+        assert_eq!(leases.break_leases(1, LeaseType::Read), Ok(()));
+        assert_eq!(leases.lease(1, 2), Some(LeaseType::Read));
+    }
+
+    #[test]
+    fn break_write() {
+        let leases = Arc::new(LeaseManager::new());
+        let breaks = Arc::new(Mutex::new(Vec::new()));
+        let breaks1 = breaks.clone();
+        let on_break: BreakCallback = Arc::new(move |inode, to| {
+            breaks1.lock().unwrap().push((inode, to));
+        });
+        leases.acquire(1, 1, LeaseType::Write, on_break).unwrap();
+
+        // the holder has to downgrade before a reader gets its lease
+        assert_eq!(
+            leases.acquire(1, 2, LeaseType::Read, ignore()),
+            Err(FsError::Again)
+        );
+        assert_eq!(
+            leases.acquire(1, 2, LeaseType::Read, ignore()),
+            Err(FsError::Again)
+        );
+        assert_eq!(*breaks.lock().unwrap(), [(1, Some(LeaseType::Read))]);
+        leases.downgrade(1, 1);
+        leases.acquire(1, 2, LeaseType::Read, ignore()).unwrap();
+
+        // a holder releasing in the callback does not make the request wait
+        let leases1 = leases.clone();
+        let on_break: BreakCallback = Arc::new(move |inode, _| leases1.release(inode, 2));
+        leases.acquire(1, 2, LeaseType::Read, on_break).unwrap();
+        leases.release(1, 1);
+        assert_eq!(leases.break_leases(1, LeaseType::Write), Ok(()));
+        assert_eq!(leases.lease(1, 2), None);
+    }
+}
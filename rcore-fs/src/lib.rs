@@ -2,11 +2,15 @@
 
 extern crate alloc;
 
+pub mod dcache;
 pub mod dev;
 pub mod dirty;
 pub mod file;
+pub mod lease;
+pub mod stats;
 pub mod util;
 pub mod vfs;
+pub mod writeback;
 
 #[cfg(any(test, feature = "std"))]
 mod std;
@@ -0,0 +1,57 @@
+//! Statistics counters shared by the device, block cache, block allocator and cleaner of a file system
+
+use crate::vfs::FsStats;
+use core::fmt;
+use spin::Mutex;
+
+/// A set of counters updated under one lock,
+/// so that a snapshot never mixes values from before and after an update
+#[derive(Default)]
+pub struct Stats(Mutex<FsStats>);
+
+impl Stats {
+    /// Update some counters atomically
+    pub fn update(&self, f: impl FnOnce(&mut FsStats)) {
+        f(&mut self.0.lock());
+    }
+
+    /// Sample all counters at one moment
+    pub fn snapshot(&self) -> FsStats {
+        *self.0.lock()
+    }
+}
+
+/// Number of buckets of a `LatencyHistogram`
+pub const LATENCY_BUCKETS: usize = 20;
+
+/// Operations counted by latency in powers of 2:
+/// bucket 0 is under 1us, bucket `i` from `2^(i-1)` to `2^i` us, and the last one anything slower
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct LatencyHistogram(pub [u64; LATENCY_BUCKETS]);
+
+impl LatencyHistogram {
+    /// Count an operation which took `ns` nanoseconds
+    pub fn record(&mut self, ns: u64) {
+        let us = ns / 1000;
+        let bucket = 64 - us.leading_zeros() as usize;
+        self.0[bucket.min(LATENCY_BUCKETS - 1)] += 1;
+    }
+
+    /// Number of operations counted
+    pub fn count(&self) -> u64 {
+        self.0.iter().sum()
+    }
+}
+
+/// Counts of all buckets separated by spaces
+impl fmt::Display for LatencyHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, count) in self.0.iter().enumerate() {
+            if i != 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}", count)?;
+        }
+        Ok(())
+    }
+}
@@ -22,6 +22,7 @@ impl From<std::io::Error> for FsError {
             ErrorKind::WouldBlock => FsError::Again,
             ErrorKind::InvalidInput => FsError::InvalidParam,
             ErrorKind::InvalidData => FsError::InvalidParam,
+            ErrorKind::PermissionDenied => FsError::NotPermitted,
             _ => unimplemented!(),
         }
     }
@@ -54,6 +55,7 @@ impl From<std::fs::Metadata> for Metadata {
                 libc::S_IFDIR => FileType::Dir,
                 libc::S_IFREG => FileType::File,
                 libc::S_IFLNK => FileType::SymLink,
+                libc::S_IFIFO => FileType::NamedPipe,
                 libc::S_IFSOCK => FileType::Socket,
                 _ => unimplemented!("unknown file type"),
             },
@@ -54,10 +54,58 @@ impl Iterator for BlockIter {
     }
 }
 
+/// Tell the CPU that we are in a spin loop.
+/// `core::hint::spin_loop()` is newer than the toolchain, so it is the `spin_loop_hint()`
+/// deprecated by that.
+#[inline]
+#[allow(deprecated)]
+pub fn spin_loop() {
+    core::sync::atomic::spin_loop_hint();
+}
+
+/// CRC-32 (IEEE 802.3) of `data`
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// CRC-32C (Castagnoli) of `data`, as used by iSCSI and ext4
+pub fn crc32c(data: &[u8]) -> u32 {
+    crc32c_update(0, data)
+}
+
+/// CRC-32C of what `crc` is the CRC-32C of, followed by `data`
+pub fn crc32c_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0x82F6_3B78 & mask);
+        }
+    }
+    !crc
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn crc32_check() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(&[]), 0);
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+        assert_eq!(crc32c_update(crc32c(b"1234"), b"56789"), 0xE306_9283);
+    }
+
     #[test]
     fn block_iter() {
         let mut iter = BlockIter {
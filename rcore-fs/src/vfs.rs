@@ -1,5 +1,7 @@
 use crate::dev::DevError;
-use alloc::{string::String, sync::Arc, vec::Vec};
+use crate::stats::LatencyHistogram;
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+use bitflags::bitflags;
 use core::any::Any;
 use core::fmt;
 use core::result;
@@ -57,6 +59,34 @@ pub trait INode: Any + Sync + Send {
         self.create(name, type_, mode)
     }
 
+    /// Create a char or block device `name` in the directory, of device number (major, minor),
+    /// like mknod(2)
+    fn create_device(
+        &self,
+        name: &str,
+        type_: FileType,
+        mode: u32,
+        major: usize,
+        minor: usize,
+    ) -> Result<Arc<dyn INode>> {
+        match type_ {
+            FileType::CharDevice | FileType::BlockDevice => {
+                self.create2(name, type_, mode, make_rdev(major, minor))
+            }
+            _ => Err(FsError::InvalidParam),
+        }
+    }
+
+    /// Get the defaults the directory gives to entries created in it
+    fn dir_defaults(&self) -> Result<DirDefaults> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Set the defaults the directory gives to entries created in it
+    fn set_dir_defaults(&self, _defaults: DirDefaults) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+
     /// Create a hard link `name` to `other`
     fn link(&self, _name: &str, _other: &Arc<dyn INode>) -> Result<()> {
         Err(FsError::NotSupported)
@@ -67,6 +97,12 @@ pub trait INode: Any + Sync + Send {
         Err(FsError::NotSupported)
     }
 
+    /// Delete a hard link `name`, so that the data of the file can not be read again once its
+    /// last link is gone, even where it is left on the device, e.g. by destroying its key
+    fn secure_unlink(&self, _name: &str) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+
     /// Move INode `self/old_name` to `target/new_name`.
     /// If `target` equals `self`, do rename.
     fn move_(&self, _old_name: &str, _target: &Arc<dyn INode>, _new_name: &str) -> Result<()> {
@@ -83,6 +119,40 @@ pub trait INode: Any + Sync + Send {
         Err(FsError::NotSupported)
     }
 
+    /// Get the name of the first directory entry from cookie `cookie` on, with the cookie to
+    /// go on from. Unlike the number of an entry, its cookie stays as others come and go,
+    /// so a dir is listed from cookie 0 in one pass, skipping or repeating none of those
+    /// there all along.
+    fn get_entry_from(&self, cookie: usize) -> Result<(String, usize)> {
+        Ok((self.get_entry(cookie)?, cookie + 1))
+    }
+
+    /// Get the name of directory entry, with the metadata of the INode it refers to
+    fn get_entry_with_metadata(&self, id: usize) -> Result<(Metadata, String)> {
+        let name = self.get_entry(id)?;
+        let metadata = self.find(&name)?.metadata()?;
+        Ok((metadata, name))
+    }
+
+    /// Get up to `max` directory entries from `begin` with the metadata of the INodes
+    /// they refer to, like readdirplus, none past the last entry.
+    /// A FS may read them in a few passes over its blocks, rather than look up each.
+    fn get_entries_with_metadata(
+        &self,
+        begin: usize,
+        max: usize,
+    ) -> Result<Vec<(Metadata, String)>> {
+        let mut entries = Vec::new();
+        for id in begin..begin.saturating_add(max) {
+            match self.get_entry_with_metadata(id) {
+                Ok(entry) => entries.push(entry),
+                Err(FsError::EntryNotFound) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(entries)
+    }
+
     /// Control device
     fn io_control(&self, _cmd: u32, _data: usize) -> Result<()> {
         Err(FsError::NotSupported)
@@ -93,20 +163,88 @@ pub trait INode: Any + Sync + Send {
         Err(FsError::NotSupported)
     }
 
+    /// Copy `len` bytes at `offset` to `dst` at `dst_offset`, return the number of bytes copied.
+    /// A FS may share the data instead, e.g. by reflink.
+    /// Use `copy_range()`, which falls back to reading and writing.
+    fn copy_file_range(
+        &self,
+        _offset: usize,
+        _dst: &Arc<dyn INode>,
+        _dst_offset: usize,
+        _len: usize,
+    ) -> Result<usize> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Get the value of extended attribute `name`, `EntryNotFound` if there is none
+    fn get_xattr(&self, _name: &str) -> Result<Vec<u8>> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Set extended attribute `name` to `value`
+    fn set_xattr(&self, _name: &str, _value: &[u8]) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Get names of all extended attributes
+    fn list_xattr(&self) -> Result<Vec<String>> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Remove extended attribute `name`
+    fn remove_xattr(&self, _name: &str) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+
     /// Get the file system of the INode
     fn fs(&self) -> Arc<dyn FileSystem> {
         unimplemented!();
     }
 
+    /// The INode wrapped by this one, e.g. by a mount wrapper, so that `downcast_ref()` reaches it
+    fn inner_ref(&self) -> Option<&dyn INode> {
+        None
+    }
+
     /// This is used to implement dynamics cast.
     /// Simply return self in the implement of the function.
     fn as_any_ref(&self) -> &dyn Any;
 }
 
 impl dyn INode {
-    /// Downcast the INode to specific struct
+    /// Downcast the INode to specific struct, or the INode it wraps
     pub fn downcast_ref<T: INode>(&self) -> Option<&T> {
-        self.as_any_ref().downcast_ref::<T>()
+        match self.as_any_ref().downcast_ref::<T>() {
+            Some(inode) => Some(inode),
+            None => self.inner_ref()?.downcast_ref::<T>(),
+        }
+    }
+
+    /// Copy `len` bytes at `offset` to `dst` at `dst_offset`, return the number of bytes copied.
+    /// Use `copy_file_range()` of the FS if it can, or read and write.
+    pub fn copy_range(
+        &self,
+        offset: usize,
+        dst: &Arc<dyn INode>,
+        dst_offset: usize,
+        len: usize,
+    ) -> Result<usize> {
+        match self.copy_file_range(offset, dst, dst_offset, len) {
+            Err(FsError::NotSupported) | Err(FsError::NotSameFs) => {}
+            result => return result,
+        }
+        let mut buf = [0u8; 0x1000];
+        let mut copied = 0;
+        while copied < len {
+            let chunk = (len - copied).min(buf.len());
+            let read = self.read_at(offset + copied, &mut buf[..chunk])?;
+            if read == 0 {
+                break;
+            }
+            dst.write_at(dst_offset + copied, &buf[..read])?;
+            copied += read;
+        }
+        Ok(copied)
     }
 
     /// Get all directory entries as a Vec
@@ -115,11 +253,13 @@ impl dyn INode {
         if info.type_ != FileType::Dir {
             return Err(FsError::NotDir);
         }
-        Ok((0..)
-            .map(|i| self.get_entry(i))
-            .take_while(|result| result.is_ok())
-            .filter_map(|result| result.ok())
-            .collect())
+        let mut names = Vec::new();
+        let mut cookie = 0;
+        while let Ok((name, next)) = self.get_entry_from(cookie) {
+            names.push(name);
+            cookie = next;
+        }
+        Ok(names)
     }
 
     /// Lookup path from current INode, and do not follow symlinks
@@ -127,12 +267,34 @@ impl dyn INode {
         self.lookup_follow(path, 0)
     }
 
-    /// Lookup path from current INode, and follow symlinks at most `follow_times` times
-    pub fn lookup_follow(&self, path: &str, mut follow_times: usize) -> Result<Arc<dyn INode>> {
+    /// Lookup path from current INode, and follow symlinks at most `follow_times` times.
+    ///
+    /// "." and ".." are handled here, not by the FS: ".." is found by `find("..")`,
+    /// which crosses mount points, and "/" is the top of the whole tree.
+    pub fn lookup_follow(&self, path: &str, follow_times: usize) -> Result<Arc<dyn INode>> {
+        self.resolve(None, path, follow_times)
+    }
+
+    /// Lookup path from current INode with `root` as "/", e.g. the root of a chroot.
+    /// ".." never goes above `root`, even by symlinks.
+    pub fn lookup_in(
+        &self,
+        root: &Arc<dyn INode>,
+        path: &str,
+        follow_times: usize,
+    ) -> Result<Arc<dyn INode>> {
+        self.resolve(Some(root), path, follow_times)
+    }
+
+    fn resolve(
+        &self,
+        root: Option<&Arc<dyn INode>>,
+        path: &str,
+        mut follow_times: usize,
+    ) -> Result<Arc<dyn INode>> {
         if self.metadata()?.type_ != FileType::Dir {
             return Err(FsError::NotDir);
         }
-        println!("start find .");
         let mut result = self.find(".")?;
         let mut rest_path = String::from(path);
         while rest_path != "" {
@@ -141,7 +303,10 @@ impl dyn INode {
             }
             // handle absolute path
             if let Some('/') = rest_path.chars().next() {
-                result = self.fs().root_inode();
+                result = match root {
+                    Some(root) => root.clone(),
+                    None => top(result)?,
+                };
                 rest_path = String::from(&rest_path[1..]);
                 continue;
             }
@@ -156,15 +321,28 @@ impl dyn INode {
                     rest_path = String::from(&rest_path[pos + 1..]);
                 }
             };
-            if name == "" {
-                continue;
+            match name.as_str() {
+                "" | "." => continue,
+                ".." => {
+                    if let Some(root) = root {
+                        if result.is_same(&**root)? {
+                            continue;
+                        }
+                    }
+                    result = result.find("..")?;
+                    continue;
+                }
+                _ => {}
             }
-            println!("lookup: finding {}", name);
             let inode = result.find(&name)?;
             // Handle symlink
-            if inode.metadata()?.type_ == FileType::SymLink && follow_times > 0 {
+            let info = inode.metadata()?;
+            if info.type_ == FileType::SymLink && follow_times > 0 {
                 follow_times -= 1;
-                let mut content = [0u8; 256];
+                if info.size > PATH_MAX {
+                    return Err(FsError::InvalidParam);
+                }
+                let mut content = vec![0u8; info.size];
                 let len = inode.read_at(0, &mut content)?;
                 let path = str::from_utf8(&content[..len]).map_err(|_| FsError::NotDir)?;
                 // result remains unchanged
@@ -184,6 +362,91 @@ impl dyn INode {
         }
         Ok(result)
     }
+
+    /// Whether `other` is the same INode, which may be reached by another path
+    pub fn is_same(&self, other: &dyn INode) -> Result<bool> {
+        let fs = &*self.fs() as *const dyn FileSystem as *const u8;
+        let other_fs = &*other.fs() as *const dyn FileSystem as *const u8;
+        Ok(fs == other_fs && self.metadata()?.inode == other.metadata()?.inode)
+    }
+
+    /// Sync this INode and all INodes under it, then the directories containing it,
+    /// then the metadata of the file system, so that the subtree is consistent on disk.
+    /// A file does not know the directories it is in, so they are looked for from the root,
+    /// till one for each of its links is found.
+    pub fn sync_recursive(&self) -> Result<()> {
+        let metadata = self.metadata()?;
+        let dirs = match metadata.type_ {
+            FileType::Dir => {
+                let parent = self.find("..")?;
+                // the root is its own parent
+                match parent.metadata()?.inode == metadata.inode {
+                    true => Vec::new(),
+                    false => vec![parent],
+                }
+            }
+            _ => self.containing_dirs(metadata.nlinks)?,
+        };
+        self.sync_tree()?;
+        for dir in dirs {
+            dir.sync_up()?;
+        }
+        self.fs().sync_metadata()
+    }
+
+    /// Sync this directory and the directories containing it, up to the root
+    fn sync_up(&self) -> Result<()> {
+        // each is looked up in before it is synced, as that may change its atime
+        let mut parent = self.find("..")?;
+        self.sync_all()?;
+        let mut id = self.metadata()?.inode;
+        while parent.metadata()?.inode != id {
+            let dir = parent;
+            parent = dir.find("..")?;
+            dir.sync_all()?;
+            id = dir.metadata()?.inode;
+        }
+        Ok(())
+    }
+
+    /// Up to `links` directories with an entry of this INode, looked for from the root
+    fn containing_dirs(&self, links: usize) -> Result<Vec<Arc<dyn INode>>> {
+        let mut found = Vec::new();
+        let mut stack = vec![self.fs().root_inode()];
+        while let Some(dir) = stack.pop() {
+            if found.len() >= links {
+                break;
+            }
+            let mut contains = false;
+            for name in dir.list()? {
+                if name == "." || name == ".." {
+                    continue;
+                }
+                let inode = dir.find(&name)?;
+                if inode.is_same(self)? {
+                    contains = true;
+                } else if inode.metadata()?.type_ == FileType::Dir {
+                    stack.push(inode);
+                }
+            }
+            if contains {
+                found.push(dir);
+            }
+        }
+        Ok(found)
+    }
+
+    /// Sync INodes under this one first, then itself
+    fn sync_tree(&self) -> Result<()> {
+        if self.metadata()?.type_ == FileType::Dir {
+            for name in self.list()? {
+                if name != "." && name != ".." {
+                    self.find(&name)?.sync_tree()?;
+                }
+            }
+        }
+        self.sync_all()
+    }
 }
 
 pub enum IOCTLError {
@@ -193,6 +456,26 @@ pub enum IOCTLError {
     NotCharDevice = 25,  // ENOTTY
 }
 
+/// `io_control()` command to get the `FS_*_FL` flags of an INode into the u32 at `data`,
+/// as in Linux
+pub const FS_IOC_GETFLAGS: u32 = 0x8008_6601;
+/// `io_control()` command to set the `FS_*_FL` flags of an INode to the u32 at `data`
+pub const FS_IOC_SETFLAGS: u32 = 0x4008_6602;
+/// The INode can not be changed, linked to, renamed or removed,
+/// nor can entries of a dir be added or removed
+pub const FS_IMMUTABLE_FL: u32 = 0x10;
+/// The content can only be added to at the end, and the INode can not be renamed or removed,
+/// nor can entries of a dir be removed
+pub const FS_APPEND_FL: u32 = 0x20;
+/// The INode is skipped by backups
+pub const FS_NODUMP_FL: u32 = 0x40;
+
+/// Whether `io_control()` command `cmd` passes a value to set on the INode,
+/// i.e. its direction bits are those of `_IOW` in Linux, as of `FS_IOC_SETFLAGS`
+pub fn io_control_sets(cmd: u32) -> bool {
+    cmd >> 30 == 1
+}
+
 #[derive(Debug, Default)]
 pub struct PollStatus {
     pub read: bool,
@@ -252,9 +535,43 @@ pub struct Metadata {
     pub uid: usize,
     /// Group ID
     pub gid: usize,
-    /// Raw device id
+    /// Raw device id, made by `make_rdev()`
     /// e.g. /dev/null: makedev(0x1, 0x3)
-    pub rdev: usize, // (major << 8) | minor
+    pub rdev: usize,
+}
+
+/// Max length of a path, and so of the target of a symlink
+pub const PATH_MAX: usize = 4096;
+
+/// Set-group-ID bit of `Metadata::mode`.
+/// Entries created in a directory with it get the group of the directory,
+/// and new subdirectories get the bit too.
+pub const S_ISGID: u16 = 0o2000;
+
+/// Defaults a directory gives to entries created in it, see `INode::dir_defaults()`.
+/// New subdirectories inherit them, so they cover a whole project tree.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct DirDefaults {
+    /// Permission bits cleared from the mode given to `create()`
+    pub umask: u16,
+    /// Extended attributes, as `(name, value)`, set on new entries,
+    /// by the file systems having them
+    pub xattrs: Vec<(String, Vec<u8>)>,
+}
+
+impl DirDefaults {
+    /// Mode and group of a new entry of `type_` created with `mode` in directory `dir`
+    pub fn apply(&self, dir: &Metadata, type_: FileType, mode: u32) -> (u16, usize) {
+        let mut mode = mode as u16 & !self.umask;
+        let mut gid = 0;
+        if dir.mode & S_ISGID != 0 {
+            gid = dir.gid;
+            if type_ == FileType::Dir {
+                mode |= S_ISGID;
+            }
+        }
+        (mode, gid)
+    }
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
@@ -297,6 +614,116 @@ pub struct FsInfo {
     pub namemax: usize,
 }
 
+bitflags! {
+    /// Optional features of a file system
+    pub struct FsFeatures: u32 {
+        /// Symbolic links can be created
+        const SYMLINK = 1 << 0;
+        /// Hard links can be created by `link()`
+        const HARDLINK = 1 << 1;
+        /// Extended attributes are supported
+        const XATTR = 1 << 2;
+        /// Holes in files do not take up space
+        const SPARSE = 1 << 3;
+        /// File names are case sensitive
+        const CASE_SENSITIVE = 1 << 4;
+    }
+}
+
+/// What a file system can do, so that upper layers need not probe it
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct FsCapabilities {
+    /// Supported optional features
+    pub features: FsFeatures,
+    /// Maximum filename length
+    pub namemax: usize,
+}
+
+/// Counters of a file system since it is opened
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct FsStats {
+    /// Block cache lookups which found the block
+    pub cache_hits: u64,
+    /// Block cache lookups which missed
+    pub cache_misses: u64,
+    /// Dirty blocks written back by the block cache
+    pub cache_writebacks: u64,
+    /// Blocks allocated
+    pub blocks_allocated: u64,
+    /// Blocks freed
+    pub blocks_freed: u64,
+    /// Segments reclaimed by the cleaner
+    pub segments_cleaned: u64,
+    /// Inodes written back
+    pub inodes_written: u64,
+    /// Blocks taken to write inodes back, fewer than the inodes if several share a block
+    pub inode_blocks_written: u64,
+    /// Bytes of file content written through the file system
+    pub data_bytes_written: u64,
+    /// Live blocks moved by the cleaner
+    pub blocks_moved: u64,
+    /// Reads from the device
+    pub reads: u64,
+    /// Writes to the device
+    pub writes: u64,
+    /// Syncs and flushes of the device
+    pub syncs: u64,
+    /// Trims of the device
+    pub trims: u64,
+    /// Failed operations on the device
+    pub io_errors: u64,
+    /// Bytes read from the device
+    pub bytes_read: u64,
+    /// Bytes written to the device
+    pub bytes_written: u64,
+    /// Latency of reads from the device
+    pub read_latency: LatencyHistogram,
+    /// Latency of writes to the device
+    pub write_latency: LatencyHistogram,
+    /// Latency of syncs and flushes of the device
+    pub sync_latency: LatencyHistogram,
+}
+
+/// One `name value` pair per line, like files under /proc
+impl fmt::Display for FsStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "cache_hits {}", self.cache_hits)?;
+        writeln!(f, "cache_misses {}", self.cache_misses)?;
+        writeln!(f, "cache_writebacks {}", self.cache_writebacks)?;
+        writeln!(f, "blocks_allocated {}", self.blocks_allocated)?;
+        writeln!(f, "blocks_freed {}", self.blocks_freed)?;
+        writeln!(f, "segments_cleaned {}", self.segments_cleaned)?;
+        writeln!(f, "inodes_written {}", self.inodes_written)?;
+        writeln!(f, "inode_blocks_written {}", self.inode_blocks_written)?;
+        writeln!(f, "data_bytes_written {}", self.data_bytes_written)?;
+        writeln!(f, "blocks_moved {}", self.blocks_moved)?;
+        writeln!(f, "reads {}", self.reads)?;
+        writeln!(f, "writes {}", self.writes)?;
+        writeln!(f, "syncs {}", self.syncs)?;
+        writeln!(f, "trims {}", self.trims)?;
+        writeln!(f, "io_errors {}", self.io_errors)?;
+        writeln!(f, "bytes_read {}", self.bytes_read)?;
+        writeln!(f, "bytes_written {}", self.bytes_written)?;
+        writeln!(f, "read_latency_us {}", self.read_latency)?;
+        writeln!(f, "write_latency_us {}", self.write_latency)?;
+        writeln!(f, "sync_latency_us {}", self.sync_latency)
+    }
+}
+
+/// One `name value` pair per line, as `FsStats`
+impl fmt::Display for FsInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "bsize {}", self.bsize)?;
+        writeln!(f, "frsize {}", self.frsize)?;
+        writeln!(f, "blocks {}", self.blocks)?;
+        writeln!(f, "bfree {}", self.bfree)?;
+        writeln!(f, "bavail {}", self.bavail)?;
+        writeln!(f, "files {}", self.files)?;
+        writeln!(f, "ffree {}", self.ffree)?;
+        writeln!(f, "namemax {}", self.namemax)
+    }
+}
+
 // Note: IOError/NoMemory always lead to a panic since it's hard to recover from it.
 //       We also panic when we can not parse the fs on disk normally
 #[derive(Debug, Eq, PartialEq)]
@@ -316,9 +743,12 @@ pub enum FsError {
     DeviceError,
     IOCTLError,
     NoDevice,
-    Again,   // E_AGAIN, when no data is available, never happens in fs
-    SymLoop, // E_LOOP
-    Busy,    // E_BUSY
+    Again,            // E_AGAIN, when no data is available, never happens in fs
+    SymLoop,          // E_LOOP
+    Busy,             // E_BUSY
+    Corrupted(usize), // E_IO, when the block of the device does not match its checksum
+    NotPermitted,     // E_PERM, e.g. when an immutable INode would be changed
+    ReadOnlyFs,       // E_ROFS, when a file system mounted read-only would be changed
 }
 
 impl fmt::Display for FsError {
@@ -328,8 +758,11 @@ impl fmt::Display for FsError {
 }
 
 impl From<DevError> for FsError {
-    fn from(_: DevError) -> Self {
-        FsError::DeviceError
+    fn from(err: DevError) -> Self {
+        match err {
+            DevError::Io => FsError::DeviceError,
+            DevError::Corrupted(block) => FsError::Corrupted(block),
+        }
     }
 }
 
@@ -337,7 +770,8 @@ pub type Result<T> = result::Result<T, FsError>;
 
 /// Abstract file system
 pub trait FileSystem: Sync + Send {
-    /// Sync all data to the storage
+    /// Sync all data to the storage.
+    /// A FS on a `Device` should end with `Device::flush()`.
     fn sync(&self) -> Result<()>;
 
     /// Get the root INode of the file system
@@ -345,8 +779,218 @@ pub trait FileSystem: Sync + Send {
 
     /// Get the file system information
     fn info(&self) -> FsInfo;
+
+    /// Get a consistent snapshot of the statistics counters
+    fn snapshot_stats(&self) -> FsStats {
+        FsStats::default()
+    }
+
+    /// Sync the metadata of the file system itself, e.g. the free block bitmap, but no INode.
+    /// Used by `INode::sync_recursive()`.
+    fn sync_metadata(&self) -> Result<()> {
+        self.sync()
+    }
+
+    /// Get the capabilities of the file system
+    fn capabilities(&self) -> FsCapabilities {
+        FsCapabilities {
+            features: FsFeatures::CASE_SENSITIVE,
+            namemax: self.info().namemax,
+        }
+    }
+
+    /// Start a batch of unlinks, e.g. from `remove_dir_all()`.
+    /// The file system may defer freeing and syncing of removed INodes until `end_batch()`.
+    fn begin_batch(&self) {}
+
+    /// Finish the batch started by `begin_batch()`
+    fn end_batch(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Zero all unallocated blocks and the slack space after the end of files,
+    /// so that the image does not leak deleted data.
+    /// Return the number of free blocks zeroed.
+    fn wipe_free_space(&self) -> Result<usize> {
+        Err(FsError::NotSupported)
+    }
+}
+
+/// Remove everything under directory `dir` recursively, `dir` itself is kept.
+///
+/// The whole subtree is walked before anything is unlinked,
+/// so an error while reading it leaves the tree untouched.
+/// Symlinks are removed but never followed.
+pub fn remove_dir_all(dir: &Arc<dyn INode>) -> Result<()> {
+    let mut victims = Vec::new();
+    collect_subtree(dir, &mut victims)?;
+    let fs = dir.fs();
+    fs.begin_batch();
+    let result = victims
+        .iter()
+        .try_for_each(|(parent, name)| parent.unlink(name));
+    drop(victims);
+    fs.end_batch()?;
+    result
+}
+
+/// Move `src_parent/src_name` to `dst_parent/dst_name`, even across file systems.
+///
+/// If `move_()` fails with `NotSameFs`, the entry is copied with its metadata,
+/// then the source is removed. An existing destination is not replaced in this case.
+/// If copying fails, whatever has been created at the destination is removed
+/// and the source is left untouched.
+/// Symlinks are copied as links, hard links in a copied directory become separate files.
+pub fn move_path(
+    src_parent: &Arc<dyn INode>,
+    src_name: &str,
+    dst_parent: &Arc<dyn INode>,
+    dst_name: &str,
+) -> Result<()> {
+    match src_parent.move_(src_name, dst_parent, dst_name) {
+        Err(FsError::NotSameFs) => {}
+        result => return result,
+    }
+    if dst_parent.find(dst_name).is_ok() {
+        return Err(FsError::EntryExist);
+    }
+    let src = src_parent.find(src_name)?;
+    if let Err(err) = copy_tree(&src, dst_parent, dst_name) {
+        // best effort, the error of copying is more interesting
+        if let Ok(dst) = dst_parent.find(dst_name) {
+            if let Ok(Metadata {
+                type_: FileType::Dir,
+                ..
+            }) = dst.metadata()
+            {
+                remove_dir_all(&dst).ok();
+            }
+            drop(dst);
+            dst_parent.unlink(dst_name).ok();
+        }
+        return Err(err);
+    }
+    if src.metadata()?.type_ == FileType::Dir {
+        remove_dir_all(&src)?;
+    }
+    drop(src);
+    src_parent.unlink(src_name)
 }
 
+/// A dir being copied, with its names left, its copy, and its metadata set on the copy at last
+type CopiedDir = (
+    Arc<dyn INode>,
+    vec::IntoIter<String>,
+    Arc<dyn INode>,
+    Metadata,
+);
+
+/// Copy `src` to `dst_parent/name` with all under it, with data and metadata.
+/// The dirs on the way are kept in a stack of their own, not in that of the kernel,
+/// however deep the tree is.
+fn copy_tree(src: &Arc<dyn INode>, dst_parent: &Arc<dyn INode>, name: &str) -> Result<()> {
+    let mut stack: Vec<CopiedDir> = copy_entry(src, dst_parent, name)?.into_iter().collect();
+    while let Some((src, names, dst, _)) = stack.last_mut() {
+        match names.next() {
+            Some(name) if name == "." || name == ".." => {}
+            Some(name) => {
+                if let Some(dir) = copy_entry(&src.find(&name)?, dst, &name)? {
+                    stack.push(dir);
+                }
+            }
+            None => {
+                let (_, _, dst, meta) = stack.pop().unwrap();
+                set_copied_metadata(&dst, &meta)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Copy `src` to `dst_parent/name`, but the entries of a dir, which is given back to copy them
+fn copy_entry(
+    src: &Arc<dyn INode>,
+    dst_parent: &Arc<dyn INode>,
+    name: &str,
+) -> Result<Option<CopiedDir>> {
+    let meta = src.metadata()?;
+    let dst = dst_parent.create2(name, meta.type_, meta.mode as u32, meta.rdev)?;
+    match meta.type_ {
+        FileType::Dir => return Ok(Some((src.clone(), src.list()?.into_iter(), dst, meta))),
+        FileType::File | FileType::SymLink => {
+            src.copy_range(0, &dst, 0, meta.size)?;
+        }
+        _ => {}
+    }
+    set_copied_metadata(&dst, &meta)?;
+    Ok(None)
+}
+
+/// Set the metadata of the copy `dst`, after its content, so that the times stay
+fn set_copied_metadata(dst: &Arc<dyn INode>, meta: &Metadata) -> Result<()> {
+    match dst.set_metadata(meta) {
+        Ok(()) | Err(FsError::NotSupported) => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// The top of the tree containing `dir`, whose ".." is itself
+fn top(mut dir: Arc<dyn INode>) -> Result<Arc<dyn INode>> {
+    loop {
+        let parent = dir.find("..")?;
+        if parent.is_same(&*dir)? {
+            return Ok(dir);
+        }
+        dir = parent;
+    }
+}
+
+/// Collect `(parent, name)` of all entries under `dir`, children before their parents.
+/// The dirs on the way are kept in a stack of their own, not in that of the kernel,
+/// however deep the tree is.
+fn collect_subtree(
+    dir: &Arc<dyn INode>,
+    victims: &mut Vec<(Arc<dyn INode>, String)>,
+) -> Result<()> {
+    // each dir walked, its names left, and its own name
+    let mut stack = vec![(dir.clone(), dir.list()?.into_iter(), String::new())];
+    loop {
+        let (dir, names, _) = stack.last_mut().unwrap();
+        match names.next() {
+            Some(name) if name == "." || name == ".." => {}
+            Some(name) => {
+                let child = dir.find(&name)?;
+                if child.metadata()?.type_ == FileType::Dir {
+                    let names = child.list()?.into_iter();
+                    stack.push((child, names, name));
+                } else {
+                    victims.push((dir.clone(), name));
+                }
+            }
+            None => {
+                let (_, _, name) = stack.pop().unwrap();
+                match stack.last() {
+                    Some((parent, _, _)) => victims.push((parent.clone(), name)),
+                    None => return Ok(()),
+                }
+            }
+        }
+    }
+}
+
+/// Device number of (major, minor), encoded as by Linux in 32 bits:
+/// a major of 12 bits and a minor of 20 bits, whose low 8 bits are at the bottom.
+/// A major below 256 and a minor below 256 give `(major << 8) | minor`.
 pub fn make_rdev(major: usize, minor: usize) -> usize {
-    ((major & 0xfff) << 8) | (minor & 0xff)
+    ((major & 0xfff) << 8) | (minor & 0xff) | ((minor & 0xfff00) << 12)
+}
+
+/// Major of device number `rdev`
+pub fn rdev_major(rdev: usize) -> usize {
+    (rdev >> 8) & 0xfff
+}
+
+/// Minor of device number `rdev`
+pub fn rdev_minor(rdev: usize) -> usize {
+    (rdev & 0xff) | ((rdev >> 12) & 0xfff00)
 }
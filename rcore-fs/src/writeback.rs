@@ -0,0 +1,185 @@
+//! Writeback of dirty state before a sync is asked for,
+//! so that a crash loses bounded data and a sync has bounded work to do
+//!
+//! A file system counts its writes in a `DirtyTracker` against a `WritebackPolicy`.
+//! A write passing a limit of the policy writes back at once, unless the policy leaves it
+//! to the background: then the kernel drives the `Flusher` of the file system,
+//! e.g. from a thread which polls `flush_if_needed()` every second.
+
+use crate::vfs::{Result, Timespec};
+use spin::Mutex;
+
+/// When a file system writes back its dirty state by itself, a limit of 0 is no limit
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct WritebackPolicy {
+    /// Blocks written since the last writeback
+    pub dirty_blocks: usize,
+    /// Bytes written since the last writeback
+    pub dirty_bytes: usize,
+    /// Milliseconds since a poll of the `Flusher` first saw the dirty state
+    pub max_age_ms: u64,
+    /// Leave the writeback to the `Flusher` when a limit is passed,
+    /// rather than doing it in the write which passed it
+    pub background: bool,
+}
+
+/// A file system whose dirty state is written back when the kernel asks for it
+pub trait Flusher: Send + Sync {
+    /// Whether the dirty state passed a limit of the policy, or is older than its max age.
+    /// `now` is from a clock which never goes backwards, the same in each call.
+    fn needs_flush(&self, now: Timespec) -> bool;
+
+    /// Write back all dirty state, like `FileSystem::sync()`
+    fn flush(&self) -> Result<()>;
+
+    /// Write back if needed at `now`, return whether it did
+    fn flush_if_needed(&self, now: Timespec) -> Result<bool> {
+        if !self.needs_flush(now) {
+            return Ok(false);
+        }
+        self.flush()?;
+        Ok(true)
+    }
+}
+
+/// Writes counted since the last writeback
+#[derive(Default)]
+pub struct DirtyTracker(Mutex<Dirty>);
+
+#[derive(Default)]
+struct Dirty {
+    policy: Option<WritebackPolicy>,
+    blocks: usize,
+    bytes: usize,
+    /// When a poll first saw the writes counted
+    since: Option<Timespec>,
+}
+
+impl Dirty {
+    fn over_limit(&self) -> bool {
+        let policy = match self.policy {
+            Some(policy) => policy,
+            None => return false,
+        };
+        let over = |count: usize, limit: usize| limit != 0 && count >= limit;
+        over(self.blocks, policy.dirty_blocks) || over(self.bytes, policy.dirty_bytes)
+    }
+}
+
+impl DirtyTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the policy, `None` for writing back only when synced
+    pub fn set_policy(&self, policy: Option<WritebackPolicy>) {
+        self.0.lock().policy = policy;
+    }
+
+    pub fn policy(&self) -> Option<WritebackPolicy> {
+        self.0.lock().policy
+    }
+
+    /// Count a write of `bytes` bytes to `blocks` blocks.
+    /// Return whether the writer should write back now, as it passed a limit
+    /// and the policy does not leave it to the background.
+    pub fn record(&self, blocks: usize, bytes: usize) -> bool {
+        let mut dirty = self.0.lock();
+        dirty.blocks += blocks;
+        dirty.bytes += bytes;
+        match dirty.policy {
+            Some(policy) => !policy.background && dirty.over_limit(),
+            None => false,
+        }
+    }
+
+    /// See `Flusher::needs_flush()`
+    pub fn needs_flush(&self, now: Timespec) -> bool {
+        let mut dirty = self.0.lock();
+        if dirty.blocks == 0 && dirty.bytes == 0 {
+            return false;
+        }
+        let policy = match dirty.policy {
+            Some(policy) => policy,
+            None => return false,
+        };
+        let since = *dirty.since.get_or_insert(now);
+        let age_ms = (now.sec - since.sec) * 1000 + (now.nsec - since.nsec) as i64 / 1_000_000;
+        dirty.over_limit() || (policy.max_age_ms != 0 && age_ms >= policy.max_age_ms as i64)
+    }
+
+    /// Forget the writes counted, when a writeback starts,
+    /// so that those done meanwhile are counted for the next one
+    pub fn clear(&self) {
+        let mut dirty = self.0.lock();
+        dirty.blocks = 0;
+        dirty.bytes = 0;
+        dirty.since = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn at(ms: i64) -> Timespec {
+        Timespec {
+            sec: ms / 1000,
+            nsec: (ms % 1000 * 1_000_000) as i32,
+        }
+    }
+
+    #[test]
+    fn limits() {
+        let tracker = DirtyTracker::new();
+        assert!(!tracker.record(100, 100 * 4096));
+        assert!(!tracker.needs_flush(at(0)));
+
+        tracker.set_policy(Some(WritebackPolicy {
+            dirty_blocks: 10,
+            dirty_bytes: 8192,
+            ..WritebackPolicy::default()
+        }));
+        tracker.clear();
+        assert!(!tracker.record(1, 100));
+        assert!(!tracker.needs_flush(at(0)));
+        // by bytes, then by blocks
+        assert!(tracker.record(1, 8092));
+        tracker.clear();
+        for _ in 0..9 {
+            assert!(!tracker.record(1, 1));
+        }
+        assert!(tracker.record(1, 1));
+        assert!(tracker.needs_flush(at(0)));
+
+        // left to the flusher
+        tracker.set_policy(Some(WritebackPolicy {
+            dirty_blocks: 10,
+            background: true,
+            ..WritebackPolicy::default()
+        }));
+        assert!(!tracker.record(10, 10));
+        assert!(tracker.needs_flush(at(0)));
+    }
+
+    #[test]
+    fn max_age() {
+        let tracker = DirtyTracker::new();
+        tracker.set_policy(Some(WritebackPolicy {
+            max_age_ms: 5000,
+            background: true,
+            ..WritebackPolicy::default()
+        }));
+        assert!(!tracker.needs_flush(at(0)));
+        // from the first poll which sees the write
+        tracker.record(1, 1);
+        assert!(!tracker.needs_flush(at(1500)));
+        assert!(!tracker.needs_flush(at(6499)));
+        assert!(tracker.needs_flush(at(6500)));
+        tracker.clear();
+        assert!(!tracker.needs_flush(at(20000)));
+        tracker.record(1, 1);
+        assert!(!tracker.needs_flush(at(21000)));
+        assert!(tracker.needs_flush(at(26000)));
+    }
+}
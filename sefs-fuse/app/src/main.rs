@@ -93,6 +93,8 @@ fn main() {
     };
 
     let device = sgx_dev::SgxStorage::new(enclave.geteid(), &opt.image);
+    // each call to a file is an ecall, so they are batched
+    let device = sefs::dev::BatchedStorage::new(device, 32);
     let fs = match create {
         true => {
             std::fs::create_dir(&opt.image)
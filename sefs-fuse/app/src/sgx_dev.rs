@@ -1,5 +1,6 @@
 use sgx_types::*;
-use rcore_fs_sefs::dev::{File, Storage, DevResult, DeviceError};
+use rcore_fs_sefs::dev::{File, Storage, StdStorage, DevResult, DeviceError};
+use rcore_fs_sefs::Key;
 use std::path::*;
 use std::fs::remove_file;
 
@@ -15,17 +16,25 @@ impl SgxStorage {
 }
 
 impl Storage for SgxStorage {
-    fn open(&self, file_id: usize) -> DevResult<Box<File>> {
+    fn open(&self, file_id: usize, key: Option<&Key>) -> DevResult<Box<File>> {
+        let key = match key {
+            Some(key) => key,
+            None => return StdStorage::new(&self.path).open(file_id, None),
+        };
         let mut path = self.path.clone();
         path.push(format!("{}", file_id));
-        let file = file_open(path.to_str().unwrap(), false, &[0u8; 16]);
+        let file = file_open(path.to_str().unwrap(), false, key);
         Ok(Box::new(SgxFile { file }))
     }
 
-    fn create(&self, file_id: usize) -> DevResult<Box<File>> {
+    fn create(&self, file_id: usize, key: Option<&Key>) -> DevResult<Box<File>> {
+        let key = match key {
+            Some(key) => key,
+            None => return StdStorage::new(&self.path).create(file_id, None),
+        };
         let mut path = self.path.clone();
         path.push(format!("{}", file_id));
-        let file = file_open(path.to_str().unwrap(), true, &[0u8; 16]);
+        let file = file_open(path.to_str().unwrap(), true, key);
         Ok(Box::new(SgxFile { file }))
     }
 